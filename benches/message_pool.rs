@@ -0,0 +1,93 @@
+//! Allocation benchmark for pooled receives.
+//!
+//! Receives many small multipart messages, with fresh buffers and with buffers recycled by
+//! a `MessagePool`, and reports the allocations, and the rate of messages, of each
+//! approach.
+//!
+//! Only the allocations of the Rust global allocator are counted. libzmq allocates frames
+//! of more than 33 bytes with its own `malloc`, for every message, pooled or not, so the
+//! benchmark runs with a frame that libzmq keeps inline, and with one that it does not.
+//!
+//! Run with `cargo bench --bench message_pool`.
+extern crate neuras;
+extern crate zmq;
+
+use neuras::socket::{MessagePool, SocketRecv};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const MESSAGES: usize = 100_000;
+const FRAME_SIZES: [usize; 2] = [32, 256];
+
+fn run<F>(name: &str, frame_size: usize, recv: F)
+where
+    F: Fn(&zmq::Socket),
+{
+    let ctx = zmq::Context::new();
+    let receiver = ctx.socket(zmq::PULL).unwrap();
+    receiver.set_rcvhwm(0).unwrap();
+    receiver.bind("inproc://bench").unwrap();
+    let sender = ctx.socket(zmq::PUSH).unwrap();
+    sender.set_sndhwm(0).unwrap();
+    sender.connect("inproc://bench").unwrap();
+    // queue every message first, so that only the receiving side is measured.
+    let frame = zmq::Message::from(&vec![7; frame_size][..]);
+    for _ in 0..MESSAGES {
+        sender.send("topic", zmq::SNDMORE).unwrap();
+        sender.send(&frame[..], 0).unwrap();
+    }
+    thread::sleep(Duration::from_millis(100));
+
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst);
+    let bytes = ALLOCATED_BYTES.load(Ordering::SeqCst);
+    let start = Instant::now();
+    for _ in 0..MESSAGES {
+        recv(&receiver);
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:>8} ({:>3} byte frames): {:>7} allocations, {:>10} bytes allocated, {:>9.0} msg/s",
+        name,
+        frame_size,
+        ALLOCATIONS.load(Ordering::SeqCst) - allocations,
+        ALLOCATED_BYTES.load(Ordering::SeqCst) - bytes,
+        MESSAGES as f64 / elapsed.as_secs_f64()
+    );
+}
+
+fn main() {
+    for &frame_size in FRAME_SIZES.iter() {
+        run("fresh", frame_size, |socket| {
+            let msg = SocketRecv::recv_multipart(socket, 0).unwrap();
+            assert_eq!(msg.len(), 2);
+        });
+        let pool = MessagePool::new(16);
+        run("pooled", frame_size, |socket| {
+            let msg = socket.recv_multipart_pooled(&pool, 0).unwrap();
+            assert_eq!(msg.len(), 2);
+            pool.recycle_multipart(msg);
+        });
+    }
+}
@@ -0,0 +1,82 @@
+//! Allocation benchmark for zero-copy sends.
+//!
+//! Sends the same large multipart message many times, by cloning its frames and by sharing
+//! them, and reports the bytes allocated and the time taken by each approach. Only the
+//! allocations of Rust are counted: frames copied out of shared buffers are allocated by
+//! libzmq, and show up in the time taken only.
+//!
+//! Run with `cargo bench --bench zerocopy`.
+extern crate neuras;
+extern crate zmq;
+
+use neuras::socket::SocketSend;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        ALLOCATED_BYTES.fetch_add(layout.size(), Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+const FRAME_SIZE: usize = 1 << 20;
+const FRAMES: usize = 4;
+const ROUNDS: usize = 200;
+
+fn run<F>(name: &str, send: F)
+where
+    F: Fn(&zmq::Socket),
+{
+    let ctx = zmq::Context::new();
+    let sender = ctx.socket(zmq::PAIR).unwrap();
+    sender.bind("inproc://bench").unwrap();
+    let receiver = ctx.socket(zmq::PAIR).unwrap();
+    receiver.connect("inproc://bench").unwrap();
+    let mut msg = zmq::Message::new();
+
+    let allocations = ALLOCATIONS.load(Ordering::SeqCst);
+    let bytes = ALLOCATED_BYTES.load(Ordering::SeqCst);
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        send(&sender);
+        for _ in 0..FRAMES {
+            receiver.recv(&mut msg, 0).unwrap();
+        }
+    }
+    let elapsed = start.elapsed();
+    println!(
+        "{:>8}: {:>6} allocations, {:>12} bytes allocated, {:?}",
+        name,
+        ALLOCATIONS.load(Ordering::SeqCst) - allocations,
+        ALLOCATED_BYTES.load(Ordering::SeqCst) - bytes,
+        elapsed
+    );
+}
+
+fn main() {
+    let frames: Vec<Vec<u8>> = (0..FRAMES).map(|i| vec![i as u8; FRAME_SIZE]).collect();
+    let shared: Vec<Arc<[u8]>> = frames.iter().map(|f| Arc::from(&f[..])).collect();
+
+    run("copied", |socket| {
+        SocketSend::send_multipart(socket, &frames, 0).unwrap();
+    });
+    run("shared", |socket| {
+        SocketSend::send_multipart_frames(socket, &shared, 0).unwrap();
+    });
+}
@@ -1,6 +1,8 @@
 #[macro_use]
 extern crate failure;
 extern crate futures;
+#[macro_use]
+extern crate log;
 extern crate neuras;
 extern crate tokio_core;
 extern crate tokio_signal;
@@ -15,6 +17,7 @@ use futures::future::{err, ok};
 use futures::stream;
 use futures::stream::Take;
 use futures::{FlattenStream, Future, Stream};
+use log::{Level, LevelFilter, Log, Metadata, Record};
 use neuras::actor::Actorling;
 use neuras::utils::run_named_thread;
 use tokio_core::reactor::{Core, Handle};
@@ -23,11 +26,36 @@ const POLL_TIMEOUT: i64 = 100;
 
 type Result<T> = ::std::result::Result<T, Error>;
 
+// Prints log records on `stderr`, with the thread name and target of each record.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= Level::Debug
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{:5} [{}] {}: {}",
+                record.level(),
+                thread::current().name().unwrap_or("main"),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
 fn run_pipe_thread(actor: &Actorling) -> Result<thread::JoinHandle<Result<()>>> {
     let addr = actor.address();
     let context = actor.context();
     let pipe_thread = run_named_thread("pipe", move || {
-        println!("enter: pipe thread");
+        debug!("enter: pipe thread");
         let pipe = context.socket(zmq::PAIR)?;
         pipe.bind(&addr)?;
         let controller = context.socket(zmq::PUB)?;
@@ -37,28 +65,25 @@ fn run_pipe_thread(actor: &Actorling) -> Result<thread::JoinHandle<Result<()>>>
         loop {
             zmq::poll(&mut items, POLL_TIMEOUT)?;
             if items[0].is_readable() {
-                eprintln!("pipe is readable");
+                trace!("pipe is readable");
                 pipe.recv(&mut msg, 0)?;
-                match msg.as_str() {
-                    Some(a) if a == "STOP" => {
-                        println!("broadcast: STOP");
-                        controller.send("STOP", 0)?;
-                        eprintln!("stop: pipe");
-                        break;
-                    }
-                    _ => {}
+                if let Some("STOP") = msg.as_str() {
+                    info!("broadcast: STOP");
+                    controller.send("STOP", 0)?;
+                    debug!("stop: pipe");
+                    break;
                 }
             } else {
-                eprintln!("pipe not readable");
+                trace!("pipe not readable");
             }
             if items[0].is_writable() {
-                eprintln!("pipe is writable");
+                trace!("pipe is writable");
             } else {
-                eprintln!("pipe not writable");
+                trace!("pipe not writable");
             }
             thread::sleep(Duration::from_millis(POLL_TIMEOUT as u64));
         }
-        println!("exit: pipe thread");
+        debug!("exit: pipe thread");
         Ok(())
     })
     .unwrap();
@@ -69,7 +94,7 @@ fn run_public(actor: &Actorling) -> Result<thread::JoinHandle<Result<()>>> {
     let addr = actor.address();
     let context = actor.context();
     let public_thread = run_named_thread("public", move || {
-        println!("enter: public thread");
+        debug!("enter: public thread");
         let public = context.socket(zmq::REP)?;
         public.bind(&addr)?;
         let controller = context.socket(zmq::SUB)?;
@@ -84,38 +109,35 @@ fn run_public(actor: &Actorling) -> Result<thread::JoinHandle<Result<()>>> {
             zmq::poll(&mut items, POLL_TIMEOUT)?;
             if items[1].is_readable() {
                 controller.recv(&mut msg, 0)?;
-                match msg.as_str() {
-                    Some(a) if a == "STOP" => {
-                        eprintln!("stop: public");
-                        public.disconnect(&addr)?;
-                        controller.set_unsubscribe(b"")?;
-                        controller.disconnect("inproc://controller")?;
-                        break;
-                    }
-                    _ => {}
+                if let Some("STOP") = msg.as_str() {
+                    debug!("stop: public");
+                    public.disconnect(&addr)?;
+                    controller.set_unsubscribe(b"")?;
+                    controller.disconnect("inproc://controller")?;
+                    break;
                 }
             } else {
-                eprintln!("public controller not readable");
+                trace!("public controller not readable");
             }
 
             if items[0].is_readable() {
                 public.recv(&mut msg, 0)?;
-                eprintln!("public is readable");
+                trace!("public is readable");
                 if let Some(a) = msg.as_str() {
                     println!("ECHO {}", a);
                     public.send(a, 0)?;
                 }
             } else {
-                eprintln!("public not readable");
+                trace!("public not readable");
             }
             if items[0].is_writable() {
-                eprintln!("public is writable");
+                trace!("public is writable");
             } else {
-                eprintln!("public not writable");
+                trace!("public not writable");
             }
             thread::sleep(Duration::from_millis(POLL_TIMEOUT as u64));
         }
-        println!("exit: public thread");
+        debug!("exit: public thread");
         Ok(())
     })
     .unwrap();
@@ -131,8 +153,8 @@ fn control_pipe_stream(context: &zmq::Context) -> Result<()> {
     let control_pipe = stream::unfold(controller, |controller| {
         controller.recv(&mut msg, 0).unwrap();
         let fut = match msg.as_str() {
-            Some(m) if m == "STOP" => {
-                eprintln!("stop: play");
+            Some("STOP") => {
+                debug!("stop: play");
                 controller.set_unsubscribe(b"").unwrap();
                 controller.disconnect("inproc://controller").unwrap();
                 return None;
@@ -143,7 +165,7 @@ fn control_pipe_stream(context: &zmq::Context) -> Result<()> {
         Some(fut)
     })
     .for_each(|msg| {
-        println!("msg: {:?}", msg);
+        info!("msg: {:?}", msg);
         Ok(())
     });
     core.run(control_pipe).unwrap();
@@ -154,14 +176,14 @@ fn run_playful(actor: &Actorling) -> Result<thread::JoinHandle<Result<()>>> {
     let addr = actor.address();
     let context = actor.context();
     let public_thread = run_named_thread("play", move || {
-        println!("enter: play thread");
+        debug!("enter: play thread");
         let public = context.socket(zmq::REQ).unwrap();
         public.connect(&addr).unwrap();
 
         control_pipe_stream(&context).unwrap();
 
         public.disconnect(&addr).unwrap();
-        println!("exit: play thread");
+        debug!("exit: play thread");
         Ok(())
     })
     .unwrap();
@@ -179,6 +201,9 @@ fn catch_sigint(handle: &Handle) -> SignalInterruption {
 }
 
 fn main() {
+    log::set_logger(&LOGGER).unwrap();
+    log::set_max_level(LevelFilter::Debug);
+
     let actor = Actorling::new("tcp://127.0.0.1:8889").unwrap();
 
     // spawn the pipe thread.
@@ -208,7 +233,7 @@ fn main() {
         })
         .for_each(|sender| {
             // Send the `STOP` message to the pipe thread.
-            eprintln!("control: STOP");
+            info!("control: STOP");
             sender.send("STOP", 0)?;
             Ok(())
         });
@@ -239,11 +264,11 @@ fn cleanup_n_exit(threads: Vec<thread::JoinHandle<Result<()>>>) -> Result<()> {
         match t.join() {
             Ok(_) => (),
             Err(e) => {
-                eprintln!("thread not joined {:?}", e);
+                error!("thread not joined {:?}", e);
                 format_err!("thread not joined");
             }
         }
-        println!("joined {:?} thread with parent", name);
+        debug!("joined {:?} thread with parent", name);
     }
     Ok(())
 }
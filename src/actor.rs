@@ -15,17 +15,85 @@
 //!
 //!
 
-use super::socket::{PollingSocket, SocketRecv, SocketWrapper};
+use super::clock::Clock;
+use super::endpoint::{Endpoint, EndpointCheck, ToEndpoint, Transport};
+use super::pipeline::{Middleware, Pipeline};
+use super::security::{
+    has_zap_handler, monitor_handshakes, secure_curve_server, Authenticator, CurveKeyPair,
+    Handshake, HandshakeEvents, KeysCertificate, SecurityError,
+};
+use super::socket::{
+    pack, FlowControl, Identity, PollingSocket, SocketRecv, SocketSend, SocketStats,
+    SocketWrapper,
+};
 use super::utils::run_named_thread;
 
 use failure::Error;
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
 use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::{Duration, Instant};
+use toml;
 use uuid::Uuid;
 use zmq;
 
-const PIPE_ADDR: &str = "inproc://neuras.actor.pipe";
+#[path = "actor_ask.rs"]
+mod ask;
+#[path = "actor_directory.rs"]
+mod directory;
+#[path = "actor_http.rs"]
+mod http;
+#[path = "actor_mailbox.rs"]
+mod mailbox;
+#[path = "actor_pipe.rs"]
+mod pipe;
+#[path = "actor_runner.rs"]
+mod runner;
+#[path = "actor_scheduler.rs"]
+mod scheduler;
+#[path = "actor_sockets.rs"]
+mod sockets;
+#[path = "actor_spool.rs"]
+mod spool;
+
+#[cfg(feature = "async-tokio")]
+pub use self::ask::AskFuture;
+pub use self::ask::{ask, ActorRef, AskHandle, ASK};
+pub use self::directory::{Sibling, TopologyEvent, DIRECTORY_ADDR, TOPOLOGY};
+pub use self::http::{HealthProbe, HealthServer, DEFAULT_PROBE_TIMEOUT};
+pub use self::mailbox::{Priority, CONTROL, DEFAULT_STARVATION_LIMIT, HIGH};
+pub use self::pipe::{Drain, Multipart, PipeClient, PipeReply, PipeStatus, DRAIN_BATCH};
+pub use self::runner::{Actor, ActorContext, ActorRunner};
+pub use self::scheduler::{Scheduled, Scheduler};
+pub use self::sockets::{ActorSocket, SocketHandler};
+pub use self::spool::{Spool, SpoolConfig, DEFAULT_SPOOL_LIMIT};
+
+use self::mailbox::Inbox;
+use self::sockets::OpenSocket;
+
+/// Address of the pipe that was shared by every `Actorling`, so that two actors in the
+/// same context collided. Pipes are now unique for each actor, see
+/// `Actorling::pipe_endpoint`.
+#[deprecated(note = "pipes are unique for each actor, use `Actorling::pipe_endpoint`")]
+pub const PIPE_ADDR: &str = "inproc://neuras.actor.pipe";
+// Prefix for the unique pipe address of each actor, followed by its UUID.
+const PIPE_PREFIX: &str = "inproc://neuras.actor.pipe";
+// ZAP domain of secure service sockets.
+const SECURE_DOMAIN: &str = "neuras.actor";
+// Maximum number of service messages received per poll wakeup.
+const SERVICE_BATCH: usize = 64;
+/// Command that forwards a message, over the pipe of an actor, to the actor with the UUID
+/// in its first argument, see `Actorling::send_to`.
+pub const FORWARD: &str = "$FORWARD";
+/// Command that asks an actor for its `Health`, over its pipe, or over its service socket
+/// when it replies to requests.
+pub const HEALTH: &str = "$HEALTH";
+/// Command that takes up to as many messages from the inbox of an actor, over its pipe, as
+/// its argument, see `Actorling::drain`.
+pub const POPN: &str = "$POPN";
 
 /// Actorling Errors.
 #[derive(Debug, Fail)]
@@ -34,32 +102,352 @@ pub enum ActorlingError {
     Interrupted,
     #[fail(display = "invalid command")]
     InvalidCommand,
+    #[fail(display = "no reply over the pipe")]
+    NoReply,
+    #[fail(display = "unexpected reply over the pipe: {}", _0)]
+    UnexpectedReply(String),
+    #[fail(display = "no reply to ask {}", _0)]
+    AskTimeout(Uuid),
     #[fail(display = "{}", _0)]
     SocketSend(#[cause] zmq::Error),
 }
 
-/// A mailbox where every incoming message goes through.
+/// A mailbox where every incoming message goes through. Its inbox has a lane for each
+/// `Priority`, which the poll loop drains from the highest priority first. The inbox can be
+/// spooled to disk, see `Mailbox::with_spool`.
 #[derive(Debug, Default, PartialEq)]
 pub struct Mailbox {
-    inbox: VecDeque<Vec<Vec<u8>>>,
+    inbox: Inbox,
     outbox: VecDeque<PipeCommand>,
+    spool: Option<Spool>,
+}
+
+impl Mailbox {
+    /// Create an empty `Mailbox`, which takes a lower priority message after every
+    /// `starvation_limit` consecutive messages of higher priority taken ahead of it, or
+    /// never if it is 0.
+    pub fn with_starvation_limit(starvation_limit: usize) -> Mailbox {
+        Mailbox {
+            inbox: Inbox::with_starvation_limit(starvation_limit),
+            outbox: VecDeque::new(),
+            spool: None,
+        }
+    }
+
+    /// Create a `Mailbox`, as with `with_starvation_limit`, whose inbox is spooled to disk
+    /// as configured by `spool`. The messages left in the spool, e.g. by an actor that
+    /// crashed, are queued again.
+    pub fn with_spool(starvation_limit: usize, spool: &SpoolConfig) -> Result<Mailbox, Error> {
+        let spool = Spool::open(spool)?;
+        let mut mbox = Mailbox::with_starvation_limit(starvation_limit);
+        for (sequence, msg) in spool.pending() {
+            mbox.inbox.push(msg, Some(sequence));
+        }
+        mbox.spool = Some(spool);
+        Ok(mbox)
+    }
+
+    /// Returns the spool of the inbox, if it has one.
+    pub fn spool(&self) -> Option<&Spool> {
+        self.spool.as_ref()
+    }
+
+    // Queue `msg` in the inbox, spooling it first. Messages that can't be spooled are
+    // queued anyway.
+    fn push(&mut self, msg: Vec<Vec<u8>>) {
+        let sequence = match self.spool {
+            Some(ref mut spool) => spool.push(&msg).unwrap_or_else(|e| {
+                nlog!(warn, "message not spooled error={}", e);
+                None
+            }),
+            None => None,
+        };
+        self.inbox.push(msg, sequence);
+    }
+
+    // Take the next message from the inbox, confirming it in the spool.
+    fn pop(&mut self) -> Option<Vec<Vec<u8>>> {
+        let (sequence, msg) = self.inbox.pop()?;
+        if let (Some(spool), Some(sequence)) = (self.spool.as_mut(), sequence) {
+            if let Err(e) = spool.confirm(sequence) {
+                nlog!(warn, "message not confirmed in the spool error={}", e);
+            }
+        }
+        Some(msg)
+    }
+
+    // Returns the next message of the inbox, without taking it.
+    fn peek(&self) -> Option<&Vec<Vec<u8>>> {
+        self.inbox.peek()
+    }
+
+    /// Returns the number of messages waiting in the lane of `priority`.
+    pub fn waiting(&self, priority: Priority) -> usize {
+        self.inbox.lane_len(priority)
+    }
+}
+
+/// Liveness, and readiness, of a running `Actorling`, as answered to `$HEALTH` commands,
+/// serialized as TOML.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    /// Milliseconds since the actor started.
+    pub uptime: i64,
+    /// Number of messages waiting in the inbox.
+    pub inbox: usize,
+    /// Number of commands waiting in the outbox.
+    pub outbox: usize,
+    /// The last error that the actor recovered from, if any.
+    pub last_error: Option<String>,
+    /// Counters of the pipe socket.
+    pub pipe: SocketStats,
+    /// Counters of the service socket.
+    pub service: SocketStats,
+}
+
+impl Health {
+    /// Parse a `Health` from its serialized form.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Health, Error> {
+        Ok(toml::from_str(::std::str::from_utf8(bytes)?)?)
+    }
+
+    /// Serialize the `Health`.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        Ok(toml::to_string(self)?.into_bytes())
+    }
+}
+
+/// The kind of service socket that an `Actorling` binds to its address, matching the
+/// messaging pattern it takes part in.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum ServiceKind {
+    /// Receive messages pushed by other actors. This is the default.
+    #[default]
+    Pull,
+    /// Receive requests that are answered, one at a time, with `Actorling::reply`.
+    Rep,
+    /// Receive requests prefixed by the identity of their sender. Replies sent with
+    /// `Actorling::reply` must start with that identity, as `Actorling::reply_to` does.
+    /// The service socket has the identity of the actor, see `Actorling::identity`.
+    Router,
+    /// Receive messages published under any of the `topics`. An empty topic matches every
+    /// message.
+    Sub { topics: Vec<Vec<u8>> },
+}
+
+impl ServiceKind {
+    /// Returns the socket type for this kind of service.
+    pub fn socket_type(&self) -> zmq::SocketType {
+        match *self {
+            ServiceKind::Pull => zmq::PULL,
+            ServiceKind::Rep => zmq::REP,
+            ServiceKind::Router => zmq::ROUTER,
+            ServiceKind::Sub { .. } => zmq::SUB,
+        }
+    }
+
+    /// Returns true if the service socket can send replies.
+    pub fn replies(&self) -> bool {
+        match *self {
+            ServiceKind::Rep | ServiceKind::Router => true,
+            ServiceKind::Pull | ServiceKind::Sub { .. } => false,
+        }
+    }
+
+    // Create the service socket, with its subscriptions if any.
+    fn socket(&self, context: &zmq::Context) -> Result<zmq::Socket, zmq::Error> {
+        let socket = context.socket(self.socket_type())?;
+        if let ServiceKind::Sub { ref topics } = *self {
+            for topic in topics {
+                socket.set_subscribe(topic)?;
+            }
+        }
+        Ok(socket)
+    }
+}
+
+/// A token that is handed out to actor threads and poll loops, to check whether they
+/// have been asked to shut down.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownToken {
+    flag: Arc<AtomicBool>,
+}
+
+impl ShutdownToken {
+    /// Returns true once shutdown has been requested by the controller.
+    pub fn is_shutdown(&self) -> bool {
+        self.flag.load(Ordering::SeqCst)
+    }
+}
+
+/// Outcome of a coordinated shutdown.
+#[derive(Debug, Default, PartialEq)]
+pub struct ShutdownReport {
+    /// Names of the threads that finished within the grace period.
+    pub joined: Vec<String>,
+    /// Names of the threads that were still running after the grace period.
+    pub stuck: Vec<String>,
+}
+
+impl ShutdownReport {
+    /// Returns true if every registered thread finished within the grace period.
+    pub fn is_clean(&self) -> bool {
+        self.stuck.is_empty()
+    }
+}
+
+/// Why the poll loop of an actor ended.
+#[derive(Debug)]
+pub enum ExitReason {
+    /// The actor was asked to `$STOP` over its pipe.
+    Stopped,
+    /// The `ShutdownToken` of the actor was signaled.
+    Shutdown,
+    /// The actor stopped itself, e.g. with `ActorContext::stop`.
+    Finished,
+    /// The pipe could not be read anymore, e.g. because its context was terminated.
+    PipeClosed,
+}
+
+/// How an actor exited, with the messages that were still in its inbox, taken by
+/// priority, so that a supervisor can hand them to its replacement.
+#[derive(Debug)]
+pub struct ActorExit {
+    pub reason: ExitReason,
+    pub drained_mailbox: Vec<Vec<Vec<u8>>>,
+}
+
+impl ActorExit {
+    fn new(reason: ExitReason) -> ActorExit {
+        ActorExit {
+            reason,
+            drained_mailbox: Vec::new(),
+        }
+    }
+
+    /// Returns true unless the poll loop lost its pipe.
+    pub fn is_clean(&self) -> bool {
+        match self.reason {
+            ExitReason::Stopped | ExitReason::Shutdown | ExitReason::Finished => true,
+            ExitReason::PipeClosed => false,
+        }
+    }
+}
+
+/// Error of a poll loop that failed, with the messages that were still in its inbox, as
+/// in `ActorExit`. Supervisors get it back with `Error::downcast`.
+#[derive(Debug, Fail)]
+#[fail(display = "actor failed: {}", error)]
+pub struct ActorFailure {
+    pub error: Error,
+    pub drained_mailbox: Vec<Vec<Vec<u8>>>,
+}
+
+// Thread handle for a running actor.
+type ActorThread = thread::JoinHandle<Result<ActorExit, Error>>;
+
+/// Signals every `ShutdownToken` it has handed out to stop at once, and waits for the
+/// registered threads to finish.
+#[derive(Default)]
+pub struct ShutdownController {
+    token: ShutdownToken,
+    threads: Mutex<Vec<(String, ActorThread)>>,
+}
+
+impl ShutdownController {
+    /// Create a new `ShutdownController`.
+    pub fn new() -> Self {
+        ShutdownController::default()
+    }
+
+    /// Returns a token for an actor or poll loop to watch.
+    pub fn token(&self) -> ShutdownToken {
+        self.token.clone()
+    }
+
+    /// Register a thread to be waited on during shutdown.
+    pub fn register(&self, name: &str, handle: ActorThread) {
+        self.threads
+            .lock()
+            .expect("shutdown controller lock poisoned")
+            .push((name.to_string(), handle));
+    }
+
+    /// Signal every token to shut down, without waiting for threads.
+    pub fn signal(&self) {
+        self.token.flag.store(true, Ordering::SeqCst);
+    }
+
+    /// Signal every token to shut down, and wait up to `grace` for the registered threads
+    /// to finish. Threads that are still running afterwards are reported as stuck, and
+    /// are left detached.
+    pub fn shutdown(&self, grace: Duration) -> ShutdownReport {
+        self.signal();
+        let deadline = Instant::now() + grace;
+        let mut pending: Vec<_> = self
+            .threads
+            .lock()
+            .expect("shutdown controller lock poisoned")
+            .drain(..)
+            .collect();
+        let mut report = ShutdownReport::default();
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) =
+                pending.into_iter().partition(|(_, h)| h.is_finished());
+            for (name, handle) in finished {
+                let _ = handle.join();
+                report.joined.push(name);
+            }
+            pending = running;
+            if pending.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        report.stuck = pending.into_iter().map(|(name, _)| name).collect();
+        report
+    }
 }
 
-impl Mailbox {}
+// The sockets of a started actor, and what it is known by, for its poll loop.
+struct Service {
+    pipe: zmq::Socket,
+    socket: zmq::Socket,
+    forward: zmq::Socket,
+    topology: Option<zmq::Socket>,
+    kind: ServiceKind,
+    sibling: Sibling,
+    context: zmq::Context,
+    token: ShutdownToken,
+    mailbox: Mailbox,
+    sockets: Vec<OpenSocket>,
+    middleware: Pipeline,
+}
 
 #[allow(dead_code)]
 /// A base type for actor-like entities
 pub struct Actorling {
-    address: String,
+    addresses: Vec<Endpoint>,
     context: zmq::Context,
     pipe: zmq::Socket,
+    pipe_endpoint: String,
+    service: ServiceKind,
+    flow: FlowControl,
+    curve: Option<KeysCertificate>,
+    name: Option<String>,
+    starvation_limit: usize,
+    spool: Option<SpoolConfig>,
+    sockets: Vec<ActorSocket>,
+    middleware: Pipeline,
+    topology: bool,
+    handshakes: Option<HandshakeEvents>,
     uuid: Uuid,
 }
 
 impl Actorling {
     /// Create a new `Actorling` instance with the address that it will be known for within
     /// the network.
-    pub fn new(addr: &str) -> Result<Self, Error> {
+    pub fn new<E: ToEndpoint>(addr: E) -> Result<Self, Error> {
         Actorling::new_with_context(addr, zmq::Context::new())
     }
 
@@ -67,31 +455,169 @@ impl Actorling {
     /// Useful for creating actors in the same process (possibly/commonly in child threads),
     /// that can talk to the creator actor (usually running on the main thread, but could be
     /// run from a child thread as well).
-    pub fn new_with_context(addr: &str, context: zmq::Context) -> Result<Self, Error> {
-        let address = addr.to_string();
-        let pipe = context.socket(zmq::PAIR)?;
-        pipe.connect(PIPE_ADDR)?;
+    pub fn new_with_context<E: ToEndpoint>(addr: E, context: zmq::Context) -> Result<Self, Error> {
+        Actorling::new_with_service(addr, context, ServiceKind::default())
+    }
+
+    /// Create a new `Actorling` instance that shares network context with the creator, and
+    /// listens on its address with the given kind of service socket.
+    pub fn new_with_service<E: ToEndpoint>(
+        addr: E,
+        context: zmq::Context,
+        service: ServiceKind,
+    ) -> Result<Self, Error> {
+        let addresses = vec![addr.to_endpoint()?];
         let uuid = Uuid::new_v4();
+        let pipe_endpoint = format!("{}.{}", PIPE_PREFIX, uuid.to_simple());
+        let pipe = context.socket(zmq::PAIR)?;
+        pipe.connect(&pipe_endpoint)?;
         let actorling = Actorling {
-            address,
+            addresses,
             context,
             pipe,
+            pipe_endpoint,
+            service,
+            flow: FlowControl::default(),
+            curve: None,
+            name: None,
+            starvation_limit: DEFAULT_STARVATION_LIMIT,
+            spool: None,
+            sockets: Vec::new(),
+            middleware: Pipeline::new(),
+            topology: false,
+            handshakes: None,
             uuid,
         };
         Ok(actorling)
     }
+
+    /// Create a new `Actorling` whose service socket is a CURVE server with the key pair of
+    /// `server_cert`. Clients are authenticated by `authenticator`, which has to be started
+    /// for them to connect, and must present a certificate that it allows. The actor shares
+    /// the context of the authenticator. The pipe remains inproc, and is not encrypted.
+    ///
+    /// libzmq lets every client in when no ZAP handler is running, so the actor fails to
+    /// start, with `SecurityError::NoZapHandler`, unless the authenticator was started.
+    pub fn new_secure<E: ToEndpoint>(
+        addr: E,
+        server_cert: &KeysCertificate,
+        authenticator: &Authenticator,
+    ) -> Result<Self, Error> {
+        // fail early on malformed, or public-only, certificates.
+        CurveKeyPair::try_from(server_cert)?;
+        let mut actorling = Actorling::new_with_context(addr, authenticator.context())?;
+        actorling.curve = Some(server_cert.clone());
+        Ok(actorling)
+    }
+}
+
+impl Actorling {
+    /// Use a different endpoint for the pipe, instead of the one derived from the actor's
+    /// UUID. Compatibility shim for code that expects the old, shared, `PIPE_ADDR`; only
+    /// one actor per context may use it at a time.
+    pub fn with_pipe_endpoint(mut self, endpoint: &str) -> Result<Self, Error> {
+        let pipe = self.context.socket(zmq::PAIR)?;
+        pipe.connect(endpoint)?;
+        self.pipe = pipe;
+        self.pipe_endpoint = endpoint.to_string();
+        Ok(self)
+    }
+
+    /// Check the addresses of the service socket with `check`, e.g. to look their hosts up
+    /// before the actor starts, instead of failing when it binds.
+    pub fn with_endpoint_check(self, check: &EndpointCheck) -> Result<Self, Error> {
+        for address in &self.addresses {
+            address.check_bind(check)?;
+        }
+        Ok(self)
+    }
+
+    /// Give the actor a name, under which its siblings find it, see `Actorling::siblings`.
+    pub fn with_name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    /// Take a message of lower `Priority` after every `limit` consecutive messages of higher
+    /// priority taken ahead of it, instead of `DEFAULT_STARVATION_LIMIT`, or never if it is
+    /// 0. Takes effect on `start`.
+    pub fn with_starvation_limit(mut self, limit: usize) -> Self {
+        self.starvation_limit = limit;
+        self
+    }
+
+    /// Spool the inbox to disk, as configured by `spool`, so that messages received, but
+    /// not yet taken, survive a crash of the actor, and are queued again when it starts
+    /// with the same spool. Takes effect on `start`.
+    pub fn with_spool(mut self, spool: SpoolConfig) -> Self {
+        self.spool = Some(spool);
+        self
+    }
+
+    /// Queue a `TopologyEvent` in the inbox, at `Priority::Control`, for every sibling that
+    /// is running when the actor starts, and for every sibling that starts, or stops, while
+    /// it runs. Takes effect on `start`.
+    pub fn with_topology_events(mut self) -> Self {
+        self.topology = true;
+        self
+    }
+
+    /// Monitor the handshakes of the clients of the service socket, e.g. of a secure actor,
+    /// see `Actorling::new_secure`, for `Actorling::handshake_result`. Their results queue
+    /// until they are read, so they should be read as clients connect. Takes effect on
+    /// `start`.
+    pub fn with_handshake_events(mut self) -> Result<Self, Error> {
+        let events = HandshakeEvents::connect(&self.context, &self.handshake_endpoint())?;
+        self.handshakes = Some(events);
+        Ok(self)
+    }
+
+    /// Poll `socket` alongside the pipe and the service socket, e.g. a `SUB` for
+    /// broadcasts, handing its messages to its handler instead of the inbox. Takes effect
+    /// on `start`.
+    pub fn with_socket(mut self, socket: ActorSocket) -> Self {
+        self.sockets.push(socket);
+        self
+    }
+
+    /// Run the messages of the service socket, and those forwarded to the actor, through
+    /// `middleware`, after the middlewares added before it, and before they are queued in
+    /// the inbox. Commands, e.g. `$HEALTH`, are answered without going through it. Messages
+    /// that it fails are dropped, and its error is kept as the last error of the actor.
+    pub fn with_middleware<M: Middleware + 'static>(mut self, middleware: M) -> Self {
+        self.middleware = self.middleware.with(middleware);
+        self
+    }
 }
 
 impl Default for Actorling {
     fn default() -> Self {
-        Self::new("").unwrap()
+        let address = format!("inproc://neuras.actor.{}", Uuid::new_v4().to_simple());
+        Self::new(address).unwrap()
     }
 }
 
 impl Actorling {
     /// Returns a `String` with the address for the Actorling.
     pub fn address(&self) -> String {
-        self.address.clone()
+        self.addresses[0].to_string()
+    }
+
+    /// Returns every address the Actorling's service socket will be bound to.
+    pub fn addresses(&self) -> Vec<String> {
+        self.addresses.iter().map(|e| e.to_string()).collect()
+    }
+
+    /// Returns the parsed endpoints the Actorling's service socket will be bound to.
+    pub fn service_endpoints(&self) -> &[Endpoint] {
+        &self.addresses
+    }
+
+    /// Add another address for the service socket to be bound to, e.g. to serve both local
+    /// (`ipc://...`) and remote (`tcp://...`) clients. Takes effect on `start`.
+    pub fn add_address<E: ToEndpoint>(&mut self, addr: E) -> Result<(), Error> {
+        self.addresses.push(addr.to_endpoint()?);
+        Ok(())
     }
 
     /// Returns the actorling's network context.
@@ -101,6 +627,64 @@ impl Actorling {
         self.context.clone()
     }
 
+    /// Returns the kind of service socket the actorling listens with.
+    pub fn service_kind(&self) -> &ServiceKind {
+        &self.service
+    }
+
+    /// Returns the inproc endpoint of the pipe, which is unique for each actor.
+    pub fn pipe_endpoint(&self) -> String {
+        self.pipe_endpoint.clone()
+    }
+
+    // Returns the inproc endpoint of the handshake events of the service socket.
+    fn handshake_endpoint(&self) -> String {
+        format!("{}.handshakes", self.pipe_endpoint)
+    }
+
+    /// Returns the flow-control configuration of the service socket.
+    pub fn flow_control(&self) -> &FlowControl {
+        &self.flow
+    }
+
+    /// Set the flow-control configuration of the service socket, e.g. its high-water marks.
+    /// Takes effect on `start`.
+    pub fn set_flow_control(&mut self, flow: FlowControl) {
+        self.flow = flow;
+    }
+
+    /// Returns the starvation limit of the inbox, see `Actorling::with_starvation_limit`.
+    pub fn starvation_limit(&self) -> usize {
+        self.starvation_limit
+    }
+
+    /// Returns the configuration of the spool of the inbox, see `Actorling::with_spool`.
+    pub fn spool(&self) -> Option<&SpoolConfig> {
+        self.spool.as_ref()
+    }
+
+    /// Returns the sockets that the actorling polls, other than its pipe and its service
+    /// socket, see `Actorling::with_socket`.
+    pub fn sockets(&self) -> &[ActorSocket] {
+        &self.sockets
+    }
+
+    /// Returns true if the service socket is a CURVE server, see `Actorling::new_secure`.
+    pub fn is_secure(&self) -> bool {
+        self.curve.is_some()
+    }
+
+    /// Returns the result of the latest handshake of a client with the service socket since
+    /// the last call, or waits up to `timeout` for the next one. Fails with
+    /// `SecurityError::NotMonitored` unless the actor was created
+    /// `with_handshake_events`.
+    pub fn handshake_result(&self, timeout: Duration) -> Result<Handshake, SecurityError> {
+        match self.handshakes {
+            Some(ref events) => events.handshake_result(timeout),
+            None => Err(SecurityError::NotMonitored),
+        }
+    }
+
     /// Return a reference to the underlying pipe socket.
     pub fn pipe(&self) -> &zmq::Socket {
         &self.pipe
@@ -112,139 +696,800 @@ impl Actorling {
     }
 
     /// Start the current actorling instance.
-    pub fn start(&self) -> Result<thread::JoinHandle<Result<(), Error>>, io::Error> {
-        // We create a new UUID that will only be known to each PAIR socket at runtime.
+    pub fn start(&self) -> Result<ActorThread, io::Error> {
+        self.start_with_shutdown(ShutdownToken::default())
+    }
+
+    /// Start the current actorling instance, which will also stop when the `token`
+    /// is signaled by its `ShutdownController`.
+    pub fn start_with_shutdown(&self, token: ShutdownToken) -> Result<ActorThread, io::Error> {
+        self.start_service(token, |service| {
+            let mut mbox = service.mailbox;
+            let sockets = LoopSockets {
+                pipe: service.pipe,
+                service: service.socket,
+                forward: Some((service.forward, Forwarder::new(service.context))),
+                topology: service.topology,
+                extra: service.sockets,
+                middleware: service.middleware,
+            };
+            poll_service(sockets, &service.kind, &mut mbox, 10, &service.token, None)
+        })
+    }
+
+    // Start a thread that binds the pipe, the service socket, and opens the other sockets
+    // of the actor, registers the actor with
+    // its siblings, and hands them to `run`, until it returns.
+    fn start_service<F>(&self, token: ShutdownToken, run: F) -> Result<ActorThread, io::Error>
+    where
+        F: FnOnce(Service) -> Result<ActorExit, Error> + Send + 'static,
+    {
+        // The pipe endpoint is derived from the actor's UUID, so it is only known to each
+        // PAIR socket at runtime.
         let context = self.context();
-        let address = self.address();
-        let mut mbox = Mailbox::default();
+        let pipe_endpoint = self.pipe_endpoint();
+        let addresses = self.addresses.clone();
+        for address in &self.addresses {
+            Transport::from_endpoint(address)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        }
+        if self.curve.is_some() && !has_zap_handler(&context)? {
+            let e = SecurityError::NoZapHandler;
+            return Err(io::Error::new(io::ErrorKind::PermissionDenied, e.to_string()));
+        }
+        let kind = self.service.clone();
+        let flow = self.flow.clone();
+        let curve = self.curve.clone();
+        let starvation_limit = self.starvation_limit;
+        let spool = self.spool.clone();
+        let topology = self.topology;
+        let handshakes = self.handshakes.as_ref().map(|_| self.handshake_endpoint());
+        let extra = self.sockets.clone();
+        let middleware = self.middleware.clone();
+        let uuid = self.uuid();
+        let name = self.name();
+        let identity = self.identity();
 
         run_named_thread("pipe", move || {
+            let _span = nspan!("actor", "pipe={}", pipe_endpoint);
             let pipe = context.socket(zmq::PAIR)?;
-            pipe.bind(PIPE_ADDR)?;
+            pipe.bind(&pipe_endpoint)?;
 
-            let service = context.socket(zmq::PULL)?;
-            service.bind(&address)?;
-            let pub_addr = service
-                .get_last_endpoint()?
-                .expect("unparsable actor endpoint");
-            pipe.send(&pub_addr, 0)?;
+            let service = kind.socket(&context)?;
+            if let Some(ref endpoint) = handshakes {
+                monitor_handshakes(&service, endpoint)?;
+            }
+            identity.apply(&service)?;
+            flow.apply(&service)?;
+            if let Some(ref cert) = curve {
+                secure_curve_server(&service, &CurveKeyPair::try_from(cert)?)?;
+                service.set_zap_domain(SECURE_DOMAIN)?;
+            }
+            let mut endpoints = Vec::with_capacity(addresses.len());
+            for address in &addresses {
+                endpoints.push(service.bind_resolved(address)?.to_string());
+            }
+            let mailbox = match spool {
+                Some(ref spool) => Mailbox::with_spool(starvation_limit, spool)?,
+                None => Mailbox::with_starvation_limit(starvation_limit),
+            };
+            let mut sockets = Vec::with_capacity(extra.len());
+            for socket in &extra {
+                sockets.push(socket.open(&context)?);
+            }
+            let sibling = Sibling {
+                uuid,
+                name,
+                pipe: pipe_endpoint.clone(),
+                endpoints,
+            };
+            let forward = context.socket(zmq::PULL)?;
+            forward.bind(&sibling.forward_endpoint())?;
+            let topology = if topology {
+                let socket = context.socket(zmq::PULL)?;
+                socket.bind(&sibling.topology_endpoint())?;
+                Some(socket)
+            } else {
+                None
+            };
+            // One frame for each bound endpoint, in the same order as the addresses.
+            pipe.send_multipart(&sibling.endpoints, 0)?;
+            nlog!(
+                debug,
+                "actor started pipe={} service={:?} endpoints={:?}",
+                pipe_endpoint,
+                kind,
+                sibling.endpoints
+            );
+            match directory::register(&context, &sibling) {
+                Ok(()) if topology.is_some() => {
+                    if let Err(e) = directory::watch_topology(&context, &sibling) {
+                        nlog!(error, "actor not watching its siblings error={}", e);
+                    }
+                }
+                Ok(()) => {}
+                Err(e) => nlog!(error, "actor not registered with its siblings error={}", e),
+            }
 
-            poll_zmq_actor(pipe, service, &mut mbox, 10)
+            let result = run(Service {
+                pipe,
+                socket: service,
+                forward,
+                topology,
+                kind,
+                sibling: sibling.clone(),
+                context: context.clone(),
+                token,
+                mailbox,
+                sockets,
+                middleware,
+            });
+            if let Err(e) = directory::deregister(&context, &sibling.uuid) {
+                nlog!(
+                    error,
+                    "actor not deregistered from its siblings error={}",
+                    e
+                );
+            }
+            result
         })
     }
 
+    /// Receive the endpoints that the service socket was bound to, as reported over the
+    /// pipe right after `start`. Dynamic addresses, e.g. `tcp://127.0.0.1:*`, are resolved.
+    pub fn recv_endpoints(&self) -> Result<Vec<String>, Error> {
+        let mut endpoints = Vec::new();
+        for frame in self.pipe().recv_multipart(0)? {
+            endpoints.push(String::from_utf8(frame)?);
+        }
+        Ok(endpoints)
+    }
+
     /// Stop the current actorling instance.
     pub fn stop(&self) -> Result<(), zmq::Error> {
         self.pipe().send("$STOP", 0)
     }
 
+    /// Take the oldest message from the inbox of the running actorling, if any.
     pub fn pop(&self) -> Result<Option<Vec<zmq::Message>>, Error> {
-        self.pipe().send("$POP", 0)?;
-        let mut msgs = Vec::<zmq::Message>::new();
-        let msg = self.pipe().recv_msg(0)?;
-        match &*msg {
-            b"$NONE" => Ok(None),
-            _ => {
-                msgs.push(msg);
-                while self.pipe().get_rcvmore()? {
-                    let msg = self.pipe().recv_msg(0)?;
-                    msgs.push(msg)
-                }
-                Ok(Some(msgs))
-            }
-        }
+        let msg = self.client().pop()?;
+        Ok(msg.map(|frames| frames.into_iter().map(zmq::Message::from).collect()))
+    }
+
+    /// Take every message from the inbox of the running actorling, oldest first, in
+    /// batches of up to `DRAIN_BATCH` messages for each round trip over the pipe. The
+    /// iterator ends once the inbox is empty, or the pipe fails, see `Drain::error`.
+    pub fn drain(&self) -> Drain<'_> {
+        self.client().drain(DRAIN_BATCH)
+    }
+
+    /// Send a reply on the service socket, for `ServiceKind::Rep` and `ServiceKind::Router`
+    /// actorlings. Router replies must start with the identity of the requester.
+    pub fn reply<I, T>(&self, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.client().reply(frames)
+    }
+
+    /// Send `frames` on the service socket of a `ServiceKind::Router` actorling, to the
+    /// requester with `identity`.
+    pub fn reply_to<I, T>(&self, identity: &Identity, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.reply(identity.route(frames))
+    }
+
+    /// Ask the running actorling for its `Health`.
+    pub fn health(&self) -> Result<Health, Error> {
+        self.client().health()
+    }
+
+    /// Returns a typed client for the pipe of the running actorling.
+    pub fn client(&self) -> PipeClient<'_> {
+        PipeClient::new(&self.pipe)
     }
 
     /// Returns the actorling's UUID as a `String`
     pub fn uuid(&self) -> String {
         self.uuid.to_simple().to_string()
     }
+
+    /// Returns the identity of the service socket, made of the actorling's UUID, which
+    /// `ROUTER` sockets that connect to the service can route messages to.
+    pub fn identity(&self) -> Identity {
+        Identity::from_uuid(&self.uuid)
+    }
+
+    /// Returns the actorling's name, or its UUID if it was not named.
+    pub fn name(&self) -> String {
+        self.name.clone().unwrap_or_else(|| self.uuid())
+    }
+
+    /// Send `msg` to the running actor with `uuid`, in the same context, through the pipe of
+    /// this running actorling. The message is queued in the inbox of the other actor, like
+    /// the messages of its service socket. Fails with `ActorlingError::InvalidCommand` if
+    /// there is no such actor.
+    pub fn send_to<I, T>(&self, uuid: &str, msg: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.client().forward(uuid, msg)
+    }
+
+    /// Returns the other running actors that share the actorling's context, as registered
+    /// when they start, and until they stop.
+    pub fn siblings(&self) -> Result<Vec<Sibling>, Error> {
+        let uuid = self.uuid();
+        Ok(directory::list(&self.context)?
+            .into_iter()
+            .filter(|sibling| sibling.uuid != uuid)
+            .collect())
+    }
 }
 
+/// Poll the `pipe`, and the `PULL` `service` socket, of an actor, until it exits. Errors of
+/// the loop end it with an `ActorFailure`, so that the inbox is still drained into it.
 pub fn poll_zmq_actor(
     pipe: zmq::Socket,
     service: zmq::Socket,
     mbox: &mut Mailbox,
     timeout: i64,
-) -> Result<(), Error> {
-    let p = PollingSocket::new(pipe);
-    let s = PollingSocket::new(service);
-    let mut pollable = [
+) -> Result<ActorExit, Error> {
+    poll_zmq_actor_with_shutdown(pipe, service, mbox, timeout, &ShutdownToken::default())
+}
+
+/// Same as `poll_zmq_actor`, but the loop also ends when the `token` is signaled.
+pub fn poll_zmq_actor_with_shutdown(
+    pipe: zmq::Socket,
+    service: zmq::Socket,
+    mbox: &mut Mailbox,
+    timeout: i64,
+    token: &ShutdownToken,
+) -> Result<ActorExit, Error> {
+    poll_zmq_service(pipe, service, &ServiceKind::Pull, mbox, timeout, token)
+}
+
+/// Same as `poll_zmq_actor_with_shutdown`, for a service socket of the given `kind`.
+/// Replies requested over the pipe are sent on the service socket, when the `kind` of
+/// service allows them. Messages are kept in the inbox of `mbox`, in the lane of their
+/// `Priority`, and `$POP` takes them from the highest priority first.
+pub fn poll_zmq_service(
+    pipe: zmq::Socket,
+    service: zmq::Socket,
+    kind: &ServiceKind,
+    mbox: &mut Mailbox,
+    timeout: i64,
+    token: &ShutdownToken,
+) -> Result<ActorExit, Error> {
+    let sockets = LoopSockets {
+        pipe,
+        service,
+        forward: None,
+        topology: None,
+        extra: Vec::new(),
+        middleware: Pipeline::new(),
+    };
+    poll_service(sockets, kind, mbox, timeout, token, None)
+}
+
+// What is done with each message taken from the inbox. Returns false to stop the actor.
+type Deliver<'a> = dyn FnMut(&PollingSocket, Vec<Vec<u8>>) -> Result<bool, Error> + 'a;
+
+// Forwards the messages of an actor, by UUID, to the other actors in its context, over
+// PUSH sockets connected to their `Sibling::forward_endpoint`.
+struct Forwarder {
+    context: zmq::Context,
+    peers: HashMap<String, zmq::Socket>,
+}
+
+impl Forwarder {
+    fn new(context: zmq::Context) -> Forwarder {
+        Forwarder {
+            context,
+            peers: HashMap::new(),
+        }
+    }
+
+    // Forward `msg` to the actor with `uuid`. Returns false if it is not running, or can't
+    // take more messages.
+    fn forward(&mut self, uuid: &str, msg: Vec<Vec<u8>>) -> Result<bool, Error> {
+        let sibling = match directory::lookup(&self.context, uuid)? {
+            Some(sibling) => sibling,
+            None => {
+                self.peers.remove(uuid);
+                return Ok(false);
+            }
+        };
+        if !self.peers.contains_key(uuid) {
+            let peer = self.context.socket(zmq::PUSH)?;
+            peer.set_linger(0)?;
+            peer.connect(&sibling.forward_endpoint())?;
+            self.peers.insert(uuid.to_string(), peer);
+        }
+        match self.peers[uuid].send_multipart(msg, zmq::DONTWAIT) {
+            Ok(()) => Ok(true),
+            Err(zmq::Error::EAGAIN) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+// Sockets of the poll loop. Started actors also receive the messages forwarded to them,
+// and forward their own, and poll the sockets added with `Actorling::with_socket`. Their
+// messages go through the `middleware` of the actor on their way to the inbox. Those that
+// watch the directory receive its `TopologyEvent` messages on a socket of their own.
+struct LoopSockets {
+    pipe: zmq::Socket,
+    service: zmq::Socket,
+    forward: Option<(zmq::Socket, Forwarder)>,
+    topology: Option<zmq::Socket>,
+    extra: Vec<OpenSocket>,
+    middleware: Pipeline,
+}
+
+// The poll loop of `poll_zmq_service`. Service, and forwarded, messages, other than health
+// requests, are queued in the inbox, and, with `deliver`, every batch is then drained by
+// priority. Messages of the other sockets go to their handlers. The messages left in the
+// inbox when the loop ends are drained into the `ActorExit`, or the `ActorFailure`.
+fn poll_service(
+    sockets: LoopSockets,
+    kind: &ServiceKind,
+    mbox: &mut Mailbox,
+    timeout: i64,
+    token: &ShutdownToken,
+    mut deliver: Option<&mut Deliver>,
+) -> Result<ActorExit, Error> {
+    let started = Clock::new();
+    let mut last_error = None;
+    let p = PollingSocket::with_stats(sockets.pipe);
+    let s = PollingSocket::with_stats(sockets.service);
+    let (inbox, mut forwarder) = match sockets.forward {
+        Some((inbox, forwarder)) => (Some(inbox), Some(forwarder)),
+        None => (None, None),
+    };
+    let mut pollable = vec![
         p.get_socket_ref().as_poll_item(zmq::POLLIN),
         s.get_socket_ref().as_poll_item(zmq::POLLIN),
     ];
+    if let Some(ref inbox) = inbox {
+        pollable.push(inbox.as_poll_item(zmq::POLLIN));
+    }
+    let topology_item = pollable.len();
+    if let Some(ref topology) = sockets.topology {
+        pollable.push(topology.as_poll_item(zmq::POLLIN));
+    }
+    let first_extra = pollable.len();
+    for open in &sockets.extra {
+        pollable.push(open.socket().as_poll_item(zmq::POLLIN));
+    }
 
-    let mut msg = zmq::Message::new();
-
-    loop {
-        zmq::poll(&mut pollable, timeout)?;
+    let reason = 'poll: loop {
+        if token.is_shutdown() {
+            nlog!(debug, "actor shut down by token");
+            break Ok(ExitReason::Shutdown);
+        }
+        match zmq::poll(&mut pollable, timeout) {
+            Ok(_) => {}
+            Err(zmq::Error::ETERM) => break Ok(ExitReason::PipeClosed),
+            Err(e) => break Err(e.into()),
+        }
         if pollable[0].is_readable() {
-            if let Err(e) = p.recv(&mut msg, 0) {
-                match e.kind() {
+            let frames = match p.recv_multipart(0) {
+                Ok(frames) => frames,
+                Err(e) => match e.kind() {
                     io::ErrorKind::WouldBlock => continue,
-                    _ => bail!("actor pipe could not be read"),
-                }
+                    _ => {
+                        nlog!(debug, "actor pipe could not be read error={}", e);
+                        break Ok(ExitReason::PipeClosed);
+                    }
+                },
             };
 
-            let cmd = parse_pipe_command(&*msg)?;
-            println!("command: {:?}", cmd);
+            let cmd = match parse_pipe_command(frames) {
+                Ok(cmd) => cmd,
+                Err(e) => break Err(e),
+            };
+            nlog!(trace, "pipe command cmd={:?}", cmd);
+            let _span = nspan!("pipe_command", "cmd={:?}", cmd);
 
-            if let Err(e) = execute_command(p.get_socket_ref(), &cmd) {
+            let executed = match cmd {
+                PipeCommand::Health => {
+                    let health = health(&started, mbox, &p, &s, &last_error);
+                    send_health(p.get_socket_ref(), &health)
+                }
+                PipeCommand::Pop => pop_inbox(p.get_socket_ref(), mbox),
+                PipeCommand::PopN(count) => pop_inbox_n(p.get_socket_ref(), mbox, count),
+                PipeCommand::Reply(reply) => send_reply(p.get_socket_ref(), &s, kind, reply),
+                PipeCommand::Forward(uuid, msg) => {
+                    forward_message(p.get_socket_ref(), forwarder.as_mut(), &uuid, msg)
+                }
+                _ => execute_command(p.get_socket_ref(), cmd),
+            };
+            if let Err(e) = executed {
                 match e {
-                    ActorlingError::Interrupted => break,
-                    ActorlingError::InvalidCommand => continue,
-                    _ => bail!(e),
+                    ActorlingError::Interrupted => {
+                        nlog!(debug, "actor stopping");
+                        break Ok(ExitReason::Stopped);
+                    }
+                    ActorlingError::InvalidCommand => {
+                        last_error = Some(e.to_string());
+                        continue;
+                    }
+                    _ => {
+                        nlog!(error, "pipe command failed error={}", e);
+                        break Err(e.into());
+                    }
                 }
             };
         }
         if pollable[1].is_readable() {
-            loop {
-                match s.recv_multipart(0) {
-                    Ok(msg) => mbox.inbox.push_back(msg),
-                    Err(e) => match e.kind() {
-                        io::ErrorKind::WouldBlock => break,
-                        _ => bail!("actor service could not be read"),
-                    },
+            let mut span = nspan!("service_batch");
+            match s.recv_batch(SERVICE_BATCH, 0) {
+                Ok(batch) => {
+                    nlog!(trace, "service messages count={}", batch.len());
+                    span.record(format_args!("count={}", batch.len()));
+                    for msg in batch {
+                        match health_request(kind, &msg) {
+                            Some(envelope) => {
+                                let health = health(&started, mbox, &p, &s, &last_error);
+                                if let Err(e) = reply_health(&s, envelope, &health) {
+                                    nlog!(warn, "health reply failed error={}", e);
+                                    last_error = Some(e.to_string());
+                                }
+                            }
+                            None => {
+                                let envelope = envelope_len(kind, &msg);
+                                queue(&sockets.middleware, mbox, msg, envelope, &mut last_error)
+                            }
+                        }
+                    }
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::WouldBlock => continue,
+                    _ => break Err(format_err!("actor service could not be read")),
+                },
+            }
+        }
+        if let Some(ref inbox) = inbox {
+            if pollable[2].is_readable() {
+                for _ in 0..SERVICE_BATCH {
+                    match inbox.recv_multipart(zmq::DONTWAIT) {
+                        Ok(msg) => queue(&sockets.middleware, mbox, msg, 0, &mut last_error),
+                        Err(zmq::Error::EAGAIN) => break,
+                        Err(e) => break 'poll Err(e.into()),
+                    }
+                }
+            }
+        }
+        if let Some(ref topology) = sockets.topology {
+            if pollable[topology_item].is_readable() {
+                for _ in 0..SERVICE_BATCH {
+                    match topology.recv_multipart(zmq::DONTWAIT) {
+                        Ok(msg) => mbox.push(msg),
+                        Err(zmq::Error::EAGAIN) => break,
+                        Err(e) => break 'poll Err(e.into()),
+                    }
+                }
+            }
+        }
+        for (i, open) in sockets.extra.iter().enumerate() {
+            if !pollable[first_extra + i].is_readable() {
+                continue;
+            }
+            match open.dispatch(SERVICE_BATCH) {
+                Ok(errors) => {
+                    for e in errors {
+                        nlog!(
+                            warn,
+                            "message not handled socket={} error={}",
+                            open.name(),
+                            e
+                        );
+                        last_error = Some(e.to_string());
+                    }
                 }
+                Err(e) => break 'poll Err(e.into()),
             }
         }
+        let deliver = match deliver {
+            Some(ref mut deliver) => deliver,
+            None => continue,
+        };
+        while let Some(msg) = mbox.pop() {
+            match deliver(&s, msg) {
+                Ok(true) => {}
+                Ok(false) => {
+                    nlog!(debug, "actor stopping");
+                    break 'poll Ok(ExitReason::Finished);
+                }
+                Err(e) => {
+                    nlog!(warn, "message not handled error={}", e);
+                    last_error = Some(e.to_string());
+                }
+            }
+        }
+    };
+    let mut drained_mailbox = Vec::new();
+    while let Some(msg) = mbox.pop() {
+        drained_mailbox.push(msg);
     }
-    Ok(())
+    match reason {
+        Ok(reason) => Ok(ActorExit {
+            reason,
+            drained_mailbox,
+        }),
+        Err(error) => Err(ActorFailure {
+            error,
+            drained_mailbox,
+        }
+        .into()),
+    }
+}
+
+// Queue `msg` in the inbox, once it has been through the `middleware` of the actor. Its
+// priority tag follows its `envelope` of routing frames. Messages that look like a
+// `TopologyEvent` are refused, as only the directory sends those.
+fn queue(
+    middleware: &Pipeline,
+    mbox: &mut Mailbox,
+    msg: Vec<Vec<u8>>,
+    envelope: usize,
+    last_error: &mut Option<String>,
+) {
+    let mut msg = if middleware.is_empty() {
+        msg
+    } else {
+        match middleware.run(msg) {
+            Ok(Some(msg)) => msg,
+            Ok(None) => {
+                nlog!(trace, "message dropped by middleware");
+                return;
+            }
+            Err(e) => {
+                nlog!(warn, "message failed middleware error={}", e);
+                *last_error = Some(e.to_string());
+                return;
+            }
+        }
+    };
+    mailbox::lift_tag(&mut msg, envelope);
+    if directory::is_topology_event(&msg) {
+        nlog!(warn, "topology event refused, not sent by the directory");
+        *last_error = Some("topology event not sent by the directory".to_string());
+        return;
+    }
+    mbox.push(msg)
 }
 
 #[derive(Debug, PartialEq)]
 enum PipeCommand {
+    Health,
     Interrupt,
     Invalid,
-    Send(&'static str),
+    Forward(String, Vec<Vec<u8>>),
+    Ping(Vec<Vec<u8>>),
+    Pop,
+    PopN(usize),
+    Reply(Vec<Vec<u8>>),
 }
 
-fn parse_pipe_command(msg: &[u8]) -> Result<PipeCommand, Error> {
-    let cmd = match msg {
-        b"$PING" => PipeCommand::Send("$PONG"),
-        b"$STOP" => PipeCommand::Interrupt,
+// Parse a command, in its first frame, with its arguments, in the rest.
+fn parse_pipe_command(mut frames: Vec<Vec<u8>>) -> Result<PipeCommand, Error> {
+    if frames.is_empty() {
+        return Ok(PipeCommand::Invalid);
+    }
+    let mut args = frames.split_off(1);
+    if frames[0] == FORWARD.as_bytes() && args.len() > 1 {
+        let msg = args.split_off(1);
+        return Ok(match String::from_utf8(args.remove(0)) {
+            Ok(uuid) => PipeCommand::Forward(uuid, msg),
+            Err(_) => PipeCommand::Invalid,
+        });
+    }
+    let cmd = match (&frames[0][..], args.is_empty()) {
+        (b"$HEALTH", true) => PipeCommand::Health,
+        (b"$PING", _) => PipeCommand::Ping(args),
+        (b"$POP", true) => PipeCommand::Pop,
+        (b"$POPN", false) => match pop_count(&args) {
+            Some(count) => PipeCommand::PopN(count),
+            None => PipeCommand::Invalid,
+        },
+        (b"$REPLY", _) => PipeCommand::Reply(args),
+        (b"$STOP", true) => PipeCommand::Interrupt,
         _ => PipeCommand::Invalid,
     };
     Ok(cmd)
 }
 
-fn execute_command(pipe: &zmq::Socket, cmd: &PipeCommand) -> Result<(), ActorlingError> {
-    match *cmd {
-        PipeCommand::Send(message) => pipe.send(message, 0).map_err(ActorlingError::SocketSend)?,
-        PipeCommand::Interrupt => {
-            pipe.send("$STOPPING", 0)
-                .map_err(ActorlingError::SocketSend)?;
-            return Err(ActorlingError::Interrupted);
+// Send a reply over the pipe, starting with its `status`.
+fn send_status(
+    pipe: &zmq::Socket,
+    status: PipeStatus,
+    frames: Vec<Vec<u8>>,
+) -> Result<(), ActorlingError> {
+    let mut reply = vec![status.as_str().as_bytes().to_vec()];
+    reply.extend(frames);
+    pipe.send_multipart(reply, 0)
+        .map_err(ActorlingError::SocketSend)
+}
+
+// Take a snapshot of the health of a running actor.
+fn health(
+    started: &Clock,
+    mbox: &Mailbox,
+    pipe: &PollingSocket,
+    service: &PollingSocket,
+    last_error: &Option<String>,
+) -> Health {
+    Health {
+        uptime: started.mono(),
+        inbox: mbox.inbox.len(),
+        outbox: mbox.outbox.len(),
+        last_error: last_error.clone(),
+        pipe: pipe.stats().unwrap_or_default(),
+        service: service.stats().unwrap_or_default(),
+    }
+}
+
+// Send the serialized `health` over the pipe.
+fn send_health(pipe: &zmq::Socket, health: &Health) -> Result<(), ActorlingError> {
+    match health.to_bytes() {
+        Ok(bytes) => send_status(pipe, PipeStatus::Ok, vec![bytes]),
+        Err(_) => {
+            send_status(pipe, PipeStatus::WontDo, vec![])?;
+            Err(ActorlingError::InvalidCommand)
         }
-        PipeCommand::Invalid => {
-            pipe.send("$WONTDO", 0)
-                .map_err(ActorlingError::SocketSend)?;
-            return Err(ActorlingError::InvalidCommand);
+    }
+}
+
+// Returns the envelope of a `$HEALTH` request received on the service socket, i.e. the
+// frames before the command, if the service replies to requests.
+fn health_request<'a>(kind: &ServiceKind, msg: &'a [Vec<u8>]) -> Option<&'a [Vec<u8>]> {
+    let (command, envelope) = msg.split_last()?;
+    let is_request = match *kind {
+        ServiceKind::Rep => envelope.is_empty(),
+        // the identity of the requester, and the empty delimiter of `REQ` clients.
+        ServiceKind::Router => {
+            !envelope.is_empty() && envelope[1..].iter().all(|frame| frame.is_empty())
+        }
+        _ => false,
+    };
+    if is_request && command == HEALTH.as_bytes() {
+        Some(envelope)
+    } else {
+        None
+    }
+}
+
+// Returns the number of routing frames of `msg`, received on a service of `kind`: the
+// identity of the sender on `ROUTER` services, and the empty delimiter of `REQ` clients.
+fn envelope_len(kind: &ServiceKind, msg: &[Vec<u8>]) -> usize {
+    match *kind {
+        ServiceKind::Router if !msg.is_empty() => {
+            1 + msg[1..].iter().take_while(|frame| frame.is_empty()).count()
         }
+        _ => 0,
     }
+}
+
+// Answer a `$HEALTH` request on the service socket.
+fn reply_health(
+    service: &PollingSocket,
+    envelope: &[Vec<u8>],
+    health: &Health,
+) -> Result<(), Error> {
+    let mut reply = envelope.to_vec();
+    reply.push(health.to_bytes()?);
+    service.send_multipart(reply, 0)?;
     Ok(())
 }
 
+// Forward `msg` to the actor with `uuid`, answering `$OK` on the pipe, or `$WONTDO` if it
+// is not running, or if the actor does not forward messages.
+fn forward_message(
+    pipe: &zmq::Socket,
+    forwarder: Option<&mut Forwarder>,
+    uuid: &str,
+    msg: Vec<Vec<u8>>,
+) -> Result<(), ActorlingError> {
+    let forwarded = match forwarder {
+        Some(forwarder) => forwarder.forward(uuid, msg).unwrap_or_else(|e| {
+            nlog!(warn, "message not forwarded uuid={} error={}", uuid, e);
+            false
+        }),
+        None => false,
+    };
+    if forwarded {
+        return send_status(pipe, PipeStatus::Ok, vec![]);
+    }
+    send_status(pipe, PipeStatus::WontDo, vec![])?;
+    Err(ActorlingError::InvalidCommand)
+}
+
+// Send the oldest message in the inbox over the pipe, or `$NONE` if it is empty.
+fn pop_inbox(pipe: &zmq::Socket, mbox: &mut Mailbox) -> Result<(), ActorlingError> {
+    match mbox.pop() {
+        Some(msg) => send_status(pipe, PipeStatus::Ok, msg),
+        None => send_status(pipe, PipeStatus::None, vec![]),
+    }
+}
+
+// Parse the argument of `$POPN`, a positive count in decimal.
+fn pop_count(args: &[Vec<u8>]) -> Option<usize> {
+    if args.len() != 1 {
+        return None;
+    }
+    match ::std::str::from_utf8(&args[0]).ok()?.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(count) => Some(count),
+    }
+}
+
+// Take up to `count` messages from the inbox, answering `$OK` with a packed frame for each,
+// or `$NONE` if it is empty. A message that can't be packed is left in the inbox, for `$POP`,
+// and ends the frames taken so far, or is answered `$WONTDO` if it is the first one.
+fn pop_inbox_n(pipe: &zmq::Socket, mbox: &mut Mailbox, count: usize) -> Result<(), ActorlingError> {
+    let mut frames = Vec::new();
+    while frames.len() < count {
+        let packed = match mbox.peek() {
+            Some(msg) => pack(msg),
+            None => break,
+        };
+        match packed {
+            Ok(frame) => {
+                mbox.pop();
+                frames.push(frame);
+            }
+            Err(e) => {
+                nlog!(warn, "message too large to pop error={}", e);
+                if !frames.is_empty() {
+                    break;
+                }
+                send_status(pipe, PipeStatus::WontDo, vec![])?;
+                return Err(ActorlingError::InvalidCommand);
+            }
+        }
+    }
+    if frames.is_empty() {
+        return send_status(pipe, PipeStatus::None, vec![]);
+    }
+    send_status(pipe, PipeStatus::Ok, frames)
+}
+
+// Send a reply on the service socket, answering `$OK` on the pipe, or `$WONTDO` if the
+// service does not reply, or is not ready to.
+fn send_reply(
+    pipe: &zmq::Socket,
+    service: &PollingSocket,
+    kind: &ServiceKind,
+    reply: Vec<Vec<u8>>,
+) -> Result<(), ActorlingError> {
+    if kind.replies() && !reply.is_empty() && service.send_multipart(reply, 0).is_ok() {
+        return send_status(pipe, PipeStatus::Ok, vec![]);
+    }
+    send_status(pipe, PipeStatus::WontDo, vec![])?;
+    Err(ActorlingError::InvalidCommand)
+}
+
+fn execute_command(pipe: &zmq::Socket, cmd: PipeCommand) -> Result<(), ActorlingError> {
+    match cmd {
+        PipeCommand::Ping(args) => send_status(pipe, PipeStatus::Pong, args),
+        PipeCommand::Interrupt => {
+            send_status(pipe, PipeStatus::Stopping, vec![])?;
+            Err(ActorlingError::Interrupted)
+        }
+        _ => {
+            send_status(pipe, PipeStatus::WontDo, vec![])?;
+            Err(ActorlingError::InvalidCommand)
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -270,10 +1515,740 @@ mod tests {
         assert!(handle.join().is_ok());
     }
 
+    #[test]
+    fn actorlings_exit_with_their_reason_and_inbox() {
+        let acty = Actorling::new("inproc://exit_report").unwrap();
+        let handle = acty.start().unwrap();
+        acty.recv_endpoints().unwrap();
+        let push = acty.context().socket(zmq::PUSH).unwrap();
+        push.connect("inproc://exit_report").unwrap();
+        push.send_multipart(vec!["first"], 0).unwrap();
+        push.send_multipart(vec!["second"], 0).unwrap();
+        while acty.health().unwrap().inbox < 2 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        acty.stop().unwrap();
+        let exit = handle.join().unwrap().unwrap();
+        match exit.reason {
+            ExitReason::Stopped => {}
+            ref other => panic!("unexpected reason: {:?}", other),
+        }
+        assert!(exit.is_clean());
+        assert_eq!(
+            exit.drained_mailbox,
+            vec![vec![b"first".to_vec()], vec![b"second".to_vec()]]
+        );
+    }
+
+    #[test]
+    fn actorlings_poll_their_other_sockets_with_their_handlers() {
+        use std::sync::mpsc;
+
+        let ctx = zmq::Context::new();
+        let publisher = ctx.socket(zmq::PUB).unwrap();
+        publisher.bind("inproc://actor_broadcasts").unwrap();
+        let (tx, rx) = mpsc::channel();
+        let broadcasts = ActorSocket::new("broadcasts", zmq::SUB, move |_, msg| {
+            tx.send(msg)?;
+            Ok(())
+        })
+        .connect("inproc://actor_broadcasts")
+        .unwrap()
+        .subscribe(b"");
+        let requests = ActorSocket::new("requests", zmq::ROUTER, |socket, mut msg| {
+            msg.truncate(1);
+            msg.push(b"ack".to_vec());
+            socket.send_multipart(msg, 0)?;
+            Ok(())
+        })
+        .bind("inproc://actor_requests")
+        .unwrap();
+        let acty = Actorling::new_with_context("inproc://actor_with_sockets", ctx.clone())
+            .unwrap()
+            .with_socket(broadcasts)
+            .with_socket(requests);
+        assert_eq!(acty.sockets().len(), 2);
+        let handle = acty.start().unwrap();
+        acty.recv_endpoints().unwrap();
+
+        let dealer = ctx.socket(zmq::DEALER).unwrap();
+        dealer.connect("inproc://actor_requests").unwrap();
+        dealer.send("hello", 0).unwrap();
+        assert_eq!(dealer.recv_bytes(0).unwrap(), b"ack");
+        // SUB sockets join late, so broadcast until one arrives.
+        let received = loop {
+            publisher.send("news", 0).unwrap();
+            if let Ok(msg) = rx.recv_timeout(Duration::from_millis(10)) {
+                break msg;
+            }
+        };
+        assert_eq!(received, vec![b"news".to_vec()]);
+
+        acty.stop().unwrap();
+        let exit = handle.join().unwrap().unwrap();
+        assert!(exit.is_clean());
+        assert!(exit.drained_mailbox.is_empty());
+    }
+
+    #[test]
+    fn spooled_inboxes_survive_crashes() {
+        let path = ::std::env::temp_dir().join(format!("neuras-spool-{}", Uuid::new_v4()));
+        let mut config = SpoolConfig::new(&path);
+        config.max_messages = 3;
+        config.sync = false;
+        {
+            // an actor that crashes before taking the rest of its inbox.
+            let mut mbox = Mailbox::with_spool(0, &config).unwrap();
+            mbox.push(vec![b"lost?".to_vec()]);
+            mbox.push(Priority::High.tagged(vec!["urgent"]));
+            mbox.push(vec![b"kept".to_vec()]);
+            mbox.push(vec![b"not spooled".to_vec()]);
+            assert_eq!(mbox.spool().unwrap().len(), 3);
+            assert_eq!(mbox.pop(), Some(vec![b"urgent".to_vec()]));
+        }
+
+        let acty = Actorling::new("inproc://spooled")
+            .unwrap()
+            .with_spool(config.clone());
+        let handle = acty.start().unwrap();
+        acty.recv_endpoints().unwrap();
+        let drained: Vec<Multipart> = acty.drain().collect();
+        assert_eq!(
+            drained,
+            vec![vec![b"lost?".to_vec()], vec![b"kept".to_vec()]]
+        );
+        acty.stop().unwrap();
+        assert!(handle.join().unwrap().unwrap().is_clean());
+        assert!(Mailbox::with_spool(0, &config).unwrap().pop().is_none());
+        ::std::fs::remove_file(&path).unwrap();
+    }
+
     #[test]
     fn actorlings_return_ok_if_stopped_when_not_running() {
         let acty = Actorling::new("inproc://my_actorling").unwrap();
         let stop = acty.stop();
         assert!(stop.is_ok());
     }
+
+    // Start an actorling with the given service, returning its bound endpoint.
+    fn start_service(acty: &Actorling) -> String {
+        acty.start().unwrap();
+        acty.recv_endpoints().unwrap().remove(0)
+    }
+
+    // Pop the next message from the actorling, waiting for it to arrive.
+    fn pop_next(acty: &Actorling) -> Vec<zmq::Message> {
+        for _ in 0..100 {
+            if let Some(msg) = acty.pop().unwrap() {
+                return msg;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no message arrived");
+    }
+
+    #[test]
+    fn actorlings_listen_with_pull_sockets_by_default() {
+        let acty = Actorling::new("inproc://service_default").unwrap();
+        assert_eq!(acty.service_kind(), &ServiceKind::Pull);
+        let endpoint = start_service(&acty);
+        let push = acty.context().socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        push.send("pushed", 0).unwrap();
+        assert_eq!(pop_next(&acty)[0].as_str(), Some("pushed"));
+        assert!(acty.reply(vec!["nope"]).is_err());
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn control_messages_do_not_queue_behind_bulk_data() {
+        let acty = Actorling::new("inproc://service_priorities")
+            .unwrap()
+            .with_starvation_limit(0);
+        let endpoint = start_service(&acty);
+        let push = acty.context().socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        for n in 0..3 {
+            push.send(&format!("bulk{}", n), 0).unwrap();
+        }
+        push.send_multipart(Priority::High.tagged(vec!["high"]), 0)
+            .unwrap();
+        push.send_multipart(Priority::Control.tagged(vec!["control"]), 0)
+            .unwrap();
+        while acty.health().unwrap().inbox < 5 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let order: Vec<String> = (0..5)
+            .map(|_| pop_next(&acty)[0].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(order, vec!["control", "high", "bulk0", "bulk1", "bulk2"]);
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn actorlings_have_unique_pipe_endpoints() {
+        let acty = Actorling::new("inproc://pipe_unique").unwrap();
+        assert!(acty.pipe_endpoint().ends_with(&acty.uuid()));
+        assert!(acty.pipe_endpoint().starts_with(PIPE_PREFIX));
+    }
+
+    #[test]
+    fn actorlings_check_their_addresses_before_starting() {
+        let check = EndpointCheck::resolving();
+        let acty = Actorling::new("tcp://localhost:*").unwrap();
+        assert!(acty.with_endpoint_check(&check).is_ok());
+        let acty = Actorling::new("tcp://no-such-host.invalid:*").unwrap();
+        assert!(acty.with_endpoint_check(&check).is_err());
+    }
+
+    #[test]
+    fn actorlings_sharing_a_context_do_not_collide() {
+        let ctx = zmq::Context::new();
+        let actors: Vec<Actorling> = (0..2)
+            .map(|i| Actorling::new_with_context(format!("inproc://pipe_{}", i), ctx.clone()))
+            .collect::<Result<_, _>>()
+            .unwrap();
+        for acty in &actors {
+            start_service(acty);
+            acty.pipe().send("$PING", 0).unwrap();
+            assert_eq!(acty.pipe().recv_string(0).unwrap().unwrap(), "$PONG");
+        }
+        for acty in &actors {
+            acty.stop().unwrap();
+        }
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn actorlings_can_use_the_legacy_pipe_endpoint() {
+        let acty = Actorling::new("inproc://pipe_legacy")
+            .unwrap()
+            .with_pipe_endpoint(PIPE_ADDR)
+            .unwrap();
+        assert_eq!(acty.pipe_endpoint(), PIPE_ADDR);
+        start_service(&acty);
+        acty.pipe().send("$PING", 0).unwrap();
+        assert_eq!(acty.pipe().recv_string(0).unwrap().unwrap(), "$PONG");
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn actorlings_bind_and_report_every_address() {
+        let mut acty = Actorling::new("inproc://service_many").unwrap();
+        acty.add_address("tcp://127.0.0.1:*").unwrap();
+        assert_eq!(acty.address(), "inproc://service_many");
+        assert_eq!(acty.addresses().len(), 2);
+        acty.start().unwrap();
+        let endpoints = acty.recv_endpoints().unwrap();
+        assert_eq!(endpoints.len(), 2);
+        assert_eq!(endpoints[0], "inproc://service_many");
+        assert!(endpoints[1].starts_with("tcp://127.0.0.1:"));
+        assert!(!endpoints[1].ends_with('*'));
+
+        let push = acty.context().socket(zmq::PUSH).unwrap();
+        push.connect(&endpoints[1]).unwrap();
+        push.send("remote", 0).unwrap();
+        assert_eq!(pop_next(&acty)[0].as_str(), Some("remote"));
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn actorlings_are_not_created_with_invalid_addresses() {
+        assert!(Actorling::new("localhost:5555").is_err());
+        let mut acty = Actorling::default();
+        assert!(acty.address().starts_with("inproc://neuras.actor."));
+        assert!(acty.add_address("tcp://127.0.0.1").is_err());
+    }
+
+    #[test]
+    fn secure_actorlings_only_accept_allowed_certificates() {
+        use super::super::security::{CipherSender, Handshake, HandshakeFailure};
+
+        if !zmq::has("curve").unwrap_or(false) {
+            return;
+        }
+        let ctx = zmq::Context::new();
+        let allowed = KeysCertificate::new().unwrap();
+        let mut auth = Authenticator::new(&ctx).unwrap();
+        auth.allow_certificate(&allowed.public_only()).unwrap();
+        let handle = auth.start().unwrap();
+
+        let server_cert = KeysCertificate::new().unwrap();
+        let acty = Actorling::new_secure("tcp://127.0.0.1:*", &server_cert, &auth)
+            .unwrap()
+            .with_handshake_events()
+            .unwrap();
+        assert!(acty.is_secure());
+        let endpoint = start_service(&acty);
+
+        let server = server_cert.public_only();
+        let denied = KeysCertificate::new().unwrap();
+        let intruder = CipherSender::new(&ctx, zmq::PUSH, &server, &denied)
+            .unwrap()
+            .monitor_handshakes()
+            .unwrap();
+        intruder.get_ref().set_linger(0).unwrap();
+        intruder.connect(endpoint.as_str()).unwrap();
+        intruder.get_ref().send("intruder", 0).unwrap();
+        let timeout = Duration::from_secs(5);
+        match intruder.handshake_result(timeout).unwrap() {
+            Handshake::Failed { reason, .. } => assert_eq!(reason, HandshakeFailure::Auth(400)),
+            other => panic!("unexpected handshake {:?}", other),
+        }
+        match acty.handshake_result(timeout).unwrap() {
+            Handshake::Failed { reason, .. } => assert_eq!(reason, HandshakeFailure::Auth(400)),
+            other => panic!("unexpected handshake {:?}", other),
+        }
+        drop(intruder);
+
+        let client = CipherSender::new(&ctx, zmq::PUSH, &server, &allowed)
+            .unwrap()
+            .monitor_handshakes()
+            .unwrap();
+        client.connect(endpoint.as_str()).unwrap();
+        assert!(client.handshake_result(timeout).unwrap().is_succeeded());
+        client.get_ref().send("allowed", 0).unwrap();
+        let msg = pop_next(&acty);
+        assert_eq!(msg[0].as_str(), Some("allowed"));
+        assert!(acty.handshake_result(timeout).unwrap().is_succeeded());
+        // the handshake of the intruder failed, so its message never reached the actor.
+        assert!(acty.pop().unwrap().is_none());
+
+        acty.stop().unwrap();
+        auth.stop().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn secure_actorlings_do_not_start_without_a_zap_handler() {
+        if !zmq::has("curve").unwrap_or(false) {
+            return;
+        }
+        let ctx = zmq::Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        let server_cert = KeysCertificate::new().unwrap();
+        let acty = Actorling::new_secure("tcp://127.0.0.1:*", &server_cert, &auth).unwrap();
+        let err = acty.start().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let handle = auth.start().unwrap();
+        assert!(has_zap_handler(&ctx).unwrap());
+        auth.stop().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn secure_actorlings_need_a_secret_key() {
+        let ctx = zmq::Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        let public = KeysCertificate::new().unwrap().public_only();
+        assert!(Actorling::new_secure("tcp://127.0.0.1:*", &public, &auth).is_err());
+    }
+
+    #[test]
+    fn actorlings_do_not_start_with_unsupported_transports() {
+        let acty = Actorling::new("norm://239.192.1.1:5555").unwrap();
+        if !Transport::Norm.is_supported() {
+            let err = acty.start().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn rep_actorlings_answer_requests() {
+        let ctx = zmq::Context::new();
+        let acty =
+            Actorling::new_with_service("inproc://service_rep", ctx.clone(), ServiceKind::Rep)
+                .unwrap();
+        let endpoint = start_service(&acty);
+        let req = ctx.socket(zmq::REQ).unwrap();
+        req.connect(&endpoint).unwrap();
+        req.send("hello", 0).unwrap();
+        assert_eq!(pop_next(&acty)[0].as_str(), Some("hello"));
+        acty.reply(vec!["world"]).unwrap();
+        assert_eq!(req.recv_string(0).unwrap().unwrap(), "world");
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn router_actorlings_reply_to_the_requester_identity() {
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_service(
+            "inproc://service_router",
+            ctx.clone(),
+            ServiceKind::Router,
+        )
+        .unwrap();
+        let endpoint = start_service(&acty);
+        let dealer = ctx.socket(zmq::DEALER).unwrap();
+        dealer.set_identity(b"dealer").unwrap();
+        dealer.connect(&endpoint).unwrap();
+        dealer.send("hello", 0).unwrap();
+        let msg = pop_next(&acty);
+        assert_eq!(&*msg[0], b"dealer");
+        acty.reply(vec![msg[0].to_vec(), b"world".to_vec()])
+            .unwrap();
+        assert_eq!(dealer.recv_string(0).unwrap().unwrap(), "world");
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn router_actorlings_are_routed_to_by_their_identity() {
+        use testkit::wait_until;
+
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_service(
+            "tcp://127.0.0.1:*",
+            ctx.clone(),
+            ServiceKind::Router,
+        )
+        .unwrap();
+        let endpoint = start_service(&acty);
+        let client = ctx.socket(zmq::ROUTER).unwrap();
+        let identity = Identity::named("client").unwrap();
+        identity.apply(&client).unwrap();
+        client.set_router_mandatory(true).unwrap();
+        client.connect(&endpoint).unwrap();
+        // the service is unreachable until the connection is made.
+        let service = acty.identity();
+        assert!(wait_until(2_000, || client
+            .send_multipart(service.route(vec!["hello"]), 0)
+            .is_ok()));
+
+        let msg = pop_next(&acty);
+        let requester = Identity::from_envelope(&msg).unwrap();
+        assert_eq!(requester, identity);
+        acty.reply_to(&requester, vec!["world"]).unwrap();
+        let reply = client.recv_multipart(0).unwrap();
+        assert_eq!(Identity::from_envelope(&reply).unwrap(), service);
+        assert_eq!(reply[1], b"world");
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn sub_actorlings_only_receive_their_topics() {
+        let ctx = zmq::Context::new();
+        let kind = ServiceKind::Sub {
+            topics: vec![b"news".to_vec()],
+        };
+        let acty = Actorling::new_with_service("inproc://service_sub", ctx.clone(), kind).unwrap();
+        let endpoint = start_service(&acty);
+        let publisher = ctx.socket(zmq::PUB).unwrap();
+        publisher.connect(&endpoint).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        publisher.send("weather: sunny", 0).unwrap();
+        publisher.send("news: hello", 0).unwrap();
+        assert_eq!(pop_next(&acty)[0].as_str(), Some("news: hello"));
+        assert_eq!(acty.pop().unwrap(), None);
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn actorlings_report_their_health() {
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_context("inproc://service_health", ctx.clone()).unwrap();
+        let endpoint = start_service(&acty);
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        push.send("queued", 0).unwrap();
+        pop_next(&acty);
+        push.send("waiting", 0).unwrap();
+        thread::sleep(Duration::from_millis(50));
+        acty.pipe().send("$UNKNOWN", 0).unwrap();
+        acty.pipe().recv_msg(0).unwrap();
+
+        let health = acty.health().unwrap();
+        assert_eq!(health.inbox, 1);
+        assert_eq!(health.service.messages_received, 2);
+        assert_eq!(health.last_error, Some("invalid command".to_string()));
+        assert!(health.uptime >= 50);
+        assert_eq!(
+            Health::from_bytes(&health.to_bytes().unwrap()).unwrap(),
+            health
+        );
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn rep_actorlings_answer_health_requests() {
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_service(
+            "inproc://service_rep_health",
+            ctx.clone(),
+            ServiceKind::Rep,
+        )
+        .unwrap();
+        let endpoint = start_service(&acty);
+        let req = ctx.socket(zmq::REQ).unwrap();
+        req.connect(&endpoint).unwrap();
+        req.send(HEALTH, 0).unwrap();
+        let health = Health::from_bytes(&req.recv_bytes(0).unwrap()).unwrap();
+        assert_eq!(health.inbox, 0);
+        assert_eq!(acty.pop().unwrap(), None);
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn actorlings_drain_their_inbox_in_batches() {
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_context("inproc://drained", ctx.clone()).unwrap();
+        let endpoint = start_service(&acty);
+        let mut client = acty.client();
+        client.set_timeout(1_000);
+        assert!(client.pop_n(3).unwrap().is_empty());
+        for count in &["0", "-1", "many"] {
+            assert_eq!(
+                client.request(POPN, vec![*count]).unwrap().status,
+                PipeStatus::WontDo
+            );
+        }
+
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        for i in 0..5 {
+            push.send_multipart(vec![i.to_string().into_bytes(), vec![]], 0)
+                .unwrap();
+        }
+        while acty.health().unwrap().inbox < 5 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let mut drain = client.drain(2);
+        let drained: Vec<Multipart> = drain.by_ref().collect();
+        assert!(drain.error().is_none());
+        let expected: Vec<Multipart> = (0..5)
+            .map(|i| vec![i.to_string().into_bytes(), vec![]])
+            .collect();
+        assert_eq!(drained, expected);
+        assert_eq!(acty.drain().count(), 0);
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn actorlings_run_their_messages_through_middlewares() {
+        use pipeline::{from_fn, Multipart, Next};
+
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_context("inproc://middlewares", ctx.clone())
+            .unwrap()
+            .with_middleware(from_fn(|msg: Multipart, next: Next| match &msg[0][..] {
+                b"denied" => Ok(None),
+                b"broken" => Err(format_err!("broken message")),
+                _ => next.run(vec![msg[0].to_ascii_uppercase()]),
+            }));
+        let endpoint = start_service(&acty);
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        for msg in &["denied", "broken", "allowed"] {
+            push.send(*msg, 0).unwrap();
+        }
+        assert_eq!(pop_next(&acty)[0].to_vec(), b"ALLOWED");
+        let health = acty.health().unwrap();
+        assert_eq!(health.inbox, 0);
+        assert_eq!(health.last_error, Some("broken message".to_string()));
+        acty.stop().unwrap();
+    }
+
+    #[test]
+    fn pipe_clients_send_commands_with_arguments() {
+        let ctx = zmq::Context::new();
+        let acty = Actorling::new_with_context("inproc://pipe_client", ctx.clone()).unwrap();
+        let endpoint = start_service(&acty);
+        let mut client = acty.client();
+        client.set_timeout(1_000);
+        assert_eq!(
+            client.ping(vec!["a", "b"]).unwrap(),
+            vec![b"a".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(
+            client.request("$POP", vec!["extra"]).unwrap(),
+            PipeReply {
+                status: PipeStatus::WontDo,
+                frames: vec![],
+            }
+        );
+        assert_eq!(client.pop().unwrap(), None);
+
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        push.send_multipart(["x", "y"], 0).unwrap();
+        pop_next(&acty);
+        assert_eq!(client.health().unwrap().inbox, 0);
+        client.stop().unwrap();
+        let err = client.ping(vec!["late"]).unwrap_err();
+        match err.downcast_ref::<ActorlingError>() {
+            Some(ActorlingError::NoReply) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn sibling_actorlings_find_each_other() {
+        let ctx = zmq::Context::new();
+        let first = Actorling::new_with_context("inproc://sibling_first", ctx.clone())
+            .unwrap()
+            .with_name("first");
+        let second = Actorling::new_with_context("inproc://sibling_second", ctx.clone()).unwrap();
+        assert_eq!(second.name(), second.uuid());
+        let first_handle = first.start().unwrap();
+        first.recv_endpoints().unwrap();
+        let second_handle = second.start().unwrap();
+        second.recv_endpoints().unwrap();
+        // actors register right after reporting their endpoints.
+        let mut siblings = Vec::new();
+        for _ in 0..100 {
+            siblings = second.siblings().unwrap();
+            if !siblings.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(
+            siblings,
+            vec![Sibling {
+                uuid: first.uuid(),
+                name: "first".into(),
+                pipe: first.pipe_endpoint(),
+                endpoints: vec!["inproc://sibling_first".into()],
+            }]
+        );
+
+        first.stop().unwrap();
+        assert!(first_handle.join().unwrap().unwrap().is_clean());
+        assert!(second.siblings().unwrap().is_empty());
+        second.stop().unwrap();
+        assert!(second_handle.join().unwrap().unwrap().is_clean());
+    }
+
+    #[test]
+    fn watching_actorlings_are_told_of_their_siblings() {
+        let next_event = |acty: &Actorling| {
+            let msg: Vec<Vec<u8>> = pop_next(acty).iter().map(|f| f.to_vec()).collect();
+            TopologyEvent::from_message(&msg).unwrap()
+        };
+        let ctx = zmq::Context::new();
+        let first = Actorling::new_with_context("inproc://topology_first", ctx.clone()).unwrap();
+        let first_handle = first.start().unwrap();
+        first.recv_endpoints().unwrap();
+        let watcher = Actorling::new_with_context("inproc://topology_watcher", ctx.clone())
+            .unwrap()
+            .with_topology_events();
+        let watcher_handle = watcher.start().unwrap();
+        watcher.recv_endpoints().unwrap();
+        match next_event(&watcher) {
+            TopologyEvent::PeerUp(ref sibling) => assert_eq!(sibling.uuid, first.uuid()),
+            other => panic!("unexpected event: {:?}", other),
+        }
+        // siblings can't pass their messages off as events of the directory.
+        let spoofed = TopologyEvent::PeerDown(Sibling {
+            uuid: first.uuid(),
+            name: "first".into(),
+            pipe: first.pipe_endpoint(),
+            endpoints: Vec::new(),
+        });
+        first
+            .send_to(&watcher.uuid(), spoofed.to_message().unwrap())
+            .unwrap();
+
+        let second = Actorling::new_with_context("inproc://topology_second", ctx.clone())
+            .unwrap()
+            .with_name("second");
+        let second_handle = second.start().unwrap();
+        second.recv_endpoints().unwrap();
+        let up = next_event(&watcher);
+        assert_eq!(up.sibling().name, "second");
+        assert_eq!(up.sibling().endpoints, vec!["inproc://topology_second"]);
+        second.stop().unwrap();
+        assert!(second_handle.join().unwrap().unwrap().is_clean());
+        assert_eq!(
+            next_event(&watcher),
+            TopologyEvent::PeerDown(up.sibling().clone())
+        );
+        assert_eq!(
+            TopologyEvent::from_message(&up.to_message().unwrap()[1..]),
+            Some(up)
+        );
+
+        for (acty, handle) in [(first, first_handle), (watcher, watcher_handle)] {
+            acty.stop().unwrap();
+            assert!(handle.join().unwrap().unwrap().is_clean());
+        }
+    }
+
+    #[test]
+    fn actorlings_send_messages_to_each_other_by_uuid() {
+        let ctx = zmq::Context::new();
+        let sender = Actorling::new_with_context("inproc://forward_sender", ctx.clone()).unwrap();
+        let receiver =
+            Actorling::new_with_context("inproc://forward_receiver", ctx.clone()).unwrap();
+        let sender_handle = sender.start().unwrap();
+        sender.recv_endpoints().unwrap();
+        let receiver_handle = receiver.start().unwrap();
+        receiver.recv_endpoints().unwrap();
+
+        // actors register right after reporting their endpoints.
+        let mut sent = false;
+        for _ in 0..100 {
+            if sender
+                .send_to(&receiver.uuid(), vec!["hello", "there"])
+                .is_ok()
+            {
+                sent = true;
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(sent);
+        let mut received = None;
+        for _ in 0..100 {
+            received = receiver.client().pop().unwrap();
+            if received.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(received, Some(vec![b"hello".to_vec(), b"there".to_vec()]));
+        let unknown = Uuid::new_v4().to_simple().to_string();
+        assert!(sender.send_to(&unknown, vec!["lost"]).is_err());
+
+        sender.stop().unwrap();
+        assert!(sender_handle.join().unwrap().unwrap().is_clean());
+        receiver.stop().unwrap();
+        assert!(receiver_handle.join().unwrap().unwrap().is_clean());
+    }
+
+    #[test]
+    fn shutdown_controller_stops_many_actorlings_at_once() {
+        let controller = ShutdownController::new();
+        let actors: Vec<Actorling> = (0..3)
+            .map(|i| Actorling::new(format!("inproc://shutdown_{}", i)).unwrap())
+            .collect();
+        for (i, acty) in actors.iter().enumerate() {
+            let handle = acty.start_with_shutdown(controller.token()).unwrap();
+            controller.register(&format!("actor-{}", i), handle);
+        }
+        let report = controller.shutdown(Duration::from_secs(2));
+        assert!(report.is_clean());
+        assert_eq!(report.joined.len(), 3);
+    }
+
+    #[test]
+    fn shutdown_controller_reports_stuck_threads() {
+        let controller = ShutdownController::new();
+        let handle = thread::spawn(|| {
+            thread::sleep(Duration::from_millis(500));
+            Ok(ActorExit::new(ExitReason::Finished))
+        });
+        controller.register("sleepy", handle);
+        let report = controller.shutdown(Duration::from_millis(10));
+        assert_eq!(report.stuck, vec!["sleepy".to_string()]);
+    }
+
+    #[test]
+    fn shutdown_tokens_observe_the_controller_signal() {
+        let controller = ShutdownController::new();
+        let token = controller.token();
+        assert!(!token.is_shutdown());
+        controller.signal();
+        assert!(token.is_shutdown());
+    }
 }
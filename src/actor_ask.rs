@@ -0,0 +1,301 @@
+//! Requests, and their replies, between actors.
+//!
+//! `ask` sends a message to an actor, with a correlation id, and returns an `AskHandle` to
+//! wait for the reply, or to turn it into a future. Asks are sent from a `DEALER` socket of
+//! their own, so they reach actors with a `ServiceKind::Router` service, whose reply, sent
+//! with `ActorContext::reply`, goes back over the service socket to the identity of the
+//! asker, without blocking the actor.
+//!
+//! The frames of an ask are `$ASK`, the correlation id, and the message, which the actor
+//! receives after the identity of the asker. The frames of its reply are the correlation
+//! id, and the reply. Messages of the other kinds of services are never taken for asks.
+use super::super::clock::Deadline;
+use super::{ActorlingError, ServiceKind};
+
+use failure::Error;
+use uuid::Uuid;
+use zmq;
+
+/// Command that starts the frames of an ask.
+pub const ASK: &str = "$ASK";
+
+/// Reference to an actor, by the endpoint of its service socket, for asks.
+#[derive(Clone)]
+pub struct ActorRef {
+    context: zmq::Context,
+    endpoint: String,
+}
+
+impl ActorRef {
+    /// Create a new `ActorRef` to the actor listening on `endpoint`, which has to be in
+    /// `context` for the replies to arrive.
+    pub fn new(context: &zmq::Context, endpoint: &str) -> ActorRef {
+        ActorRef {
+            context: context.clone(),
+            endpoint: endpoint.to_string(),
+        }
+    }
+
+    /// Returns the context of the actor.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Returns the endpoint of the actor.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+}
+
+/// Send `msg` to `actor`, and return the `AskHandle` of its reply, which is waited for up
+/// to `timeout` milliseconds, or forever if it is `-1`.
+pub fn ask<I, T>(actor: &ActorRef, msg: I, timeout: i64) -> Result<AskHandle, Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<Vec<u8>>,
+{
+    let id = Uuid::new_v4();
+    let socket = actor.context.socket(zmq::DEALER)?;
+    socket.set_linger(0)?;
+    socket.set_sndtimeo(timeout as i32)?;
+    socket.connect(&actor.endpoint)?;
+    let mut frames = vec![ASK.as_bytes().to_vec(), id.as_bytes().to_vec()];
+    frames.extend(msg.into_iter().map(Into::into));
+    socket
+        .send_multipart(frames, 0)
+        .map_err(ActorlingError::SocketSend)?;
+    let deadline = Deadline::after(timeout);
+    Ok(AskHandle {
+        id,
+        socket,
+        deadline,
+    })
+}
+
+/// The pending reply to an ask.
+pub struct AskHandle {
+    id: Uuid,
+    socket: zmq::Socket,
+    deadline: Deadline,
+}
+
+impl AskHandle {
+    /// Returns the correlation id of the ask.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Wait for the reply, which fails with `ActorlingError::AskTimeout` once the ask times
+    /// out.
+    pub fn wait(self) -> Result<Vec<Vec<u8>>, Error> {
+        loop {
+            if self.socket.poll(zmq::POLLIN, self.deadline.remaining())? == 0 {
+                return Err(ActorlingError::AskTimeout(self.id).into());
+            }
+            if let Some(reply) = self.take_reply(self.socket.recv_multipart(0)?) {
+                return Ok(reply);
+            }
+        }
+    }
+
+    // Returns the reply in `frames`, if it is the reply to this ask.
+    fn take_reply(&self, mut frames: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+        if frames.is_empty() {
+            return None;
+        }
+        let reply = frames.split_off(1);
+        if frames[0] == self.id.as_bytes() {
+            Some(reply)
+        } else {
+            nlog!(debug, "dropped reply of another ask");
+            None
+        }
+    }
+}
+
+// An ask received by an actor, which is answered with `ActorContext::reply`.
+pub struct Asked {
+    identity: Vec<u8>,
+    id: Vec<u8>,
+}
+
+impl Asked {
+    // Split the ask from the frames of `msg`, received on a service of `kind`, if it is one.
+    pub fn parse(kind: &ServiceKind, mut msg: Vec<Vec<u8>>) -> (Option<Asked>, Vec<Vec<u8>>) {
+        let is_ask = *kind == ServiceKind::Router
+            && msg.len() >= 3
+            && msg[1] == ASK.as_bytes()
+            && msg[2].len() == 16;
+        if !is_ask {
+            return (None, msg);
+        }
+        let payload = msg.split_off(3);
+        let id = msg.pop().unwrap_or_default();
+        msg.pop();
+        let identity = msg.pop().unwrap_or_default();
+        (Some(Asked { identity, id }), payload)
+    }
+
+    // Send `frames` to the asker, as the reply, on the `service` socket that the ask came
+    // from. Fails with `EAGAIN`, instead of blocking, if the asker can't take the reply.
+    pub fn reply(&self, service: &zmq::Socket, frames: Vec<Vec<u8>>) -> Result<(), Error> {
+        let mut reply = vec![self.identity.clone(), self.id.clone()];
+        reply.extend(frames);
+        service.send_multipart(reply, zmq::DONTWAIT)?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+pub use self::future::AskFuture;
+
+#[cfg(feature = "async-tokio")]
+mod future {
+    use super::super::super::socket::tokio::TokioSocket;
+    use super::super::super::socket::SocketRecv;
+    use super::super::ActorlingError;
+    use super::AskHandle;
+
+    use failure::Error;
+    use futures::{Async, Future, Poll};
+    use std::io;
+    use tokio_core::reactor::{Handle, Timeout};
+    use uuid::Uuid;
+
+    /// A `Future` that resolves into the reply to an ask, see `AskHandle::into_future`.
+    pub struct AskFuture {
+        id: Uuid,
+        socket: TokioSocket,
+        timeout: Option<Timeout>,
+    }
+
+    impl AskHandle {
+        /// Returns a `Future` of the reply, on the reactor of `handle`, which fails with
+        /// `ActorlingError::AskTimeout` once the ask times out.
+        pub fn into_future(self, handle: &Handle) -> Result<AskFuture, Error> {
+            let timeout = match self.deadline.remaining_duration() {
+                Some(remaining) => Some(Timeout::new(remaining, handle)?),
+                None => None,
+            };
+            Ok(AskFuture {
+                id: self.id,
+                socket: TokioSocket::new(self.socket, handle)?,
+                timeout,
+            })
+        }
+    }
+
+    impl Future for AskFuture {
+        type Item = Vec<Vec<u8>>;
+        type Error = Error;
+
+        fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+            loop {
+                match SocketRecv::recv_multipart(&self.socket, 0) {
+                    Ok(mut frames) => {
+                        if !frames.is_empty() && frames[0] == self.id.as_bytes() {
+                            return Ok(Async::Ready(frames.split_off(1)));
+                        }
+                        nlog!(debug, "dropped reply of another ask");
+                    }
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            match self.timeout {
+                Some(ref mut timeout) => match timeout.poll()? {
+                    Async::Ready(()) => Err(ActorlingError::AskTimeout(self.id).into()),
+                    Async::NotReady => Ok(Async::NotReady),
+                },
+                None => Ok(Async::NotReady),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Actor, ActorContext, ActorRunner, Actorling};
+    use super::*;
+    use testkit::wait_until;
+
+    struct Greeter;
+
+    impl Actor for Greeter {
+        fn on_message(&mut self, msg: Vec<Vec<u8>>, ctx: &mut ActorContext) -> Result<(), Error> {
+            if !ctx.is_ask() {
+                return Ok(());
+            }
+            let mut reply = vec![b"hello".to_vec()];
+            reply.extend(msg);
+            ctx.reply(reply)
+        }
+    }
+
+    #[test]
+    fn asks_are_answered_with_their_correlation_id() {
+        let ctx = zmq::Context::new();
+        let runner = ActorRunner::new(
+            Actorling::new_with_service("inproc://ask_greeter", ctx.clone(), ServiceKind::Router)
+                .unwrap(),
+        );
+        let handle = runner.start(Greeter).unwrap();
+        runner.actorling().recv_endpoints().unwrap();
+        let greeter = ActorRef::new(&ctx, "inproc://ask_greeter");
+
+        let reply = ask(&greeter, vec!["world"], 1_000).unwrap().wait().unwrap();
+        assert_eq!(reply, vec![b"hello".to_vec(), b"world".to_vec()]);
+
+        // messages that are not asks are not answered.
+        let tell = ctx.socket(zmq::DEALER).unwrap();
+        tell.connect("inproc://ask_greeter").unwrap();
+        tell.send("ignored", 0).unwrap();
+        assert!(!wait_until(20, || tell.poll(zmq::POLLIN, 0).unwrap() > 0));
+        let unanswered = ctx.socket(zmq::ROUTER).unwrap();
+        unanswered.bind("inproc://ask_nobody").unwrap();
+        let nobody = ActorRef::new(&ctx, "inproc://ask_nobody");
+        let unanswered = ask(&nobody, vec!["anyone?"], 20).unwrap();
+        let id = unanswered.id();
+        match unanswered
+            .wait()
+            .map_err(|e| e.downcast::<ActorlingError>())
+        {
+            Err(Ok(ActorlingError::AskTimeout(timed_out))) => assert_eq!(timed_out, id),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        #[cfg(feature = "async-tokio")]
+        {
+            let mut core = ::tokio_core::reactor::Core::new().unwrap();
+            let asked = ask(&greeter, vec!["future"], 1_000).unwrap();
+            let reply = core
+                .run(asked.into_future(&core.handle()).unwrap())
+                .unwrap();
+            assert_eq!(reply[1], b"future".to_vec());
+        }
+
+        runner.actorling().stop().unwrap();
+        assert!(handle.join().unwrap().unwrap().is_clean());
+    }
+    #[test]
+    fn only_router_services_take_asks() {
+        let id = Uuid::new_v4().as_bytes().to_vec();
+        let msg = vec![
+            b"peer".to_vec(),
+            ASK.as_bytes().to_vec(),
+            id,
+            b"hi".to_vec(),
+        ];
+        let (asked, payload) = Asked::parse(&ServiceKind::Router, msg.clone());
+        assert!(asked.is_some());
+        assert_eq!(payload, vec![b"hi".to_vec()]);
+
+        let (asked, payload) = Asked::parse(&ServiceKind::Pull, msg[1..].to_vec());
+        assert!(asked.is_none());
+        assert_eq!(payload, msg[1..].to_vec());
+        let (asked, _) = Asked::parse(&ServiceKind::Router, msg[..3].to_vec());
+        assert!(asked.is_some());
+        let invalid = vec![b"peer".to_vec(), ASK.as_bytes().to_vec(), b"short".to_vec()];
+        assert!(Asked::parse(&ServiceKind::Router, invalid).0.is_none());
+    }
+}
@@ -0,0 +1,363 @@
+//! Directory of the actors that share a context.
+//!
+//! inproc endpoints are only visible within their context, so the directory of a context
+//! listens on the well-known `DIRECTORY_ADDR`. It is started by the first actor that
+//! registers in the context, on a thread of its own, and ends once the last actor
+//! deregisters. Actors that find it ending, register again with a new directory.
+//!
+//! The directory maps the UUID of every actor to its pipe endpoint, from which the endpoint
+//! of its forwarded messages is derived, see `Sibling::forward_endpoint`.
+//!
+//! Actors that watch the directory, see `Actorling::with_topology_events`, are told of the
+//! siblings that register, and deregister, with `TopologyEvent` messages, sent to their
+//! `Sibling::topology_endpoint`, and queued in their inbox at `Priority::Control`. Once an
+//! actor watches, it is told of every sibling already registered. Messages that look like
+//! events, but come from the service socket, or are forwarded, are refused.
+use super::super::socket::{pack, unpack};
+use super::super::utils::run_named_thread;
+use super::{CONTROL, HIGH};
+
+use failure::Error;
+use std::collections::BTreeMap;
+use zmq;
+
+/// Address of the directory of the actors in a context.
+pub const DIRECTORY_ADDR: &str = "inproc://neuras.actor.directory";
+// Milliseconds to wait for each reply from the directory.
+const DIRECTORY_TIMEOUT: i64 = 100;
+// Attempts to reach a directory before giving up.
+const DIRECTORY_RETRIES: usize = 10;
+// Suffix of the pipe endpoint of an actor, for the endpoint of its forwarded messages.
+const FORWARD_SUFFIX: &str = ".forward";
+// Suffix of the pipe endpoint of an actor, for the endpoint of its topology events.
+const TOPOLOGY_SUFFIX: &str = ".topology";
+/// First frame of `TopologyEvent` messages, after their priority tag.
+pub const TOPOLOGY: &str = "$TOPOLOGY";
+const PEER_UP: &[u8] = b"$PEER_UP";
+const PEER_DOWN: &[u8] = b"$PEER_DOWN";
+
+/// An actor registered in the directory of its context.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sibling {
+    /// UUID of the actor, see `Actorling::uuid`.
+    pub uuid: String,
+    /// Name of the actor, see `Actorling::name`.
+    pub name: String,
+    /// inproc endpoint of the actor's pipe.
+    pub pipe: String,
+    /// Endpoints that the actor's service socket is bound to.
+    pub endpoints: Vec<String>,
+}
+
+impl Sibling {
+    /// Returns the inproc endpoint where the actor receives messages forwarded by UUID, see
+    /// `Actorling::send_to`.
+    pub fn forward_endpoint(&self) -> String {
+        forward_endpoint(&self.pipe)
+    }
+
+    /// Returns the inproc endpoint where the actor receives the `TopologyEvent` messages of
+    /// the directory, see `Actorling::with_topology_events`.
+    pub fn topology_endpoint(&self) -> String {
+        format!("{}{}", self.pipe, TOPOLOGY_SUFFIX)
+    }
+
+    fn to_frame(&self) -> Result<Vec<u8>, Error> {
+        let mut fields = vec![&self.uuid, &self.name, &self.pipe];
+        fields.extend(&self.endpoints);
+        Ok(pack(fields)?)
+    }
+
+    fn from_frame(frame: &[u8]) -> Result<Sibling, Error> {
+        let mut fields = unpack(frame)?
+            .into_iter()
+            .map(String::from_utf8)
+            .collect::<Result<Vec<_>, _>>()?;
+        if fields.len() < 3 {
+            bail!("invalid sibling in directory");
+        }
+        let endpoints = fields.split_off(3);
+        let pipe = fields.pop().unwrap_or_default();
+        let name = fields.pop().unwrap_or_default();
+        let uuid = fields.pop().unwrap_or_default();
+        Ok(Sibling {
+            uuid,
+            name,
+            pipe,
+            endpoints,
+        })
+    }
+}
+
+/// A change in the siblings of an actor, as told by the directory of its context.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TopologyEvent {
+    /// The sibling registered, i.e. it started.
+    PeerUp(Sibling),
+    /// The sibling deregistered, i.e. it stopped.
+    PeerDown(Sibling),
+}
+
+impl TopologyEvent {
+    /// Returns the sibling that the event is about.
+    pub fn sibling(&self) -> &Sibling {
+        match *self {
+            TopologyEvent::PeerUp(ref sibling) | TopologyEvent::PeerDown(ref sibling) => sibling,
+        }
+    }
+
+    /// Returns the event as a message, tagged with `Priority::Control`, as the directory
+    /// sends it to the actors that watch it.
+    pub fn to_message(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let kind = match *self {
+            TopologyEvent::PeerUp(_) => PEER_UP,
+            TopologyEvent::PeerDown(_) => PEER_DOWN,
+        };
+        Ok(event_message(kind, self.sibling().to_frame()?))
+    }
+
+    /// Parse an event from a message taken from the inbox, i.e. without its priority tag.
+    /// Returns `None` if the message is not a `TopologyEvent`.
+    pub fn from_message(msg: &[Vec<u8>]) -> Option<TopologyEvent> {
+        if msg.len() != 3 || msg[0] != TOPOLOGY.as_bytes() {
+            return None;
+        }
+        let sibling = Sibling::from_frame(&msg[2]).ok()?;
+        match &msg[1][..] {
+            PEER_UP => Some(TopologyEvent::PeerUp(sibling)),
+            PEER_DOWN => Some(TopologyEvent::PeerDown(sibling)),
+            _ => None,
+        }
+    }
+}
+
+fn event_message(kind: &[u8], sibling: Vec<u8>) -> Vec<Vec<u8>> {
+    vec![
+        CONTROL.as_bytes().to_vec(),
+        TOPOLOGY.as_bytes().to_vec(),
+        kind.to_vec(),
+        sibling,
+    ]
+}
+
+// Start the directory of `context`, unless it is already running.
+fn start_directory(context: &zmq::Context) -> Result<(), Error> {
+    let socket = context.socket(zmq::ROUTER)?;
+    match socket.bind(DIRECTORY_ADDR) {
+        Ok(()) => {}
+        Err(zmq::Error::EADDRINUSE) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    let context = context.clone();
+    run_named_thread("directory", move || run_directory(&context, &socket))?;
+    Ok(())
+}
+
+// Actors that watch the directory, by UUID, with a socket to their forward endpoint.
+type Watchers = BTreeMap<String, zmq::Socket>;
+
+// Tell every watcher, other than the sibling with `uuid` itself, of an event. Watchers that
+// can't take more messages miss it.
+fn notify(watchers: &Watchers, uuid: &str, kind: &[u8], sibling: &[u8]) {
+    for watcher in watchers.keys() {
+        if watcher != uuid {
+            notify_one(watchers, watcher, kind, sibling);
+        }
+    }
+}
+
+// Tell the `watcher` of an event, unless it can't take more messages.
+fn notify_one(watchers: &Watchers, watcher: &str, kind: &[u8], sibling: &[u8]) {
+    let msg = event_message(kind, sibling.to_vec());
+    if let Err(e) = watchers[watcher].send_multipart(msg, zmq::DONTWAIT) {
+        nlog!(
+            warn,
+            "topology event not sent watcher={} error={}",
+            watcher,
+            e
+        );
+    }
+}
+
+// Start telling the sibling with `uuid` of the others, beginning with those registered.
+fn watch(
+    context: &zmq::Context,
+    siblings: &BTreeMap<String, Vec<u8>>,
+    watchers: &mut Watchers,
+    uuid: &str,
+) -> Result<(), Error> {
+    let sibling = match siblings.get(uuid) {
+        Some(frame) => Sibling::from_frame(frame)?,
+        None => bail!("unknown sibling"),
+    };
+    let socket = context.socket(zmq::PUSH)?;
+    socket.set_linger(0)?;
+    socket.connect(&sibling.topology_endpoint())?;
+    watchers.insert(uuid.to_string(), socket);
+    for (other, frame) in siblings {
+        if other != uuid {
+            notify_one(watchers, uuid, PEER_UP, frame);
+        }
+    }
+    Ok(())
+}
+
+fn run_directory(context: &zmq::Context, socket: &zmq::Socket) -> Result<(), Error> {
+    let mut siblings = BTreeMap::new();
+    let mut watchers = Watchers::new();
+    loop {
+        let mut msg = socket.recv_multipart(0)?;
+        if msg.len() < 3 {
+            continue;
+        }
+        let request = msg.split_off(2);
+        let mut reply = msg;
+        let empty = match &request[0][..] {
+            b"$REGISTER" if request.len() == 2 => {
+                match Sibling::from_frame(&request[1]) {
+                    Ok(sibling) => {
+                        notify(&watchers, &sibling.uuid, PEER_UP, &request[1]);
+                        siblings.insert(sibling.uuid, request[1].clone());
+                        reply.push(b"$OK".to_vec());
+                    }
+                    Err(_) => reply.push(b"$WONTDO".to_vec()),
+                }
+                false
+            }
+            b"$DEREGISTER" if request.len() == 2 => {
+                let uuid = String::from_utf8_lossy(&request[1]).to_string();
+                watchers.remove(&uuid);
+                if let Some(frame) = siblings.remove(&uuid) {
+                    notify(&watchers, &uuid, PEER_DOWN, &frame);
+                }
+                reply.push(b"$OK".to_vec());
+                siblings.is_empty()
+            }
+            b"$WATCH" if request.len() == 2 => {
+                let uuid = String::from_utf8_lossy(&request[1]).to_string();
+                match watch(context, &siblings, &mut watchers, &uuid) {
+                    Ok(()) => reply.push(b"$OK".to_vec()),
+                    Err(_) => reply.push(b"$WONTDO".to_vec()),
+                }
+                false
+            }
+            b"$LOOKUP" if request.len() == 2 => {
+                let uuid = String::from_utf8_lossy(&request[1]).to_string();
+                reply.push(b"$OK".to_vec());
+                reply.extend(siblings.get(&uuid).cloned());
+                false
+            }
+            b"$LIST" => {
+                reply.push(b"$OK".to_vec());
+                reply.extend(siblings.values().cloned());
+                false
+            }
+            _ => {
+                reply.push(b"$WONTDO".to_vec());
+                false
+            }
+        };
+        socket.send_multipart(reply, 0)?;
+        if empty {
+            nlog!(debug, "directory ending without actors");
+            return Ok(());
+        }
+    }
+}
+
+// Send a request to the directory of `context`, starting it if `start` is set, and return
+// the frames of its reply. Returns `None` if there is no directory.
+fn request(
+    context: &zmq::Context,
+    msg: &[Vec<u8>],
+    start: bool,
+) -> Result<Option<Vec<Vec<u8>>>, Error> {
+    for _ in 0..DIRECTORY_RETRIES {
+        if start {
+            start_directory(context)?;
+        }
+        let req = context.socket(zmq::REQ)?;
+        req.set_linger(0)?;
+        req.connect(DIRECTORY_ADDR)?;
+        req.send_multipart(msg, 0)?;
+        if req.poll(zmq::POLLIN, DIRECTORY_TIMEOUT)? > 0 {
+            let reply = req.recv_multipart(0)?;
+            if reply.first().map(|r| &r[..]) != Some(b"$OK") {
+                bail!("directory refused the request");
+            }
+            return Ok(Some(reply[1..].to_vec()));
+        }
+        if !start {
+            return Ok(None);
+        }
+        // the directory was ending, and a new one is started on the next attempt.
+    }
+    bail!("directory did not answer")
+}
+
+/// Register `sibling` in the directory of `context`.
+pub fn register(context: &zmq::Context, sibling: &Sibling) -> Result<(), Error> {
+    request(context, &[b"$REGISTER".to_vec(), sibling.to_frame()?], true)?;
+    Ok(())
+}
+
+/// Deregister the actor with `uuid` from the directory of `context`.
+pub fn deregister(context: &zmq::Context, uuid: &str) -> Result<(), Error> {
+    request(
+        context,
+        &[b"$DEREGISTER".to_vec(), uuid.as_bytes().to_vec()],
+        false,
+    )?;
+    Ok(())
+}
+
+/// Tell the registered `sibling` of the siblings that register, and deregister, in the
+/// directory of `context`, with `TopologyEvent` messages. A directory that does not know
+/// the sibling, e.g. one started after the directory that it registered with ended,
+/// refuses with `$WONTDO`, and the sibling registers again before it is watched.
+pub fn watch_topology(context: &zmq::Context, sibling: &Sibling) -> Result<(), Error> {
+    let msg = [b"$WATCH".to_vec(), sibling.uuid.as_bytes().to_vec()];
+    match request(context, &msg, false) {
+        Ok(Some(_)) => return Ok(()),
+        Ok(None) => {}
+        Err(e) => nlog!(debug, "directory refused to watch error={}", e),
+    }
+    register(context, sibling)?;
+    match request(context, &msg, false)? {
+        Some(_) => Ok(()),
+        None => bail!("directory did not answer"),
+    }
+}
+
+/// Returns true if `msg`, tagged or not with its priority, looks like a `TopologyEvent`,
+/// which only the directory sends.
+pub fn is_topology_event(msg: &[Vec<u8>]) -> bool {
+    let tagged = match msg.first() {
+        Some(tag) => tag == CONTROL.as_bytes() || tag == HIGH.as_bytes(),
+        None => false,
+    };
+    let start = if tagged { 1 } else { 0 };
+    msg.get(start).map(|frame| &frame[..]) == Some(TOPOLOGY.as_bytes())
+}
+
+// Returns the endpoint of the forwarded messages of the actor with the `pipe` endpoint.
+pub fn forward_endpoint(pipe: &str) -> String {
+    format!("{}{}", pipe, FORWARD_SUFFIX)
+}
+
+/// Returns the actor with `uuid`, if it is registered in the directory of `context`.
+pub fn lookup(context: &zmq::Context, uuid: &str) -> Result<Option<Sibling>, Error> {
+    let msg = [b"$LOOKUP".to_vec(), uuid.as_bytes().to_vec()];
+    match request(context, &msg, false)? {
+        Some(frames) => frames.first().map(|f| Sibling::from_frame(f)).transpose(),
+        None => Ok(None),
+    }
+}
+
+/// Returns the actors registered in the directory of `context`.
+pub fn list(context: &zmq::Context) -> Result<Vec<Sibling>, Error> {
+    match request(context, &[b"$LIST".to_vec()], false)? {
+        Some(frames) => frames.iter().map(|f| Sibling::from_frame(f)).collect(),
+        None => Ok(Vec::new()),
+    }
+}
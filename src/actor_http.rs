@@ -0,0 +1,417 @@
+//! HTTP health, and metrics, of actors.
+//!
+//! A `HealthServer` answers plain HTTP/1.1 requests on a `STREAM` socket, on a thread of
+//! its own, so that load balancers, and orchestrators, can probe the process without a web
+//! stack in it:
+//!
+//! | path       | answer                                                             |
+//! |------------|--------------------------------------------------------------------|
+//! | `/healthz` | `200 OK` if every probe answers a `Health`, `503` otherwise        |
+//! | `/metrics` | the `Health`, and `SocketStats`, of each probe, as Prometheus text |
+//!
+//! Each request runs every probe, either a closure, or a `$HEALTH` request sent to the
+//! service socket of an actor that replies to requests, see `HealthServer::probe_service`.
+//! Connections are closed after each response.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::actor::{Health, HealthServer, ShutdownController};
+//! use std::io::{Read, Write};
+//! use std::net::TcpStream;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let server = HealthServer::bind(&ctx, "tcp://127.0.0.1:*")
+//!     .unwrap()
+//!     .probe("worker", || Ok(Health::default()));
+//! let endpoint = server.endpoint().to_string();
+//! let shutdown = ShutdownController::new();
+//! shutdown.register("health", server.start(shutdown.token()).unwrap());
+//!
+//! let mut client = TcpStream::connect(endpoint.trim_start_matches("tcp://")).unwrap();
+//! client.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+//! let mut response = String::new();
+//! client.read_to_string(&mut response).unwrap();
+//! assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+//!
+//! assert!(shutdown.shutdown(std::time::Duration::from_secs(1)).is_clean());
+//! # }
+//! ```
+use super::super::endpoint::{ToEndpoint, Transport};
+use super::super::socket::{RawStream, SocketStats, SocketWrapper, StreamEvent};
+use super::super::utils::run_named_thread;
+use super::{ActorExit, ActorThread, ExitReason, Health, ShutdownToken, HEALTH};
+
+use failure::Error;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use zmq;
+
+/// Default milliseconds that a `$HEALTH` request waits for its reply.
+pub const DEFAULT_PROBE_TIMEOUT: i64 = 1_000;
+// Largest request head that is read, in bytes.
+const MAX_REQUEST: usize = 8 * 1024;
+// Milliseconds between checks of the shutdown token.
+const POLL_INTERVAL: i64 = 100;
+
+/// Takes the `Health` of an actor, for each request to the `HealthServer`.
+pub type HealthProbe = dyn FnMut() -> Result<Health, Error> + Send;
+
+/// Serves `/healthz`, and `/metrics`, over HTTP.
+pub struct HealthServer {
+    context: zmq::Context,
+    stream: RawStream,
+    endpoint: String,
+    probes: Vec<(String, Box<HealthProbe>)>,
+    timeout: i64,
+}
+
+impl HealthServer {
+    /// Bind a `STREAM` socket to the TCP `endpoint`, e.g. `tcp://0.0.0.0:8080`, or
+    /// `tcp://127.0.0.1:*` for any free port.
+    pub fn bind<E: ToEndpoint>(context: &zmq::Context, endpoint: E) -> Result<Self, Error> {
+        let endpoint = endpoint.to_endpoint()?;
+        if endpoint.transport() != Transport::Tcp {
+            return Err(format_err!("health is served over tcp, not {}", endpoint));
+        }
+        let stream = RawStream::new(context)?;
+        let endpoint = stream.bind(&endpoint.to_string())?;
+        Ok(HealthServer {
+            context: context.clone(),
+            stream,
+            endpoint,
+            probes: Vec::new(),
+            timeout: DEFAULT_PROBE_TIMEOUT,
+        })
+    }
+
+    /// Add a probe, known by `name` in the responses.
+    pub fn probe<F>(mut self, name: &str, probe: F) -> Self
+    where
+        F: FnMut() -> Result<Health, Error> + Send + 'static,
+    {
+        self.probes.push((name.to_string(), Box::new(probe)));
+        self
+    }
+
+    /// Add a probe that sends `$HEALTH` to the service socket of an actor at `endpoint`,
+    /// which must be a `ServiceKind::Rep`, or `ServiceKind::Router`, service.
+    pub fn probe_service<E: ToEndpoint>(self, name: &str, endpoint: E) -> Result<Self, Error> {
+        let endpoint = endpoint.to_endpoint()?.to_string();
+        let context = self.context.clone();
+        let timeout = self.timeout;
+        Ok(self.probe(name, move || request_health(&context, &endpoint, timeout)))
+    }
+
+    /// Set the milliseconds that probes added afterwards with `probe_service` wait for a
+    /// reply.
+    pub fn with_timeout(mut self, timeout: i64) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Returns the endpoint that the server is bound to.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Serve requests on a thread of its own, until `token` is signaled.
+    pub fn start(self, token: ShutdownToken) -> Result<ActorThread, io::Error> {
+        run_named_thread("health", move || {
+            let mut server = self;
+            server.run(&token)?;
+            Ok(ActorExit::new(ExitReason::Shutdown))
+        })
+    }
+
+    fn run(&mut self, token: &ShutdownToken) -> Result<(), Error> {
+        let mut requests: HashMap<Vec<u8>, Vec<u8>> = HashMap::new();
+        while !token.is_shutdown() {
+            if self
+                .stream
+                .get_socket_ref()
+                .poll(zmq::POLLIN, POLL_INTERVAL)?
+                == 0
+            {
+                continue;
+            }
+            loop {
+                let event = match self.stream.recv(zmq::DONTWAIT) {
+                    Ok(event) => event,
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                    Err(e) => return Err(e.into()),
+                };
+                match event {
+                    StreamEvent::Connected(peer) => {
+                        requests.insert(peer, Vec::new());
+                    }
+                    StreamEvent::Disconnected(peer) => {
+                        requests.remove(&peer);
+                    }
+                    StreamEvent::Data(peer, data) => {
+                        let head = {
+                            let request = requests.entry(peer.clone()).or_default();
+                            request.extend(data);
+                            request_head(request)
+                        };
+                        if let Some(head) = head {
+                            requests.remove(&peer);
+                            let response = self.respond(head);
+                            self.stream.send(&peer, &response)?;
+                            self.stream.close(&peer)?;
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Build the response to the request with `head`.
+    fn respond(&mut self, head: Result<String, u16>) -> Vec<u8> {
+        let head = match head {
+            Ok(head) => head,
+            Err(status) => return response(status, "", false),
+        };
+        let mut words = head.split_whitespace();
+        let (method, target) = match (words.next(), words.next(), words.next()) {
+            (Some(method), Some(target), Some(version)) if version.starts_with("HTTP/1.") => {
+                (method, target)
+            }
+            _ => return response(400, "", false),
+        };
+        let body = match method {
+            "GET" | "HEAD" => method == "GET",
+            _ => return response(405, "", false),
+        };
+        let path = target.split('?').next().unwrap_or_default();
+        match path {
+            "/healthz" => {
+                let reports = self.run_probes();
+                let status = if reports.iter().all(|(_, health)| health.is_ok()) {
+                    200
+                } else {
+                    503
+                };
+                response(status, &healthz(&reports), body)
+            }
+            "/metrics" => response(200, &metrics(&self.run_probes()), body),
+            _ => response(404, "", false),
+        }
+    }
+
+    fn run_probes(&mut self) -> Vec<(String, Result<Health, Error>)> {
+        self.probes
+            .iter_mut()
+            .map(|(name, probe)| (name.clone(), probe()))
+            .collect()
+    }
+}
+
+// Returns the request line once the head of the request has arrived, or the status to
+// answer with if it is too large, or is not text.
+fn request_head(request: &[u8]) -> Option<Result<String, u16>> {
+    let end = request.windows(4).position(|window| window == b"\r\n\r\n");
+    match end {
+        Some(end) => Some(
+            ::std::str::from_utf8(&request[..end])
+                .map(|head| head.lines().next().unwrap_or_default().to_string())
+                .map_err(|_| 400),
+        ),
+        None if request.len() > MAX_REQUEST => Some(Err(431)),
+        None => None,
+    }
+}
+
+// Send `$HEALTH` to the service socket at `endpoint`, and wait up to `timeout`
+// milliseconds for the reply.
+fn request_health(context: &zmq::Context, endpoint: &str, timeout: i64) -> Result<Health, Error> {
+    let socket = context.socket(zmq::REQ)?;
+    socket.set_linger(0)?;
+    socket.connect(endpoint)?;
+    socket.send(HEALTH, 0)?;
+    if socket.poll(zmq::POLLIN, timeout)? == 0 {
+        return Err(format_err!(
+            "no health from {} within {}ms",
+            endpoint,
+            timeout
+        ));
+    }
+    Health::from_bytes(&socket.recv_bytes(0)?)
+}
+
+fn response(status: u16, body: &str, with_body: bool) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        431 => "Request Header Fields Too Large",
+        _ => "Service Unavailable",
+    };
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        status,
+        reason,
+        body.len()
+    );
+    if with_body {
+        response.push_str(body);
+    }
+    response.into_bytes()
+}
+
+// One line for each probe, with its error if it failed.
+fn healthz(reports: &[(String, Result<Health, Error>)]) -> String {
+    let mut body = String::new();
+    for (name, health) in reports {
+        let _ = match *health {
+            Ok(_) => writeln!(body, "{}: ok", name),
+            Err(ref e) => writeln!(body, "{}: {}", name, e),
+        };
+    }
+    if reports.is_empty() {
+        body.push_str("ok\n");
+    }
+    body
+}
+
+type ActorMetric = (&'static str, &'static str, fn(&Health) -> i64);
+type SocketMetric = (&'static str, fn(&SocketStats) -> usize);
+
+const ACTOR_METRICS: &[ActorMetric] = &[
+    ("neuras_actor_uptime_milliseconds", "gauge", |h| h.uptime),
+    ("neuras_actor_inbox_messages", "gauge", |h| h.inbox as i64),
+    ("neuras_actor_outbox_commands", "gauge", |h| h.outbox as i64),
+];
+
+const SOCKET_METRICS: &[SocketMetric] = &[
+    ("neuras_socket_messages_sent_total", |s| s.messages_sent),
+    ("neuras_socket_messages_received_total", |s| {
+        s.messages_received
+    }),
+    ("neuras_socket_bytes_sent_total", |s| s.bytes_sent),
+    ("neuras_socket_bytes_received_total", |s| s.bytes_received),
+    ("neuras_socket_would_block_total", |s| s.would_block),
+];
+
+// The metrics of every probe, in the Prometheus text format. Probes that failed are only
+// reported as down.
+fn metrics(reports: &[(String, Result<Health, Error>)]) -> String {
+    let mut body = String::new();
+    let _ = writeln!(body, "# TYPE neuras_actor_up gauge");
+    for (name, health) in reports {
+        let up = if health.is_ok() { 1 } else { 0 };
+        let _ = writeln!(body, "neuras_actor_up{{actor=\"{}\"}} {}", label(name), up);
+    }
+    let healthy: Vec<(String, &Health)> = reports
+        .iter()
+        .filter_map(|(name, health)| health.as_ref().ok().map(|health| (label(name), health)))
+        .collect();
+    for &(metric, kind, value) in ACTOR_METRICS {
+        let _ = writeln!(body, "# TYPE {} {}", metric, kind);
+        for (name, health) in &healthy {
+            let _ = writeln!(body, "{}{{actor=\"{}\"}} {}", metric, name, value(health));
+        }
+    }
+    for &(metric, value) in SOCKET_METRICS {
+        let _ = writeln!(body, "# TYPE {} counter", metric);
+        for (name, health) in &healthy {
+            for (socket, stats) in &[("pipe", &health.pipe), ("service", &health.service)] {
+                let _ = writeln!(
+                    body,
+                    "{}{{actor=\"{}\",socket=\"{}\"}} {}",
+                    metric,
+                    name,
+                    socket,
+                    value(stats)
+                );
+            }
+        }
+    }
+    body
+}
+
+// Escape a label value.
+fn label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Actorling, ServiceKind, ShutdownController};
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+    use std::time::Duration;
+
+    fn get(endpoint: &str, request: &str) -> String {
+        let mut client = TcpStream::connect(endpoint.trim_start_matches("tcp://")).unwrap();
+        client.write_all(request.as_bytes()).unwrap();
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    #[test]
+    fn health_servers_answer_probes_over_http() {
+        let ctx = zmq::Context::new();
+        let actorling =
+            Actorling::new_with_service("inproc://http-probed", ctx.clone(), ServiceKind::Rep)
+                .unwrap();
+        let handle = actorling.start().unwrap();
+        actorling.recv_endpoints().unwrap();
+
+        let server = HealthServer::bind(&ctx, "tcp://127.0.0.1:*")
+            .unwrap()
+            .probe_service("probed", "inproc://http-probed")
+            .unwrap()
+            .with_timeout(50)
+            .probe_service("missing", "inproc://http-missing")
+            .unwrap();
+        let endpoint = server.endpoint().to_string();
+        let shutdown = ShutdownController::new();
+        shutdown.register("health", server.start(shutdown.token()).unwrap());
+
+        let healthz = get(&endpoint, "GET /healthz HTTP/1.1\r\nHost: test\r\n\r\n");
+        assert!(healthz.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert!(healthz.contains("\r\n\r\nprobed: ok\nmissing: no health from"));
+
+        let metrics = get(&endpoint, "GET /metrics HTTP/1.0\r\n\r\n");
+        assert!(metrics.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(metrics.contains("neuras_actor_up{actor=\"probed\"} 1\n"));
+        assert!(metrics.contains("neuras_actor_up{actor=\"missing\"} 0\n"));
+        assert!(metrics.contains(
+            "neuras_socket_messages_received_total{actor=\"probed\",socket=\"service\"} "
+        ));
+        assert!(!metrics.contains("inbox_messages{actor=\"missing\"}"));
+
+        let head = get(&endpoint, "HEAD /metrics HTTP/1.1\r\n\r\n");
+        assert!(head.ends_with("Connection: close\r\n\r\n"));
+        assert!(get(&endpoint, "GET /nowhere HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        assert!(get(&endpoint, "POST /healthz HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+
+        assert!(shutdown.shutdown(Duration::from_secs(1)).is_clean());
+        actorling.stop().unwrap();
+        assert!(handle.join().unwrap().unwrap().is_clean());
+    }
+
+    #[test]
+    fn health_servers_only_serve_tcp() {
+        let ctx = zmq::Context::new();
+        assert!(HealthServer::bind(&ctx, "inproc://health").is_err());
+        assert_eq!(request_head(b"GET / HTTP/1.1\r\n"), None);
+        assert_eq!(
+            request_head(b"GET / HTTP/1.1\r\nHost: x\r\n\r\n"),
+            Some(Ok("GET / HTTP/1.1".to_string()))
+        );
+        assert_eq!(request_head(&[b'a'; MAX_REQUEST + 1]), Some(Err(431)));
+    }
+}
@@ -0,0 +1,240 @@
+//! Priority lanes of the actor inbox.
+//!
+//! Messages whose first frame is a priority tag, i.e. `$CONTROL`, or `$HIGH`, are queued in
+//! the lane of that priority, without the tag. On `ROUTER` services, the tag follows the
+//! identity of the sender, which is kept. Other messages are queued in the `Normal` lane.
+//! Messages are taken from the highest priority lane first, but, so that lower lanes are not
+//! starved, a lane is taken from once `starvation_limit` consecutive messages were taken
+//! ahead of its oldest message, unless the limit is 0.
+use std::collections::VecDeque;
+
+/// Tag of `Priority::Control` messages.
+pub const CONTROL: &str = "$CONTROL";
+/// Tag of `Priority::High` messages.
+pub const HIGH: &str = "$HIGH";
+/// Default number of consecutive messages of higher priority taken ahead of a waiting
+/// lower priority message.
+pub const DEFAULT_STARVATION_LIMIT: usize = 16;
+
+/// Priority of messages in the inbox of an actor, from highest to lowest.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    /// Commands that must not wait behind data.
+    Control,
+    High,
+    /// Untagged messages.
+    Normal,
+}
+
+impl Priority {
+    /// Returns the tag of the priority, if it has one.
+    pub fn tag(self) -> Option<&'static str> {
+        match self {
+            Priority::Control => Some(CONTROL),
+            Priority::High => Some(HIGH),
+            Priority::Normal => None,
+        }
+    }
+
+    /// Returns `frames`, tagged with the priority, for the actor to queue them in its lane.
+    pub fn tagged<I, T>(self, frames: I) -> Vec<Vec<u8>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.tag()
+            .map(|tag| tag.as_bytes().to_vec())
+            .into_iter()
+            .chain(frames.into_iter().map(Into::into))
+            .collect()
+    }
+
+    // Returns the priority of `msg`, and whether it is tagged.
+    fn of(msg: &[Vec<u8>]) -> (Priority, bool) {
+        match msg.first().map(|frame| &frame[..]) {
+            Some(tag) if tag == CONTROL.as_bytes() => (Priority::Control, true),
+            Some(tag) if tag == HIGH.as_bytes() => (Priority::High, true),
+            _ => (Priority::Normal, false),
+        }
+    }
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+// Move the priority tag of `msg`, that follows its `envelope` of routing frames, e.g. the
+// identity of the sender on `ROUTER` sockets, to the front, where the inbox looks for it.
+pub fn lift_tag(msg: &mut Vec<Vec<u8>>, envelope: usize) {
+    if envelope > 0 && msg.len() > envelope && Priority::of(&msg[envelope..]).1 {
+        let tag = msg.remove(envelope);
+        msg.insert(0, tag);
+    }
+}
+
+// A queued message, with its sequence number in the spool, if it was spooled.
+type Queued = (Option<u64>, Vec<Vec<u8>>);
+
+// The lanes of queued messages, by priority.
+#[derive(Debug, PartialEq)]
+pub struct Inbox {
+    lanes: [VecDeque<Queued>; 3],
+    starvation_limit: usize,
+    // consecutive messages taken from other lanes while each lane was waiting.
+    ages: [usize; 3],
+}
+
+impl Inbox {
+    // Create an empty inbox, which takes a lower priority message after every
+    // `starvation_limit` consecutive messages taken ahead of it, or never if it is 0.
+    pub fn with_starvation_limit(starvation_limit: usize) -> Inbox {
+        Inbox {
+            lanes: Default::default(),
+            starvation_limit,
+            ages: [0; 3],
+        }
+    }
+
+    // Queue `msg` in the lane of its tag, without the tag, with its `sequence` number in the
+    // spool, if it was spooled.
+    pub fn push(&mut self, mut msg: Vec<Vec<u8>>, sequence: Option<u64>) {
+        let (priority, tagged) = Priority::of(&msg);
+        if tagged {
+            msg.remove(0);
+        }
+        self.lanes[priority.lane()].push_back((sequence, msg));
+    }
+
+    // Returns the lane of the next message, by priority.
+    fn next_lane(&self) -> Option<usize> {
+        let waiting: Vec<usize> = (0..self.lanes.len())
+            .filter(|&lane| !self.lanes[lane].is_empty())
+            .collect();
+        let highest = *waiting.first()?;
+        // the lane that waited the longest, once it waited too long, by priority on ties.
+        let starved = waiting
+            .iter()
+            .cloned()
+            .filter(|&lane| self.starvation_limit > 0 && self.ages[lane] >= self.starvation_limit)
+            .fold(None, |oldest: Option<usize>, lane| match oldest {
+                Some(oldest) if self.ages[oldest] >= self.ages[lane] => Some(oldest),
+                _ => Some(lane),
+            });
+        Some(starved.unwrap_or(highest))
+    }
+
+    // Returns the next message, by priority, without taking it.
+    pub fn peek(&self) -> Option<&Vec<Vec<u8>>> {
+        let lane = self.next_lane()?;
+        self.lanes[lane].front().map(|queued| &queued.1)
+    }
+
+    // Take the next message, by priority, with its sequence number in the spool.
+    pub fn pop(&mut self) -> Option<Queued> {
+        let lane = self.next_lane()?;
+        for other in 0..self.lanes.len() {
+            if other == lane || self.lanes[other].is_empty() {
+                self.ages[other] = 0;
+            } else {
+                self.ages[other] += 1;
+            }
+        }
+        self.lanes[lane].pop_front()
+    }
+
+    // Returns the number of messages waiting in every lane.
+    pub fn len(&self) -> usize {
+        self.lanes.iter().map(VecDeque::len).sum()
+    }
+
+    // Returns the number of messages waiting in the lane of `priority`.
+    pub fn lane_len(&self, priority: Priority) -> usize {
+        self.lanes[priority.lane()].len()
+    }
+}
+
+impl Default for Inbox {
+    fn default() -> Self {
+        Inbox::with_starvation_limit(DEFAULT_STARVATION_LIMIT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(frame: &str) -> Vec<Vec<u8>> {
+        vec![frame.as_bytes().to_vec()]
+    }
+
+    #[test]
+    fn higher_lanes_go_first_without_starving_lower_ones() {
+        let mut inbox = Inbox::with_starvation_limit(2);
+        inbox.push(msg("bulk"), None);
+        for n in 0..3 {
+            inbox.push(Priority::High.tagged(vec![format!("high{}", n)]), Some(n));
+        }
+        inbox.push(Priority::Control.tagged(vec!["stop"]), None);
+        assert_eq!(inbox.len(), 5);
+        assert_eq!(inbox.lane_len(Priority::High), 3);
+
+        let order: Vec<Vec<Vec<u8>>> = (0..5).filter_map(|_| inbox.pop()).map(|q| q.1).collect();
+        assert_eq!(
+            order,
+            vec![
+                msg("stop"),
+                msg("high0"),
+                // two messages were taken ahead of it.
+                msg("bulk"),
+                msg("high1"),
+                msg("high2"),
+            ]
+        );
+        assert_eq!(inbox.pop(), None);
+    }
+
+    #[test]
+    fn every_lane_ages_while_it_waits() {
+        let mut inbox = Inbox::with_starvation_limit(2);
+        inbox.push(msg("bulk"), None);
+        inbox.push(Priority::High.tagged(vec!["high"]), None);
+        for n in 0..6 {
+            inbox.push(
+                Priority::Control.tagged(vec![format!("control{}", n)]),
+                None,
+            );
+        }
+        let order: Vec<Vec<Vec<u8>>> = (0..8)
+            .filter_map(|_| {
+                let next = inbox.peek().cloned();
+                let popped = inbox.pop().map(|q| q.1);
+                assert_eq!(next, popped);
+                popped
+            })
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                msg("control0"),
+                msg("control1"),
+                // both waited for two messages, and the normal lane waits its turn.
+                msg("high"),
+                msg("bulk"),
+                msg("control2"),
+                msg("control3"),
+                msg("control4"),
+                msg("control5"),
+            ]
+        );
+
+        // the identity of routed messages stays in front of them.
+        let mut routed = vec![b"peer".to_vec(), HIGH.as_bytes().to_vec(), b"hi".to_vec()];
+        lift_tag(&mut routed, 1);
+        inbox.push(routed, None);
+        assert_eq!(inbox.lane_len(Priority::High), 1);
+        assert_eq!(
+            inbox.pop().unwrap().1,
+            vec![b"peer".to_vec(), b"hi".to_vec()]
+        );
+    }
+}
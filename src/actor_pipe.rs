@@ -0,0 +1,270 @@
+//! Multipart protocol of the actor pipe.
+//!
+//! Commands are multipart messages: the first frame is the command, e.g. `$PING`, and the
+//! rest are its arguments. Every command is answered with a multipart reply, whose first
+//! frame is a `PipeStatus`, followed by the frames of its result, if any.
+//!
+//! | command   | arguments  | reply                          |
+//! |-----------|------------|--------------------------------|
+//! | `$FORWARD`| UUID, msg  | `$OK`                          |
+//! | `$HEALTH` |            | `$OK`, `Health` as TOML        |
+//! | `$PING`   | any        | `$PONG`, the arguments         |
+//! | `$POP`    |            | `$OK`, the message, or `$NONE` |
+//! | `$POPN`   | count      | `$OK`, the messages, or `$NONE`|
+//! | `$REPLY`  | the reply  | `$OK`                          |
+//! | `$STOP`   |            | `$STOPPING`                    |
+//!
+//! `$POPN` takes up to `count` messages, a positive decimal, from the inbox, and answers
+//! with a frame for each, with its frames packed as by `socket::pack`.
+//!
+//! Invalid commands, or commands that can not be done, are answered with `$WONTDO`.
+use super::super::socket::unpack;
+use super::{ActorlingError, Health, FORWARD, HEALTH, POPN};
+
+use failure::Error;
+use std::collections::VecDeque;
+use zmq;
+
+/// Messages taken from the inbox for each `$POPN` of `Actorling::drain`.
+pub const DRAIN_BATCH: usize = 64;
+
+/// The frames of a message.
+pub type Multipart = Vec<Vec<u8>>;
+
+/// Status frame that starts every reply over the pipe.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PipeStatus {
+    /// The command was done, and its result follows.
+    Ok,
+    /// There is nothing to return, e.g. the inbox is empty.
+    None,
+    /// Answer to `$PING`.
+    Pong,
+    /// Answer to `$STOP`, right before the actor stops.
+    Stopping,
+    /// The command is invalid, or can not be done.
+    WontDo,
+}
+
+impl PipeStatus {
+    /// Returns the frame of the status.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            PipeStatus::Ok => "$OK",
+            PipeStatus::None => "$NONE",
+            PipeStatus::Pong => "$PONG",
+            PipeStatus::Stopping => "$STOPPING",
+            PipeStatus::WontDo => "$WONTDO",
+        }
+    }
+
+    /// Parse a status frame.
+    pub fn from_bytes(frame: &[u8]) -> Option<PipeStatus> {
+        match frame {
+            b"$OK" => Some(PipeStatus::Ok),
+            b"$NONE" => Some(PipeStatus::None),
+            b"$PONG" => Some(PipeStatus::Pong),
+            b"$STOPPING" => Some(PipeStatus::Stopping),
+            b"$WONTDO" => Some(PipeStatus::WontDo),
+            _ => None,
+        }
+    }
+}
+
+/// A reply received over the pipe.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PipeReply {
+    pub status: PipeStatus,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl PipeReply {
+    /// Parse the frames of a reply.
+    pub fn from_frames(mut frames: Vec<Vec<u8>>) -> Result<PipeReply, ActorlingError> {
+        if frames.is_empty() {
+            return Err(ActorlingError::UnexpectedReply(String::new()));
+        }
+        let rest = frames.split_off(1);
+        match PipeStatus::from_bytes(&frames[0]) {
+            Some(status) => Ok(PipeReply {
+                status,
+                frames: rest,
+            }),
+            None => Err(ActorlingError::UnexpectedReply(
+                String::from_utf8_lossy(&frames[0]).to_string(),
+            )),
+        }
+    }
+
+    // Returns the frames of the reply if it has the `expected` status.
+    fn expect(self, expected: PipeStatus) -> Result<Vec<Vec<u8>>, ActorlingError> {
+        match self.status {
+            status if status == expected => Ok(self.frames),
+            PipeStatus::WontDo => Err(ActorlingError::InvalidCommand),
+            status => Err(ActorlingError::UnexpectedReply(status.as_str().to_string())),
+        }
+    }
+}
+
+/// Typed client for the pipe of a running actor, e.g. for tests, and supervisors.
+pub struct PipeClient<'a> {
+    pipe: &'a zmq::Socket,
+    timeout: i64,
+}
+
+impl<'a> PipeClient<'a> {
+    /// Create a new `PipeClient` on the creator's end of an actor pipe, see
+    /// `Actorling::pipe`. Replies are waited for forever.
+    pub fn new(pipe: &'a zmq::Socket) -> Self {
+        PipeClient { pipe, timeout: -1 }
+    }
+
+    /// Set the milliseconds to wait for each command to be sent, and for its reply, or `-1`
+    /// to wait forever. Commands that time out fail with `ActorlingError::NoReply`.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Send `command` with its arguments, and return the reply.
+    pub fn request<I, T>(&self, command: &str, args: I) -> Result<PipeReply, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut msg = vec![command.as_bytes().to_vec()];
+        msg.extend(args.into_iter().map(Into::into));
+        // sending blocks, instead of failing, once the actor has stopped.
+        if self.timeout >= 0 && self.pipe.poll(zmq::POLLOUT, self.timeout)? == 0 {
+            return Err(ActorlingError::NoReply.into());
+        }
+        self.pipe.send_multipart(msg, 0)?;
+        if self.timeout >= 0 && self.pipe.poll(zmq::POLLIN, self.timeout)? == 0 {
+            return Err(ActorlingError::NoReply.into());
+        }
+        Ok(PipeReply::from_frames(self.pipe.recv_multipart(0)?)?)
+    }
+
+    /// Ping the actor, which answers with the same `payload`.
+    pub fn ping<I, T>(&self, payload: I) -> Result<Vec<Vec<u8>>, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        Ok(self.request("$PING", payload)?.expect(PipeStatus::Pong)?)
+    }
+
+    /// Take the oldest message from the actor's inbox, if any.
+    pub fn pop(&self) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        let reply = self.request("$POP", Vec::<Vec<u8>>::new())?;
+        if reply.status == PipeStatus::None {
+            return Ok(None);
+        }
+        Ok(Some(reply.expect(PipeStatus::Ok)?))
+    }
+
+    /// Take up to `count` of the oldest messages from the actor's inbox, in one round trip.
+    /// Returns no messages if the inbox is empty.
+    pub fn pop_n(&self, count: usize) -> Result<Vec<Multipart>, Error> {
+        let reply = self.request(POPN, vec![count.to_string()])?;
+        if reply.status == PipeStatus::None {
+            return Ok(Vec::new());
+        }
+        let mut msgs = Vec::new();
+        for frame in reply.expect(PipeStatus::Ok)? {
+            msgs.push(unpack(&frame)?);
+        }
+        Ok(msgs)
+    }
+
+    /// Returns an iterator that takes every message from the actor's inbox, with a
+    /// `$POPN` of up to `batch` messages whenever the messages taken so far run out.
+    pub fn drain(&self, batch: usize) -> Drain<'a> {
+        Drain {
+            client: PipeClient {
+                pipe: self.pipe,
+                timeout: self.timeout,
+            },
+            batch: batch.max(1),
+            taken: VecDeque::new(),
+            done: false,
+            error: None,
+        }
+    }
+
+    /// Ask the actor for its `Health`.
+    pub fn health(&self) -> Result<Health, Error> {
+        let frames = self
+            .request(HEALTH, Vec::<Vec<u8>>::new())?
+            .expect(PipeStatus::Ok)?;
+        match frames.first() {
+            Some(frame) => Health::from_bytes(frame),
+            None => Err(ActorlingError::UnexpectedReply(String::new()).into()),
+        }
+    }
+
+    /// Forward `msg` to the running actor with `uuid`, in the same context.
+    pub fn forward<I, T>(&self, uuid: &str, msg: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut args = vec![uuid.as_bytes().to_vec()];
+        args.extend(msg.into_iter().map(Into::into));
+        self.request(FORWARD, args)?.expect(PipeStatus::Ok)?;
+        Ok(())
+    }
+
+    /// Send a reply on the actor's service socket.
+    pub fn reply<I, T>(&self, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.request("$REPLY", frames)?.expect(PipeStatus::Ok)?;
+        Ok(())
+    }
+
+    /// Stop the actor, waiting for it to confirm.
+    pub fn stop(&self) -> Result<(), Error> {
+        self.request("$STOP", Vec::<Vec<u8>>::new())?
+            .expect(PipeStatus::Stopping)?;
+        Ok(())
+    }
+}
+
+/// Iterator over the messages of an actor's inbox, see `PipeClient::drain`.
+pub struct Drain<'a> {
+    client: PipeClient<'a>,
+    batch: usize,
+    taken: VecDeque<Multipart>,
+    done: bool,
+    error: Option<Error>,
+}
+
+impl<'a> Drain<'a> {
+    /// Returns the error that ended the iteration early, if any.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+}
+
+impl<'a> Iterator for Drain<'a> {
+    type Item = Multipart;
+
+    fn next(&mut self) -> Option<Multipart> {
+        if self.taken.is_empty() && !self.done {
+            match self.client.pop_n(self.batch) {
+                // a short batch means that the inbox is empty.
+                Ok(msgs) => {
+                    self.done = msgs.len() < self.batch;
+                    self.taken.extend(msgs);
+                }
+                Err(e) => {
+                    self.done = true;
+                    self.error = Some(e);
+                }
+            }
+        }
+        self.taken.pop_front()
+    }
+}
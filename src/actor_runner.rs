@@ -0,0 +1,322 @@
+//! Actors that implement their behavior, instead of a poll loop.
+//!
+//! An `Actor` is driven by an `ActorRunner`, which starts its `Actorling` as usual, and
+//! hands every message of the service socket to `Actor::on_message`, from the same poll
+//! loop that answers the pipe. Commands, e.g. `$HEALTH`, and `$STOP`, are still answered by
+//! the loop, so the `Actorling` of the runner is used to control the actor.
+//!
+//! The messages of each batch received are handed over by `Priority`, see `Mailbox`.
+//! Messages sent with `ask` to `ServiceKind::Router` actors are handed over without their
+//! envelope, and their reply, sent with `ActorContext::reply`, goes back to the asker.
+//!
+//! ```
+//! extern crate failure;
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::actor::{Actor, ActorContext, ActorRunner, Actorling, ServiceKind};
+//!
+//! struct Echo;
+//!
+//! impl Actor for Echo {
+//!     fn on_message(
+//!         &mut self,
+//!         msg: Vec<Vec<u8>>,
+//!         ctx: &mut ActorContext,
+//!     ) -> Result<(), failure::Error> {
+//!         ctx.reply(msg)
+//!     }
+//! }
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let actorling =
+//!     Actorling::new_with_service("inproc://echo", ctx.clone(), ServiceKind::Rep).unwrap();
+//! let runner = ActorRunner::new(actorling);
+//! let handle = runner.start(Echo).unwrap();
+//! runner.actorling().recv_endpoints().unwrap();
+//!
+//! let client = ctx.socket(zmq::REQ).unwrap();
+//! client.connect("inproc://echo").unwrap();
+//! client.send("hello", 0).unwrap();
+//! assert_eq!(client.recv_bytes(0).unwrap(), b"hello");
+//!
+//! runner.actorling().stop().unwrap();
+//! assert!(handle.join().unwrap().unwrap().is_clean());
+//! # }
+//! ```
+use super::super::socket::{Identity, SocketWrapper};
+use super::ask::Asked;
+use super::{poll_service, Actorling, ActorlingError, ServiceKind, ShutdownToken};
+use super::{ActorExit, ExitReason, Forwarder, LoopSockets, Service, Sibling, TopologyEvent};
+
+use failure::Error;
+use std::io;
+use std::thread;
+use zmq;
+
+/// Behavior of an actor, that is run by an `ActorRunner`.
+pub trait Actor: Send + 'static {
+    /// Called once the service socket is bound, before any message is received. Errors
+    /// stop the actor before it runs.
+    fn on_start(&mut self, _ctx: &mut ActorContext) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called for each message received on the service socket. Errors are logged, and
+    /// reported as the last error of the actor's `Health`.
+    fn on_message(&mut self, msg: Vec<Vec<u8>>, ctx: &mut ActorContext) -> Result<(), Error>;
+
+    /// Called for each change in the siblings of the actor, if its `Actorling` was created
+    /// `with_topology_events`, instead of `on_message`. Errors are handled as those of
+    /// `on_message`.
+    fn on_topology(&mut self, _event: TopologyEvent, _ctx: &mut ActorContext) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called once the actor stops, whatever the reason.
+    fn on_stop(&mut self) {}
+}
+
+/// What an `Actor` can do while it runs.
+pub struct ActorContext<'a> {
+    service: &'a zmq::Socket,
+    kind: &'a ServiceKind,
+    sibling: &'a Sibling,
+    context: &'a zmq::Context,
+    asked: Option<Asked>,
+    stopping: bool,
+}
+
+impl<'a> ActorContext<'a> {
+    /// Returns the UUID of the actor.
+    pub fn uuid(&self) -> &str {
+        &self.sibling.uuid
+    }
+
+    /// Returns the name of the actor, or its UUID if it was not named.
+    pub fn name(&self) -> &str {
+        &self.sibling.name
+    }
+
+    /// Returns the endpoints that the service socket is bound to.
+    pub fn endpoints(&self) -> &[String] {
+        &self.sibling.endpoints
+    }
+
+    /// Returns the actor's network context, e.g. to reach other actors.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Returns true if the current message was sent with `ask`.
+    pub fn is_ask(&self) -> bool {
+        self.asked.is_some()
+    }
+
+    /// Send a reply to the current message. Replies to asks go back to the asker, other
+    /// replies are sent on the service socket, for `ServiceKind::Rep` and
+    /// `ServiceKind::Router` actors. Router replies must start with the identity of the
+    /// requester.
+    pub fn reply<I, T>(&self, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let frames: Vec<Vec<u8>> = frames.into_iter().map(Into::into).collect();
+        if let Some(ref asked) = self.asked {
+            return asked.reply(self.service, frames);
+        }
+        if !self.kind.replies() {
+            return Err(ActorlingError::InvalidCommand.into());
+        }
+        self.service.send_multipart(frames, zmq::DONTWAIT)?;
+        Ok(())
+    }
+
+    /// Send `frames` on the service socket of a `ServiceKind::Router` actor, to the
+    /// requester with `identity`, e.g. the sender of a message that is handled later.
+    pub fn reply_to<I, T>(&self, identity: &Identity, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        if *self.kind != ServiceKind::Router {
+            return Err(ActorlingError::InvalidCommand.into());
+        }
+        self.service
+            .send_multipart(identity.route(frames), zmq::DONTWAIT)?;
+        Ok(())
+    }
+
+    /// Stop the actor, once the current message is handled.
+    pub fn stop(&mut self) {
+        self.stopping = true;
+    }
+}
+
+/// Runs an `Actor`, on top of an `Actorling`.
+pub struct ActorRunner {
+    actorling: Actorling,
+}
+
+impl ActorRunner {
+    /// Create a new `ActorRunner`, for actors that live on the address of `actorling`.
+    pub fn new(actorling: Actorling) -> ActorRunner {
+        ActorRunner { actorling }
+    }
+
+    /// Returns the `Actorling` that the actor lives on, e.g. to control it over its pipe.
+    pub fn actorling(&self) -> &Actorling {
+        &self.actorling
+    }
+
+    /// Start `actor` on a thread of its own.
+    pub fn start<A: Actor>(
+        &self,
+        actor: A,
+    ) -> Result<thread::JoinHandle<Result<ActorExit, Error>>, io::Error> {
+        self.start_with_shutdown(actor, ShutdownToken::default())
+    }
+
+    /// Start `actor`, which will also stop when the `token` is signaled by its
+    /// `ShutdownController`.
+    pub fn start_with_shutdown<A: Actor>(
+        &self,
+        mut actor: A,
+        token: ShutdownToken,
+    ) -> Result<thread::JoinHandle<Result<ActorExit, Error>>, io::Error> {
+        self.actorling
+            .start_service(token, move |service| run_actor(&mut actor, service))
+    }
+}
+
+// Run `actor` on the sockets of `service`, until it stops.
+fn run_actor<A: Actor>(actor: &mut A, service: Service) -> Result<ActorExit, Error> {
+    let Service {
+        pipe,
+        socket,
+        forward,
+        topology,
+        kind,
+        sibling,
+        context,
+        token,
+        mailbox: mut mbox,
+        sockets: extra,
+        middleware,
+    } = service;
+    let mut ctx = ActorContext {
+        service: &socket,
+        kind: &kind,
+        sibling: &sibling,
+        context: &context,
+        asked: None,
+        stopping: false,
+    };
+    let started = actor.on_start(&mut ctx);
+    if let Err(e) = started {
+        actor.on_stop();
+        return Err(e);
+    }
+    if ctx.stopping {
+        actor.on_stop();
+        return Ok(ActorExit::new(ExitReason::Finished));
+    }
+    let sockets = LoopSockets {
+        pipe,
+        service: socket,
+        forward: Some((forward, Forwarder::new(context.clone()))),
+        topology,
+        extra,
+        middleware,
+    };
+    let result = poll_service(
+        sockets,
+        &kind,
+        &mut mbox,
+        10,
+        &token,
+        Some(&mut |service, msg| {
+            let (asked, msg) = Asked::parse(&kind, msg);
+            let mut ctx = ActorContext {
+                service: service.get_socket_ref(),
+                kind: &kind,
+                sibling: &sibling,
+                context: &context,
+                asked,
+                stopping: false,
+            };
+            match TopologyEvent::from_message(&msg) {
+                Some(event) => actor.on_topology(event, &mut ctx)?,
+                None => actor.on_message(msg, &mut ctx)?,
+            }
+            Ok(!ctx.stopping)
+        }),
+    );
+    actor.on_stop();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct Counter {
+        started: Arc<AtomicBool>,
+        received: Arc<AtomicUsize>,
+        stopped: Arc<AtomicBool>,
+    }
+
+    impl Actor for Counter {
+        fn on_start(&mut self, ctx: &mut ActorContext) -> Result<(), Error> {
+            assert_eq!(ctx.name(), "counter");
+            self.started.store(true, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn on_message(&mut self, msg: Vec<Vec<u8>>, ctx: &mut ActorContext) -> Result<(), Error> {
+            let count = self.received.fetch_add(1, Ordering::SeqCst) + 1;
+            ctx.reply(vec![count.to_string()])?;
+            if msg == vec![b"bye".to_vec()] {
+                ctx.stop();
+            }
+            Ok(())
+        }
+
+        fn on_stop(&mut self) {
+            self.stopped.store(true, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn runners_hand_messages_to_actors_until_they_stop() {
+        let ctx = zmq::Context::new();
+        let actorling =
+            Actorling::new_with_service("inproc://runner_counter", ctx.clone(), ServiceKind::Rep)
+                .unwrap()
+                .with_name("counter");
+        let runner = ActorRunner::new(actorling);
+        let counter = Counter {
+            started: Arc::new(AtomicBool::new(false)),
+            received: Arc::new(AtomicUsize::new(0)),
+            stopped: Arc::new(AtomicBool::new(false)),
+        };
+        let (started, stopped) = (counter.started.clone(), counter.stopped.clone());
+        let handle = runner.start(counter).unwrap();
+        runner.actorling().recv_endpoints().unwrap();
+
+        let client = ctx.socket(zmq::REQ).unwrap();
+        client.connect("inproc://runner_counter").unwrap();
+        client.send("hello", 0).unwrap();
+        assert_eq!(client.recv_bytes(0).unwrap(), b"1");
+        assert!(started.load(Ordering::SeqCst));
+        assert_eq!(runner.actorling().health().unwrap().inbox, 0);
+        client.send("bye", 0).unwrap();
+        assert_eq!(client.recv_bytes(0).unwrap(), b"2");
+        assert!(handle.join().unwrap().unwrap().is_clean());
+        assert!(stopped.load(Ordering::SeqCst));
+    }
+}
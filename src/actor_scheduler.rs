@@ -0,0 +1,268 @@
+//! Delayed, and periodic, messages to actors.
+//!
+//! A `Scheduler` runs the timers of a `Poller` on a thread of its own, and sends each
+//! scheduled message to its actor when its timer is due, instead of every caller sleeping on
+//! a thread until then. Messages are pushed next to the pipe of the actor, where it receives
+//! the messages forwarded to it, see `Actorling::send_to`, and not to its service socket,
+//! which is shared with its clients; tag them with `Priority` for them not to wait behind
+//! other messages.
+use super::super::poller::{Handle, Poller, Waker};
+use super::super::utils::run_named_thread;
+use super::directory;
+use super::Actorling;
+
+use failure::Error;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use zmq;
+
+// A message, and the socket that it is sent on, each time its timer is due.
+struct Job {
+    socket: zmq::Socket,
+    frames: Vec<Vec<u8>>,
+    repeats: bool,
+}
+
+enum Command {
+    Add {
+        id: usize,
+        job: Job,
+        delay: i64,
+        interval: Option<i64>,
+    },
+    Cancel(usize),
+    Stop,
+}
+
+// What the callers of a scheduler share with its thread.
+#[derive(Clone)]
+struct Commands {
+    sender: mpsc::Sender<Command>,
+    waker: Waker,
+}
+
+impl Commands {
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.sender
+            .send(command)
+            .map_err(|_| format_err!("scheduler is not running"))?;
+        self.waker.wake()?;
+        Ok(())
+    }
+}
+
+/// Sends messages to actors after a delay, or on an interval.
+pub struct Scheduler {
+    commands: Commands,
+    next_id: AtomicUsize,
+    thread: Option<thread::JoinHandle<Result<(), Error>>>,
+}
+
+impl Scheduler {
+    /// Create a new `Scheduler`, and start its thread, which runs until it is dropped.
+    pub fn new() -> Result<Scheduler, Error> {
+        let (sender, receiver) = mpsc::channel();
+        let (waker_tx, waker_rx) = mpsc::channel();
+        let thread = run_named_thread("scheduler", move || {
+            let mut poller = Poller::new();
+            waker_tx.send(poller.waker()?)?;
+            run_scheduler(&mut poller, &receiver)
+        })?;
+        let waker = match waker_rx.recv() {
+            Ok(waker) => waker,
+            Err(_) => {
+                return Err(thread
+                    .join()
+                    .map_err(|_| format_err!("scheduler panicked"))?
+                    .err()
+                    .unwrap_or_else(|| format_err!("scheduler stopped")))
+            }
+        };
+        Ok(Scheduler {
+            commands: Commands { sender, waker },
+            next_id: AtomicUsize::new(0),
+            thread: Some(thread),
+        })
+    }
+
+    /// Send `frames` to the actor of `actor` once, in `delay` milliseconds.
+    pub fn send_after<I, T>(
+        &self,
+        actor: &Actorling,
+        frames: I,
+        delay: i64,
+    ) -> Result<Scheduled, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.schedule(actor, frames, delay, None)
+    }
+
+    /// Send `frames` to the actor of `actor` every `interval` milliseconds, starting in
+    /// `interval` milliseconds, until it is cancelled. Intervals that are missed are skipped.
+    pub fn send_every<I, T>(
+        &self,
+        actor: &Actorling,
+        frames: I,
+        interval: i64,
+    ) -> Result<Scheduled, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.schedule(actor, frames, interval, Some(interval))
+    }
+
+    fn schedule<I, T>(
+        &self,
+        actor: &Actorling,
+        frames: I,
+        delay: i64,
+        interval: Option<i64>,
+    ) -> Result<Scheduled, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let socket = actor.context().socket(zmq::PUSH)?;
+        socket.set_linger(0)?;
+        socket.connect(&directory::forward_endpoint(&actor.pipe_endpoint()))?;
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let job = Job {
+            socket,
+            frames: frames.into_iter().map(Into::into).collect(),
+            repeats: interval.is_some(),
+        };
+        self.commands.send(Command::Add {
+            id,
+            job,
+            delay,
+            interval,
+        })?;
+        Ok(Scheduled {
+            id,
+            commands: self.commands.clone(),
+        })
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        let _ = self.commands.send(Command::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Handle of a scheduled message, to cancel it.
+pub struct Scheduled {
+    id: usize,
+    commands: Commands,
+}
+
+impl Scheduled {
+    /// Cancel the message, once the scheduler gets to it, so a message that is already
+    /// due may still be sent. Dropping the handle does not cancel it.
+    pub fn cancel(&self) -> Result<(), Error> {
+        self.commands.send(Command::Cancel(self.id))
+    }
+}
+
+// Run the timers of the scheduled jobs, until the scheduler stops.
+fn run_scheduler(poller: &mut Poller, commands: &mpsc::Receiver<Command>) -> Result<(), Error> {
+    let mut jobs: HashMap<Handle, Job> = HashMap::new();
+    let mut timers: HashMap<usize, Handle> = HashMap::new();
+    loop {
+        for event in poller.run_once(None)? {
+            if event.handle == Handle::WAKER {
+                // wakeups are merged, so every pending command is handled.
+                for command in commands.try_iter() {
+                    match command {
+                        Command::Add {
+                            id,
+                            job,
+                            delay,
+                            interval,
+                        } => {
+                            let timer = poller.add_timer(delay, interval);
+                            jobs.insert(timer, job);
+                            timers.insert(id, timer);
+                        }
+                        Command::Cancel(id) => {
+                            if let Some(timer) = timers.remove(&id) {
+                                poller.cancel_timer(timer);
+                                jobs.remove(&timer);
+                            }
+                        }
+                        Command::Stop => return Ok(()),
+                    }
+                }
+                continue;
+            }
+            let repeats = match jobs.get(&event.handle) {
+                Some(job) => {
+                    if let Err(e) = job.socket.send_multipart(&job.frames, zmq::DONTWAIT) {
+                        nlog!(warn, "scheduled message not sent error={}", e);
+                    }
+                    job.repeats
+                }
+                None => continue,
+            };
+            if !repeats {
+                jobs.remove(&event.handle);
+                timers.retain(|_, timer| *timer != event.handle);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    // Pop the next message from the actorling, waiting for it to arrive.
+    fn pop_next(acty: &Actorling) -> Vec<u8> {
+        for _ in 0..100 {
+            if let Some(msg) = acty.pop().unwrap() {
+                return msg[0].to_vec();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("no message arrived");
+    }
+
+    #[test]
+    fn scheduled_messages_arrive_in_order_until_cancelled() {
+        let acty = Actorling::new("inproc://scheduled").unwrap();
+        acty.start().unwrap();
+        acty.recv_endpoints().unwrap();
+        let scheduler = Scheduler::new().unwrap();
+
+        scheduler.send_after(&acty, vec!["late"], 200).unwrap();
+        scheduler.send_after(&acty, vec!["early"], 10).unwrap();
+        assert_eq!(pop_next(&acty), b"early");
+        assert_eq!(pop_next(&acty), b"late");
+
+        let every = scheduler.send_every(&acty, vec!["tick"], 5).unwrap();
+        for _ in 0..3 {
+            assert_eq!(pop_next(&acty), b"tick");
+        }
+        every.cancel().unwrap();
+        scheduler.send_after(&acty, vec!["cancelled"], 0).unwrap();
+        // ticks that were sent before the cancellation may still arrive.
+        while pop_next(&acty) != b"cancelled" {}
+        thread::sleep(Duration::from_millis(20));
+        while let Some(msg) = acty.pop().unwrap() {
+            assert_eq!(msg[0].as_str(), Some("tick"));
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(acty.pop().unwrap().is_none());
+        drop(scheduler);
+        acty.stop().unwrap();
+    }
+}
@@ -0,0 +1,146 @@
+//! Sockets, other than the service socket, that actors poll.
+//!
+//! An `ActorSocket` is created, bound, and connected, on the thread of the actor when it
+//! starts, and polled alongside its pipe and its service socket, e.g. a `SUB` socket for
+//! broadcasts, next to a `PULL` service socket for work. Its messages are not queued in
+//! the inbox, but handed to the handler of the socket, as they arrive.
+use super::super::endpoint::{Endpoint, ToEndpoint};
+use super::super::socket::SocketBuilder;
+
+use failure::Error;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use zmq;
+
+/// Handles each message received on an `ActorSocket`, with the socket it arrived on, e.g.
+/// to reply on a `ROUTER`. Errors are logged, and kept as the last error of the actor.
+pub type SocketHandler = dyn FnMut(&zmq::Socket, Vec<Vec<u8>>) -> Result<(), Error> + Send;
+
+/// A socket that an actor polls, with the handler of its messages.
+#[derive(Clone)]
+pub struct ActorSocket {
+    name: String,
+    kind: zmq::SocketType,
+    binds: Vec<Endpoint>,
+    connects: Vec<Endpoint>,
+    subscriptions: Vec<Vec<u8>>,
+    handler: Arc<Mutex<Box<SocketHandler>>>,
+}
+
+impl ActorSocket {
+    /// Create a socket of `kind`, known by `name`, whose messages are handled by `handler`.
+    pub fn new<F>(name: &str, kind: zmq::SocketType, handler: F) -> ActorSocket
+    where
+        F: FnMut(&zmq::Socket, Vec<Vec<u8>>) -> Result<(), Error> + Send + 'static,
+    {
+        ActorSocket {
+            name: name.to_string(),
+            kind,
+            binds: Vec::new(),
+            connects: Vec::new(),
+            subscriptions: Vec::new(),
+            handler: Arc::new(Mutex::new(Box::new(handler))),
+        }
+    }
+
+    /// Add an endpoint to bind to, when the actor starts.
+    pub fn bind<E: ToEndpoint>(mut self, endpoint: E) -> Result<Self, Error> {
+        self.binds.push(endpoint.to_endpoint()?);
+        Ok(self)
+    }
+
+    /// Add an endpoint to connect to, when the actor starts.
+    pub fn connect<E: ToEndpoint>(mut self, endpoint: E) -> Result<Self, Error> {
+        self.connects.push(endpoint.to_endpoint()?);
+        Ok(self)
+    }
+
+    /// Subscribe a `SUB` socket to messages that start with `prefix`, or to every message
+    /// if it is empty.
+    pub fn subscribe(mut self, prefix: &[u8]) -> Self {
+        self.subscriptions.push(prefix.to_vec());
+        self
+    }
+
+    /// Returns the name of the socket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the kind of socket.
+    pub fn kind(&self) -> zmq::SocketType {
+        self.kind
+    }
+
+    /// Create the socket in `context`, and bind, connect, and subscribe it.
+    pub fn open(&self, context: &zmq::Context) -> Result<OpenSocket, Error> {
+        let mut builder = SocketBuilder::new(context, self.kind).linger(0);
+        for endpoint in &self.binds {
+            builder = builder.bind(endpoint.clone())?;
+        }
+        for endpoint in &self.connects {
+            builder = builder.connect(endpoint.clone())?;
+        }
+        let socket = builder.build()?;
+        for prefix in &self.subscriptions {
+            socket.set_subscribe(prefix)?;
+        }
+        Ok(OpenSocket {
+            name: self.name.clone(),
+            socket,
+            handler: Arc::clone(&self.handler),
+        })
+    }
+}
+
+impl fmt::Debug for ActorSocket {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ActorSocket")
+            .field("name", &self.name)
+            .field("kind", &self.kind)
+            .field("binds", &self.binds)
+            .field("connects", &self.connects)
+            .finish()
+    }
+}
+
+/// An `ActorSocket` opened on the thread of its actor.
+pub struct OpenSocket {
+    name: String,
+    socket: zmq::Socket,
+    handler: Arc<Mutex<Box<SocketHandler>>>,
+}
+
+impl OpenSocket {
+    /// Returns the name of the socket.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns a reference to the underlying socket.
+    pub fn socket(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Receive the messages waiting on the socket, up to `batch`, and hand each to the
+    /// handler. Returns the errors of the handler, without stopping at them, or fails if
+    /// the socket can't be read.
+    pub fn dispatch(&self, batch: usize) -> Result<Vec<Error>, zmq::Error> {
+        let mut handler = match self.handler.lock() {
+            Ok(handler) => handler,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        let mut errors = Vec::new();
+        for _ in 0..batch {
+            let msg = match self.socket.recv_multipart(zmq::DONTWAIT) {
+                Ok(msg) => msg,
+                Err(zmq::Error::EAGAIN) => break,
+                Err(e) => return Err(e),
+            };
+            if let Err(e) = handler(&self.socket, msg) {
+                errors.push(e);
+            }
+        }
+        Ok(errors)
+    }
+}
@@ -0,0 +1,117 @@
+//! Spooling of the actor inbox to disk.
+//!
+//! A spooled inbox journals every message it queues in an `Outbox`, and confirms it once it
+//! is taken, i.e. popped over the pipe, handed to an `Actor`, or drained when the actor
+//! exits. Messages that were queued, but never taken, e.g. because the actor thread
+//! panicked, are recovered into the inbox when the actor starts again with the same spool.
+use super::super::socket::{Confirm, Outbox, OutboxConfig, OutboxError};
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+/// Default maximum number of messages kept in a spool.
+pub const DEFAULT_SPOOL_LIMIT: usize = 10_000;
+
+/// Configuration of the spool of an actor inbox.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpoolConfig {
+    /// Path to the journal of the spool.
+    pub path: PathBuf,
+    /// Most messages kept in the spool. Messages queued while it is full are not spooled,
+    /// and are lost if the actor crashes.
+    pub max_messages: usize,
+    /// Size of the journal, in bytes, after which it is rotated.
+    pub max_bytes: u64,
+    /// Whether to flush every journal record to disk before going on.
+    pub sync: bool,
+}
+
+impl SpoolConfig {
+    /// Create the configuration of a spool journaled at `path`, with the default limits,
+    /// that flushes every record to disk.
+    pub fn new<P: AsRef<Path>>(path: P) -> SpoolConfig {
+        let outbox = OutboxConfig::default();
+        SpoolConfig {
+            path: path.as_ref().to_path_buf(),
+            max_messages: DEFAULT_SPOOL_LIMIT,
+            max_bytes: outbox.max_bytes,
+            sync: outbox.sync,
+        }
+    }
+}
+
+/// Journal of the messages queued in an inbox, and not yet taken.
+pub struct Spool {
+    outbox: Outbox,
+    max_messages: usize,
+}
+
+impl Spool {
+    /// Open the spool of `config`, creating its journal if needed, and recover the messages
+    /// that it kept.
+    pub fn open(config: &SpoolConfig) -> Result<Spool, OutboxError> {
+        let outbox = Outbox::open(
+            &config.path,
+            OutboxConfig {
+                confirm: Confirm::Manual,
+                max_bytes: config.max_bytes,
+                sync: config.sync,
+            },
+        )?;
+        Ok(Spool {
+            outbox,
+            max_messages: config.max_messages,
+        })
+    }
+
+    /// Returns the messages in the spool, with their sequence numbers, in the order they
+    /// were queued.
+    pub fn pending(&self) -> Vec<(u64, Vec<Vec<u8>>)> {
+        self.outbox.pending()
+    }
+
+    /// Returns the path to the journal.
+    pub fn path(&self) -> &Path {
+        self.outbox.path()
+    }
+
+    /// Returns the number of messages in the spool.
+    pub fn len(&self) -> usize {
+        self.outbox.len()
+    }
+
+    /// Returns true if there are no messages in the spool.
+    pub fn is_empty(&self) -> bool {
+        self.outbox.is_empty()
+    }
+
+    /// Journal `msg`, returning its sequence number, or `None` if the spool is full.
+    pub fn push(&mut self, msg: &[Vec<u8>]) -> Result<Option<u64>, OutboxError> {
+        if self.outbox.len() >= self.max_messages {
+            return Ok(None);
+        }
+        self.outbox.journal(msg.to_vec()).map(Some)
+    }
+
+    /// Confirm that the message with the `sequence` number was taken from the inbox.
+    pub fn confirm(&mut self, sequence: u64) -> Result<(), OutboxError> {
+        self.outbox.confirm(sequence)
+    }
+}
+
+impl fmt::Debug for Spool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Spool")
+            .field("path", &self.path())
+            .field("len", &self.len())
+            .field("max_messages", &self.max_messages)
+            .finish()
+    }
+}
+
+// Spools are equal when they journal to the same file.
+impl PartialEq for Spool {
+    fn eq(&self, other: &Spool) -> bool {
+        self.path() == other.path()
+    }
+}
@@ -0,0 +1,741 @@
+//! Reliable request-reply brokers.
+//!
+//! An implementation of the
+//! "[Majordomo Protocol](https://rfc.zeromq.org/spec/7/)" (MDP), as described in
+//! "[Service-Oriented Reliable Queuing](http://zguide.zeromq.org/page:all#toc111)".
+//!
+//! A `Broker` runs on a child thread, listening on a `ROUTER` socket for both clients and
+//! workers. Workers register for a named service, and the broker routes client requests to
+//! idle workers for that service, keeping track of their liveness with heartbeats.
+//!
+//! `Client` and `Worker` are blocking APIs that speak the protocol to the broker.
+use super::clock::Clock;
+use super::utils::run_named_thread;
+
+use failure::Error;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// Protocol header for MDP clients.
+pub const MDPC_CLIENT: &str = "MDPC01";
+/// Protocol header for MDP workers.
+pub const MDPW_WORKER: &str = "MDPW01";
+
+/// Worker command announcing it is ready to serve.
+pub const MDPW_READY: &[u8] = b"\x01";
+/// Worker command carrying a client request.
+pub const MDPW_REQUEST: &[u8] = b"\x02";
+/// Worker command carrying a reply for a client.
+pub const MDPW_REPLY: &[u8] = b"\x03";
+/// Worker command signaling liveness.
+pub const MDPW_HEARTBEAT: &[u8] = b"\x04";
+/// Worker command signaling the end of the connection.
+pub const MDPW_DISCONNECT: &[u8] = b"\x05";
+
+/// Number of missed heartbeats before a peer is considered dead.
+pub const HEARTBEAT_LIVENESS: usize = 3;
+/// Milliseconds between heartbeats.
+pub const HEARTBEAT_INTERVAL: i64 = 2_500;
+/// Milliseconds before a silent worker expires.
+pub const HEARTBEAT_EXPIRY: i64 = HEARTBEAT_INTERVAL * HEARTBEAT_LIVENESS as i64;
+
+/// Broker Errors.
+#[derive(Debug, Fail)]
+pub enum BrokerError {
+    #[fail(display = "broker was interrupted")]
+    Interrupted,
+    #[fail(display = "invalid protocol message")]
+    InvalidMessage,
+    #[fail(display = "no reply after {} attempts", _0)]
+    NoReply(usize),
+}
+
+// A worker known to the broker.
+struct WorkerEntry {
+    identity: Vec<u8>,
+    service: Option<String>,
+    expiry: i64,
+}
+
+// A named service, with pending requests and idle workers.
+#[derive(Default)]
+struct Service {
+    requests: VecDeque<Vec<Vec<u8>>>,
+    waiting: VecDeque<Vec<u8>>,
+}
+
+// Broker state, owned by the broker thread.
+struct BrokerState {
+    clock: Clock,
+    services: HashMap<String, Service>,
+    workers: HashMap<Vec<u8>, WorkerEntry>,
+    waiting: VecDeque<Vec<u8>>,
+}
+
+impl BrokerState {
+    fn new() -> Self {
+        BrokerState {
+            clock: Clock::new(),
+            services: HashMap::new(),
+            workers: HashMap::new(),
+            waiting: VecDeque::new(),
+        }
+    }
+
+    // Process a message sent by a client: `[sender, "", MDPC01, service, body...]`.
+    fn client_msg(
+        &mut self,
+        socket: &zmq::Socket,
+        sender: Vec<u8>,
+        mut msg: VecDeque<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let service = match msg.pop_front() {
+            Some(s) => String::from_utf8_lossy(&s).to_string(),
+            None => return Err(BrokerError::InvalidMessage.into()),
+        };
+        if service.starts_with("mmi.") {
+            let status: &[u8] = if service == "mmi.service" {
+                let name = msg
+                    .front()
+                    .map(|n| String::from_utf8_lossy(n).to_string())
+                    .unwrap_or_default();
+                // a service is known while it has workers, idle or busy.
+                let known = self
+                    .workers
+                    .values()
+                    .any(|worker| worker.service.as_ref() == Some(&name));
+                if known {
+                    b"200"
+                } else {
+                    b"404"
+                }
+            } else {
+                b"501"
+            };
+            let reply: Vec<&[u8]> = vec![
+                &sender,
+                b"",
+                MDPC_CLIENT.as_bytes(),
+                service.as_bytes(),
+                status,
+            ];
+            socket.send_multipart(reply, 0)?;
+            return Ok(());
+        }
+        let mut request = vec![sender, Vec::new()];
+        request.extend(msg);
+        self.services
+            .entry(service.clone())
+            .or_default()
+            .requests
+            .push_back(request);
+        self.dispatch(socket, &service)
+    }
+
+    // Process a message sent by a worker: `[sender, "", MDPW01, command, ...]`.
+    fn worker_msg(
+        &mut self,
+        socket: &zmq::Socket,
+        sender: Vec<u8>,
+        mut msg: VecDeque<Vec<u8>>,
+    ) -> Result<(), Error> {
+        let command = match msg.pop_front() {
+            Some(c) => c,
+            None => return Err(BrokerError::InvalidMessage.into()),
+        };
+        let known = self.workers.contains_key(&sender);
+        match &command[..] {
+            MDPW_READY => {
+                let service = match msg.pop_front() {
+                    Some(s) => String::from_utf8_lossy(&s).to_string(),
+                    None => return Err(BrokerError::InvalidMessage.into()),
+                };
+                if known || service.starts_with("mmi.") {
+                    self.delete_worker(socket, &sender, true)?;
+                } else {
+                    self.workers.insert(
+                        sender.clone(),
+                        WorkerEntry {
+                            identity: sender.clone(),
+                            service: Some(service.clone()),
+                            expiry: 0,
+                        },
+                    );
+                    self.worker_waiting(&sender);
+                    self.dispatch(socket, &service)?;
+                }
+            }
+            MDPW_REPLY => {
+                if !known {
+                    return self.delete_worker(socket, &sender, true);
+                }
+                let client = match msg.pop_front() {
+                    Some(c) => c,
+                    None => return Err(BrokerError::InvalidMessage.into()),
+                };
+                // drop the empty delimiter
+                msg.pop_front();
+                let service = self.workers[&sender].service.clone().unwrap_or_default();
+                let mut reply = vec![
+                    client,
+                    Vec::new(),
+                    MDPC_CLIENT.as_bytes().to_vec(),
+                    service.as_bytes().to_vec(),
+                ];
+                reply.extend(msg);
+                socket.send_multipart(reply, 0)?;
+                self.worker_waiting(&sender);
+                self.dispatch(socket, &service)?;
+            }
+            MDPW_HEARTBEAT => {
+                if known {
+                    let expiry = self.clock.mono() + HEARTBEAT_EXPIRY;
+                    if let Some(worker) = self.workers.get_mut(&sender) {
+                        worker.expiry = expiry;
+                    }
+                } else {
+                    self.delete_worker(socket, &sender, true)?;
+                }
+            }
+            MDPW_DISCONNECT => self.delete_worker(socket, &sender, false)?,
+            _ => return Err(BrokerError::InvalidMessage.into()),
+        }
+        Ok(())
+    }
+
+    // Mark a worker as idle, and ready for new requests.
+    fn worker_waiting(&mut self, identity: &[u8]) {
+        let expiry = self.clock.mono() + HEARTBEAT_EXPIRY;
+        let service = match self.workers.get_mut(identity) {
+            Some(worker) => {
+                worker.expiry = expiry;
+                worker.service.clone()
+            }
+            None => return,
+        };
+        self.waiting.push_back(identity.to_vec());
+        if let Some(name) = service {
+            self.services
+                .entry(name)
+                .or_default()
+                .waiting
+                .push_back(identity.to_vec());
+        }
+    }
+
+    // Remove a worker, optionally telling it to disconnect.
+    fn delete_worker(
+        &mut self,
+        socket: &zmq::Socket,
+        identity: &[u8],
+        disconnect: bool,
+    ) -> Result<(), Error> {
+        if disconnect {
+            send_to_worker(socket, identity, MDPW_DISCONNECT, None)?;
+        }
+        if let Some(worker) = self.workers.remove(identity) {
+            if let Some(ref name) = worker.service {
+                if let Some(service) = self.services.get_mut(name) {
+                    service.waiting.retain(|w| w[..] != worker.identity[..]);
+                }
+            }
+        }
+        self.waiting.retain(|w| w[..] != identity[..]);
+        Ok(())
+    }
+
+    // Send pending requests for a service to its idle workers.
+    fn dispatch(&mut self, socket: &zmq::Socket, name: &str) -> Result<(), Error> {
+        self.purge(socket)?;
+        let service = match self.services.get_mut(name) {
+            Some(s) => s,
+            None => return Ok(()),
+        };
+        while !service.waiting.is_empty() && !service.requests.is_empty() {
+            let worker = service.waiting.pop_front().unwrap();
+            let request = service.requests.pop_front().unwrap();
+            self.waiting.retain(|w| w[..] != worker[..]);
+            send_to_worker(socket, &worker, MDPW_REQUEST, Some(request))?;
+        }
+        Ok(())
+    }
+
+    // Remove workers that have not sent heartbeats in time.
+    fn purge(&mut self, socket: &zmq::Socket) -> Result<(), Error> {
+        let now = self.clock.mono();
+        let expired: Vec<Vec<u8>> = self
+            .waiting
+            .iter()
+            .filter(|w| self.workers.get(*w).is_none_or(|e| e.expiry < now))
+            .cloned()
+            .collect();
+        for identity in expired {
+            self.delete_worker(socket, &identity, false)?;
+        }
+        Ok(())
+    }
+
+    // Send heartbeats to idle workers.
+    fn heartbeat(&mut self, socket: &zmq::Socket) -> Result<(), Error> {
+        for identity in &self.waiting {
+            send_to_worker(socket, identity, MDPW_HEARTBEAT, None)?;
+        }
+        Ok(())
+    }
+}
+
+// Send a command to a worker: `[identity, "", MDPW01, command, body...]`.
+fn send_to_worker(
+    socket: &zmq::Socket,
+    identity: &[u8],
+    command: &[u8],
+    body: Option<Vec<Vec<u8>>>,
+) -> Result<(), Error> {
+    let mut msg = vec![
+        identity.to_vec(),
+        Vec::new(),
+        MDPW_WORKER.as_bytes().to_vec(),
+        command.to_vec(),
+    ];
+    if let Some(body) = body {
+        msg.extend(body);
+    }
+    socket.send_multipart(msg, 0)?;
+    Ok(())
+}
+
+/// A Majordomo broker, routing requests from clients to workers by service name.
+pub struct Broker {
+    address: String,
+    context: zmq::Context,
+    pipe: zmq::Socket,
+    uuid: Uuid,
+}
+
+impl Broker {
+    /// Create a new `Broker` instance, which will listen for clients and workers on
+    /// the given address.
+    pub fn new(addr: &str) -> Result<Self, Error> {
+        Broker::new_with_context(addr, zmq::Context::new())
+    }
+
+    /// Create a new `Broker` instance that shares network context with the creator.
+    pub fn new_with_context(addr: &str, context: zmq::Context) -> Result<Self, Error> {
+        let address = addr.to_string();
+        let uuid = Uuid::new_v4();
+        let pipe = context.socket(zmq::PAIR)?;
+        pipe.connect(&pipe_address(&uuid))?;
+        Ok(Broker {
+            address,
+            context,
+            pipe,
+            uuid,
+        })
+    }
+
+    /// Returns a `String` with the address for the broker.
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    /// Returns the broker's network context.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Return a reference to the underlying pipe socket.
+    pub fn pipe(&self) -> &zmq::Socket {
+        &self.pipe
+    }
+
+    /// Start the broker on a child thread. The bound endpoint is sent back over the pipe
+    /// once the broker is listening.
+    pub fn start(&self) -> Result<thread::JoinHandle<Result<(), Error>>, io::Error> {
+        let context = self.context();
+        let address = self.address();
+        let pipe_addr = pipe_address(&self.uuid);
+
+        run_named_thread("broker", move || {
+            let pipe = context.socket(zmq::PAIR)?;
+            pipe.bind(&pipe_addr)?;
+
+            let socket = context.socket(zmq::ROUTER)?;
+            socket.bind(&address)?;
+            let endpoint = socket
+                .get_last_endpoint()?
+                .expect("unparsable broker endpoint");
+            pipe.send(&endpoint, 0)?;
+
+            poll_broker(&pipe, &socket, &mut BrokerState::new())
+        })
+    }
+
+    /// Stop the broker.
+    pub fn stop(&self) -> Result<(), zmq::Error> {
+        self.pipe().send("$STOP", 0)
+    }
+}
+
+// Each broker gets its own pipe address.
+fn pipe_address(uuid: &Uuid) -> String {
+    format!("inproc://neuras.broker.{}", uuid.to_simple())
+}
+
+fn poll_broker(
+    pipe: &zmq::Socket,
+    socket: &zmq::Socket,
+    state: &mut BrokerState,
+) -> Result<(), Error> {
+    let mut heartbeat_at = state.clock.mono() + HEARTBEAT_INTERVAL;
+    loop {
+        let mut pollable = [
+            pipe.as_poll_item(zmq::POLLIN),
+            socket.as_poll_item(zmq::POLLIN),
+        ];
+        zmq::poll(&mut pollable, HEARTBEAT_INTERVAL)?;
+        if pollable[0].is_readable() {
+            let cmd = pipe.recv_msg(0)?;
+            if &*cmd == b"$STOP" {
+                pipe.send("$STOPPING", 0)?;
+                break;
+            }
+            pipe.send("$WONTDO", 0)?;
+        }
+        if pollable[1].is_readable() {
+            let mut msg: VecDeque<Vec<u8>> = socket.recv_multipart(0)?.into();
+            let sender = msg.pop_front();
+            let empty = msg.pop_front();
+            let header = msg.pop_front();
+            let handled = match (sender, empty, header) {
+                (Some(sender), Some(_), Some(ref header)) if header == MDPC_CLIENT.as_bytes() => {
+                    state.client_msg(socket, sender, msg)
+                }
+                (Some(sender), Some(_), Some(ref header)) if header == MDPW_WORKER.as_bytes() => {
+                    state.worker_msg(socket, sender, msg)
+                }
+                _ => Err(BrokerError::InvalidMessage.into()),
+            };
+            // invalid messages are dropped, other errors stop the broker.
+            if let Err(e) = handled {
+                match e.downcast::<BrokerError>() {
+                    Ok(BrokerError::InvalidMessage) => nlog!(debug, "dropped invalid message"),
+                    Ok(e) => return Err(e.into()),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        if state.clock.mono() >= heartbeat_at {
+            state.purge(socket)?;
+            state.heartbeat(socket)?;
+            heartbeat_at = state.clock.mono() + HEARTBEAT_INTERVAL;
+        }
+    }
+    Ok(())
+}
+
+/// Blocking Majordomo client.
+pub struct Client {
+    broker: String,
+    context: zmq::Context,
+    socket: zmq::Socket,
+    timeout: i64,
+    retries: usize,
+}
+
+impl Client {
+    /// Create a new `Client`, connected to the broker at the given address.
+    pub fn new(broker: &str, context: zmq::Context) -> Result<Self, Error> {
+        let socket = Client::connect_to_broker(&context, broker)?;
+        Ok(Client {
+            broker: broker.to_string(),
+            context,
+            socket,
+            timeout: HEARTBEAT_INTERVAL,
+            retries: HEARTBEAT_LIVENESS,
+        })
+    }
+
+    fn connect_to_broker(context: &zmq::Context, broker: &str) -> Result<zmq::Socket, Error> {
+        let socket = context.socket(zmq::REQ)?;
+        socket.set_linger(0)?;
+        socket.connect(broker)?;
+        Ok(socket)
+    }
+
+    /// Set the timeout, in milliseconds, to wait for each reply.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Set the number of attempts for each request before giving up.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Send a request to a service, and wait for the reply body. Retries with a fresh
+    /// connection when the broker does not answer in time.
+    pub fn send<I, T>(&mut self, service: &str, request: I) -> Result<Vec<Vec<u8>>, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut msg = vec![
+            MDPC_CLIENT.as_bytes().to_vec(),
+            service.as_bytes().to_vec(),
+        ];
+        msg.extend(request.into_iter().map(|m| m.into()));
+
+        for _ in 0..self.retries {
+            self.socket.send_multipart(&msg, 0)?;
+            if self.socket.poll(zmq::POLLIN, self.timeout)? > 0 {
+                let mut reply: VecDeque<Vec<u8>> = self.socket.recv_multipart(0)?.into();
+                let header = reply.pop_front();
+                let reply_service = reply.pop_front();
+                if header.as_ref().map(|h| &h[..]) != Some(MDPC_CLIENT.as_bytes())
+                    || reply_service.as_ref().map(|s| &s[..]) != Some(service.as_bytes())
+                {
+                    return Err(BrokerError::InvalidMessage.into());
+                }
+                return Ok(reply.into_iter().collect());
+            }
+            self.socket = Client::connect_to_broker(&self.context, &self.broker)?;
+        }
+        Err(BrokerError::NoReply(self.retries).into())
+    }
+}
+
+/// Blocking Majordomo worker.
+pub struct Worker {
+    broker: String,
+    service: String,
+    context: zmq::Context,
+    socket: zmq::Socket,
+    clock: Clock,
+    liveness: usize,
+    heartbeat_at: i64,
+    reconnect: u64,
+    reply_to: Option<Vec<u8>>,
+}
+
+impl Worker {
+    /// Create a new `Worker` for a service, connected to the broker at the given address.
+    pub fn new(broker: &str, service: &str, context: zmq::Context) -> Result<Self, Error> {
+        let clock = Clock::new();
+        let socket = Worker::connect_to_broker(&context, broker, service)?;
+        Ok(Worker {
+            broker: broker.to_string(),
+            service: service.to_string(),
+            context,
+            socket,
+            heartbeat_at: clock.mono() + HEARTBEAT_INTERVAL,
+            clock,
+            liveness: HEARTBEAT_LIVENESS,
+            reconnect: HEARTBEAT_INTERVAL as u64,
+            reply_to: None,
+        })
+    }
+
+    fn connect_to_broker(
+        context: &zmq::Context,
+        broker: &str,
+        service: &str,
+    ) -> Result<zmq::Socket, Error> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(broker)?;
+        let ready: Vec<&[u8]> = vec![
+            b"",
+            MDPW_WORKER.as_bytes(),
+            MDPW_READY,
+            service.as_bytes(),
+        ];
+        socket.send_multipart(ready, 0)?;
+        Ok(socket)
+    }
+
+    fn send_command(&self, command: &[u8], body: Option<Vec<Vec<u8>>>) -> Result<(), Error> {
+        let mut msg = vec![Vec::new(), MDPW_WORKER.as_bytes().to_vec(), command.to_vec()];
+        if let Some(body) = body {
+            msg.extend(body);
+        }
+        self.socket.send_multipart(msg, 0)?;
+        Ok(())
+    }
+
+    /// Set the delay, in milliseconds, before reconnecting to a silent broker.
+    pub fn set_reconnect(&mut self, reconnect: u64) {
+        self.reconnect = reconnect;
+    }
+
+    /// Send the reply to the previous request, if any, and wait for the next request.
+    pub fn recv(&mut self, reply: Option<Vec<Vec<u8>>>) -> Result<Vec<Vec<u8>>, Error> {
+        if let Some(body) = reply {
+            if let Some(client) = self.reply_to.take() {
+                let mut msg = vec![client, Vec::new()];
+                msg.extend(body);
+                self.send_command(MDPW_REPLY, Some(msg))?;
+            }
+        }
+
+        loop {
+            if self.socket.poll(zmq::POLLIN, HEARTBEAT_INTERVAL)? > 0 {
+                let mut msg: VecDeque<Vec<u8>> = self.socket.recv_multipart(0)?.into();
+                self.liveness = HEARTBEAT_LIVENESS;
+                let _empty = msg.pop_front();
+                let header = msg.pop_front();
+                if header.as_ref().map(|h| &h[..]) != Some(MDPW_WORKER.as_bytes()) {
+                    return Err(BrokerError::InvalidMessage.into());
+                }
+                match msg.pop_front() {
+                    Some(ref command) if &command[..] == MDPW_REQUEST => {
+                        self.reply_to = msg.pop_front();
+                        // drop the empty delimiter
+                        msg.pop_front();
+                        return Ok(msg.into_iter().collect());
+                    }
+                    Some(ref command) if &command[..] == MDPW_HEARTBEAT => {}
+                    Some(ref command) if &command[..] == MDPW_DISCONNECT => {
+                        self.reconnect_to_broker()?;
+                    }
+                    _ => return Err(BrokerError::InvalidMessage.into()),
+                }
+            } else {
+                self.liveness -= 1;
+                if self.liveness == 0 {
+                    self.clock.sleep(self.reconnect);
+                    self.reconnect_to_broker()?;
+                }
+            }
+            if self.clock.mono() >= self.heartbeat_at {
+                self.send_command(MDPW_HEARTBEAT, None)?;
+                self.heartbeat_at = self.clock.mono() + HEARTBEAT_INTERVAL;
+            }
+        }
+    }
+
+    fn reconnect_to_broker(&mut self) -> Result<(), Error> {
+        self.socket = Worker::connect_to_broker(&self.context, &self.broker, &self.service)?;
+        self.liveness = HEARTBEAT_LIVENESS;
+        self.heartbeat_at = self.clock.mono() + HEARTBEAT_INTERVAL;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    fn start_broker(addr: &str) -> Broker {
+        let broker = Broker::new(addr).unwrap();
+        broker.start().unwrap();
+        broker.pipe().recv_msg(0).unwrap();
+        broker
+    }
+
+    #[test]
+    fn brokers_report_their_endpoint_on_start() {
+        let broker = Broker::new("tcp://127.0.0.1:*").unwrap();
+        broker.start().unwrap();
+        let endpoint = broker.pipe().recv_string(0).unwrap().unwrap();
+        assert!(endpoint.starts_with("tcp://127.0.0.1:"));
+        broker.stop().unwrap();
+    }
+
+    #[test]
+    fn brokers_join_thread_on_stop() {
+        let broker = Broker::new("inproc://broker_stop").unwrap();
+        let handle = broker.start().unwrap();
+        broker.pipe().recv_msg(0).unwrap();
+        broker.stop().unwrap();
+        assert_eq!(broker.pipe().recv_msg(0).unwrap().as_str(), Some("$STOPPING"));
+        assert!(handle.join().unwrap().is_ok());
+    }
+
+    #[test]
+    fn brokers_route_requests_to_workers_by_service() {
+        let broker = start_broker("inproc://broker_echo");
+        let context = broker.context();
+
+        let ctx = context.clone();
+        let handle = thread::spawn(move || {
+            let mut worker = Worker::new("inproc://broker_echo", "echo", ctx).unwrap();
+            let mut reply = None;
+            loop {
+                let request = worker.recv(reply.take()).unwrap();
+                // the worker leaves, without a reply, on the last request.
+                if request == vec![b"bye".to_vec()] {
+                    break;
+                }
+                reply = Some(request);
+            }
+        });
+
+        let mut client = Client::new("inproc://broker_echo", context).unwrap();
+        let reply = client.send("echo", vec!["hello", "world"]).unwrap();
+        assert_eq!(reply, vec![b"hello".to_vec(), b"world".to_vec()]);
+        client.set_timeout(10);
+        client.set_retries(1);
+        assert!(client.send("echo", vec!["bye"]).is_err());
+        handle.join().unwrap();
+        broker.stop().unwrap();
+    }
+
+    #[test]
+    fn brokers_answer_mmi_service_queries() {
+        let broker = start_broker("inproc://broker_mmi");
+        let mut client = Client::new("inproc://broker_mmi", broker.context()).unwrap();
+        let reply = client.send("mmi.service", vec!["missing"]).unwrap();
+        assert_eq!(reply, vec![b"404".to_vec()]);
+        broker.stop().unwrap();
+    }
+
+    #[test]
+    fn busy_services_are_known_to_mmi() {
+        let broker = start_broker("inproc://broker_mmi_busy");
+        let context = broker.context();
+        let (busy_tx, busy_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+
+        let ctx = context.clone();
+        let worker = thread::spawn(move || {
+            let mut worker = Worker::new("inproc://broker_mmi_busy", "slow", ctx).unwrap();
+            let request = worker.recv(None).unwrap();
+            busy_tx.send(()).unwrap();
+            // stay busy until the test asked the broker about the service.
+            done_rx.recv().unwrap();
+            let bye = worker.recv(Some(request)).unwrap();
+            assert_eq!(bye, vec![b"bye".to_vec()]);
+        });
+        let ctx = context.clone();
+        let client = thread::spawn(move || {
+            let mut client = Client::new("inproc://broker_mmi_busy", ctx).unwrap();
+            let reply = client.send("slow", vec!["work"]).unwrap();
+            assert_eq!(reply, vec![b"work".to_vec()]);
+            client.set_timeout(10);
+            client.set_retries(1);
+            assert!(client.send("slow", vec!["bye"]).is_err());
+        });
+
+        busy_rx.recv().unwrap();
+        let mut mmi = Client::new("inproc://broker_mmi_busy", context).unwrap();
+        let reply = mmi.send("mmi.service", vec!["slow"]).unwrap();
+        assert_eq!(reply, vec![b"200".to_vec()]);
+        done_tx.send(()).unwrap();
+        client.join().unwrap();
+        worker.join().unwrap();
+        broker.stop().unwrap();
+    }
+
+    #[test]
+    fn clients_give_up_after_retries() {
+        let context = zmq::Context::new();
+        let mut client = Client::new("inproc://broker_missing", context).unwrap();
+        client.set_timeout(10);
+        client.set_retries(2);
+        assert!(client.send("echo", vec!["hello"]).is_err());
+    }
+}
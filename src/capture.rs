@@ -0,0 +1,257 @@
+//! Recording, and replay, of multipart messages.
+//!
+//! A `Recorder` appends the multipart messages that it is given, or that it receives from a
+//! socket, to a capture file, each one with the time it was recorded at. A `Replayer` reads
+//! them back, and sends them into a socket with the same delays between them, or faster,
+//! e.g. to reproduce the traffic of an actor pipeline offline.
+//!
+//! Capture files start with `MAGIC`, followed by one record for each message: its
+//! timestamp, in microseconds since its recorder was created, as a big-endian `u64`, the
+//! length of its frames as a big-endian `u32`, and its frames, packed with
+//! `socket::pack`.
+//!
+//! ```
+//! use neuras::capture::{Recorder, Replayer};
+//!
+//! let mut recorder = Recorder::new(Vec::new()).unwrap();
+//! recorder.record(&["topic", "hello"]).unwrap();
+//! let file = recorder.into_inner().unwrap();
+//!
+//! let mut replayer = Replayer::new(&file[..]).unwrap();
+//! let record = replayer.next_record().unwrap().unwrap();
+//! assert_eq!(record.frames, vec![b"topic".to_vec(), b"hello".to_vec()]);
+//! ```
+use super::clock::Clock;
+use super::socket::{pack, unpack};
+
+use failure::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use zmq;
+
+/// Magic bytes at the start of every capture file.
+pub const MAGIC: &[u8] = b"NRSCAP01";
+
+/// A recorded multipart message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    /// Microseconds since the recorder was created.
+    pub timestamp: u64,
+    pub frames: Vec<Vec<u8>>,
+}
+
+/// Writes multipart messages to a capture.
+pub struct Recorder<W: Write> {
+    writer: W,
+    clock: Clock,
+    count: usize,
+}
+
+impl Recorder<BufWriter<File>> {
+    /// Append to the capture file at `path`, which is created if it does not exist.
+    pub fn append<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let empty = file.metadata()?.len() == 0;
+        let mut writer = BufWriter::new(file);
+        if empty {
+            writer.write_all(MAGIC)?;
+        }
+        Ok(Recorder::from_parts(writer))
+    }
+}
+
+impl<W: Write> Recorder<W> {
+    /// Create a new `Recorder`, writing a new capture into `writer`.
+    pub fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        Ok(Recorder::from_parts(writer))
+    }
+
+    fn from_parts(writer: W) -> Self {
+        Recorder {
+            writer,
+            clock: Clock::new(),
+            count: 0,
+        }
+    }
+
+    /// Returns the number of messages recorded.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Record a multipart message.
+    pub fn record<I, T>(&mut self, frames: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let packed = pack(frames)?;
+        if packed.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message is too large to be recorded",
+            ));
+        }
+        let timestamp = self.clock.usecs() as u64;
+        self.writer.write_all(&timestamp.to_be_bytes())?;
+        self.writer
+            .write_all(&(packed.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&packed)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Receive a multipart message from `socket`, and record it before returning it.
+    pub fn record_from(&mut self, socket: &zmq::Socket, flags: i32) -> Result<Vec<Vec<u8>>, Error> {
+        let frames = socket.recv_multipart(flags)?;
+        self.record(&frames)?;
+        Ok(frames)
+    }
+
+    /// Flush the records to the underlying writer.
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Flush the records, and return the underlying writer.
+    pub fn into_inner(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads multipart messages from a capture, and replays them into sockets.
+pub struct Replayer<R: Read> {
+    reader: R,
+    speed: f64,
+}
+
+impl Replayer<BufReader<File>> {
+    /// Open the capture file at `path`.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Replayer::new(BufReader::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> Replayer<R> {
+    /// Create a new `Replayer`, reading a capture from `reader`.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a capture file",
+            ));
+        }
+        Ok(Replayer { reader, speed: 1.0 })
+    }
+
+    /// Set the speed of replays, relative to the original: `2.0` replays twice as fast, and
+    /// `0.0`, or less, without any delays.
+    pub fn set_speed(&mut self, speed: f64) {
+        self.speed = speed;
+    }
+
+    /// Read the next record, or `None` at the end of the capture.
+    pub fn next_record(&mut self) -> io::Result<Option<Record>> {
+        let mut timestamp = [0u8; 8];
+        match self.reader.read_exact(&mut timestamp) {
+            Ok(()) => {}
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+        let mut length = [0u8; 4];
+        self.reader.read_exact(&mut length)?;
+        let mut packed = vec![0u8; u32::from_be_bytes(length) as usize];
+        self.reader.read_exact(&mut packed)?;
+        Ok(Some(Record {
+            timestamp: u64::from_be_bytes(timestamp),
+            frames: unpack(&packed)?,
+        }))
+    }
+
+    /// Send every remaining record into `socket`, waiting between them as long as they
+    /// were apart when recorded, divided by the speed. Returns the number of messages sent.
+    ///
+    /// Records that are older than the previous one, e.g. from an appended capture, are
+    /// sent right away.
+    pub fn replay_into(&mut self, socket: &zmq::Socket) -> Result<usize, Error> {
+        let mut previous = None;
+        let mut count = 0;
+        while let Some(record) = self.next_record()? {
+            if let Some(previous) = previous {
+                let delay = record.timestamp.saturating_sub(previous);
+                if self.speed > 0.0 && delay > 0 {
+                    let delay = (delay as f64 / self.speed) as u64;
+                    thread::sleep(Duration::from_micros(delay));
+                }
+            }
+            previous = Some(record.timestamp);
+            socket.send_multipart(record.frames, 0)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn recorded_messages_are_replayed_into_sockets() {
+        let ctx = zmq::Context::new();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.bind("inproc://capture_record").unwrap();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.connect("inproc://capture_record").unwrap();
+
+        let mut recorder = Recorder::new(Vec::new()).unwrap();
+        push.send_multipart(["a", "1"], 0).unwrap();
+        recorder.record_from(&pull, 0).unwrap();
+        Clock::new().sleep(20);
+        recorder.record(["b"]).unwrap();
+        assert_eq!(recorder.count(), 2);
+        let capture = recorder.into_inner().unwrap();
+
+        let mut replayer = Replayer::new(&capture[..]).unwrap();
+        let start = Instant::now();
+        assert_eq!(replayer.replay_into(&push).unwrap(), 2);
+        assert!(start.elapsed() >= Duration::from_millis(20));
+        assert_eq!(
+            pull.recv_multipart(0).unwrap(),
+            vec![b"a".to_vec(), b"1".to_vec()]
+        );
+        assert_eq!(pull.recv_multipart(0).unwrap(), vec![b"b".to_vec()]);
+    }
+
+    #[test]
+    fn captures_are_appended_to() {
+        let path = ::std::env::temp_dir().join(format!("neuras-{}.cap", ::uuid::Uuid::new_v4()));
+        for msg in &["first", "second"] {
+            let mut recorder = Recorder::append(&path).unwrap();
+            recorder.record([msg]).unwrap();
+            recorder.flush().unwrap();
+        }
+        let mut replayer = Replayer::open(&path).unwrap();
+        replayer.set_speed(0.0);
+        let mut frames = Vec::new();
+        while let Some(record) = replayer.next_record().unwrap() {
+            frames.extend(record.frames);
+        }
+        ::std::fs::remove_file(&path).unwrap();
+        assert_eq!(frames, vec![b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn other_files_are_invalid_data() {
+        let err = Replayer::new(&b"not a capture"[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
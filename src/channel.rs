@@ -0,0 +1,246 @@
+//! Typed channels between threads, over `inproc` PAIR sockets.
+//!
+//! `pair` returns both ends of a channel, already bound, and connected, to an endpoint of
+//! their own, so that neither thread has to bind before the other connects, as with a
+//! shared, well-known, address like `actor::PIPE_ADDR`. Move one end to the spawned thread,
+//! and keep the other; each end sends messages of type `S`, and receives messages of type
+//! `R`, so the ends of a `pair::<Command, Reply>` are a `Channel<Command, Reply>`, and a
+//! `Channel<Reply, Command>`.
+//!
+//! Messages are encoded as TOML tables, so they are structs, or maps. An end that is
+//! dropped tells its peer, whose `recv` then returns `None`, so a thread can loop until the
+//! other end goes away.
+//!
+//! ```
+//! extern crate neuras;
+//! #[macro_use]
+//! extern crate serde_derive;
+//!
+//! use neuras::channel;
+//! use std::thread;
+//!
+//! #[derive(Debug, Deserialize, PartialEq, Serialize)]
+//! struct Add {
+//!     a: i32,
+//!     b: i32,
+//! }
+//!
+//! #[derive(Debug, Deserialize, PartialEq, Serialize)]
+//! struct Sum {
+//!     sum: i32,
+//! }
+//!
+//! # fn main() {
+//! let (client, worker) = channel::pair::<Add, Sum>().unwrap();
+//! let thread = thread::spawn(move || {
+//!     while let Some(add) = worker.recv().unwrap() {
+//!         worker.send(&Sum { sum: add.a + add.b }).unwrap();
+//!     }
+//! });
+//! client.send(&Add { a: 1, b: 2 }).unwrap();
+//! assert_eq!(client.recv().unwrap(), Some(Sum { sum: 3 }));
+//! drop(client);
+//! thread.join().unwrap();
+//! # }
+//! ```
+use super::context::sys_context;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cell::Cell;
+use std::marker::PhantomData;
+use toml;
+use uuid::Uuid;
+use zmq;
+
+// First frame of the messages of a channel.
+const MESSAGE: &str = "$MSG";
+// Only frame of the message sent by an end that is dropped.
+const CLOSED: &str = "$CLOSED";
+
+/// Channel errors.
+#[derive(Debug, Fail)]
+pub enum ChannelError {
+    #[fail(display = "invalid channel message: {}", _0)]
+    InvalidMessage(String),
+    #[fail(display = "message could not be encoded: {}", _0)]
+    Encode(#[cause] toml::ser::Error),
+    #[fail(display = "message could not be decoded: {}", _0)]
+    Decode(#[cause] toml::de::Error),
+    #[fail(display = "the other end of the channel was dropped")]
+    Closed,
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<toml::ser::Error> for ChannelError {
+    fn from(e: toml::ser::Error) -> ChannelError {
+        ChannelError::Encode(e)
+    }
+}
+
+impl From<toml::de::Error> for ChannelError {
+    fn from(e: toml::de::Error) -> ChannelError {
+        ChannelError::Decode(e)
+    }
+}
+
+impl From<zmq::Error> for ChannelError {
+    fn from(e: zmq::Error) -> ChannelError {
+        ChannelError::Zmq(e)
+    }
+}
+
+/// Both ends of a channel, as returned by `pair`.
+pub type Pair<A, B> = (Channel<A, B>, Channel<B, A>);
+
+/// Create both ends of a channel, on the shared context.
+pub fn pair<A, B>() -> Result<Pair<A, B>, ChannelError>
+where
+    A: Serialize + DeserializeOwned,
+    B: Serialize + DeserializeOwned,
+{
+    pair_with_context(&sys_context())
+}
+
+/// Create both ends of a channel, on `context`.
+pub fn pair_with_context<A, B>(context: &zmq::Context) -> Result<Pair<A, B>, ChannelError>
+where
+    A: Serialize + DeserializeOwned,
+    B: Serialize + DeserializeOwned,
+{
+    let endpoint = format!("inproc://neuras.channel.{}", Uuid::new_v4().to_simple());
+    let bound = Channel::new(context.socket(zmq::PAIR)?, &endpoint)?;
+    bound.socket.bind(&endpoint)?;
+    let connected = Channel::new(context.socket(zmq::PAIR)?, &endpoint)?;
+    connected.socket.connect(&endpoint)?;
+    Ok((bound, connected))
+}
+
+/// One end of a channel, which sends messages of type `S`, and receives messages of type
+/// `R`.
+pub struct Channel<S, R> {
+    socket: zmq::Socket,
+    endpoint: String,
+    // set once the other end was dropped.
+    closed: Cell<bool>,
+    _messages: PhantomData<fn(S) -> R>,
+}
+
+impl<S, R> Channel<S, R>
+where
+    S: Serialize,
+    R: DeserializeOwned,
+{
+    fn new(socket: zmq::Socket, endpoint: &str) -> Result<Channel<S, R>, ChannelError> {
+        socket.set_linger(0)?;
+        Ok(Channel {
+            socket,
+            endpoint: endpoint.to_string(),
+            closed: Cell::new(false),
+            _messages: PhantomData,
+        })
+    }
+
+    /// Returns the endpoint of the channel.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Returns a reference to the inner socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Returns true once the other end was dropped.
+    pub fn is_closed(&self) -> bool {
+        self.closed.get()
+    }
+
+    /// Send `msg` to the other end. Fails with `ChannelError::Closed` once it was dropped.
+    pub fn send(&self, msg: &S) -> Result<(), ChannelError> {
+        if self.closed.get() {
+            return Err(ChannelError::Closed);
+        }
+        let encoded = toml::to_string(msg)?;
+        self.socket
+            .send_multipart([MESSAGE.as_bytes(), encoded.as_bytes()], 0)?;
+        Ok(())
+    }
+
+    /// Wait for the next message, or return `None` once the other end was dropped.
+    pub fn recv(&self) -> Result<Option<R>, ChannelError> {
+        self.recv_timeout(-1)
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it is `-1`, for the next message.
+    /// Returns `None` if there is none, or once the other end was dropped.
+    pub fn recv_timeout(&self, timeout: i64) -> Result<Option<R>, ChannelError> {
+        if self.closed.get() || self.socket.poll(zmq::POLLIN, timeout)? == 0 {
+            return Ok(None);
+        }
+        let msg = self.socket.recv_multipart(zmq::DONTWAIT)?;
+        match msg.first().map(|frame| &frame[..]) {
+            Some(frame) if frame == CLOSED.as_bytes() => {
+                self.closed.set(true);
+                Ok(None)
+            }
+            Some(frame) if frame == MESSAGE.as_bytes() && msg.len() == 2 => {
+                let encoded = ::std::str::from_utf8(&msg[1])
+                    .map_err(|e| ChannelError::InvalidMessage(e.to_string()))?;
+                Ok(Some(toml::from_str(encoded)?))
+            }
+            _ => Err(ChannelError::InvalidMessage(format!(
+                "{} frames",
+                msg.len()
+            ))),
+        }
+    }
+}
+
+impl<S, R> Drop for Channel<S, R> {
+    fn drop(&mut self) {
+        if !self.closed.get() {
+            let _ = self.socket.send(CLOSED, zmq::DONTWAIT);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Job {
+        id: u32,
+    }
+
+    #[test]
+    fn dropped_ends_close_the_channel() {
+        let ctx = zmq::Context::new();
+        let (main, worker) = pair_with_context::<Job, Job>(&ctx).unwrap();
+        assert_eq!(main.endpoint(), worker.endpoint());
+        assert_eq!(main.recv_timeout(10).unwrap(), None);
+
+        let thread = thread::spawn(move || {
+            let mut done = 0;
+            while let Some(job) = worker.recv().unwrap() {
+                worker.send(&Job { id: job.id * 10 }).unwrap();
+                done += 1;
+            }
+            assert!(worker.is_closed());
+            match worker.send(&Job { id: 0 }) {
+                Err(ChannelError::Closed) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+            done
+        });
+        for id in 1..4 {
+            main.send(&Job { id }).unwrap();
+            assert_eq!(main.recv().unwrap(), Some(Job { id: id * 10 }));
+        }
+        drop(main);
+        assert_eq!(thread.join().unwrap(), 3);
+    }
+}
@@ -36,6 +36,10 @@
 //! // Return formatted RFC 3339 UTC date/time string.
 //! let time_str: String = clock.time_str().unwrap();
 //! ```
+//!
+//! Clocks read the time from a `TimeSource`, which is the system clock, unless the clock
+//! is created `with_source`, e.g. with the `FakeClock` of the `testkit`, so that timeouts
+//! can be tested without waiting for them.
 use chrono::{DateTime, NaiveDateTime, Utc};
 use failure::Error;
 use std::time::{Duration, Instant, SystemTime, SystemTimeError, UNIX_EPOCH};
@@ -67,14 +71,59 @@ fn duration_to_millis(d: Duration) -> i64 {
 fn get_system_time() -> Result<Duration, ClockError> {
     SystemTime::now()
         .duration_since(UNIX_EPOCH)
-        .map_err(|e| ClockError::SystemTime(e))
+        .map_err(ClockError::SystemTime)
+}
+
+/// Source of the time of a `Clock`.
+pub trait TimeSource: Send + Sync {
+    /// Returns the monotonic time in microseconds, since the source was created.
+    fn usecs(&self) -> i64;
+
+    /// Returns the system time, as the duration since UNIX EPOCH.
+    fn since_epoch(&self) -> Result<Duration, ClockError>;
+
+    /// Sleep for a number of milliseconds.
+    fn sleep(&self, ms: u64);
+}
+
+/// The system clock, whose monotonic time starts when it is created.
+#[derive(Copy, Clone, Debug)]
+pub struct SystemTimeSource {
+    start: Instant,
+}
+
+impl SystemTimeSource {
+    /// Create a source whose monotonic time starts now.
+    pub fn new() -> SystemTimeSource {
+        SystemTimeSource {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemTimeSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TimeSource for SystemTimeSource {
+    fn usecs(&self) -> i64 {
+        duration_to_micros(self.start.elapsed())
+    }
+
+    fn since_epoch(&self) -> Result<Duration, ClockError> {
+        get_system_time()
+    }
+
+    fn sleep(&self, ms: u64) {
+        clock_sleep(ms)
+    }
 }
 
 /// A new `Clock` instance is created with the `std::time::Instant` it was started.
 pub fn clock_new() -> Clock {
-    Clock {
-        start: Instant::now(),
-    }
+    Clock::with_source(SystemTimeSource::new())
 }
 
 /// Sleep for a number of milliseconds.
@@ -83,15 +132,13 @@ pub fn clock_sleep(ms: u64) {
 }
 
 /// Returns monotonic clock in milliseconds.
-pub fn clock_mono(clock: &Clock) -> i64 {
-    let timestamp = clock.start.elapsed();
-    duration_to_millis(timestamp)
+pub fn clock_mono<S: TimeSource>(clock: &Clock<S>) -> i64 {
+    clock.source.usecs() / 1_000
 }
 
 /// Returns monotonic clock in microseconds.
-pub fn clock_usecs(clock: &Clock) -> i64 {
-    let timestamp = clock.start.elapsed();
-    duration_to_micros(timestamp)
+pub fn clock_usecs<S: TimeSource>(clock: &Clock<S>) -> i64 {
+    clock.source.usecs()
 }
 
 /// Returns monotonic clock in milliseconds.
@@ -103,18 +150,24 @@ pub fn clock_time() -> Result<i64, Error> {
 
 /// Returns an RFC 3339 and ISO 8601 UTC date and time string.
 pub fn clock_time_str() -> Result<String, Error> {
-    let timestamp = get_system_time()?;
+    time_str(get_system_time()?)
+}
+
+fn time_str(timestamp: Duration) -> Result<String, Error> {
     let ndt =
-        NaiveDateTime::from_timestamp(timestamp.as_secs() as i64, timestamp.subsec_nanos() as u32);
+        NaiveDateTime::from_timestamp(timestamp.as_secs() as i64, timestamp.subsec_nanos());
     let dt = DateTime::<Utc>::from_utc(ndt, Utc);
     let dt_str = dt.to_rfc3339();
     Ok(dt_str)
 }
 
 /// Convenient API for clocks and delays.
+///
+/// Clocks of the system time, the default `SystemTimeSource`, are `Copy`; clones of clocks
+/// of other sources are as their sources are cloned, e.g. share the time of a `FakeClock`.
 #[derive(Copy, Clone, Debug)]
-pub struct Clock {
-    start: Instant,
+pub struct Clock<S = SystemTimeSource> {
+    source: S,
 }
 
 impl Clock {
@@ -122,10 +175,17 @@ impl Clock {
     pub fn new() -> Clock {
         clock_new()
     }
+}
+
+impl<S: TimeSource + Clone> Clock<S> {
+    /// A new `Clock` instance that reads the time from `source`.
+    pub fn with_source(source: S) -> Clock<S> {
+        Clock { source }
+    }
 
     /// Sleep for a number of milliseconds.
     pub fn sleep(&self, ms: u64) {
-        clock_sleep(ms)
+        self.source.sleep(ms)
     }
 
     /// Returns monotonic clock in milliseconds.
@@ -140,12 +200,35 @@ impl Clock {
 
     /// Returns monotonic clock in milliseconds.
     pub fn time(&self) -> Result<i64, Error> {
-        clock_time()
+        Ok(duration_to_millis(self.source.since_epoch()?))
     }
 
     /// Returns an RFC 3339 and ISO 8601 UTC date and time string.
     pub fn time_str(&self) -> Result<String, Error> {
-        clock_time_str()
+        time_str(self.source.since_epoch()?)
+    }
+
+    /// Returns a deadline `timeout` milliseconds from now, or that never expires if
+    /// `timeout` is negative.
+    pub fn deadline(&self, timeout: i64) -> Deadline<S> {
+        Deadline {
+            clock: self.clone(),
+            at: if timeout < 0 {
+                None
+            } else {
+                Some(self.mono() + timeout)
+            },
+        }
+    }
+
+    /// Returns a stopwatch started now.
+    pub fn stopwatch(&self) -> Stopwatch<S> {
+        let now = self.usecs();
+        Stopwatch {
+            clock: self.clone(),
+            start: now,
+            lap: now,
+        }
     }
 }
 
@@ -155,6 +238,114 @@ impl Default for Clock {
     }
 }
 
+/// A point of the monotonic clock, that operations wait until.
+///
+/// ```
+/// use neuras::clock::Deadline;
+///
+/// let deadline = Deadline::after(50);
+/// assert!(!deadline.is_expired());
+/// assert!(deadline.remaining() <= 50);
+/// assert_eq!(Deadline::never().remaining(), -1);
+/// ```
+#[derive(Clone, Debug)]
+pub struct Deadline<S = SystemTimeSource> {
+    clock: Clock<S>,
+    at: Option<i64>,
+}
+
+impl Deadline {
+    /// A deadline `timeout` milliseconds from now, or never if `timeout` is negative.
+    pub fn after(timeout: i64) -> Deadline {
+        Clock::new().deadline(timeout)
+    }
+
+    /// A deadline `duration` from now.
+    pub fn from_duration(duration: Duration) -> Deadline {
+        Deadline::after(duration_to_millis(duration))
+    }
+
+    /// A deadline that never expires.
+    pub fn never() -> Deadline {
+        Deadline::after(-1)
+    }
+}
+
+impl<S: TimeSource + Clone> Deadline<S> {
+    /// Returns the milliseconds left, `0` once expired, or `-1` if it never expires, i.e.
+    /// the timeout to give to `zmq::poll`.
+    pub fn remaining(&self) -> i64 {
+        match self.at {
+            Some(at) => (at - self.clock.mono()).max(0),
+            None => -1,
+        }
+    }
+
+    /// Returns the time left, or `None` if it never expires.
+    pub fn remaining_duration(&self) -> Option<Duration> {
+        match self.remaining() {
+            -1 => None,
+            ms => Some(Duration::from_millis(ms as u64)),
+        }
+    }
+
+    /// Returns true if the deadline has passed.
+    pub fn is_expired(&self) -> bool {
+        self.remaining() == 0
+    }
+}
+
+/// Measures the time elapsed since it was started, and between laps.
+#[derive(Clone, Debug)]
+pub struct Stopwatch<S = SystemTimeSource> {
+    clock: Clock<S>,
+    start: i64,
+    lap: i64,
+}
+
+impl Stopwatch {
+    /// A stopwatch started now.
+    pub fn new() -> Stopwatch {
+        Clock::new().stopwatch()
+    }
+}
+
+impl<S: TimeSource + Clone> Stopwatch<S> {
+    /// Returns the milliseconds elapsed since the stopwatch was started.
+    pub fn elapsed(&self) -> i64 {
+        (self.clock.usecs() - self.start) / 1_000
+    }
+
+    /// Returns the microseconds elapsed since the stopwatch was started.
+    pub fn elapsed_usecs(&self) -> i64 {
+        self.clock.usecs() - self.start
+    }
+
+    /// Returns the milliseconds elapsed since the previous lap, or since the stopwatch was
+    /// started, and starts the next lap.
+    pub fn lap(&mut self) -> i64 {
+        let now = self.clock.usecs();
+        let lap = (now - self.lap) / 1_000;
+        self.lap = now;
+        lap
+    }
+
+    /// Returns the milliseconds elapsed, and starts over.
+    pub fn restart(&mut self) -> i64 {
+        let elapsed = self.elapsed();
+        let now = self.clock.usecs();
+        self.start = now;
+        self.lap = now;
+        elapsed
+    }
+}
+
+impl Default for Stopwatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -174,7 +365,7 @@ mod tests {
     fn clock_time_returns_milliseconds_from_unix_epoch() {
         let clock = Clock::new();
         let now = clock.time().unwrap() / 1_000;
-        let dt = NaiveDateTime::from_timestamp(now as i64, 0);
+        let dt = NaiveDateTime::from_timestamp(now, 0);
         assert_eq!(dt.timestamp(), now);
     }
 
@@ -186,6 +377,37 @@ mod tests {
         assert!(dt.is_ok());
     }
 
+    #[test]
+    fn deadlines_expire_and_stopwatches_measure_laps() {
+        let clock = Clock::new();
+        let deadline = clock.deadline(30);
+        let mut stopwatch = clock.stopwatch();
+        assert!(!deadline.is_expired());
+        assert!(deadline.remaining_duration().unwrap() <= Duration::from_millis(30));
+        clock.sleep(40);
+        assert!(deadline.is_expired());
+        assert_eq!(deadline.remaining(), 0);
+        assert!(stopwatch.lap() >= 40);
+        assert!(stopwatch.lap() < 40);
+        assert!(stopwatch.elapsed() >= 40);
+        assert!(stopwatch.restart() >= 40);
+        assert!(stopwatch.elapsed_usecs() < 40_000);
+
+        let never = Deadline::never();
+        assert_eq!(never.remaining(), -1);
+        assert!(!never.is_expired());
+        assert_eq!(never.remaining_duration(), None);
+        assert!(!Deadline::from_duration(Duration::from_secs(1)).is_expired());
+    }
+
+    #[test]
+    fn system_clocks_are_copied() {
+        let clock = Clock::new();
+        let copy = clock;
+        assert!(copy.usecs() <= clock.usecs());
+        assert!(format!("{:?}", clock).starts_with("Clock"));
+    }
+
     #[test]
     fn converts_duration_to_micros() {
         let dur = Duration::from_millis(1000);
@@ -0,0 +1,534 @@
+//! Lifecycle of the network context.
+//!
+//! inproc endpoints only connect sockets of the same context, so the crate shares a single,
+//! process-wide, context: `sys_context` returns the same context in every thread. It is
+//! never terminated, and lives as long as its last handle.
+//!
+//! A `ContextManager` owns a context of its own, and keeps count of the sockets created
+//! through it. It only hands out those sockets, as `ManagedSocket`s, and never a
+//! `zmq::Context`, so that no handle to the context outlives its termination, and its
+//! inproc endpoints only connect the sockets of the manager. `ContextManager::shutdown`
+//! terminates the context: blocking calls on its sockets fail with `ETERM`, so that their
+//! threads close them, and the sockets it counts linger no longer than the shutdown
+//! timeout.
+//!
+//! The I/O threads of libzmq carry the traffic of every socket of a context, one I/O thread
+//! by default. Heavy deployments give the context of a manager more I/O threads, with
+//! `ContextManager::with_io_threads`, e.g. `default_io_threads()`, before it creates any
+//! socket, and pin their busiest sockets to I/O threads of their own, with
+//! `SocketBuilder::affinity`, or `zmq::Socket::set_affinity`.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::context::ContextManager;
+//! use std::thread;
+//!
+//! # fn main() {
+//! let manager = ContextManager::new();
+//! let socket = manager.socket(zmq::PULL).unwrap();
+//! socket.bind("inproc://context_example").unwrap();
+//! let worker = thread::spawn(move || {
+//!     // fails with `ETERM` once the context is terminated.
+//!     assert_eq!(socket.recv_bytes(0), Err(zmq::Error::ETERM));
+//! });
+//! manager.shutdown(1_000).unwrap();
+//! worker.join().unwrap();
+//! assert_eq!(manager.open_sockets(), 0);
+//! # }
+//! ```
+use super::clock::Clock;
+use super::utils::run_named_thread;
+
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::ops::{Deref, DerefMut};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+use zmq;
+use zmq_sys;
+
+/// Context errors.
+#[derive(Debug, Fail)]
+pub enum ContextError {
+    #[fail(display = "context is shut down")]
+    Terminated,
+    #[fail(display = "context is shutting down, with {} sockets still open", _0)]
+    OpenSockets(usize),
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<io::Error> for ContextError {
+    fn from(e: io::Error) -> ContextError {
+        ContextError::Io(e)
+    }
+}
+
+impl From<zmq::Error> for ContextError {
+    fn from(e: zmq::Error) -> ContextError {
+        ContextError::Zmq(e)
+    }
+}
+
+// Raw socket of a `ManagedSocket`, which is removed from the lifecycle before it closes.
+struct RawSocket(*mut c_void);
+
+unsafe impl Send for RawSocket {}
+
+// Raw context of a manager, which is terminated once, by the shutdown, or on drop.
+struct RawContext(*mut c_void);
+
+unsafe impl Send for RawContext {}
+
+impl RawContext {
+    fn new() -> RawContext {
+        // like `zmq::Context::new`, a null context only fails the calls that use it.
+        RawContext(unsafe { zmq_sys::zmq_ctx_new() })
+    }
+
+    fn socket(&self, kind: zmq::SocketType) -> Result<zmq::Socket, ContextError> {
+        let socket = unsafe { zmq_sys::zmq_socket(self.0, raw_socket_type(kind)) };
+        if socket.is_null() {
+            return Err(last_error().into());
+        }
+        // the socket is closed on drop, before the context is terminated, which waits for
+        // it.
+        Ok(unsafe { zmq::Socket::from_raw(socket) })
+    }
+
+    fn get(&self, option: u32) -> Result<i32, ContextError> {
+        match unsafe { zmq_sys::zmq_ctx_get(self.0, option as i32) } {
+            -1 => Err(last_error().into()),
+            value => Ok(value),
+        }
+    }
+
+    fn set(&self, option: u32, value: i32) -> Result<(), ContextError> {
+        match unsafe { zmq_sys::zmq_ctx_set(self.0, option as i32, value) } {
+            -1 => Err(last_error().into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn term(self) -> Result<(), zmq::Error> {
+        loop {
+            match unsafe { zmq_sys::zmq_ctx_term(self.0) } {
+                -1 if last_error() == zmq::Error::EINTR => {}
+                -1 => return Err(last_error()),
+                _ => return Ok(()),
+            }
+        }
+    }
+}
+
+// State shared by a manager, and its sockets.
+struct Lifecycle {
+    clock: Clock,
+    // the context, until the shutdown takes it to terminate it.
+    context: Mutex<Option<RawContext>>,
+    next_socket: AtomicUsize,
+    open: Mutex<HashMap<usize, RawSocket>>,
+    shutting_down: AtomicBool,
+    // deadline of the shutdown, as milliseconds of `clock`, unless it waits forever.
+    deadline: Mutex<Option<i64>>,
+}
+
+impl Lifecycle {
+    // Milliseconds left until the deadline of the shutdown, if there is one.
+    fn remaining(&self) -> Option<i64> {
+        let deadline = self.deadline.lock().unwrap();
+        deadline.map(|deadline| (deadline - self.clock.mono()).max(0))
+    }
+
+    // Set the linger of the open sockets to `linger` milliseconds. libzmq reads the linger
+    // of a socket atomically, as its reaper does, so it is set from any thread.
+    fn set_linger(&self, linger: i64) {
+        let linger = linger.min(i64::from(i32::MAX)) as i32;
+        for socket in self.open.lock().unwrap().values() {
+            let rc = unsafe {
+                zmq_sys::zmq_setsockopt(
+                    socket.0,
+                    zmq_sys::ZMQ_LINGER as i32,
+                    &linger as *const i32 as *const c_void,
+                    mem::size_of::<i32>(),
+                )
+            };
+            if rc == -1 {
+                nlog!(debug, "socket linger not set error={}", last_error());
+            }
+        }
+    }
+}
+
+impl Drop for Lifecycle {
+    fn drop(&mut self) {
+        // every managed socket is closed by now, as each of them shares the lifecycle.
+        if let Some(context) = self.context.get_mut().unwrap().take() {
+            if let Err(e) = context.term() {
+                nlog!(warn, "context not terminated error={}", e);
+            }
+        }
+    }
+}
+
+// Termination of the context, which runs on a thread of its own.
+enum Termination {
+    Running,
+    Pending(
+        mpsc::Receiver<Result<(), zmq::Error>>,
+        thread::JoinHandle<()>,
+    ),
+    Done,
+}
+
+/// Owner of a context, and of the lifecycle of its sockets.
+pub struct ContextManager {
+    lifecycle: Arc<Lifecycle>,
+    termination: Mutex<Termination>,
+}
+
+impl ContextManager {
+    /// Create a new `ContextManager`, with a context of its own.
+    pub fn new() -> ContextManager {
+        ContextManager {
+            lifecycle: Arc::new(Lifecycle {
+                clock: Clock::new(),
+                context: Mutex::new(Some(RawContext::new())),
+                next_socket: AtomicUsize::new(0),
+                open: Mutex::new(HashMap::new()),
+                shutting_down: AtomicBool::new(false),
+                deadline: Mutex::new(None),
+            }),
+            termination: Mutex::new(Termination::Running),
+        }
+    }
+
+    /// Create a new `ContextManager`, with a context of its own, that runs `io_threads` I/O
+    /// threads.
+    pub fn with_io_threads(io_threads: i32) -> Result<ContextManager, ContextError> {
+        let manager = ContextManager::new();
+        manager.set_io_threads(io_threads)?;
+        Ok(manager)
+    }
+
+    /// Create a new socket of `kind`, that is counted until it is closed. Fails with
+    /// `ContextError::Terminated` once `shutdown` was called.
+    pub fn socket<T: Into<zmq::SocketType>>(&self, kind: T) -> Result<ManagedSocket, ContextError> {
+        // the socket is counted before the shutdown can take the context.
+        let context = self.lifecycle.context.lock().unwrap();
+        let mut socket = match *context {
+            Some(ref context) => context.socket(kind.into())?,
+            None => return Err(ContextError::Terminated),
+        };
+        let id = self.lifecycle.next_socket.fetch_add(1, Ordering::SeqCst);
+        self.lifecycle
+            .open
+            .lock()
+            .unwrap()
+            .insert(id, RawSocket(socket.as_mut_ptr()));
+        Ok(ManagedSocket {
+            socket: Some(socket),
+            id,
+            lifecycle: self.lifecycle.clone(),
+        })
+    }
+
+    /// Returns the number of I/O threads of the context.
+    pub fn io_threads(&self) -> Result<i32, ContextError> {
+        self.get_option(zmq_sys::ZMQ_IO_THREADS)
+    }
+
+    /// Set the number of I/O threads of the context. libzmq starts them along with the
+    /// first socket of the context, so this has no effect once it created a socket.
+    pub fn set_io_threads(&self, io_threads: i32) -> Result<(), ContextError> {
+        self.set_option(zmq_sys::ZMQ_IO_THREADS, io_threads)
+    }
+
+    /// Returns the maximum number of sockets of the context.
+    pub fn max_sockets(&self) -> Result<i32, ContextError> {
+        self.get_option(zmq_sys::ZMQ_MAX_SOCKETS)
+    }
+
+    /// Set the maximum number of sockets of the context, up to `socket_limit`. Like the I/O
+    /// threads, this has no effect once the context created a socket.
+    pub fn set_max_sockets(&self, max_sockets: i32) -> Result<(), ContextError> {
+        self.set_option(zmq_sys::ZMQ_MAX_SOCKETS, max_sockets)
+    }
+
+    /// Returns the largest number of sockets that libzmq allows for a context.
+    pub fn socket_limit(&self) -> Result<i32, ContextError> {
+        self.get_option(zmq_sys::ZMQ_SOCKET_LIMIT)
+    }
+
+    // Options fail with `ContextError::Terminated` once the shutdown took the context.
+    fn get_option(&self, option: u32) -> Result<i32, ContextError> {
+        match *self.lifecycle.context.lock().unwrap() {
+            Some(ref context) => context.get(option),
+            None => Err(ContextError::Terminated),
+        }
+    }
+
+    fn set_option(&self, option: u32, value: i32) -> Result<(), ContextError> {
+        match *self.lifecycle.context.lock().unwrap() {
+            Some(ref context) => context.set(option, value),
+            None => Err(ContextError::Terminated),
+        }
+    }
+
+    /// Returns the number of sockets created through the manager, that are still open.
+    pub fn open_sockets(&self) -> usize {
+        self.lifecycle.open.lock().unwrap().len()
+    }
+
+    /// Returns true once `shutdown` was called.
+    pub fn is_shutting_down(&self) -> bool {
+        self.lifecycle.shutting_down.load(Ordering::SeqCst)
+    }
+
+    /// Terminate the context, waiting up to `timeout` milliseconds, or forever if it is
+    /// `-1`, for its sockets to be closed.
+    ///
+    /// The open sockets of the manager linger until the timeout at most, and blocking calls
+    /// on the sockets of the context fail with `ETERM`. Fails with
+    /// `ContextError::OpenSockets` if sockets are still open after the timeout, and the
+    /// context is terminated in the background, once they are closed.
+    pub fn shutdown(&self, timeout: i64) -> Result<(), ContextError> {
+        let mut termination = self.termination.lock().unwrap();
+        if let Termination::Running = *termination {
+            let context = self.lifecycle.context.lock().unwrap().take();
+            self.lifecycle.shutting_down.store(true, Ordering::SeqCst);
+            if timeout >= 0 {
+                *self.lifecycle.deadline.lock().unwrap() =
+                    Some(self.lifecycle.clock.mono() + timeout);
+                self.lifecycle.set_linger(timeout);
+            }
+            let (tx, rx) = mpsc::channel();
+            let handle = run_named_thread("context-term", move || {
+                // the context is terminated once, here, and not again on drop.
+                let result = context.map_or(Ok(()), RawContext::term);
+                let _ = tx.send(result);
+            })?;
+            *termination = Termination::Pending(rx, handle);
+        }
+        let terminated = match *termination {
+            Termination::Pending(ref rx, _) if timeout < 0 => rx.recv().ok(),
+            Termination::Pending(ref rx, _) => {
+                rx.recv_timeout(Duration::from_millis(timeout as u64)).ok()
+            }
+            _ => return Ok(()),
+        };
+        match terminated {
+            Some(result) => {
+                nlog!(debug, "context terminated");
+                if let Termination::Pending(_, handle) =
+                    mem::replace(&mut *termination, Termination::Done)
+                {
+                    // the thread is done once it sent its result.
+                    let _ = handle.join();
+                }
+                Ok(result?)
+            }
+            None => Err(ContextError::OpenSockets(self.open_sockets())),
+        }
+    }
+}
+
+impl Default for ContextManager {
+    fn default() -> Self {
+        ContextManager::new()
+    }
+}
+
+/// Returns the process-wide context, which is the same in every thread, so that their
+/// sockets connect over inproc endpoints. It is never terminated, unlike the contexts of
+/// `ContextManager`s.
+pub fn sys_context() -> zmq::Context {
+    static CONTEXT: OnceLock<zmq::Context> = OnceLock::new();
+    CONTEXT.get_or_init(zmq::Context::new).clone()
+}
+
+/// Returns a number of I/O threads for the available parallelism: one for every four CPUs,
+/// and at least one, leaving the others to the threads of the application.
+pub fn default_io_threads() -> i32 {
+    let cpus = thread::available_parallelism().map_or(1, |cpus| cpus.get());
+    (cpus / 4).max(1) as i32
+}
+
+// Returns the error of the last call to libzmq on this thread.
+fn last_error() -> zmq::Error {
+    zmq::Error::from_raw(unsafe { zmq_sys::zmq_errno() })
+}
+
+// Returns the libzmq constant of `kind`, which zmq 0.9 keeps to itself.
+fn raw_socket_type(kind: zmq::SocketType) -> i32 {
+    let kind = match kind {
+        zmq::PAIR => zmq_sys::ZMQ_PAIR,
+        zmq::PUB => zmq_sys::ZMQ_PUB,
+        zmq::SUB => zmq_sys::ZMQ_SUB,
+        zmq::REQ => zmq_sys::ZMQ_REQ,
+        zmq::REP => zmq_sys::ZMQ_REP,
+        zmq::DEALER => zmq_sys::ZMQ_DEALER,
+        zmq::ROUTER => zmq_sys::ZMQ_ROUTER,
+        zmq::PULL => zmq_sys::ZMQ_PULL,
+        zmq::PUSH => zmq_sys::ZMQ_PUSH,
+        zmq::XPUB => zmq_sys::ZMQ_XPUB,
+        zmq::XSUB => zmq_sys::ZMQ_XSUB,
+        zmq::STREAM => zmq_sys::ZMQ_STREAM,
+    };
+    kind as i32
+}
+
+/// A socket created by a `ContextManager`, which is counted until it is closed, on drop.
+pub struct ManagedSocket {
+    socket: Option<zmq::Socket>,
+    id: usize,
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Deref for ManagedSocket {
+    type Target = zmq::Socket;
+
+    fn deref(&self) -> &zmq::Socket {
+        self.socket.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for ManagedSocket {
+    fn deref_mut(&mut self) -> &mut zmq::Socket {
+        self.socket.as_mut().unwrap()
+    }
+}
+
+impl Drop for ManagedSocket {
+    fn drop(&mut self) {
+        if let Some(socket) = self.socket.take() {
+            self.lifecycle.open.lock().unwrap().remove(&self.id);
+            // pending messages must not outlive the shutdown.
+            if let Some(remaining) = self.lifecycle.remaining() {
+                let _ = socket.set_linger(remaining.min(i64::from(i32::MAX)) as i32);
+            }
+            drop(socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sys_context_is_shared_across_threads() {
+        let receiver = sys_context().socket(zmq::PAIR).unwrap();
+        receiver.bind("inproc://sys_context_shared").unwrap();
+        thread::spawn(|| {
+            let sender = sys_context().socket(zmq::PAIR).unwrap();
+            sender.connect("inproc://sys_context_shared").unwrap();
+            sender.send("across", 0).unwrap();
+        })
+        .join()
+        .unwrap();
+        receiver.set_rcvtimeo(1_000).unwrap();
+        assert_eq!(receiver.recv_bytes(0).unwrap(), b"across");
+    }
+
+    #[test]
+    fn context_options_are_set_before_the_first_socket() {
+        let manager = ContextManager::with_io_threads(default_io_threads() + 1).unwrap();
+        assert_eq!(manager.io_threads().unwrap(), default_io_threads() + 1);
+        assert_eq!(ContextManager::new().io_threads().unwrap(), 1);
+        manager.set_max_sockets(64).unwrap();
+        assert_eq!(manager.max_sockets().unwrap(), 64);
+        assert!(manager.socket_limit().unwrap() >= 64);
+        match manager.set_io_threads(-1) {
+            Err(ContextError::Zmq(zmq::Error::EINVAL)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        // the busiest socket gets the second I/O thread to itself.
+        let pull = manager.socket(zmq::PULL).unwrap();
+        pull.set_affinity(0b10).unwrap();
+        pull.bind("tcp://127.0.0.1:*").unwrap();
+        assert_eq!(pull.get_affinity().unwrap(), 0b10);
+        let endpoint = pull.get_last_endpoint().unwrap().unwrap();
+        let push = manager.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        push.send("pinned", 0).unwrap();
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"pinned");
+        drop((pull, push));
+
+        manager.shutdown(1_000).unwrap();
+        match manager.io_threads() {
+            Err(ContextError::Terminated) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn managers_keep_their_context_to_themselves() {
+        let manager = ContextManager::new();
+        let pull = manager.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://context_managed").unwrap();
+        // inproc endpoints of the manager are not reachable from the process-wide context.
+        let other = sys_context().socket(zmq::PUSH).unwrap();
+        other.set_linger(0).unwrap();
+        other.connect("inproc://context_managed").unwrap();
+        other.send("lost", 0).unwrap();
+        pull.set_rcvtimeo(50).unwrap();
+        assert_eq!(pull.recv_bytes(0), Err(zmq::Error::EAGAIN));
+        drop(pull);
+        manager.shutdown(1_000).unwrap();
+        // the process-wide context outlives the shutdown of every manager.
+        assert!(sys_context().socket(zmq::PUSH).is_ok());
+        match manager.socket(zmq::PUSH) {
+            Err(ContextError::Terminated) => {}
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+    }
+
+    #[test]
+    fn shutdown_waits_for_open_sockets() {
+        let manager = ContextManager::new();
+        let socket = manager.socket(zmq::PUSH).unwrap();
+        socket.connect("tcp://127.0.0.1:1").unwrap();
+        // queued for a peer that never comes.
+        socket.send("pending", zmq::DONTWAIT).unwrap();
+        assert_eq!(manager.open_sockets(), 1);
+        match manager.shutdown(50) {
+            Err(ContextError::OpenSockets(1)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match manager.socket(zmq::PUSH) {
+            Err(ContextError::Terminated) => {}
+            other => panic!("unexpected result {:?}", other.err()),
+        }
+        // the pending message lingers no longer than the shutdown.
+        drop(socket);
+        assert_eq!(manager.open_sockets(), 0);
+        manager.shutdown(1_000).unwrap();
+        manager.shutdown(0).unwrap();
+    }
+
+    #[test]
+    fn shutdown_sets_the_linger_of_open_sockets() {
+        let manager = ContextManager::new();
+        let socket = manager.socket(zmq::PUSH).unwrap();
+        socket.connect("tcp://127.0.0.1:1").unwrap();
+        socket.send("pending", zmq::DONTWAIT).unwrap();
+        assert_eq!(socket.get_linger(), Ok(-1));
+        assert!(manager.shutdown(50).is_err());
+        assert_eq!(socket.get_linger(), Ok(50));
+        drop(socket);
+        manager.shutdown(1_000).unwrap();
+        // the context is terminated once, and not again when the manager is dropped.
+        drop(manager);
+    }
+}
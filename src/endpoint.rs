@@ -0,0 +1,631 @@
+//! Network endpoints.
+//!
+//! An `Endpoint` is a parsed, and validated, address that sockets can bind or connect to.
+//! Endpoints are parsed from, and rendered back to, the strings used by ØMQ.
+//!
+//! ```
+//! use neuras::endpoint::{Endpoint, Port};
+//!
+//! let endpoint: Endpoint = "tcp://127.0.0.1:*".parse().unwrap();
+//! assert_eq!(endpoint, Endpoint::Tcp { host: "127.0.0.1".into(), port: Port::Wildcard });
+//! assert_eq!(endpoint.to_string(), "tcp://127.0.0.1:*");
+//! ```
+//!
+//! Parsing does not look the hosts up, so ØMQ only fails on unknown hosts, or on ports
+//! that can not be connected to, when a socket binds or connects, with a vague `EINVAL`.
+//! An `EndpointCheck` does those checks beforehand, naming the failing host.
+use std::fmt;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc;
+use std::thread;
+
+use url::{self, Url};
+use zmq;
+
+/// Errors from parsing endpoints.
+#[derive(Debug, Fail, PartialEq)]
+pub enum AddressParse {
+    #[fail(display = "endpoint has no transport: {}", _0)]
+    MissingTransport(String),
+    #[fail(display = "unknown transport: {}", _0)]
+    UnknownTransport(String),
+    #[fail(display = "endpoint has no address: {}", _0)]
+    MissingAddress(String),
+    #[fail(display = "endpoint has no host: {}", _0)]
+    MissingHost(String),
+    #[fail(display = "endpoint has no valid port: {}", _0)]
+    InvalidPort(String),
+    #[fail(display = "invalid multicast endpoint: {}", _0)]
+    InvalidMulticast(String),
+    #[fail(display = "invalid url: {}", _0)]
+    Url(#[cause] url::ParseError),
+    #[fail(display = "host {} could not be resolved: {}", _0, _1)]
+    Unresolved(String, String),
+}
+
+impl From<url::ParseError> for AddressParse {
+    fn from(e: url::ParseError) -> AddressParse {
+        AddressParse::Url(e)
+    }
+}
+
+/// Transports that sockets can bind or connect to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transport {
+    Tcp,
+    Ipc,
+    Inproc,
+    /// UDP, for RADIO and DISH sockets. Requires libzmq built with the draft API.
+    Udp,
+    /// WebSocket, requires libzmq 4.3.2 or newer, built with WebSocket support.
+    Ws,
+    /// WebSocket over TLS, requires libzmq built with WebSocket and TLS support.
+    Wss,
+    /// PGM reliable multicast, over raw IP. Requires libzmq built with OpenPGM.
+    Pgm,
+    /// PGM reliable multicast, encapsulated in UDP. Requires libzmq built with OpenPGM.
+    Epgm,
+    /// NORM reliable multicast. Requires libzmq built with NORM.
+    Norm,
+}
+
+impl Transport {
+    /// Returns the name of the transport, as used by endpoint schemes.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transport::Tcp => "tcp",
+            Transport::Ipc => "ipc",
+            Transport::Inproc => "inproc",
+            Transport::Udp => "udp",
+            Transport::Ws => "ws",
+            Transport::Wss => "wss",
+            Transport::Pgm => "pgm",
+            Transport::Epgm => "epgm",
+            Transport::Norm => "norm",
+        }
+    }
+
+    /// Returns the transport with the given scheme name, if any.
+    pub fn from_name(name: &str) -> Option<Transport> {
+        let transport = match name {
+            "tcp" => Transport::Tcp,
+            "ipc" => Transport::Ipc,
+            "inproc" => Transport::Inproc,
+            "udp" => Transport::Udp,
+            "ws" => Transport::Ws,
+            "wss" => Transport::Wss,
+            "pgm" => Transport::Pgm,
+            "epgm" => Transport::Epgm,
+            "norm" => Transport::Norm,
+            _ => return None,
+        };
+        Some(transport)
+    }
+
+    /// Returns true for multicast transports.
+    pub fn is_multicast(&self) -> bool {
+        matches!(*self, Transport::Pgm | Transport::Epgm | Transport::Norm)
+    }
+
+    /// Returns true if sockets of `socket_type` can use the transport. Multicast transports
+    /// only carry one-way, one-to-many, traffic, so they are limited to publishers and
+    /// subscribers.
+    pub fn allows<T: Into<zmq::SocketType>>(&self, socket_type: T) -> bool {
+        if !self.is_multicast() {
+            return true;
+        }
+        matches!(
+            socket_type.into(),
+            zmq::PUB | zmq::XPUB | zmq::SUB | zmq::XSUB
+        )
+    }
+
+    /// Returns true for WebSocket transports.
+    pub fn is_websocket(&self) -> bool {
+        *self == Transport::Ws || *self == Transport::Wss
+    }
+
+    /// Returns true if the local libzmq supports the transport.
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            Transport::Tcp | Transport::Inproc => true,
+            Transport::Udp => zmq::has("draft").unwrap_or(false),
+            _ => zmq::has(self.name()).unwrap_or(false),
+        }
+    }
+}
+
+/// Ports of network endpoints.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Port {
+    /// A fixed port number.
+    Fixed(u16),
+    /// Any free port, chosen when binding (`*`).
+    Wildcard,
+}
+
+impl fmt::Display for Port {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Port::Fixed(port) => write!(f, "{}", port),
+            Port::Wildcard => write!(f, "*"),
+        }
+    }
+}
+
+/// Checks of endpoints, before sockets bind, or connect, to them.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EndpointCheck {
+    /// Look up the hosts of network endpoints.
+    pub resolve_hosts: bool,
+    /// Reject ports that can not be connected to, i.e. `*` and `0`.
+    pub check_ports: bool,
+}
+
+impl EndpointCheck {
+    /// Create a new `EndpointCheck`, that checks ports, without looking hosts up.
+    pub fn new() -> Self {
+        EndpointCheck {
+            resolve_hosts: false,
+            check_ports: true,
+        }
+    }
+
+    /// Create a new `EndpointCheck`, that checks ports, and looks hosts up.
+    pub fn resolving() -> Self {
+        EndpointCheck {
+            resolve_hosts: true,
+            check_ports: true,
+        }
+    }
+}
+
+/// Parsed endpoints.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Endpoint {
+    /// `tcp://host:port`, where the host may be an interface name, or `*`.
+    Tcp { host: String, port: Port },
+    /// `ipc://path`, where the path may be `*` for a temporary file.
+    Ipc { path: PathBuf },
+    /// `inproc://name`, within a single context.
+    Inproc { name: String },
+    /// `udp://host:port`.
+    Udp { host: String, port: Port },
+    /// `ws://host:port/path`, or `wss://host:port/path` when `secure`.
+    Ws {
+        host: String,
+        port: Port,
+        path: String,
+        secure: bool,
+    },
+    /// `pgm://`, `epgm://` or `norm://` with an optional `interface;`, followed by the
+    /// multicast `group:port`.
+    Multicast {
+        transport: Transport,
+        interface: Option<String>,
+        group: String,
+        port: u16,
+    },
+}
+
+impl Endpoint {
+    /// Parse and validate an endpoint.
+    pub fn parse(endpoint: &str) -> Result<Endpoint, AddressParse> {
+        let scheme_end = endpoint
+            .find("://")
+            .ok_or_else(|| AddressParse::MissingTransport(endpoint.to_string()))?;
+        let scheme = &endpoint[..scheme_end];
+        let transport = Transport::from_name(scheme)
+            .ok_or_else(|| AddressParse::UnknownTransport(scheme.to_string()))?;
+        let address = &endpoint[scheme_end + 3..];
+        if address.is_empty() {
+            return Err(AddressParse::MissingAddress(endpoint.to_string()));
+        }
+        let parsed = match transport {
+            Transport::Ipc => Endpoint::Ipc {
+                path: PathBuf::from(address),
+            },
+            Transport::Inproc => Endpoint::Inproc {
+                name: address.to_string(),
+            },
+            Transport::Tcp | Transport::Udp => {
+                let (host, port, _) = parse_authority(endpoint, scheme, address)?;
+                match transport {
+                    Transport::Tcp => Endpoint::Tcp { host, port },
+                    _ => Endpoint::Udp { host, port },
+                }
+            }
+            Transport::Ws | Transport::Wss => {
+                let (host, port, path) = parse_authority(endpoint, scheme, address)?;
+                Endpoint::Ws {
+                    host,
+                    port,
+                    path,
+                    secure: transport == Transport::Wss,
+                }
+            }
+            Transport::Pgm | Transport::Epgm | Transport::Norm => {
+                parse_multicast(endpoint, transport, address)?
+            }
+        };
+        Ok(parsed)
+    }
+
+    /// Returns the transport of the endpoint.
+    pub fn transport(&self) -> Transport {
+        match *self {
+            Endpoint::Tcp { .. } => Transport::Tcp,
+            Endpoint::Ipc { .. } => Transport::Ipc,
+            Endpoint::Inproc { .. } => Transport::Inproc,
+            Endpoint::Udp { .. } => Transport::Udp,
+            Endpoint::Ws { secure: false, .. } => Transport::Ws,
+            Endpoint::Ws { secure: true, .. } => Transport::Wss,
+            Endpoint::Multicast { transport, .. } => transport,
+        }
+    }
+
+    /// Returns the port of network endpoints.
+    pub fn port(&self) -> Option<Port> {
+        match *self {
+            Endpoint::Tcp { port, .. } | Endpoint::Udp { port, .. } | Endpoint::Ws { port, .. } => {
+                Some(port)
+            }
+            Endpoint::Multicast { port, .. } => Some(Port::Fixed(port)),
+            _ => None,
+        }
+    }
+
+    /// Returns true if the endpoint has a wildcard port, to be chosen when binding.
+    pub fn has_wildcard_port(&self) -> bool {
+        self.port() == Some(Port::Wildcard)
+    }
+
+    /// Returns the host of `tcp`, `udp` and websocket endpoints.
+    pub fn host(&self) -> Option<&str> {
+        match *self {
+            Endpoint::Tcp { ref host, .. }
+            | Endpoint::Udp { ref host, .. }
+            | Endpoint::Ws { ref host, .. } => Some(host),
+            _ => None,
+        }
+    }
+
+    /// Look up the socket addresses of the host, which blocks on DNS for hostnames.
+    /// Endpoints without a host, or with the `*` host, have none.
+    pub fn resolve(&self) -> Result<Vec<SocketAddr>, AddressParse> {
+        let host = match self.host() {
+            Some("*") | None => return Ok(Vec::new()),
+            Some(host) => host,
+        };
+        let port = match self.port() {
+            Some(Port::Fixed(port)) => port,
+            _ => 0,
+        };
+        // IPv6 hosts are bracketed, as in URLs.
+        let name = host.trim_start_matches('[').trim_end_matches(']');
+        match (name, port).to_socket_addrs() {
+            Ok(addrs) => Ok(addrs.collect()),
+            Err(e) => Err(AddressParse::Unresolved(host.to_string(), e.to_string())),
+        }
+    }
+
+    /// Look up the socket addresses of the host on another thread, whose result is sent to
+    /// the returned receiver, e.g. to wait for it with a timeout.
+    pub fn resolve_async(&self) -> mpsc::Receiver<Result<Vec<SocketAddr>, AddressParse>> {
+        let (sender, receiver) = mpsc::channel();
+        let endpoint = self.clone();
+        thread::spawn(move || {
+            // the receiver may have given up already.
+            let _ = sender.send(endpoint.resolve());
+        });
+        receiver
+    }
+
+    /// Check that sockets can connect to the endpoint, which needs a host, and a port.
+    pub fn check_connect(&self, check: &EndpointCheck) -> Result<(), AddressParse> {
+        if check.check_ports {
+            match self.port() {
+                Some(Port::Wildcard) | Some(Port::Fixed(0)) => {
+                    return Err(AddressParse::InvalidPort(self.to_string()))
+                }
+                _ => {}
+            }
+            if self.host() == Some("*") {
+                return Err(AddressParse::MissingHost(self.to_string()));
+            }
+        }
+        if check.resolve_hosts {
+            self.resolve()?;
+        }
+        Ok(())
+    }
+
+    /// Check that sockets can bind to the endpoint. Hosts that are not found are taken for
+    /// the names of network interfaces, e.g. `eth0`, unless they have dots.
+    pub fn check_bind(&self, check: &EndpointCheck) -> Result<(), AddressParse> {
+        if check.check_ports {
+            if let Endpoint::Multicast { port: 0, .. } = *self {
+                return Err(AddressParse::InvalidPort(self.to_string()));
+            }
+        }
+        if check.resolve_hosts {
+            match self.resolve() {
+                Err(AddressParse::Unresolved(ref host, _)) if !host.contains('.') => {}
+                Err(e) => return Err(e),
+                Ok(_) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = AddressParse;
+
+    fn from_str(s: &str) -> Result<Endpoint, AddressParse> {
+        Endpoint::parse(s)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Tcp { ref host, port } => write!(f, "tcp://{}:{}", host, port),
+            Endpoint::Ipc { ref path } => write!(f, "ipc://{}", path.display()),
+            Endpoint::Inproc { ref name } => write!(f, "inproc://{}", name),
+            Endpoint::Udp { ref host, port } => write!(f, "udp://{}:{}", host, port),
+            Endpoint::Ws {
+                ref host,
+                port,
+                ref path,
+                secure,
+            } => {
+                let scheme = if secure { "wss" } else { "ws" };
+                write!(f, "{}://{}:{}{}", scheme, host, port, path)
+            }
+            Endpoint::Multicast {
+                transport,
+                ref interface,
+                ref group,
+                port,
+            } => {
+                write!(f, "{}://", transport.name())?;
+                if let Some(ref interface) = *interface {
+                    write!(f, "{};", interface)?;
+                }
+                write!(f, "{}:{}", group, port)
+            }
+        }
+    }
+}
+
+/// Types that can be turned into endpoints, so APIs can take either strings or `Endpoint`.
+pub trait ToEndpoint {
+    /// Parse, or clone, into an `Endpoint`.
+    fn to_endpoint(&self) -> Result<Endpoint, AddressParse>;
+}
+
+impl ToEndpoint for Endpoint {
+    fn to_endpoint(&self) -> Result<Endpoint, AddressParse> {
+        Ok(self.clone())
+    }
+}
+
+impl ToEndpoint for str {
+    fn to_endpoint(&self) -> Result<Endpoint, AddressParse> {
+        Endpoint::parse(self)
+    }
+}
+
+impl ToEndpoint for String {
+    fn to_endpoint(&self) -> Result<Endpoint, AddressParse> {
+        Endpoint::parse(self)
+    }
+}
+
+impl<T: ToEndpoint + ?Sized> ToEndpoint for &T {
+    fn to_endpoint(&self) -> Result<Endpoint, AddressParse> {
+        (**self).to_endpoint()
+    }
+}
+
+// Parse `host:port[/path]` with the `url` crate. `*` hosts and ports are wildcards, which
+// are swapped for placeholders the `url` crate accepts.
+fn parse_authority(
+    endpoint: &str,
+    scheme: &str,
+    address: &str,
+) -> Result<(String, Port, String), AddressParse> {
+    let (authority, path) = match address.find('/') {
+        Some(i) => (&address[..i], &address[i..]),
+        None => (address, ""),
+    };
+    let port_start = authority
+        .rfind(':')
+        .ok_or_else(|| AddressParse::InvalidPort(endpoint.to_string()))?;
+    let (host, port) = (&authority[..port_start], &authority[port_start + 1..]);
+    let port = match port {
+        "*" => Port::Wildcard,
+        _ => Port::Fixed(
+            port.parse()
+                .map_err(|_| AddressParse::InvalidPort(endpoint.to_string()))?,
+        ),
+    };
+    if host.is_empty() {
+        return Err(AddressParse::MissingHost(endpoint.to_string()));
+    }
+    if host != "*" {
+        let url = Url::parse(&format!("{}://{}{}", scheme, host, path))?;
+        if url.host_str().is_none() {
+            return Err(AddressParse::MissingHost(endpoint.to_string()));
+        }
+    }
+    Ok((host.to_string(), port, path.to_string()))
+}
+
+// Multicast endpoints are `interface;group:port`; NORM may omit the interface.
+fn parse_multicast(
+    endpoint: &str,
+    transport: Transport,
+    address: &str,
+) -> Result<Endpoint, AddressParse> {
+    let invalid = || AddressParse::InvalidMulticast(endpoint.to_string());
+    let (interface, group) = match address.rfind(';') {
+        Some(i) if i > 0 => (Some(address[..i].to_string()), &address[i + 1..]),
+        Some(_) => return Err(invalid()),
+        None if transport == Transport::Norm => (None, address),
+        None => return Err(invalid()),
+    };
+    let port_start = group.rfind(':').ok_or_else(invalid)?;
+    if port_start == 0 {
+        return Err(invalid());
+    }
+    let port = group[port_start + 1..].parse().map_err(|_| invalid())?;
+    Ok(Endpoint::Multicast {
+        transport,
+        interface,
+        group: group[..port_start].to_string(),
+        port,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(endpoint: &str) -> Endpoint {
+        let parsed = Endpoint::parse(endpoint).unwrap();
+        assert_eq!(parsed.to_string(), endpoint);
+        parsed
+    }
+
+    #[test]
+    fn tcp_endpoints_have_hosts_and_ports() {
+        assert_eq!(
+            round_trip("tcp://127.0.0.1:5555"),
+            Endpoint::Tcp {
+                host: "127.0.0.1".into(),
+                port: Port::Fixed(5555),
+            }
+        );
+        assert!(round_trip("tcp://*:*").has_wildcard_port());
+        round_trip("tcp://[::1]:5555");
+        round_trip("tcp://eth0:5555");
+    }
+
+    #[test]
+    fn local_endpoints_have_paths_and_names() {
+        assert_eq!(
+            round_trip("ipc:///tmp/neuras.sock"),
+            Endpoint::Ipc {
+                path: PathBuf::from("/tmp/neuras.sock"),
+            }
+        );
+        assert_eq!(
+            round_trip("inproc://neuras.actor"),
+            Endpoint::Inproc {
+                name: "neuras.actor".into(),
+            }
+        );
+    }
+
+    #[test]
+    fn websocket_and_udp_endpoints_are_parsed() {
+        let ws = round_trip("wss://localhost:8080/neuras");
+        assert_eq!(ws.transport(), Transport::Wss);
+        assert_eq!(ws.port(), Some(Port::Fixed(8080)));
+        assert_eq!(round_trip("ws://127.0.0.1:*").transport(), Transport::Ws);
+        assert_eq!(
+            round_trip("udp://127.0.0.1:5555").transport(),
+            Transport::Udp
+        );
+    }
+
+    #[test]
+    fn multicast_endpoints_are_parsed() {
+        assert_eq!(
+            round_trip("epgm://eth0;239.192.1.1:5555"),
+            Endpoint::Multicast {
+                transport: Transport::Epgm,
+                interface: Some("eth0".into()),
+                group: "239.192.1.1".into(),
+                port: 5555,
+            }
+        );
+        round_trip("norm://239.192.1.1:5555");
+    }
+
+    #[test]
+    fn invalid_endpoints_are_rejected() {
+        let cases = vec![
+            ("127.0.0.1:5555", "MissingTransport"),
+            ("http://127.0.0.1:80", "UnknownTransport"),
+            ("inproc://", "MissingAddress"),
+            ("tcp://127.0.0.1", "InvalidPort"),
+            ("tcp://127.0.0.1:port", "InvalidPort"),
+            ("tcp://:5555", "MissingHost"),
+            ("ws://:5555", "MissingHost"),
+            ("epgm://eth0", "InvalidMulticast"),
+            ("pgm://;239.192.1.1:5555", "InvalidMulticast"),
+            ("norm://239.192.1.1:port", "InvalidMulticast"),
+        ];
+        for (endpoint, expected) in cases {
+            let err = Endpoint::parse(endpoint).unwrap_err();
+            assert!(format!("{:?}", err).starts_with(expected), "{}", endpoint);
+        }
+    }
+
+    #[test]
+    fn strings_and_endpoints_convert_to_endpoints() {
+        let endpoint = Endpoint::parse("inproc://to").unwrap();
+        assert_eq!("inproc://to".to_endpoint().unwrap(), endpoint);
+        assert_eq!(String::from("inproc://to").to_endpoint().unwrap(), endpoint);
+        assert_eq!(endpoint.to_endpoint().unwrap(), endpoint);
+    }
+
+    #[test]
+    fn endpoints_are_checked_before_connecting() {
+        let check = EndpointCheck::resolving();
+        let local = Endpoint::parse("tcp://localhost:5555").unwrap();
+        assert!(local.check_connect(&check).is_ok());
+        assert!(!local.resolve().unwrap().is_empty());
+        let ipv6 = Endpoint::parse("tcp://[::1]:5555").unwrap();
+        assert_eq!(ipv6.resolve().unwrap()[0].port(), 5555);
+
+        let wildcard = Endpoint::parse("tcp://127.0.0.1:*").unwrap();
+        assert!(wildcard.check_bind(&check).is_ok());
+        assert_eq!(
+            wildcard.check_connect(&check),
+            Err(AddressParse::InvalidPort("tcp://127.0.0.1:*".into()))
+        );
+        assert!(wildcard.check_connect(&EndpointCheck::default()).is_ok());
+
+        let unknown = Endpoint::parse("tcp://no-such-host.invalid:5555").unwrap();
+        assert!(unknown.check_connect(&EndpointCheck::new()).is_ok());
+        for result in [
+            unknown.check_connect(&check),
+            unknown.check_bind(&check),
+            unknown.resolve_async().recv().unwrap().map(|_| ()),
+        ] {
+            match result {
+                Err(AddressParse::Unresolved(host, _)) => {
+                    assert_eq!(host, "no-such-host.invalid")
+                }
+                other => panic!("host was resolved: {:?}", other),
+            }
+        }
+        // hosts without dots may be network interfaces.
+        let interface = Endpoint::parse("tcp://neuras0:5555").unwrap();
+        assert!(interface.check_bind(&check).is_ok());
+    }
+
+    #[test]
+    fn multicast_transports_are_limited_to_pub_and_sub_sockets() {
+        assert!(Transport::Epgm.allows(zmq::PUB));
+        assert!(Transport::Pgm.allows(zmq::XSUB));
+        assert!(!Transport::Norm.allows(zmq::REQ));
+        assert!(Transport::Tcp.allows(zmq::REQ));
+    }
+}
@@ -0,0 +1,188 @@
+//! Errors of every module, in a single type.
+//!
+//! Modules return their own errors, wrapped in `failure::Error`. `neuras::Error` keeps
+//! them apart, one variant for each module, and implements `std::error::Error`, so that
+//! downstream crates can use `?` across modules, without depending on `failure`.
+//!
+//! ```
+//! extern crate neuras;
+//!
+//! use neuras::actor::Actorling;
+//! use neuras::endpoint::AddressParse;
+//!
+//! fn actor(address: &str) -> Result<Actorling, neuras::Error> {
+//!     Ok(Actorling::new(address)?)
+//! }
+//!
+//! # fn main() {
+//! match actor("127.0.0.1:5555") {
+//!     Err(neuras::Error::Address(AddressParse::MissingTransport(_))) => {}
+//!     _ => panic!("addresses need a transport"),
+//! }
+//! # }
+//! ```
+use super::actor::ActorlingError;
+use super::broker::BrokerError;
+use super::channel::ChannelError;
+use super::clock::ClockError;
+use super::context::ContextError;
+use super::endpoint::AddressParse;
+use super::eventbus::EventBusError;
+use super::filetransfer::FileTransferError;
+use super::freelance::FreelanceError;
+use super::jobqueue::JobQueueError;
+use super::kvstate::KvStateError;
+use super::message::DeliveryError;
+use super::pool::PoolError;
+use super::ppp::PppError;
+use super::proxy::ProxyError;
+use super::registry::RegistryError;
+use super::rpc::RpcError;
+use super::security::SecurityError;
+use super::socket::{FlowError, HelloError, OutboxError, SocketError};
+use super::titanic::TitanicError;
+use super::topic::TopicError;
+use super::work::WorkError;
+
+use failure;
+use std::error;
+use std::fmt;
+use std::io;
+use std::result;
+use zmq;
+
+/// Result of the operations of every module.
+pub type Result<T> = result::Result<T, Error>;
+
+// Define the variants of `Error`, with their conversions.
+macro_rules! module_errors {
+    ($($(#[$doc:meta])* $variant:ident($error:ty),)*) => {
+        /// Errors of every module.
+        #[derive(Debug)]
+        pub enum Error {
+            $($(#[$doc])* $variant($error),)*
+            /// Errors of other crates, that are not listed above.
+            Other(failure::Error),
+        }
+
+        impl fmt::Display for Error {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                match *self {
+                    $(Error::$variant(ref e) => fmt::Display::fmt(e, f),)*
+                    Error::Other(ref e) => fmt::Display::fmt(e, f),
+                }
+            }
+        }
+
+        $(
+            impl From<$error> for Error {
+                fn from(e: $error) -> Error {
+                    Error::$variant(e)
+                }
+            }
+        )*
+
+        impl From<failure::Error> for Error {
+            // Recover the module error that was wrapped.
+            fn from(e: failure::Error) -> Error {
+                $(
+                    let e = match e.downcast::<$error>() {
+                        Ok(e) => return Error::$variant(e),
+                        Err(e) => e,
+                    };
+                )*
+                Error::Other(e)
+            }
+        }
+    };
+}
+
+module_errors! {
+    /// Errors of `actor`.
+    Actorling(ActorlingError),
+    /// Errors of `endpoint`.
+    Address(AddressParse),
+    /// Errors of `broker`.
+    Broker(BrokerError),
+    /// Errors of `channel`.
+    Channel(ChannelError),
+    /// Errors of `clock`.
+    Clock(ClockError),
+    /// Errors of `context`.
+    Context(ContextError),
+    /// Errors of `message`.
+    Delivery(DeliveryError),
+    /// Errors of `eventbus`.
+    EventBus(EventBusError),
+    /// Errors of `filetransfer`.
+    FileTransfer(FileTransferError),
+    /// Errors of `freelance`.
+    Freelance(FreelanceError),
+    /// Errors of flow-controlled sockets.
+    Flow(FlowError),
+    /// Errors of the hellos of sockets.
+    Hello(HelloError),
+    /// Errors of `jobqueue`.
+    JobQueue(JobQueueError),
+    /// Errors of `kvstate`.
+    KvState(KvStateError),
+    /// Errors of outbox sockets.
+    Outbox(OutboxError),
+    /// Errors of `pool`.
+    Pool(PoolError),
+    /// Errors of `ppp`.
+    Ppp(PppError),
+    /// Errors of `proxy`.
+    Proxy(ProxyError),
+    /// Errors of `registry`.
+    Registry(RegistryError),
+    /// Errors of `rpc`.
+    Rpc(RpcError),
+    /// Errors of `security`.
+    Security(SecurityError),
+    /// Errors of `socket`.
+    Socket(SocketError),
+    /// Errors of `titanic`.
+    Titanic(TitanicError),
+    /// Errors of `topic`.
+    Topic(TopicError),
+    /// Errors of `work`.
+    Work(WorkError),
+    /// I/O errors.
+    Io(io::Error),
+    /// ØMQ errors.
+    Zmq(zmq::Error),
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Io(ref e) => Some(e),
+            Error::Zmq(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_errors_are_recovered_from_failures() {
+        let failed: failure::Error = WorkError::DrainTimeout(2).into();
+        match Error::from(failed) {
+            Error::Work(WorkError::DrainTimeout(2)) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+        let failed: failure::Error = zmq::Error::EAGAIN.into();
+        let error = Error::from(failed);
+        assert_eq!(error.to_string(), zmq::Error::EAGAIN.to_string());
+        assert!(error::Error::source(&error).is_some());
+        let failed = failure::err_msg("elsewhere");
+        match Error::from(failed) {
+            Error::Other(ref e) => assert_eq!(e.to_string(), "elsewhere"),
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Process-wide bus of typed events.
+//!
+//! Actors, pollers, and user code in the same process publish events to the bus, and
+//! subscribe to them by topic, without knowing about each other. The bus of a context is a
+//! proxy, from an `XSUB` socket bound to `PUBLISH_ADDR`, to an `XPUB` socket bound to
+//! `SUBSCRIBE_ADDR`, and it is started by the first publisher, or subscriber, of the context.
+//! By default, they use the shared context of `context::sys_context`.
+//!
+//! Events are messages of two frames: their topic, as in `topic`, and the event encoded as
+//! a TOML table, so events are structs, or maps. As with any `PUB` socket, events published
+//! before a subscription reaches the bus are not delivered.
+use super::context::sys_context;
+use super::topic::{validate_topic, TopicError, TopicSubscriber};
+use super::utils::run_named_thread;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io;
+use std::marker::PhantomData;
+use toml;
+use zmq;
+
+/// Address of the `XSUB` socket of the bus, that publishers connect to.
+pub const PUBLISH_ADDR: &str = "inproc://neuras.eventbus.publish";
+/// Address of the `XPUB` socket of the bus, that subscribers connect to.
+pub const SUBSCRIBE_ADDR: &str = "inproc://neuras.eventbus.subscribe";
+
+/// Event bus errors.
+#[derive(Debug, Fail)]
+pub enum EventBusError {
+    #[fail(display = "invalid event: {}", _0)]
+    InvalidEvent(String),
+    #[fail(display = "event could not be encoded: {}", _0)]
+    Encode(#[cause] toml::ser::Error),
+    #[fail(display = "event could not be decoded: {}", _0)]
+    Decode(#[cause] toml::de::Error),
+    #[fail(display = "{}", _0)]
+    Topic(#[cause] TopicError),
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<toml::ser::Error> for EventBusError {
+    fn from(e: toml::ser::Error) -> EventBusError {
+        EventBusError::Encode(e)
+    }
+}
+
+impl From<toml::de::Error> for EventBusError {
+    fn from(e: toml::de::Error) -> EventBusError {
+        EventBusError::Decode(e)
+    }
+}
+
+impl From<TopicError> for EventBusError {
+    fn from(e: TopicError) -> EventBusError {
+        EventBusError::Topic(e)
+    }
+}
+
+impl From<io::Error> for EventBusError {
+    fn from(e: io::Error) -> EventBusError {
+        EventBusError::Io(e)
+    }
+}
+
+impl From<zmq::Error> for EventBusError {
+    fn from(e: zmq::Error) -> EventBusError {
+        EventBusError::Zmq(e)
+    }
+}
+
+// Start the bus of `context`, unless it is already running. The bus runs until the context
+// is terminated.
+fn start_bus(context: &zmq::Context) -> Result<(), EventBusError> {
+    let xsub = context.socket(zmq::XSUB)?;
+    match xsub.bind(PUBLISH_ADDR) {
+        Ok(()) => {}
+        Err(zmq::Error::EADDRINUSE) => return Ok(()),
+        Err(e) => return Err(e.into()),
+    }
+    let xpub = context.socket(zmq::XPUB)?;
+    xpub.bind(SUBSCRIBE_ADDR)?;
+    run_named_thread("eventbus", move || {
+        if let Err(e) = zmq::proxy(&xsub, &xpub) {
+            nlog!(debug, "eventbus ended: {}", e);
+        }
+    })?;
+    Ok(())
+}
+
+/// Publishes events to the bus.
+pub struct EventPublisher {
+    socket: zmq::Socket,
+}
+
+impl EventPublisher {
+    /// Create a new `EventPublisher` on the bus of the shared context.
+    pub fn new() -> Result<EventPublisher, EventBusError> {
+        EventPublisher::with_context(&sys_context())
+    }
+
+    /// Create a new `EventPublisher` on the bus of `context`.
+    pub fn with_context(context: &zmq::Context) -> Result<EventPublisher, EventBusError> {
+        start_bus(context)?;
+        let socket = context.socket(zmq::PUB)?;
+        socket.connect(PUBLISH_ADDR)?;
+        Ok(EventPublisher { socket })
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Publish `event` with `topic`, which can not have wildcards.
+    pub fn publish<E: Serialize>(&self, topic: &str, event: &E) -> Result<(), EventBusError> {
+        validate_topic(topic)?;
+        let encoded = toml::to_string(event)?;
+        self.socket
+            .send_multipart([topic.as_bytes(), encoded.as_bytes()], 0)?;
+        Ok(())
+    }
+}
+
+/// Receives the events of type `E` from the bus, whose topics match its filters.
+pub struct EventSubscriber<E> {
+    subscriber: TopicSubscriber,
+    _events: PhantomData<fn() -> E>,
+}
+
+impl<E: DeserializeOwned> EventSubscriber<E> {
+    /// Create a new `EventSubscriber` on the bus of the shared context.
+    pub fn new() -> Result<EventSubscriber<E>, EventBusError> {
+        EventSubscriber::with_context(&sys_context())
+    }
+
+    /// Create a new `EventSubscriber` on the bus of `context`.
+    pub fn with_context(context: &zmq::Context) -> Result<EventSubscriber<E>, EventBusError> {
+        start_bus(context)?;
+        let subscriber = TopicSubscriber::new(context)?;
+        subscriber.get_ref().connect(SUBSCRIBE_ADDR)?;
+        Ok(EventSubscriber {
+            subscriber,
+            _events: PhantomData,
+        })
+    }
+
+    /// Returns a reference to the inner socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        self.subscriber.get_ref()
+    }
+
+    /// Subscribe to the events whose topics match `filter`, see `topic::TopicFilter`.
+    pub fn subscribe(&mut self, filter: &str) -> Result<(), EventBusError> {
+        Ok(self.subscriber.subscribe(filter)?)
+    }
+
+    /// Unsubscribe from `filter`. Returns false if the subscriber was not subscribed to it.
+    pub fn unsubscribe(&mut self, filter: &str) -> Result<bool, EventBusError> {
+        Ok(self.subscriber.unsubscribe(filter)?)
+    }
+
+    /// Receive the next event, with its topic. Fails with `EventBusError::Decode` for
+    /// events that are not of type `E`.
+    pub fn recv(&self, flags: i32) -> Result<(String, E), EventBusError> {
+        let msg = self.subscriber.recv_multipart(flags)?;
+        if msg.len() != 2 {
+            return Err(EventBusError::InvalidEvent(format!("{} frames", msg.len())));
+        }
+        let topic = String::from_utf8_lossy(&msg[0]).to_string();
+        let encoded = ::std::str::from_utf8(&msg[1])
+            .map_err(|e| EventBusError::InvalidEvent(e.to_string()))?;
+        Ok((topic, toml::from_str(encoded)?))
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it is `-1`, for the next event.
+    pub fn recv_timeout(&self, timeout: i64) -> Result<Option<(String, E)>, EventBusError> {
+        if self.get_ref().poll(zmq::POLLIN, timeout)? == 0 {
+            return Ok(None);
+        }
+        match self.recv(zmq::DONTWAIT) {
+            Ok(event) => Ok(Some(event)),
+            // the message had a topic that no filter matched.
+            Err(EventBusError::Io(ref e)) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Started {
+        name: String,
+        threads: u32,
+    }
+
+    #[test]
+    fn subscribers_receive_typed_events_by_topic() {
+        let ctx = zmq::Context::new();
+        let mut subscriber = EventSubscriber::<Started>::with_context(&ctx).unwrap();
+        subscriber.subscribe("actors/+/started").unwrap();
+        let publisher = EventPublisher::with_context(&ctx).unwrap();
+        assert!(publisher.publish("actors/#", &()).is_err());
+        let event = Started {
+            name: "worker".to_string(),
+            threads: 2,
+        };
+        // subscriptions reach the bus asynchronously.
+        let received = (0..50)
+            .filter_map(|_| {
+                publisher.publish("actors/a1/stopped", &event).unwrap();
+                publisher.publish("actors/a1/started", &event).unwrap();
+                subscriber.recv_timeout(20).unwrap()
+            })
+            .next();
+        assert_eq!(received, Some(("actors/a1/started".to_string(), event)));
+    }
+}
@@ -0,0 +1,697 @@
+//! Transfers of files, in chunks.
+//!
+//! An implementation of the
+//! "[file transfer](http://zguide.zeromq.org/page:all#Transferring-Files)" of the zguide,
+//! with credit-based flow control.
+//!
+//! A `FileServer` is a `ROUTER` socket that serves the files under a root directory. A
+//! `FileClient` is a `DEALER` socket that asks for the size, and checksum, of a file, and
+//! then fetches it in chunks of a fixed size, keeping up to `credit` chunk requests in
+//! flight, so the transfer is pipelined, but the server never queues more than `credit`
+//! chunks for a client. Downloads resume from the size of the local file, and every chunk,
+//! as well as the whole file, is checked against its checksum.
+//!
+//! Requests are `[STAT, name]`, answered with `[STAT, size, checksum]`, and
+//! `[FETCH, name, offset, size]`, answered with `[CHUNK, offset, checksum, data]`, where
+//! sizes, offsets, and checksums are big-endian integers. Failed requests are answered with
+//! `[ERROR, reason]`. Checksums are 64-bit FNV-1a hashes.
+//!
+//! Both ends can be driven by a poller: poll their `get_ref` sockets, and call their `poll`
+//! with a timeout of 0 when they are readable.
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Component, Path, PathBuf};
+use zmq;
+
+/// First frame of requests, and replies, for the size, and checksum, of a file.
+pub const STAT: &str = "$STAT";
+/// First frame of requests for a chunk of a file.
+pub const FETCH: &str = "$FETCH";
+/// First frame of the chunks of a file.
+pub const CHUNK: &str = "$CHUNK";
+/// First frame of the replies to failed requests.
+pub const ERROR: &str = "$ERROR";
+/// Default size, in bytes, of the chunks fetched by clients.
+pub const DEFAULT_CHUNK_SIZE: u32 = 64 * 1024;
+/// Default number of chunk requests that clients keep in flight.
+pub const DEFAULT_CREDIT: usize = 8;
+/// Largest chunk, in bytes, that servers send.
+pub const MAX_CHUNK_SIZE: u32 = 1024 * 1024;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0100_0000_01b3;
+
+/// File transfer errors.
+#[derive(Debug, Fail)]
+pub enum FileTransferError {
+    #[fail(display = "invalid file transfer message")]
+    InvalidMessage,
+    #[fail(display = "invalid file name: {}", _0)]
+    InvalidName(String),
+    #[fail(display = "server failed the request: {}", _0)]
+    Remote(String),
+    #[fail(display = "checksum mismatch at offset {}", _0)]
+    Checksum(u64),
+    #[fail(display = "no transfer in progress")]
+    NoTransfer,
+    #[fail(display = "transfer timed out")]
+    Timeout,
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<io::Error> for FileTransferError {
+    fn from(e: io::Error) -> FileTransferError {
+        FileTransferError::Io(e)
+    }
+}
+
+impl From<zmq::Error> for FileTransferError {
+    fn from(e: zmq::Error) -> FileTransferError {
+        FileTransferError::Zmq(e)
+    }
+}
+
+/// Size, and checksum, of a file.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FileStat {
+    pub size: u64,
+    pub checksum: u64,
+}
+
+// Update the FNV-1a `hash` with `data`.
+fn fnv1a(mut hash: u64, data: &[u8]) -> u64 {
+    for byte in data {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+/// Returns the checksum of `data`.
+pub fn checksum(data: &[u8]) -> u64 {
+    fnv1a(FNV_OFFSET, data)
+}
+
+/// Returns the size, and checksum, of the file at `path`.
+pub fn file_stat<P: AsRef<Path>>(path: P) -> io::Result<FileStat> {
+    let mut file = File::open(path)?;
+    let mut buffer = vec![0; DEFAULT_CHUNK_SIZE as usize];
+    let mut stat = FileStat {
+        size: 0,
+        checksum: FNV_OFFSET,
+    };
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(stat);
+        }
+        stat.size += read as u64;
+        stat.checksum = fnv1a(stat.checksum, &buffer[..read]);
+    }
+}
+
+fn decode_u64(frame: &[u8]) -> Result<u64, FileTransferError> {
+    if frame.len() != 8 {
+        return Err(FileTransferError::InvalidMessage);
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(frame);
+    Ok(u64::from_be_bytes(bytes))
+}
+
+fn decode_u32(frame: &[u8]) -> Result<u32, FileTransferError> {
+    if frame.len() != 4 {
+        return Err(FileTransferError::InvalidMessage);
+    }
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(frame);
+    Ok(u32::from_be_bytes(bytes))
+}
+
+fn decode_name(frame: &[u8]) -> Result<&str, FileTransferError> {
+    ::std::str::from_utf8(frame).map_err(|_| FileTransferError::InvalidMessage)
+}
+
+/// A `ROUTER` socket that serves the files under a root directory.
+pub struct FileServer {
+    socket: zmq::Socket,
+    root: PathBuf,
+}
+
+impl FileServer {
+    /// Create a new `FileServer` for the files under `root`, with a socket from `context`.
+    pub fn new<P: AsRef<Path>>(
+        context: &zmq::Context,
+        root: P,
+    ) -> Result<FileServer, FileTransferError> {
+        let socket = context.socket(zmq::ROUTER)?;
+        socket.set_linger(0)?;
+        Ok(FileServer {
+            socket,
+            root: root.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Returns a reference to the inner socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Returns the root directory of the served files.
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Bind the server to `endpoint`.
+    pub fn bind(&self, endpoint: &str) -> Result<(), FileTransferError> {
+        Ok(self.socket.bind(endpoint)?)
+    }
+
+    /// Answer requests, waiting up to `timeout` milliseconds for the first one. Returns the
+    /// number of requests that were answered.
+    pub fn poll(&mut self, timeout: i64) -> Result<usize, FileTransferError> {
+        let mut answered = 0;
+        let mut wait = timeout;
+        while self.socket.poll(zmq::POLLIN, wait)? > 0 {
+            let mut msg = self.socket.recv_multipart(0)?;
+            wait = 0;
+            if msg.len() < 2 {
+                nlog!(warn, "file transfer request without a command");
+                continue;
+            }
+            let body = msg.split_off(1);
+            let reply = self
+                .reply(&body)
+                .unwrap_or_else(|e| vec![ERROR.as_bytes().to_vec(), e.to_string().into_bytes()]);
+            msg.extend(reply);
+            self.socket.send_multipart(msg, 0)?;
+            answered += 1;
+        }
+        Ok(answered)
+    }
+
+    fn reply(&self, request: &[Vec<u8>]) -> Result<Vec<Vec<u8>>, FileTransferError> {
+        match request {
+            [command, name] if command == STAT.as_bytes() => {
+                let stat = file_stat(self.resolve(decode_name(name)?)?)?;
+                Ok(vec![
+                    STAT.as_bytes().to_vec(),
+                    stat.size.to_be_bytes().to_vec(),
+                    stat.checksum.to_be_bytes().to_vec(),
+                ])
+            }
+            [command, name, offset, size] if command == FETCH.as_bytes() => {
+                let offset = decode_u64(offset)?;
+                let size = cmp::min(decode_u32(size)?, MAX_CHUNK_SIZE);
+                let mut file = File::open(self.resolve(decode_name(name)?)?)?;
+                file.seek(SeekFrom::Start(offset))?;
+                let mut data = Vec::with_capacity(size as usize);
+                file.take(u64::from(size)).read_to_end(&mut data)?;
+                Ok(vec![
+                    CHUNK.as_bytes().to_vec(),
+                    offset.to_be_bytes().to_vec(),
+                    checksum(&data).to_be_bytes().to_vec(),
+                    data,
+                ])
+            }
+            _ => Err(FileTransferError::InvalidMessage),
+        }
+    }
+
+    // Returns the path of `name` under the root, which may not leave it, not even through
+    // symbolic links.
+    fn resolve(&self, name: &str) -> Result<PathBuf, FileTransferError> {
+        let path = Path::new(name);
+        let relative = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)));
+        if name.is_empty() || !relative {
+            return Err(FileTransferError::InvalidName(name.to_string()));
+        }
+        let resolved = self.root.join(path).canonicalize()?;
+        if !resolved.starts_with(self.root.canonicalize()?) {
+            return Err(FileTransferError::InvalidName(name.to_string()));
+        }
+        Ok(resolved)
+    }
+}
+
+// State of the download of a client.
+struct Download {
+    name: String,
+    path: PathBuf,
+    file: File,
+    stat: Option<FileStat>,
+    // offset of the next chunk to request.
+    requested: u64,
+    received: u64,
+    // end of each chunk request in flight, by its offset.
+    in_flight: HashMap<u64, u64>,
+}
+
+/// A `DEALER` socket that downloads files from a `FileServer`, one at a time.
+pub struct FileClient {
+    context: zmq::Context,
+    endpoints: Vec<String>,
+    socket: zmq::Socket,
+    chunk_size: u32,
+    credit: usize,
+    timeout: i64,
+    download: Option<Download>,
+}
+
+impl FileClient {
+    /// Create a new `FileClient`, with a socket from `context`.
+    pub fn new(context: &zmq::Context) -> Result<FileClient, FileTransferError> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        Ok(FileClient {
+            context: context.clone(),
+            endpoints: Vec::new(),
+            socket,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            credit: DEFAULT_CREDIT,
+            timeout: 2_500,
+            download: None,
+        })
+    }
+
+    /// Returns a reference to the inner socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Connect the client to a `FileServer`.
+    pub fn connect(&mut self, endpoint: &str) -> Result<(), FileTransferError> {
+        self.socket.connect(endpoint)?;
+        self.endpoints.push(endpoint.to_string());
+        Ok(())
+    }
+
+    /// Set the size, in bytes, of the chunks to fetch, up to `MAX_CHUNK_SIZE`.
+    pub fn set_chunk_size(&mut self, chunk_size: u32) {
+        self.chunk_size = chunk_size.clamp(1, MAX_CHUNK_SIZE);
+    }
+
+    /// Set the number of chunk requests to keep in flight.
+    pub fn set_credit(&mut self, credit: usize) {
+        self.credit = cmp::max(1, credit);
+    }
+
+    /// Set how long, in milliseconds, `fetch` waits for each reply.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Returns the bytes received by the current download, and the size of its file, once
+    /// it is known.
+    pub fn progress(&self) -> Option<(u64, Option<u64>)> {
+        self.download
+            .as_ref()
+            .map(|download| (download.received, download.stat.map(|stat| stat.size)))
+    }
+
+    /// Download the file `name` to `dest`, waiting until it is done. The download resumes
+    /// from the size of `dest`, if it exists, so a partial file is kept when the download
+    /// fails, and a later call continues it.
+    pub fn fetch<P: AsRef<Path>>(
+        &mut self,
+        name: &str,
+        dest: P,
+    ) -> Result<FileStat, FileTransferError> {
+        self.start(name, dest)?;
+        loop {
+            let (handled, done) = self.poll_replies(self.timeout)?;
+            if let Some(stat) = done {
+                return Ok(stat);
+            }
+            if handled == 0 {
+                self.abandon()?;
+                return Err(FileTransferError::Timeout);
+            }
+        }
+    }
+
+    /// Start downloading the file `name` to `dest`, replacing the current download, if any.
+    /// Replies are handled by `poll`.
+    pub fn start<P: AsRef<Path>>(&mut self, name: &str, dest: P) -> Result<(), FileTransferError> {
+        let path = dest.as_ref().to_path_buf();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let received = file.metadata()?.len();
+        self.socket.send_multipart([STAT, name], 0)?;
+        self.download = Some(Download {
+            name: name.to_string(),
+            path,
+            file,
+            stat: None,
+            requested: received,
+            received,
+            in_flight: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Handle replies to the current download, waiting up to `timeout` milliseconds for the
+    /// first one. Returns the size, and checksum, of the file once it was downloaded.
+    pub fn poll(&mut self, timeout: i64) -> Result<Option<FileStat>, FileTransferError> {
+        Ok(self.poll_replies(timeout)?.1)
+    }
+
+    fn poll_replies(
+        &mut self,
+        timeout: i64,
+    ) -> Result<(usize, Option<FileStat>), FileTransferError> {
+        if self.download.is_none() {
+            return Err(FileTransferError::NoTransfer);
+        }
+        let mut handled = 0;
+        let mut wait = timeout;
+        while self.socket.poll(zmq::POLLIN, wait)? > 0 {
+            let reply = self.socket.recv_multipart(0)?;
+            wait = 0;
+            handled += 1;
+            match self.handle(&reply) {
+                Ok(None) => {}
+                Ok(Some(stat)) => {
+                    self.download = None;
+                    return Ok((handled, Some(stat)));
+                }
+                Err(e) => {
+                    self.abandon()?;
+                    return Err(e);
+                }
+            }
+        }
+        Ok((handled, None))
+    }
+
+    // Drop the current download. Replies to its requests that are still in flight would be
+    // taken for replies to the next download, so the socket is replaced.
+    fn abandon(&mut self) -> Result<(), FileTransferError> {
+        let in_flight = self
+            .download
+            .take()
+            .is_some_and(|download| !download.in_flight.is_empty());
+        if in_flight {
+            let socket = self.context.socket(zmq::DEALER)?;
+            socket.set_linger(0)?;
+            for endpoint in &self.endpoints {
+                socket.connect(endpoint)?;
+            }
+            self.socket = socket;
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, reply: &[Vec<u8>]) -> Result<Option<FileStat>, FileTransferError> {
+        let chunk_size = u64::from(self.chunk_size);
+        let download = self
+            .download
+            .as_mut()
+            .ok_or(FileTransferError::NoTransfer)?;
+        match reply {
+            [command, size, sum] if command == STAT.as_bytes() => {
+                let stat = FileStat {
+                    size: decode_u64(size)?,
+                    checksum: decode_u64(sum)?,
+                };
+                // a local file larger than the remote one is not a part of it.
+                if download.received > stat.size {
+                    download.file.set_len(0)?;
+                    download.received = 0;
+                    download.requested = 0;
+                }
+                download.stat = Some(stat);
+            }
+            [command, offset, sum, data] if command == CHUNK.as_bytes() => {
+                let offset = decode_u64(offset)?;
+                if checksum(data) != decode_u64(sum)? {
+                    return Err(FileTransferError::Checksum(offset));
+                }
+                if data.is_empty() {
+                    return Err(FileTransferError::Remote("file was truncated".into()));
+                }
+                let len = data.len() as u64;
+                let end = match download.in_flight.get(&offset) {
+                    Some(&end) if offset + len <= end => end,
+                    _ => return Err(FileTransferError::InvalidMessage),
+                };
+                download.in_flight.remove(&offset);
+                download.file.seek(SeekFrom::Start(offset))?;
+                download.file.write_all(data)?;
+                download.received += len;
+                // the rest of a short chunk, e.g. capped by the server, is requested again,
+                // up to the end of its request.
+                if offset + len < end {
+                    let request = vec![
+                        FETCH.as_bytes().to_vec(),
+                        download.name.as_bytes().to_vec(),
+                        (offset + len).to_be_bytes().to_vec(),
+                        ((end - offset - len) as u32).to_be_bytes().to_vec(),
+                    ];
+                    self.socket.send_multipart(request, 0)?;
+                    download.in_flight.insert(offset + len, end);
+                }
+            }
+            [command, reason] if command == ERROR.as_bytes() => {
+                let reason = String::from_utf8_lossy(reason).to_string();
+                return Err(FileTransferError::Remote(reason));
+            }
+            _ => return Err(FileTransferError::InvalidMessage),
+        }
+        let stat = match download.stat {
+            Some(stat) => stat,
+            None => return Ok(None),
+        };
+        if download.received >= stat.size {
+            download.file.flush()?;
+            let local = file_stat(&download.path)?;
+            if local != stat {
+                return Err(FileTransferError::Checksum(0));
+            }
+            return Ok(Some(stat));
+        }
+        while download.in_flight.len() < self.credit && download.requested < stat.size {
+            let size = cmp::min(chunk_size, stat.size - download.requested);
+            let request = vec![
+                FETCH.as_bytes().to_vec(),
+                download.name.as_bytes().to_vec(),
+                download.requested.to_be_bytes().to_vec(),
+                (size as u32).to_be_bytes().to_vec(),
+            ];
+            self.socket.send_multipart(request, 0)?;
+            download
+                .in_flight
+                .insert(download.requested, download.requested + size);
+            download.requested += size;
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use uuid::Uuid;
+
+    fn serve(
+        ctx: &zmq::Context,
+        root: &Path,
+        endpoint: &str,
+    ) -> (Arc<AtomicBool>, thread::JoinHandle<()>) {
+        let mut server = FileServer::new(ctx, root).unwrap();
+        server.bind(endpoint).unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                server.poll(10).unwrap();
+            }
+        });
+        (stop, thread)
+    }
+
+    #[test]
+    fn downloads_resume_from_partial_files() {
+        let root = env::temp_dir().join(format!("neuras-files-{}", Uuid::new_v4()));
+        fs::create_dir(&root).unwrap();
+        let content: Vec<u8> = (0..10_000u32).map(|n| (n % 251) as u8).collect();
+        fs::write(root.join("data.bin"), &content).unwrap();
+        let ctx = zmq::Context::new();
+        let (stop, server) = serve(&ctx, &root, "inproc://filetransfer_resume");
+
+        let mut client = FileClient::new(&ctx).unwrap();
+        client.connect("inproc://filetransfer_resume").unwrap();
+        client.set_chunk_size(1_000);
+        client.set_credit(3);
+        let dest = root.join("copy.bin");
+        fs::write(&dest, &content[..2_500]).unwrap();
+        let stat = client.fetch("data.bin", &dest).unwrap();
+        assert_eq!(stat, file_stat(root.join("data.bin")).unwrap());
+        assert_eq!(fs::read(&dest).unwrap(), content);
+        assert_eq!(client.progress(), None);
+
+        // partial files that do not match the remote file fail the download.
+        fs::write(&dest, vec![0u8; 2_500]).unwrap();
+        match client.fetch("data.bin", &dest) {
+            Err(FileTransferError::Checksum(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&dest).unwrap();
+        assert_eq!(client.fetch("data.bin", &dest).unwrap(), stat);
+
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn servers_only_serve_files_under_their_root() {
+        let root = env::temp_dir().join(format!("neuras-files-{}", Uuid::new_v4()));
+        fs::create_dir(&root).unwrap();
+        let ctx = zmq::Context::new();
+        let (stop, server) = serve(&ctx, &root, "inproc://filetransfer_root");
+
+        let mut client = FileClient::new(&ctx).unwrap();
+        client.connect("inproc://filetransfer_root").unwrap();
+        let dest = root.join("copy.bin");
+        let outside = env::temp_dir().join(format!("neuras-secret-{}", Uuid::new_v4()));
+        fs::write(&outside, b"secret").unwrap();
+        #[cfg(unix)]
+        ::std::os::unix::fs::symlink(&outside, root.join("escape")).unwrap();
+        for name in &["../secret", "/etc/hostname", "missing.bin", "escape"] {
+            match client.fetch(name, &dest) {
+                Err(FileTransferError::Remote(_)) => {}
+                other => panic!("unexpected result for {}: {:?}", name, other),
+            }
+        }
+        match client.poll(0) {
+            Err(FileTransferError::NoTransfer) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        fs::remove_file(&outside).unwrap();
+    }
+
+    #[test]
+    fn the_rest_of_short_chunks_is_fetched_again() {
+        let ctx = zmq::Context::new();
+        let server = ctx.socket(zmq::ROUTER).unwrap();
+        server.bind("inproc://filetransfer_short").unwrap();
+        let mut client = FileClient::new(&ctx).unwrap();
+        client.connect("inproc://filetransfer_short").unwrap();
+        client.set_chunk_size(10);
+        let dest = env::temp_dir().join(format!("neuras-short-{}", Uuid::new_v4()));
+        client.start("short.bin", &dest).unwrap();
+
+        let content = b"0123456789";
+        let stat = [
+            STAT.as_bytes(),
+            &10u64.to_be_bytes(),
+            &checksum(content).to_be_bytes(),
+        ];
+        let chunk = |offset: u64, data: &[u8]| {
+            vec![
+                CHUNK.as_bytes().to_vec(),
+                offset.to_be_bytes().to_vec(),
+                checksum(data).to_be_bytes().to_vec(),
+                data.to_vec(),
+            ]
+        };
+        let mut request = server.recv_multipart(0).unwrap();
+        request.truncate(1);
+        request.extend(stat.iter().map(|frame| frame.to_vec()));
+        server.send_multipart(request, 0).unwrap();
+        assert_eq!(client.poll(1_000).unwrap(), None);
+
+        // the server only sends the first 6 bytes of the chunk.
+        let mut request = server.recv_multipart(0).unwrap();
+        assert_eq!(request[3], 0u64.to_be_bytes());
+        request.truncate(1);
+        request.extend(chunk(0, &content[..6]));
+        server.send_multipart(request, 0).unwrap();
+        assert_eq!(client.poll(1_000).unwrap(), None);
+
+        let mut request = server.recv_multipart(0).unwrap();
+        assert_eq!(request[3], 6u64.to_be_bytes());
+        assert_eq!(request[4], 4u32.to_be_bytes());
+        request.truncate(1);
+        request.extend(chunk(6, &content[6..]));
+        server.send_multipart(request, 0).unwrap();
+        let done = client.poll(1_000).unwrap().unwrap();
+        assert_eq!(done.size, 10);
+        assert_eq!(fs::read(&dest).unwrap(), content);
+        fs::remove_file(&dest).unwrap();
+    }
+
+    #[test]
+    fn short_chunks_of_many_chunk_files_are_only_fetched_once() {
+        let content: Vec<u8> = (0..95u8).collect();
+        let ctx = zmq::Context::new();
+        let server = ctx.socket(zmq::ROUTER).unwrap();
+        server.bind("inproc://filetransfer_short_many").unwrap();
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let served = content.clone();
+        // a server that sends no more than 4 bytes of each chunk.
+        let server = thread::spawn(move || {
+            while !stopped.load(Ordering::SeqCst) {
+                if server.poll(zmq::POLLIN, 10).unwrap() == 0 {
+                    continue;
+                }
+                let mut request = server.recv_multipart(0).unwrap();
+                let reply = if request[1] == STAT.as_bytes() {
+                    vec![
+                        STAT.as_bytes().to_vec(),
+                        (served.len() as u64).to_be_bytes().to_vec(),
+                        checksum(&served).to_be_bytes().to_vec(),
+                    ]
+                } else {
+                    let offset = decode_u64(&request[3]).unwrap() as usize;
+                    let size = cmp::min(decode_u32(&request[4]).unwrap() as usize, 4);
+                    let data = served[offset..offset + size].to_vec();
+                    vec![
+                        CHUNK.as_bytes().to_vec(),
+                        (offset as u64).to_be_bytes().to_vec(),
+                        checksum(&data).to_be_bytes().to_vec(),
+                        data,
+                    ]
+                };
+                request.truncate(1);
+                request.extend(reply);
+                server.send_multipart(request, 0).unwrap();
+            }
+        });
+
+        let mut client = FileClient::new(&ctx).unwrap();
+        client.connect("inproc://filetransfer_short_many").unwrap();
+        client.set_chunk_size(10);
+        client.set_credit(3);
+        let dest = env::temp_dir().join(format!("neuras-short-many-{}", Uuid::new_v4()));
+        let stat = client.fetch("many.bin", &dest).unwrap();
+        assert_eq!(stat.size, 95);
+        assert_eq!(fs::read(&dest).unwrap(), content);
+
+        stop.store(true, Ordering::SeqCst);
+        server.join().unwrap();
+        fs::remove_file(&dest).unwrap();
+    }
+}
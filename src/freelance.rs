@@ -0,0 +1,296 @@
+//! Brokerless reliable request-reply.
+//!
+//! An implementation of the
+//! "[Freelance Protocol](https://rfc.zeromq.org/spec/10/)", as described in
+//! "[Brokerless Reliability](http://zguide.zeromq.org/page:all#Brokerless-Reliability-Freelance-Pattern)".
+//!
+//! A `Client` is a `DEALER` socket connected to every server. Each request goes to one
+//! server, and is sent again, to the next server, when no reply arrives in time, so that a
+//! dead, or slow, server only costs a timeout. Requests carry a sequence number, and the
+//! late replies to earlier requests are dropped, so retries need no connection state.
+//!
+//! A `Server` is a `ROUTER` socket, that answers each `Request` with `Server::reply`.
+//!
+//! | from   | frames                                          |
+//! |--------|-------------------------------------------------|
+//! | client | sequence number, as a big-endian u64, request   |
+//! | server | the same sequence number, reply                 |
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::freelance::{Client, Server};
+//! use std::thread;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let server = Server::new(&ctx).unwrap();
+//! let endpoint = server.bind("tcp://127.0.0.1:*").unwrap();
+//! thread::spawn(move || loop {
+//!     let request = server.recv().unwrap();
+//!     let reply = request.body().to_vec();
+//!     server.reply(&request, reply).unwrap();
+//! });
+//!
+//! let mut client = Client::new(&ctx).unwrap();
+//! client.connect(&endpoint).unwrap();
+//! assert_eq!(client.send(vec!["hello"]).unwrap(), vec![b"hello".to_vec()]);
+//! # }
+//! ```
+use super::clock::Clock;
+use super::socket::SocketError;
+
+use failure::Error;
+use std::convert::TryInto;
+use zmq;
+
+/// Default milliseconds that a request waits for its reply, before it is sent again.
+pub const REQUEST_TIMEOUT: i64 = 1_000;
+/// Default number of times that a request is sent, before giving up.
+pub const REQUEST_RETRIES: usize = 3;
+
+/// Freelance Errors.
+#[derive(Debug, Fail)]
+pub enum FreelanceError {
+    #[fail(display = "invalid protocol message")]
+    InvalidMessage,
+    #[fail(display = "no servers to send requests to")]
+    NoServers,
+    #[fail(display = "no reply after {} attempts", _0)]
+    NoReply(usize),
+}
+
+/// Blocking Freelance client.
+pub struct Client {
+    socket: zmq::Socket,
+    servers: Vec<String>,
+    sequence: u64,
+    timeout: i64,
+    retries: usize,
+    clock: Clock,
+}
+
+impl Client {
+    /// Create a new `Client`, not connected to any server.
+    pub fn new(context: &zmq::Context) -> Result<Self, Error> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        Ok(Client {
+            socket,
+            servers: Vec::new(),
+            sequence: 0,
+            timeout: REQUEST_TIMEOUT,
+            retries: REQUEST_RETRIES,
+            clock: Clock::new(),
+        })
+    }
+
+    /// Connect to the server at `endpoint`. Requests are sent to the servers in turns.
+    pub fn connect(&mut self, endpoint: &str) -> Result<(), Error> {
+        self.socket.connect(endpoint)?;
+        self.servers.push(endpoint.to_string());
+        Ok(())
+    }
+
+    /// Returns the endpoints of the servers.
+    pub fn servers(&self) -> &[String] {
+        &self.servers
+    }
+
+    /// Set the timeout, in milliseconds, to wait for each reply.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Set the number of attempts for each request before giving up, each to the next
+    /// server.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Send a request, and wait for the reply.
+    pub fn send<I, T>(&mut self, request: I) -> Result<Vec<Vec<u8>>, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        if self.servers.is_empty() {
+            return Err(FreelanceError::NoServers.into());
+        }
+        self.sequence += 1;
+        let sequence = self.sequence.to_be_bytes().to_vec();
+        let mut msg = vec![sequence.clone()];
+        msg.extend(request.into_iter().map(Into::into));
+
+        for _ in 0..self.retries {
+            self.socket.send_multipart(&msg, 0)?;
+            let deadline = self.clock.mono() + self.timeout;
+            loop {
+                let left = deadline - self.clock.mono();
+                if left <= 0 || self.socket.poll(zmq::POLLIN, left)? == 0 {
+                    break;
+                }
+                let mut reply = self.socket.recv_multipart(0)?;
+                if reply.is_empty() {
+                    return Err(FreelanceError::InvalidMessage.into());
+                }
+                if reply[0] == sequence {
+                    return Ok(reply.split_off(1));
+                }
+                nlog!(debug, "dropped late reply sequence={:?}", reply[0]);
+            }
+        }
+        Err(FreelanceError::NoReply(self.retries).into())
+    }
+}
+
+/// A request received by a `Server`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Request {
+    envelope: Vec<Vec<u8>>,
+    body: Vec<Vec<u8>>,
+}
+
+impl Request {
+    /// Returns the sequence number of the request.
+    pub fn sequence(&self) -> u64 {
+        let bytes = self
+            .envelope
+            .last()
+            .map(|frame| &frame[..])
+            .unwrap_or_default();
+        bytes.try_into().map(u64::from_be_bytes).unwrap_or_default()
+    }
+
+    /// Returns the frames of the request.
+    pub fn body(&self) -> &[Vec<u8>] {
+        &self.body
+    }
+}
+
+/// Freelance server.
+pub struct Server {
+    socket: zmq::Socket,
+}
+
+impl Server {
+    /// Create a new `Server`, not bound to any endpoint.
+    pub fn new(context: &zmq::Context) -> Result<Self, Error> {
+        let socket = context.socket(zmq::ROUTER)?;
+        socket.set_linger(0)?;
+        Ok(Server { socket })
+    }
+
+    /// Bind to `endpoint`, returning the endpoint that it was actually bound to.
+    pub fn bind(&self, endpoint: &str) -> Result<String, Error> {
+        self.socket.bind(endpoint)?;
+        Ok(self
+            .socket
+            .get_last_endpoint()?
+            .map_err(SocketError::Endpoint)?)
+    }
+
+    /// Return a reference to the underlying socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Wait for the next request. Messages that are not requests are dropped.
+    pub fn recv(&self) -> Result<Request, Error> {
+        loop {
+            let mut msg = self.socket.recv_multipart(0)?;
+            // the identity of the client, and the sequence number.
+            if msg.len() < 2 || msg[1].len() != 8 {
+                nlog!(debug, "dropped invalid request");
+                continue;
+            }
+            let body = msg.split_off(2);
+            return Ok(Request {
+                envelope: msg,
+                body,
+            });
+        }
+    }
+
+    /// Send `reply` to the client of `request`.
+    pub fn reply<I, T>(&self, request: &Request, reply: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut msg = request.envelope.clone();
+        msg.extend(reply.into_iter().map(Into::into));
+        self.socket.send_multipart(msg, 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn start_echo(ctx: &zmq::Context, endpoint: &str) {
+        let server = Server::new(ctx).unwrap();
+        server.bind(endpoint).unwrap();
+        thread::spawn(move || loop {
+            let request = server.recv().unwrap();
+            let reply = request.body().to_vec();
+            server.reply(&request, reply).unwrap();
+        });
+    }
+
+    #[test]
+    fn clients_shift_to_the_next_server_and_drop_late_replies() {
+        let ctx = zmq::Context::new();
+        let silent = Server::new(&ctx).unwrap();
+        silent.bind("inproc://freelance-silent").unwrap();
+        start_echo(&ctx, "inproc://freelance-echo");
+
+        let mut client = Client::new(&ctx).unwrap();
+        client.connect("inproc://freelance-silent").unwrap();
+        client.connect("inproc://freelance-echo").unwrap();
+        client.set_timeout(50);
+        for i in 0..4 {
+            let request = format!("request {}", i);
+            assert_eq!(
+                client.send(vec![request.clone()]).unwrap(),
+                vec![request.into_bytes()]
+            );
+        }
+
+        // the silent server wakes up, and answers the requests it was sent.
+        while silent.get_ref().poll(zmq::POLLIN, 0).unwrap() > 0 {
+            let request = silent.recv().unwrap();
+            assert!(request.sequence() <= 4);
+            silent.reply(&request, vec!["late"]).unwrap();
+        }
+        assert_eq!(client.send(vec!["again"]).unwrap(), vec![b"again".to_vec()]);
+    }
+
+    #[test]
+    fn clients_give_up_after_retries() {
+        let ctx = zmq::Context::new();
+        let mut client = Client::new(&ctx).unwrap();
+        match client
+            .send(vec!["hello"])
+            .unwrap_err()
+            .downcast::<FreelanceError>()
+        {
+            Ok(FreelanceError::NoServers) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        client.connect("inproc://freelance-missing").unwrap();
+        client.set_timeout(10);
+        client.set_retries(2);
+        match client
+            .send(vec!["hello"])
+            .unwrap_err()
+            .downcast::<FreelanceError>()
+        {
+            Ok(FreelanceError::NoReply(2)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
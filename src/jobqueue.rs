@@ -0,0 +1,485 @@
+//! Queues of typed jobs, with acknowledgments.
+//!
+//! Jobs that are pushed to `PULL` sockets are lost with the worker that pulled them, if it
+//! dies before it is done. A `JobQueue` runs a small broker on a thread of its own, which
+//! keeps every job until it is acknowledged: `Producer`s push jobs to it, `Consumer`s lease
+//! them, one at a time, and `Consumer::ack` each job once it is done. Jobs that are not
+//! acknowledged within the visibility timeout of the queue are delivered again, to the next
+//! consumer that asks, so every job is handled at least once.
+//!
+//! Jobs are encoded as TOML tables, as the messages of `channel`, so they are structs, or
+//! maps. Jobs that do not decode are acknowledged, and dropped, so that they are not
+//! delivered over and over.
+//!
+//! ```
+//! extern crate neuras;
+//! #[macro_use]
+//! extern crate serde_derive;
+//! extern crate zmq;
+//!
+//! use neuras::jobqueue::{Consumer, JobQueue, Producer};
+//!
+//! #[derive(Debug, Deserialize, PartialEq, Serialize)]
+//! struct Resize {
+//!     image: String,
+//!     width: u32,
+//! }
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let queue = JobQueue::bind(&ctx, "inproc://resize-jobs", "inproc://resize-leases", 5_000)
+//!     .unwrap();
+//! let producer = Producer::connect(&ctx, "inproc://resize-jobs").unwrap();
+//! let mut consumer = Consumer::<Resize>::connect(&ctx, "inproc://resize-leases").unwrap();
+//!
+//! let resize = Resize {
+//!     image: "cat.png".to_string(),
+//!     width: 64,
+//! };
+//! producer.enqueue(&resize).unwrap();
+//! let lease = consumer.lease(1_000).unwrap().unwrap();
+//! assert_eq!(lease.job(), &resize);
+//! assert_eq!(lease.attempt(), 1);
+//! consumer.ack(&lease).unwrap();
+//! # drop(queue);
+//! # }
+//! ```
+use super::clock::Clock;
+use super::utils::run_named_thread;
+
+use failure::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use toml;
+use uuid::Uuid;
+use zmq;
+
+const LEASE: &[u8] = b"$LEASE";
+const ACK: &[u8] = b"$ACK";
+const JOB: &[u8] = b"$JOB";
+const STOP: &[u8] = b"$STOP";
+
+/// Job queue errors.
+#[derive(Debug, Fail)]
+pub enum JobQueueError {
+    #[fail(display = "job could not be encoded: {}", _0)]
+    Encode(#[cause] toml::ser::Error),
+    #[fail(display = "job {} could not be decoded: {}", _0, _1)]
+    Decode(u64, #[cause] toml::de::Error),
+    #[fail(display = "invalid job queue message")]
+    InvalidMessage,
+}
+
+/// Counters of a `JobQueue`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JobQueueStats {
+    /// Jobs waiting for a consumer.
+    pub pending: usize,
+    /// Jobs leased by consumers, and not acknowledged yet.
+    pub leased: usize,
+    /// Jobs that were acknowledged.
+    pub acked: u64,
+    /// Deliveries of jobs whose lease ran out.
+    pub redelivered: u64,
+}
+
+// A job, and the number of times that it was delivered.
+struct Job {
+    id: u64,
+    body: Vec<u8>,
+    attempts: u32,
+}
+
+/// Broker of a queue of jobs, which runs on a thread of its own, until it is dropped.
+pub struct JobQueue {
+    control: zmq::Socket,
+    stats: Arc<Mutex<JobQueueStats>>,
+    thread: Option<thread::JoinHandle<Result<(), Error>>>,
+}
+
+impl JobQueue {
+    /// Bind a new `JobQueue`, which takes jobs from producers on the `jobs` endpoint, and
+    /// leases them to consumers on the `leases` endpoint, for `visibility` milliseconds.
+    pub fn bind(
+        context: &zmq::Context,
+        jobs: &str,
+        leases: &str,
+        visibility: i64,
+    ) -> Result<JobQueue, Error> {
+        let intake = context.socket(zmq::PULL)?;
+        intake.bind(jobs)?;
+        let router = context.socket(zmq::ROUTER)?;
+        router.set_linger(0)?;
+        router.bind(leases)?;
+        let control_addr = format!("inproc://neuras.jobqueue.{}", Uuid::new_v4().to_simple());
+        let stopped = context.socket(zmq::PAIR)?;
+        stopped.bind(&control_addr)?;
+        let control = context.socket(zmq::PAIR)?;
+        control.connect(&control_addr)?;
+
+        let stats = Arc::new(Mutex::new(JobQueueStats::default()));
+        let broker = Broker {
+            intake,
+            router,
+            visibility: visibility.max(0),
+            clock: Clock::new(),
+            pending: VecDeque::new(),
+            leased: HashMap::new(),
+            waiting: VecDeque::new(),
+            next_id: 0,
+            stats: Arc::clone(&stats),
+        };
+        let thread = run_named_thread("jobqueue", move || broker.run(&stopped))?;
+        Ok(JobQueue {
+            control,
+            stats,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the counters of the queue.
+    pub fn stats(&self) -> JobQueueStats {
+        *self.stats.lock().unwrap()
+    }
+}
+
+impl Drop for JobQueue {
+    fn drop(&mut self) {
+        let _ = self.control.send(STOP, 0);
+        if let Some(thread) = self.thread.take() {
+            if let Ok(Err(e)) = thread.join() {
+                nlog!(warn, "job queue failed error={}", e);
+            }
+        }
+    }
+}
+
+// The state of the broker of a queue, on its thread.
+struct Broker {
+    intake: zmq::Socket,
+    router: zmq::Socket,
+    visibility: i64,
+    clock: Clock,
+    pending: VecDeque<Job>,
+    // leased jobs, by id, with their deadlines.
+    leased: HashMap<u64, (Job, i64)>,
+    // identities of the consumers that asked for a job.
+    waiting: VecDeque<Vec<u8>>,
+    next_id: u64,
+    stats: Arc<Mutex<JobQueueStats>>,
+}
+
+impl Broker {
+    // Run the queue until it is stopped.
+    fn run(mut self, control: &zmq::Socket) -> Result<(), Error> {
+        loop {
+            self.dispatch()?;
+            self.update_stats();
+            let timeout = self
+                .leased
+                .values()
+                .map(|&(_, deadline)| (deadline - self.clock.mono()).max(0))
+                .min()
+                .unwrap_or(-1);
+            let mut items = [
+                control.as_poll_item(zmq::POLLIN),
+                self.intake.as_poll_item(zmq::POLLIN),
+                self.router.as_poll_item(zmq::POLLIN),
+            ];
+            zmq::poll(&mut items, timeout)?;
+            if items[0].is_readable() {
+                nlog!(debug, "job queue stopping");
+                return Ok(());
+            }
+            if items[1].is_readable() {
+                while let Ok(body) = self.intake.recv_bytes(zmq::DONTWAIT) {
+                    self.next_id += 1;
+                    self.pending.push_back(Job {
+                        id: self.next_id,
+                        body,
+                        attempts: 0,
+                    });
+                }
+            }
+            if items[2].is_readable() {
+                while let Ok(msg) = self.router.recv_multipart(zmq::DONTWAIT) {
+                    self.handle(msg);
+                }
+            }
+            self.expire();
+        }
+    }
+
+    // Take the requests, and acknowledgments, of a consumer.
+    fn handle(&mut self, mut msg: Vec<Vec<u8>>) {
+        if msg.len() < 2 {
+            return;
+        }
+        let identity = msg.remove(0);
+        match (&msg[0][..], msg.get(1)) {
+            (LEASE, None) => self.waiting.push_back(identity),
+            (ACK, Some(id)) => match decode_id(id) {
+                Some(id) if self.leased.remove(&id).is_some() => {
+                    self.stats.lock().unwrap().acked += 1;
+                }
+                // acknowledged late, after it was delivered again.
+                Some(id) => nlog!(debug, "job was not leased id={}", id),
+                None => nlog!(debug, "dropped invalid acknowledgment"),
+            },
+            _ => nlog!(debug, "dropped invalid job queue message"),
+        }
+    }
+
+    // Lease the pending jobs to the consumers that are waiting for them.
+    fn dispatch(&mut self) -> Result<(), Error> {
+        while !self.pending.is_empty() && !self.waiting.is_empty() {
+            let identity = self.waiting.pop_front().unwrap();
+            let mut job = self.pending.pop_front().unwrap();
+            job.attempts += 1;
+            let msg = vec![
+                identity,
+                JOB.to_vec(),
+                job.id.to_be_bytes().to_vec(),
+                job.attempts.to_be_bytes().to_vec(),
+                job.body.clone(),
+            ];
+            // jobs sent to consumers that went away are delivered again, once they expire.
+            self.router.send_multipart(msg, zmq::DONTWAIT)?;
+            let deadline = self.clock.mono() + self.visibility;
+            self.leased.insert(job.id, (job, deadline));
+        }
+        Ok(())
+    }
+
+    // Deliver the jobs whose lease ran out again, ahead of the other pending jobs.
+    fn expire(&mut self) {
+        let now = self.clock.mono();
+        let mut expired: Vec<u64> = self
+            .leased
+            .iter()
+            .filter(|&(_, &(_, deadline))| deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort_unstable_by(|a, b| b.cmp(a));
+        for id in expired {
+            if let Some((job, _)) = self.leased.remove(&id) {
+                nlog!(
+                    debug,
+                    "job lease expired id={} attempts={}",
+                    id,
+                    job.attempts
+                );
+                self.pending.push_front(job);
+                self.stats.lock().unwrap().redelivered += 1;
+            }
+        }
+    }
+
+    fn update_stats(&self) {
+        let mut stats = self.stats.lock().unwrap();
+        stats.pending = self.pending.len();
+        stats.leased = self.leased.len();
+    }
+}
+
+fn decode_id(frame: &[u8]) -> Option<u64> {
+    if frame.len() != 8 {
+        return None;
+    }
+    let mut id = [0u8; 8];
+    id.copy_from_slice(frame);
+    Some(u64::from_be_bytes(id))
+}
+
+/// Pushes jobs to a `JobQueue`.
+pub struct Producer {
+    socket: zmq::Socket,
+}
+
+impl Producer {
+    /// Connect a new `Producer` to the `jobs` endpoint of a queue.
+    pub fn connect(context: &zmq::Context, jobs: &str) -> Result<Producer, Error> {
+        let socket = context.socket(zmq::PUSH)?;
+        socket.connect(jobs)?;
+        Ok(Producer { socket })
+    }
+
+    /// Push `job` to the queue.
+    pub fn enqueue<T: Serialize>(&self, job: &T) -> Result<(), Error> {
+        let body = toml::to_string(job).map_err(JobQueueError::Encode)?;
+        self.socket.send(body.as_bytes(), 0)?;
+        Ok(())
+    }
+}
+
+/// A job leased from a `JobQueue`, which is delivered again unless it is acknowledged in
+/// time.
+#[derive(Debug, PartialEq)]
+pub struct Lease<T> {
+    id: u64,
+    attempt: u32,
+    job: T,
+}
+
+impl<T> Lease<T> {
+    /// Returns the id of the job, in its queue.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the number of times that the job was delivered, including this one.
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    /// Returns the job.
+    pub fn job(&self) -> &T {
+        &self.job
+    }
+
+    /// Returns the job, e.g. after it was acknowledged.
+    pub fn into_job(self) -> T {
+        self.job
+    }
+}
+
+/// Leases jobs of type `T` from a `JobQueue`, one at a time.
+pub struct Consumer<T> {
+    socket: zmq::Socket,
+    // true while a lease was asked for, and no job arrived yet.
+    asked: bool,
+    phantom: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> Consumer<T> {
+    /// Connect a new `Consumer` to the `leases` endpoint of a queue.
+    pub fn connect(context: &zmq::Context, leases: &str) -> Result<Consumer<T>, Error> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(leases)?;
+        Ok(Consumer {
+            socket,
+            asked: false,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Lease the next job, waiting up to `timeout` milliseconds, or forever if it is `-1`.
+    /// Returns `None` if no job arrived in time; the queue keeps the request, and hands the
+    /// next job to the next call. Jobs that do not decode fail with
+    /// `JobQueueError::Decode`, and are acknowledged.
+    pub fn lease(&mut self, timeout: i64) -> Result<Option<Lease<T>>, Error> {
+        if !self.asked {
+            self.socket.send(LEASE, 0)?;
+            self.asked = true;
+        }
+        if self.socket.poll(zmq::POLLIN, timeout)? == 0 {
+            return Ok(None);
+        }
+        let msg = self.socket.recv_multipart(0)?;
+        self.asked = false;
+        if msg.len() != 4 || msg[0] != JOB || msg[2].len() != 4 {
+            return Err(JobQueueError::InvalidMessage.into());
+        }
+        let id = decode_id(&msg[1]).ok_or(JobQueueError::InvalidMessage)?;
+        let mut attempt = [0u8; 4];
+        attempt.copy_from_slice(&msg[2]);
+        let decoded = String::from_utf8_lossy(&msg[3]);
+        match toml::from_str(&decoded) {
+            Ok(job) => Ok(Some(Lease {
+                id,
+                attempt: u32::from_be_bytes(attempt),
+                job,
+            })),
+            Err(e) => {
+                self.ack_id(id)?;
+                Err(JobQueueError::Decode(id, e).into())
+            }
+        }
+    }
+
+    /// Acknowledge the job of `lease`, which the queue then forgets. Acknowledgments that
+    /// arrive after the lease ran out are ignored, and the job is handled again.
+    pub fn ack(&self, lease: &Lease<T>) -> Result<(), Error> {
+        self.ack_id(lease.id)
+    }
+
+    fn ack_id(&self, id: u64) -> Result<(), Error> {
+        self.socket
+            .send_multipart([ACK, &id.to_be_bytes()[..]], 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testkit::{inproc_endpoint, wait_until};
+
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    struct Task {
+        n: u32,
+    }
+
+    fn queue(ctx: &zmq::Context, visibility: i64) -> (JobQueue, String, String) {
+        let jobs = inproc_endpoint("jobqueue-jobs");
+        let leases = inproc_endpoint("jobqueue-leases");
+        let queue = JobQueue::bind(ctx, &jobs, &leases, visibility).unwrap();
+        (queue, jobs, leases)
+    }
+
+    #[test]
+    fn jobs_are_delivered_again_until_acknowledged() {
+        let ctx = zmq::Context::new();
+        let (queue, jobs, leases) = queue(&ctx, 50);
+        let producer = Producer::connect(&ctx, &jobs).unwrap();
+        let mut first = Consumer::<Task>::connect(&ctx, &leases).unwrap();
+        let mut second = Consumer::<Task>::connect(&ctx, &leases).unwrap();
+
+        producer.enqueue(&Task { n: 7 }).unwrap();
+        let lease = first.lease(1_000).unwrap().unwrap();
+        assert_eq!((lease.job(), lease.attempt()), (&Task { n: 7 }, 1));
+        // the first consumer dies without acknowledging the job.
+        drop(first);
+        let again = second.lease(1_000).unwrap().unwrap();
+        assert_eq!((again.id(), again.attempt()), (lease.id(), 2));
+        second.ack(&again).unwrap();
+        assert!(second.lease(100).unwrap().is_none());
+        assert!(wait_until(1_000, || queue.stats().acked == 1));
+        assert_eq!(
+            queue.stats(),
+            JobQueueStats {
+                pending: 0,
+                leased: 0,
+                acked: 1,
+                redelivered: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn jobs_that_do_not_decode_are_dropped() {
+        let ctx = zmq::Context::new();
+        let (queue, jobs, leases) = queue(&ctx, 5_000);
+        let producer = Producer::connect(&ctx, &jobs).unwrap();
+        let mut consumer = Consumer::<Task>::connect(&ctx, &leases).unwrap();
+
+        let mut other = HashMap::new();
+        other.insert("name", "not a task");
+        producer.enqueue(&other).unwrap();
+        producer.enqueue(&Task { n: 1 }).unwrap();
+        let err = consumer.lease(1_000).unwrap_err();
+        match err.downcast_ref::<JobQueueError>() {
+            Some(JobQueueError::Decode(1, _)) => {}
+            other => panic!("unexpected error {:?}", other),
+        }
+        let lease = consumer.lease(1_000).unwrap().unwrap();
+        assert_eq!(lease.into_job(), Task { n: 1 });
+        assert!(wait_until(1_000, || queue.stats().acked == 1));
+        assert_eq!(queue.stats().leased, 1);
+    }
+}
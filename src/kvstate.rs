@@ -0,0 +1,428 @@
+//! Key-value state replication.
+//!
+//! An implementation of the
+//! "[Clone pattern](http://zguide.zeromq.org/page:all#Reliable-Pub-Sub-Clone-Pattern)".
+//!
+//! A `Server` runs on a child thread, holding the authoritative key-value store. Every update
+//! is given a sequence number, and published on a `PUB` socket. New clients ask for a snapshot
+//! of the current state over a `ROUTER` socket, and then keep their local copy in sync by
+//! applying the published updates that are newer than the snapshot.
+//!
+//! Updates with an empty value delete the key.
+use super::utils::run_named_thread;
+
+use failure::Error;
+use std::collections::HashMap;
+use std::io;
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// Snapshot request command.
+pub const ICANHAZ: &str = "ICANHAZ?";
+/// Snapshot end marker.
+pub const KTHXBAI: &str = "KTHXBAI";
+
+/// Key-value state Errors.
+#[derive(Debug, Fail)]
+pub enum KvStateError {
+    #[fail(display = "invalid key-value message")]
+    InvalidMessage,
+    #[fail(display = "snapshot was not received in time")]
+    SnapshotTimeout,
+}
+
+/// A key-value update, as sent over the wire: `[key, sequence, value]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct KvMsg {
+    pub key: String,
+    pub sequence: i64,
+    pub value: Vec<u8>,
+}
+
+impl KvMsg {
+    /// Create a new `KvMsg`.
+    pub fn new(key: &str, sequence: i64, value: &[u8]) -> Self {
+        KvMsg {
+            key: key.to_string(),
+            sequence,
+            value: value.to_vec(),
+        }
+    }
+
+    /// Returns the frames for this message.
+    pub fn to_frames(&self) -> Vec<Vec<u8>> {
+        vec![
+            self.key.as_bytes().to_vec(),
+            encode_sequence(self.sequence).to_vec(),
+            self.value.clone(),
+        ]
+    }
+
+    /// Parse a message from its frames.
+    pub fn from_frames(frames: &[Vec<u8>]) -> Result<Self, KvStateError> {
+        match frames {
+            [key, sequence, value] => Ok(KvMsg {
+                key: String::from_utf8(key.clone()).map_err(|_| KvStateError::InvalidMessage)?,
+                sequence: decode_sequence(sequence)?,
+                value: value.clone(),
+            }),
+            _ => Err(KvStateError::InvalidMessage),
+        }
+    }
+
+    /// Store the message into a key-value map, removing the key when the value is empty.
+    pub fn store(self, map: &mut HashMap<String, Vec<u8>>) {
+        if self.value.is_empty() {
+            map.remove(&self.key);
+        } else {
+            map.insert(self.key, self.value);
+        }
+    }
+}
+
+// Sequence numbers travel as big-endian 8-byte frames.
+fn encode_sequence(sequence: i64) -> [u8; 8] {
+    sequence.to_be_bytes()
+}
+
+fn decode_sequence(frame: &[u8]) -> Result<i64, KvStateError> {
+    if frame.len() != 8 {
+        return Err(KvStateError::InvalidMessage);
+    }
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(frame);
+    Ok(i64::from_be_bytes(bytes))
+}
+
+/// A key-value state server.
+pub struct Server {
+    snapshot: String,
+    publisher: String,
+    context: zmq::Context,
+    pipe: zmq::Socket,
+    uuid: Uuid,
+}
+
+impl Server {
+    /// Create a new `Server` instance, which will serve snapshots and publish updates on
+    /// the given addresses.
+    pub fn new(snapshot: &str, publisher: &str) -> Result<Self, Error> {
+        Server::new_with_context(snapshot, publisher, zmq::Context::new())
+    }
+
+    /// Create a new `Server` instance that shares network context with the creator.
+    pub fn new_with_context(
+        snapshot: &str,
+        publisher: &str,
+        context: zmq::Context,
+    ) -> Result<Self, Error> {
+        let uuid = Uuid::new_v4();
+        let pipe = context.socket(zmq::PAIR)?;
+        pipe.connect(&pipe_address(&uuid))?;
+        Ok(Server {
+            snapshot: snapshot.to_string(),
+            publisher: publisher.to_string(),
+            context,
+            pipe,
+            uuid,
+        })
+    }
+
+    /// Returns the server's network context.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Return a reference to the underlying pipe socket.
+    pub fn pipe(&self) -> &zmq::Socket {
+        &self.pipe
+    }
+
+    /// Start the server on a child thread. The bound snapshot and publisher endpoints are
+    /// sent back over the pipe as a two-part message.
+    pub fn start(&self) -> Result<thread::JoinHandle<Result<(), Error>>, io::Error> {
+        let context = self.context();
+        let snapshot_addr = self.snapshot.clone();
+        let publisher_addr = self.publisher.clone();
+        let pipe_addr = pipe_address(&self.uuid);
+
+        run_named_thread("kvstate", move || {
+            let pipe = context.socket(zmq::PAIR)?;
+            pipe.bind(&pipe_addr)?;
+
+            let snapshot = context.socket(zmq::ROUTER)?;
+            snapshot.bind(&snapshot_addr)?;
+            let publisher = context.socket(zmq::PUB)?;
+            publisher.bind(&publisher_addr)?;
+
+            let endpoints = vec![
+                snapshot
+                    .get_last_endpoint()?
+                    .expect("unparsable snapshot endpoint"),
+                publisher
+                    .get_last_endpoint()?
+                    .expect("unparsable publisher endpoint"),
+            ];
+            pipe.send_multipart(&endpoints, 0)?;
+
+            poll_kvstate(&pipe, &snapshot, &publisher)
+        })
+    }
+
+    /// Set a key to a value, publishing the update. Returns the update's sequence number.
+    pub fn set(&self, key: &str, value: &[u8]) -> Result<i64, Error> {
+        let cmd: Vec<&[u8]> = vec![b"$SET", key.as_bytes(), value];
+        self.pipe().send_multipart(cmd, 0)?;
+        let reply = self.pipe().recv_bytes(0)?;
+        Ok(decode_sequence(&reply)?)
+    }
+
+    /// Delete a key, publishing the update. Returns the update's sequence number.
+    pub fn delete(&self, key: &str) -> Result<i64, Error> {
+        self.set(key, b"")
+    }
+
+    /// Stop the server.
+    pub fn stop(&self) -> Result<(), zmq::Error> {
+        self.pipe().send("$STOP", 0)
+    }
+}
+
+// Each server gets its own pipe address.
+fn pipe_address(uuid: &Uuid) -> String {
+    format!("inproc://neuras.kvstate.{}", uuid.to_simple())
+}
+
+fn poll_kvstate(
+    pipe: &zmq::Socket,
+    snapshot: &zmq::Socket,
+    publisher: &zmq::Socket,
+) -> Result<(), Error> {
+    let mut map = HashMap::<String, Vec<u8>>::new();
+    let mut sequence: i64 = 0;
+
+    loop {
+        let mut pollable = [
+            pipe.as_poll_item(zmq::POLLIN),
+            snapshot.as_poll_item(zmq::POLLIN),
+        ];
+        zmq::poll(&mut pollable, -1)?;
+        if pollable[0].is_readable() {
+            let cmd = pipe.recv_multipart(0)?;
+            match cmd.first().map(|c| &c[..]) {
+                Some(b"$STOP") => {
+                    pipe.send("$STOPPING", 0)?;
+                    break;
+                }
+                Some(b"$SET") if cmd.len() == 3 => {
+                    sequence += 1;
+                    let key = String::from_utf8_lossy(&cmd[1]).to_string();
+                    let update = KvMsg::new(&key, sequence, &cmd[2]);
+                    publisher.send_multipart(update.to_frames(), 0)?;
+                    update.store(&mut map);
+                    pipe.send(&encode_sequence(sequence)[..], 0)?;
+                }
+                _ => pipe.send("$WONTDO", 0)?,
+            }
+        }
+        if pollable[1].is_readable() {
+            let request = snapshot.recv_multipart(0)?;
+            if request.len() < 2 || request[1] != ICANHAZ.as_bytes() {
+                continue;
+            }
+            let identity = &request[0];
+            let subtree = request.get(2).cloned().unwrap_or_default();
+            for (key, value) in &map {
+                if !key.as_bytes().starts_with(&subtree) {
+                    continue;
+                }
+                let mut frames = vec![identity.clone()];
+                frames.extend(KvMsg::new(key, sequence, value).to_frames());
+                snapshot.send_multipart(frames, 0)?;
+            }
+            let mut frames = vec![identity.clone()];
+            frames.extend(
+                KvMsg {
+                    key: KTHXBAI.to_string(),
+                    sequence,
+                    value: subtree,
+                }
+                .to_frames(),
+            );
+            snapshot.send_multipart(frames, 0)?;
+        }
+    }
+    Ok(())
+}
+
+/// A key-value state client, keeping a local copy of the server's state.
+pub struct Client {
+    map: HashMap<String, Vec<u8>>,
+    sequence: i64,
+    snapshot: zmq::Socket,
+    subscriber: zmq::Socket,
+    subtree: String,
+}
+
+impl Client {
+    /// Create a new `Client` connected to a server's snapshot and publisher endpoints.
+    pub fn new(context: &zmq::Context, snapshot: &str, publisher: &str) -> Result<Self, Error> {
+        Client::with_subtree(context, snapshot, publisher, "")
+    }
+
+    /// Create a new `Client` that only replicates keys starting with `subtree`.
+    pub fn with_subtree(
+        context: &zmq::Context,
+        snapshot: &str,
+        publisher: &str,
+        subtree: &str,
+    ) -> Result<Self, Error> {
+        // subscribe first, so no updates are lost while the snapshot is being received.
+        let subscriber = context.socket(zmq::SUB)?;
+        subscriber.set_subscribe(subtree.as_bytes())?;
+        subscriber.connect(publisher)?;
+        let snapshot_socket = context.socket(zmq::DEALER)?;
+        snapshot_socket.set_linger(0)?;
+        snapshot_socket.connect(snapshot)?;
+        Ok(Client {
+            map: HashMap::new(),
+            sequence: 0,
+            snapshot: snapshot_socket,
+            subscriber,
+            subtree: subtree.to_string(),
+        })
+    }
+
+    /// Request a snapshot of the server's state, waiting up to `timeout` milliseconds for
+    /// each part of it.
+    pub fn sync(&mut self, timeout: i64) -> Result<(), Error> {
+        let request: Vec<&[u8]> = vec![ICANHAZ.as_bytes(), self.subtree.as_bytes()];
+        self.snapshot.send_multipart(request, 0)?;
+        loop {
+            if self.snapshot.poll(zmq::POLLIN, timeout)? == 0 {
+                return Err(KvStateError::SnapshotTimeout.into());
+            }
+            let update = KvMsg::from_frames(&self.snapshot.recv_multipart(0)?)?;
+            if update.key == KTHXBAI {
+                self.sequence = update.sequence;
+                return Ok(());
+            }
+            update.store(&mut self.map);
+        }
+    }
+
+    /// Apply published updates, waiting up to `timeout` milliseconds for the first one.
+    /// Returns the number of updates that were applied.
+    pub fn poll(&mut self, timeout: i64) -> Result<usize, Error> {
+        let mut applied = 0;
+        let mut wait = timeout;
+        while self.subscriber.poll(zmq::POLLIN, wait)? > 0 {
+            let update = KvMsg::from_frames(&self.subscriber.recv_multipart(0)?)?;
+            // updates already contained in the snapshot are discarded.
+            if update.sequence > self.sequence {
+                self.sequence = update.sequence;
+                update.store(&mut self.map);
+                applied += 1;
+            }
+            wait = 0;
+        }
+        Ok(applied)
+    }
+
+    /// Returns the value stored for a key.
+    pub fn get(&self, key: &str) -> Option<&Vec<u8>> {
+        self.map.get(key)
+    }
+
+    /// Returns a reference to the local key-value map.
+    pub fn map(&self) -> &HashMap<String, Vec<u8>> {
+        &self.map
+    }
+
+    /// Returns the sequence number of the last applied update.
+    pub fn sequence(&self) -> i64 {
+        self.sequence
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_server(snapshot: &str, publisher: &str) -> Server {
+        let server = Server::new(snapshot, publisher).unwrap();
+        server.start().unwrap();
+        server.pipe().recv_multipart(0).unwrap();
+        server
+    }
+
+    #[test]
+    fn kvmsg_round_trips_through_frames() {
+        let msg = KvMsg::new("key", 42, b"value");
+        assert_eq!(KvMsg::from_frames(&msg.to_frames()).unwrap(), msg);
+    }
+
+    #[test]
+    fn kvmsg_with_empty_value_deletes_key() {
+        let mut map = HashMap::new();
+        KvMsg::new("key", 1, b"value").store(&mut map);
+        assert_eq!(map.len(), 1);
+        KvMsg::new("key", 2, b"").store(&mut map);
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn servers_assign_increasing_sequence_numbers() {
+        let server = start_server("inproc://kv_seq_snapshot", "inproc://kv_seq_updates");
+        assert_eq!(server.set("a", b"1").unwrap(), 1);
+        assert_eq!(server.set("b", b"2").unwrap(), 2);
+        assert_eq!(server.delete("a").unwrap(), 3);
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn clients_sync_snapshot_and_apply_updates() {
+        let server = start_server("inproc://kv_sync_snapshot", "inproc://kv_sync_updates");
+        server.set("sensors/temp", b"20").unwrap();
+        server.set("sensors/hum", b"50").unwrap();
+
+        let mut client = Client::new(
+            &server.context(),
+            "inproc://kv_sync_snapshot",
+            "inproc://kv_sync_updates",
+        )
+        .unwrap();
+        client.sync(1_000).unwrap();
+        assert_eq!(client.sequence(), 2);
+        assert_eq!(client.get("sensors/temp"), Some(&b"20".to_vec()));
+        // give the subscription time to reach the publisher.
+        thread::sleep(::std::time::Duration::from_millis(200));
+
+        server.set("sensors/temp", b"21").unwrap();
+        server.delete("sensors/hum").unwrap();
+        assert_eq!(client.poll(1_000).unwrap(), 2);
+        assert_eq!(client.get("sensors/temp"), Some(&b"21".to_vec()));
+        assert_eq!(client.get("sensors/hum"), None);
+        server.stop().unwrap();
+    }
+
+    #[test]
+    fn clients_sync_only_their_subtree() {
+        let server = start_server("inproc://kv_tree_snapshot", "inproc://kv_tree_updates");
+        server.set("a/1", b"x").unwrap();
+        server.set("b/1", b"y").unwrap();
+
+        let mut client = Client::with_subtree(
+            &server.context(),
+            "inproc://kv_tree_snapshot",
+            "inproc://kv_tree_updates",
+            "a/",
+        )
+        .unwrap();
+        client.sync(1_000).unwrap();
+        assert_eq!(client.map().len(), 1);
+        assert!(client.get("a/1").is_some());
+        server.stop().unwrap();
+    }
+}
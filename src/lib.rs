@@ -6,17 +6,36 @@
 //! "[Features of a Higher-Level API](http://zguide.zeromq.org/page:all#toc74)",
 //! by using tokio's reactor and tools.
 #![recursion_limit = "1024"]
+// `failure_derive` expands `#[derive(Fail)]` into non-local impl blocks.
+#![allow(non_local_definitions)]
 
+extern crate chacha20poly1305;
 extern crate chrono;
+extern crate crc32fast;
 #[macro_use]
 extern crate failure;
+extern crate hmac;
+extern crate pbkdf2;
+extern crate rand;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate sha2;
+extern crate signal_hook;
 extern crate slab;
 extern crate toml;
 extern crate url;
 extern crate uuid;
+extern crate zeroize;
 
 extern crate mio as mio_lib;
+// Re-exported, with the `zmq-reexport` feature, so that downstream crates use the same
+// version of `zmq`, see also `socket::SocketType`.
+#[cfg(feature = "zmq-reexport")]
+pub extern crate zmq;
+#[cfg(not(feature = "zmq-reexport"))]
 extern crate zmq;
+extern crate zmq_sys;
 
 // Optional crates from `async-tokio` feature
 #[cfg(feature = "async-tokio")]
@@ -26,20 +45,95 @@ extern crate tokio_core;
 #[cfg(feature = "async-tokio")]
 extern crate tokio_signal;
 
+// Optional crate from `deflate` feature
+#[cfg(feature = "deflate")]
+extern crate miniz_oxide;
+
+// Optional crate from `lz4` feature
+#[cfg(feature = "lz4")]
+extern crate lz4_flex;
+
+// Optional crate from `zstd` feature
+#[cfg(feature = "zstd")]
+extern crate zstd;
+
+// Optional crate from `logging` feature
+#[cfg(feature = "logging")]
+#[macro_use]
+extern crate log;
+
+// Optional crate from `tracing` feature
+#[cfg(feature = "tracing")]
+extern crate tracing;
+
+#[cfg(test)]
+extern crate libc;
+
+// Logging macros, which have to be defined before the modules that use them.
+#[macro_use]
+mod logging;
+
 // Actors that interact over the network.
 pub mod actor;
+// Reliable request-reply brokers (Majordomo pattern).
+pub mod broker;
+// Recording, and replay, of messages.
+pub mod capture;
+// Typed channels between threads.
+pub mod channel;
 // Millisecond clocks and delays.
 pub mod clock;
-// Messages for sockets.
-mod message;
+// Lifecycle of the network context.
+pub mod context;
+// Parsed, and validated, network endpoints.
+pub mod endpoint;
+// Errors of every module, in a single type.
+mod error;
+// Process-wide bus of typed events.
+pub mod eventbus;
+// Transfers of files, in chunks.
+pub mod filetransfer;
+// Brokerless reliable request-reply (Freelance pattern).
+pub mod freelance;
+// Queues of typed jobs, with acknowledgments.
+pub mod jobqueue;
+// Key-value state replication (Clone pattern).
+pub mod kvstate;
+// Envelopes of messages, with their delivery mode.
+pub mod message;
+// Middlewares for the messages of sockets, and actors.
+pub mod pipeline;
 // Polling for sockets.
 pub mod poller;
-// Proxy actor.
-mod proxy;
+// Pools of client sockets.
+pub mod pool;
+// Reliable request-reply queues, with heartbeats (Paranoid Pirate pattern).
+pub mod ppp;
+// Proxies between sockets, and topologies of them.
+pub mod proxy;
+// Service registry, for looking up endpoints by name.
+pub mod registry;
+// Remote procedure calls, with correlation ids.
+pub mod rpc;
+// Authentication, and encryption, for sockets.
+pub mod security;
 // Sockets for networking.
 pub mod socket;
+// Utilities to test actors, and sockets, without sleeping.
+#[cfg(any(test, feature = "testkit"))]
+pub mod testkit;
+// Disconnected, disk-based, reliable requests (Titanic pattern).
+pub mod titanic;
+// Rate limits for sockets.
+pub mod throttle;
+// Hierarchical topics for subscribers.
+pub mod topic;
 // Useful utilities to deal with ZMQ.
 pub mod utils;
+// Pools of worker threads.
+pub mod work;
 
 // Convenient API type for dealing with clocks and delays.
 pub use clock::Clock;
+// Errors, and results, of every module.
+pub use error::{Error, Result};
@@ -0,0 +1,100 @@
+//! Internal logging macros.
+//!
+//! Diagnostics go through the `log` facade. The target of every record is the path of the
+//! module that logs it (e.g. `neuras::actor`, or `neuras::security::zap`), so they can be
+//! filtered per module, and context such as the actor, peer, or command is written as
+//! `key=value` pairs after the message.
+//!
+//! Without the `logging` feature, `nlog!` expands to dead code, so that neither the record
+//! nor its arguments are evaluated on hot paths.
+//!
+//! The `tracing` feature adds spans of the `tracing` crate, entered with `nspan!`, around
+//! the actor's lifecycle, its pipe commands, and the sends, and receives, of polling
+//! sockets. They are `TRACE` spans, whose target is the path of their module, and are also
+//! logged through the `log` facade when no `tracing` subscriber is installed.
+
+/// Log a record at the given level, e.g. `nlog!(debug, "stopping actor={}", uuid)`.
+#[cfg(feature = "logging")]
+macro_rules! nlog {
+    ($level:ident, $($arg:tt)+) => {
+        $level!($($arg)+)
+    };
+}
+
+/// Log a record at the given level, e.g. `nlog!(debug, "stopping actor={}", uuid)`.
+#[cfg(not(feature = "logging"))]
+macro_rules! nlog {
+    ($level:ident, $($arg:tt)+) => {{
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+/// Enter a span, named `$name`, that lasts until the returned `Span` is dropped, e.g.
+/// `let _span = nspan!("actor", "pipe={}", endpoint);`.
+#[cfg(feature = "tracing")]
+macro_rules! nspan {
+    ($name:expr) => {
+        $crate::logging::Span::enter(::tracing::trace_span!(
+            target: module_path!(),
+            $name,
+            recorded = ::tracing::field::Empty
+        ))
+    };
+    ($name:expr, $($arg:tt)+) => {
+        $crate::logging::Span::enter(::tracing::trace_span!(
+            target: module_path!(),
+            $name,
+            fields = %format_args!($($arg)+),
+            recorded = ::tracing::field::Empty
+        ))
+    };
+}
+
+/// Enter a span, named `$name`, that lasts until the returned `Span` is dropped, e.g.
+/// `let _span = nspan!("actor", "pipe={}", endpoint);`.
+#[cfg(not(feature = "tracing"))]
+macro_rules! nspan {
+    ($name:expr) => {
+        $crate::logging::Span
+    };
+    ($name:expr, $($arg:tt)+) => {{
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+        $crate::logging::Span
+    }};
+}
+
+/// A span of execution, entered until it is dropped.
+///
+/// With the `tracing` feature, it is a `TRACE` span of the `tracing` crate, whose
+/// subscribers time it, and nest it under the spans that were entered before it.
+#[cfg(feature = "tracing")]
+pub struct Span(::tracing::span::EnteredSpan);
+
+#[cfg(feature = "tracing")]
+impl Span {
+    pub fn enter(span: ::tracing::Span) -> Span {
+        Span(span.entered())
+    }
+
+    /// Record `key=value` fields on the span, e.g. values that are only known once the
+    /// span's work is done.
+    pub fn record(&mut self, fields: ::std::fmt::Arguments) {
+        self.0.record("recorded", &::tracing::field::display(fields));
+    }
+}
+
+/// A span of execution, which is not traced without the `tracing` feature.
+#[cfg(not(feature = "tracing"))]
+pub struct Span;
+
+#[cfg(not(feature = "tracing"))]
+impl Span {
+    /// Record `key=value` fields on the span, e.g. values that are only known once the
+    /// span's work is done.
+    #[inline]
+    pub fn record(&mut self, _fields: ::std::fmt::Arguments) {}
+}
@@ -1 +1,419 @@
-//! [WIP] Messages for sockets.
+//! Envelopes of messages, with their delivery mode.
+//!
+//! Every message sent with a `DeliverySocket` is wrapped in an `Envelope`: a header frame,
+//! with its `Delivery` mode and its sequence number, followed by its frames. The mode is
+//! picked for each message:
+//!
+//! * `Delivery::AtMostOnce` messages are sent, and forgotten, as with plain sockets. They
+//!   are lost if the peer is not there to receive them.
+//! * `Delivery::AtLeastOnce` messages are kept until the peer acknowledges them, which its
+//!   `DeliverySocket` does as soon as it receives them, and are sent again on every retry
+//!   interval until then. Messages that are not acknowledged after the last attempt are
+//!   given up on, and returned by `DeliverySocket::take_undelivered`. Peers may receive
+//!   them more than once, and tell them apart by their `Envelope::sequence`.
+//!
+//! Acknowledgments are read, and messages are sent again, while `DeliverySocket::recv`
+//! waits, so both ends of a connection are `DeliverySocket`s that keep receiving, on
+//! sockets that talk back to their peer, such as `PAIR`, or `DEALER`, sockets.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::message::{Delivery, DeliverySocket};
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let a = ctx.socket(zmq::PAIR).unwrap();
+//! a.bind("inproc://delivery").unwrap();
+//! let b = ctx.socket(zmq::PAIR).unwrap();
+//! b.connect("inproc://delivery").unwrap();
+//! let (mut a, mut b) = (DeliverySocket::new(a), DeliverySocket::new(b));
+//!
+//! a.send(vec!["metrics"], Delivery::AtMostOnce).unwrap();
+//! a.send(vec!["order"], Delivery::AtLeastOnce).unwrap();
+//! assert_eq!(a.unacked(), 1);
+//! assert_eq!(b.recv(1_000).unwrap().unwrap().frames, vec![b"metrics".to_vec()]);
+//! let order = b.recv(1_000).unwrap().unwrap();
+//! assert_eq!(order.delivery, Delivery::AtLeastOnce);
+//! // reads the acknowledgment of the order.
+//! assert!(a.recv(100).unwrap().is_none());
+//! assert_eq!(a.unacked(), 0);
+//! # }
+//! ```
+use super::clock::Clock;
+use super::socket::{SocketRecv, SocketSend, SocketWrapper};
+
+use failure::Error;
+use std::collections::BTreeMap;
+use std::io;
+use zmq;
+
+/// Default milliseconds between the attempts to deliver a message.
+pub const DEFAULT_RETRY_INTERVAL: i64 = 1_000;
+/// Default number of attempts to deliver a message, before it is given up on.
+pub const DEFAULT_MAX_ATTEMPTS: u32 = 5;
+
+// First byte of the header frames, followed by a big-endian `u64` sequence.
+const AT_MOST_ONCE: u8 = 0;
+const AT_LEAST_ONCE: u8 = 1;
+const ACK: u8 = 2;
+const HEADER_SIZE: usize = 9;
+
+/// Delivery errors.
+#[derive(Debug, Fail, PartialEq)]
+pub enum DeliveryError {
+    #[fail(display = "invalid message envelope")]
+    InvalidEnvelope,
+}
+
+/// How many times a message may be delivered.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Delivery {
+    /// Sent once, and lost if it does not make it.
+    AtMostOnce,
+    /// Sent until it is acknowledged, so it may arrive more than once.
+    AtLeastOnce,
+}
+
+/// A message, with its delivery mode, and its sequence number.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Envelope {
+    pub delivery: Delivery,
+    pub sequence: u64,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl Envelope {
+    /// Create a new `Envelope`.
+    pub fn new(delivery: Delivery, sequence: u64, frames: Vec<Vec<u8>>) -> Envelope {
+        Envelope {
+            delivery,
+            sequence,
+            frames,
+        }
+    }
+
+    /// Returns the frames of the message, behind its header frame.
+    pub fn encode(&self) -> Vec<Vec<u8>> {
+        let mode = match self.delivery {
+            Delivery::AtMostOnce => AT_MOST_ONCE,
+            Delivery::AtLeastOnce => AT_LEAST_ONCE,
+        };
+        let mut msg = Vec::with_capacity(self.frames.len() + 1);
+        msg.push(header(mode, self.sequence));
+        msg.extend(self.frames.iter().cloned());
+        msg
+    }
+
+    /// Returns the envelope of `msg`. Fails with `DeliveryError::InvalidEnvelope` if it
+    /// has no valid header frame, or if it is an acknowledgment.
+    pub fn decode(mut msg: Vec<Vec<u8>>) -> Result<Envelope, DeliveryError> {
+        let (mode, sequence) = match msg.first() {
+            Some(frame) => parse_header(frame)?,
+            None => return Err(DeliveryError::InvalidEnvelope),
+        };
+        let delivery = match mode {
+            AT_MOST_ONCE => Delivery::AtMostOnce,
+            AT_LEAST_ONCE => Delivery::AtLeastOnce,
+            _ => return Err(DeliveryError::InvalidEnvelope),
+        };
+        Ok(Envelope::new(delivery, sequence, msg.split_off(1)))
+    }
+}
+
+fn header(mode: u8, sequence: u64) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_SIZE);
+    frame.push(mode);
+    frame.extend_from_slice(&sequence.to_be_bytes());
+    frame
+}
+
+fn parse_header(frame: &[u8]) -> Result<(u8, u64), DeliveryError> {
+    if frame.len() != HEADER_SIZE || frame[0] > ACK {
+        return Err(DeliveryError::InvalidEnvelope);
+    }
+    let mut sequence = [0u8; 8];
+    sequence.copy_from_slice(&frame[1..]);
+    Ok((frame[0], u64::from_be_bytes(sequence)))
+}
+
+// A message that was not acknowledged yet.
+struct Unacked {
+    envelope: Envelope,
+    attempts: u32,
+    // when it is sent again, as milliseconds of the clock of the socket.
+    deadline: i64,
+}
+
+/// Socket that sends, and receives, messages in envelopes, acknowledging, and sending
+/// again, the messages that are delivered at least once.
+pub struct DeliverySocket<S> {
+    inner: S,
+    clock: Clock,
+    retry_interval: i64,
+    max_attempts: u32,
+    next_sequence: u64,
+    unacked: BTreeMap<u64, Unacked>,
+    undelivered: Vec<Envelope>,
+}
+
+impl<S> DeliverySocket<S>
+where
+    S: SocketWrapper + SocketSend + SocketRecv,
+{
+    /// Create a new `DeliverySocket`, that makes up to `DEFAULT_MAX_ATTEMPTS` attempts to
+    /// deliver messages, every `DEFAULT_RETRY_INTERVAL` milliseconds.
+    pub fn new(inner: S) -> DeliverySocket<S> {
+        DeliverySocket {
+            inner,
+            clock: Clock::new(),
+            retry_interval: DEFAULT_RETRY_INTERVAL,
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            next_sequence: 0,
+            unacked: BTreeMap::new(),
+            undelivered: Vec::new(),
+        }
+    }
+
+    /// Make up to `max_attempts` attempts to deliver messages, every `interval`
+    /// milliseconds.
+    pub fn with_retry(mut self, interval: i64, max_attempts: u32) -> Self {
+        self.retry_interval = interval.max(1);
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Returns the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns the wrapped socket, forgetting the messages that were not acknowledged.
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns the number of messages that were not acknowledged yet.
+    pub fn unacked(&self) -> usize {
+        self.unacked.len()
+    }
+
+    /// Returns the messages that were given up on, after their last attempt.
+    pub fn take_undelivered(&mut self) -> Vec<Envelope> {
+        self.undelivered.drain(..).collect()
+    }
+
+    /// Send `frames` with the `delivery` mode, returning their sequence number.
+    ///
+    /// Messages that are delivered at least once are sent without blocking: if the socket
+    /// can't take them, e.g. its peer is gone, the attempt is missed, and they are sent
+    /// again, like messages that were not acknowledged.
+    pub fn send<I, T>(&mut self, frames: I, delivery: Delivery) -> io::Result<u64>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.next_sequence += 1;
+        let frames = frames.into_iter().map(Into::into).collect();
+        let envelope = Envelope::new(delivery, self.next_sequence, frames);
+        if delivery == Delivery::AtMostOnce {
+            self.inner.send_multipart(envelope.encode(), 0)?;
+        } else {
+            self.attempt(self.next_sequence, &envelope)?;
+            let unacked = Unacked {
+                envelope,
+                attempts: 1,
+                deadline: self.clock.mono() + self.retry_interval,
+            };
+            self.unacked.insert(self.next_sequence, unacked);
+        }
+        Ok(self.next_sequence)
+    }
+
+    // Send `envelope` without blocking, missing the attempt if the socket can't take it.
+    fn attempt(&self, sequence: u64, envelope: &Envelope) -> io::Result<()> {
+        match self.inner.send_multipart(envelope.encode(), zmq::DONTWAIT) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                nlog!(debug, "delivery attempt missed sequence={}", sequence);
+                Ok(())
+            }
+            sent => sent,
+        }
+    }
+
+    /// Receive the next message, waiting up to `timeout` milliseconds, or forever if it is
+    /// `-1`, and acknowledge it if it is delivered at least once. Returns `None` if no
+    /// message arrived in time.
+    ///
+    /// Meanwhile, acknowledgments are read, and the messages that were not acknowledged in
+    /// time are sent again, until the timeout, even if acknowledgments keep coming. Fails with `DeliveryError::InvalidEnvelope` for messages that
+    /// were not sent by a `DeliverySocket`.
+    pub fn recv(&mut self, timeout: i64) -> Result<Option<Envelope>, Error> {
+        let deadline = if timeout < 0 {
+            None
+        } else {
+            Some(self.clock.mono() + timeout)
+        };
+        loop {
+            self.retry()?;
+            let now = self.clock.mono();
+            let retry = self.unacked.values().map(|unacked| unacked.deadline).min();
+            let wait = match (deadline, retry) {
+                (Some(deadline), Some(retry)) => (deadline.min(retry) - now).max(0),
+                (Some(until), None) | (None, Some(until)) => (until - now).max(0),
+                (None, None) => -1,
+            };
+            if self.inner.get_socket_ref().poll(zmq::POLLIN, wait)? > 0 {
+                let msg = self.inner.recv_multipart(0)?;
+                if let Some(envelope) = self.receive(msg)? {
+                    return Ok(Some(envelope));
+                }
+            }
+            if deadline.is_some_and(|deadline| self.clock.mono() >= deadline) {
+                return Ok(None);
+            }
+        }
+    }
+
+    // Returns the envelope of `msg`, after acknowledging it, or `None` for acknowledgments.
+    fn receive(&mut self, msg: Vec<Vec<u8>>) -> Result<Option<Envelope>, Error> {
+        let (mode, sequence) = match msg.first() {
+            Some(frame) => parse_header(frame)?,
+            None => return Err(DeliveryError::InvalidEnvelope.into()),
+        };
+        if mode == ACK {
+            self.unacked.remove(&sequence);
+            return Ok(None);
+        }
+        let envelope = Envelope::decode(msg)?;
+        if envelope.delivery == Delivery::AtLeastOnce {
+            // a lost acknowledgment only means that the message is sent again.
+            if let Err(e) = self.inner.send(header(ACK, sequence), zmq::DONTWAIT) {
+                nlog!(
+                    debug,
+                    "acknowledgment not sent sequence={} error={}",
+                    sequence,
+                    e
+                );
+            }
+        }
+        Ok(Some(envelope))
+    }
+
+    // Send again the messages that are due, and give up on those out of attempts.
+    fn retry(&mut self) -> io::Result<()> {
+        let now = self.clock.mono();
+        let due: Vec<u64> = self
+            .unacked
+            .iter()
+            .filter(|&(_, unacked)| unacked.deadline <= now)
+            .map(|(sequence, _)| *sequence)
+            .collect();
+        for sequence in due {
+            let unacked = self.unacked.get_mut(&sequence).unwrap();
+            if unacked.attempts >= self.max_attempts {
+                nlog!(debug, "message undelivered sequence={}", sequence);
+                let unacked = self.unacked.remove(&sequence).unwrap();
+                self.undelivered.push(unacked.envelope);
+                continue;
+            }
+            unacked.attempts += 1;
+            unacked.deadline = now + self.retry_interval;
+            let unacked = &self.unacked[&sequence];
+            self.attempt(sequence, &unacked.envelope)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testkit::{inproc_endpoint, wait_until};
+
+    fn pair(ctx: &zmq::Context) -> (zmq::Socket, zmq::Socket) {
+        let endpoint = inproc_endpoint("delivery");
+        let a = ctx.socket(zmq::PAIR).unwrap();
+        a.bind(&endpoint).unwrap();
+        let b = ctx.socket(zmq::PAIR).unwrap();
+        b.connect(&endpoint).unwrap();
+        (a, b)
+    }
+
+    #[test]
+    fn messages_are_sent_again_until_acknowledged() {
+        let ctx = zmq::Context::new();
+        let (a, b) = pair(&ctx);
+        let mut a = DeliverySocket::new(a).with_retry(20, 10);
+        let sequence = a.send(vec!["order"], Delivery::AtLeastOnce).unwrap();
+
+        // the peer does not acknowledge the first delivery.
+        let first = Envelope::decode(b.recv_multipart(0).unwrap()).unwrap();
+        assert_eq!(first.sequence, sequence);
+        assert!(a.recv(50).unwrap().is_none());
+        let mut b = DeliverySocket::new(b);
+        let again = b.recv(1_000).unwrap().unwrap();
+        assert_eq!(again, first);
+
+        assert!(wait_until(1_000, || {
+            assert!(a.recv(10).unwrap().is_none());
+            a.unacked() == 0
+        }));
+        assert!(a.take_undelivered().is_empty());
+    }
+
+    #[test]
+    fn acknowledgments_do_not_hold_off_the_timeout() {
+        let ctx = zmq::Context::new();
+        let (a, b) = pair(&ctx);
+        let mut a = DeliverySocket::new(a);
+        for sequence in 0..100 {
+            b.send(header(ACK, sequence), 0).unwrap();
+        }
+        assert_eq!(a.get_ref().poll(zmq::POLLIN, 1_000).unwrap(), 1);
+        // a single pass reads an acknowledgment, and the timeout is up.
+        assert!(a.recv(0).unwrap().is_none());
+        assert!(a.get_ref().poll(zmq::POLLIN, 0).unwrap() > 0);
+    }
+
+    #[test]
+    fn messages_without_acknowledgments_are_given_up_on() {
+        let ctx = zmq::Context::new();
+        let (a, b) = pair(&ctx);
+        let mut a = DeliverySocket::new(a).with_retry(10, 3);
+        a.send(vec!["metrics"], Delivery::AtMostOnce).unwrap();
+        let sequence = a.send(vec!["order"], Delivery::AtLeastOnce).unwrap();
+        assert_eq!(a.unacked(), 1);
+        assert!(a.recv(100).unwrap().is_none());
+        assert_eq!(a.unacked(), 0);
+        assert_eq!(
+            a.take_undelivered(),
+            vec![Envelope::new(
+                Delivery::AtLeastOnce,
+                sequence,
+                vec![b"order".to_vec()]
+            )]
+        );
+
+        let mut received = Vec::new();
+        while let Ok(msg) = b.recv_multipart(zmq::DONTWAIT) {
+            received.push(Envelope::decode(msg).unwrap().delivery);
+        }
+        let mut expected = vec![Delivery::AtMostOnce];
+        expected.extend(vec![Delivery::AtLeastOnce; 3]);
+        assert_eq!(received, expected);
+
+        // without a peer, attempts are missed, instead of blocking.
+        let lonely = ctx.socket(zmq::PAIR).unwrap();
+        lonely.bind(&inproc_endpoint("delivery-lonely")).unwrap();
+        let mut lonely = DeliverySocket::new(lonely).with_retry(10, 3);
+        lonely.send(vec!["order"], Delivery::AtLeastOnce).unwrap();
+        assert!(lonely.recv(100).unwrap().is_none());
+        assert_eq!(lonely.take_undelivered().len(), 1);
+        assert_eq!(
+            Envelope::decode(vec![header(ACK, 1)]),
+            Err(DeliveryError::InvalidEnvelope)
+        );
+    }
+}
@@ -0,0 +1,334 @@
+//! Middlewares for the messages of sockets, and actors.
+//!
+//! A `Middleware` sees every message that goes through a `Pipeline`, and hands it on to the
+//! rest of the chain with `Next::run`, after changing it, e.g. to decompress it, or without
+//! handing it on at all, e.g. when it fails an authorization check, so that it is dropped.
+//! Middlewares run in the order they were added, and see what the rest of the chain made
+//! of the message, e.g. to log, or count, the messages that made it through.
+//!
+//! `PipelineSocket` runs a pipeline on the messages that a socket receives, and another on
+//! those it sends. `Actorling::with_middleware` runs one on the messages of an actor,
+//! before they are queued in its inbox.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::pipeline::{from_fn, Pipeline, PipelineSocket};
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let push = ctx.socket(zmq::PUSH).unwrap();
+//! push.bind("inproc://pipeline").unwrap();
+//! let pull = ctx.socket(zmq::PULL).unwrap();
+//! pull.connect("inproc://pipeline").unwrap();
+//!
+//! // Drop the messages that are not signed, and strip the signature of the others.
+//! let verify = from_fn(|mut msg: Vec<Vec<u8>>, next| {
+//!     if msg.first().map(|frame| &frame[..]) != Some(b"signed") {
+//!         return Ok(None);
+//!     }
+//!     msg.remove(0);
+//!     next.run(msg)
+//! });
+//! let pull = PipelineSocket::new(pull).on_recv(Pipeline::new().with(verify));
+//!
+//! push.send_multipart(vec!["forged", "hello"], 0).unwrap();
+//! push.send_multipart(vec!["signed", "hello"], 0).unwrap();
+//! assert_eq!(pull.recv_multipart(0).unwrap(), vec![b"hello".to_vec()]);
+//! # }
+//! ```
+use super::socket::{SocketRecv, SocketSend, SocketWrapper};
+
+use failure::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex, MutexGuard};
+use zmq;
+
+/// Frames of a message.
+pub type Multipart = Vec<Vec<u8>>;
+
+/// A step of a `Pipeline`.
+pub trait Middleware: Send {
+    /// Handle `msg`, and return what the rest of the chain, run with `next`, makes of it, or
+    /// `None` to drop it. Errors drop the message, and are returned by the pipeline.
+    fn handle(&mut self, msg: Multipart, next: Next<'_>) -> Result<Option<Multipart>, Error>;
+}
+
+/// The middlewares of a `Pipeline` that come after the one handling a message.
+pub struct Next<'a> {
+    rest: &'a mut [Box<dyn Middleware>],
+}
+
+impl<'a> Next<'a> {
+    /// Hand `msg` to the next middleware, returning what is left of it at the end of the
+    /// chain.
+    pub fn run(self, msg: Multipart) -> Result<Option<Multipart>, Error> {
+        match self.rest.split_first_mut() {
+            Some((middleware, rest)) => middleware.handle(msg, Next { rest }),
+            None => Ok(Some(msg)),
+        }
+    }
+}
+
+/// A `Middleware` made of a closure, see `from_fn`.
+pub struct FnMiddleware<F> {
+    f: F,
+}
+
+impl<F> Middleware for FnMiddleware<F>
+where
+    F: FnMut(Multipart, Next<'_>) -> Result<Option<Multipart>, Error> + Send,
+{
+    fn handle(&mut self, msg: Multipart, next: Next<'_>) -> Result<Option<Multipart>, Error> {
+        (self.f)(msg, next)
+    }
+}
+
+/// Make a `Middleware` of the closure `f`, which is called as `Middleware::handle`.
+pub fn from_fn<F>(f: F) -> FnMiddleware<F>
+where
+    F: FnMut(Multipart, Next<'_>) -> Result<Option<Multipart>, Error> + Send,
+{
+    FnMiddleware { f }
+}
+
+/// Logs the number of frames, and bytes, of every message, at `debug` level, with the
+/// `logging` feature.
+#[derive(Clone, Debug)]
+pub struct Logging {
+    label: String,
+}
+
+impl Logging {
+    /// Create a middleware that logs the messages it sees with `label`, e.g. `recv`.
+    pub fn new(label: &str) -> Logging {
+        Logging {
+            label: label.to_string(),
+        }
+    }
+}
+
+impl Middleware for Logging {
+    fn handle(&mut self, msg: Multipart, next: Next<'_>) -> Result<Option<Multipart>, Error> {
+        nlog!(
+            debug,
+            "message label={} frames={} bytes={}",
+            self.label,
+            msg.len(),
+            msg.iter().map(Vec::len).sum::<usize>()
+        );
+        next.run(msg)
+    }
+}
+
+/// A chain of middlewares. Clones share their middlewares, and the state of them.
+#[derive(Clone, Default)]
+pub struct Pipeline {
+    middlewares: Arc<Mutex<Vec<Box<dyn Middleware>>>>,
+}
+
+impl Pipeline {
+    /// Create an empty `Pipeline`, that hands on every message as it is.
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Add `middleware` at the end of the chain.
+    pub fn with<M: Middleware + 'static>(self, middleware: M) -> Self {
+        self.lock().push(Box::new(middleware));
+        self
+    }
+
+    /// Returns the number of middlewares in the chain.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns true if there are no middlewares in the chain.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Run `msg` through the chain, returning what is left of it, or `None` if it was
+    /// dropped.
+    pub fn run(&self, msg: Multipart) -> Result<Option<Multipart>, Error> {
+        Next {
+            rest: &mut self.lock()[..],
+        }
+        .run(msg)
+    }
+
+    // A middleware that panicked leaves the chain as it was.
+    fn lock(&self) -> MutexGuard<'_, Vec<Box<dyn Middleware>>> {
+        match self.middlewares.lock() {
+            Ok(middlewares) => middlewares,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("middlewares", &self.len())
+            .finish()
+    }
+}
+
+/// A socket whose messages go through a `Pipeline` as they are received, and another as
+/// they are sent.
+pub struct PipelineSocket<S> {
+    socket: S,
+    recv: Pipeline,
+    send: Pipeline,
+}
+
+impl<S: SocketWrapper> PipelineSocket<S> {
+    /// Wrap `socket`, with empty pipelines.
+    pub fn new(socket: S) -> PipelineSocket<S> {
+        PipelineSocket {
+            socket,
+            recv: Pipeline::new(),
+            send: Pipeline::new(),
+        }
+    }
+
+    /// Run the received messages through `pipeline`.
+    pub fn on_recv(mut self, pipeline: Pipeline) -> Self {
+        self.recv = pipeline;
+        self
+    }
+
+    /// Run the messages to send through `pipeline`.
+    pub fn on_send(mut self, pipeline: Pipeline) -> Self {
+        self.send = pipeline;
+        self
+    }
+
+    /// Returns a reference to the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Returns the wrapped socket.
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+}
+
+impl<S: SocketRecv> PipelineSocket<S> {
+    /// Receive the next message that makes it through the receive pipeline. Messages that
+    /// are dropped are skipped, so this waits for the next one, unless `flags` has
+    /// `zmq::DONTWAIT`. Errors of the pipeline fail with `io::ErrorKind::InvalidData`.
+    pub fn recv_multipart(&self, flags: i32) -> io::Result<Multipart> {
+        loop {
+            let msg = self.socket.recv_multipart(flags)?;
+            if let Some(msg) = self.recv.run(msg).map_err(invalid_data)? {
+                return Ok(msg);
+            }
+        }
+    }
+}
+
+impl<S: SocketSend> PipelineSocket<S> {
+    /// Send `msg` once it has been through the send pipeline, or not at all if it was
+    /// dropped. Errors of the pipeline fail with `io::ErrorKind::InvalidData`.
+    pub fn send_multipart(&self, msg: Multipart, flags: i32) -> io::Result<()> {
+        match self.send.run(msg).map_err(invalid_data)? {
+            Some(msg) => self.socket.send_multipart(msg, flags),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<S: SocketWrapper> SocketWrapper for PipelineSocket<S> {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        self.socket.get_socket_ref()
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.socket.get_rcvmore()
+    }
+}
+
+fn invalid_data(e: Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use testkit::inproc_endpoint;
+
+    #[test]
+    fn middlewares_run_in_order_and_can_drop_messages() {
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&seen);
+        let pipeline = Pipeline::new()
+            .with(Logging::new("test"))
+            .with(from_fn(move |msg: Multipart, next: Next| {
+                // counts what the rest of the chain lets through.
+                let out = next.run(msg)?;
+                if out.is_some() {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(out)
+            }))
+            .with(from_fn(|msg: Multipart, next: Next| {
+                if msg.is_empty() {
+                    return Err(format_err!("empty message"));
+                }
+                if msg[0] == b"drop" {
+                    return Ok(None);
+                }
+                next.run(
+                    msg.into_iter()
+                        .map(|frame| frame.to_ascii_uppercase())
+                        .collect(),
+                )
+            }));
+        assert_eq!(pipeline.len(), 3);
+        assert_eq!(
+            pipeline.run(vec![b"a".to_vec(), b"b".to_vec()]).unwrap(),
+            Some(vec![b"A".to_vec(), b"B".to_vec()])
+        );
+        assert_eq!(pipeline.run(vec![b"drop".to_vec()]).unwrap(), None);
+        assert!(pipeline.run(vec![]).is_err());
+        assert_eq!(seen.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            Pipeline::new().run(vec![b"a".to_vec()]).unwrap(),
+            Some(vec![b"a".to_vec()])
+        );
+    }
+
+    #[test]
+    fn pipeline_sockets_run_their_pipelines_both_ways() {
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("pipeline");
+        let suffix = |suffix: &'static [u8]| {
+            from_fn(move |mut msg: Multipart, next: Next| {
+                msg.push(suffix.to_vec());
+                next.run(msg)
+            })
+        };
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind(&endpoint).unwrap();
+        let pull = PipelineSocket::new(pull).on_recv(Pipeline::new().with(suffix(b"recv")));
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        let push = PipelineSocket::new(push).on_send(Pipeline::new().with(suffix(b"send")));
+
+        push.send_multipart(vec![b"hello".to_vec()], 0).unwrap();
+        assert_eq!(
+            pull.recv_multipart(0).unwrap(),
+            vec![b"hello".to_vec(), b"send".to_vec(), b"recv".to_vec()]
+        );
+        assert_eq!(
+            pull.recv_multipart(zmq::DONTWAIT).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+}
@@ -1,14 +1,117 @@
 //! Polling for evented actor types.
+//!
+//! Sockets, or actors, that are registered with `Poller::register_socket` are labeled with
+//! a `Handle`, which is also the label of their events, as returned by `Poller::run_once`.
+//!
+//! Other threads wake a poller, that is blocked in `Poller::run_once`, with a `Waker`, e.g.
+//! to hand it work, or to shut it down. Wakeups are labeled with `Handle::WAKER`.
+//!
+//! Timers, added with `Poller::add_timer`, are labeled with their own `Handle`. Deadlines
+//! are milliseconds of the poller's monotonic `Clock`, and `Poller::run_until` waits until
+//! the earliest of a deadline, and of the timers, so that callers do not compute timeouts.
+//!
+//! The `ZMQ_FD` of a `PollingSocket` is edge-triggered, and only signals changes of its
+//! `ZMQ_EVENTS`: it may be readable without messages, and messages may arrive without a new
+//! edge. Their events are reported from `ZMQ_EVENTS` instead, which the poller reads for
+//! every registered `PollingSocket`, before and after each poll.
+//!
+//! A `ShardedPoller` partitions its sockets across threads, each of which runs a `Poller`.
+use super::clock::Clock;
+use super::socket::PollingSocket;
+
 use mio_lib::event::Evented;
-use mio_lib::Poll;
+use mio_lib::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
 use slab::Slab;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::time::Duration;
 use zmq;
 
+#[path = "poller_sharded.rs"]
+mod sharded;
+
+pub use self::sharded::{Shard, ShardEvent, ShardHandle, ShardedPoller};
+
+// Capacity of the events returned by each poll.
+const EVENTS_CAPACITY: usize = 1024;
+// First handle of the timers, far above the handles of sockets.
+const TIMER_BASE: usize = usize::MAX / 2;
+
+/// Label of a socket registered with a `Poller`, and of its events.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Handle(usize);
+
+impl Handle {
+    /// Label of the events of the poller's `Waker`.
+    pub const WAKER: Handle = Handle(usize::MAX - 1);
+
+    /// Returns the `mio` token of the socket.
+    pub fn token(&self) -> Token {
+        Token(self.0)
+    }
+}
+
+/// An event of a registered socket, as returned by `Poller::run_once`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PollEvent {
+    pub handle: Handle,
+    pub readiness: Ready,
+}
+
+/// Wakes a `Poller` from other threads.
+#[derive(Clone)]
+pub struct Waker {
+    readiness: SetReadiness,
+}
+
+impl Waker {
+    /// Wake the poller, whose next, or current, `run_once` returns an event labeled with
+    /// `Handle::WAKER`. Wakeups before that are merged into a single event.
+    pub fn wake(&self) -> io::Result<()> {
+        self.readiness.set_readiness(Ready::readable())
+    }
+}
+
+/// Sockets, or actors, that are registered with a `Poller`.
+pub trait Pollable: Evented {
+    /// Returns the current readiness of a `PollingSocket`, from its `ZMQ_EVENTS`, or `None`
+    /// for other types, whose events are reported as they are.
+    fn zmq_readiness(&self) -> Option<io::Result<Ready>>;
+
+    /// Returns the socket if it is a `PollingSocket`.
+    fn polling_socket(&self) -> Option<&PollingSocket>;
+}
+
+impl<E: Evented + 'static> Pollable for E {
+    fn zmq_readiness(&self) -> Option<io::Result<Ready>> {
+        (self as &dyn Any)
+            .downcast_ref::<PollingSocket>()
+            .map(PollingSocket::events)
+    }
+
+    fn polling_socket(&self) -> Option<&PollingSocket> {
+        (self as &dyn Any).downcast_ref::<PollingSocket>()
+    }
+}
+
+// A timer, that is due at its `deadline`, and repeats every `interval`, if any.
+struct Timer {
+    deadline: i64,
+    interval: Option<i64>,
+}
+
 /// Polling instance for evented actors.
 pub struct Poller {
     context: zmq::Context,
     pub poll: Poll,
-    pub actors: Slab<Box<dyn Evented>>,
+    pub actors: Slab<Box<dyn Pollable>>,
+    interests: HashMap<Handle, (Ready, PollOpt)>,
+    events: Events,
+    waker: Option<(Registration, Waker)>,
+    clock: Clock,
+    timers: BTreeMap<Handle, Timer>,
+    next_timer: usize,
 }
 
 impl Poller {
@@ -40,7 +143,233 @@ impl Poller {
             context,
             poll,
             actors,
+            interests: HashMap::new(),
+            events: Events::with_capacity(EVENTS_CAPACITY),
+            waker: None,
+            clock: Clock::new(),
+            timers: BTreeMap::new(),
+            next_timer: TIMER_BASE,
+        }
+    }
+
+    /// Returns the poller's network context.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Register `socket` for the `interest` events, returning the `Handle` that labels
+    /// them. The poller keeps the socket until it is deregistered.
+    pub fn register_socket<E>(
+        &mut self,
+        socket: E,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<Handle>
+    where
+        E: Evented + 'static,
+    {
+        let grows = self.actors.len() == self.actors.capacity();
+        let handle = Handle(self.actors.insert(Box::new(socket)));
+        if let Err(e) = self.actors[handle.0].register(&self.poll, handle.token(), interest, opts) {
+            self.actors.remove(handle.0);
+            return Err(e);
+        }
+        self.interests.insert(handle, (interest, opts));
+        if grows {
+            self.reregister_all(handle)?;
+        }
+        Ok(handle)
+    }
+
+    /// Deregister the socket labeled by `handle`, and return it.
+    pub fn deregister(&mut self, handle: Handle) -> io::Result<Box<dyn Evented>> {
+        if !self.actors.contains(handle.0) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "handle is not registered",
+            ));
+        }
+        let socket = self.actors.remove(handle.0);
+        self.interests.remove(&handle);
+        socket.deregister(&self.poll)?;
+        Ok(socket as Box<dyn Evented>)
+    }
+
+    /// Returns the socket labeled by `handle`.
+    pub fn get(&self, handle: Handle) -> Option<&dyn Evented> {
+        self.actors
+            .get(handle.0)
+            .map(|socket| &**socket as &dyn Evented)
+    }
+
+    /// Returns the socket labeled by `handle`, if it is a `PollingSocket`.
+    pub fn socket(&self, handle: Handle) -> Option<&PollingSocket> {
+        self.actors
+            .get(handle.0)
+            .and_then(|socket| socket.polling_socket())
+    }
+
+    /// Returns the number of registered sockets.
+    pub fn len(&self) -> usize {
+        self.actors.len()
+    }
+
+    /// Returns true if no sockets are registered.
+    pub fn is_empty(&self) -> bool {
+        self.actors.is_empty()
+    }
+
+    /// Returns a `Waker` for this poller, that can be sent to other threads.
+    pub fn waker(&mut self) -> io::Result<Waker> {
+        if let Some((_, ref waker)) = self.waker {
+            return Ok(waker.clone());
         }
+        let (registration, readiness) = Registration::new2();
+        self.poll.register(
+            &registration,
+            Handle::WAKER.token(),
+            Ready::readable(),
+            PollOpt::edge(),
+        )?;
+        let waker = Waker { readiness };
+        self.waker = Some((registration, waker.clone()));
+        Ok(waker)
+    }
+
+    /// Returns the monotonic clock of the poller, whose milliseconds are its deadlines.
+    pub fn clock(&self) -> &Clock {
+        &self.clock
+    }
+
+    /// Add a timer, due in `delay` milliseconds, and then every `interval` milliseconds if
+    /// it is set, returning the `Handle` that labels its events.
+    pub fn add_timer(&mut self, delay: i64, interval: Option<i64>) -> Handle {
+        let handle = Handle(self.next_timer);
+        self.next_timer += 1;
+        let timer = Timer {
+            deadline: self.clock.mono() + delay.max(0),
+            interval: interval.map(|interval| interval.max(1)),
+        };
+        self.timers.insert(handle, timer);
+        handle
+    }
+
+    /// Cancel the timer labeled by `handle`. Returns false if it was not pending.
+    pub fn cancel_timer(&mut self, handle: Handle) -> bool {
+        self.timers.remove(&handle).is_some()
+    }
+
+    /// Returns the earliest deadline of the pending timers.
+    pub fn next_deadline(&self) -> Option<i64> {
+        self.timers.values().map(|timer| timer.deadline).min()
+    }
+
+    /// Poll once, waiting up to `timeout`, or forever if it is `None`, but no longer than
+    /// the next timer, and return the events of the registered sockets, of the `Waker`, and
+    /// of the timers that are due.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> io::Result<Vec<PollEvent>> {
+        let deadline = timeout.map(|timeout| self.clock.mono() + timeout.as_millis() as i64);
+        self.run_until(deadline)
+    }
+
+    /// Poll once, waiting until the `deadline`, as milliseconds of `Poller::clock`, or
+    /// forever if it is `None`, but no longer than the next timer. Returns the events of
+    /// the registered sockets, of the `Waker`, and of the timers that are due.
+    pub fn run_until(&mut self, deadline: Option<i64>) -> io::Result<Vec<PollEvent>> {
+        loop {
+            let wait_until = match (deadline, self.next_deadline()) {
+                (Some(deadline), Some(timer)) => Some(deadline.min(timer)),
+                (deadline, timer) => deadline.or(timer),
+            };
+            let mut timeout = wait_until.map(|wait_until| {
+                Duration::from_millis((wait_until - self.clock.mono()).max(0) as u64)
+            });
+            // sockets with pending events may never be signaled again.
+            if !self.zmq_events()?.is_empty() {
+                timeout = Some(Duration::from_millis(0));
+            }
+            self.poll.poll(&mut self.events, timeout)?;
+            let mut events: Vec<PollEvent> = self
+                .events
+                .iter()
+                .map(|event| PollEvent {
+                    handle: Handle(event.token().0),
+                    readiness: event.readiness(),
+                })
+                // `PollingSocket`s report the readiness of their `ZMQ_EVENTS`, below.
+                .filter(|event| {
+                    let socket = self.actors.get(event.handle.0);
+                    socket.is_none_or(|socket| socket.zmq_readiness().is_none())
+                })
+                .collect();
+            events.extend(self.zmq_events()?);
+            if let Some((_, ref waker)) = self.waker {
+                if events.iter().any(|event| event.handle == Handle::WAKER) {
+                    // ready again on the next wakeup.
+                    waker.readiness.set_readiness(Ready::empty())?;
+                }
+            }
+            self.fire_timers(&mut events);
+            // polls may return early, by less than a millisecond, or without any events.
+            let expired = deadline.is_some_and(|deadline| self.clock.mono() >= deadline);
+            if !events.is_empty() || expired {
+                return Ok(events);
+            }
+        }
+    }
+
+    // Returns an event for each registered `PollingSocket` whose `ZMQ_EVENTS` match its
+    // interest. Reading them also re-arms the `ZMQ_FD` of the socket.
+    fn zmq_events(&self) -> io::Result<Vec<PollEvent>> {
+        let mut events = Vec::new();
+        for (handle, &(interest, _)) in &self.interests {
+            if let Some(readiness) = self.actors[handle.0].zmq_readiness() {
+                let readiness = readiness? & interest;
+                if !readiness.is_empty() {
+                    events.push(PollEvent {
+                        handle: *handle,
+                        readiness,
+                    });
+                }
+            }
+        }
+        Ok(events)
+    }
+
+    // Add an event for each timer that is due, and schedule the ones that repeat.
+    fn fire_timers(&mut self, events: &mut Vec<PollEvent>) {
+        let now = self.clock.mono();
+        let mut expired = Vec::new();
+        for (handle, timer) in &mut self.timers {
+            if timer.deadline > now {
+                continue;
+            }
+            events.push(PollEvent {
+                handle: *handle,
+                readiness: Ready::readable(),
+            });
+            match timer.interval {
+                // skip the intervals that were missed, instead of firing them all at once.
+                Some(interval) => {
+                    timer.deadline = now + interval - (now - timer.deadline) % interval;
+                }
+                None => expired.push(*handle),
+            }
+        }
+        for handle in expired {
+            self.timers.remove(&handle);
+        }
+    }
+
+    // Register every socket again, but the `added` one, after the actors grew.
+    fn reregister_all(&self, added: Handle) -> io::Result<()> {
+        nlog!(debug, "poller grew capacity={}", self.actors.capacity());
+        for (handle, &(interest, opts)) in &self.interests {
+            if *handle != added {
+                self.actors[handle.0].reregister(&self.poll, handle.token(), interest, opts)?;
+            }
+        }
+        Ok(())
     }
 }
 
@@ -53,8 +382,41 @@ impl Default for Poller {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::thread;
+    use std::time::Instant;
     use zmq;
 
+    #[test]
+    fn pollers_run_until_their_deadline() {
+        let mut poller = Poller::new();
+        let deadline = poller.clock().mono() + 30;
+        assert!(poller.run_until(Some(deadline)).unwrap().is_empty());
+        assert!(poller.clock().mono() >= deadline);
+    }
+
+    #[test]
+    fn timers_cut_the_wait_short() {
+        let mut poller = Poller::new();
+        let once = poller.add_timer(20, None);
+        let every = poller.add_timer(10, Some(10));
+        let far = poller.clock().mono() + 5_000;
+
+        let mut fired = Vec::new();
+        while !fired.contains(&once) {
+            let start = poller.clock().mono();
+            let events = poller.run_until(Some(far)).unwrap();
+            assert!(poller.clock().mono() - start < 1_000);
+            fired.extend(events.into_iter().map(|event| event.handle));
+        }
+        assert!(poller.clock().mono() >= 20);
+        assert!(fired.iter().filter(|handle| **handle == every).count() >= 1);
+        // one-shot timers are done, and repeating timers go on until they are cancelled.
+        assert!(poller.next_deadline().is_some());
+        assert!(!poller.cancel_timer(once));
+        assert!(poller.cancel_timer(every));
+        assert_eq!(poller.next_deadline(), None);
+    }
+
     #[test]
     fn default_poller_has_no_pre_allocation() {
         let poller: Poller = Default::default();
@@ -74,4 +436,122 @@ mod tests {
         let poller: Poller = Poller::with_context_and_capacity(ctx, 30);
         assert_eq!(poller.actors.capacity(), 30);
     }
+
+    #[test]
+    fn registered_sockets_label_their_events() {
+        let mut poller = Poller::with_capacity(1);
+        let ctx = poller.context();
+        let mut handles = Vec::new();
+        let mut senders = Vec::new();
+        for n in 0..3 {
+            let endpoint = format!("inproc://poller_handles_{}", n);
+            let receiver = ctx.socket(zmq::PAIR).unwrap();
+            receiver.bind(&endpoint).unwrap();
+            let sender = ctx.socket(zmq::PAIR).unwrap();
+            sender.connect(&endpoint).unwrap();
+            let handle = poller
+                .register_socket(
+                    PollingSocket::new(receiver),
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )
+                .unwrap();
+            handles.push(handle);
+            senders.push(sender);
+        }
+        assert_eq!(poller.len(), 3);
+        senders[1].send("ping", 0).unwrap();
+        let events = poller.run_once(Some(Duration::from_secs(1))).unwrap();
+        assert!(events.iter().all(|event| handles.contains(&event.handle)));
+        assert!(events
+            .iter()
+            .any(|event| event.handle == handles[1] && event.readiness.is_readable()));
+
+        poller.deregister(handles[1]).unwrap();
+        assert!(poller.get(handles[1]).is_none());
+        assert_eq!(
+            poller.deregister(handles[1]).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+    }
+
+    #[test]
+    fn pending_messages_are_reported_until_received() {
+        let mut poller = Poller::new();
+        let ctx = poller.context();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.bind("inproc://poller_pending").unwrap();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.connect("inproc://poller_pending").unwrap();
+        let handle = poller
+            .register_socket(
+                PollingSocket::new(receiver),
+                Ready::readable(),
+                PollOpt::edge(),
+            )
+            .unwrap();
+        sender.send("ping", 0).unwrap();
+        for _ in 0..2 {
+            let events = poller.run_once(Some(Duration::from_secs(1))).unwrap();
+            assert_eq!(
+                events,
+                vec![PollEvent {
+                    handle,
+                    readiness: Ready::readable(),
+                }]
+            );
+        }
+    }
+
+    #[test]
+    fn readable_fds_without_messages_are_not_reported() {
+        let mut poller = Poller::new();
+        let ctx = poller.context();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.bind("inproc://poller_stale").unwrap();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.connect("inproc://poller_stale").unwrap();
+        // connecting left its `ZMQ_FD` readable, without messages.
+        let fd = receiver.get_fd().unwrap();
+        let mut items = [zmq::PollItem::from_fd(fd, zmq::POLLIN)];
+        assert_eq!(zmq::poll(&mut items, 0).unwrap(), 1);
+        poller
+            .register_socket(
+                PollingSocket::new(receiver),
+                Ready::readable(),
+                PollOpt::edge(),
+            )
+            .unwrap();
+        assert!(poller
+            .run_once(Some(Duration::from_millis(20)))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn wakers_wake_blocked_pollers_from_other_threads() {
+        let mut poller = Poller::new();
+        let waker = poller.waker().unwrap();
+        let woke = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            waker.wake().unwrap();
+            waker.wake().unwrap();
+        });
+        let start = Instant::now();
+        let events = poller.run_once(Some(Duration::from_secs(5))).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(5));
+        woke.join().unwrap();
+        let events: Vec<_> = events.into_iter().map(|event| event.handle).collect();
+        assert_eq!(events, vec![Handle::WAKER]);
+        // merged wakeups are only returned once.
+        assert!(poller
+            .run_once(Some(Duration::from_millis(10)))
+            .unwrap()
+            .is_empty());
+        poller.waker().unwrap().wake().unwrap();
+        assert_eq!(
+            poller.run_once(Some(Duration::from_secs(1))).unwrap()[0].handle,
+            Handle::WAKER
+        );
+    }
 }
@@ -0,0 +1,478 @@
+//! Pollers sharded across threads.
+//!
+//! A single `Poller` waits for, and handles, the events of all of its sockets on one
+//! thread, which falls behind when there are many busy sockets. A `ShardedPoller` partitions
+//! its sockets across a number of shards, each of which runs a `Poller` of its own, with its
+//! own `mio::Poll`, on a thread of its own, and hands their events to a handler that runs on
+//! that thread. A `ShardedPoller` of a single shard is a `Poller` on a thread of its own.
+//!
+//! Sockets are registered with `ShardedPoller::register_socket`, on the shard with the
+//! fewest sockets, or with `ShardedPoller::register_socket_on`, on a given shard, and are
+//! labeled with a `ShardHandle`. Registered sockets are moved to the thread of their shard,
+//! and used there, by the handler, through `Shard::socket`.
+//!
+//! Every shard has an inbox, a `PULL` socket bound to an `inproc` endpoint, and shards pass
+//! messages to each other with `Shard::send_to`, or are sent messages with
+//! `ShardedPoller::send_to`. They are handed to the handler as `ShardEvent::Message`.
+use super::super::socket::{PollingSocket, SocketRecv};
+use super::super::utils::run_named_thread;
+use super::{Handle, PollEvent, Poller, Waker};
+
+use failure::Error;
+use mio_lib::event::Evented;
+use mio_lib::{PollOpt, Ready};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use zmq;
+
+// Numbers the sharded pollers of the process, for the endpoints of their inboxes.
+static NEXT_POLLER: AtomicUsize = AtomicUsize::new(0);
+
+/// Label of a socket registered with a `ShardedPoller`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct ShardHandle {
+    /// Index of the shard of the socket.
+    pub shard: usize,
+    /// Label of the socket in the `Poller` of its shard.
+    pub handle: Handle,
+}
+
+/// What the handler of a `ShardedPoller` is handed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ShardEvent {
+    /// An event of a socket, or of a timer, of the shard.
+    Ready(PollEvent),
+    /// A message sent to the inbox of the shard.
+    Message(Vec<Vec<u8>>),
+}
+
+/// A shard of a `ShardedPoller`, as seen by its handler.
+pub struct Shard {
+    index: usize,
+    poller: Poller,
+    inbox: Handle,
+    peers: Vec<zmq::Socket>,
+}
+
+impl Shard {
+    /// Returns the index of the shard.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Returns the number of shards of the poller.
+    pub fn shards(&self) -> usize {
+        self.peers.len()
+    }
+
+    /// Returns the `Poller` of the shard, e.g. to add timers.
+    pub fn poller(&mut self) -> &mut Poller {
+        &mut self.poller
+    }
+
+    /// Returns the `PollingSocket` labeled by `handle`, if it is one.
+    pub fn socket(&self, handle: Handle) -> Option<&PollingSocket> {
+        self.poller.socket(handle)
+    }
+
+    /// Send `frames` to the inbox of the `shard`, which may be this one.
+    ///
+    /// Fails with `zmq::Error::EAGAIN`, instead of blocking the thread of this shard, when
+    /// the inbox is full, e.g. because the other shard is blocked sending to this one.
+    pub fn send_to<I, T>(&self, shard: usize, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        send_to(&self.peers, shard, frames)
+    }
+}
+
+type Handler = dyn Fn(&mut Shard, ShardEvent) + Send + Sync;
+type Register = Box<dyn FnOnce(&mut Poller) -> io::Result<Handle> + Send>;
+
+enum Command {
+    Register(Register, mpsc::Sender<io::Result<Handle>>),
+    Deregister(Handle, mpsc::Sender<io::Result<()>>),
+    Stop,
+}
+
+// What a sharded poller keeps of each of its shards.
+struct ShardThread {
+    sender: mpsc::Sender<Command>,
+    waker: Waker,
+    len: usize,
+    thread: Option<thread::JoinHandle<Result<(), Error>>>,
+}
+
+impl ShardThread {
+    fn send(&self, command: Command) -> Result<(), Error> {
+        self.sender
+            .send(command)
+            .map_err(|_| format_err!("shard is not running"))?;
+        self.waker.wake()?;
+        Ok(())
+    }
+}
+
+/// Poller whose sockets are partitioned across threads.
+pub struct ShardedPoller {
+    context: zmq::Context,
+    shards: Vec<ShardThread>,
+    inboxes: Vec<zmq::Socket>,
+}
+
+impl ShardedPoller {
+    /// Create a new `ShardedPoller`, and start the threads of its `shards`, which run until
+    /// it is dropped, handing the events of their sockets to `handler`.
+    pub fn new<F>(context: zmq::Context, shards: usize, handler: F) -> Result<ShardedPoller, Error>
+    where
+        F: Fn(&mut Shard, ShardEvent) + Send + Sync + 'static,
+    {
+        if shards == 0 {
+            bail!("sharded pollers need at least one shard");
+        }
+        let id = NEXT_POLLER.fetch_add(1, Ordering::SeqCst);
+        let endpoints: Vec<String> = (0..shards)
+            .map(|index| format!("inproc://sharded-poller-{}-{}", id, index))
+            .collect();
+        let handler: Arc<Handler> = Arc::new(handler);
+        let mut poller = ShardedPoller {
+            context: context.clone(),
+            shards: Vec::with_capacity(shards),
+            inboxes: Vec::new(),
+        };
+        for index in 0..shards {
+            let (sender, receiver) = mpsc::channel();
+            let (waker_tx, waker_rx) = mpsc::channel();
+            let context = context.clone();
+            let endpoints = endpoints.clone();
+            let handler = Arc::clone(&handler);
+            let thread = run_named_thread(&format!("shard-{}", index), move || {
+                let mut shard = start_shard(context, index, &endpoints)?;
+                waker_tx.send(shard.poller.waker()?)?;
+                run_shard(&mut shard, &receiver, &*handler)
+            })?;
+            let waker = match waker_rx.recv() {
+                Ok(waker) => waker,
+                Err(_) => {
+                    return Err(thread
+                        .join()
+                        .map_err(|_| format_err!("shard panicked"))?
+                        .err()
+                        .unwrap_or_else(|| format_err!("shard stopped")))
+                }
+            };
+            poller.shards.push(ShardThread {
+                sender,
+                waker,
+                len: 0,
+                thread: Some(thread),
+            });
+        }
+        poller.inboxes = connect_peers(&context, &endpoints)?;
+        Ok(poller)
+    }
+
+    /// Returns the poller's network context.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Returns the number of shards.
+    pub fn shards(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Returns the number of sockets registered on the `shard`.
+    pub fn len(&self, shard: usize) -> usize {
+        self.shards.get(shard).map_or(0, |shard| shard.len)
+    }
+
+    /// Returns true if no sockets are registered on any shard.
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|shard| shard.len == 0)
+    }
+
+    /// Register `socket` for the `interest` events, on the shard with the fewest sockets,
+    /// returning the `ShardHandle` that labels them.
+    pub fn register_socket<E>(
+        &mut self,
+        socket: E,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> Result<ShardHandle, Error>
+    where
+        E: Evented + Send + 'static,
+    {
+        let shard = (0..self.shards.len())
+            .min_by_key(|shard| self.shards[*shard].len)
+            .unwrap_or(0);
+        self.register_socket_on(shard, socket, interest, opts)
+    }
+
+    /// Register `socket` for the `interest` events, on the `shard`.
+    pub fn register_socket_on<E>(
+        &mut self,
+        shard: usize,
+        socket: E,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> Result<ShardHandle, Error>
+    where
+        E: Evented + Send + 'static,
+    {
+        let (reply, replies) = mpsc::channel();
+        let register: Register =
+            Box::new(move |poller: &mut Poller| poller.register_socket(socket, interest, opts));
+        self.shard(shard)?
+            .send(Command::Register(register, reply))?;
+        let handle = replies
+            .recv()
+            .map_err(|_| format_err!("shard is not running"))??;
+        self.shards[shard].len += 1;
+        Ok(ShardHandle { shard, handle })
+    }
+
+    /// Deregister the socket labeled by `handle`, which is closed on the thread of its
+    /// shard.
+    pub fn deregister(&mut self, handle: ShardHandle) -> Result<(), Error> {
+        let (reply, replies) = mpsc::channel();
+        self.shard(handle.shard)?
+            .send(Command::Deregister(handle.handle, reply))?;
+        replies
+            .recv()
+            .map_err(|_| format_err!("shard is not running"))??;
+        self.shards[handle.shard].len -= 1;
+        Ok(())
+    }
+
+    /// Send `frames` to the inbox of the `shard`.
+    ///
+    /// Fails with `zmq::Error::EAGAIN` when the inbox is full.
+    pub fn send_to<I, T>(&self, shard: usize, frames: I) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        send_to(&self.inboxes, shard, frames)
+    }
+
+    fn shard(&self, shard: usize) -> Result<&ShardThread, Error> {
+        self.shards
+            .get(shard)
+            .ok_or_else(|| format_err!("no shard {} of {}", shard, self.shards.len()))
+    }
+}
+
+impl Drop for ShardedPoller {
+    fn drop(&mut self) {
+        for shard in &self.shards {
+            let _ = shard.send(Command::Stop);
+        }
+        for (index, shard) in self.shards.iter_mut().enumerate() {
+            if let Some(thread) = shard.thread.take() {
+                match thread.join() {
+                    Ok(Err(e)) => nlog!(warn, "shard failed shard={} error={}", index, e),
+                    Err(_) => nlog!(warn, "shard panicked shard={}", index),
+                    Ok(Ok(())) => {}
+                }
+            }
+        }
+    }
+}
+
+// Returns a `PUSH` socket connected to each of the inboxes at `endpoints`.
+fn connect_peers(context: &zmq::Context, endpoints: &[String]) -> Result<Vec<zmq::Socket>, Error> {
+    let mut peers = Vec::with_capacity(endpoints.len());
+    for endpoint in endpoints {
+        let peer = context.socket(zmq::PUSH)?;
+        peer.set_linger(0)?;
+        peer.connect(endpoint)?;
+        peers.push(peer);
+    }
+    Ok(peers)
+}
+
+fn send_to<I, T>(peers: &[zmq::Socket], shard: usize, frames: I) -> Result<(), Error>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<Vec<u8>>,
+{
+    let peer = peers
+        .get(shard)
+        .ok_or_else(|| format_err!("no shard {} of {}", shard, peers.len()))?;
+    let frames: Vec<Vec<u8>> = frames.into_iter().map(Into::into).collect();
+    peer.send_multipart(frames, zmq::DONTWAIT)?;
+    Ok(())
+}
+
+// Bind the inbox of the shard at `index`, and connect to the inboxes of every shard.
+fn start_shard(context: zmq::Context, index: usize, endpoints: &[String]) -> Result<Shard, Error> {
+    let inbox = context.socket(zmq::PULL)?;
+    inbox.bind(&endpoints[index])?;
+    let peers = connect_peers(&context, endpoints)?;
+    let mut poller = Poller::with_context(context);
+    let inbox = poller.register_socket(
+        PollingSocket::new(inbox),
+        Ready::readable(),
+        PollOpt::edge(),
+    )?;
+    Ok(Shard {
+        index,
+        poller,
+        inbox,
+        peers,
+    })
+}
+
+// Run the commands, and hand the events, of the shard to `handler`, until it stops.
+fn run_shard(
+    shard: &mut Shard,
+    commands: &mpsc::Receiver<Command>,
+    handler: &Handler,
+) -> Result<(), Error> {
+    loop {
+        for event in shard.poller.run_once(None)? {
+            if event.handle == Handle::WAKER {
+                for command in commands.try_iter() {
+                    match command {
+                        Command::Register(register, reply) => {
+                            let _ = reply.send(register(&mut shard.poller));
+                        }
+                        Command::Deregister(handle, reply) => {
+                            let deregistered = if handle == shard.inbox {
+                                Err(io::Error::new(
+                                    io::ErrorKind::NotFound,
+                                    "handle is not registered",
+                                ))
+                            } else {
+                                shard.poller.deregister(handle).map(|_| ())
+                            };
+                            let _ = reply.send(deregistered);
+                        }
+                        Command::Stop => return Ok(()),
+                    }
+                }
+            } else if event.handle == shard.inbox {
+                let mut messages = Vec::new();
+                if let Some(inbox) = shard.socket(shard.inbox) {
+                    while let Ok(frames) = inbox.recv_multipart(zmq::DONTWAIT) {
+                        messages.push(frames);
+                    }
+                }
+                for frames in messages {
+                    handler(shard, ShardEvent::Message(frames));
+                }
+            } else {
+                handler(shard, ShardEvent::Ready(event));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    #[test]
+    fn sockets_are_spread_across_shards_that_message_each_other() {
+        let (tx, rx) = mpsc::channel();
+        let tx = Mutex::new(tx);
+        let ctx = zmq::Context::new();
+        let mut poller = ShardedPoller::new(ctx.clone(), 2, move |shard, event| match event {
+            // forward every message to the next shard, labeled with this one.
+            ShardEvent::Ready(event) => {
+                let socket = shard.socket(event.handle).unwrap();
+                while let Ok(msg) = socket.recv_bytes(zmq::DONTWAIT) {
+                    let next = (shard.index() + 1) % shard.shards();
+                    let from = shard.index().to_string().into_bytes();
+                    shard.send_to(next, vec![from, msg]).unwrap();
+                }
+            }
+            ShardEvent::Message(frames) => {
+                tx.lock().unwrap().send((shard.index(), frames)).unwrap();
+            }
+        })
+        .unwrap();
+
+        let mut senders = Vec::new();
+        let mut handles = Vec::new();
+        for n in 0..4 {
+            let endpoint = format!("inproc://sharded-{}", n);
+            let receiver = ctx.socket(zmq::PULL).unwrap();
+            receiver.bind(&endpoint).unwrap();
+            let sender = ctx.socket(zmq::PUSH).unwrap();
+            sender.connect(&endpoint).unwrap();
+            let handle = poller
+                .register_socket(
+                    PollingSocket::new(receiver),
+                    Ready::readable(),
+                    PollOpt::edge(),
+                )
+                .unwrap();
+            handles.push(handle);
+            senders.push(sender);
+        }
+        assert_eq!((poller.len(0), poller.len(1)), (2, 2));
+
+        for (sender, handle) in senders.iter().zip(&handles) {
+            sender.send(&format!("{}", handle.shard), 0).unwrap();
+        }
+        for _ in 0..4 {
+            let (shard, frames) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(frames[0], frames[1]);
+            assert_eq!(frames[0], ((shard + 1) % 2).to_string().into_bytes());
+        }
+
+        poller.send_to(1, vec!["direct"]).unwrap();
+        let (shard, frames) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((shard, frames), (1, vec![b"direct".to_vec()]));
+    }
+
+    #[test]
+    fn full_inboxes_fail_to_send_instead_of_blocking() {
+        let (tx, rx) = mpsc::channel::<()>();
+        let rx = Mutex::new(rx);
+        let poller = ShardedPoller::new(zmq::Context::new(), 1, move |_, _| {
+            // block the shard, until the test is done.
+            let _ = rx.lock().unwrap().recv();
+        })
+        .unwrap();
+
+        let full = (0..100_000)
+            .map(|_| poller.send_to(0, vec!["message"]))
+            .find(Result::is_err)
+            .expect("inbox is never full");
+        match full.unwrap_err().downcast::<zmq::Error>() {
+            Ok(zmq::Error::EAGAIN) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        drop(tx);
+    }
+
+    #[test]
+    fn sockets_are_deregistered_from_their_shard() {
+        assert!(ShardedPoller::new(zmq::Context::new(), 0, |_, _| {}).is_err());
+        let mut poller = ShardedPoller::new(zmq::Context::new(), 3, |_, _| {}).unwrap();
+        let socket = PollingSocket::new(poller.context().socket(zmq::PULL).unwrap());
+        assert!(poller
+            .register_socket_on(3, socket, Ready::readable(), PollOpt::edge())
+            .is_err());
+
+        let socket = PollingSocket::new(poller.context().socket(zmq::PULL).unwrap());
+        let handle = poller
+            .register_socket_on(2, socket, Ready::readable(), PollOpt::edge())
+            .unwrap();
+        assert_eq!(handle.shard, 2);
+        assert_eq!(poller.len(2), 1);
+        poller.deregister(handle).unwrap();
+        assert!(poller.is_empty());
+        assert!(poller.deregister(handle).is_err());
+    }
+}
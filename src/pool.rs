@@ -0,0 +1,434 @@
+//! Pools of client sockets.
+//!
+//! A single `REQ` socket has one request in flight at a time, so it serializes every
+//! request made through it. A `ReqPool` keeps many `DEALER` sockets, spread over a set of
+//! server endpoints, sends each request on one of them, and matches the replies back to
+//! their requests. Requests keep the `REQ` envelope, so servers can be plain `REP`, or
+//! `ROUTER`, sockets.
+//!
+//! A socket whose oldest request was not answered within the request timeout is considered
+//! failed: it is replaced by a new socket to the same endpoint, and its requests are sent
+//! again on the rest of the pool, until they run out of retries.
+use super::clock::Clock;
+use super::endpoint::{AddressParse, Endpoint, ToEndpoint};
+
+use std::collections::VecDeque;
+use zmq;
+
+/// Default time, in milliseconds, to wait for a reply before a request is retried.
+pub const REQUEST_TIMEOUT: i64 = 2_500;
+/// Default number of times that a request is sent again before it fails.
+pub const REQUEST_RETRIES: usize = 3;
+
+/// Pool errors.
+#[derive(Debug, Fail)]
+pub enum PoolError {
+    #[fail(display = "pool has no endpoints to connect to")]
+    NoEndpoints,
+    #[fail(
+        display = "requests {:?} were not answered after all of their retries",
+        _0
+    )]
+    RequestFailed(Vec<u64>),
+    #[fail(display = "invalid reply, without the request envelope")]
+    InvalidReply,
+    #[fail(display = "{}", _0)]
+    Address(#[cause] AddressParse),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<AddressParse> for PoolError {
+    fn from(e: AddressParse) -> PoolError {
+        PoolError::Address(e)
+    }
+}
+
+impl From<zmq::Error> for PoolError {
+    fn from(e: zmq::Error) -> PoolError {
+        PoolError::Zmq(e)
+    }
+}
+
+/// A reply, as the id of its request, and its frames.
+pub type Reply = (u64, Vec<Vec<u8>>);
+
+/// How requests are dispatched to the sockets of a pool.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Balance {
+    /// Each socket in turn.
+    RoundRobin,
+    /// The socket with the fewest requests waiting for a reply.
+    LeastOutstanding,
+}
+
+// A request waiting for its reply.
+struct Pending {
+    id: u64,
+    frames: Vec<Vec<u8>>,
+    sent_at: i64,
+    attempts: usize,
+}
+
+// A socket of the pool, connected to a single endpoint, so that its replies arrive in the
+// same order as its requests.
+struct Member {
+    socket: zmq::Socket,
+    endpoint: String,
+    pending: VecDeque<Pending>,
+}
+
+impl Member {
+    fn connect(context: &zmq::Context, endpoint: &str) -> Result<Member, PoolError> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(endpoint)?;
+        Ok(Member {
+            socket,
+            endpoint: endpoint.to_string(),
+            pending: VecDeque::new(),
+        })
+    }
+
+    fn send(&mut self, request: Pending) -> Result<(), PoolError> {
+        let mut msg = vec![Vec::new()];
+        msg.extend(request.frames.iter().cloned());
+        self.socket.send_multipart(msg, 0)?;
+        self.pending.push_back(request);
+        Ok(())
+    }
+}
+
+/// A pool of client sockets, that load-balances requests over a set of servers.
+pub struct ReqPool {
+    context: zmq::Context,
+    members: Vec<Member>,
+    balance: Balance,
+    timeout: i64,
+    retries: usize,
+    next_member: usize,
+    next_id: u64,
+    clock: Clock,
+}
+
+impl ReqPool {
+    /// Create a pool of `size` sockets, connected to `endpoints` in turn. There is at least
+    /// one socket for each endpoint.
+    pub fn new<E: ToEndpoint>(
+        context: &zmq::Context,
+        endpoints: &[E],
+        size: usize,
+    ) -> Result<ReqPool, PoolError> {
+        if endpoints.is_empty() {
+            return Err(PoolError::NoEndpoints);
+        }
+        let endpoints = endpoints
+            .iter()
+            .map(|e| e.to_endpoint().map(|e: Endpoint| e.to_string()))
+            .collect::<Result<Vec<String>, AddressParse>>()?;
+        let size = size.max(endpoints.len());
+        let mut members = Vec::with_capacity(size);
+        for i in 0..size {
+            members.push(Member::connect(context, &endpoints[i % endpoints.len()])?);
+        }
+        Ok(ReqPool {
+            context: context.clone(),
+            members,
+            balance: Balance::RoundRobin,
+            timeout: REQUEST_TIMEOUT,
+            retries: REQUEST_RETRIES,
+            next_member: 0,
+            next_id: 0,
+            clock: Clock::new(),
+        })
+    }
+
+    /// Returns the number of sockets in the pool.
+    pub fn size(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns the number of requests waiting for a reply.
+    pub fn outstanding(&self) -> usize {
+        self.members.iter().map(|m| m.pending.len()).sum()
+    }
+
+    /// Set how requests are dispatched to the sockets of the pool.
+    pub fn set_balance(&mut self, balance: Balance) {
+        self.balance = balance;
+    }
+
+    /// Set the time, in milliseconds, to wait for a reply before the request is retried.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Set the number of times that a request is sent again before it fails.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Send a request, returning its id, which is returned with its reply.
+    pub fn send<I, T>(&mut self, msg: I) -> Result<u64, PoolError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.next_id += 1;
+        let request = Pending {
+            id: self.next_id,
+            frames: msg.into_iter().map(Into::into).collect(),
+            sent_at: 0,
+            attempts: 0,
+        };
+        self.dispatch(request, None)?;
+        Ok(self.next_id)
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it is `-1`, for the next reply, as
+    /// the id of its request, and its frames.
+    ///
+    /// Requests that time out while waiting are retried, and fail with
+    /// `PoolError::RequestFailed`, with the ids of every request that ran out of retries, or
+    /// could not be sent again.
+    pub fn recv(&mut self, timeout: i64) -> Result<Option<Reply>, PoolError> {
+        let deadline = self.clock.mono() + timeout;
+        loop {
+            self.retry_expired()?;
+            let now = self.clock.mono();
+            if timeout >= 0 && now >= deadline {
+                return Ok(None);
+            }
+            let mut wait = match self.next_expiry() {
+                Some(expiry) => (expiry - now).max(0),
+                None => -1,
+            };
+            if timeout >= 0 && (wait < 0 || deadline - now < wait) {
+                wait = deadline - now;
+            }
+            let ready = {
+                let mut items: Vec<zmq::PollItem> = self
+                    .members
+                    .iter()
+                    .map(|m| m.socket.as_poll_item(zmq::POLLIN))
+                    .collect();
+                zmq::poll(&mut items, wait)?;
+                items.iter().position(|item| item.is_readable())
+            };
+            if let Some(index) = ready {
+                let reply = self.members[index].socket.recv_multipart(0)?;
+                match self.members[index].pending.pop_front() {
+                    Some(request) => return Ok(Some((request.id, unwrap_reply(reply)?))),
+                    None => nlog!(debug, "dropped unexpected reply"),
+                }
+            }
+        }
+    }
+
+    /// Send a request, and wait up to `timeout` milliseconds for its reply. Replies to
+    /// other requests that arrive in the meantime are dropped.
+    pub fn request<I, T>(&mut self, msg: I, timeout: i64) -> Result<Option<Vec<Vec<u8>>>, PoolError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let id = self.send(msg)?;
+        let deadline = self.clock.mono() + timeout;
+        loop {
+            let wait = if timeout < 0 {
+                -1
+            } else {
+                (deadline - self.clock.mono()).max(0)
+            };
+            match self.recv(wait)? {
+                Some((reply_id, frames)) if reply_id == id => return Ok(Some(frames)),
+                Some(_) => {}
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn pick(&mut self, exclude: Option<usize>) -> usize {
+        let count = self.members.len();
+        let allowed = |i: usize| count == 1 || Some(i) != exclude;
+        match self.balance {
+            Balance::RoundRobin => {
+                let mut index = self.next_member % count;
+                if !allowed(index) {
+                    index = (index + 1) % count;
+                }
+                self.next_member = index + 1;
+                index
+            }
+            Balance::LeastOutstanding => (0..count)
+                .filter(|i| allowed(*i))
+                .min_by_key(|i| self.members[*i].pending.len())
+                .unwrap_or(0),
+        }
+    }
+
+    fn dispatch(&mut self, mut request: Pending, exclude: Option<usize>) -> Result<(), PoolError> {
+        let index = self.pick(exclude);
+        request.sent_at = self.clock.mono();
+        request.attempts += 1;
+        self.members[index].send(request)
+    }
+
+    fn next_expiry(&self) -> Option<i64> {
+        self.members
+            .iter()
+            .filter_map(|m| m.pending.front())
+            .map(|request| request.sent_at + self.timeout)
+            .min()
+    }
+
+    // Replace the sockets whose oldest request expired, and send their requests again on
+    // the rest of the pool. A socket that can't be replaced is kept, without its requests,
+    // and the first error is returned once every socket is handled.
+    fn retry_expired(&mut self) -> Result<(), PoolError> {
+        let now = self.clock.mono();
+        let mut failed_ids = Vec::new();
+        let mut error = None;
+        for index in 0..self.members.len() {
+            let expired = self.members[index]
+                .pending
+                .front()
+                .is_some_and(|request| now - request.sent_at >= self.timeout);
+            if !expired {
+                continue;
+            }
+            let endpoint = self.members[index].endpoint.clone();
+            nlog!(
+                debug,
+                "replacing pool socket endpoint={} pending={}",
+                endpoint,
+                self.members[index].pending.len()
+            );
+            let pending = match Member::connect(&self.context, &endpoint) {
+                Ok(member) => ::std::mem::replace(&mut self.members[index], member).pending,
+                Err(e) => {
+                    nlog!(
+                        warn,
+                        "pool socket not replaced endpoint={} error={}",
+                        endpoint,
+                        e
+                    );
+                    error = error.or(Some(e));
+                    ::std::mem::take(&mut self.members[index].pending)
+                }
+            };
+            for request in pending {
+                let id = request.id;
+                if request.attempts > self.retries {
+                    failed_ids.push(id);
+                } else if let Err(e) = self.dispatch(request, Some(index)) {
+                    failed_ids.push(id);
+                    error = error.or(Some(e));
+                }
+            }
+        }
+        if !failed_ids.is_empty() {
+            return Err(PoolError::RequestFailed(failed_ids));
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+// Remove the empty delimiter of the `REQ` envelope.
+fn unwrap_reply(mut reply: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, PoolError> {
+    if reply.is_empty() || !reply[0].is_empty() {
+        return Err(PoolError::InvalidReply);
+    }
+    reply.remove(0);
+    Ok(reply)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    // A REP server that answers every request with its frames, prefixed by `name`.
+    fn echo_server(ctx: &zmq::Context, endpoint: &str, name: &'static str, count: usize) {
+        let server = ctx.socket(zmq::REP).unwrap();
+        server.bind(endpoint).unwrap();
+        thread::spawn(move || {
+            for _ in 0..count {
+                let mut msg = server.recv_multipart(0).unwrap();
+                msg.insert(0, name.as_bytes().to_vec());
+                server.send_multipart(msg, 0).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn pools_need_endpoints() {
+        let ctx = zmq::Context::new();
+        let endpoints: Vec<&str> = Vec::new();
+        match ReqPool::new(&ctx, &endpoints, 2) {
+            Err(PoolError::NoEndpoints) => {}
+            _ => panic!("pools can't be empty"),
+        }
+    }
+
+    #[test]
+    fn requests_are_spread_over_the_servers() {
+        let ctx = zmq::Context::new();
+        echo_server(&ctx, "inproc://pool_a", "a", 2);
+        echo_server(&ctx, "inproc://pool_b", "b", 2);
+        let mut pool = ReqPool::new(&ctx, &["inproc://pool_a", "inproc://pool_b"], 4).unwrap();
+        assert_eq!(pool.size(), 4);
+        let ids: Vec<u64> = (0..4).map(|i| pool.send(vec![vec![i]]).unwrap()).collect();
+        assert_eq!(pool.outstanding(), 4);
+
+        let mut servers = Vec::new();
+        for _ in 0..4 {
+            let (id, reply) = pool.recv(1_000).unwrap().unwrap();
+            assert!(ids.contains(&id));
+            assert_eq!(reply[1], vec![(id - 1) as u8]);
+            servers.push(reply[0].clone());
+        }
+        servers.sort();
+        assert_eq!(
+            servers,
+            vec![b"a".to_vec(), b"a".to_vec(), b"b".to_vec(), b"b".to_vec()]
+        );
+        assert_eq!(pool.outstanding(), 0);
+    }
+
+    #[test]
+    fn requests_to_failed_sockets_are_retried_elsewhere() {
+        let ctx = zmq::Context::new();
+        // nobody answers on the first endpoint.
+        let silent = ctx.socket(zmq::ROUTER).unwrap();
+        silent.bind("inproc://pool_silent").unwrap();
+        echo_server(&ctx, "inproc://pool_alive", "alive", 1);
+        let mut pool =
+            ReqPool::new(&ctx, &["inproc://pool_silent", "inproc://pool_alive"], 2).unwrap();
+        pool.set_balance(Balance::LeastOutstanding);
+        pool.set_timeout(50);
+        let reply = pool.request(vec!["hello"], 1_000).unwrap().unwrap();
+        assert_eq!(reply, vec![b"alive".to_vec(), b"hello".to_vec()]);
+    }
+
+    #[test]
+    fn requests_fail_after_their_retries() {
+        let ctx = zmq::Context::new();
+        let silent = ctx.socket(zmq::ROUTER).unwrap();
+        silent.bind("inproc://pool_retries").unwrap();
+        let mut pool = ReqPool::new(&ctx, &["inproc://pool_retries"], 1).unwrap();
+        pool.set_timeout(10);
+        pool.set_retries(1);
+        let ids = vec![
+            pool.send(vec!["lost"]).unwrap(),
+            pool.send(vec!["also lost"]).unwrap(),
+        ];
+        match pool.recv(1_000) {
+            Err(PoolError::RequestFailed(failed)) => assert_eq!(failed, ids),
+            other => panic!("unexpected outcome: {:?}", other.map(|_| ())),
+        }
+    }
+}
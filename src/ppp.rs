@@ -0,0 +1,616 @@
+//! Reliable request-reply queues, with heartbeats.
+//!
+//! An implementation of the
+//! "[Paranoid Pirate Protocol](https://rfc.zeromq.org/spec/6/)" (PPP), as described in
+//! "[Robust Reliable Queuing](http://zguide.zeromq.org/page:all#Robust-Reliable-Queuing-Paranoid-Pirate-Pattern)".
+//!
+//! A `Queue` runs on a child thread, with a `ROUTER` frontend for clients, and a `ROUTER`
+//! backend for workers. Workers announce themselves with `READY`, and are handed requests
+//! in the order they became ready. The queue, and its workers, send each other
+//! `HEARTBEAT`s, and the queue expires the workers that stay silent for `liveness`
+//! intervals, while workers reconnect, backing off, to a silent queue.
+//!
+//! | from   | frames                                        |
+//! |--------|-----------------------------------------------|
+//! | worker | `READY`, or `HEARTBEAT`, in a single frame    |
+//! | queue  | `HEARTBEAT`, in a single frame                |
+//! | queue  | the envelope of the client, `""`, the request |
+//! | worker | the envelope of the client, `""`, the reply   |
+//!
+//! `Client` is a blocking, "Lazy Pirate", client, that retries its requests with a fresh
+//! connection, and `Worker` is a blocking worker that speaks the protocol to the queue.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::ppp::{Client, Queue, Worker};
+//! use std::thread;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let queue = Queue::new_with_context("inproc://ppp-front", "inproc://ppp-back", ctx.clone())
+//!     .unwrap();
+//! queue.start().unwrap();
+//! queue.pipe().recv_multipart(0).unwrap();
+//!
+//! let worker_ctx = ctx.clone();
+//! thread::spawn(move || {
+//!     let mut worker = Worker::new("inproc://ppp-back", worker_ctx).unwrap();
+//!     let mut reply = None;
+//!     loop {
+//!         reply = Some(worker.recv(reply.take()).unwrap());
+//!     }
+//! });
+//!
+//! let mut client = Client::new("inproc://ppp-front", ctx).unwrap();
+//! assert_eq!(client.send(vec!["hello"]).unwrap(), vec![b"hello".to_vec()]);
+//! queue.stop().unwrap();
+//! # }
+//! ```
+use super::clock::Clock;
+use super::socket::SocketError;
+use super::utils::run_named_thread;
+
+use failure::Error;
+use std::collections::VecDeque;
+use std::io;
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// Worker command announcing it is ready to serve.
+pub const PPP_READY: &[u8] = b"\x01";
+/// Command signaling liveness, sent both ways.
+pub const PPP_HEARTBEAT: &[u8] = b"\x02";
+
+/// Number of missed heartbeats before a peer is considered dead.
+pub const HEARTBEAT_LIVENESS: usize = 3;
+/// Milliseconds between heartbeats.
+pub const HEARTBEAT_INTERVAL: i64 = 1_000;
+/// Milliseconds before a worker reconnects to a silent queue the first time.
+pub const INTERVAL_INIT: i64 = 1_000;
+/// Most milliseconds before a worker reconnects to a silent queue, after backing off.
+pub const INTERVAL_MAX: i64 = 32_000;
+
+/// Paranoid Pirate Errors.
+#[derive(Debug, Fail)]
+pub enum PppError {
+    #[fail(display = "invalid protocol message")]
+    InvalidMessage,
+    #[fail(display = "no reply after {} attempts", _0)]
+    NoReply(usize),
+}
+
+/// How often peers send heartbeats, and how many they miss before they are considered dead.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Heartbeat {
+    /// Milliseconds between heartbeats.
+    pub interval: i64,
+    /// Number of missed heartbeats before a peer is considered dead.
+    pub liveness: usize,
+}
+
+impl Heartbeat {
+    /// Returns the milliseconds after which a silent peer is dead.
+    pub fn expiry(&self) -> i64 {
+        self.interval * self.liveness as i64
+    }
+}
+
+impl Default for Heartbeat {
+    fn default() -> Self {
+        Heartbeat {
+            interval: HEARTBEAT_INTERVAL,
+            liveness: HEARTBEAT_LIVENESS,
+        }
+    }
+}
+
+// A worker that is ready for a request, until it expires.
+struct ReadyWorker {
+    identity: Vec<u8>,
+    expiry: i64,
+}
+
+// Queue state, owned by the queue thread. Workers are kept in the order they became ready.
+struct QueueState {
+    clock: Clock,
+    heartbeat: Heartbeat,
+    workers: VecDeque<ReadyWorker>,
+}
+
+impl QueueState {
+    fn new(heartbeat: Heartbeat) -> Self {
+        QueueState {
+            clock: Clock::new(),
+            heartbeat,
+            workers: VecDeque::new(),
+        }
+    }
+
+    // Put the worker with `identity` at the back of the ready workers.
+    fn ready(&mut self, identity: Vec<u8>) {
+        self.workers.retain(|worker| worker.identity != identity);
+        self.workers.push_back(ReadyWorker {
+            identity,
+            expiry: self.clock.mono() + self.heartbeat.expiry(),
+        });
+    }
+
+    // Extend the life of the worker with `identity`, if it is ready.
+    fn alive(&mut self, identity: &[u8]) {
+        let expiry = self.clock.mono() + self.heartbeat.expiry();
+        if let Some(worker) = self.workers.iter_mut().find(|w| w.identity == identity) {
+            worker.expiry = expiry;
+        }
+    }
+
+    // Handle a message from a worker, forwarding replies to the frontend.
+    fn worker_msg(&mut self, frontend: &zmq::Socket, mut msg: Vec<Vec<u8>>) -> Result<(), Error> {
+        if msg.len() < 2 {
+            return Err(PppError::InvalidMessage.into());
+        }
+        let body = msg.split_off(1);
+        let identity = msg.remove(0);
+        if body.len() == 1 {
+            match &body[0][..] {
+                PPP_READY => self.ready(identity),
+                PPP_HEARTBEAT => self.alive(&identity),
+                _ => return Err(PppError::InvalidMessage.into()),
+            }
+            return Ok(());
+        }
+        // a reply, after which the worker is ready again.
+        self.ready(identity);
+        frontend.send_multipart(body, 0)?;
+        Ok(())
+    }
+
+    // Hand a client request to the worker that has been ready the longest.
+    fn client_msg(&mut self, backend: &zmq::Socket, msg: Vec<Vec<u8>>) -> Result<(), Error> {
+        let worker = match self.workers.pop_front() {
+            Some(worker) => worker,
+            None => return Err(PppError::InvalidMessage.into()),
+        };
+        let mut request = vec![worker.identity];
+        request.extend(msg);
+        backend.send_multipart(request, 0)?;
+        Ok(())
+    }
+
+    // Send a heartbeat to every ready worker.
+    fn send_heartbeats(&self, backend: &zmq::Socket) -> Result<(), Error> {
+        for worker in &self.workers {
+            backend.send_multipart(vec![&worker.identity[..], PPP_HEARTBEAT], 0)?;
+        }
+        Ok(())
+    }
+
+    // Drop the workers that have been silent for too long.
+    fn purge(&mut self) {
+        let now = self.clock.mono();
+        self.workers.retain(|worker| {
+            if worker.expiry < now {
+                nlog!(debug, "worker expired identity={:?}", worker.identity);
+            }
+            worker.expiry >= now
+        });
+    }
+}
+
+/// A Paranoid Pirate queue, handing requests from clients to ready workers.
+pub struct Queue {
+    frontend: String,
+    backend: String,
+    context: zmq::Context,
+    pipe: zmq::Socket,
+    heartbeat: Heartbeat,
+    uuid: Uuid,
+}
+
+impl Queue {
+    /// Create a new `Queue` instance, which will listen for clients on the `frontend`
+    /// address, and for workers on the `backend` address.
+    pub fn new(frontend: &str, backend: &str) -> Result<Self, Error> {
+        Queue::new_with_context(frontend, backend, zmq::Context::new())
+    }
+
+    /// Create a new `Queue` instance that shares network context with the creator.
+    pub fn new_with_context(
+        frontend: &str,
+        backend: &str,
+        context: zmq::Context,
+    ) -> Result<Self, Error> {
+        let uuid = Uuid::new_v4();
+        let pipe = context.socket(zmq::PAIR)?;
+        pipe.connect(&pipe_address(&uuid))?;
+        Ok(Queue {
+            frontend: frontend.to_string(),
+            backend: backend.to_string(),
+            context,
+            pipe,
+            heartbeat: Heartbeat::default(),
+            uuid,
+        })
+    }
+
+    /// Set the heartbeat of the queue, which its workers must match. Takes effect on
+    /// `start`.
+    pub fn with_heartbeat(mut self, heartbeat: Heartbeat) -> Self {
+        self.heartbeat = heartbeat;
+        self
+    }
+
+    /// Returns the queue's network context.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Return a reference to the underlying pipe socket.
+    pub fn pipe(&self) -> &zmq::Socket {
+        &self.pipe
+    }
+
+    /// Start the queue on a child thread. The bound frontend, and backend, endpoints are
+    /// sent back over the pipe, in two frames, once the queue is listening.
+    pub fn start(&self) -> Result<thread::JoinHandle<Result<(), Error>>, io::Error> {
+        let context = self.context();
+        let (frontend_addr, backend_addr) = (self.frontend.clone(), self.backend.clone());
+        let heartbeat = self.heartbeat;
+        let pipe_addr = pipe_address(&self.uuid);
+
+        run_named_thread("ppp-queue", move || {
+            let pipe = context.socket(zmq::PAIR)?;
+            pipe.bind(&pipe_addr)?;
+
+            let frontend = context.socket(zmq::ROUTER)?;
+            frontend.bind(&frontend_addr)?;
+            let backend = context.socket(zmq::ROUTER)?;
+            backend.bind(&backend_addr)?;
+            let endpoints = [
+                frontend
+                    .get_last_endpoint()?
+                    .map_err(SocketError::Endpoint)?,
+                backend
+                    .get_last_endpoint()?
+                    .map_err(SocketError::Endpoint)?,
+            ];
+            pipe.send_multipart(endpoints.iter().map(String::as_bytes), 0)?;
+
+            poll_queue(&pipe, &frontend, &backend, &mut QueueState::new(heartbeat))
+        })
+    }
+
+    /// Stop the queue.
+    pub fn stop(&self) -> Result<(), zmq::Error> {
+        self.pipe().send("$STOP", 0)
+    }
+}
+
+// Each queue gets its own pipe address.
+fn pipe_address(uuid: &Uuid) -> String {
+    format!("inproc://neuras.ppp.{}", uuid.to_simple())
+}
+
+fn poll_queue(
+    pipe: &zmq::Socket,
+    frontend: &zmq::Socket,
+    backend: &zmq::Socket,
+    state: &mut QueueState,
+) -> Result<(), Error> {
+    let interval = state.heartbeat.interval;
+    let mut heartbeat_at = state.clock.mono() + interval;
+    loop {
+        // clients are only heard while there are workers to hand their requests to.
+        let mut pollable = [
+            pipe.as_poll_item(zmq::POLLIN),
+            backend.as_poll_item(zmq::POLLIN),
+            frontend.as_poll_item(zmq::POLLIN),
+        ];
+        let polled = if state.workers.is_empty() { 2 } else { 3 };
+        zmq::poll(&mut pollable[..polled], interval)?;
+        if pollable[0].is_readable() {
+            let cmd = pipe.recv_msg(0)?;
+            if &*cmd == b"$STOP" {
+                pipe.send("$STOPPING", 0)?;
+                break;
+            }
+            pipe.send("$WONTDO", 0)?;
+        }
+        if pollable[1].is_readable() {
+            let msg = backend.recv_multipart(0)?;
+            if let Err(e) = state.worker_msg(frontend, msg) {
+                match e.downcast::<PppError>() {
+                    Ok(PppError::InvalidMessage) => nlog!(debug, "dropped invalid message"),
+                    Ok(e) => return Err(e.into()),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        if polled == 3 && pollable[2].is_readable() {
+            let msg = frontend.recv_multipart(0)?;
+            state.client_msg(backend, msg)?;
+        }
+        if state.clock.mono() >= heartbeat_at {
+            state.send_heartbeats(backend)?;
+            heartbeat_at = state.clock.mono() + interval;
+        }
+        state.purge();
+    }
+    Ok(())
+}
+
+/// Blocking Lazy Pirate client.
+pub struct Client {
+    queue: String,
+    context: zmq::Context,
+    socket: zmq::Socket,
+    timeout: i64,
+    retries: usize,
+}
+
+impl Client {
+    /// Create a new `Client`, connected to the frontend of the queue at the given address.
+    pub fn new(queue: &str, context: zmq::Context) -> Result<Self, Error> {
+        let socket = Client::connect_to_queue(&context, queue)?;
+        Ok(Client {
+            queue: queue.to_string(),
+            context,
+            socket,
+            timeout: HEARTBEAT_INTERVAL * HEARTBEAT_LIVENESS as i64,
+            retries: HEARTBEAT_LIVENESS,
+        })
+    }
+
+    fn connect_to_queue(context: &zmq::Context, queue: &str) -> Result<zmq::Socket, Error> {
+        let socket = context.socket(zmq::REQ)?;
+        socket.set_linger(0)?;
+        socket.connect(queue)?;
+        Ok(socket)
+    }
+
+    /// Set the timeout, in milliseconds, to wait for each reply.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Set the number of attempts for each request before giving up.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Send a request, and wait for the reply. Retries with a fresh connection when the
+    /// queue does not answer in time.
+    pub fn send<I, T>(&mut self, request: I) -> Result<Vec<Vec<u8>>, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let msg: Vec<Vec<u8>> = request.into_iter().map(|m| m.into()).collect();
+        for _ in 0..self.retries {
+            self.socket.send_multipart(&msg, 0)?;
+            if self.socket.poll(zmq::POLLIN, self.timeout)? > 0 {
+                return Ok(self.socket.recv_multipart(0)?);
+            }
+            nlog!(
+                debug,
+                "no reply from queue, reconnecting queue={}",
+                self.queue
+            );
+            self.socket = Client::connect_to_queue(&self.context, &self.queue)?;
+        }
+        Err(PppError::NoReply(self.retries).into())
+    }
+}
+
+/// Blocking Paranoid Pirate worker.
+pub struct Worker {
+    queue: String,
+    context: zmq::Context,
+    socket: zmq::Socket,
+    clock: Clock,
+    heartbeat: Heartbeat,
+    liveness: usize,
+    heartbeat_at: i64,
+    reconnect: i64,
+    reply_to: Option<Vec<Vec<u8>>>,
+}
+
+impl Worker {
+    /// Create a new `Worker`, connected to the backend of the queue at the given address.
+    pub fn new(queue: &str, context: zmq::Context) -> Result<Self, Error> {
+        let clock = Clock::new();
+        let socket = Worker::connect_to_queue(&context, queue)?;
+        let heartbeat = Heartbeat::default();
+        Ok(Worker {
+            queue: queue.to_string(),
+            context,
+            socket,
+            heartbeat_at: clock.mono() + heartbeat.interval,
+            clock,
+            heartbeat,
+            liveness: heartbeat.liveness,
+            reconnect: INTERVAL_INIT,
+            reply_to: None,
+        })
+    }
+
+    fn connect_to_queue(context: &zmq::Context, queue: &str) -> Result<zmq::Socket, Error> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(queue)?;
+        socket.send(PPP_READY, 0)?;
+        Ok(socket)
+    }
+
+    /// Set the heartbeat of the worker, which must match that of its queue.
+    pub fn set_heartbeat(&mut self, heartbeat: Heartbeat) {
+        self.heartbeat = heartbeat;
+        self.liveness = heartbeat.liveness;
+        self.heartbeat_at = self.clock.mono() + heartbeat.interval;
+    }
+
+    /// Send the reply to the previous request, if any, and wait for the next request.
+    pub fn recv(&mut self, reply: Option<Vec<Vec<u8>>>) -> Result<Vec<Vec<u8>>, Error> {
+        if let Some(body) = reply {
+            if let Some(mut msg) = self.reply_to.take() {
+                msg.extend(body);
+                self.socket.send_multipart(msg, 0)?;
+            }
+        }
+
+        loop {
+            if self.socket.poll(zmq::POLLIN, self.heartbeat.interval)? > 0 {
+                let mut msg = self.socket.recv_multipart(0)?;
+                self.liveness = self.heartbeat.liveness;
+                self.reconnect = INTERVAL_INIT;
+                if msg.len() == 1 && msg[0] == PPP_HEARTBEAT {
+                    // the queue is alive.
+                } else if let Some(delimiter) = msg.iter().position(|frame| frame.is_empty()) {
+                    let body = msg.split_off(delimiter + 1);
+                    self.reply_to = Some(msg);
+                    return Ok(body);
+                } else {
+                    return Err(PppError::InvalidMessage.into());
+                }
+            } else {
+                self.liveness -= 1;
+                if self.liveness == 0 {
+                    nlog!(
+                        debug,
+                        "queue is silent, reconnecting in {}ms",
+                        self.reconnect
+                    );
+                    self.clock.sleep(self.reconnect as u64);
+                    self.reconnect = (self.reconnect * 2).min(INTERVAL_MAX);
+                    self.reconnect_to_queue()?;
+                }
+            }
+            if self.clock.mono() >= self.heartbeat_at {
+                self.socket.send(PPP_HEARTBEAT, 0)?;
+                self.heartbeat_at = self.clock.mono() + self.heartbeat.interval;
+            }
+        }
+    }
+
+    fn reconnect_to_queue(&mut self) -> Result<(), Error> {
+        self.socket = Worker::connect_to_queue(&self.context, &self.queue)?;
+        self.liveness = self.heartbeat.liveness;
+        self.heartbeat_at = self.clock.mono() + self.heartbeat.interval;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FAST: Heartbeat = Heartbeat {
+        interval: 20,
+        liveness: 2,
+    };
+
+    fn start_queue(name: &str) -> (Queue, String, String) {
+        let queue = Queue::new(
+            &format!("inproc://{}-front", name),
+            &format!("inproc://{}-back", name),
+        )
+        .unwrap()
+        .with_heartbeat(FAST);
+        queue.start().unwrap();
+        let mut endpoints = queue.pipe().recv_multipart(0).unwrap();
+        let backend = String::from_utf8(endpoints.pop().unwrap()).unwrap();
+        let frontend = String::from_utf8(endpoints.pop().unwrap()).unwrap();
+        (queue, frontend, backend)
+    }
+
+    #[test]
+    fn queues_hand_requests_to_workers_and_back() {
+        let (queue, frontend, backend) = start_queue("ppp_echo");
+        let ctx = queue.context();
+        let echo = thread::spawn(move || {
+            let mut worker = Worker::new(&backend, ctx).unwrap();
+            worker.set_heartbeat(FAST);
+            let mut reply = None;
+            loop {
+                let request = worker.recv(reply.take()).unwrap();
+                if request == vec![b"bye".to_vec()] {
+                    return;
+                }
+                reply = Some(request);
+            }
+        });
+
+        let mut client = Client::new(&frontend, queue.context()).unwrap();
+        for i in 0..3 {
+            let reply = client.send(vec![format!("hello {}", i)]).unwrap();
+            assert_eq!(reply, vec![format!("hello {}", i).into_bytes()]);
+        }
+        // the worker leaves without answering.
+        client.set_timeout(FAST.expiry());
+        client.set_retries(1);
+        assert!(client.send(vec!["bye"]).is_err());
+        echo.join().unwrap();
+        queue.stop().unwrap();
+    }
+
+    #[test]
+    fn queues_speak_the_protocol_and_expire_silent_workers() {
+        let (queue, frontend, backend) = start_queue("ppp_wire");
+        let ctx = queue.context();
+        let worker = ctx.socket(zmq::DEALER).unwrap();
+        worker.connect(&backend).unwrap();
+        worker.send(PPP_READY, 0).unwrap();
+
+        let client = ctx.socket(zmq::REQ).unwrap();
+        client.connect(&frontend).unwrap();
+        client.send("ping", 0).unwrap();
+        // heartbeats may come ahead of the request.
+        let request = loop {
+            let msg = worker.recv_multipart(0).unwrap();
+            if msg != vec![PPP_HEARTBEAT.to_vec()] {
+                break msg;
+            }
+        };
+        assert_eq!(request.len(), 3);
+        assert_eq!(&request[1..], &[b"".to_vec(), b"ping".to_vec()]);
+        let reply = vec![request[0].clone(), vec![], b"pong".to_vec()];
+        worker.send_multipart(reply, 0).unwrap();
+        assert_eq!(client.recv_bytes(0).unwrap(), b"pong");
+        assert_eq!(
+            worker.recv_multipart(0).unwrap(),
+            vec![PPP_HEARTBEAT.to_vec()]
+        );
+
+        // the silent worker expires, and the request waits for a live one.
+        thread::sleep(::std::time::Duration::from_millis(FAST.expiry() as u64 * 3));
+        client.send("again", 0).unwrap();
+        let mut live = Worker::new(&backend, ctx).unwrap();
+        live.set_heartbeat(FAST);
+        assert_eq!(live.recv(None).unwrap(), vec![b"again".to_vec()]);
+        while worker.poll(zmq::POLLIN, 0).unwrap() > 0 {
+            assert_eq!(
+                worker.recv_multipart(0).unwrap(),
+                vec![PPP_HEARTBEAT.to_vec()]
+            );
+        }
+        queue.stop().unwrap();
+    }
+
+    #[test]
+    fn clients_give_up_after_retries() {
+        let context = zmq::Context::new();
+        let mut client = Client::new("inproc://ppp_missing", context).unwrap();
+        client.set_timeout(10);
+        client.set_retries(2);
+        match client
+            .send(vec!["hello"])
+            .unwrap_err()
+            .downcast::<PppError>()
+        {
+            Ok(PppError::NoReply(2)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
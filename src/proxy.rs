@@ -1 +1,243 @@
-//! [WIP] Polling for sockets.
+//! Proxies between sockets, and topologies of them.
+//!
+//! A `Proxy` runs `zmq_proxy_steerable` on a thread of its own, between a frontend, and a
+//! backend socket, copying every message to an optional capture socket. It is steered over
+//! an inproc control socket: it can be paused, resumed, asked for its statistics, and it
+//! is terminated when it is stopped, or dropped.
+//!
+//! A `Topology` declares proxies, and the endpoints of their sockets, so that multi-hop
+//! pipelines, e.g. from an `XSUB`/`XPUB` proxy to another one, are started at once, and
+//! controlled by name.
+//!
+//! A `ChaosProxy` forwards messages with delays, drops, duplicates, and reordering, to
+//! test retries, and heartbeats, against an unreliable network.
+//!
+//! A `LastValueCache` replays the last message of each topic to new subscribers.
+//!
+//! A `FairQueue` queues requests from many clients, to many workers, by client priority.
+use super::socket::SocketError;
+use super::utils::run_named_thread;
+
+use std::io;
+use std::thread;
+use toml;
+use uuid::Uuid;
+use zmq;
+
+#[path = "proxy_chaos.rs"]
+mod chaos;
+#[path = "proxy_fair.rs"]
+mod fair;
+#[path = "proxy_lvc.rs"]
+mod lvc;
+#[path = "proxy_topology.rs"]
+mod topology;
+
+pub use self::chaos::{ChaosConfig, ChaosProxy, ChaosStatistics, Delay};
+pub use self::fair::{
+    DepthHook, FairQueue, FairQueueConfig, FairQueueStatistics, DEFAULT_PRIORITY, WORKER_READY,
+};
+pub use self::lvc::{LastValueCache, LvcStatistics};
+pub use self::topology::{NodeSpec, SocketSpec, Topology, TopologyHandle};
+
+/// Command that pauses a proxy.
+pub const PAUSE: &str = "PAUSE";
+/// Command that resumes a paused proxy.
+pub const RESUME: &str = "RESUME";
+/// Command that terminates a proxy.
+pub const TERMINATE: &str = "TERMINATE";
+/// Command that asks a proxy for its statistics.
+pub const STATISTICS: &str = "STATISTICS";
+
+// How long to wait, in milliseconds, for the statistics of a proxy.
+const STATISTICS_TIMEOUT: i64 = 1_000;
+
+/// Proxy errors.
+#[derive(Debug, Fail)]
+pub enum ProxyError {
+    #[fail(display = "invalid topology: {}", _0)]
+    InvalidTopology(String),
+    #[fail(display = "unknown socket type: {}", _0)]
+    UnknownSocketType(String),
+    #[fail(display = "invalid topology file: {}", _0)]
+    Config(#[cause] toml::de::Error),
+    #[fail(display = "unexpected socket type: {:?}", _0)]
+    UnexpectedSocketType(zmq::SocketType),
+    #[fail(display = "proxy is not running")]
+    NotRunning,
+    #[fail(display = "{}", _0)]
+    Socket(#[cause] SocketError),
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<toml::de::Error> for ProxyError {
+    fn from(e: toml::de::Error) -> ProxyError {
+        ProxyError::Config(e)
+    }
+}
+
+impl From<SocketError> for ProxyError {
+    fn from(e: SocketError) -> ProxyError {
+        ProxyError::Socket(e)
+    }
+}
+
+impl From<io::Error> for ProxyError {
+    fn from(e: io::Error) -> ProxyError {
+        ProxyError::Io(e)
+    }
+}
+
+impl From<zmq::Error> for ProxyError {
+    fn from(e: zmq::Error) -> ProxyError {
+        ProxyError::Zmq(e)
+    }
+}
+
+/// Messages, and bytes, that went through one socket of a proxy.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub messages_in: u64,
+    pub bytes_in: u64,
+    pub messages_out: u64,
+    pub bytes_out: u64,
+}
+
+/// Statistics of a proxy, since it started.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ProxyStatistics {
+    pub frontend: Traffic,
+    pub backend: Traffic,
+}
+
+impl ProxyStatistics {
+    // Parse the reply to `STATISTICS`, eight frames of native-endian `u64`.
+    fn from_frames(frames: &[Vec<u8>]) -> Option<ProxyStatistics> {
+        if frames.len() != 8 || frames.iter().any(|frame| frame.len() != 8) {
+            return None;
+        }
+        let values: Vec<u64> = frames
+            .iter()
+            .map(|frame| {
+                let mut bytes = [0u8; 8];
+                bytes.copy_from_slice(frame);
+                u64::from_ne_bytes(bytes)
+            })
+            .collect();
+        let traffic = |v: &[u64]| Traffic {
+            messages_in: v[0],
+            bytes_in: v[1],
+            messages_out: v[2],
+            bytes_out: v[3],
+        };
+        Some(ProxyStatistics {
+            frontend: traffic(&values[..4]),
+            backend: traffic(&values[4..]),
+        })
+    }
+}
+
+/// A proxy between two sockets, running on a thread of its own.
+pub struct Proxy {
+    name: String,
+    control: zmq::Socket,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Proxy {
+    /// Start a proxy named `name`, from `frontend` to `backend`, copying messages to
+    /// `capture`, if any. The sockets must come from `context`.
+    pub fn start(
+        name: &str,
+        context: &zmq::Context,
+        mut frontend: zmq::Socket,
+        mut backend: zmq::Socket,
+        capture: Option<zmq::Socket>,
+    ) -> Result<Proxy, ProxyError> {
+        let endpoint = format!("inproc://neuras.proxy.{}", Uuid::new_v4().to_simple());
+        let mut steering = context.socket(zmq::PAIR)?;
+        steering.bind(&endpoint)?;
+        let control = context.socket(zmq::PAIR)?;
+        control.set_linger(0)?;
+        control.connect(&endpoint)?;
+        let proxy_name = name.to_string();
+        let thread = run_named_thread(&format!("proxy-{}", name), move || {
+            let result = match capture {
+                Some(mut capture) => zmq::proxy_steerable_with_capture(
+                    &mut frontend,
+                    &mut backend,
+                    &mut capture,
+                    &mut steering,
+                ),
+                None => zmq::proxy_steerable(&mut frontend, &mut backend, &mut steering),
+            };
+            match result {
+                Ok(()) => nlog!(debug, "proxy terminated name={}", proxy_name),
+                Err(e) => nlog!(debug, "proxy ended name={} error={}", proxy_name, e),
+            }
+        })?;
+        Ok(Proxy {
+            name: name.to_string(),
+            control,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the name of the proxy.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns true while the proxy is running, even if it is paused.
+    pub fn is_running(&self) -> bool {
+        self.thread.is_some()
+    }
+
+    /// Pause the proxy; messages are queued in its sockets until it is resumed.
+    pub fn pause(&self) -> Result<(), ProxyError> {
+        self.command(PAUSE)
+    }
+
+    /// Resume the proxy, after a pause.
+    pub fn resume(&self) -> Result<(), ProxyError> {
+        self.command(RESUME)
+    }
+
+    /// Returns the statistics of the proxy.
+    pub fn statistics(&self) -> Result<ProxyStatistics, ProxyError> {
+        self.command(STATISTICS)?;
+        if self.control.poll(zmq::POLLIN, STATISTICS_TIMEOUT)? == 0 {
+            return Err(ProxyError::NotRunning);
+        }
+        let reply = self.control.recv_multipart(0)?;
+        ProxyStatistics::from_frames(&reply).ok_or(ProxyError::NotRunning)
+    }
+
+    /// Terminate the proxy, and wait for its thread to end.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            // the proxy may have ended on its own, e.g. when its context was terminated.
+            let _ = self.control.send(TERMINATE, zmq::DONTWAIT);
+            let _ = thread.join();
+        }
+    }
+
+    fn command(&self, command: &str) -> Result<(), ProxyError> {
+        if !self.is_running() {
+            return Err(ProxyError::NotRunning);
+        }
+        match self.control.send(command, zmq::DONTWAIT) {
+            Err(zmq::Error::EAGAIN) => Err(ProxyError::NotRunning),
+            other => Ok(other?),
+        }
+    }
+}
+
+impl Drop for Proxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,446 @@
+//! A proxy that delays, drops, duplicates, and reorders, messages, to test how peers cope
+//! with unreliable networks.
+//!
+//! `ChaosProxy` forwards messages both ways between its frontend, and its backend, like a
+//! `Proxy`, but every message goes through the faults of a `ChaosConfig` first. Faults are
+//! drawn from a seeded generator, so that a failing test can be replayed with the same
+//! faults.
+use super::super::utils::run_named_thread;
+use super::{ProxyError, TERMINATE};
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use zmq;
+
+/// Distribution of the delays of messages, in milliseconds.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delay {
+    /// Messages are forwarded as soon as they arrive.
+    None,
+    /// Every message is delayed the same.
+    Fixed(u64),
+    /// Delays are uniformly distributed between `min`, and `max`.
+    Uniform { min: u64, max: u64 },
+    /// Delays are exponentially distributed, with a `mean`.
+    Exponential { mean: u64 },
+}
+
+impl Delay {
+    fn sample(&self, rng: &mut Rng) -> u64 {
+        match *self {
+            Delay::None => 0,
+            Delay::Fixed(ms) => ms,
+            Delay::Uniform { min, max } if max > min => min + rng.below(max - min + 1),
+            Delay::Uniform { min, .. } => min,
+            Delay::Exponential { mean } => (-(mean as f64) * (1.0 - rng.unit()).ln()) as u64,
+        }
+    }
+}
+
+/// Faults of a `ChaosProxy`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChaosConfig {
+    /// Delay of every message.
+    pub delay: Delay,
+    /// Probability, from 0 to 1, that a message is dropped.
+    pub drop: f64,
+    /// Probability that a message is sent twice.
+    pub duplicate: f64,
+    /// Probability that a message is held back, for `reorder_delay` milliseconds, so that
+    /// the messages after it overtake it.
+    pub reorder: f64,
+    pub reorder_delay: u64,
+    /// Seed of the faults; the same seed, and messages, give the same faults.
+    pub seed: u64,
+}
+
+impl Default for ChaosConfig {
+    fn default() -> ChaosConfig {
+        ChaosConfig {
+            delay: Delay::None,
+            drop: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: 10,
+            seed: 1,
+        }
+    }
+}
+
+impl ChaosConfig {
+    /// Create a configuration without faults.
+    pub fn new() -> ChaosConfig {
+        ChaosConfig::default()
+    }
+
+    /// Delay messages by `delay`.
+    pub fn delay(mut self, delay: Delay) -> ChaosConfig {
+        self.delay = delay;
+        self
+    }
+
+    /// Drop messages with a `probability`.
+    pub fn drop(mut self, probability: f64) -> ChaosConfig {
+        self.drop = probability;
+        self
+    }
+
+    /// Duplicate messages with a `probability`.
+    pub fn duplicate(mut self, probability: f64) -> ChaosConfig {
+        self.duplicate = probability;
+        self
+    }
+
+    /// Hold back messages, for `delay` milliseconds, with a `probability`.
+    pub fn reorder(mut self, probability: f64, delay: u64) -> ChaosConfig {
+        self.reorder = probability;
+        self.reorder_delay = delay;
+        self
+    }
+
+    /// Draw the faults from `seed`.
+    pub fn seed(mut self, seed: u64) -> ChaosConfig {
+        self.seed = seed;
+        self
+    }
+}
+
+/// Messages that a `ChaosProxy` forwarded, and the faults it injected.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChaosStatistics {
+    pub received: u64,
+    pub forwarded: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    forwarded: AtomicU64,
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    reordered: AtomicU64,
+}
+
+// xorshift64*, which is good enough for faults, and needs no dependencies.
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Rng {
+        Rng(if seed == 0 {
+            0x9e37_79b9_7f4a_7c15
+        } else {
+            seed
+        })
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // Uniform in `[0, 1)`.
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    fn below(&mut self, bound: u64) -> u64 {
+        self.next() % bound
+    }
+
+    fn chance(&mut self, probability: f64) -> bool {
+        probability > 0.0 && self.unit() < probability
+    }
+}
+
+// Messages waiting to be sent: when, in which order they arrived, and whether they go to
+// the backend.
+type Scheduled = Reverse<(Instant, u64, bool, Vec<Vec<u8>>)>;
+
+/// A proxy that injects faults into the messages it forwards, on a thread of its own.
+pub struct ChaosProxy {
+    name: String,
+    control: zmq::Socket,
+    counters: Arc<Counters>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl ChaosProxy {
+    /// Start a proxy named `name`, between `frontend`, and `backend`, which come from
+    /// `context`, injecting the faults of `config`.
+    pub fn start(
+        name: &str,
+        context: &zmq::Context,
+        frontend: zmq::Socket,
+        backend: zmq::Socket,
+        config: ChaosConfig,
+    ) -> Result<ChaosProxy, ProxyError> {
+        let endpoint = format!("inproc://neuras.chaos.{}", Uuid::new_v4().to_simple());
+        let steering = context.socket(zmq::PAIR)?;
+        steering.bind(&endpoint)?;
+        let control = context.socket(zmq::PAIR)?;
+        control.set_linger(0)?;
+        control.connect(&endpoint)?;
+        let counters = Arc::new(Counters::default());
+        let shared = Arc::clone(&counters);
+        let proxy_name = name.to_string();
+        let thread = run_named_thread(&format!("chaos-{}", name), move || {
+            let mut chaos = Chaos {
+                config,
+                rng: Rng::new(config.seed),
+                counters: shared,
+                queue: BinaryHeap::new(),
+                sequence: 0,
+            };
+            if let Err(e) = chaos.run(&frontend, &backend, &steering) {
+                nlog!(debug, "chaos proxy ended name={} error={}", proxy_name, e);
+            }
+        })?;
+        Ok(ChaosProxy {
+            name: name.to_string(),
+            control,
+            counters,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the name of the proxy.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the messages forwarded, and the faults injected, so far.
+    pub fn statistics(&self) -> ChaosStatistics {
+        let counters = &self.counters;
+        ChaosStatistics {
+            received: counters.received.load(Ordering::SeqCst),
+            forwarded: counters.forwarded.load(Ordering::SeqCst),
+            dropped: counters.dropped.load(Ordering::SeqCst),
+            duplicated: counters.duplicated.load(Ordering::SeqCst),
+            reordered: counters.reordered.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Terminate the proxy, discarding the messages it holds, and wait for its thread.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.control.send(TERMINATE, zmq::DONTWAIT);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for ChaosProxy {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// State of the thread of a `ChaosProxy`.
+struct Chaos {
+    config: ChaosConfig,
+    rng: Rng,
+    counters: Arc<Counters>,
+    queue: BinaryHeap<Scheduled>,
+    sequence: u64,
+}
+
+impl Chaos {
+    fn run(
+        &mut self,
+        frontend: &zmq::Socket,
+        backend: &zmq::Socket,
+        control: &zmq::Socket,
+    ) -> Result<(), zmq::Error> {
+        loop {
+            let timeout = match self.queue.peek() {
+                Some(&Reverse((due, ..))) => {
+                    let wait = due.saturating_duration_since(Instant::now());
+                    // round up, so that messages are not polled for before they are due.
+                    (wait.as_millis() as i64) + i64::from(wait.subsec_nanos() % 1_000_000 > 0)
+                }
+                None => -1,
+            };
+            let (from_frontend, from_backend, commanded) = {
+                let mut items = [
+                    frontend.as_poll_item(zmq::POLLIN),
+                    backend.as_poll_item(zmq::POLLIN),
+                    control.as_poll_item(zmq::POLLIN),
+                ];
+                zmq::poll(&mut items, timeout)?;
+                (
+                    items[0].is_readable(),
+                    items[1].is_readable(),
+                    items[2].is_readable(),
+                )
+            };
+            if commanded && control.recv_bytes(0)? == TERMINATE.as_bytes() {
+                return Ok(());
+            }
+            if from_frontend {
+                self.receive(frontend, true)?;
+            }
+            if from_backend {
+                self.receive(backend, false)?;
+            }
+            self.release(frontend, backend)?;
+        }
+    }
+
+    // Read every message waiting on `socket`, and schedule them, unless they are dropped.
+    fn receive(&mut self, socket: &zmq::Socket, to_backend: bool) -> Result<(), zmq::Error> {
+        loop {
+            let msg = match socket.recv_multipart(zmq::DONTWAIT) {
+                Ok(msg) => msg,
+                Err(zmq::Error::EAGAIN) => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            self.counters.received.fetch_add(1, Ordering::SeqCst);
+            if self.rng.chance(self.config.drop) {
+                self.counters.dropped.fetch_add(1, Ordering::SeqCst);
+                continue;
+            }
+            if self.rng.chance(self.config.duplicate) {
+                self.counters.duplicated.fetch_add(1, Ordering::SeqCst);
+                self.schedule(msg.clone(), to_backend);
+            }
+            self.schedule(msg, to_backend);
+        }
+    }
+
+    fn schedule(&mut self, msg: Vec<Vec<u8>>, to_backend: bool) {
+        let mut delay = self.config.delay.sample(&mut self.rng);
+        if self.rng.chance(self.config.reorder) {
+            self.counters.reordered.fetch_add(1, Ordering::SeqCst);
+            delay += self.config.reorder_delay;
+        }
+        let due = Instant::now() + Duration::from_millis(delay);
+        self.sequence += 1;
+        self.queue
+            .push(Reverse((due, self.sequence, to_backend, msg)));
+    }
+
+    // Send the messages that are due. Messages that a socket cannot take without blocking,
+    // e.g. it has no peers, or they are at their high-water mark, are dropped, as they would
+    // be on a congested network, so that the proxy keeps answering its control socket.
+    fn release(&mut self, frontend: &zmq::Socket, backend: &zmq::Socket) -> Result<(), zmq::Error> {
+        let now = Instant::now();
+        while self
+            .queue
+            .peek()
+            .is_some_and(|&Reverse((due, ..))| due <= now)
+        {
+            let Reverse((_, _, to_backend, msg)) = self.queue.pop().expect("peeked message");
+            let socket = if to_backend { backend } else { frontend };
+            match socket.send_multipart(msg, zmq::DONTWAIT) {
+                Ok(()) => self.counters.forwarded.fetch_add(1, Ordering::SeqCst),
+                Err(zmq::Error::EAGAIN) => self.counters.dropped.fetch_add(1, Ordering::SeqCst),
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testkit::wait_until;
+
+    // A PUSH socket, connected through a chaos proxy, to a PULL socket.
+    fn pipeline(
+        ctx: &zmq::Context,
+        name: &str,
+        config: ChaosConfig,
+    ) -> (zmq::Socket, ChaosProxy, zmq::Socket) {
+        let frontend = ctx.socket(zmq::PULL).unwrap();
+        frontend.bind(&format!("inproc://{}.in", name)).unwrap();
+        let backend = ctx.socket(zmq::PUSH).unwrap();
+        backend.bind(&format!("inproc://{}.out", name)).unwrap();
+        let proxy = ChaosProxy::start(name, ctx, frontend, backend, config).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&format!("inproc://{}.in", name)).unwrap();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.connect(&format!("inproc://{}.out", name)).unwrap();
+        (push, proxy, pull)
+    }
+
+    fn drain(socket: &zmq::Socket, timeout: i64) -> Vec<String> {
+        let mut received = Vec::new();
+        while socket.poll(zmq::POLLIN, timeout).unwrap() > 0 {
+            received.push(socket.recv_string(0).unwrap().unwrap());
+        }
+        received
+    }
+
+    #[test]
+    fn faults_are_injected_and_counted() {
+        let ctx = zmq::Context::new();
+        let config = ChaosConfig::new().drop(1.0);
+        let (push, proxy, pull) = pipeline(&ctx, "chaos.drop", config);
+        push.send("lost", 0).unwrap();
+        assert!(drain(&pull, 50).is_empty());
+        assert_eq!(proxy.statistics().dropped, 1);
+
+        let config = ChaosConfig::new().duplicate(1.0).delay(Delay::Fixed(30));
+        let (push, proxy, pull) = pipeline(&ctx, "chaos.twice", config);
+        let start = Instant::now();
+        push.send("echo", 0).unwrap();
+        assert_eq!(drain(&pull, 200), vec!["echo", "echo"]);
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        let statistics = proxy.statistics();
+        assert_eq!((statistics.forwarded, statistics.duplicated), (2, 1));
+
+        let config = ChaosConfig::new().reorder(1.0, 50);
+        let (push, proxy, pull) = pipeline(&ctx, "chaos.late", config);
+        push.send("first", 0).unwrap();
+        assert!(drain(&pull, 10).is_empty());
+        assert_eq!(drain(&pull, 200), vec!["first"]);
+        assert_eq!(proxy.statistics().reordered, 1);
+    }
+
+    #[test]
+    fn messages_that_cannot_be_sent_are_dropped() {
+        let ctx = zmq::Context::new();
+        let frontend = ctx.socket(zmq::PULL).unwrap();
+        frontend.bind("inproc://chaos.stuck.in").unwrap();
+        // the backend has no peers, so it cannot take the message.
+        let backend = ctx.socket(zmq::PUSH).unwrap();
+        backend.bind("inproc://chaos.stuck.out").unwrap();
+        let config = ChaosConfig::new();
+        let mut proxy = ChaosProxy::start("chaos.stuck", &ctx, frontend, backend, config).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect("inproc://chaos.stuck.in").unwrap();
+        push.send("stuck", 0).unwrap();
+        assert!(wait_until(1_000, || proxy.statistics().dropped == 1));
+        assert_eq!(proxy.statistics().forwarded, 0);
+        proxy.stop();
+    }
+
+    #[test]
+    fn seeded_delays_are_repeatable() {
+        let delay = Delay::Uniform { min: 5, max: 10 };
+        let samples = |seed| {
+            let mut rng = Rng::new(seed);
+            (0..32).map(|_| delay.sample(&mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(samples(7), samples(7));
+        assert_ne!(samples(7), samples(8));
+        assert!(samples(7).iter().all(|ms| (5..=10).contains(ms)));
+        let mut rng = Rng::new(3);
+        let mean = (0..1_000)
+            .map(|_| Delay::Exponential { mean: 20 }.sample(&mut rng))
+            .sum::<u64>()
+            / 1_000;
+        assert!(mean > 10 && mean < 30);
+    }
+}
@@ -0,0 +1,444 @@
+//! A fair queue of requests, from many clients to many workers, with priorities.
+//!
+//! `FairQueue` receives requests on a `ROUTER` frontend, and queues them by the identity of
+//! the client. Workers connect to its `ROUTER` backend, with `REQ` sockets, and announce
+//! themselves with `WORKER_READY`; every idle worker is given the next request, and its
+//! reply goes back to the client, as in the load-balancing broker of the guide.
+//!
+//! The next request is chosen with stride scheduling: every client has a priority, and
+//! clients with twice the priority are served twice as often, while every client with
+//! requests is served in turn, so that none of them starves. Clients that were idle
+//! resume at the pace of the others, instead of catching up on the time they were idle.
+//!
+//! Workers receive the identity of the client, an empty frame, and the request, and send
+//! the reply with the same envelope.
+use super::super::utils::run_named_thread;
+use super::{ProxyError, TERMINATE};
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// Message that workers send to the backend when they are ready for their first request.
+pub const WORKER_READY: &str = "$READY";
+/// Priority of the clients without one.
+pub const DEFAULT_PRIORITY: u32 = 1;
+
+// Pass added for a request of a client of priority 1; higher priorities add less.
+const STRIDE: u64 = 1 << 20;
+
+/// Hook that is called with the identity of a client, and the number of its queued
+/// requests, every time that it changes.
+pub type DepthHook = Box<dyn Fn(&[u8], usize) + Send>;
+
+/// Priorities, and limits, of a `FairQueue`.
+pub struct FairQueueConfig {
+    default_priority: u32,
+    priorities: HashMap<Vec<u8>, u32>,
+    max_depth: Option<usize>,
+    on_depth: Option<DepthHook>,
+}
+
+impl Default for FairQueueConfig {
+    fn default() -> FairQueueConfig {
+        FairQueueConfig {
+            default_priority: DEFAULT_PRIORITY,
+            priorities: HashMap::new(),
+            max_depth: None,
+            on_depth: None,
+        }
+    }
+}
+
+impl FairQueueConfig {
+    /// Create a configuration where every client has the `DEFAULT_PRIORITY`, and queues
+    /// have no limit.
+    pub fn new() -> FairQueueConfig {
+        FairQueueConfig::default()
+    }
+
+    /// Give `priority` to the clients without one.
+    pub fn default_priority(mut self, priority: u32) -> FairQueueConfig {
+        self.default_priority = priority;
+        self
+    }
+
+    /// Give `priority` to the client with `identity`.
+    pub fn priority(mut self, identity: &[u8], priority: u32) -> FairQueueConfig {
+        self.priorities.insert(identity.to_vec(), priority);
+        self
+    }
+
+    /// Drop the requests of clients that already have `max_depth` queued requests.
+    pub fn max_depth(mut self, max_depth: usize) -> FairQueueConfig {
+        self.max_depth = Some(max_depth);
+        self
+    }
+
+    /// Call `hook` on the thread of the queue, every time that the depth of the queue of a
+    /// client changes.
+    pub fn on_depth<F>(mut self, hook: F) -> FairQueueConfig
+    where
+        F: Fn(&[u8], usize) + Send + 'static,
+    {
+        self.on_depth = Some(Box::new(hook));
+        self
+    }
+}
+
+/// Requests that a `FairQueue` queued, dispatched, and rejected, and the replies that it
+/// forwarded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FairQueueStatistics {
+    pub queued: u64,
+    pub dispatched: u64,
+    pub rejected: u64,
+    pub replied: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    queued: AtomicU64,
+    dispatched: AtomicU64,
+    rejected: AtomicU64,
+    replied: AtomicU64,
+}
+
+// State shared with the handle of the queue.
+#[derive(Default)]
+struct Shared {
+    priorities: Mutex<HashMap<Vec<u8>, u32>>,
+    depths: Mutex<HashMap<Vec<u8>, usize>>,
+    counters: Counters,
+}
+
+/// A broker that fair-queues requests to workers, by client priority, on a thread of its
+/// own.
+pub struct FairQueue {
+    name: String,
+    control: zmq::Socket,
+    shared: Arc<Shared>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl FairQueue {
+    /// Start a queue named `name`, from a `ROUTER` `frontend`, for the clients, to a
+    /// `ROUTER` `backend`, for the workers. Both come from `context`.
+    pub fn start(
+        name: &str,
+        context: &zmq::Context,
+        frontend: zmq::Socket,
+        backend: zmq::Socket,
+        config: FairQueueConfig,
+    ) -> Result<FairQueue, ProxyError> {
+        for socket in &[&frontend, &backend] {
+            match socket.get_socket_type()? {
+                zmq::ROUTER => {}
+                other => return Err(ProxyError::UnexpectedSocketType(other)),
+            }
+        }
+        let endpoint = format!("inproc://neuras.fair.{}", Uuid::new_v4().to_simple());
+        let steering = context.socket(zmq::PAIR)?;
+        steering.bind(&endpoint)?;
+        let control = context.socket(zmq::PAIR)?;
+        control.set_linger(0)?;
+        control.connect(&endpoint)?;
+        let shared = Arc::new(Shared::default());
+        *shared.priorities.lock().unwrap() = config.priorities.clone();
+        let queue_shared = Arc::clone(&shared);
+        let queue_name = name.to_string();
+        let thread = run_named_thread(&format!("fair-{}", name), move || {
+            let mut scheduler = Scheduler::new(config, queue_shared);
+            if let Err(e) = run_queue(&frontend, &backend, &steering, &mut scheduler) {
+                nlog!(debug, "fair queue ended name={} error={}", queue_name, e);
+            }
+        })?;
+        Ok(FairQueue {
+            name: name.to_string(),
+            control,
+            shared,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the name of the queue.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Give `priority` to the client with `identity`, from its next request on.
+    pub fn set_priority(&self, identity: &[u8], priority: u32) {
+        let mut priorities = self.shared.priorities.lock().unwrap();
+        priorities.insert(identity.to_vec(), priority);
+    }
+
+    /// Returns the number of queued requests of every client that has some.
+    pub fn depths(&self) -> HashMap<Vec<u8>, usize> {
+        self.shared.depths.lock().unwrap().clone()
+    }
+
+    /// Returns the requests queued, dispatched, and rejected, and the replies forwarded,
+    /// so far.
+    pub fn statistics(&self) -> FairQueueStatistics {
+        let counters = &self.shared.counters;
+        FairQueueStatistics {
+            queued: counters.queued.load(Ordering::SeqCst),
+            dispatched: counters.dispatched.load(Ordering::SeqCst),
+            rejected: counters.rejected.load(Ordering::SeqCst),
+            replied: counters.replied.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Terminate the queue, and wait for its thread to end.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.control.send(TERMINATE, zmq::DONTWAIT);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for FairQueue {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+// Requests of a client, and its pass, the virtual time of its next request.
+struct ClientQueue {
+    requests: VecDeque<Vec<Vec<u8>>>,
+    pass: u64,
+}
+
+struct Scheduler {
+    config: FairQueueConfig,
+    shared: Arc<Shared>,
+    // clients with queued requests, by identity, so that ties are broken the same way.
+    clients: BTreeMap<Vec<u8>, ClientQueue>,
+    // pass of the last request that was dispatched.
+    now: u64,
+}
+
+impl Scheduler {
+    fn new(config: FairQueueConfig, shared: Arc<Shared>) -> Scheduler {
+        Scheduler {
+            config,
+            shared,
+            clients: BTreeMap::new(),
+            now: 0,
+        }
+    }
+
+    fn depth_changed(&self, identity: &[u8], depth: usize) {
+        {
+            let mut depths = self.shared.depths.lock().unwrap();
+            if depth == 0 {
+                depths.remove(identity);
+            } else {
+                depths.insert(identity.to_vec(), depth);
+            }
+        }
+        if let Some(ref hook) = self.config.on_depth {
+            hook(identity, depth);
+        }
+    }
+
+    // Queue the `request` of the client with `identity`, unless its queue is full.
+    fn push(&mut self, identity: Vec<u8>, request: Vec<Vec<u8>>) {
+        let counters = &self.shared.counters;
+        let queued = self.clients.get(&identity).map_or(0, |c| c.requests.len());
+        if let Some(max) = self.config.max_depth {
+            if queued >= max {
+                counters.rejected.fetch_add(1, Ordering::SeqCst);
+                return;
+            }
+        }
+        let now = self.now;
+        let client = self.clients.entry(identity.clone()).or_insert(ClientQueue {
+            requests: VecDeque::new(),
+            pass: now,
+        });
+        client.requests.push_back(request);
+        counters.queued.fetch_add(1, Ordering::SeqCst);
+        self.depth_changed(&identity, queued + 1);
+    }
+
+    fn priority(&self, identity: &[u8]) -> u32 {
+        let priorities = self.shared.priorities.lock().unwrap();
+        priorities
+            .get(identity)
+            .cloned()
+            .unwrap_or(self.config.default_priority)
+            .max(1)
+    }
+
+    // Take the next request, from the client with the lowest pass.
+    fn pop(&mut self) -> Option<(Vec<u8>, Vec<Vec<u8>>)> {
+        let identity = self
+            .clients
+            .iter()
+            .min_by_key(|&(_, client)| client.pass)
+            .map(|(identity, _)| identity.clone())?;
+        let stride = STRIDE / u64::from(self.priority(&identity));
+        let (request, depth) = {
+            let client = self.clients.get_mut(&identity)?;
+            self.now = client.pass;
+            client.pass += stride;
+            (client.requests.pop_front()?, client.requests.len())
+        };
+        if depth == 0 {
+            self.clients.remove(&identity);
+        }
+        self.depth_changed(&identity, depth);
+        Some((identity, request))
+    }
+}
+
+fn run_queue(
+    frontend: &zmq::Socket,
+    backend: &zmq::Socket,
+    control: &zmq::Socket,
+    scheduler: &mut Scheduler,
+) -> Result<(), zmq::Error> {
+    let mut workers: VecDeque<Vec<u8>> = VecDeque::new();
+    loop {
+        let (requested, replied, commanded) = {
+            let mut items = [
+                frontend.as_poll_item(zmq::POLLIN),
+                backend.as_poll_item(zmq::POLLIN),
+                control.as_poll_item(zmq::POLLIN),
+            ];
+            zmq::poll(&mut items, -1)?;
+            (
+                items[0].is_readable(),
+                items[1].is_readable(),
+                items[2].is_readable(),
+            )
+        };
+        if commanded && control.recv_bytes(0)? == TERMINATE.as_bytes() {
+            return Ok(());
+        }
+        if replied {
+            let mut msg = backend.recv_multipart(0)?;
+            // workers send their identity, an empty frame, and either `WORKER_READY`, or the
+            // envelope of the client, followed by the reply.
+            if msg.len() >= 3 {
+                workers.push_back(msg.remove(0));
+                msg.remove(0);
+                if msg.len() > 1 || msg[0] != WORKER_READY.as_bytes() {
+                    frontend.send_multipart(msg, 0)?;
+                    let counters = &scheduler.shared.counters;
+                    counters.replied.fetch_add(1, Ordering::SeqCst);
+                }
+            }
+        }
+        if requested {
+            let mut msg = frontend.recv_multipart(0)?;
+            let identity = msg.remove(0);
+            scheduler.push(identity, msg);
+        }
+        while !workers.is_empty() {
+            let (identity, request) = match scheduler.pop() {
+                Some(next) => next,
+                None => break,
+            };
+            let worker = workers.pop_front().unwrap();
+            let mut frames = vec![worker, Vec::new(), identity];
+            frames.extend(request);
+            backend.send_multipart(frames, 0)?;
+            let counters = &scheduler.shared.counters;
+            counters.dispatched.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn clients_are_served_by_priority_without_starving() {
+        let ctx = zmq::Context::new();
+        let frontend = ctx.socket(zmq::ROUTER).unwrap();
+        frontend.bind("inproc://fair.front").unwrap();
+        let backend = ctx.socket(zmq::ROUTER).unwrap();
+        backend.bind("inproc://fair.back").unwrap();
+        let (depth_tx, depth_rx) = mpsc::channel();
+        let config =
+            FairQueueConfig::new()
+                .priority(b"high", 3)
+                .on_depth(move |identity, depth| {
+                    let _ = depth_tx.send((identity.to_vec(), depth));
+                });
+        let mut queue = FairQueue::start("fair", &ctx, frontend, backend, config).unwrap();
+
+        let mut clients = Vec::new();
+        for name in &["high", "low"] {
+            let client = ctx.socket(zmq::DEALER).unwrap();
+            client.set_identity(name.as_bytes()).unwrap();
+            client.connect("inproc://fair.front").unwrap();
+            for i in 0..8 {
+                client.send_multipart(["", &i.to_string()], 0).unwrap();
+            }
+            clients.push(client);
+        }
+        // every request is queued before the first worker is ready.
+        while queue.depths().values().sum::<usize>() < 16 {
+            depth_rx.recv().unwrap();
+        }
+
+        let worker = ctx.socket(zmq::REQ).unwrap();
+        worker.connect("inproc://fair.back").unwrap();
+        worker.send(WORKER_READY, 0).unwrap();
+        let mut served = Vec::new();
+        for _ in 0..16 {
+            let request = worker.recv_multipart(0).unwrap();
+            served.push(request[0].clone());
+            worker
+                .send_multipart(vec![request[0].clone(), Vec::new(), request[2].clone()], 0)
+                .unwrap();
+        }
+        let high = served[..8].iter().filter(|c| c == &b"high").count();
+        assert_eq!(high, 6);
+        assert_eq!(served[1], b"low");
+        for client in &clients {
+            for _ in 0..8 {
+                assert_eq!(client.recv_multipart(0).unwrap().len(), 2);
+            }
+        }
+
+        assert!(queue.depths().is_empty());
+        let statistics = queue.statistics();
+        assert_eq!(statistics.queued, 16);
+        assert_eq!(statistics.dispatched, 16);
+        assert_eq!(statistics.replied, 16);
+        queue.stop();
+    }
+
+    #[test]
+    fn full_client_queues_reject_requests() {
+        let ctx = zmq::Context::new();
+        let shared = Arc::new(Shared::default());
+        let mut scheduler = Scheduler::new(FairQueueConfig::new().max_depth(2), shared);
+        for i in 0..3 {
+            scheduler.push(b"eager".to_vec(), vec![vec![i]]);
+        }
+        scheduler.push(b"patient".to_vec(), vec![vec![9]]);
+        assert_eq!(scheduler.shared.counters.rejected.load(Ordering::SeqCst), 1);
+        let order: Vec<u8> = (0..3).map(|_| scheduler.pop().unwrap().1[0][0]).collect();
+        assert_eq!(order, vec![0, 9, 1]);
+        assert!(scheduler.pop().is_none());
+
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        let router = ctx.socket(zmq::ROUTER).unwrap();
+        match FairQueue::start("wrong", &ctx, router, pull, FairQueueConfig::new()) {
+            Err(ProxyError::UnexpectedSocketType(zmq::PULL)) => {}
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("PULL sockets have no identities"),
+        }
+    }
+}
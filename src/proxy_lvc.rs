@@ -0,0 +1,224 @@
+//! A last value cache between publishers, and subscribers.
+//!
+//! `LastValueCache` subscribes to every topic upstream, forwards messages downstream, and
+//! keeps the last message of each topic, i.e. of each first frame. Its `XPUB` backend is
+//! verbose, so that every subscription reaches the cache, even for topics that other
+//! subscribers already have, and the cached messages of the topics that match it are sent
+//! right away, instead of the subscriber waiting for the next update.
+use super::super::utils::run_named_thread;
+use super::{ProxyError, TERMINATE};
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// Topics cached, and messages forwarded, or replayed, by a `LastValueCache`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LvcStatistics {
+    pub topics: u64,
+    pub forwarded: u64,
+    pub replayed: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    topics: AtomicU64,
+    forwarded: AtomicU64,
+    replayed: AtomicU64,
+}
+
+/// A proxy that replays the last message of each topic to new subscribers, on a thread
+/// of its own.
+pub struct LastValueCache {
+    name: String,
+    control: zmq::Socket,
+    counters: Arc<Counters>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl LastValueCache {
+    /// Start a cache named `name`, from a `SUB`, or `XSUB`, `frontend`, connected to the
+    /// publishers, to an `XPUB` `backend`, for the subscribers. Both come from `context`.
+    pub fn start(
+        name: &str,
+        context: &zmq::Context,
+        frontend: zmq::Socket,
+        backend: zmq::Socket,
+    ) -> Result<LastValueCache, ProxyError> {
+        match frontend.get_socket_type()? {
+            zmq::SUB => frontend.set_subscribe(b"")?,
+            zmq::XSUB => frontend.send(&[1u8][..], 0)?,
+            other => return Err(ProxyError::UnexpectedSocketType(other)),
+        }
+        match backend.get_socket_type()? {
+            zmq::XPUB => backend.set_xpub_verbose(true)?,
+            other => return Err(ProxyError::UnexpectedSocketType(other)),
+        }
+        let endpoint = format!("inproc://neuras.lvc.{}", Uuid::new_v4().to_simple());
+        let steering = context.socket(zmq::PAIR)?;
+        steering.bind(&endpoint)?;
+        let control = context.socket(zmq::PAIR)?;
+        control.set_linger(0)?;
+        control.connect(&endpoint)?;
+        let counters = Arc::new(Counters::default());
+        let shared = Arc::clone(&counters);
+        let cache_name = name.to_string();
+        let thread = run_named_thread(&format!("lvc-{}", name), move || {
+            if let Err(e) = run_cache(&frontend, &backend, &steering, &shared) {
+                nlog!(
+                    debug,
+                    "last value cache ended name={} error={}",
+                    cache_name,
+                    e
+                );
+            }
+        })?;
+        Ok(LastValueCache {
+            name: name.to_string(),
+            control,
+            counters,
+            thread: Some(thread),
+        })
+    }
+
+    /// Returns the name of the cache.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the topics cached, and the messages forwarded, and replayed, so far.
+    pub fn statistics(&self) -> LvcStatistics {
+        LvcStatistics {
+            topics: self.counters.topics.load(Ordering::SeqCst),
+            forwarded: self.counters.forwarded.load(Ordering::SeqCst),
+            replayed: self.counters.replayed.load(Ordering::SeqCst),
+        }
+    }
+
+    /// Terminate the cache, and wait for its thread to end.
+    pub fn stop(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = self.control.send(TERMINATE, zmq::DONTWAIT);
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for LastValueCache {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+fn run_cache(
+    frontend: &zmq::Socket,
+    backend: &zmq::Socket,
+    control: &zmq::Socket,
+    counters: &Counters,
+) -> Result<(), zmq::Error> {
+    let mut cache: BTreeMap<Vec<u8>, Vec<Vec<u8>>> = BTreeMap::new();
+    loop {
+        let (published, subscribed, commanded) = {
+            let mut items = [
+                frontend.as_poll_item(zmq::POLLIN),
+                backend.as_poll_item(zmq::POLLIN),
+                control.as_poll_item(zmq::POLLIN),
+            ];
+            zmq::poll(&mut items, -1)?;
+            (
+                items[0].is_readable(),
+                items[1].is_readable(),
+                items[2].is_readable(),
+            )
+        };
+        if commanded && control.recv_bytes(0)? == TERMINATE.as_bytes() {
+            return Ok(());
+        }
+        if published {
+            let msg = frontend.recv_multipart(0)?;
+            backend.send_multipart(&msg, 0)?;
+            counters.forwarded.fetch_add(1, Ordering::SeqCst);
+            let topic = msg.first().cloned().unwrap_or_default();
+            cache.insert(topic, msg);
+            counters.topics.store(cache.len() as u64, Ordering::SeqCst);
+        }
+        if subscribed {
+            let event = backend.recv_bytes(0)?;
+            // only subscriptions are answered, and they start with 1.
+            if event.first() != Some(&1) {
+                continue;
+            }
+            let prefix = &event[1..];
+            for (_, msg) in cache.iter().filter(|(topic, _)| topic.starts_with(prefix)) {
+                backend.send_multipart(msg, 0)?;
+                counters.replayed.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn late_subscribers_receive_the_last_value() {
+        let ctx = zmq::Context::new();
+        let publisher = ctx.socket(zmq::PUB).unwrap();
+        publisher.bind("inproc://lvc.upstream").unwrap();
+        let frontend = ctx.socket(zmq::XSUB).unwrap();
+        frontend.connect("inproc://lvc.upstream").unwrap();
+        let backend = ctx.socket(zmq::XPUB).unwrap();
+        backend.bind("inproc://lvc.downstream").unwrap();
+        let mut lvc = LastValueCache::start("weather", &ctx, frontend, backend).unwrap();
+
+        let early = ctx.socket(zmq::SUB).unwrap();
+        early.connect("inproc://lvc.downstream").unwrap();
+        early.set_subscribe(b"temperature").unwrap();
+        // the first subscriber waits for the upstream subscription to reach the publisher.
+        let mut value = 0;
+        loop {
+            value += 1;
+            let update = value.to_string();
+            publisher
+                .send_multipart(["temperature", update.as_str()], 0)
+                .unwrap();
+            if early.poll(zmq::POLLIN, 10).unwrap() > 0 {
+                break;
+            }
+            assert!(value < 500, "the cache forwarded nothing");
+        }
+        // the last update went through the cache once the first subscriber has it.
+        while early.recv_multipart(0).unwrap()[1] != value.to_string().as_bytes() {}
+        publisher.send_multipart(["humidity", "80"], 0).unwrap();
+
+        let late = ctx.socket(zmq::SUB).unwrap();
+        late.connect("inproc://lvc.downstream").unwrap();
+        late.set_subscribe(b"temp").unwrap();
+        assert!(late.poll(zmq::POLLIN, 1_000).unwrap() > 0);
+        let last = late.recv_multipart(0).unwrap();
+        assert_eq!(last[0], b"temperature");
+        assert_eq!(last[1], value.to_string().as_bytes());
+        assert_eq!(late.poll(zmq::POLLIN, 20).unwrap(), 0);
+
+        let statistics = lvc.statistics();
+        assert_eq!(statistics.topics, 2);
+        assert!(statistics.replayed >= 1);
+        lvc.stop();
+    }
+
+    #[test]
+    fn caches_need_publish_subscribe_sockets() {
+        let ctx = zmq::Context::new();
+        let frontend = ctx.socket(zmq::PULL).unwrap();
+        let backend = ctx.socket(zmq::XPUB).unwrap();
+        match LastValueCache::start("wrong", &ctx, frontend, backend) {
+            Err(ProxyError::UnexpectedSocketType(zmq::PULL)) => {}
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("PULL sockets don't subscribe"),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Topologies of proxies, declared in code, or in TOML files.
+//!
+//! Every node is a proxy with a name, and the specs of its frontend, backend, and optional
+//! capture, sockets. Nodes are started in the order they are declared, so that a node can
+//! connect to the sockets that the nodes before it bind. Endpoints can set socket options
+//! inline, see `SocketUri`.
+//!
+//! ```toml
+//! [[node]]
+//! name = "edge"
+//! frontend = { type = "XSUB", bind = ["tcp://*:5555"] }
+//! backend = { type = "XPUB", bind = ["inproc://edge"] }
+//!
+//! [[node]]
+//! name = "core"
+//! frontend = { type = "XSUB", connect = ["inproc://edge"] }
+//! backend = { type = "XPUB", bind = ["tcp://*:5556?sndhwm=10000"] }
+//! capture = { type = "PUSH", connect = ["tcp://127.0.0.1:5557"] }
+//! ```
+use super::super::socket::{SocketBuilder, SocketType};
+use super::{Proxy, ProxyError};
+
+use std::collections::HashSet;
+use toml;
+use zmq;
+
+/// Type, and endpoints, of a socket of a node.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct SocketSpec {
+    /// Name of the socket type, e.g. `XPUB`, or `ROUTER`.
+    #[serde(rename = "type")]
+    pub socket_type: String,
+    #[serde(default)]
+    pub bind: Vec<String>,
+    #[serde(default)]
+    pub connect: Vec<String>,
+}
+
+impl SocketSpec {
+    /// Create the spec of a socket of `socket_type`, without endpoints.
+    pub fn new(socket_type: &str) -> SocketSpec {
+        SocketSpec {
+            socket_type: socket_type.to_string(),
+            bind: Vec::new(),
+            connect: Vec::new(),
+        }
+    }
+
+    /// Bind the socket to `endpoint`.
+    pub fn bind(mut self, endpoint: &str) -> SocketSpec {
+        self.bind.push(endpoint.to_string());
+        self
+    }
+
+    /// Connect the socket to `endpoint`.
+    pub fn connect(mut self, endpoint: &str) -> SocketSpec {
+        self.connect.push(endpoint.to_string());
+        self
+    }
+
+    fn kind(&self) -> Result<SocketType, ProxyError> {
+        self.socket_type
+            .parse()
+            .map_err(|_| ProxyError::UnknownSocketType(self.socket_type.clone()))
+    }
+
+    fn build(&self, context: &zmq::Context) -> Result<zmq::Socket, ProxyError> {
+        let mut builder = SocketBuilder::new(context, self.kind()?).linger(0);
+        for endpoint in &self.bind {
+            builder = builder.bind_uri(endpoint)?;
+        }
+        for endpoint in &self.connect {
+            builder = builder.connect_uri(endpoint)?;
+        }
+        let socket = builder.build()?;
+        if socket.get_socket_type()? == zmq::SUB {
+            socket.set_subscribe(b"")?;
+        }
+        Ok(socket)
+    }
+}
+
+/// A proxy of a topology.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct NodeSpec {
+    pub name: String,
+    pub frontend: SocketSpec,
+    pub backend: SocketSpec,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub capture: Option<SocketSpec>,
+}
+
+impl NodeSpec {
+    /// Create a node named `name`, proxying from `frontend` to `backend`.
+    pub fn new(name: &str, frontend: SocketSpec, backend: SocketSpec) -> NodeSpec {
+        NodeSpec {
+            name: name.to_string(),
+            frontend,
+            backend,
+            capture: None,
+        }
+    }
+
+    /// Copy every message that goes through the node to `capture`.
+    pub fn capture(mut self, capture: SocketSpec) -> NodeSpec {
+        self.capture = Some(capture);
+        self
+    }
+
+    fn start(&self, context: &zmq::Context) -> Result<Proxy, ProxyError> {
+        let frontend = self.frontend.build(context)?;
+        let backend = self.backend.build(context)?;
+        let capture = match self.capture {
+            Some(ref spec) => Some(spec.build(context)?),
+            None => None,
+        };
+        Proxy::start(&self.name, context, frontend, backend, capture)
+    }
+}
+
+/// Nodes of a topology, in the order they are started.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Topology {
+    #[serde(rename = "node", default)]
+    pub nodes: Vec<NodeSpec>,
+}
+
+impl Topology {
+    /// Create an empty topology.
+    pub fn new() -> Topology {
+        Topology::default()
+    }
+
+    /// Read a topology from TOML, with a `[[node]]` table for each node.
+    pub fn from_toml(source: &str) -> Result<Topology, ProxyError> {
+        Ok(toml::from_str(source)?)
+    }
+
+    /// Add `node`, which is started after the nodes before it.
+    pub fn node(mut self, node: NodeSpec) -> Topology {
+        self.nodes.push(node);
+        self
+    }
+
+    /// Check that node names are unique, and that socket types are known.
+    pub fn validate(&self) -> Result<(), ProxyError> {
+        let mut names = HashSet::new();
+        for node in &self.nodes {
+            if !names.insert(node.name.as_str()) {
+                return Err(ProxyError::InvalidTopology(format!(
+                    "duplicate node name {}",
+                    node.name
+                )));
+            }
+            node.frontend.kind()?;
+            node.backend.kind()?;
+            if let Some(ref capture) = node.capture {
+                capture.kind()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Validate the topology, and start its nodes, on `context`. Nodes that were started
+    /// are stopped if a later one fails.
+    pub fn start(&self, context: &zmq::Context) -> Result<TopologyHandle, ProxyError> {
+        self.validate()?;
+        let mut proxies = Vec::with_capacity(self.nodes.len());
+        for node in &self.nodes {
+            proxies.push(node.start(context)?);
+        }
+        Ok(TopologyHandle { proxies })
+    }
+}
+
+/// Running proxies of a topology, which are stopped when it is dropped.
+pub struct TopologyHandle {
+    proxies: Vec<Proxy>,
+}
+
+impl TopologyHandle {
+    /// Returns the proxy of the node named `name`.
+    pub fn get(&self, name: &str) -> Option<&Proxy> {
+        self.proxies.iter().find(|proxy| proxy.name() == name)
+    }
+
+    /// Returns the names of the nodes, in the order they were started.
+    pub fn names(&self) -> Vec<&str> {
+        self.proxies.iter().map(|proxy| proxy.name()).collect()
+    }
+
+    /// Pause every node.
+    pub fn pause_all(&self) -> Result<(), ProxyError> {
+        for proxy in &self.proxies {
+            proxy.pause()?;
+        }
+        Ok(())
+    }
+
+    /// Resume every node.
+    pub fn resume_all(&self) -> Result<(), ProxyError> {
+        for proxy in &self.proxies {
+            proxy.resume()?;
+        }
+        Ok(())
+    }
+
+    /// Stop every node, from the last one started to the first.
+    pub fn stop(&mut self) {
+        while let Some(mut proxy) = self.proxies.pop() {
+            proxy.stop();
+        }
+    }
+}
+
+impl Drop for TopologyHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CHAIN: &str = r#"
+        [[node]]
+        name = "edge"
+        frontend = { type = "XSUB", bind = ["inproc://topology.in"] }
+        backend = { type = "XPUB", bind = ["inproc://topology.edge"] }
+
+        [[node]]
+        name = "core"
+        frontend = { type = "XSUB", connect = ["inproc://topology.edge"] }
+        backend = { type = "XPUB", bind = ["inproc://topology.out"] }
+        capture = { type = "PUSH", bind = ["inproc://topology.capture"] }
+    "#;
+
+    #[test]
+    fn chained_proxies_forward_and_capture() {
+        let ctx = zmq::Context::new();
+        let topology = Topology::from_toml(CHAIN).unwrap();
+        let handle = topology.start(&ctx).unwrap();
+        assert_eq!(handle.names(), vec!["edge", "core"]);
+
+        let capture = ctx.socket(zmq::PULL).unwrap();
+        capture.connect("inproc://topology.capture").unwrap();
+        let publisher = ctx.socket(zmq::PUB).unwrap();
+        publisher.connect("inproc://topology.in").unwrap();
+        let subscriber = ctx.socket(zmq::SUB).unwrap();
+        subscriber.connect("inproc://topology.out").unwrap();
+        subscriber.set_subscribe(b"news").unwrap();
+
+        // subscriptions travel through both nodes before messages reach the subscriber.
+        let mut received = None;
+        for _ in 0..100 {
+            publisher.send("news", 0).unwrap();
+            if subscriber.poll(zmq::POLLIN, 20).unwrap() > 0 {
+                received = Some(subscriber.recv_bytes(0).unwrap());
+                break;
+            }
+        }
+        assert_eq!(received, Some(b"news".to_vec()));
+        assert!(capture.poll(zmq::POLLIN, 1_000).unwrap() > 0);
+
+        let core = handle.get("core").unwrap();
+        let statistics = core.statistics().unwrap();
+        assert!(statistics.frontend.messages_in > 0);
+        assert!(statistics.backend.messages_out > 0);
+        handle.pause_all().unwrap();
+        handle.resume_all().unwrap();
+        assert!(handle.get("missing").is_none());
+    }
+
+    #[test]
+    fn invalid_topologies_are_rejected() {
+        let node = NodeSpec::new(
+            "twice",
+            SocketSpec::new("ROUTER").bind("inproc://twice.front"),
+            SocketSpec::new("DEALER").bind("inproc://twice.back"),
+        );
+        let topology = Topology::new().node(node.clone()).node(node);
+        match topology.validate() {
+            Err(ProxyError::InvalidTopology(_)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let topology = Topology::new().node(NodeSpec::new(
+            "unknown",
+            SocketSpec::new("ROUTER"),
+            SocketSpec::new("QUEUE"),
+        ));
+        match topology.start(&zmq::Context::new()) {
+            Err(ProxyError::UnknownSocketType(name)) => assert_eq!(name, "QUEUE"),
+            Err(other) => panic!("unexpected error: {:?}", other),
+            Ok(_) => panic!("QUEUE is not a socket type"),
+        }
+    }
+}
@@ -0,0 +1,414 @@
+//! Service registry, for looking up endpoints by name.
+//!
+//! A `Registry` runs on a child thread, listening on a `ROUTER` socket. Actorlings register
+//! the endpoints they are bound to under a service name, with a time-to-live, and refresh
+//! them by registering again before it runs out. Entries that are not refreshed in time
+//! are expired.
+//!
+//! `RegistryClient` is a blocking API to register services, and to `resolve` their names
+//! into endpoints, instead of hard-coding them.
+//!
+//! The protocol is made of request-reply messages, whose replies start with a status code:
+//!
+//! * `[REGISTER, name, endpoint, ttl]` -> `[200]`
+//! * `[UNREGISTER, name]` -> `[200]`, or `[404]`
+//! * `[LOOKUP, name]` -> `[200, endpoint]`, or `[404]`
+use super::clock::Clock;
+use super::endpoint::{Endpoint, ToEndpoint};
+use super::utils::run_named_thread;
+
+use failure::Error;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// Command to register a service endpoint.
+pub const REGISTER: &str = "REGISTER";
+/// Command to unregister a service.
+pub const UNREGISTER: &str = "UNREGISTER";
+/// Command to look up the endpoint of a service.
+pub const LOOKUP: &str = "LOOKUP";
+/// Milliseconds between purges of the expired entries.
+pub const PURGE_INTERVAL: i64 = 1_000;
+/// Milliseconds to wait for each reply from the registry.
+pub const REQUEST_TIMEOUT: i64 = 2_500;
+/// Attempts for each request to the registry before giving up.
+pub const REQUEST_RETRIES: usize = 3;
+
+const STATUS_OK: &[u8] = b"200";
+const STATUS_INVALID: &[u8] = b"400";
+const STATUS_NOT_FOUND: &[u8] = b"404";
+const STATUS_UNKNOWN: &[u8] = b"501";
+
+/// Registry Errors.
+#[derive(Debug, Fail)]
+pub enum RegistryError {
+    #[fail(display = "invalid registry message")]
+    InvalidMessage,
+    #[fail(display = "service not found: {}", _0)]
+    NotFound(String),
+    #[fail(display = "registry rejected the request with status {}", _0)]
+    Rejected(String),
+    #[fail(display = "no reply after {} attempts", _0)]
+    NoReply(usize),
+}
+
+// An endpoint registered for a service.
+struct Entry {
+    endpoint: String,
+    expiry: i64,
+}
+
+// Registry state, owned by the registry thread.
+struct RegistryState {
+    clock: Clock,
+    entries: HashMap<String, Entry>,
+}
+
+impl RegistryState {
+    fn new() -> Self {
+        RegistryState {
+            clock: Clock::new(),
+            entries: HashMap::new(),
+        }
+    }
+
+    // Answer a request, with the frames of the reply.
+    fn request(&mut self, mut msg: VecDeque<Vec<u8>>) -> Vec<Vec<u8>> {
+        let command = msg.pop_front().unwrap_or_default();
+        let name = match msg.pop_front() {
+            Some(name) => String::from_utf8_lossy(&name).to_string(),
+            None => return vec![STATUS_INVALID.to_vec()],
+        };
+        match &command[..] {
+            c if c == REGISTER.as_bytes() => {
+                let endpoint = msg
+                    .pop_front()
+                    .and_then(|e| String::from_utf8(e).ok())
+                    .and_then(|e| e.to_endpoint().ok());
+                let ttl = msg
+                    .pop_front()
+                    .and_then(|t| String::from_utf8(t).ok())
+                    .and_then(|t| t.parse::<i64>().ok())
+                    .filter(|t| *t > 0);
+                match (endpoint, ttl) {
+                    (Some(endpoint), Some(ttl)) => {
+                        nlog!(debug, "registered service={} endpoint={}", name, endpoint);
+                        let expiry = self.clock.mono() + ttl;
+                        let endpoint = endpoint.to_string();
+                        self.entries.insert(name, Entry { endpoint, expiry });
+                        vec![STATUS_OK.to_vec()]
+                    }
+                    _ => vec![STATUS_INVALID.to_vec()],
+                }
+            }
+            c if c == UNREGISTER.as_bytes() => match self.entries.remove(&name) {
+                Some(_) => vec![STATUS_OK.to_vec()],
+                None => vec![STATUS_NOT_FOUND.to_vec()],
+            },
+            c if c == LOOKUP.as_bytes() => {
+                let now = self.clock.mono();
+                match self.entries.get(&name) {
+                    Some(entry) if entry.expiry > now => {
+                        vec![STATUS_OK.to_vec(), entry.endpoint.as_bytes().to_vec()]
+                    }
+                    _ => vec![STATUS_NOT_FOUND.to_vec()],
+                }
+            }
+            _ => vec![STATUS_UNKNOWN.to_vec()],
+        }
+    }
+
+    // Forget the entries that were not refreshed in time.
+    fn purge(&mut self) {
+        let now = self.clock.mono();
+        self.entries.retain(|name, entry| {
+            let alive = entry.expiry > now;
+            if !alive {
+                nlog!(
+                    debug,
+                    "expired service={} endpoint={}",
+                    name,
+                    entry.endpoint
+                );
+            }
+            alive
+        });
+    }
+}
+
+/// A registry of service endpoints, looked up by name.
+pub struct Registry {
+    address: String,
+    context: zmq::Context,
+    pipe: zmq::Socket,
+    uuid: Uuid,
+}
+
+impl Registry {
+    /// Create a new `Registry` instance, which will listen for clients on the given
+    /// address.
+    pub fn new(addr: &str) -> Result<Self, Error> {
+        Registry::new_with_context(addr, zmq::Context::new())
+    }
+
+    /// Create a new `Registry` instance that shares network context with the creator.
+    pub fn new_with_context(addr: &str, context: zmq::Context) -> Result<Self, Error> {
+        let address = addr.to_string();
+        let uuid = Uuid::new_v4();
+        let pipe = context.socket(zmq::PAIR)?;
+        pipe.connect(&pipe_address(&uuid))?;
+        Ok(Registry {
+            address,
+            context,
+            pipe,
+            uuid,
+        })
+    }
+
+    /// Returns a `String` with the address for the registry.
+    pub fn address(&self) -> String {
+        self.address.clone()
+    }
+
+    /// Returns the registry's network context.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Return a reference to the underlying pipe socket.
+    pub fn pipe(&self) -> &zmq::Socket {
+        &self.pipe
+    }
+
+    /// Start the registry on a child thread. The bound endpoint is sent back over the pipe
+    /// once the registry is listening.
+    pub fn start(&self) -> Result<thread::JoinHandle<Result<(), Error>>, io::Error> {
+        let context = self.context();
+        let address = self.address();
+        let pipe_addr = pipe_address(&self.uuid);
+
+        run_named_thread("registry", move || {
+            let pipe = context.socket(zmq::PAIR)?;
+            pipe.bind(&pipe_addr)?;
+
+            let socket = context.socket(zmq::ROUTER)?;
+            socket.bind(&address)?;
+            let endpoint = socket
+                .get_last_endpoint()?
+                .expect("unparsable registry endpoint");
+            pipe.send(&endpoint, 0)?;
+
+            poll_registry(&pipe, &socket, &mut RegistryState::new())
+        })
+    }
+
+    /// Stop the registry.
+    pub fn stop(&self) -> Result<(), zmq::Error> {
+        self.pipe().send("$STOP", 0)
+    }
+}
+
+// Each registry gets its own pipe address.
+fn pipe_address(uuid: &Uuid) -> String {
+    format!("inproc://neuras.registry.{}", uuid.to_simple())
+}
+
+fn poll_registry(
+    pipe: &zmq::Socket,
+    socket: &zmq::Socket,
+    state: &mut RegistryState,
+) -> Result<(), Error> {
+    let mut purge_at = state.clock.mono() + PURGE_INTERVAL;
+    loop {
+        let mut pollable = [
+            pipe.as_poll_item(zmq::POLLIN),
+            socket.as_poll_item(zmq::POLLIN),
+        ];
+        zmq::poll(&mut pollable, PURGE_INTERVAL)?;
+        if pollable[0].is_readable() {
+            let cmd = pipe.recv_msg(0)?;
+            if &*cmd == b"$STOP" {
+                pipe.send("$STOPPING", 0)?;
+                break;
+            }
+            pipe.send("$WONTDO", 0)?;
+        }
+        if pollable[1].is_readable() {
+            let mut msg: VecDeque<Vec<u8>> = socket.recv_multipart(0)?.into();
+            let sender = msg.pop_front();
+            let empty = msg.pop_front();
+            match (sender, empty) {
+                (Some(sender), Some(_)) => {
+                    let mut reply = vec![sender, Vec::new()];
+                    reply.extend(state.request(msg));
+                    socket.send_multipart(reply, 0)?;
+                }
+                _ => nlog!(debug, "dropped invalid message"),
+            }
+        }
+        if state.clock.mono() >= purge_at {
+            state.purge();
+            purge_at = state.clock.mono() + PURGE_INTERVAL;
+        }
+    }
+    Ok(())
+}
+
+/// Blocking registry client.
+pub struct RegistryClient {
+    registry: String,
+    context: zmq::Context,
+    socket: zmq::Socket,
+    timeout: i64,
+    retries: usize,
+}
+
+impl RegistryClient {
+    /// Create a new `RegistryClient`, connected to the registry at the given address.
+    pub fn new(registry: &str, context: zmq::Context) -> Result<Self, Error> {
+        let socket = RegistryClient::connect_to_registry(&context, registry)?;
+        Ok(RegistryClient {
+            registry: registry.to_string(),
+            context,
+            socket,
+            timeout: REQUEST_TIMEOUT,
+            retries: REQUEST_RETRIES,
+        })
+    }
+
+    fn connect_to_registry(context: &zmq::Context, registry: &str) -> Result<zmq::Socket, Error> {
+        let socket = context.socket(zmq::REQ)?;
+        socket.set_linger(0)?;
+        socket.connect(registry)?;
+        Ok(socket)
+    }
+
+    /// Set the timeout, in milliseconds, to wait for each reply.
+    pub fn set_timeout(&mut self, timeout: i64) {
+        self.timeout = timeout;
+    }
+
+    /// Set the number of attempts for each request before giving up.
+    pub fn set_retries(&mut self, retries: usize) {
+        self.retries = retries;
+    }
+
+    /// Register `endpoint` under the service `name`, for `ttl` milliseconds. Registering
+    /// again refreshes the entry, or replaces its endpoint.
+    pub fn register<E: ToEndpoint>(
+        &mut self,
+        name: &str,
+        endpoint: E,
+        ttl: i64,
+    ) -> Result<(), Error> {
+        let endpoint = endpoint.to_endpoint()?.to_string();
+        let ttl = ttl.to_string();
+        self.request(&[REGISTER, name, &endpoint, &ttl])?;
+        Ok(())
+    }
+
+    /// Unregister the service `name`.
+    pub fn unregister(&mut self, name: &str) -> Result<(), Error> {
+        self.request(&[UNREGISTER, name])?;
+        Ok(())
+    }
+
+    /// Resolve the service `name` into the endpoint it is registered with.
+    pub fn resolve(&mut self, name: &str) -> Result<Endpoint, Error> {
+        let mut reply = self.request(&[LOOKUP, name])?;
+        match reply.pop_front() {
+            Some(endpoint) => Ok(String::from_utf8(endpoint)?.to_endpoint()?),
+            None => Err(RegistryError::InvalidMessage.into()),
+        }
+    }
+
+    // Send a request, and return the frames of its reply after the status code. Retries
+    // with a fresh connection when the registry does not answer in time.
+    fn request(&mut self, msg: &[&str]) -> Result<VecDeque<Vec<u8>>, Error> {
+        for _ in 0..self.retries {
+            self.socket.send_multipart(msg, 0)?;
+            if self.socket.poll(zmq::POLLIN, self.timeout)? > 0 {
+                let mut reply: VecDeque<Vec<u8>> = self.socket.recv_multipart(0)?.into();
+                return match reply.pop_front() {
+                    Some(ref status) if &status[..] == STATUS_OK => Ok(reply),
+                    Some(ref status) if &status[..] == STATUS_NOT_FOUND => {
+                        Err(RegistryError::NotFound(msg[1].to_string()).into())
+                    }
+                    Some(status) => Err(RegistryError::Rejected(
+                        String::from_utf8_lossy(&status).to_string(),
+                    )
+                    .into()),
+                    None => Err(RegistryError::InvalidMessage.into()),
+                };
+            }
+            self.socket = RegistryClient::connect_to_registry(&self.context, &self.registry)?;
+        }
+        Err(RegistryError::NoReply(self.retries).into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn start_registry(addr: &str) -> Registry {
+        let registry = Registry::new(addr).unwrap();
+        registry.start().unwrap();
+        registry.pipe().recv_msg(0).unwrap();
+        registry
+    }
+
+    fn is_not_found(e: &Error) -> bool {
+        matches!(
+            e.downcast_ref::<RegistryError>(),
+            Some(RegistryError::NotFound(_))
+        )
+    }
+
+    #[test]
+    fn services_resolve_into_their_registered_endpoints() {
+        let registry = start_registry("inproc://registry_resolve");
+        let mut client =
+            RegistryClient::new("inproc://registry_resolve", registry.context()).unwrap();
+        client
+            .register("echo", "tcp://127.0.0.1:5784", 1_000)
+            .unwrap();
+        let endpoint = client.resolve("echo").unwrap();
+        assert_eq!(endpoint.to_string(), "tcp://127.0.0.1:5784");
+        assert!(is_not_found(&client.resolve("missing").unwrap_err()));
+
+        client.unregister("echo").unwrap();
+        assert!(is_not_found(&client.resolve("echo").unwrap_err()));
+        registry.stop().unwrap();
+    }
+
+    #[test]
+    fn stale_entries_expire() {
+        let registry = start_registry("inproc://registry_expiry");
+        let mut client =
+            RegistryClient::new("inproc://registry_expiry", registry.context()).unwrap();
+        client.register("echo", "ipc://echo", 20).unwrap();
+        assert!(client.resolve("echo").is_ok());
+        Clock::new().sleep(40);
+        assert!(is_not_found(&client.resolve("echo").unwrap_err()));
+        registry.stop().unwrap();
+    }
+
+    #[test]
+    fn invalid_registrations_are_rejected() {
+        let registry = start_registry("inproc://registry_invalid");
+        let mut client =
+            RegistryClient::new("inproc://registry_invalid", registry.context()).unwrap();
+        let err = client
+            .register("echo", "tcp://127.0.0.1:5784", 0)
+            .unwrap_err();
+        match err.downcast_ref::<RegistryError>() {
+            Some(RegistryError::Rejected(status)) => assert_eq!(status, "400"),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        registry.stop().unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! Asynchronous remote procedure calls.
+//!
+//! A `Client` sends requests on a `DEALER` socket, each one with a UUID as its first frame,
+//! the correlation id, so that many requests can be in flight at once, and their replies
+//! can arrive in any order. A `Server` receives requests on a `ROUTER` socket, and sends
+//! each reply back with the correlation id of its request.
+//!
+//! Requests that are not answered in time are forgotten, and replies that arrive for them
+//! afterwards are dropped.
+use super::clock::Clock;
+use super::endpoint::{AddressParse, ToEndpoint};
+
+use std::collections::HashMap;
+use uuid::Uuid;
+use zmq;
+
+#[cfg(feature = "async-tokio")]
+#[path = "rpc_async.rs"]
+mod async_client;
+
+#[cfg(feature = "async-tokio")]
+pub use self::async_client::{AsyncClient, ReplyFuture};
+
+/// RPC errors.
+#[derive(Debug, Fail)]
+pub enum RpcError {
+    #[fail(display = "request {} timed out", _0)]
+    Timeout(Uuid),
+    #[fail(display = "request {} is not pending", _0)]
+    UnknownRequest(Uuid),
+    #[fail(display = "invalid message, without a correlation id")]
+    InvalidMessage,
+    #[fail(display = "{}", _0)]
+    Address(#[cause] AddressParse),
+    #[fail(display = "{}", _0)]
+    Io(#[cause] ::std::io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<AddressParse> for RpcError {
+    fn from(e: AddressParse) -> RpcError {
+        RpcError::Address(e)
+    }
+}
+
+impl From<::std::io::Error> for RpcError {
+    fn from(e: ::std::io::Error) -> RpcError {
+        RpcError::Io(e)
+    }
+}
+
+impl From<zmq::Error> for RpcError {
+    fn from(e: zmq::Error) -> RpcError {
+        RpcError::Zmq(e)
+    }
+}
+
+// Split the correlation id from the frames of a message.
+fn split_correlation(mut frames: Vec<Vec<u8>>) -> Result<(Uuid, Vec<Vec<u8>>), RpcError> {
+    if frames.is_empty() {
+        return Err(RpcError::InvalidMessage);
+    }
+    let rest = frames.split_off(1);
+    let id = Uuid::from_slice(&frames[0]).map_err(|_| RpcError::InvalidMessage)?;
+    Ok((id, rest))
+}
+
+fn with_correlation<I, T>(id: &Uuid, frames: I) -> Vec<Vec<u8>>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<Vec<u8>>,
+{
+    let mut msg = vec![id.as_bytes().to_vec()];
+    msg.extend(frames.into_iter().map(Into::into));
+    msg
+}
+
+/// A pending request, to wait for with `Client::wait`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Request {
+    id: Uuid,
+    deadline: i64,
+}
+
+impl Request {
+    /// Returns the correlation id of the request.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+/// Blocking RPC client, over a `DEALER` socket.
+pub struct Client {
+    socket: zmq::Socket,
+    pending: HashMap<Uuid, i64>,
+    replies: HashMap<Uuid, Vec<Vec<u8>>>,
+    clock: Clock,
+}
+
+impl Client {
+    /// Create a new `Client`, connected to `endpoint`.
+    pub fn new<E: ToEndpoint>(context: &zmq::Context, endpoint: E) -> Result<Client, RpcError> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(&endpoint.to_endpoint()?.to_string())?;
+        Ok(Client {
+            socket,
+            pending: HashMap::new(),
+            replies: HashMap::new(),
+            clock: Clock::new(),
+        })
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Returns the number of requests waiting for a reply.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Send a request, that times out after `timeout` milliseconds, or never if it is `-1`.
+    pub fn send<I, T>(&mut self, frames: I, timeout: i64) -> Result<Request, RpcError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let id = Uuid::new_v4();
+        self.socket
+            .send_multipart(with_correlation(&id, frames), 0)?;
+        let deadline = if timeout < 0 {
+            i64::MAX
+        } else {
+            self.clock.mono() + timeout
+        };
+        self.pending.insert(id, deadline);
+        Ok(Request { id, deadline })
+    }
+
+    /// Wait for the reply to `request`. Replies to other pending requests that arrive in
+    /// the meantime are kept for them.
+    pub fn wait(&mut self, request: &Request) -> Result<Vec<Vec<u8>>, RpcError> {
+        loop {
+            if let Some(reply) = self.replies.remove(&request.id) {
+                return Ok(reply);
+            }
+            if !self.pending.contains_key(&request.id) {
+                return Err(RpcError::UnknownRequest(request.id));
+            }
+            let now = self.clock.mono();
+            if now >= request.deadline {
+                self.pending.remove(&request.id);
+                return Err(RpcError::Timeout(request.id));
+            }
+            let wait = if request.deadline == i64::MAX {
+                -1
+            } else {
+                request.deadline - now
+            };
+            if self.socket.poll(zmq::POLLIN, wait)? > 0 {
+                self.recv_reply()?;
+            }
+        }
+    }
+
+    /// Send a request, and wait up to `timeout` milliseconds for its reply.
+    pub fn call<I, T>(&mut self, frames: I, timeout: i64) -> Result<Vec<Vec<u8>>, RpcError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let request = self.send(frames, timeout)?;
+        self.wait(&request)
+    }
+
+    /// Forget the pending requests that timed out, returning their ids.
+    pub fn expire(&mut self) -> Vec<Uuid> {
+        let now = self.clock.mono();
+        let expired: Vec<Uuid> = self
+            .pending
+            .iter()
+            .filter(|&(_, deadline)| *deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in &expired {
+            self.pending.remove(id);
+            self.replies.remove(id);
+        }
+        expired
+    }
+
+    // Receive a reply, keeping it if its request is still pending.
+    fn recv_reply(&mut self) -> Result<(), RpcError> {
+        let (id, reply) = split_correlation(self.socket.recv_multipart(0)?)?;
+        if self.pending.remove(&id).is_some() {
+            self.replies.insert(id, reply);
+        } else {
+            nlog!(debug, "dropped straggler reply request={}", id);
+        }
+        Ok(())
+    }
+}
+
+/// A request received by a `Server`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Call {
+    identity: Vec<u8>,
+    id: Uuid,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl Call {
+    /// Returns the correlation id of the request.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Returns the identity of the client that sent the request.
+    pub fn identity(&self) -> &[u8] {
+        &self.identity
+    }
+}
+
+/// RPC server, over a `ROUTER` socket.
+pub struct Server {
+    socket: zmq::Socket,
+}
+
+impl Server {
+    /// Create a new `Server`, bound to `endpoint`.
+    pub fn new<E: ToEndpoint>(context: &zmq::Context, endpoint: E) -> Result<Server, RpcError> {
+        let socket = context.socket(zmq::ROUTER)?;
+        socket.bind(&endpoint.to_endpoint()?.to_string())?;
+        Ok(Server { socket })
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Receive the next request.
+    pub fn recv(&self, flags: i32) -> Result<Call, RpcError> {
+        let mut frames = self.socket.recv_multipart(flags)?;
+        if frames.is_empty() {
+            return Err(RpcError::InvalidMessage);
+        }
+        let rest = frames.split_off(1);
+        let identity = frames.swap_remove(0);
+        let (id, frames) = split_correlation(rest)?;
+        Ok(Call {
+            identity,
+            id,
+            frames,
+        })
+    }
+
+    /// Send the reply to `call`.
+    pub fn reply<I, T>(&self, call: &Call, frames: I) -> Result<(), RpcError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut msg = vec![call.identity.clone()];
+        msg.extend(with_correlation(&call.id, frames));
+        self.socket.send_multipart(msg, 0)?;
+        Ok(())
+    }
+
+    /// Receive the next request, and reply with the frames returned by `handler`.
+    pub fn serve_one<F>(&self, flags: i32, handler: F) -> Result<(), RpcError>
+    where
+        F: FnOnce(&Call) -> Vec<Vec<u8>>,
+    {
+        let call = self.recv(flags)?;
+        let reply = handler(&call);
+        self.reply(&call, reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replies_are_matched_to_their_requests() {
+        let ctx = zmq::Context::new();
+        let server = Server::new(&ctx, "inproc://rpc_match").unwrap();
+        let mut client = Client::new(&ctx, "inproc://rpc_match").unwrap();
+        let first = client.send(vec!["first"], 1_000).unwrap();
+        let second = client.send(vec!["second"], 1_000).unwrap();
+        assert_eq!(client.pending(), 2);
+
+        // answer in reverse order.
+        let calls = [server.recv(0).unwrap(), server.recv(0).unwrap()];
+        assert_eq!(calls[0].id(), first.id());
+        for call in calls.iter().rev() {
+            server.reply(call, call.frames.clone()).unwrap();
+        }
+        assert_eq!(client.wait(&first).unwrap(), vec![b"first".to_vec()]);
+        assert_eq!(client.wait(&second).unwrap(), vec![b"second".to_vec()]);
+        assert_eq!(client.pending(), 0);
+    }
+
+    #[test]
+    fn stragglers_time_out_and_are_dropped() {
+        let ctx = zmq::Context::new();
+        let server = Server::new(&ctx, "inproc://rpc_timeout").unwrap();
+        let mut client = Client::new(&ctx, "inproc://rpc_timeout").unwrap();
+        let request = client.send(vec!["slow"], 10).unwrap();
+        match client.wait(&request) {
+            Err(RpcError::Timeout(id)) => assert_eq!(id, request.id()),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        server.serve_one(0, |call| call.frames.clone()).unwrap();
+        let fast = client.send(vec!["fast"], 1_000).unwrap();
+        server.serve_one(0, |_| vec![b"done".to_vec()]).unwrap();
+        assert_eq!(client.wait(&fast).unwrap(), vec![b"done".to_vec()]);
+        assert!(client.replies.is_empty());
+    }
+}
@@ -0,0 +1,200 @@
+//! `tokio`-compatible RPC client.
+use super::super::endpoint::ToEndpoint;
+use super::super::socket::tokio::TokioSocket;
+use super::super::socket::{SocketRecv, SocketSend};
+use super::{split_correlation, with_correlation, RpcError};
+
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::time::Duration;
+
+use futures::{Async, Future, Poll};
+use tokio_core::reactor::{Handle, Timeout};
+use uuid::Uuid;
+use zmq;
+
+#[derive(Default)]
+struct State {
+    pending: HashSet<Uuid>,
+    replies: HashMap<Uuid, Vec<Vec<u8>>>,
+}
+
+/// RPC client over a `DEALER` socket, registered with a `tokio` reactor, whose calls are
+/// futures.
+///
+/// The futures of every call share the socket: whichever is polled receives the replies
+/// that are ready, and keeps them for the calls that they belong to. Only the task that
+/// receives a reply is notified, so the calls of a client should be driven by a single
+/// task, e.g. by joining their futures.
+pub struct AsyncClient {
+    socket: TokioSocket,
+    handle: Handle,
+    state: RefCell<State>,
+}
+
+impl AsyncClient {
+    /// Create a new `AsyncClient`, connected to `endpoint`.
+    pub fn new<E: ToEndpoint>(
+        context: &zmq::Context,
+        endpoint: E,
+        handle: &Handle,
+    ) -> Result<AsyncClient, RpcError> {
+        let socket = context.socket(zmq::DEALER)?;
+        socket.set_linger(0)?;
+        socket.connect(&endpoint.to_endpoint()?.to_string())?;
+        Ok(AsyncClient {
+            socket: TokioSocket::new(socket, handle)?,
+            handle: handle.clone(),
+            state: RefCell::new(State::default()),
+        })
+    }
+
+    /// Returns the number of calls waiting for a reply.
+    pub fn pending(&self) -> usize {
+        self.state.borrow().pending.len()
+    }
+
+    /// Returns a `Future` that sends a request, and resolves into its reply, or fails with
+    /// `RpcError::Timeout` if the reply does not arrive within `timeout`.
+    pub fn call<I, T>(&self, frames: I, timeout: Duration) -> Result<ReplyFuture<'_>, RpcError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let id = Uuid::new_v4();
+        let timeout = Timeout::new(timeout, &self.handle)?;
+        self.state.borrow_mut().pending.insert(id);
+        Ok(ReplyFuture {
+            client: self,
+            id,
+            request: Some(with_correlation(&id, frames)),
+            timeout,
+        })
+    }
+
+    // Receive the replies that are ready, keeping the ones of pending calls.
+    fn recv_replies(&self) -> Result<(), RpcError> {
+        loop {
+            let frames = match SocketRecv::recv_multipart(&self.socket, 0) {
+                Ok(frames) => frames,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            let (id, reply) = split_correlation(frames)?;
+            let mut state = self.state.borrow_mut();
+            if state.pending.remove(&id) {
+                state.replies.insert(id, reply);
+            } else {
+                nlog!(debug, "dropped straggler reply request={}", id);
+            }
+        }
+    }
+}
+
+/// A `Future` that resolves into the reply to a call.
+pub struct ReplyFuture<'a> {
+    client: &'a AsyncClient,
+    id: Uuid,
+    request: Option<Vec<Vec<u8>>>,
+    timeout: Timeout,
+}
+
+impl<'a> ReplyFuture<'a> {
+    /// Returns the correlation id of the call.
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+}
+
+impl<'a> Future for ReplyFuture<'a> {
+    type Item = Vec<Vec<u8>>;
+    type Error = RpcError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(request) = self.request.take() {
+            match SocketSend::send_multipart(&self.client.socket, &request, 0) {
+                Ok(()) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.request = Some(request);
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if self.request.is_none() {
+            self.client.recv_replies()?;
+            if let Some(reply) = self.client.state.borrow_mut().replies.remove(&self.id) {
+                return Ok(Async::Ready(reply));
+            }
+        }
+        match self.timeout.poll()? {
+            Async::Ready(()) => {
+                self.client.state.borrow_mut().pending.remove(&self.id);
+                Err(RpcError::Timeout(self.id))
+            }
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+impl<'a> Drop for ReplyFuture<'a> {
+    fn drop(&mut self) {
+        let mut state = self.client.state.borrow_mut();
+        state.pending.remove(&self.id);
+        state.replies.remove(&self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Server;
+    use super::*;
+    use std::thread;
+    use tokio_core::reactor::Core;
+
+    #[test]
+    fn calls_resolve_into_their_replies() {
+        let ctx = zmq::Context::new();
+        let server = Server::new(&ctx, "inproc://rpc_async").unwrap();
+        let serving = thread::spawn(move || {
+            for _ in 0..2 {
+                server
+                    .serve_one(0, |call| {
+                        let mut reply = vec![b"re:".to_vec()];
+                        reply.extend(call.frames.clone());
+                        reply
+                    })
+                    .unwrap();
+            }
+        });
+
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = AsyncClient::new(&ctx, "inproc://rpc_async", &handle).unwrap();
+        let first = client.call(vec!["one"], Duration::from_secs(1)).unwrap();
+        let second = client.call(vec!["two"], Duration::from_secs(1)).unwrap();
+        let (one, two) = core.run(first.join(second)).unwrap();
+        assert_eq!(one, vec![b"re:".to_vec(), b"one".to_vec()]);
+        assert_eq!(two[1], b"two".to_vec());
+        assert_eq!(client.pending(), 0);
+        serving.join().unwrap();
+    }
+
+    #[test]
+    fn calls_time_out_without_a_reply() {
+        let ctx = zmq::Context::new();
+        let _server = Server::new(&ctx, "inproc://rpc_async_timeout").unwrap();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let client = AsyncClient::new(&ctx, "inproc://rpc_async_timeout", &handle).unwrap();
+        let call = client
+            .call(vec!["lost"], Duration::from_millis(20))
+            .unwrap();
+        let id = call.id();
+        match core.run(call) {
+            Err(RpcError::Timeout(timed_out)) => assert_eq!(timed_out, id),
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert_eq!(client.pending(), 0);
+    }
+}
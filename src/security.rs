@@ -0,0 +1,581 @@
+//! Socket security.
+//!
+//! Helpers to configure sockets for ØMQ security mechanisms, and an `Authenticator` that
+//! answers [ZAP](https://rfc.zeromq.org/spec/27/) requests on behalf of server sockets.
+//!
+//! The PLAIN mechanism sends usernames and passwords in clear text, so it should only be
+//! used on trusted networks. CURVE encrypts, and authenticates, with the key pairs managed
+//! by a `Keyring`.
+use chrono::{DateTime, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use toml;
+use zeroize::Zeroizing;
+use zmq;
+
+use super::utils::{require_capability, Capability, UnsupportedCapability};
+
+#[path = "security_cipher.rs"]
+mod cipher;
+#[path = "security_handshake.rs"]
+mod handshake;
+#[path = "security_integrity.rs"]
+mod integrity;
+#[path = "security_keyring.rs"]
+mod keyring;
+#[path = "security_seal.rs"]
+mod seal;
+#[path = "security_zap.rs"]
+mod zap;
+
+pub use self::cipher::{CipherReceiver, CipherSender};
+#[cfg(feature = "async-tokio")]
+pub use self::handshake::HandshakeFuture;
+pub use self::handshake::{monitor_handshakes, Handshake, HandshakeEvents, HandshakeFailure};
+pub use self::handshake::{HandshakeMonitor, ProtocolError};
+pub use self::integrity::{IntegrityEnvelope, IntegrityKeys, CHECK_CRC32, CHECK_HMAC};
+pub use self::keyring::{Keyring, KEY_GRACE_PERIOD, REKEY};
+pub use self::zap::{has_zap_handler, Authenticator, PasswordCheck, Passwords, ZAP_ENDPOINT};
+pub use zmq::CurveKeyPair;
+
+/// Security Errors.
+#[derive(Debug, Fail)]
+pub enum SecurityError {
+    #[fail(display = "invalid password entry on line {}", _0)]
+    PasswordEntry(usize),
+    #[fail(display = "invalid CURVE key")]
+    InvalidKey,
+    #[fail(display = "certificate has no secret key")]
+    MissingSecretKey,
+    #[fail(display = "certificate secret key is encrypted")]
+    EncryptedSecretKey,
+    #[fail(display = "wrong passphrase, or tampered secret key")]
+    WrongPassphrase,
+    #[fail(display = "unsupported secret key cipher: {}", _0)]
+    UnsupportedCipher(String),
+    #[fail(display = "message has no integrity frame")]
+    MissingIntegrityFrame,
+    #[fail(display = "message failed its integrity check")]
+    IntegrityMismatch,
+    #[fail(display = "unknown integrity key id {}", _0)]
+    UnknownKeyId(u32),
+    #[fail(display = "integrity key id {} is already in use", _0)]
+    DuplicateKeyId(u32),
+    #[fail(display = "no handshake ended before the timeout")]
+    HandshakeTimeout,
+    #[fail(display = "the handshakes of the socket are not monitored")]
+    NotMonitored,
+    #[fail(display = "no ZAP handler is running, clients would not be authenticated")]
+    NoZapHandler,
+    #[fail(display = "invalid certificate: {}", _0)]
+    Certificate(#[cause] toml::de::Error),
+    #[fail(display = "{}", _0)]
+    UnsupportedCapability(#[cause] UnsupportedCapability),
+    #[fail(display = "{}", _0)]
+    Encode(#[cause] toml::ser::Error),
+    #[fail(display = "{}", _0)]
+    Io(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<io::Error> for SecurityError {
+    fn from(e: io::Error) -> SecurityError {
+        SecurityError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for SecurityError {
+    fn from(e: toml::de::Error) -> SecurityError {
+        SecurityError::Certificate(e)
+    }
+}
+
+impl From<toml::ser::Error> for SecurityError {
+    fn from(e: toml::ser::Error) -> SecurityError {
+        SecurityError::Encode(e)
+    }
+}
+
+impl From<UnsupportedCapability> for SecurityError {
+    fn from(e: UnsupportedCapability) -> SecurityError {
+        SecurityError::UnsupportedCapability(e)
+    }
+}
+
+impl From<zmq::Error> for SecurityError {
+    fn from(e: zmq::Error) -> SecurityError {
+        SecurityError::Zmq(e)
+    }
+}
+
+/// Metadata key for the name of a certificate.
+pub const CERT_NAME: &str = "name";
+/// Metadata key for the creation time of a certificate, in RFC 3339 format.
+pub const CERT_CREATED: &str = "created";
+
+/// Default iterations of the derivation of keys from passphrases, for `encrypt`.
+pub const KDF_ITERATIONS: u32 = 100_000;
+
+/// Encryption of the secret key of a certificate, under a key derived from a passphrase.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SecretKeyCipher {
+    /// Cipher of the secret key, i.e. `chacha20-poly1305`.
+    pub cipher: String,
+    /// Derivation of the key of the cipher, i.e. `pbkdf2-sha256`.
+    pub kdf: String,
+    /// Iterations of the key derivation.
+    pub iterations: u32,
+    /// Z85-encoded salt of the key derivation.
+    pub salt: String,
+    /// Z85-encoded nonce of the cipher.
+    pub nonce: String,
+}
+
+/// CURVE key pair, encoded with Z85 to be stored in TOML files.
+///
+/// Certificates without a secret key are public-only, and can be given to peers. Key pairs
+/// are decoded with `CurveKeyPair::try_from`.
+///
+/// The secret key may be encrypted with a passphrase, so that it is not stored in clear
+/// text; certificates are then `decrypt`ed, e.g. by `load_with_passphrase`, before their
+/// key pair is decoded.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KeysCertificate {
+    /// Z85-encoded public key.
+    pub public_key: String,
+    /// Z85-encoded secret key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub secret_key: Option<String>,
+    /// Metadata, such as the `CERT_NAME` and `CERT_CREATED` of the certificate.
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// Encryption of the secret key, if it is encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cipher: Option<SecretKeyCipher>,
+}
+
+impl KeysCertificate {
+    /// Generate a new certificate. Returns `SecurityError::UnsupportedCapability` if the
+    /// local libzmq lacks CURVE.
+    pub fn new() -> Result<KeysCertificate, SecurityError> {
+        require_capability(Capability::Curve)?;
+        Ok(KeysCertificate::from(&CurveKeyPair::new()?))
+    }
+
+    /// Load a certificate from a TOML file.
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<KeysCertificate, SecurityError> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    /// Load a certificate from a TOML file, decrypting its secret key with `passphrase` if it
+    /// is encrypted.
+    pub fn load_with_passphrase<P: AsRef<Path>>(
+        path: P,
+        passphrase: &str,
+    ) -> Result<KeysCertificate, SecurityError> {
+        KeysCertificate::load_from(path)?.decrypt(passphrase)
+    }
+
+    /// Save the certificate to a TOML file. Certificates with a secret key can only be read
+    /// by their owner.
+    ///
+    /// The certificate is written to a temporary file which is then renamed, so a crash
+    /// never leaves a partial certificate behind.
+    pub fn save_to<P: AsRef<Path>>(&self, path: P) -> Result<(), SecurityError> {
+        let path = path.as_ref();
+        let contents = toml::to_string(self)?;
+        let tmp = path.with_extension("tmp");
+        let mut options = OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        options.mode(if self.is_public_only() { 0o644 } else { 0o600 });
+        let mut file = options.open(&tmp)?;
+        file.write_all(contents.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Returns a copy of the certificate without the secret key, to be distributed.
+    pub fn public_only(&self) -> KeysCertificate {
+        KeysCertificate {
+            secret_key: None,
+            cipher: None,
+            ..self.clone()
+        }
+    }
+
+    /// Returns true if the secret key is encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.cipher.is_some()
+    }
+
+    /// Returns a copy of the certificate, with its secret key encrypted with `passphrase`.
+    pub fn encrypt(&self, passphrase: &str) -> Result<KeysCertificate, SecurityError> {
+        self.encrypt_with_iterations(passphrase, KDF_ITERATIONS)
+    }
+
+    /// Returns a copy of the certificate, with its secret key encrypted with `passphrase`,
+    /// deriving the key of the cipher with `iterations` iterations.
+    pub fn encrypt_with_iterations(
+        &self,
+        passphrase: &str,
+        iterations: u32,
+    ) -> Result<KeysCertificate, SecurityError> {
+        if self.is_encrypted() {
+            return Err(SecurityError::EncryptedSecretKey);
+        }
+        let secret_key = self
+            .secret_key
+            .as_ref()
+            .ok_or(SecurityError::MissingSecretKey)?;
+        let secret_key = Zeroizing::new(decode_key(secret_key)?);
+        let random = random_bytes(28);
+        let (salt, nonce) = random.split_at(16);
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes.copy_from_slice(nonce);
+        let key = seal::derive_key(passphrase.as_bytes(), salt, iterations);
+        // the public key is authenticated along with the secret key.
+        let sealed = seal::seal(&key, &nonce_bytes, self.public_key.as_bytes(), &*secret_key);
+        Ok(KeysCertificate {
+            secret_key: Some(z85_encode(&sealed)),
+            cipher: Some(SecretKeyCipher {
+                cipher: seal::CIPHER.to_string(),
+                kdf: seal::KDF.to_string(),
+                iterations,
+                salt: z85_encode(salt),
+                nonce: z85_encode(nonce),
+            }),
+            ..self.clone()
+        })
+    }
+
+    /// Returns a copy of the certificate, with its secret key decrypted with `passphrase`.
+    /// Certificates whose secret key is not encrypted are returned as they are.
+    pub fn decrypt(&self, passphrase: &str) -> Result<KeysCertificate, SecurityError> {
+        let cipher = match self.cipher {
+            Some(ref cipher) => cipher,
+            None => return Ok(self.clone()),
+        };
+        if cipher.cipher != seal::CIPHER || cipher.kdf != seal::KDF {
+            return Err(SecurityError::UnsupportedCipher(format!(
+                "{}/{}",
+                cipher.cipher, cipher.kdf
+            )));
+        }
+        let sealed = self
+            .secret_key
+            .as_ref()
+            .ok_or(SecurityError::MissingSecretKey)?;
+        let sealed = zmq::z85_decode(sealed).map_err(|_| SecurityError::InvalidKey)?;
+        let salt = zmq::z85_decode(&cipher.salt).map_err(|_| SecurityError::InvalidKey)?;
+        let nonce = zmq::z85_decode(&cipher.nonce).map_err(|_| SecurityError::InvalidKey)?;
+        if nonce.len() != 12 {
+            return Err(SecurityError::InvalidKey);
+        }
+        let mut nonce_bytes = [0u8; 12];
+        nonce_bytes.copy_from_slice(&nonce);
+        let key = seal::derive_key(passphrase.as_bytes(), &salt, cipher.iterations);
+        let secret_key = seal::open(&key, &nonce_bytes, self.public_key.as_bytes(), &sealed)
+            .ok_or(SecurityError::WrongPassphrase)?;
+        if secret_key.len() != 32 {
+            return Err(SecurityError::InvalidKey);
+        }
+        let mut secret_key_bytes = Zeroizing::new([0u8; 32]);
+        secret_key_bytes.copy_from_slice(&secret_key);
+        Ok(KeysCertificate {
+            secret_key: Some(encode_key(&secret_key_bytes)),
+            cipher: None,
+            ..self.clone()
+        })
+    }
+
+    /// Returns true if the certificate has no secret key.
+    pub fn is_public_only(&self) -> bool {
+        self.secret_key.is_none()
+    }
+
+    /// Returns the name of the certificate.
+    pub fn name(&self) -> Option<&str> {
+        self.metadata.get(CERT_NAME).map(|name| name.as_str())
+    }
+
+    /// Set the name of the certificate.
+    pub fn set_name(&mut self, name: &str) {
+        self.metadata
+            .insert(CERT_NAME.to_string(), name.to_string());
+    }
+
+    /// Returns the creation time of the certificate, if it has a valid one.
+    pub fn created(&self) -> Option<DateTime<Utc>> {
+        let created = self.metadata.get(CERT_CREATED)?;
+        DateTime::parse_from_rfc3339(created)
+            .ok()
+            .map(|dt| dt.with_timezone(&Utc))
+    }
+
+    /// Decode the public key, returning `SecurityError::InvalidKey` if it is not a
+    /// Z85-encoded 32-byte key.
+    pub fn public_key(&self) -> Result<[u8; 32], SecurityError> {
+        decode_key(&self.public_key)
+    }
+}
+
+/// Encode a key pair, stamping the certificate with its creation time.
+impl<'a> From<&'a CurveKeyPair> for KeysCertificate {
+    fn from(keys: &'a CurveKeyPair) -> KeysCertificate {
+        let mut metadata = BTreeMap::new();
+        metadata.insert(CERT_CREATED.to_string(), Utc::now().to_rfc3339());
+        KeysCertificate {
+            public_key: encode_key(&keys.public_key),
+            secret_key: Some(encode_key(&keys.secret_key)),
+            metadata,
+            cipher: None,
+        }
+    }
+}
+
+/// Decode the key pair, returning `SecurityError::InvalidKey` if either key is not a
+/// Z85-encoded 32-byte key, `SecurityError::MissingSecretKey` for public-only
+/// certificates, or `SecurityError::EncryptedSecretKey` for certificates that were not
+/// decrypted.
+impl<'a> TryFrom<&'a KeysCertificate> for CurveKeyPair {
+    type Error = SecurityError;
+
+    fn try_from(cert: &'a KeysCertificate) -> Result<CurveKeyPair, SecurityError> {
+        if cert.is_encrypted() {
+            return Err(SecurityError::EncryptedSecretKey);
+        }
+        let secret_key = cert
+            .secret_key
+            .as_ref()
+            .ok_or(SecurityError::MissingSecretKey)?;
+        Ok(CurveKeyPair {
+            public_key: decode_key(&cert.public_key)?,
+            secret_key: decode_key(secret_key)?,
+        })
+    }
+}
+
+impl TryFrom<KeysCertificate> for CurveKeyPair {
+    type Error = SecurityError;
+
+    fn try_from(cert: KeysCertificate) -> Result<CurveKeyPair, SecurityError> {
+        CurveKeyPair::try_from(&cert)
+    }
+}
+
+/// Encode a binary key with Z85.
+pub fn encode_key(key: &[u8; 32]) -> String {
+    // 32 bytes are always a multiple of the 4-byte Z85 blocks.
+    zmq::z85_encode(key).expect("32-byte keys are always Z85 encodable")
+}
+
+// Encode bytes whose length is a multiple of the 4-byte Z85 blocks.
+fn z85_encode(bytes: &[u8]) -> String {
+    zmq::z85_encode(bytes).expect("sealed secrets are always Z85 encodable")
+}
+
+// Returns random bytes, from the generator of the operating system.
+fn random_bytes(len: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; len];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Decode a Z85-encoded 32-byte key.
+pub fn decode_key(key: &str) -> Result<[u8; 32], SecurityError> {
+    let bytes = zmq::z85_decode(key).map_err(|_| SecurityError::InvalidKey)?;
+    if bytes.len() != 32 {
+        return Err(SecurityError::InvalidKey);
+    }
+    let mut key = [0; 32];
+    key.copy_from_slice(&bytes);
+    Ok(key)
+}
+
+/// Configure `socket` as a CURVE server, with its own key pair. Returns
+/// `SecurityError::UnsupportedCapability` if the local libzmq lacks CURVE.
+pub fn secure_curve_server(socket: &zmq::Socket, keys: &CurveKeyPair) -> Result<(), SecurityError> {
+    require_capability(Capability::Curve)?;
+    socket.set_curve_server(true)?;
+    socket.set_curve_secretkey(&keys.secret_key)?;
+    Ok(())
+}
+
+/// Configure `socket` as a CURVE client of the server with `server_key`. Returns
+/// `SecurityError::UnsupportedCapability` if the local libzmq lacks CURVE.
+pub fn secure_curve_client(
+    socket: &zmq::Socket,
+    server_key: &[u8; 32],
+    keys: &CurveKeyPair,
+) -> Result<(), SecurityError> {
+    require_capability(Capability::Curve)?;
+    socket.set_curve_serverkey(server_key)?;
+    socket.set_curve_publickey(&keys.public_key)?;
+    socket.set_curve_secretkey(&keys.secret_key)?;
+    Ok(())
+}
+
+/// Configure `socket` as a PLAIN server. Credentials of connecting clients are checked by
+/// the ZAP handler of the context, e.g. an `Authenticator`, for the given `domain`.
+pub fn secure_plain_server(socket: &zmq::Socket, domain: &str) -> Result<(), SecurityError> {
+    socket.set_plain_server(true)?;
+    socket.set_zap_domain(domain)?;
+    Ok(())
+}
+
+/// Configure `socket` as a PLAIN client, with the given credentials.
+pub fn secure_plain_client(
+    socket: &zmq::Socket,
+    username: &str,
+    password: &str,
+) -> Result<(), SecurityError> {
+    socket.set_plain_username(Some(username))?;
+    socket.set_plain_password(Some(password))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_sockets_have_their_mechanism_set() {
+        let ctx = zmq::Context::new();
+        let server = ctx.socket(zmq::PULL).unwrap();
+        secure_plain_server(&server, "neuras").unwrap();
+        assert!(server.is_plain_server().unwrap());
+        assert_eq!(server.get_zap_domain().unwrap().unwrap(), "neuras");
+        assert_eq!(server.get_mechanism().unwrap(), zmq::Mechanism::ZMQ_PLAIN);
+
+        let client = ctx.socket(zmq::PUSH).unwrap();
+        secure_plain_client(&client, "admin", "secret").unwrap();
+        assert!(!client.is_plain_server().unwrap());
+        assert_eq!(client.get_plain_username().unwrap().unwrap(), "admin");
+        assert_eq!(client.get_plain_password().unwrap().unwrap(), "secret");
+    }
+
+    #[test]
+    fn certificates_encode_keypairs() {
+        let cert = KeysCertificate::new().unwrap();
+        assert_eq!(cert.public_key.len(), 40);
+        assert!(cert.created().is_some());
+        let keys = CurveKeyPair::try_from(&cert).unwrap();
+        let encoded = KeysCertificate::from(&keys);
+        assert_eq!(encoded.public_key, cert.public_key);
+        assert_eq!(encoded.secret_key, cert.secret_key);
+        let invalid = KeysCertificate {
+            public_key: "short".into(),
+            ..cert.clone()
+        };
+        match CurveKeyPair::try_from(invalid) {
+            Err(SecurityError::InvalidKey) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn certificates_round_trip_through_files() {
+        use std::env;
+        #[cfg(unix)]
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = env::temp_dir().join(format!("neuras-cert-{}.toml", uuid::Uuid::new_v4()));
+        let mut cert = KeysCertificate::new().unwrap();
+        cert.set_name("server");
+        cert.save_to(&path).unwrap();
+        #[cfg(unix)]
+        {
+            let mode = fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+        let loaded = KeysCertificate::load_from(&path).unwrap();
+        assert_eq!(loaded, cert);
+        assert_eq!(loaded.name(), Some("server"));
+
+        let public = cert.public_only();
+        public.save_to(&path).unwrap();
+        assert!(!fs::read_to_string(&path).unwrap().contains("secret_key"));
+        let loaded = KeysCertificate::load_from(&path).unwrap();
+        assert!(loaded.is_public_only());
+        assert_eq!(loaded.metadata, cert.metadata);
+        assert_eq!(
+            loaded.public_key().unwrap(),
+            CurveKeyPair::try_from(&cert).unwrap().public_key
+        );
+        match CurveKeyPair::try_from(loaded) {
+            Err(SecurityError::MissingSecretKey) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn encrypted_certificates_unlock_with_their_passphrase() {
+        use std::env;
+
+        let path = env::temp_dir().join(format!("neuras-cert-{}.toml", uuid::Uuid::new_v4()));
+        let cert = KeysCertificate::new().unwrap();
+        let encrypted = cert.encrypt_with_iterations("hunter2", 10).unwrap();
+        assert!(encrypted.is_encrypted());
+        assert_ne!(encrypted.secret_key, cert.secret_key);
+        encrypted.save_to(&path).unwrap();
+        let saved = fs::read_to_string(&path).unwrap();
+        assert!(saved.contains("chacha20-poly1305"));
+        assert!(!saved.contains(cert.secret_key.as_ref().unwrap().as_str()));
+
+        match CurveKeyPair::try_from(KeysCertificate::load_from(&path).unwrap()) {
+            Err(SecurityError::EncryptedSecretKey) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        match KeysCertificate::load_with_passphrase(&path, "hunter3") {
+            Err(SecurityError::WrongPassphrase) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let unlocked = KeysCertificate::load_with_passphrase(&path, "hunter2").unwrap();
+        assert_eq!(unlocked, cert);
+        assert!(encrypted.public_only().is_public_only());
+        assert!(!encrypted.public_only().is_encrypted());
+
+        // secret keys can not be moved to another public key.
+        let other = KeysCertificate::new().unwrap();
+        let swapped = KeysCertificate {
+            public_key: other.public_key,
+            ..encrypted
+        };
+        match swapped.decrypt("hunter2") {
+            Err(SecurityError::WrongPassphrase) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn curve_clients_handshake_with_the_server_key() {
+        if !zmq::has("curve").unwrap_or(false) {
+            return;
+        }
+        let ctx = zmq::Context::new();
+        let server_keys = CurveKeyPair::new().unwrap();
+        let server = ctx.socket(zmq::PULL).unwrap();
+        secure_curve_server(&server, &server_keys).unwrap();
+        server.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = server.get_last_endpoint().unwrap().unwrap();
+
+        let client = ctx.socket(zmq::PUSH).unwrap();
+        let client_keys = CurveKeyPair::new().unwrap();
+        secure_curve_client(&client, &server_keys.public_key, &client_keys).unwrap();
+        client.connect(&endpoint).unwrap();
+        client.send("encrypted", 0).unwrap();
+        assert_eq!(server.recv_bytes(0).unwrap(), b"encrypted");
+    }
+}
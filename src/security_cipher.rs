@@ -0,0 +1,472 @@
+//! Sockets secured with CURVE.
+//!
+//! A `CipherReceiver` is a CURVE server that binds, and a `CipherSender` is a CURVE client
+//! that connects to it. Both can be turned into a `PollingSocket`, for use with a `Poller`,
+//! or into a `TokioSocket`, without losing their CURVE configuration.
+//!
+//! The handshakes of either may be monitored, see `monitor_handshakes`, and then
+//! `handshake_result` tells whether the latest handshake succeeded, or why it failed,
+//! instead of sends that hang on a server that keeps rejecting the client. The inner
+//! sockets of monitored ones must not be monitored otherwise.
+use super::super::endpoint::{Endpoint, ToEndpoint};
+use super::super::socket::ZmtpHeartbeat;
+use super::super::socket::{PollingSocket, ReconnectPolicy, SocketError, SocketWrapper};
+#[cfg(feature = "async-tokio")]
+use super::HandshakeFuture;
+use super::SecurityError;
+use super::{secure_curve_client, secure_curve_server, CurveKeyPair, KeysCertificate};
+use super::{Handshake, HandshakeMonitor};
+
+#[cfg(feature = "async-tokio")]
+use super::super::socket::tokio::TokioSocket;
+#[cfg(feature = "async-tokio")]
+use tokio_core::reactor::Handle;
+
+use std::convert::TryFrom;
+use std::io;
+use std::time::Duration;
+use zmq;
+
+// The inner socket of a cipher socket, along with the monitor of its handshakes, once they
+// are monitored.
+enum Inner {
+    Plain(zmq::Socket),
+    Monitored(HandshakeMonitor),
+}
+
+impl Inner {
+    fn get_ref(&self) -> &zmq::Socket {
+        match *self {
+            Inner::Plain(ref socket) => socket,
+            Inner::Monitored(ref monitor) => monitor.get_ref(),
+        }
+    }
+
+    fn into_inner(self) -> zmq::Socket {
+        match self {
+            Inner::Plain(socket) => socket,
+            Inner::Monitored(monitor) => monitor.into_inner(),
+        }
+    }
+
+    fn monitor(self, context: &zmq::Context) -> Result<Inner, SecurityError> {
+        match self {
+            Inner::Plain(socket) => Ok(Inner::Monitored(HandshakeMonitor::new(context, socket)?)),
+            monitored => Ok(monitored),
+        }
+    }
+
+    fn handshake_result(&self, timeout: Duration) -> Result<Handshake, SecurityError> {
+        match *self {
+            Inner::Plain(_) => Err(SecurityError::NotMonitored),
+            Inner::Monitored(ref monitor) => monitor.handshake_result(timeout),
+        }
+    }
+
+    #[cfg(feature = "async-tokio")]
+    fn into_future(self, timeout: Duration, handle: &Handle) -> io::Result<HandshakeFuture> {
+        match self {
+            Inner::Plain(_) => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                SecurityError::NotMonitored.to_string(),
+            )),
+            Inner::Monitored(monitor) => monitor.into_future(timeout, handle),
+        }
+    }
+}
+
+/// CURVE server socket, which binds to its endpoints.
+pub struct CipherReceiver {
+    context: zmq::Context,
+    socket: Inner,
+}
+
+impl CipherReceiver {
+    /// Create a new `CipherReceiver` of `socket_type`, with the key pair of `cert`.
+    pub fn new(
+        context: &zmq::Context,
+        socket_type: zmq::SocketType,
+        cert: &KeysCertificate,
+    ) -> Result<CipherReceiver, SecurityError> {
+        let keys = CurveKeyPair::try_from(cert)?;
+        let socket = context.socket(socket_type)?;
+        secure_curve_server(&socket, &keys)?;
+        Ok(CipherReceiver {
+            context: context.clone(),
+            socket: Inner::Plain(socket),
+        })
+    }
+
+    /// Monitor the handshakes with clients, for `handshake_result`. Their results queue
+    /// until they are read, so they should be read as clients connect.
+    pub fn monitor_handshakes(self) -> Result<Self, SecurityError> {
+        Ok(CipherReceiver {
+            socket: self.socket.monitor(&self.context)?,
+            context: self.context,
+        })
+    }
+
+    /// Set the ZMTP 3.1 heartbeats of the connections, see `ZmtpHeartbeat`. Must be called
+    /// before it binds.
+    pub fn set_heartbeat(
+        &self,
+        interval: Duration,
+        timeout: Duration,
+        ttl: Duration,
+    ) -> Result<(), SocketError> {
+        ZmtpHeartbeat::new(interval, timeout, ttl).apply(self.socket.get_ref())?;
+        Ok(())
+    }
+
+    /// Bind to `endpoint`, returning the endpoint that was actually bound.
+    pub fn bind<E: ToEndpoint>(&self, endpoint: E) -> Result<Endpoint, SocketError> {
+        self.bind_resolved(&endpoint.to_endpoint()?)
+    }
+
+    /// Returns the result of the latest handshake with a client since the last call, or
+    /// waits up to `timeout` for the next one. Fails with `SecurityError::NotMonitored`
+    /// unless the handshakes are monitored.
+    pub fn handshake_result(&self, timeout: Duration) -> Result<Handshake, SecurityError> {
+        self.socket.handshake_result(timeout)
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        self.socket.get_ref()
+    }
+
+    /// Returns the inner, blocking, socket.
+    pub fn into_socket(self) -> zmq::Socket {
+        self.socket.into_inner()
+    }
+
+    /// Returns a `PollingSocket`, which can be registered with a `Poller`.
+    pub fn into_polling(self) -> PollingSocket {
+        PollingSocket::new(self.socket.into_inner())
+    }
+
+    /// Returns a `TokioSocket`, registered with the reactor of `handle`.
+    #[cfg(feature = "async-tokio")]
+    pub fn into_tokio(self, handle: &Handle) -> io::Result<TokioSocket> {
+        TokioSocket::new(self.socket.into_inner(), handle)
+    }
+
+    /// Returns a future of the result of the latest handshake, or of the next one, which
+    /// resolves to a `TokioSocket`, registered with the reactor of `handle`, and the
+    /// result. Fails if the handshakes are not monitored, or if no handshake ends before
+    /// `timeout`.
+    #[cfg(feature = "async-tokio")]
+    pub fn into_tokio_handshake(
+        self,
+        timeout: Duration,
+        handle: &Handle,
+    ) -> io::Result<HandshakeFuture> {
+        self.socket.into_future(timeout, handle)
+    }
+}
+
+impl SocketWrapper for CipherReceiver {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        self.socket.get_ref()
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.socket.get_ref().get_rcvmore().map_err(|e| e.into())
+    }
+}
+
+/// CURVE client socket, which connects to a `CipherReceiver`.
+pub struct CipherSender {
+    context: zmq::Context,
+    socket: Inner,
+    reconnect: Option<ReconnectPolicy>,
+}
+
+impl CipherSender {
+    /// Create a new `CipherSender` of `socket_type`, with the key pair of `cert`, for the
+    /// server with the (possibly public-only) `server` certificate.
+    pub fn new(
+        context: &zmq::Context,
+        socket_type: zmq::SocketType,
+        server: &KeysCertificate,
+        cert: &KeysCertificate,
+    ) -> Result<CipherSender, SecurityError> {
+        let server_key = server.public_key()?;
+        let keys = CurveKeyPair::try_from(cert)?;
+        let socket = context.socket(socket_type)?;
+        secure_curve_client(&socket, &server_key, &keys)?;
+        Ok(CipherSender {
+            context: context.clone(),
+            socket: Inner::Plain(socket),
+            reconnect: None,
+        })
+    }
+
+    /// Monitor the handshakes with servers, for `handshake_result`. Their results queue
+    /// until they are read, so they should be read as the sender connects.
+    pub fn monitor_handshakes(self) -> Result<Self, SecurityError> {
+        Ok(CipherSender {
+            socket: self.socket.monitor(&self.context)?,
+            context: self.context,
+            reconnect: self.reconnect,
+        })
+    }
+
+    /// Set the reconnect intervals of the socket, and retry connects that fail with the
+    /// backoff of `policy`. Must be called before it connects.
+    pub fn set_reconnect(&mut self, policy: ReconnectPolicy) -> Result<(), SocketError> {
+        policy.apply(self.socket.get_ref())?;
+        self.reconnect = Some(policy);
+        Ok(())
+    }
+
+    /// Set the ZMTP 3.1 heartbeats of the connections, see `ZmtpHeartbeat`. Must be called
+    /// before it connects.
+    pub fn set_heartbeat(
+        &self,
+        interval: Duration,
+        timeout: Duration,
+        ttl: Duration,
+    ) -> Result<(), SocketError> {
+        ZmtpHeartbeat::new(interval, timeout, ttl).apply(self.socket.get_ref())?;
+        Ok(())
+    }
+
+    /// Connect to `endpoint`.
+    pub fn connect<E: ToEndpoint>(&self, endpoint: E) -> Result<(), SocketError> {
+        let endpoint = endpoint.to_endpoint()?.to_string();
+        match self.reconnect {
+            Some(ref policy) => policy.connect(self.socket.get_ref(), &endpoint)?,
+            None => self.socket.get_ref().connect(&endpoint)?,
+        }
+        Ok(())
+    }
+
+    /// Returns the result of the latest handshake with a server since the last call, e.g.
+    /// after it connects, or reconnects, or waits up to `timeout` for the next one. Fails
+    /// with `SecurityError::NotMonitored` unless the handshakes are monitored.
+    pub fn handshake_result(&self, timeout: Duration) -> Result<Handshake, SecurityError> {
+        self.socket.handshake_result(timeout)
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        self.socket.get_ref()
+    }
+
+    /// Returns the inner, blocking, socket.
+    pub fn into_socket(self) -> zmq::Socket {
+        self.socket.into_inner()
+    }
+
+    /// Returns a `PollingSocket`, which can be registered with a `Poller`.
+    pub fn into_polling(self) -> PollingSocket {
+        PollingSocket::new(self.socket.into_inner())
+    }
+
+    /// Returns a `TokioSocket`, registered with the reactor of `handle`.
+    #[cfg(feature = "async-tokio")]
+    pub fn into_tokio(self, handle: &Handle) -> io::Result<TokioSocket> {
+        TokioSocket::new(self.socket.into_inner(), handle)
+    }
+
+    /// Returns a future of the result of the latest handshake, or of the next one, which
+    /// resolves to a `TokioSocket`, registered with the reactor of `handle`, and the
+    /// result. Fails if the handshakes are not monitored, or if no handshake ends before
+    /// `timeout`.
+    #[cfg(feature = "async-tokio")]
+    pub fn into_tokio_handshake(
+        self,
+        timeout: Duration,
+        handle: &Handle,
+    ) -> io::Result<HandshakeFuture> {
+        self.socket.into_future(timeout, handle)
+    }
+}
+
+impl SocketWrapper for CipherSender {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        self.socket.get_ref()
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.socket.get_ref().get_rcvmore().map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::socket::{SocketRecv, SocketSend};
+    use super::*;
+
+    fn cipher_pair(ctx: &zmq::Context) -> Option<(CipherReceiver, CipherSender)> {
+        if !zmq::has("curve").unwrap_or(false) {
+            return None;
+        }
+        let server_cert = KeysCertificate::new().unwrap();
+        let receiver = CipherReceiver::new(ctx, zmq::PULL, &server_cert)
+            .unwrap()
+            .monitor_handshakes()
+            .unwrap();
+        let endpoint = receiver.bind("tcp://127.0.0.1:*").unwrap();
+        assert!(!endpoint.has_wildcard_port());
+
+        let client_cert = KeysCertificate::new().unwrap();
+        let sender =
+            CipherSender::new(ctx, zmq::PUSH, &server_cert.public_only(), &client_cert).unwrap();
+        let sender = sender.monitor_handshakes().unwrap();
+        sender.connect(&endpoint).unwrap();
+        Some((receiver, sender))
+    }
+
+    #[test]
+    fn cipher_sockets_need_secret_keys() {
+        let ctx = zmq::Context::new();
+        let public = KeysCertificate::new().unwrap().public_only();
+        match CipherReceiver::new(&ctx, zmq::PULL, &public) {
+            Err(SecurityError::MissingSecretKey) => {}
+            _ => panic!("public-only certificates can't be used for receivers"),
+        }
+    }
+
+    #[test]
+    fn cipher_sockets_set_heartbeats() {
+        if !zmq::has("curve").unwrap_or(false) {
+            return;
+        }
+        let ctx = zmq::Context::new();
+        let cert = KeysCertificate::new().unwrap();
+        let receiver = CipherReceiver::new(&ctx, zmq::PULL, &cert).unwrap();
+        let sender = CipherSender::new(&ctx, zmq::PUSH, &cert.public_only(), &cert).unwrap();
+        let (interval, timeout) = (Duration::from_millis(500), Duration::from_secs(2));
+        receiver
+            .set_heartbeat(interval, timeout, Duration::from_secs(0))
+            .unwrap();
+        sender
+            .set_heartbeat(interval, timeout, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(receiver.get_ref().get_heartbeat_ivl().unwrap(), 500);
+        assert_eq!(sender.get_ref().get_heartbeat_timeout().unwrap(), 2_000);
+        assert_eq!(sender.get_ref().get_heartbeat_ttl().unwrap(), 5_000);
+        match receiver.handshake_result(Duration::from_millis(0)) {
+            Err(SecurityError::NotMonitored) => {}
+            other => panic!("unexpected handshake {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cipher_sockets_report_their_handshakes() {
+        use super::super::{HandshakeFailure, ProtocolError};
+
+        let ctx = zmq::Context::new();
+        let (receiver, sender) = match cipher_pair(&ctx) {
+            Some(pair) => pair,
+            None => return,
+        };
+        let timeout = Duration::from_secs(5);
+        assert!(sender.handshake_result(timeout).unwrap().is_succeeded());
+        assert!(receiver.handshake_result(timeout).unwrap().is_succeeded());
+
+        // a client that was given the wrong server key.
+        let endpoint = receiver.get_ref().get_last_endpoint().unwrap().unwrap();
+        let wrong = KeysCertificate::new().unwrap();
+        let intruder = CipherSender::new(&ctx, zmq::PUSH, &wrong, &wrong)
+            .unwrap()
+            .monitor_handshakes()
+            .unwrap();
+        intruder.get_ref().set_linger(0).unwrap();
+        intruder.connect(endpoint.as_str()).unwrap();
+        match intruder.handshake_result(timeout).unwrap() {
+            Handshake::Failed { reason, .. } => assert_eq!(reason, HandshakeFailure::NoDetail),
+            other => panic!("unexpected handshake {:?}", other),
+        }
+        match receiver.handshake_result(timeout).unwrap() {
+            Handshake::Failed { reason, .. } => assert_eq!(
+                reason,
+                HandshakeFailure::Protocol(ProtocolError::Cryptographic)
+            ),
+            other => panic!("unexpected handshake {:?}", other),
+        }
+        match receiver.handshake_result(Duration::from_millis(0)) {
+            Err(SecurityError::HandshakeTimeout) | Ok(Handshake::Failed { .. }) => {}
+            other => panic!("unexpected handshake {:?}", other),
+        }
+    }
+
+    #[test]
+    fn cipher_sockets_keep_curve_as_polling_sockets() {
+        let ctx = zmq::Context::new();
+        let (receiver, sender) = match cipher_pair(&ctx) {
+            Some(pair) => pair,
+            None => return,
+        };
+        let receiver = receiver.into_polling();
+        let sender = sender.into_polling();
+        assert!(receiver.get_socket_ref().is_curve_server().unwrap());
+        SocketSend::send(&sender, "polled", 0).unwrap();
+        // polling sockets never block, so wait for the handshake, and the message.
+        assert_eq!(receiver.get_socket_ref().poll(zmq::POLLIN, 5_000).unwrap(), 1);
+        assert_eq!(SocketRecv::recv_bytes(&receiver, 0).unwrap(), b"polled");
+    }
+
+    #[cfg(feature = "async-tokio")]
+    #[test]
+    fn cipher_sockets_keep_curve_as_tokio_sockets() {
+        use tokio_core::reactor::Core;
+
+        let ctx = zmq::Context::new();
+        let (receiver, sender) = match cipher_pair(&ctx) {
+            Some(pair) => pair,
+            None => return,
+        };
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let receiver = receiver.into_tokio(&handle).unwrap();
+        let sender = sender.into_tokio(&handle).unwrap();
+        assert!(receiver.get_socket_ref().is_curve_server().unwrap());
+        core.run(sender.send("futures", 0)).unwrap();
+        let msg = core.run(receiver.recv_multipart(0)).unwrap();
+        assert_eq!(msg.len(), 1);
+        assert_eq!(&msg[0][..], b"futures");
+    }
+
+    #[cfg(feature = "async-tokio")]
+    #[test]
+    fn cipher_sockets_resolve_handshakes_as_futures() {
+        use tokio_core::reactor::Core;
+
+        if !zmq::has("curve").unwrap_or(false) {
+            return;
+        }
+        let ctx = zmq::Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let server_cert = KeysCertificate::new().unwrap();
+        let receiver = CipherReceiver::new(&ctx, zmq::PULL, &server_cert).unwrap();
+        let endpoint = receiver.bind("tcp://127.0.0.1:*").unwrap();
+        let cert = KeysCertificate::new().unwrap();
+        let sender = CipherSender::new(&ctx, zmq::PUSH, &server_cert, &cert).unwrap();
+        let sender = sender.monitor_handshakes().unwrap();
+        sender.connect(&endpoint).unwrap();
+        let handshake = sender
+            .into_tokio_handshake(Duration::from_secs(5), &handle)
+            .unwrap();
+        let (sender, handshake) = core.run(handshake).unwrap();
+        assert!(handshake.is_succeeded());
+        assert_eq!(handshake.endpoint(), endpoint.to_string());
+        core.run(sender.send("secured", 0)).unwrap();
+        let receiver = receiver.into_socket();
+        assert_eq!(receiver.recv_bytes(0).unwrap(), b"secured");
+
+        let silent = CipherSender::new(&ctx, zmq::PUSH, &server_cert, &cert).unwrap();
+        let handshake = silent
+            .monitor_handshakes()
+            .unwrap()
+            .into_tokio_handshake(Duration::from_millis(50), &handle)
+            .unwrap();
+        match core.run(handshake) {
+            Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {}
+            _ => panic!("handshakes time out without a server"),
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! Results of CURVE handshakes.
+//!
+//! A CURVE connection that fails its handshake is closed, and retried, by libzmq, without
+//! telling the application: messages sent to it wait in the queue, or are dropped, and
+//! receivers never hear from it. A `HandshakeMonitor` watches the handshake events of a
+//! socket, and reports whether each handshake succeeded, or why it failed. Sockets that are
+//! owned elsewhere, e.g. the service socket of an actor, are watched with
+//! `monitor_handshakes`, and their events read with `HandshakeEvents`.
+//!
+//! Monitoring is opt-in: libzmq queues the events until they are read, and blocks once the
+//! queue is full, so they should be read as the handshakes happen. Reading them keeps only
+//! the latest result, and drops the older ones.
+//!
+//! The reasons that are reported differ on each side. Both learn of the ZAP status code
+//! when a client is denied, e.g. by an `Authenticator`. A server learns of the ZMTP
+//! protocol error, e.g. `ProtocolError::Cryptographic` when a client used the wrong server
+//! key, while the client is only told that the server closed the connection, i.e.
+//! `HandshakeFailure::NoDetail`.
+use super::SecurityError;
+
+#[cfg(feature = "async-tokio")]
+use super::super::socket::tokio::TokioSocket;
+#[cfg(feature = "async-tokio")]
+use super::super::socket::SocketRecv;
+#[cfg(feature = "async-tokio")]
+use futures::{Async, Future, Poll};
+#[cfg(feature = "async-tokio")]
+use tokio_core::reactor::{Handle, Timeout};
+
+use std::io;
+use std::ptr;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use zmq;
+use zmq_sys;
+
+// Events of the handshakes of a socket.
+const HANDSHAKE_EVENTS: i32 = zmq::SocketEvent::HANDSHAKE_SUCCEEDED as i32
+    | zmq::SocketEvent::HANDSHAKE_FAILED_NO_DETAIL as i32
+    | zmq::SocketEvent::HANDSHAKE_FAILED_PROTOCOL as i32
+    | zmq::SocketEvent::HANDSHAKE_FAILED_AUTH as i32;
+
+/// Result of a handshake with a peer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Handshake {
+    /// The handshake with the peer at `endpoint` succeeded.
+    Succeeded { endpoint: String },
+    /// The handshake with the peer at `endpoint` failed, for `reason`.
+    Failed {
+        endpoint: String,
+        reason: HandshakeFailure,
+    },
+}
+
+impl Handshake {
+    /// Returns true if the handshake succeeded.
+    pub fn is_succeeded(&self) -> bool {
+        matches!(*self, Handshake::Succeeded { .. })
+    }
+
+    /// Returns the endpoint of the peer.
+    pub fn endpoint(&self) -> &str {
+        match *self {
+            Handshake::Succeeded { ref endpoint } | Handshake::Failed { ref endpoint, .. } => {
+                endpoint
+            }
+        }
+    }
+}
+
+/// Reason of a failed handshake.
+#[derive(Clone, Debug, Fail, PartialEq)]
+pub enum HandshakeFailure {
+    /// The connection was closed during the handshake, with no further details, as clients
+    /// are told when the server rejects them.
+    #[fail(display = "handshake failed with no details")]
+    NoDetail,
+    /// The peer broke the ZMTP protocol, or the security mechanism, e.g. with a wrong key.
+    #[fail(display = "handshake failed with ZMTP error: {:?}", _0)]
+    Protocol(ProtocolError),
+    /// The ZAP handler denied the peer, with the status code of its reply, e.g. 400.
+    #[fail(display = "handshake failed with ZAP status {}", _0)]
+    Auth(u32),
+}
+
+/// ZMTP, and ZAP, protocol errors of failed handshakes.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ProtocolError {
+    Unspecified,
+    UnexpectedCommand,
+    InvalidSequence,
+    KeyExchange,
+    /// A command of the handshake was malformed.
+    MalformedCommand,
+    InvalidMetadata,
+    /// A command could not be decrypted, e.g. because the client used the wrong server key.
+    Cryptographic,
+    /// The peers use different security mechanisms, e.g. CURVE and NULL.
+    MechanismMismatch,
+    ZapUnspecified,
+    ZapMalformedReply,
+    ZapBadRequestId,
+    ZapBadVersion,
+    ZapInvalidStatusCode,
+    ZapInvalidMetadata,
+    /// An error that is unknown to this version.
+    Other(u32),
+}
+
+impl ProtocolError {
+    /// Returns the error of a `ZMQ_PROTOCOL_ERROR_*` code.
+    pub fn from_code(code: u32) -> ProtocolError {
+        match code {
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_UNSPECIFIED => ProtocolError::Unspecified,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_UNEXPECTED_COMMAND => ProtocolError::UnexpectedCommand,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_SEQUENCE => ProtocolError::InvalidSequence,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_KEY_EXCHANGE => ProtocolError::KeyExchange,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_UNSPECIFIED
+                ..=zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_WELCOME => {
+                ProtocolError::MalformedCommand
+            }
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_INVALID_METADATA => ProtocolError::InvalidMetadata,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_CRYPTOGRAPHIC => ProtocolError::Cryptographic,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_MECHANISM_MISMATCH => ProtocolError::MechanismMismatch,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZAP_UNSPECIFIED => ProtocolError::ZapUnspecified,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZAP_MALFORMED_REPLY => ProtocolError::ZapMalformedReply,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZAP_BAD_REQUEST_ID => ProtocolError::ZapBadRequestId,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZAP_BAD_VERSION => ProtocolError::ZapBadVersion,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZAP_INVALID_STATUS_CODE => {
+                ProtocolError::ZapInvalidStatusCode
+            }
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZAP_INVALID_METADATA => ProtocolError::ZapInvalidMetadata,
+            code => ProtocolError::Other(code),
+        }
+    }
+}
+
+// Returns the handshake of a monitor event, or `None` for other events.
+fn parse_event(msg: &[Vec<u8>]) -> Option<Handshake> {
+    if msg.len() < 2 || msg[0].len() < 6 {
+        return None;
+    }
+    let event = u16::from(msg[0][0]) | (u16::from(msg[0][1]) << 8);
+    let mut value = [0; 4];
+    value.copy_from_slice(&msg[0][2..6]);
+    let value = u32::from_le_bytes(value);
+    let endpoint = String::from_utf8_lossy(&msg[1]).into_owned();
+    let reason = match zmq::SocketEvent::from_raw(event) {
+        zmq::SocketEvent::HANDSHAKE_SUCCEEDED => return Some(Handshake::Succeeded { endpoint }),
+        zmq::SocketEvent::HANDSHAKE_FAILED_NO_DETAIL => HandshakeFailure::NoDetail,
+        zmq::SocketEvent::HANDSHAKE_FAILED_PROTOCOL => {
+            HandshakeFailure::Protocol(ProtocolError::from_code(value))
+        }
+        zmq::SocketEvent::HANDSHAKE_FAILED_AUTH => HandshakeFailure::Auth(value),
+        _ => return None,
+    };
+    Some(Handshake::Failed { endpoint, reason })
+}
+
+/// Send the handshake events of `socket` to the `HandshakeEvents` connected to `endpoint`.
+/// The socket must not be monitored otherwise, as libzmq keeps a single monitor for each
+/// socket.
+pub fn monitor_handshakes(socket: &zmq::Socket, endpoint: &str) -> Result<(), SecurityError> {
+    socket.monitor(endpoint, HANDSHAKE_EVENTS)?;
+    Ok(())
+}
+
+/// Reader of the handshake events of a socket, see `monitor_handshakes`.
+pub struct HandshakeEvents {
+    monitor: zmq::Socket,
+}
+
+impl HandshakeEvents {
+    /// Connect to the events sent to `endpoint`, in `context`, which may be done before
+    /// the socket is monitored.
+    pub fn connect(context: &zmq::Context, endpoint: &str) -> Result<Self, SecurityError> {
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(endpoint)?;
+        Ok(HandshakeEvents { monitor })
+    }
+
+    /// Returns the result of the latest handshake that ended since the last call, or waits
+    /// up to `timeout` for the next one. Fails with `SecurityError::HandshakeTimeout` if no
+    /// handshake ended in time.
+    pub fn handshake_result(&self, timeout: Duration) -> Result<Handshake, SecurityError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(handshake) = self.try_handshake_result()? {
+                return Ok(handshake);
+            }
+            let left = deadline.saturating_duration_since(Instant::now());
+            let millis = left.as_millis().min(i64::MAX as u128) as i64;
+            if self.monitor.poll(zmq::POLLIN, millis)? == 0 {
+                return Err(SecurityError::HandshakeTimeout);
+            }
+        }
+    }
+
+    /// Returns the result of the latest handshake that ended since the last call, without
+    /// blocking. The results of the handshakes that ended before it are dropped.
+    pub fn try_handshake_result(&self) -> io::Result<Option<Handshake>> {
+        let mut latest = None;
+        loop {
+            let msg = match self.monitor.recv_multipart(zmq::DONTWAIT) {
+                Ok(msg) => msg,
+                Err(zmq::Error::EAGAIN) => return Ok(latest),
+                Err(e) => return Err(e.into()),
+            };
+            if let Some(handshake) = parse_event(&msg) {
+                nlog!(debug, "handshake {:?}", handshake);
+                latest = Some(handshake);
+            }
+        }
+    }
+
+    /// Returns a reference to the socket of the events, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.monitor
+    }
+}
+
+// Stop monitoring `socket`, which is not wrapped by the `zmq` crate.
+fn stop_monitor(socket: &mut zmq::Socket) {
+    unsafe {
+        zmq_sys::zmq_socket_monitor(socket.as_mut_ptr(), ptr::null(), 0);
+    }
+}
+
+/// A socket, along with the monitor of its handshakes.
+///
+/// libzmq blocks while sending monitor events that have no reader, so the socket is only
+/// released, or dropped, once it is no longer monitored. It must not be monitored
+/// otherwise, as libzmq keeps a single monitor for each socket.
+pub struct HandshakeMonitor {
+    socket: Option<zmq::Socket>,
+    events: Option<HandshakeEvents>,
+}
+
+impl HandshakeMonitor {
+    /// Start monitoring the handshakes of `socket`, in `context`.
+    pub fn new(context: &zmq::Context, socket: zmq::Socket) -> Result<Self, SecurityError> {
+        let endpoint = format!(
+            "inproc://neuras.handshake.monitor.{}",
+            Uuid::new_v4().to_simple()
+        );
+        monitor_handshakes(&socket, &endpoint)?;
+        let events = HandshakeEvents::connect(context, &endpoint)?;
+        Ok(HandshakeMonitor {
+            socket: Some(socket),
+            events: Some(events),
+        })
+    }
+
+    /// Returns a reference to the monitored socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        self.socket.as_ref().unwrap()
+    }
+
+    /// Returns the result of the latest handshake that ended since the last call, or waits
+    /// up to `timeout` for the next one, see `HandshakeEvents::handshake_result`.
+    pub fn handshake_result(&self, timeout: Duration) -> Result<Handshake, SecurityError> {
+        self.events().handshake_result(timeout)
+    }
+
+    /// Returns the result of the latest handshake that ended since the last call, without
+    /// blocking.
+    pub fn try_handshake_result(&self) -> io::Result<Option<Handshake>> {
+        self.events().try_handshake_result()
+    }
+
+    fn events(&self) -> &HandshakeEvents {
+        self.events.as_ref().unwrap()
+    }
+
+    /// Stop monitoring, and return the socket.
+    pub fn into_inner(mut self) -> zmq::Socket {
+        let mut socket = self.socket.take().unwrap();
+        stop_monitor(&mut socket);
+        socket
+    }
+
+    /// Returns a future of the result of the latest handshake that ended, or of the next
+    /// one, which resolves to the socket,
+    /// no longer monitored, as a `TokioSocket` registered with the reactor of `handle`, and
+    /// the result. Fails with an error of kind `TimedOut` if no handshake ends before
+    /// `timeout`.
+    #[cfg(feature = "async-tokio")]
+    pub fn into_future(
+        mut self,
+        timeout: Duration,
+        handle: &Handle,
+    ) -> io::Result<HandshakeFuture> {
+        let monitor = TokioSocket::new(self.events.take().unwrap().monitor, handle)?;
+        Ok(HandshakeFuture {
+            socket: self.socket.take(),
+            monitor,
+            timeout: Timeout::new(timeout, handle)?,
+            handle: handle.clone(),
+        })
+    }
+}
+
+impl Drop for HandshakeMonitor {
+    fn drop(&mut self) {
+        if let Some(ref mut socket) = self.socket {
+            stop_monitor(socket);
+        }
+    }
+}
+
+/// A future of the result of the next handshake of a socket, see
+/// `HandshakeMonitor::into_future`.
+#[cfg(feature = "async-tokio")]
+pub struct HandshakeFuture {
+    socket: Option<zmq::Socket>,
+    monitor: TokioSocket,
+    timeout: Timeout,
+    handle: Handle,
+}
+
+#[cfg(feature = "async-tokio")]
+impl Future for HandshakeFuture {
+    type Item = (TokioSocket, Handshake);
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let mut latest = None;
+        loop {
+            match SocketRecv::recv_multipart(&self.monitor, zmq::DONTWAIT) {
+                Ok(msg) => latest = parse_event(&msg).or(latest),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(handshake) = latest {
+            let mut socket = self.socket.take().unwrap();
+            stop_monitor(&mut socket);
+            let socket = TokioSocket::new(socket, &self.handle)?;
+            return Ok(Async::Ready((socket, handshake)));
+        }
+        match self.timeout.poll()? {
+            Async::Ready(()) => Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "no handshake before the timeout",
+            )),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+impl Drop for HandshakeFuture {
+    fn drop(&mut self) {
+        if let Some(ref mut socket) = self.socket {
+            stop_monitor(socket);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event: zmq::SocketEvent, value: u32) -> Vec<Vec<u8>> {
+        let mut frame = event.to_raw().to_le_bytes().to_vec();
+        frame.extend_from_slice(&value.to_le_bytes());
+        vec![frame, b"tcp://127.0.0.1:5555".to_vec()]
+    }
+
+    #[test]
+    fn handshake_events_carry_their_reason() {
+        assert_eq!(
+            parse_event(&event(zmq::SocketEvent::HANDSHAKE_SUCCEEDED, 0)),
+            Some(Handshake::Succeeded {
+                endpoint: "tcp://127.0.0.1:5555".to_string()
+            })
+        );
+        let failed = parse_event(&event(
+            zmq::SocketEvent::HANDSHAKE_FAILED_PROTOCOL,
+            zmq_sys::ZMQ_PROTOCOL_ERROR_ZMTP_MALFORMED_COMMAND_HELLO,
+        ))
+        .unwrap();
+        assert!(!failed.is_succeeded());
+        assert_eq!(failed.endpoint(), "tcp://127.0.0.1:5555");
+        assert_eq!(
+            failed,
+            Handshake::Failed {
+                endpoint: "tcp://127.0.0.1:5555".to_string(),
+                reason: HandshakeFailure::Protocol(ProtocolError::MalformedCommand),
+            }
+        );
+        match parse_event(&event(zmq::SocketEvent::HANDSHAKE_FAILED_AUTH, 400)) {
+            Some(Handshake::Failed { reason, .. }) => {
+                assert_eq!(reason, HandshakeFailure::Auth(400))
+            }
+            other => panic!("unexpected handshake {:?}", other),
+        }
+        assert_eq!(ProtocolError::from_code(7), ProtocolError::Other(7));
+        assert_eq!(parse_event(&event(zmq::SocketEvent::CONNECTED, 0)), None);
+    }
+}
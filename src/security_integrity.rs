@@ -0,0 +1,366 @@
+//! Integrity of messages, for connections that are not secured with CURVE.
+//!
+//! An `IntegrityEnvelope` appends a trailer frame to every message that it seals, and
+//! checks, and strips, it from every message that it opens. A CRC-32 detects corruption;
+//! an HMAC-SHA256, under a key that peers share, also rejects the traffic of peers without
+//! the key, e.g. of other applications that connect to a shared endpoint. Neither encrypts
+//! messages, nor detects replays.
+//!
+//! The trailer starts with a byte for the check:
+//!
+//! * `1`, followed by the big-endian CRC-32 of the message.
+//! * `2`, followed by the id of the key, a big-endian `u32`, and the HMAC of the message.
+//!
+//! Every frame is checked along with its size, so that bytes moved from a frame to the
+//! next are detected.
+//!
+//! ```
+//! use neuras::security::{IntegrityEnvelope, IntegrityKeys};
+//!
+//! let keys = IntegrityKeys::new(1, b"shared secret");
+//! let envelope = IntegrityEnvelope::hmac(keys.clone());
+//! let sealed = envelope.seal_multipart(vec!["ping"]);
+//! assert_eq!(sealed.len(), 2);
+//! assert_eq!(envelope.open_multipart(sealed).unwrap(), vec![b"ping".to_vec()]);
+//! ```
+use super::super::socket::{SocketRecv, SocketSend};
+use super::{SecurityError, KEY_GRACE_PERIOD};
+
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// First byte of the trailers of CRC-32 checks.
+pub const CHECK_CRC32: u8 = 1;
+/// First byte of the trailers of HMAC-SHA256 checks.
+pub const CHECK_HMAC: u8 = 2;
+
+const CRC32_TRAILER_SIZE: usize = 5;
+const HMAC_TRAILER_SIZE: usize = 37;
+
+// HMAC-SHA256, keeping the state of the padded key, to be cloned for every message.
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac_key(key: &[u8]) -> Arc<HmacSha256> {
+    Arc::new(HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size"))
+}
+
+// Returns the HMAC of `data`, under `key`.
+fn mac(key: &HmacSha256, data: &[u8]) -> HmacSha256 {
+    let mut mac = key.clone();
+    mac.update(data);
+    mac
+}
+
+// Frames, each preceded by its size, as they are checked.
+fn framed<T: AsRef<[u8]>>(frames: &[T]) -> Vec<u8> {
+    let size = frames.iter().map(|f| f.as_ref().len() + 4).sum();
+    let mut data = Vec::with_capacity(size);
+    for frame in frames {
+        let frame = frame.as_ref();
+        data.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+        data.extend_from_slice(frame);
+    }
+    data
+}
+
+type RotationHook = Arc<dyn Fn(u32) + Send + Sync>;
+
+struct KeySet {
+    current: (u32, Arc<HmacSha256>),
+    previous: Vec<(u32, Arc<HmacSha256>, Instant)>,
+    grace: i64,
+    hooks: Vec<RotationHook>,
+}
+
+impl KeySet {
+    fn expire(&mut self) {
+        let now = Instant::now();
+        self.previous.retain(|&(_, _, until)| until > now);
+    }
+}
+
+/// Keys of HMAC checks, by id, shared by every clone.
+///
+/// Messages are sealed with the current key. When it is rotated, the previous key is
+/// still accepted for a grace period, so that peers have time to pick up the new one.
+#[derive(Clone)]
+pub struct IntegrityKeys {
+    keys: Arc<Mutex<KeySet>>,
+}
+
+impl IntegrityKeys {
+    /// Create keys, with `key` as the current one, under `id`.
+    pub fn new(id: u32, key: &[u8]) -> IntegrityKeys {
+        IntegrityKeys {
+            keys: Arc::new(Mutex::new(KeySet {
+                current: (id, hmac_key(key)),
+                previous: Vec::new(),
+                grace: KEY_GRACE_PERIOD,
+                hooks: Vec::new(),
+            })),
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, KeySet> {
+        self.keys.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Returns the id of the current key.
+    pub fn current_id(&self) -> u32 {
+        self.lock().current.0
+    }
+
+    /// Returns the grace period, in milliseconds, of rotated keys.
+    pub fn grace_period(&self) -> i64 {
+        self.lock().grace
+    }
+
+    /// Set the grace period, in milliseconds, of the keys rotated from now on.
+    pub fn set_grace_period(&self, grace: i64) {
+        self.lock().grace = grace;
+    }
+
+    /// Returns true if messages sealed with the key of `id` are accepted.
+    pub fn accepts(&self, id: u32) -> bool {
+        self.key(id).is_some()
+    }
+
+    /// Call `hook` with the id of the new key, after every rotation, e.g. to hand the key
+    /// to peers.
+    pub fn on_rotate<F>(&self, hook: F)
+    where
+        F: Fn(u32) + Send + Sync + 'static,
+    {
+        self.lock().hooks.push(Arc::new(hook));
+    }
+
+    /// Make `key` the current key, under `id`, keeping the previous one for the grace
+    /// period. Fails if `id` is the id of a key that is still accepted.
+    pub fn rotate(&self, id: u32, key: &[u8]) -> Result<(), SecurityError> {
+        let hooks = {
+            let mut keys = self.lock();
+            keys.expire();
+            if keys.current.0 == id || keys.previous.iter().any(|k| k.0 == id) {
+                return Err(SecurityError::DuplicateKeyId(id));
+            }
+            let until = Instant::now() + Duration::from_millis(keys.grace.max(0) as u64);
+            let (old_id, old_key) = ::std::mem::replace(&mut keys.current, (id, hmac_key(key)));
+            keys.previous.push((old_id, old_key, until));
+            keys.hooks.clone()
+        };
+        for hook in hooks {
+            hook(id);
+        }
+        Ok(())
+    }
+
+    /// Stop accepting every previous key, before the end of its grace period.
+    pub fn expire_all(&self) {
+        self.lock().previous.clear();
+    }
+
+    fn current(&self) -> (u32, Arc<HmacSha256>) {
+        let keys = self.lock();
+        (keys.current.0, Arc::clone(&keys.current.1))
+    }
+
+    fn key(&self, id: u32) -> Option<Arc<HmacSha256>> {
+        let mut keys = self.lock();
+        if keys.current.0 == id {
+            return Some(Arc::clone(&keys.current.1));
+        }
+        keys.expire();
+        keys.previous
+            .iter()
+            .find(|k| k.0 == id)
+            .map(|k| Arc::clone(&k.1))
+    }
+}
+
+/// Checks appended to, and verified on, every message.
+#[derive(Clone)]
+pub enum IntegrityEnvelope {
+    /// A CRC-32, against corruption.
+    Crc32,
+    /// An HMAC-SHA256, against corruption, and peers without the keys.
+    Hmac(IntegrityKeys),
+}
+
+impl IntegrityEnvelope {
+    /// Create an envelope that checks messages with a CRC-32.
+    pub fn crc32() -> IntegrityEnvelope {
+        IntegrityEnvelope::Crc32
+    }
+
+    /// Create an envelope that checks messages with an HMAC, under `keys`.
+    pub fn hmac(keys: IntegrityKeys) -> IntegrityEnvelope {
+        IntegrityEnvelope::Hmac(keys)
+    }
+
+    /// Returns the frames of a message, followed by their trailer.
+    pub fn seal_multipart<I, T>(&self, frames: I) -> Vec<Vec<u8>>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        let mut frames: Vec<Vec<u8>> = frames.into_iter().map(|f| f.as_ref().to_vec()).collect();
+        let data = framed(&frames);
+        let trailer = match *self {
+            IntegrityEnvelope::Crc32 => {
+                let mut trailer = vec![CHECK_CRC32];
+                trailer.extend_from_slice(&crc32fast::hash(&data).to_be_bytes());
+                trailer
+            }
+            IntegrityEnvelope::Hmac(ref keys) => {
+                let (id, key) = keys.current();
+                let mut trailer = vec![CHECK_HMAC];
+                trailer.extend_from_slice(&id.to_be_bytes());
+                trailer.extend_from_slice(&mac(&key, &data).finalize().into_bytes());
+                trailer
+            }
+        };
+        frames.push(trailer);
+        frames
+    }
+
+    /// Returns the frames of a message, without their trailer, if it checks out.
+    pub fn open_multipart(&self, mut frames: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, SecurityError> {
+        let trailer = frames.pop().ok_or(SecurityError::MissingIntegrityFrame)?;
+        let data = framed(&frames);
+        match *self {
+            IntegrityEnvelope::Crc32 => {
+                if trailer.len() != CRC32_TRAILER_SIZE || trailer[0] != CHECK_CRC32 {
+                    return Err(SecurityError::MissingIntegrityFrame);
+                }
+                if crc32fast::hash(&data).to_be_bytes() != trailer[1..] {
+                    return Err(SecurityError::IntegrityMismatch);
+                }
+            }
+            IntegrityEnvelope::Hmac(ref keys) => {
+                if trailer.len() != HMAC_TRAILER_SIZE || trailer[0] != CHECK_HMAC {
+                    return Err(SecurityError::MissingIntegrityFrame);
+                }
+                let mut id = [0u8; 4];
+                id.copy_from_slice(&trailer[1..5]);
+                let id = u32::from_be_bytes(id);
+                let key = keys.key(id).ok_or(SecurityError::UnknownKeyId(id))?;
+                // the tag is compared in constant time.
+                if mac(&key, &data).verify(&trailer[5..]).is_err() {
+                    return Err(SecurityError::IntegrityMismatch);
+                }
+            }
+        }
+        Ok(frames)
+    }
+
+    /// Seal, and send, a message on `socket`.
+    pub fn send_multipart<S, I, T>(
+        &self,
+        socket: &S,
+        frames: I,
+        flags: i32,
+    ) -> Result<(), SecurityError>
+    where
+        S: SocketSend,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        Ok(socket.send_multipart(self.seal_multipart(frames), flags)?)
+    }
+
+    /// Receive, and open, a message from `socket`.
+    pub fn recv_multipart<S: SocketRecv>(
+        &self,
+        socket: &S,
+        flags: i32,
+    ) -> Result<Vec<Vec<u8>>, SecurityError> {
+        self.open_multipart(socket.recv_multipart(flags)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use zmq;
+
+    #[test]
+    fn corrupted_and_foreign_messages_are_rejected() {
+        assert_eq!(crc32fast::hash(b"123456789"), 0xcbf4_3926);
+
+        let envelope = IntegrityEnvelope::crc32();
+        let mut sealed = envelope.seal_multipart(vec!["topic", "payload"]);
+        assert_eq!(envelope.open_multipart(sealed.clone()).unwrap().len(), 2);
+        sealed[1][0] ^= 1;
+        match envelope.open_multipart(sealed) {
+            Err(SecurityError::IntegrityMismatch) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // moving a byte from a frame to the next changes the check.
+        let mut moved = envelope.seal_multipart(vec!["ab", "c"]);
+        moved[0] = b"a".to_vec();
+        moved[1] = b"bc".to_vec();
+        assert!(envelope.open_multipart(moved).is_err());
+
+        let ours = IntegrityEnvelope::hmac(IntegrityKeys::new(1, b"ours"));
+        let theirs = IntegrityEnvelope::hmac(IntegrityKeys::new(1, b"theirs"));
+        let ctx = zmq::Context::new();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://integrity.shared").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect("inproc://integrity.shared").unwrap();
+        theirs.send_multipart(&push, vec!["intruder"], 0).unwrap();
+        ours.send_multipart(&push, vec!["friend"], 0).unwrap();
+        push.send("unsealed", 0).unwrap();
+        match ours.recv_multipart(&pull, 0) {
+            Err(SecurityError::IntegrityMismatch) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert_eq!(
+            ours.recv_multipart(&pull, 0).unwrap(),
+            vec![b"friend".to_vec()]
+        );
+        match ours.recv_multipart(&pull, 0) {
+            Err(SecurityError::MissingIntegrityFrame) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rotated_keys_are_accepted_during_the_grace_period() {
+        let keys = IntegrityKeys::new(1, b"first");
+        let rotations = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&rotations);
+        keys.on_rotate(move |id| {
+            assert_eq!(id, 2);
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let sender = IntegrityEnvelope::hmac(IntegrityKeys::new(1, b"first"));
+        let receiver = IntegrityEnvelope::hmac(keys.clone());
+        let old = sender.seal_multipart(vec!["old"]);
+
+        keys.rotate(2, b"second").unwrap();
+        assert_eq!(rotations.load(Ordering::SeqCst), 1);
+        assert_eq!(keys.current_id(), 2);
+        assert!(keys.accepts(1));
+        assert!(receiver.open_multipart(old.clone()).is_ok());
+        match keys.rotate(1, b"again") {
+            Err(SecurityError::DuplicateKeyId(1)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        keys.expire_all();
+        match receiver.open_multipart(old) {
+            Err(SecurityError::UnknownKeyId(1)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        let renewed = IntegrityEnvelope::hmac(IntegrityKeys::new(2, b"second"));
+        let sealed = renewed.seal_multipart(vec!["new"]);
+        assert_eq!(
+            receiver.open_multipart(sealed).unwrap(),
+            vec![b"new".to_vec()]
+        );
+    }
+}
@@ -0,0 +1,337 @@
+//! CURVE key generation, storage, and rotation.
+//!
+//! A `Keyring` keeps its key pairs as `KeysCertificate` TOML files in a directory that only
+//! its owner can read. When the current key pair is rotated, the previous one is still
+//! accepted for a grace period, so that peers have time to pick up the new public key.
+//!
+//! Keyrings opened with a passphrase encrypt the secret keys of the certificates that they
+//! write, and decrypt the ones that they read; certificates written without one are read as
+//! they are.
+use super::{encode_key, CurveKeyPair, KeysCertificate, SecurityError, KDF_ITERATIONS};
+
+use std::convert::TryFrom;
+use std::fs::{self, DirBuilder};
+#[cfg(unix)]
+use std::os::unix::fs::DirBuilderExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+use zmq;
+
+/// Default grace period, in milliseconds, during which a rotated key pair is still accepted.
+pub const KEY_GRACE_PERIOD: i64 = 60_000;
+
+/// First frame of the notifications published by a `Keyring` when its key pair is
+/// rotated. The second frame is the new Z85-encoded public key.
+pub const REKEY: &str = "$REKEY";
+
+const CURRENT_CERT: &str = "current.toml";
+const PREVIOUS_CERT: &str = "previous.toml";
+
+/// CURVE key pairs, persisted in a directory.
+///
+/// Rotations are published on an inproc `PUB` socket; actors that hold connections secured
+/// with the previous key pair should `subscribe`, and re-connect with the new key when they
+/// receive a `REKEY` notification.
+pub struct Keyring {
+    dir: PathBuf,
+    grace: i64,
+    current: CurveKeyPair,
+    previous: Option<(CurveKeyPair, SystemTime)>,
+    passphrase: Option<Zeroizing<String>>,
+    kdf_iterations: u32,
+    context: zmq::Context,
+    endpoint: String,
+    notifier: zmq::Socket,
+}
+
+impl Keyring {
+    /// Open the keyring stored in `dir`, generating a key pair if there is none. The
+    /// directory is created if it does not exist.
+    pub fn open<P: AsRef<Path>>(dir: P, context: &zmq::Context) -> Result<Keyring, SecurityError> {
+        Keyring::open_keyring(dir.as_ref(), context, None)
+    }
+
+    /// Open the keyring stored in `dir`, as with `open`, unlocking its secret keys with
+    /// `passphrase`, and encrypting the ones that it writes.
+    pub fn open_with_passphrase<P: AsRef<Path>>(
+        dir: P,
+        context: &zmq::Context,
+        passphrase: &str,
+    ) -> Result<Keyring, SecurityError> {
+        Keyring::open_keyring(dir.as_ref(), context, Some(passphrase))
+    }
+
+    fn open_keyring(
+        dir: &Path,
+        context: &zmq::Context,
+        passphrase: Option<&str>,
+    ) -> Result<Keyring, SecurityError> {
+        let dir = dir.to_path_buf();
+        let mut builder = DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        builder.mode(0o700);
+        builder.create(&dir)?;
+
+        let current = match read_certificate(&dir.join(CURRENT_CERT), passphrase)? {
+            Some(cert) => CurveKeyPair::try_from(cert)?,
+            None => {
+                let keys = CurveKeyPair::new()?;
+                let encryption = passphrase.map(|passphrase| (passphrase, KDF_ITERATIONS));
+                write_certificate(&dir.join(CURRENT_CERT), &keys, encryption)?;
+                keys
+            }
+        };
+        let previous = match read_certificate(&dir.join(PREVIOUS_CERT), passphrase)? {
+            Some(cert) => {
+                let rotated_at = fs::metadata(dir.join(PREVIOUS_CERT))?.modified()?;
+                Some((CurveKeyPair::try_from(cert)?, rotated_at))
+            }
+            None => None,
+        };
+
+        let endpoint = format!(
+            "inproc://neuras.security.keyring.{}",
+            Uuid::new_v4().to_simple()
+        );
+        let notifier = context.socket(zmq::PUB)?;
+        notifier.bind(&endpoint)?;
+        Ok(Keyring {
+            dir,
+            grace: KEY_GRACE_PERIOD,
+            current,
+            previous,
+            passphrase: passphrase.map(|passphrase| Zeroizing::new(passphrase.to_string())),
+            kdf_iterations: KDF_ITERATIONS,
+            context: context.clone(),
+            endpoint,
+            notifier,
+        })
+    }
+
+    /// Returns the directory where the key pairs are stored.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Returns the grace period, in milliseconds.
+    pub fn grace_period(&self) -> i64 {
+        self.grace
+    }
+
+    /// Set the grace period, in milliseconds, during which the previous key pair is still
+    /// accepted after a rotation.
+    pub fn set_grace_period(&mut self, grace: i64) {
+        self.grace = grace;
+    }
+
+    /// Set the iterations of the derivation of keys from the passphrase, for the
+    /// certificates written by later rotations.
+    pub fn set_kdf_iterations(&mut self, iterations: u32) {
+        self.kdf_iterations = iterations;
+    }
+
+    /// Returns the current key pair.
+    pub fn current(&self) -> &CurveKeyPair {
+        &self.current
+    }
+
+    /// Returns the previous key pair, while it is within the grace period.
+    pub fn previous(&self) -> Option<&CurveKeyPair> {
+        match self.previous {
+            Some((ref keys, rotated_at)) if !self.expired(rotated_at) => Some(keys),
+            _ => None,
+        }
+    }
+
+    /// Returns true if `public_key` belongs to the current key pair, or to the previous one
+    /// while it is within the grace period.
+    pub fn accepts(&self, public_key: &[u8]) -> bool {
+        self.current.public_key[..] == *public_key
+            || self
+                .previous()
+                .is_some_and(|keys| keys.public_key[..] == *public_key)
+    }
+
+    /// Generate a new key pair, keeping the current one as the previous key pair for the
+    /// grace period, and notify subscribers.
+    pub fn rotate(&mut self) -> Result<(), SecurityError> {
+        let keys = CurveKeyPair::new()?;
+        let passphrase = self
+            .passphrase
+            .as_ref()
+            .map(|passphrase| (passphrase.as_str(), self.kdf_iterations));
+        write_certificate(&self.dir.join(PREVIOUS_CERT), &self.current, passphrase)?;
+        write_certificate(&self.dir.join(CURRENT_CERT), &keys, passphrase)?;
+        let previous = ::std::mem::replace(&mut self.current, keys);
+        self.previous = Some((previous, SystemTime::now()));
+        let public_key = encode_key(&self.current.public_key);
+        self.notifier.send_multipart([REKEY, &public_key], 0)?;
+        nlog!(
+            info,
+            "keyring rotated dir={:?} public_key={}",
+            self.dir,
+            public_key
+        );
+        Ok(())
+    }
+
+    /// Forget the previous key pair once its grace period is over. Returns true if it was
+    /// forgotten.
+    pub fn expire(&mut self) -> Result<bool, SecurityError> {
+        match self.previous {
+            Some((_, rotated_at)) if self.expired(rotated_at) => {}
+            _ => return Ok(false),
+        }
+        self.previous = None;
+        fs::remove_file(self.dir.join(PREVIOUS_CERT))?;
+        Ok(true)
+    }
+
+    /// Returns the inproc endpoint where rotations are published.
+    pub fn endpoint(&self) -> &str {
+        &self.endpoint
+    }
+
+    /// Returns a `SUB` socket that receives a `REKEY` notification on every rotation.
+    pub fn subscribe(&self) -> Result<zmq::Socket, SecurityError> {
+        let socket = self.context.socket(zmq::SUB)?;
+        socket.set_subscribe(REKEY.as_bytes())?;
+        socket.connect(&self.endpoint)?;
+        Ok(socket)
+    }
+
+    fn expired(&self, rotated_at: SystemTime) -> bool {
+        let grace = Duration::from_millis(self.grace.max(0) as u64);
+        rotated_at.elapsed().is_ok_and(|elapsed| elapsed >= grace)
+    }
+}
+
+fn read_certificate(
+    path: &Path,
+    passphrase: Option<&str>,
+) -> Result<Option<KeysCertificate>, SecurityError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    match passphrase {
+        Some(passphrase) => KeysCertificate::load_with_passphrase(path, passphrase).map(Some),
+        None => KeysCertificate::load_from(path).map(Some),
+    }
+}
+
+// Write the certificate of `keys`, encrypted with a passphrase, and KDF iterations, if any.
+fn write_certificate(
+    path: &Path,
+    keys: &CurveKeyPair,
+    encryption: Option<(&str, u32)>,
+) -> Result<(), SecurityError> {
+    let cert = KeysCertificate::from(keys);
+    match encryption {
+        Some((passphrase, iterations)) => cert
+            .encrypt_with_iterations(passphrase, iterations)?
+            .save_to(path),
+        None => cert.save_to(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+    use std::thread;
+
+    fn keyring_dir() -> PathBuf {
+        env::temp_dir().join(format!("neuras-keyring-{}", Uuid::new_v4()))
+    }
+
+    #[test]
+    fn keyrings_persist_their_keys_privately() {
+        let ctx = zmq::Context::new();
+        let dir = keyring_dir();
+        let public_key = {
+            let keyring = Keyring::open(&dir, &ctx).unwrap();
+            assert!(keyring.previous().is_none());
+            keyring.current().public_key
+        };
+        #[cfg(unix)]
+        {
+            let mode = fs::metadata(dir.join(CURRENT_CERT))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+            let mode = fs::metadata(&dir).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        }
+
+        let keyring = Keyring::open(&dir, &ctx).unwrap();
+        assert_eq!(keyring.current().public_key, public_key);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_keys_are_accepted_during_the_grace_period() {
+        let ctx = zmq::Context::new();
+        let dir = keyring_dir();
+        let mut keyring = Keyring::open(&dir, &ctx).unwrap();
+        let old_key = keyring.current().public_key;
+        keyring.rotate().unwrap();
+        assert_ne!(keyring.current().public_key, old_key);
+        assert!(keyring.accepts(&old_key));
+        assert!(!keyring.expire().unwrap());
+
+        let reopened = Keyring::open(&dir, &ctx).unwrap();
+        assert_eq!(reopened.previous().unwrap().public_key, old_key);
+
+        keyring.set_grace_period(0);
+        assert!(keyring.previous().is_none());
+        assert!(!keyring.accepts(&old_key));
+        assert!(keyring.expire().unwrap());
+        assert!(!dir.join(PREVIOUS_CERT).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn keyrings_with_a_passphrase_encrypt_their_secret_keys() {
+        let ctx = zmq::Context::new();
+        let dir = keyring_dir();
+        // plain certificates are read as they are.
+        Keyring::open(&dir, &ctx).unwrap();
+        let mut keyring = Keyring::open_with_passphrase(&dir, &ctx, "hunter2").unwrap();
+        keyring.set_kdf_iterations(10);
+        keyring.rotate().unwrap();
+        let secret_key = encode_key(&keyring.current().secret_key);
+        let cert = fs::read_to_string(dir.join(CURRENT_CERT)).unwrap();
+        assert!(!cert.contains(&secret_key));
+
+        match Keyring::open(&dir, &ctx) {
+            Err(SecurityError::EncryptedSecretKey) => {}
+            Err(e) => panic!("unexpected error: {:?}", e),
+            Ok(_) => panic!("encrypted keyring opened without a passphrase"),
+        }
+        let reopened = Keyring::open_with_passphrase(&dir, &ctx, "hunter2").unwrap();
+        assert_eq!(reopened.current().secret_key, keyring.current().secret_key);
+        assert!(reopened.previous().is_some());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn subscribers_are_notified_of_rotations() {
+        let ctx = zmq::Context::new();
+        let dir = keyring_dir();
+        let mut keyring = Keyring::open(&dir, &ctx).unwrap();
+        let subscriber = keyring.subscribe().unwrap();
+        // let the subscription reach the publisher.
+        thread::sleep(Duration::from_millis(50));
+        keyring.rotate().unwrap();
+        let msg = subscriber.recv_multipart(0).unwrap();
+        assert_eq!(msg[0], REKEY.as_bytes());
+        assert_eq!(msg[1], encode_key(&keyring.current().public_key).as_bytes());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! Passphrase encryption of secrets at rest.
+//!
+//! Secrets are sealed with ChaCha20-Poly1305 (RFC 8439), under a key derived from a
+//! passphrase with PBKDF2-HMAC-SHA256 (RFC 8018), from the RustCrypto implementations.
+//! Derived keys, and opened secrets, are zeroed when they are dropped.
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hmac::Hmac;
+use pbkdf2::pbkdf2;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// Cipher of sealed secrets.
+pub const CIPHER: &str = "chacha20-poly1305";
+/// Key derivation function of sealed secrets.
+pub const KDF: &str = "pbkdf2-sha256";
+
+/// Derive a 32-byte key from `passphrase`, with PBKDF2-HMAC-SHA256.
+pub fn derive_key(passphrase: &[u8], salt: &[u8], iterations: u32) -> Zeroizing<[u8; 32]> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    pbkdf2::<Hmac<Sha256>>(passphrase, salt, iterations, &mut key[..]);
+    key
+}
+
+/// Encrypt `plaintext` with ChaCha20-Poly1305, returning the ciphertext, followed by its
+/// tag, which also authenticates `aad`.
+pub fn seal(key: &[u8; 32], nonce: &[u8; 12], aad: &[u8], plaintext: &[u8]) -> Vec<u8> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .encrypt(
+            &Nonce::from(*nonce),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
+        .expect("secrets at rest are far below the size limit of the cipher")
+}
+
+/// Decrypt a secret sealed by `seal`, or return `None` if the key is wrong, or if the
+/// secret, or `aad`, were tampered with.
+pub fn open(
+    key: &[u8; 32],
+    nonce: &[u8; 12],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Option<Zeroizing<Vec<u8>>> {
+    ChaCha20Poly1305::new(&Key::from(*key))
+        .decrypt(&Nonce::from(*nonce), Payload { msg: sealed, aad })
+        .ok()
+        .map(Zeroizing::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn primitives_match_the_rfc_vectors() {
+        assert_eq!(
+            derive_key(b"password", b"salt", 2).to_vec(),
+            hex("ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43")
+        );
+        assert_eq!(
+            derive_key(&[b'p'; 100], b"salt", 3).to_vec(),
+            hex("f598272d35e2ca276ac07694cf01636c4d643ad3075956477cfdd83eda46d9f6")
+        );
+
+        // RFC 8439, section 2.8.2.
+        let mut key = [0u8; 32];
+        for (i, byte) in key.iter_mut().enumerate() {
+            *byte = 0x80 + i as u8;
+        }
+        let mut nonce = [0u8; 12];
+        nonce.copy_from_slice(&hex("070000004041424344454647"));
+        let aad = hex("50515253c0c1c2c3c4c5c6c7");
+        let plaintext: &[u8] = b"Ladies and Gentlemen of the class of '99: If I could offer \
+            you only one tip for the future, sunscreen would be it.";
+        let sealed = seal(&key, &nonce, &aad, plaintext);
+        assert_eq!(
+            sealed[..16].to_vec(),
+            hex("d31a8d34648e60db7b86afbc53ef7ec2")
+        );
+        assert_eq!(
+            sealed[sealed.len() - 16..].to_vec(),
+            hex("1ae10b594f09e26a7e902ecbd0600691")
+        );
+        assert_eq!(*open(&key, &nonce, &aad, &sealed).unwrap(), plaintext);
+        assert_eq!(open(&key, &nonce, b"other", &sealed), None);
+        key[0] ^= 1;
+        assert_eq!(open(&key, &nonce, &aad, &sealed), None);
+    }
+}
@@ -0,0 +1,410 @@
+//! ZAP authentication handler.
+//!
+//! An `Authenticator` binds the well-known ZAP endpoint of its context, and answers the
+//! authentication requests of every secure server socket of that context on a child thread.
+use super::super::socket::PollingSocket;
+use super::super::utils::run_named_thread;
+use super::{encode_key, KeysCertificate, SecurityError};
+
+use failure::Error;
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use uuid::Uuid;
+use zmq;
+
+/// The endpoint that libzmq sends ZAP requests to. Only one handler can be bound to it
+/// for each `zmq::Context`.
+pub const ZAP_ENDPOINT: &str = "inproc://zeromq.zap.01";
+
+const ZAP_VERSION: &[u8] = b"1.0";
+
+/// Callback that checks a username and password.
+pub type PasswordCheck = Arc<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// Credentials accepted for the PLAIN mechanism.
+#[derive(Clone)]
+pub enum Passwords {
+    /// Fixed usernames and their passwords.
+    Table(HashMap<String, String>),
+    /// A callback that decides for each username and password.
+    Check(PasswordCheck),
+}
+
+impl Passwords {
+    /// Load the credentials from a file with one `username=password` entry per line. Empty
+    /// lines and lines starting with `#` are ignored.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Passwords, SecurityError> {
+        let mut table = HashMap::new();
+        for (idx, line) in fs::read_to_string(path)?.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut entry = line.splitn(2, '=');
+            match (entry.next(), entry.next()) {
+                (Some(username), Some(password)) if !username.is_empty() => {
+                    table.insert(username.to_string(), password.to_string());
+                }
+                _ => return Err(SecurityError::PasswordEntry(idx + 1)),
+            }
+        }
+        Ok(Passwords::Table(table))
+    }
+
+    /// Check credentials with a callback.
+    pub fn check<F>(callback: F) -> Passwords
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        Passwords::Check(Arc::new(callback))
+    }
+
+    /// Returns true if the `username` and `password` are accepted.
+    pub fn accepts(&self, username: &str, password: &str) -> bool {
+        match *self {
+            Passwords::Table(ref table) => table.get(username).is_some_and(|p| p == password),
+            Passwords::Check(ref callback) => callback(username, password),
+        }
+    }
+}
+
+impl fmt::Debug for Passwords {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Passwords::Table(ref table) => f.debug_tuple("Table").field(&table.len()).finish(),
+            Passwords::Check(_) => f.write_str("Check"),
+        }
+    }
+}
+
+/// ZAP handler, running on a child thread.
+///
+/// Connections using the NULL mechanism are accepted. PLAIN connections are accepted when
+/// their credentials are in the `Passwords` of the authenticator, and denied when it has
+/// none. CURVE connections are accepted when the client's public key has been allowed with
+/// `allow_certificate`. Other mechanisms are denied.
+///
+/// ```
+/// extern crate neuras;
+/// extern crate zmq;
+///
+/// use neuras::security::{Authenticator, Passwords};
+///
+/// # fn main() {
+/// let ctx = zmq::Context::new();
+/// let mut auth = Authenticator::new(&ctx).unwrap();
+/// auth.set_passwords(Passwords::check(|user, pass| user == "admin" && pass == "secret"));
+/// let handle = auth.start().unwrap();
+/// auth.stop().unwrap();
+/// handle.join().unwrap().unwrap();
+/// # }
+/// ```
+pub struct Authenticator {
+    context: zmq::Context,
+    pipe: zmq::Socket,
+    uuid: Uuid,
+    passwords: Option<Passwords>,
+    curve_keys: HashSet<[u8; 32]>,
+}
+
+impl Authenticator {
+    /// Create a new `Authenticator` for the sockets of `context`.
+    pub fn new(context: &zmq::Context) -> Result<Self, Error> {
+        let uuid = Uuid::new_v4();
+        let pipe = context.socket(zmq::PAIR)?;
+        pipe.connect(&pipe_address(&uuid))?;
+        Ok(Authenticator {
+            context: context.clone(),
+            pipe,
+            uuid,
+            passwords: None,
+            curve_keys: HashSet::new(),
+        })
+    }
+
+    /// Set the credentials accepted for the PLAIN mechanism. Takes effect on `start`.
+    pub fn set_passwords(&mut self, passwords: Passwords) {
+        self.passwords = Some(passwords);
+    }
+
+    /// Returns the credentials accepted for the PLAIN mechanism.
+    pub fn passwords(&self) -> Option<&Passwords> {
+        self.passwords.as_ref()
+    }
+
+    /// Allow CURVE clients with the public key of `cert`, which may be public-only. Takes
+    /// effect on `start`.
+    pub fn allow_certificate(&mut self, cert: &KeysCertificate) -> Result<(), SecurityError> {
+        self.curve_keys.insert(cert.public_key()?);
+        Ok(())
+    }
+
+    /// Returns true if CURVE clients with `public_key` are allowed.
+    pub fn allows_key(&self, public_key: &[u8; 32]) -> bool {
+        self.curve_keys.contains(public_key)
+    }
+
+    /// Returns the context whose sockets are authenticated.
+    pub fn context(&self) -> zmq::Context {
+        self.context.clone()
+    }
+
+    /// Bind the ZAP endpoint, and answer requests on a child thread. The endpoint is bound
+    /// before returning, so sockets that connect afterwards are always authenticated.
+    pub fn start(&self) -> Result<thread::JoinHandle<Result<(), Error>>, io::Error> {
+        let context = self.context.clone();
+        let pipe_addr = pipe_address(&self.uuid);
+        let handler = Handler {
+            passwords: self.passwords.clone(),
+            curve_keys: self.curve_keys.clone(),
+        };
+        let zap = context.socket(zmq::REP)?;
+        zap.set_linger(0)?;
+        zap.bind(ZAP_ENDPOINT)?;
+
+        run_named_thread("zap", move || {
+            let pipe = context.socket(zmq::PAIR)?;
+            pipe.bind(&pipe_addr)?;
+            poll_zap(&pipe, &zap, &handler)
+        })
+    }
+
+    /// Stop the authenticator, unbinding the ZAP endpoint.
+    pub fn stop(&self) -> Result<(), zmq::Error> {
+        self.pipe.send("$STOP", 0)
+    }
+}
+
+/// Returns true if a ZAP handler, e.g. an `Authenticator`, is bound to the ZAP endpoint of
+/// `context`. Without one, libzmq lets every client of secure server sockets in, without
+/// authenticating them.
+pub fn has_zap_handler(context: &zmq::Context) -> io::Result<bool> {
+    let socket = context.socket(zmq::REP)?;
+    socket.set_linger(0)?;
+    let mut probe = PollingSocket::new(socket);
+    match probe.bind(ZAP_ENDPOINT) {
+        Ok(_) => {
+            probe.detach_all()?;
+            Ok(false)
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::AddrInUse => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+// Each authenticator gets its own pipe address.
+fn pipe_address(uuid: &Uuid) -> String {
+    format!("inproc://neuras.security.zap.{}", uuid.to_simple())
+}
+
+// Decides on ZAP requests.
+struct Handler {
+    passwords: Option<Passwords>,
+    curve_keys: HashSet<[u8; 32]>,
+}
+
+impl Handler {
+    // Returns the status code, status text, and user id for a request.
+    fn authenticate(&self, mechanism: &[u8], credentials: &[Vec<u8>]) -> (&str, &str, String) {
+        match mechanism {
+            b"NULL" => ("200", "OK", String::new()),
+            b"PLAIN" => {
+                let (username, password) = match credentials {
+                    [username, password] => (
+                        String::from_utf8_lossy(username),
+                        String::from_utf8_lossy(password),
+                    ),
+                    _ => return ("400", "Invalid credentials", String::new()),
+                };
+                match self.passwords {
+                    Some(ref passwords) if passwords.accepts(&username, &password) => {
+                        ("200", "OK", username.into_owned())
+                    }
+                    _ => ("400", "Invalid username or password", String::new()),
+                }
+            }
+            b"CURVE" => match credentials {
+                [key] if key.len() == 32 => {
+                    let mut public_key = [0; 32];
+                    public_key.copy_from_slice(key);
+                    if self.curve_keys.contains(&public_key) {
+                        ("200", "OK", encode_key(&public_key))
+                    } else {
+                        ("400", "Unknown public key", String::new())
+                    }
+                }
+                _ => ("400", "Invalid credentials", String::new()),
+            },
+            _ => ("400", "Unsupported mechanism", String::new()),
+        }
+    }
+
+    fn reply(&self, request: &[Vec<u8>]) -> Option<Vec<Vec<u8>>> {
+        // version, request id, domain, address, routing id, mechanism, credentials...
+        if request.len() < 6 || request[0] != ZAP_VERSION {
+            return None;
+        }
+        let (code, text, user_id) = self.authenticate(&request[5], &request[6..]);
+        if code == "200" {
+            nlog!(
+                debug,
+                "zap accepted mechanism={} peer={} user={}",
+                String::from_utf8_lossy(&request[5]),
+                String::from_utf8_lossy(&request[3]),
+                user_id
+            );
+        } else {
+            nlog!(
+                info,
+                "zap denied mechanism={} peer={} reason={}",
+                String::from_utf8_lossy(&request[5]),
+                String::from_utf8_lossy(&request[3]),
+                text
+            );
+        }
+        Some(vec![
+            ZAP_VERSION.to_vec(),
+            request[1].clone(),
+            code.as_bytes().to_vec(),
+            text.as_bytes().to_vec(),
+            user_id.into_bytes(),
+            Vec::new(),
+        ])
+    }
+}
+
+fn poll_zap(pipe: &zmq::Socket, zap: &zmq::Socket, handler: &Handler) -> Result<(), Error> {
+    loop {
+        let mut pollable = [
+            pipe.as_poll_item(zmq::POLLIN),
+            zap.as_poll_item(zmq::POLLIN),
+        ];
+        zmq::poll(&mut pollable, -1)?;
+        if pollable[0].is_readable() {
+            let cmd = pipe.recv_msg(0)?;
+            if &*cmd == b"$STOP" {
+                pipe.send("$STOPPING", 0)?;
+                break;
+            }
+            pipe.send("$WONTDO", 0)?;
+        }
+        if pollable[1].is_readable() {
+            let request = zap.recv_multipart(0)?;
+            nlog!(trace, "zap request frames={}", request.len());
+            // malformed requests still need a reply, to keep the REP socket usable.
+            let reply = handler.reply(&request).unwrap_or_else(|| {
+                vec![
+                    ZAP_VERSION.to_vec(),
+                    request.get(1).cloned().unwrap_or_default(),
+                    b"500".to_vec(),
+                    b"Malformed request".to_vec(),
+                    Vec::new(),
+                    Vec::new(),
+                ]
+            });
+            zap.send_multipart(reply, 0)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{secure_plain_client, secure_plain_server};
+    use super::*;
+    use std::env;
+
+    // Connect a PLAIN client to a PLAIN server, returning the message the server received.
+    fn plain_message(ctx: &zmq::Context, username: &str, password: &str) -> Option<Vec<u8>> {
+        let server = ctx.socket(zmq::PULL).unwrap();
+        secure_plain_server(&server, "test").unwrap();
+        server.set_rcvtimeo(500).unwrap();
+        server.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = server.get_last_endpoint().unwrap().unwrap();
+
+        let client = ctx.socket(zmq::PUSH).unwrap();
+        secure_plain_client(&client, username, password).unwrap();
+        client.set_linger(0).unwrap();
+        client.connect(&endpoint).unwrap();
+        client.send("hello", 0).unwrap();
+        server.recv_bytes(0).ok()
+    }
+
+    #[test]
+    fn password_files_have_one_entry_per_line() {
+        let path = env::temp_dir().join(format!("neuras-passwords-{}", Uuid::new_v4()));
+        fs::write(&path, "# users\nadmin=secret\n\nguest=a=b\n").unwrap();
+        let passwords = Passwords::from_file(&path).unwrap();
+        assert!(passwords.accepts("admin", "secret"));
+        assert!(passwords.accepts("guest", "a=b"));
+        assert!(!passwords.accepts("admin", "guess"));
+        assert!(!passwords.accepts("nobody", ""));
+
+        fs::write(&path, "admin=secret\nguest\n").unwrap();
+        match Passwords::from_file(&path) {
+            Err(SecurityError::PasswordEntry(2)) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn authenticators_check_plain_credentials() {
+        let ctx = zmq::Context::new();
+        let mut auth = Authenticator::new(&ctx).unwrap();
+        auth.set_passwords(Passwords::check(|u, p| u == "admin" && p == "secret"));
+        let handle = auth.start().unwrap();
+
+        assert_eq!(plain_message(&ctx, "admin", "secret").unwrap(), b"hello");
+        assert!(plain_message(&ctx, "admin", "guess").is_none());
+
+        auth.stop().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn authenticators_deny_plain_without_passwords() {
+        let ctx = zmq::Context::new();
+        let auth = Authenticator::new(&ctx).unwrap();
+        let handle = auth.start().unwrap();
+        assert!(plain_message(&ctx, "admin", "secret").is_none());
+        auth.stop().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+
+    #[test]
+    fn authenticators_allow_curve_keys() {
+        let ctx = zmq::Context::new();
+        let mut auth = Authenticator::new(&ctx).unwrap();
+        let cert = KeysCertificate::new().unwrap();
+        auth.allow_certificate(&cert.public_only()).unwrap();
+        let public_key = cert.public_key().unwrap();
+        assert!(auth.allows_key(&public_key));
+
+        let handler = Handler {
+            passwords: None,
+            curve_keys: auth.curve_keys.clone(),
+        };
+        let (code, _, user_id) = handler.authenticate(b"CURVE", &[public_key.to_vec()]);
+        assert_eq!(code, "200");
+        assert_eq!(user_id, cert.public_key);
+        let (code, _, _) = handler.authenticate(b"CURVE", &[vec![0; 32]]);
+        assert_eq!(code, "400");
+    }
+
+    #[test]
+    fn only_one_authenticator_is_bound_for_each_context() {
+        let ctx = zmq::Context::new();
+        let first = Authenticator::new(&ctx).unwrap();
+        let handle = first.start().unwrap();
+        let second = Authenticator::new(&ctx).unwrap();
+        assert!(second.start().is_err());
+        first.stop().unwrap();
+        handle.join().unwrap().unwrap();
+    }
+}
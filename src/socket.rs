@@ -3,14 +3,85 @@
 //! A high-level socket API that hides regular `zmq::Context` and `zmq::Socket`.
 //!
 //! Inspired by [zsock](http://czmq.zeromq.org/czmq4-0:zsock).
+use super::endpoint::{AddressParse, Endpoint};
+use super::security::SecurityError;
 use std::io;
 use std::result;
 use zmq;
 
+#[path = "socket_builder.rs"]
+mod builder;
+#[path = "socket_codec.rs"]
+mod codec;
+#[path = "socket_compress.rs"]
+mod compress;
+#[path = "socket_credit.rs"]
+mod credit;
+#[path = "socket_cursor.rs"]
+mod cursor;
+#[path = "socket_failover.rs"]
+mod failover;
+#[path = "socket_flow.rs"]
+mod flow;
+#[path = "socket_heartbeat.rs"]
+mod heartbeat;
+#[path = "socket_hello.rs"]
+mod hello;
+#[path = "socket_identity.rs"]
+mod identity;
+#[path = "socket_message_pool.rs"]
+mod message_pool;
+#[path = "socket_outbox.rs"]
+mod outbox;
 #[path = "socket_polling.rs"]
 mod polling;
+#[path = "socket_reconnect.rs"]
+mod reconnect;
+#[path = "socket_stats.rs"]
+mod stats;
+#[path = "socket_stream.rs"]
+mod stream;
+#[path = "socket_subscriber.rs"]
+mod subscriber;
+#[path = "socket_types.rs"]
+mod types;
+#[path = "socket_uri.rs"]
+mod uri;
+#[path = "socket_xpub.rs"]
+mod xpub;
+#[path = "socket_zerocopy.rs"]
+mod zerocopy;
 
+pub use self::builder::{SocketBuilder, WssOptions};
+pub use self::codec::{pack, unpack, PackedEncoder, PACKED_FRAME_SIZE};
+#[cfg(feature = "deflate")]
+pub use self::compress::Deflate;
+#[cfg(feature = "lz4")]
+pub use self::compress::Lz4;
+#[cfg(feature = "zstd")]
+pub use self::compress::Zstd;
+pub use self::compress::{CompressedCodec, Compressor, MAX_DECOMPRESSED_SIZE, UNCOMPRESSED};
+pub use self::credit::{FlowReceiver, FlowSender, CREDIT, MESSAGE, READY};
+pub use self::cursor::{MultipartCursor, PartialSend};
+pub use self::failover::{ConnectPolicy, EndpointList, FailoverEvent, FailoverSocket};
+pub use self::flow::{DropCallback, FlowControl, FlowError, FlowSocket, Overflow, SendOutcome};
+pub use self::heartbeat::ZmtpHeartbeat;
+pub use self::hello::{
+    Features, Hello, HelloError, Negotiated, HELLO, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+pub use self::identity::{Identity, MAX_IDENTITY_SIZE};
+pub use self::message_pool::{MessagePool, PoolStats, MAX_POOLED_FRAME_SIZE};
+pub use self::outbox::{Confirm, Outbox, OutboxConfig, OutboxError, OutboxSocket};
 pub use self::polling::PollingSocket;
+pub use self::reconnect::{ReconnectPolicy, RetryAttempt, RetryCallback, Transient};
+pub use self::stats::SocketStats;
+pub use self::stream::{RawStream, StreamEvent};
+pub use self::subscriber::{Subscriber, SubscriberEvent};
+pub use self::types::SocketType;
+pub use self::uri::{CurveRole, SocketUri, UriOption};
+pub use self::xpub::{Subscription, SubscriptionCallback, XPubSocket};
+pub use self::zerocopy::{coalesced_message, shared_message, IntoFrame};
+pub use super::endpoint::Transport;
 
 #[cfg(feature = "async-tokio")]
 #[path = "socket_tokio.rs"]
@@ -22,9 +93,33 @@ pub enum SocketError {
     #[fail(display = "{:?}", _0)]
     Endpoint(Vec<u8>),
     #[fail(display = "{}", _0)]
+    Address(#[cause] AddressParse),
+    #[fail(display = "unknown socket type: {}", _0)]
+    UnknownSocketType(String),
+    #[fail(display = "transport is not supported by libzmq: {}", _0)]
+    UnsupportedTransport(String),
+    #[fail(display = "transport {} can't be used with {:?} sockets", _0, _1)]
+    IncompatibleTransport(String, zmq::SocketType),
+    #[fail(display = "invalid socket option: {}", _0)]
+    InvalidOption(String),
+    #[fail(display = "{}", _0)]
+    Security(#[cause] SecurityError),
+    #[fail(display = "{}", _0)]
     Zmq(#[cause] zmq::Error),
 }
 
+impl From<AddressParse> for SocketError {
+    fn from(e: AddressParse) -> SocketError {
+        SocketError::Address(e)
+    }
+}
+
+impl From<SecurityError> for SocketError {
+    fn from(e: SecurityError) -> SocketError {
+        SocketError::Security(e)
+    }
+}
+
 impl From<zmq::Error> for SocketError {
     fn from(e: zmq::Error) -> SocketError {
         SocketError::Zmq(e)
@@ -41,6 +136,17 @@ pub trait SocketWrapper {
 
     /// Return true if there are more frames of a multipart message to receive.
     fn get_rcvmore(&self) -> io::Result<bool>;
+
+    /// Bind the socket to `endpoint`, returning the endpoint it was actually bound to.
+    ///
+    /// Dynamic endpoints, such as `tcp://127.0.0.1:*`, are resolved to the port that was
+    /// assigned by the system.
+    fn bind_resolved(&self, endpoint: &Endpoint) -> Result<Endpoint, SocketError> {
+        let socket = self.get_socket_ref();
+        socket.bind(&endpoint.to_string())?;
+        let last = socket.get_last_endpoint()?.map_err(SocketError::Endpoint)?;
+        Ok(Endpoint::parse(&last)?)
+    }
 }
 
 /// API methods for sending messages with sockets.
@@ -49,45 +155,184 @@ pub trait SocketSend: SocketWrapper {
     ///
     /// Due to the provided From implementations, this works for `&[u8]`, `Vec<u8>` and `&str`,
     /// as well as on `zmq::Message` itself.
-    fn send<T>(&self, T, i32) -> io::Result<()>
+    fn send<T>(&self, msg: T, flags: i32) -> io::Result<()>
     where
-        T: zmq::Sendable;
+        T: Into<zmq::Message>;
     /// Sends a multipart-message.
-    fn send_multipart<I, T>(&self, I, i32) -> io::Result<()>
+    fn send_multipart<I, T>(&self, msg: I, flags: i32) -> io::Result<()>
     where
         I: IntoIterator<Item = T>,
         T: Into<zmq::Message>;
+
+    /// Send a frame, moving owned buffers into it without copying their content.
+    ///
+    /// Shared buffers, such as `Arc<[u8]>`, can be sent many times without being cloned.
+    fn send_frame<F>(&self, frame: F, flags: i32) -> io::Result<()>
+    where
+        F: IntoFrame,
+    {
+        self.send(frame.into_frame(), flags)
+    }
+
+    /// Sends a multipart-message, moving owned buffers into its frames, see `send_frame`.
+    fn send_multipart_frames<I, F>(&self, frames: I, flags: i32) -> io::Result<()>
+    where
+        I: IntoIterator<Item = F>,
+        F: IntoFrame,
+    {
+        self.send_multipart(frames.into_iter().map(IntoFrame::into_frame), flags)
+    }
+
+    /// Send `segments` as the frames of a multipart message, each copied straight into its
+    /// frame, e.g. the envelope of a request followed by its payload, without collecting
+    /// them into a `Vec` of frames first. No message is sent if there are no segments.
+    fn send_segments(&self, segments: &[io::IoSlice], flags: i32) -> io::Result<()> {
+        if let Some((last, rest)) = segments.split_last() {
+            for segment in rest {
+                self.send(&segment[..], flags | zmq::SNDMORE)?;
+            }
+            self.send(&last[..], flags)?;
+        }
+        Ok(())
+    }
+
+    /// Send `segments` as a single frame, copied into one buffer of their total size, see
+    /// `coalesced_message`.
+    fn send_coalesced(&self, segments: &[io::IoSlice], flags: i32) -> io::Result<()> {
+        self.send(coalesced_message(segments), flags)
+    }
+
+    /// Send `segments` as a single frame if their total size is at most `threshold`, where
+    /// copying them is cheaper than sending more frames, or as the frames of a multipart
+    /// message otherwise, so that large segments are not copied into a bigger buffer.
+    ///
+    /// Receivers get either framing, and should read the message with
+    /// `SocketRecv::recv_gathered`.
+    fn send_gathered(
+        &self,
+        segments: &[io::IoSlice],
+        threshold: usize,
+        flags: i32,
+    ) -> io::Result<()> {
+        let size: usize = segments.iter().map(|segment| segment.len()).sum();
+        if size <= threshold {
+            self.send_coalesced(segments, flags)
+        } else {
+            self.send_segments(segments, flags)
+        }
+    }
+
+    /// Send many small messages packed into a single frame, to be received with
+    /// `SocketRecv::recv_packed`.
+    fn send_packed<I, T>(&self, msgs: I, flags: i32) -> io::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.send(pack(msgs)?, flags)
+    }
 }
 
 /// API methods for receiving messages with sockets.
 pub trait SocketRecv: SocketWrapper {
     /// Receive a message into a `zmq::Message`. The length passed to `zmq_msg_recv` is the length
     /// of the buffer.
-    fn recv(&self, &mut zmq::Message, i32) -> io::Result<()>;
+    fn recv(&self, msg: &mut zmq::Message, flags: i32) -> io::Result<()>;
 
     /// Receive bytes into a slice. The length passed to `zmq_recv` is the length of the slice. The
     /// return value is the number of bytes in the message, which may be larger than the length of
     /// the slice, indicating truncation.
-    fn recv_into(&self, &mut [u8], i32) -> io::Result<usize>;
+    fn recv_into(&self, msg: &mut [u8], flags: i32) -> io::Result<usize>;
 
     /// Receive a message into a fresh `zmq::Message`.
-    fn recv_msg(&self, i32) -> io::Result<zmq::Message>;
+    fn recv_msg(&self, flags: i32) -> io::Result<zmq::Message>;
 
     /// Receive a message as a byte vector.
-    fn recv_bytes(&self, i32) -> io::Result<Vec<u8>>;
+    fn recv_bytes(&self, flags: i32) -> io::Result<Vec<u8>>;
 
     /// Receive a `String` from the socket.
     ///
     /// If the received message is not valid UTF-8, it is returned as the original `Vec` in the `Err`
     /// part of the inner result.
-    fn recv_string(&self, i32) -> io::Result<result::Result<String, Vec<u8>>>;
+    fn recv_string(&self, flags: i32) -> io::Result<result::Result<String, Vec<u8>>>;
 
     /// Receive a multipart message from the socket.
     ///
     /// Note that this will allocate a new vector for each message part; for many applications it
     /// will be possible to process the different parts sequentially and reuse allocations that
     /// way.
-    fn recv_multipart(&self, i32) -> io::Result<Vec<Vec<u8>>>;
+    fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>>;
+
+    /// Receive up to `max_messages` multipart messages, draining the socket until it would
+    /// block.
+    ///
+    /// The first message is received with the given `flags`, so a blocking socket waits for
+    /// it; the rest are received with `zmq::DONTWAIT`. Returns an error with
+    /// `std::io::ErrorKind::WouldBlock` if no message was available. Errors after the first
+    /// message end the batch early, and will be seen again on the next receive.
+    fn recv_batch(&self, max_messages: usize, flags: i32) -> io::Result<Vec<Vec<Vec<u8>>>> {
+        let mut batch = Vec::new();
+        if max_messages == 0 {
+            return Ok(batch);
+        }
+        batch.push(self.recv_multipart(flags)?);
+        while batch.len() < max_messages {
+            match self.recv_multipart(flags | zmq::DONTWAIT) {
+                Ok(msg) => batch.push(msg),
+                Err(_) => break,
+            }
+        }
+        Ok(batch)
+    }
+
+    /// Receive a message into a byte vector taken from `pool`, which may be handed back
+    /// with `MessagePool::recycle`.
+    fn recv_bytes_pooled(&self, pool: &MessagePool, flags: i32) -> io::Result<Vec<u8>> {
+        let mut msg = zmq::Message::new();
+        self.recv(&mut msg, flags)?;
+        let mut frame = pool.frame_with_capacity(msg.len());
+        frame.extend_from_slice(&msg);
+        Ok(frame)
+    }
+
+    /// Receive a multipart message into buffers taken from `pool`, which may be handed back
+    /// with `MessagePool::recycle_multipart`.
+    fn recv_multipart_pooled(&self, pool: &MessagePool, flags: i32) -> io::Result<Vec<Vec<u8>>> {
+        let mut parts = pool.multipart();
+        let mut msg = zmq::Message::new();
+        loop {
+            if let Err(e) = self.recv(&mut msg, flags) {
+                pool.recycle_multipart(parts);
+                return Err(e);
+            }
+            let mut frame = pool.frame_with_capacity(msg.len());
+            frame.extend_from_slice(&msg);
+            parts.push(frame);
+            if !msg.get_more() {
+                return Ok(parts);
+            }
+        }
+    }
+
+    /// Receive every frame of a message, joined into a single byte vector, e.g. a message
+    /// sent with `SocketSend::send_gathered`.
+    fn recv_gathered(&self, flags: i32) -> io::Result<Vec<u8>> {
+        let mut msg = zmq::Message::new();
+        self.recv(&mut msg, flags)?;
+        let mut gathered = msg.to_vec();
+        while msg.get_more() {
+            self.recv(&mut msg, flags)?;
+            gathered.extend_from_slice(&msg);
+        }
+        Ok(gathered)
+    }
+
+    /// Receive a frame sent with `SocketSend::send_packed`, and unpack its messages.
+    ///
+    /// Frames that were not packed fail with `std::io::ErrorKind::InvalidData`.
+    fn recv_packed(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
+        unpack(&self.recv_bytes(flags)?)
+    }
 }
 
 /// API declaration for the standard socket.
@@ -108,7 +353,7 @@ impl SocketSend for zmq::Socket {
     /// as well as on `zmq::Message` itself.
     fn send<T>(&self, msg: T, flags: i32) -> io::Result<()>
     where
-        T: zmq::Sendable,
+        T: Into<zmq::Message>,
     {
         self.send(msg, flags).map_err(|e| e.into())
     }
@@ -167,4 +412,97 @@ impl SocketRecv for zmq::Socket {
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    fn setup_pair(addr: &str) -> (zmq::Socket, zmq::Socket) {
+        let ctx = zmq::Context::new();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind(addr).unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect(addr).unwrap();
+        (sender, receiver)
+    }
+
+    #[test]
+    fn recv_batch_drains_until_it_would_block() {
+        let (sender, receiver) = setup_pair("inproc://batch_drain");
+        for i in 0..3 {
+            SocketSend::send_multipart(&sender, vec![vec![i], vec![i]], 0).unwrap();
+        }
+        let batch = SocketRecv::recv_batch(&receiver, 10, 0).unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(batch[2], vec![vec![2], vec![2]]);
+    }
+
+    #[test]
+    fn recv_batch_stops_at_max_messages() {
+        let (sender, receiver) = setup_pair("inproc://batch_max");
+        for i in 0..5 {
+            SocketSend::send(&sender, &[i][..], 0).unwrap();
+        }
+        assert_eq!(SocketRecv::recv_batch(&receiver, 2, 0).unwrap().len(), 2);
+        assert_eq!(SocketRecv::recv_batch(&receiver, 10, 0).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn packed_messages_share_a_single_frame() {
+        let (sender, receiver) = setup_pair("inproc://packed");
+        SocketSend::send_packed(&sender, ["a", "bc", ""], 0).unwrap();
+        SocketSend::send(&sender, "not packed", 0).unwrap();
+        let msgs = SocketRecv::recv_packed(&receiver, 0).unwrap();
+        assert_eq!(msgs, vec![b"a".to_vec(), b"bc".to_vec(), vec![]]);
+        let err = SocketRecv::recv_packed(&receiver, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn gathered_segments_are_framed_by_their_size() {
+        let (sender, receiver) = setup_pair("inproc://gathered");
+        let payload = vec![7; 64];
+        let segments = [io::IoSlice::new(b"header"), io::IoSlice::new(&payload)];
+        SocketSend::send_gathered(&sender, &segments, 128, 0).unwrap();
+        SocketSend::send_gathered(&sender, &segments, 16, 0).unwrap();
+        assert_eq!(SocketRecv::recv_multipart(&receiver, 0).unwrap().len(), 1);
+        assert_eq!(SocketRecv::recv_multipart(&receiver, 0).unwrap().len(), 2);
+
+        SocketSend::send_gathered(&sender, &segments, 16, 0).unwrap();
+        let gathered = SocketRecv::recv_gathered(&receiver, 0).unwrap();
+        assert_eq!(&gathered[..6], b"header");
+        assert_eq!(&gathered[6..], &payload[..]);
+    }
+
+    #[test]
+    fn segments_are_sent_as_frames() {
+        let (sender, receiver) = setup_pair("inproc://segments");
+        let envelope = [b"client".to_vec(), Vec::new()];
+        let mut segments: Vec<_> = envelope.iter().map(|f| io::IoSlice::new(f)).collect();
+        segments.push(io::IoSlice::new(b"reply"));
+        SocketSend::send_segments(&sender, &segments, 0).unwrap();
+        SocketSend::send_segments(&sender, &[], 0).unwrap();
+        assert_eq!(
+            SocketRecv::recv_multipart(&receiver, 0).unwrap(),
+            vec![b"client".to_vec(), vec![], b"reply".to_vec()]
+        );
+        assert!(SocketRecv::recv_bytes(&receiver, zmq::DONTWAIT).is_err());
+    }
+
+    #[test]
+    fn bind_resolved_returns_the_assigned_port() {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::PULL).unwrap();
+        let endpoint = Endpoint::parse("tcp://127.0.0.1:*").unwrap();
+        let resolved = socket.bind_resolved(&endpoint).unwrap();
+        assert!(!resolved.has_wildcard_port());
+        assert_eq!(resolved.transport(), Transport::Tcp);
+        let inproc = Endpoint::parse("inproc://resolved").unwrap();
+        assert_eq!(socket.bind_resolved(&inproc).unwrap(), inproc);
+    }
+
+    #[test]
+    fn recv_batch_would_block_on_empty_sockets() {
+        let (_sender, receiver) = setup_pair("inproc://batch_empty");
+        let batch = SocketRecv::recv_batch(&receiver, 10, zmq::DONTWAIT);
+        assert_eq!(batch.unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+}
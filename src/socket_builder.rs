@@ -0,0 +1,645 @@
+//! Builder for configured sockets.
+//!
+//! `SocketBuilder` collects socket options and endpoints, validating every endpoint as it
+//! is added, so that unsupported transports are reported before any socket is created.
+use super::super::endpoint::{Endpoint, EndpointCheck, ToEndpoint, Transport};
+use super::super::security::{
+    secure_curve_client, secure_curve_server, secure_plain_client, secure_plain_server,
+    CurveKeyPair, KeysCertificate,
+};
+use super::{CurveRole, ReconnectPolicy, SocketError, SocketUri, UriOption, ZmtpHeartbeat};
+
+use std::convert::TryFrom;
+use std::ffi::CString;
+use std::os::raw::c_void;
+use std::time::Duration;
+
+use zmq;
+use zmq_sys;
+
+// Socket options for WebSocket over TLS, from the libzmq draft API.
+const ZMQ_WSS_KEY_PEM: i32 = 103;
+const ZMQ_WSS_CERT_PEM: i32 = 104;
+const ZMQ_WSS_TRUST_PEM: i32 = 105;
+const ZMQ_WSS_HOSTNAME: i32 = 106;
+const ZMQ_WSS_TRUST_SYSTEM: i32 = 107;
+
+impl Transport {
+    /// Parse and validate an endpoint, returning its transport. Returns
+    /// `SocketError::UnsupportedTransport` if the local libzmq does not support it.
+    pub fn from_endpoint<E: ToEndpoint>(endpoint: E) -> Result<Transport, SocketError> {
+        let transport = endpoint.to_endpoint()?.transport();
+        if !transport.is_supported() {
+            return Err(SocketError::UnsupportedTransport(
+                transport.name().to_string(),
+            ));
+        }
+        Ok(transport)
+    }
+}
+
+/// TLS options for `wss://` endpoints.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct WssOptions {
+    /// Private key of the server, in PEM format.
+    pub key_pem: Option<String>,
+    /// Certificate of the server, in PEM format.
+    pub cert_pem: Option<String>,
+    /// Certificate authority trusted by clients, in PEM format.
+    pub trust_pem: Option<String>,
+    /// Hostname that clients verify the server certificate against.
+    pub hostname: Option<String>,
+    /// Whether clients trust the certificate authorities of the system.
+    pub trust_system: bool,
+}
+
+impl WssOptions {
+    fn apply(&self, socket: &mut zmq::Socket) -> Result<(), zmq::Error> {
+        let strings = [
+            (ZMQ_WSS_KEY_PEM, &self.key_pem),
+            (ZMQ_WSS_CERT_PEM, &self.cert_pem),
+            (ZMQ_WSS_TRUST_PEM, &self.trust_pem),
+            (ZMQ_WSS_HOSTNAME, &self.hostname),
+        ];
+        for &(option, value) in &strings {
+            if let Some(ref value) = *value {
+                let value = CString::new(value.as_str()).map_err(|_| zmq::Error::EINVAL)?;
+                let bytes = value.as_bytes_with_nul();
+                setsockopt(socket, option, bytes.as_ptr() as *const c_void, bytes.len())?;
+            }
+        }
+        if self.trust_system {
+            let value: i32 = 1;
+            let ptr = &value as *const i32 as *const c_void;
+            setsockopt(socket, ZMQ_WSS_TRUST_SYSTEM, ptr, 4)?;
+        }
+        Ok(())
+    }
+}
+
+// Set a socket option that is not wrapped by the `zmq` crate.
+fn setsockopt(
+    socket: &mut zmq::Socket,
+    option: i32,
+    value: *const c_void,
+    len: usize,
+) -> Result<(), zmq::Error> {
+    let rc = unsafe { zmq_sys::zmq_setsockopt(socket.as_mut_ptr(), option, value, len) };
+    if rc == -1 {
+        return Err(zmq::Error::from_raw(unsafe { zmq_sys::zmq_errno() }));
+    }
+    Ok(())
+}
+
+// Socket options collected by the builder, applied in order.
+#[derive(Clone, Debug, PartialEq)]
+enum SocketOption {
+    Affinity(u64),
+    // server key, public key, and secret key.
+    CurveClient([u8; 32], [u8; 32], [u8; 32]),
+    // public key, and secret key.
+    CurveServer([u8; 32], [u8; 32]),
+    Heartbeat(ZmtpHeartbeat),
+    Identity(Vec<u8>),
+    Linger(i32),
+    MulticastHops(i32),
+    PlainClient(String, String),
+    PlainServer(String),
+    Rate(i32),
+    RecoveryIvl(i32),
+    Uri(UriOption),
+    Wss(WssOptions),
+}
+
+/// Builder for sockets, with validated endpoints.
+///
+/// ```
+/// extern crate neuras;
+/// extern crate zmq;
+///
+/// use neuras::socket::SocketBuilder;
+///
+/// # fn main() {
+/// let ctx = zmq::Context::new();
+/// let socket = SocketBuilder::new(&ctx, zmq::PULL)
+///     .linger(0)
+///     .bind("inproc://built")
+///     .unwrap()
+///     .build()
+///     .unwrap();
+/// # }
+/// ```
+pub struct SocketBuilder {
+    context: zmq::Context,
+    socket_type: zmq::SocketType,
+    options: Vec<SocketOption>,
+    binds: Vec<Endpoint>,
+    connects: Vec<Endpoint>,
+    reconnect: Option<ReconnectPolicy>,
+    check: Option<EndpointCheck>,
+}
+
+impl SocketBuilder {
+    /// Create a new `SocketBuilder` for sockets of `socket_type`, either a `SocketType`, or
+    /// a `zmq::SocketType`.
+    pub fn new<T: Into<zmq::SocketType>>(context: &zmq::Context, socket_type: T) -> SocketBuilder {
+        SocketBuilder {
+            context: context.clone(),
+            socket_type: socket_type.into(),
+            options: Vec::new(),
+            binds: Vec::new(),
+            connects: Vec::new(),
+            reconnect: None,
+            check: None,
+        }
+    }
+
+    /// Set the socket identity, either bytes, or an `Identity`.
+    pub fn identity<I: AsRef<[u8]>>(mut self, identity: I) -> Self {
+        self.options
+            .push(SocketOption::Identity(identity.as_ref().to_vec()));
+        self
+    }
+
+    /// Pin the connections of the socket to the I/O threads of the context in `mask`, where
+    /// bit `n` stands for the thread `n`, e.g. `0b10` for the second. Zero, the default,
+    /// spreads them over every I/O thread. Masks that match none of the I/O threads of the
+    /// context make `bind` and `connect` fail with `EMTHREAD`, for transports other than
+    /// `inproc`.
+    pub fn affinity(mut self, mask: u64) -> Self {
+        self.options.push(SocketOption::Affinity(mask));
+        self
+    }
+
+    /// Set the linger period, in milliseconds, for pending messages when the socket closes.
+    pub fn linger(mut self, linger: i32) -> Self {
+        self.options.push(SocketOption::Linger(linger));
+        self
+    }
+
+    /// Set the ZMTP 3.1 heartbeats of the connections: a `PING` every `interval`,
+    /// closing connections that receive nothing for `timeout`, and asking peers to close
+    /// them after `ttl`. See `ZmtpHeartbeat`.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration, ttl: Duration) -> Self {
+        let heartbeat = ZmtpHeartbeat::new(interval, timeout, ttl);
+        self.options.push(SocketOption::Heartbeat(heartbeat));
+        self
+    }
+
+    /// Set the maximum data rate, in kilobits per second, for multicast transports.
+    pub fn rate(mut self, kbps: i32) -> Self {
+        self.options.push(SocketOption::Rate(kbps));
+        self
+    }
+
+    /// Set the recovery interval, in milliseconds, during which multicast receivers can
+    /// recover lost data.
+    pub fn recovery_ivl(mut self, ivl: i32) -> Self {
+        self.options.push(SocketOption::RecoveryIvl(ivl));
+        self
+    }
+
+    /// Set the time-to-live of outgoing multicast packets.
+    pub fn multicast_hops(mut self, hops: i32) -> Self {
+        self.options.push(SocketOption::MulticastHops(hops));
+        self
+    }
+
+    /// Make the socket a PLAIN server, authenticating clients for the ZAP `domain`. See
+    /// `security::Authenticator`.
+    pub fn plain_server(mut self, domain: &str) -> Self {
+        self.options
+            .push(SocketOption::PlainServer(domain.to_string()));
+        self
+    }
+
+    /// Make the socket a PLAIN client, with the given credentials.
+    pub fn plain_client(mut self, username: &str, password: &str) -> Self {
+        self.options.push(SocketOption::PlainClient(
+            username.to_string(),
+            password.to_string(),
+        ));
+        self
+    }
+
+    /// Make the socket a CURVE server, with the key pair of `cert`.
+    ///
+    /// Returns `SecurityError::InvalidKey` if the certificate is malformed, or
+    /// `SecurityError::MissingSecretKey` if it is public-only.
+    pub fn curve_server(mut self, cert: &KeysCertificate) -> Result<Self, SocketError> {
+        let keys = CurveKeyPair::try_from(cert)?;
+        self.options
+            .push(SocketOption::CurveServer(keys.public_key, keys.secret_key));
+        Ok(self)
+    }
+
+    /// Make the socket a CURVE client, with the key pair of `cert`, of the server with the
+    /// (possibly public-only) `server` certificate.
+    pub fn curve_client(
+        mut self,
+        server: &KeysCertificate,
+        cert: &KeysCertificate,
+    ) -> Result<Self, SocketError> {
+        let server_key = server.public_key()?;
+        let keys = CurveKeyPair::try_from(cert)?;
+        self.options.push(SocketOption::CurveClient(
+            server_key,
+            keys.public_key,
+            keys.secret_key,
+        ));
+        Ok(self)
+    }
+
+    /// Set the TLS options for `wss://` endpoints.
+    pub fn wss(mut self, options: WssOptions) -> Self {
+        self.options.push(SocketOption::Wss(options));
+        self
+    }
+
+    /// Set the reconnect intervals of the socket, and retry connects that fail with the
+    /// backoff of `policy`.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Check the endpoints with `check` when the socket is built, before it binds, or
+    /// connects, to any of them, e.g. to look their hosts up.
+    pub fn check(mut self, check: EndpointCheck) -> Self {
+        self.check = Some(check);
+        self
+    }
+
+    /// Add an endpoint to bind to, once the socket is built.
+    pub fn bind<E: ToEndpoint>(mut self, endpoint: E) -> Result<Self, SocketError> {
+        let endpoint = self.check_endpoint(endpoint)?;
+        self.binds.push(endpoint);
+        Ok(self)
+    }
+
+    /// Add an endpoint to connect to, once the socket is built.
+    pub fn connect<E: ToEndpoint>(mut self, endpoint: E) -> Result<Self, SocketError> {
+        let endpoint = self.check_endpoint(endpoint)?;
+        self.connects.push(endpoint);
+        Ok(self)
+    }
+
+    /// Add the endpoint of `uri` to bind to, once the socket is built, with its options.
+    pub fn bind_uri(mut self, uri: &str) -> Result<Self, SocketError> {
+        let uri = SocketUri::parse(uri)?;
+        self.binds.push(self.check_endpoint(&uri.endpoint)?);
+        self.add_uri_options(uri);
+        Ok(self)
+    }
+
+    /// Add the endpoint of `uri` to connect to, once the socket is built, with its
+    /// options.
+    pub fn connect_uri(mut self, uri: &str) -> Result<Self, SocketError> {
+        let uri = SocketUri::parse(uri)?;
+        self.connects.push(self.check_endpoint(&uri.endpoint)?);
+        self.add_uri_options(uri);
+        Ok(self)
+    }
+
+    fn add_uri_options(&mut self, uri: SocketUri) {
+        self.options
+            .extend(uri.options.into_iter().map(SocketOption::Uri));
+    }
+
+    // Returns an error if an URI requires CURVE, and the socket is not secured as required.
+    fn check_curve(&self) -> Result<(), SocketError> {
+        let secured = |role| {
+            self.options.iter().any(|option| match *option {
+                SocketOption::CurveServer(..) => role == CurveRole::Server,
+                SocketOption::CurveClient(..) => role == CurveRole::Client,
+                _ => false,
+            })
+        };
+        for option in &self.options {
+            if let SocketOption::Uri(UriOption::Curve(role)) = *option {
+                if !secured(role) {
+                    let method = match role {
+                        CurveRole::Server => "curve_server",
+                        CurveRole::Client => "curve_client",
+                    };
+                    return Err(SocketError::InvalidOption(format!(
+                        "curve requires the keys of SocketBuilder::{}",
+                        method
+                    )));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Create the socket, set its options, then bind and connect it to its endpoints.
+    /// Fails with `SocketError::InvalidOption` if the `curve` option of an URI was given
+    /// without the keys for it.
+    pub fn build(self) -> Result<zmq::Socket, SocketError> {
+        self.check_curve()?;
+        if let Some(ref check) = self.check {
+            for endpoint in &self.binds {
+                endpoint.check_bind(check)?;
+            }
+            for endpoint in &self.connects {
+                endpoint.check_connect(check)?;
+            }
+        }
+        let mut socket = self.context.socket(self.socket_type)?;
+        for option in &self.options {
+            match *option {
+                SocketOption::Affinity(mask) => socket.set_affinity(mask)?,
+                SocketOption::CurveClient(ref server_key, public_key, secret_key) => {
+                    let keys = CurveKeyPair {
+                        public_key,
+                        secret_key,
+                    };
+                    secure_curve_client(&socket, server_key, &keys)?
+                }
+                SocketOption::CurveServer(public_key, secret_key) => {
+                    let keys = CurveKeyPair {
+                        public_key,
+                        secret_key,
+                    };
+                    secure_curve_server(&socket, &keys)?
+                }
+                SocketOption::Heartbeat(ref heartbeat) => heartbeat.apply(&socket)?,
+                SocketOption::Identity(ref identity) => socket.set_identity(identity)?,
+                SocketOption::Linger(linger) => socket.set_linger(linger)?,
+                SocketOption::MulticastHops(hops) => socket.set_multicast_hops(hops)?,
+                SocketOption::PlainClient(ref username, ref password) => {
+                    secure_plain_client(&socket, username, password)?
+                }
+                SocketOption::PlainServer(ref domain) => secure_plain_server(&socket, domain)?,
+                SocketOption::Rate(rate) => socket.set_rate(rate)?,
+                SocketOption::RecoveryIvl(ivl) => socket.set_recovery_ivl(ivl)?,
+                SocketOption::Uri(ref option) => option.apply(&socket)?,
+                SocketOption::Wss(ref options) => options.apply(&mut socket)?,
+            }
+        }
+        if let Some(ref policy) = self.reconnect {
+            policy.apply(&socket)?;
+        }
+        for endpoint in &self.binds {
+            socket.bind(&endpoint.to_string())?;
+        }
+        for endpoint in &self.connects {
+            match self.reconnect {
+                Some(ref policy) => policy.connect(&socket, &endpoint.to_string())?,
+                None => socket.connect(&endpoint.to_string())?,
+            }
+        }
+        Ok(socket)
+    }
+
+    // Validate the endpoint, and that its transport can be used with the socket type.
+    fn check_endpoint<E: ToEndpoint>(&self, endpoint: E) -> Result<Endpoint, SocketError> {
+        let endpoint = endpoint.to_endpoint()?;
+        let transport = Transport::from_endpoint(&endpoint)?;
+        if !transport.allows(self.socket_type) {
+            return Err(SocketError::IncompatibleTransport(
+                transport.name().to_string(),
+                self.socket_type,
+            ));
+        }
+        Ok(endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::endpoint::AddressParse;
+    use super::super::super::security::SecurityError;
+    use super::*;
+
+    #[test]
+    fn endpoints_are_validated_by_transport() {
+        assert_eq!(
+            Transport::from_endpoint("tcp://127.0.0.1:*").unwrap(),
+            Transport::Tcp
+        );
+        assert_eq!(
+            Transport::from_endpoint("inproc://name").unwrap(),
+            Transport::Inproc
+        );
+        assert!(Transport::from_endpoint("127.0.0.1:5555").is_err());
+        assert!(Transport::from_endpoint("http://127.0.0.1:80").is_err());
+        assert!(Transport::from_endpoint("inproc://").is_err());
+    }
+
+    #[test]
+    fn websocket_endpoints_need_a_host_and_port() {
+        for endpoint in &["ws://127.0.0.1", "ws://:5555", "wss://"] {
+            match Transport::from_endpoint(endpoint) {
+                Err(SocketError::Address(_)) => {}
+                other => panic!("{} was not invalid: {:?}", endpoint, other),
+            }
+        }
+    }
+
+    #[test]
+    fn websocket_endpoints_depend_on_libzmq_support() {
+        let endpoint = "ws://127.0.0.1:*/neuras";
+        match Transport::from_endpoint(endpoint) {
+            Ok(transport) => {
+                assert!(Transport::Ws.is_supported());
+                assert_eq!(transport, Transport::Ws);
+                let ctx = zmq::Context::new();
+                let socket = SocketBuilder::new(&ctx, zmq::PULL)
+                    .bind(endpoint)
+                    .unwrap()
+                    .build()
+                    .unwrap();
+                let last = socket.get_last_endpoint().unwrap().unwrap();
+                assert!(last.starts_with("ws://127.0.0.1:"));
+            }
+            Err(SocketError::UnsupportedTransport(name)) => {
+                assert!(!Transport::Ws.is_supported());
+                assert_eq!(name, "ws");
+            }
+            Err(e) => panic!("unexpected error: {:?}", e),
+        }
+    }
+
+    #[test]
+    fn builders_reject_multicast_for_other_socket_types() {
+        let ctx = zmq::Context::new();
+        let endpoint = "epgm://127.0.0.1;239.192.1.1:5555";
+        match SocketBuilder::new(&ctx, zmq::PUSH).connect(endpoint) {
+            Err(SocketError::IncompatibleTransport(name, zmq::PUSH)) => assert_eq!(name, "epgm"),
+            Err(SocketError::UnsupportedTransport(name)) => assert_eq!(name, "epgm"),
+            _ => panic!("PUSH sockets can't use multicast"),
+        }
+    }
+
+    #[test]
+    fn multicast_options_are_set_on_build() {
+        let ctx = zmq::Context::new();
+        let socket = SocketBuilder::new(&ctx, zmq::PUB)
+            .rate(1_000)
+            .recovery_ivl(5_000)
+            .multicast_hops(4)
+            .build()
+            .unwrap();
+        assert_eq!(socket.get_rate().unwrap(), 1_000);
+        assert_eq!(socket.get_recovery_ivl().unwrap(), 5_000);
+        assert_eq!(socket.get_multicast_hops().unwrap(), 4);
+    }
+
+    #[test]
+    fn heartbeat_options_are_set_on_build() {
+        let ctx = zmq::Context::new();
+        let socket = SocketBuilder::new(&ctx, zmq::DEALER)
+            .heartbeat(
+                Duration::from_secs(1),
+                Duration::from_secs(3),
+                Duration::from_secs(10),
+            )
+            .build()
+            .unwrap();
+        assert_eq!(socket.get_heartbeat_ivl().unwrap(), 1_000);
+        assert_eq!(socket.get_heartbeat_timeout().unwrap(), 3_000);
+        assert_eq!(socket.get_heartbeat_ttl().unwrap(), 10_000);
+    }
+
+    #[test]
+    fn plain_options_are_set_on_build() {
+        let ctx = zmq::Context::new();
+        let server = SocketBuilder::new(&ctx, zmq::REP)
+            .plain_server("global")
+            .build()
+            .unwrap();
+        assert!(server.is_plain_server().unwrap());
+        assert_eq!(server.get_zap_domain().unwrap().unwrap(), "global");
+        let client = SocketBuilder::new(&ctx, zmq::REQ)
+            .plain_client("admin", "secret")
+            .build()
+            .unwrap();
+        assert_eq!(client.get_plain_username().unwrap().unwrap(), "admin");
+    }
+
+    #[test]
+    fn curve_options_need_valid_certificates() {
+        let ctx = zmq::Context::new();
+        let cert = KeysCertificate::new().unwrap();
+        let public = cert.public_only();
+        match SocketBuilder::new(&ctx, zmq::REP).curve_server(&public) {
+            Err(SocketError::Security(SecurityError::MissingSecretKey)) => {}
+            _ => panic!("public-only certificates can't be used for servers"),
+        }
+        let mut invalid = cert.clone();
+        invalid.public_key = "not a key".into();
+        match SocketBuilder::new(&ctx, zmq::REQ).curve_client(&invalid, &cert) {
+            Err(SocketError::Security(SecurityError::InvalidKey)) => {}
+            _ => panic!("malformed certificates are rejected"),
+        }
+        if !zmq::has("curve").unwrap_or(false) {
+            return;
+        }
+        let server = SocketBuilder::new(&ctx, zmq::REP)
+            .curve_server(&cert)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(server.is_curve_server().unwrap());
+        let client = SocketBuilder::new(&ctx, zmq::REQ)
+            .curve_client(&public, &KeysCertificate::new().unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(
+            client.get_curve_serverkey().unwrap(),
+            public.public_key().unwrap()
+        );
+    }
+
+    #[test]
+    fn reconnect_policies_are_set_on_build() {
+        let ctx = zmq::Context::new();
+        let mut policy = ReconnectPolicy::new();
+        policy.set_reconnect_ivl(500);
+        policy.set_reconnect_ivl_max(5_000);
+        let socket = SocketBuilder::new(&ctx, zmq::DEALER)
+            .reconnect(policy)
+            .connect("tcp://127.0.0.1:25811")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(socket.get_reconnect_ivl().unwrap(), 500);
+        assert_eq!(socket.get_reconnect_ivl_max().unwrap(), 5_000);
+    }
+
+    #[test]
+    fn endpoints_are_checked_on_build() {
+        let ctx = zmq::Context::new();
+        let built = SocketBuilder::new(&ctx, zmq::DEALER)
+            .check(EndpointCheck::resolving())
+            .connect("tcp://no-such-host.invalid:25812")
+            .unwrap()
+            .build();
+        match built {
+            Err(SocketError::Address(AddressParse::Unresolved(ref host, _))) => {
+                assert_eq!(host, "no-such-host.invalid")
+            }
+            other => panic!("unknown host was not rejected: {:?}", other.err()),
+        }
+        let built = SocketBuilder::new(&ctx, zmq::DEALER)
+            .check(EndpointCheck::new())
+            .connect("tcp://localhost:*")
+            .unwrap()
+            .build();
+        assert!(built.is_err());
+    }
+
+    #[test]
+    fn built_sockets_have_their_options_and_endpoints() {
+        let ctx = zmq::Context::new();
+        let pull = SocketBuilder::new(&ctx, zmq::PULL)
+            .identity(b"builder")
+            .linger(0)
+            .bind("inproc://builder")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(pull.get_identity().unwrap(), b"builder");
+        assert_eq!(pull.get_linger().unwrap(), 0);
+        let push = SocketBuilder::new(&ctx, zmq::PUSH)
+            .connect("inproc://builder")
+            .unwrap()
+            .build()
+            .unwrap();
+        push.send("built", 0).unwrap();
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"built");
+    }
+
+    #[test]
+    fn uris_set_their_options_on_build() {
+        let ctx = zmq::Context::new();
+        let pull = SocketBuilder::new(&ctx, zmq::PULL)
+            .bind_uri("inproc://builder_uri?rcvhwm=10&linger=0&identity=uri")
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(pull.get_rcvhwm().unwrap(), 10);
+        assert_eq!(pull.get_linger().unwrap(), 0);
+        assert_eq!(pull.get_identity().unwrap(), b"uri");
+
+        let secure = "tcp://127.0.0.1:*?curve=server";
+        match SocketBuilder::new(&ctx, zmq::REP)
+            .bind_uri(secure)
+            .unwrap()
+            .build()
+        {
+            Err(SocketError::InvalidOption(_)) => {}
+            other => panic!("curve was not required: {:?}", other.map(|_| ())),
+        }
+        let server = SocketBuilder::new(&ctx, zmq::REP)
+            .bind_uri(secure)
+            .unwrap()
+            .curve_server(&KeysCertificate::new().unwrap())
+            .unwrap()
+            .build()
+            .unwrap();
+        assert!(server.is_curve_server().unwrap());
+    }
+}
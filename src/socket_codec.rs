@@ -0,0 +1,166 @@
+//! Packing of many small messages into a single frame.
+//!
+//! Every message is written as its length, a big-endian `u32`, followed by its content, so
+//! a frame holds as many messages as fit in it. Sending one packed frame, instead of one
+//! frame per message, saves the per-frame overhead of high-rate streams of small messages,
+//! e.g. telemetry.
+//!
+//! ```
+//! use neuras::socket::{pack, unpack};
+//!
+//! let frame = pack(&["temp=21", "humidity=40"]).unwrap();
+//! assert_eq!(unpack(&frame).unwrap(), vec![b"temp=21".to_vec(), b"humidity=40".to_vec()]);
+//! ```
+use std::io;
+
+/// Default maximum size, in bytes, of the frames packed by a `PackedEncoder`.
+pub const PACKED_FRAME_SIZE: usize = 64 * 1024;
+
+const LENGTH_PREFIX: usize = 4;
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Pack `msgs` into a single frame.
+pub fn pack<I, T>(msgs: I) -> io::Result<Vec<u8>>
+where
+    I: IntoIterator<Item = T>,
+    T: AsRef<[u8]>,
+{
+    let mut encoder = PackedEncoder::new(usize::MAX);
+    for msg in msgs {
+        encoder.push(msg.as_ref())?;
+    }
+    Ok(encoder.take())
+}
+
+/// Unpack the messages of a packed frame.
+pub fn unpack(frame: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+    let mut msgs = Vec::new();
+    let mut rest = frame;
+    while !rest.is_empty() {
+        if rest.len() < LENGTH_PREFIX {
+            return Err(invalid_data("truncated length prefix in packed frame"));
+        }
+        let mut length = [0u8; LENGTH_PREFIX];
+        length.copy_from_slice(&rest[..LENGTH_PREFIX]);
+        let length = u32::from_be_bytes(length) as usize;
+        rest = &rest[LENGTH_PREFIX..];
+        if rest.len() < length {
+            return Err(invalid_data("truncated message in packed frame"));
+        }
+        msgs.push(rest[..length].to_vec());
+        rest = &rest[length..];
+    }
+    Ok(msgs)
+}
+
+/// Accumulates messages into a packed frame, until it reaches its maximum size.
+#[derive(Clone, Debug)]
+pub struct PackedEncoder {
+    buf: Vec<u8>,
+    count: usize,
+    max_size: usize,
+}
+
+impl Default for PackedEncoder {
+    fn default() -> Self {
+        PackedEncoder::new(PACKED_FRAME_SIZE)
+    }
+}
+
+impl PackedEncoder {
+    /// Create a new `PackedEncoder` for frames of up to `max_size` bytes. Messages larger
+    /// than that are packed on their own.
+    pub fn new(max_size: usize) -> Self {
+        PackedEncoder {
+            buf: Vec::new(),
+            count: 0,
+            max_size,
+        }
+    }
+
+    /// Returns the number of messages in the frame.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns true if the frame has no messages.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns true if `msg` does not fit in the frame, which should be taken first.
+    pub fn is_full_for(&self, msg: &[u8]) -> bool {
+        !self.is_empty() && self.buf.len() + LENGTH_PREFIX + msg.len() > self.max_size
+    }
+
+    /// Append `msg` to the frame. Fails if it is longer than `u32::MAX` bytes.
+    pub fn push(&mut self, msg: &[u8]) -> io::Result<()> {
+        if msg.len() > u32::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "message is too large to be packed",
+            ));
+        }
+        self.buf
+            .extend_from_slice(&(msg.len() as u32).to_be_bytes());
+        self.buf.extend_from_slice(msg);
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Returns the packed frame.
+    pub fn frame(&self) -> &[u8] {
+        &self.buf
+    }
+
+    /// Drop the messages in the frame, e.g. once it was sent.
+    pub fn clear(&mut self) {
+        self.count = 0;
+        self.buf.clear();
+    }
+
+    /// Take the packed frame, leaving the encoder empty.
+    pub fn take(&mut self) -> Vec<u8> {
+        self.count = 0;
+        ::std::mem::take(&mut self.buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packed_frames_round_trip() {
+        let msgs = vec![b"".to_vec(), b"a".to_vec(), vec![0u8; 300]];
+        let frame = pack(&msgs).unwrap();
+        assert_eq!(frame.len(), 3 * LENGTH_PREFIX + 301);
+        assert_eq!(unpack(&frame).unwrap(), msgs);
+        assert!(unpack(&[]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn truncated_frames_are_invalid_data() {
+        let frame = pack(["truncated"]).unwrap();
+        for end in &[2, 6] {
+            let err = unpack(&frame[..*end]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn encoders_fill_frames_up_to_their_size() {
+        let mut encoder = PackedEncoder::new(16);
+        assert!(!encoder.is_full_for(&[0u8; 32]));
+        encoder.push(b"12345678").unwrap();
+        assert!(encoder.is_full_for(b"1"));
+        assert!(!encoder.is_full_for(b""));
+        encoder.push(b"").unwrap();
+        assert_eq!(encoder.len(), 2);
+        assert_eq!(unpack(&encoder.take()).unwrap().len(), 2);
+        assert!(encoder.is_empty());
+    }
+}
@@ -0,0 +1,468 @@
+//! Compression of the frames of messages.
+//!
+//! A `CompressedCodec` compresses every frame above a size threshold, with a `Compressor`,
+//! and tags each frame with a header byte, so that receivers decompress only the frames
+//! that were compressed:
+//!
+//! * `0`, followed by the frame, as it was given.
+//! * The tag of the compressor, followed by the size of the frame, a big-endian `u32`, and
+//!   the compressed frame.
+//!
+//! Frames that would not shrink are sent as they were given. `Lz4` is available with the
+//! `lz4` feature, which is a default one, `Zstd` with the `zstd` feature, and `Deflate` with
+//! the `deflate` feature. Envelopes, e.g. the identities of `ROUTER` peers, are not frames
+//! to compress: encode the payload only.
+//!
+//! With the `async-tokio` feature, a `CompressedCodec` is also a codec of `Framed`
+//! transports, of messages as `Vec<Vec<u8>>`.
+//!
+//! ```
+//! # #[cfg(feature = "lz4")]
+//! # {
+//! use neuras::socket::{CompressedCodec, Lz4};
+//!
+//! let codec = CompressedCodec::new(Lz4, 64);
+//! let telemetry = "temp=21;".repeat(100);
+//! let frame = codec.encode(telemetry.as_bytes()).unwrap();
+//! assert!(frame.len() < telemetry.len() / 4);
+//! assert_eq!(codec.decode(&frame).unwrap(), telemetry.as_bytes());
+//! # }
+//! ```
+#[cfg(feature = "async-tokio")]
+use super::tokio::framed::{Decoder, Encoder};
+use super::{SocketRecv, SocketSend};
+
+use std::io;
+#[cfg(feature = "async-tokio")]
+use zmq::Message;
+
+/// Header byte of the frames that were not compressed.
+pub const UNCOMPRESSED: u8 = 0;
+/// Default limit, in bytes, of a decompressed frame.
+pub const MAX_DECOMPRESSED_SIZE: usize = 64 * 1024 * 1024;
+
+// Size of the header of compressed frames: the tag, and the size of the frame.
+const HEADER_SIZE: usize = 5;
+
+fn invalid(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, reason.to_string())
+}
+
+// Fails if `data` claims to decompress to more than `MAX_DECOMPRESSED_SIZE` bytes, or to
+// more than `max_ratio` times its size, before the frame is allocated.
+#[cfg(any(feature = "lz4", feature = "zstd", feature = "deflate"))]
+fn check_size(data: &[u8], size: usize, max_ratio: usize) -> io::Result<()> {
+    if size > MAX_DECOMPRESSED_SIZE || size > data.len().saturating_mul(max_ratio) {
+        return Err(invalid("decompressed frame is too large"));
+    }
+    Ok(())
+}
+
+/// A compression algorithm for `CompressedCodec`.
+pub trait Compressor {
+    /// Tag of the frames compressed by the algorithm, other than `UNCOMPRESSED`.
+    fn tag(&self) -> u8;
+
+    /// Compress `data`.
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>>;
+
+    /// Decompress `data`, which was `size` bytes long. Implementations check `size` before
+    /// they allocate the frame, as it comes from the peer.
+    fn decompress(&self, data: &[u8], size: usize) -> io::Result<Vec<u8>>;
+}
+
+/// Compresses frames above a threshold, and decompresses them on reception.
+#[derive(Clone, Debug)]
+pub struct CompressedCodec<C> {
+    compressor: C,
+    threshold: usize,
+    max_size: usize,
+}
+
+impl<C: Compressor> CompressedCodec<C> {
+    /// Create a codec that compresses frames of at least `threshold` bytes.
+    pub fn new(compressor: C, threshold: usize) -> CompressedCodec<C> {
+        CompressedCodec {
+            compressor,
+            threshold,
+            max_size: MAX_DECOMPRESSED_SIZE,
+        }
+    }
+
+    /// Set the limit, in bytes, of decompressed frames; larger frames are rejected. Frames
+    /// are never larger than `MAX_DECOMPRESSED_SIZE`.
+    pub fn set_max_size(&mut self, max_size: usize) {
+        self.max_size = max_size;
+    }
+
+    /// Returns the compressor of the codec.
+    pub fn compressor(&self) -> &C {
+        &self.compressor
+    }
+
+    /// Encode `frame`, compressed if it is above the threshold, and it shrinks.
+    pub fn encode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        if frame.len() >= self.threshold && frame.len() <= u32::MAX as usize {
+            let compressed = self.compressor.compress(frame)?;
+            if compressed.len() + HEADER_SIZE < frame.len() + 1 {
+                let mut encoded = Vec::with_capacity(HEADER_SIZE + compressed.len());
+                encoded.push(self.compressor.tag());
+                encoded.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+                encoded.extend_from_slice(&compressed);
+                return Ok(encoded);
+            }
+        }
+        let mut encoded = Vec::with_capacity(frame.len() + 1);
+        encoded.push(UNCOMPRESSED);
+        encoded.extend_from_slice(frame);
+        Ok(encoded)
+    }
+
+    /// Decode a frame encoded by `encode`.
+    pub fn decode(&self, frame: &[u8]) -> io::Result<Vec<u8>> {
+        match frame.first() {
+            Some(&UNCOMPRESSED) => Ok(frame[1..].to_vec()),
+            Some(&tag) if tag == self.compressor.tag() && frame.len() >= HEADER_SIZE => {
+                let mut size = [0u8; 4];
+                size.copy_from_slice(&frame[1..HEADER_SIZE]);
+                let size = u32::from_be_bytes(size) as usize;
+                if size > self.max_size {
+                    return Err(invalid("decompressed frame is too large"));
+                }
+                let decompressed = self.compressor.decompress(&frame[HEADER_SIZE..], size)?;
+                if decompressed.len() != size {
+                    return Err(invalid("decompressed frame has the wrong size"));
+                }
+                Ok(decompressed)
+            }
+            Some(_) => Err(invalid("frame was compressed with another algorithm")),
+            None => Err(invalid("frame has no compression header")),
+        }
+    }
+
+    /// Encode every frame of a message.
+    pub fn encode_multipart<I, T>(&self, frames: I) -> io::Result<Vec<Vec<u8>>>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        frames
+            .into_iter()
+            .map(|frame| self.encode(frame.as_ref()))
+            .collect()
+    }
+
+    /// Decode every frame of a message.
+    pub fn decode_multipart<I, T>(&self, frames: I) -> io::Result<Vec<Vec<u8>>>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        frames
+            .into_iter()
+            .map(|frame| self.decode(frame.as_ref()))
+            .collect()
+    }
+
+    /// Encode, and send, a message on `socket`.
+    pub fn send_multipart<S, I, T>(&self, socket: &S, frames: I, flags: i32) -> io::Result<()>
+    where
+        S: SocketSend,
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        socket.send_multipart(self.encode_multipart(frames)?, flags)
+    }
+
+    /// Receive, and decode, a message from `socket`.
+    pub fn recv_multipart<S: SocketRecv>(
+        &self,
+        socket: &S,
+        flags: i32,
+    ) -> io::Result<Vec<Vec<u8>>> {
+        self.decode_multipart(socket.recv_multipart(flags)?)
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+impl<C: Compressor> Decoder for CompressedCodec<C> {
+    type Item = Vec<Vec<u8>>;
+    type Error = io::Error;
+
+    fn decode(&mut self, frames: Vec<Message>) -> io::Result<Vec<Vec<u8>>> {
+        self.decode_multipart(frames.iter().map(|frame| &frame[..]))
+    }
+}
+
+#[cfg(feature = "async-tokio")]
+impl<C: Compressor> Encoder for CompressedCodec<C> {
+    type Item = Vec<Vec<u8>>;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Vec<Vec<u8>>) -> io::Result<Vec<Message>> {
+        Ok(self
+            .encode_multipart(item)?
+            .into_iter()
+            .map(Message::from)
+            .collect())
+    }
+}
+
+// Every byte of an LZ4 block adds at most 255 bytes to the frame.
+#[cfg(feature = "lz4")]
+const LZ4_MAX_RATIO: usize = 255;
+
+/// The LZ4 block format, which is fast.
+#[cfg(feature = "lz4")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Lz4;
+
+#[cfg(feature = "lz4")]
+impl Compressor for Lz4 {
+    fn tag(&self) -> u8 {
+        1
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(::lz4_flex::block::compress(data))
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        check_size(data, size, LZ4_MAX_RATIO)?;
+        ::lz4_flex::block::decompress(data, size)
+            .map_err(|e| invalid(&format!("invalid lz4 data: {}", e)))
+    }
+}
+
+/// The Zstandard format, which compresses about as well as `Deflate`, about as fast as
+/// `Lz4`.
+#[cfg(feature = "zstd")]
+#[derive(Clone, Copy, Debug)]
+pub struct Zstd {
+    level: i32,
+}
+
+#[cfg(feature = "zstd")]
+impl Zstd {
+    /// Compress at `level`, from 1, the fastest, to 22, the best.
+    pub fn new(level: i32) -> Zstd {
+        Zstd {
+            level: level.clamp(1, 22),
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Default for Zstd {
+    fn default() -> Zstd {
+        Zstd::new(::zstd::DEFAULT_COMPRESSION_LEVEL)
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl Compressor for Zstd {
+    fn tag(&self) -> u8 {
+        3
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        ::zstd::block::compress(data, self.level)
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        // zstd frames carry their size, which is checked before the frame is allocated.
+        if ::zstd::zstd_safe::get_frame_content_size(data) != size as u64 {
+            return Err(invalid("zstd frame has the wrong size"));
+        }
+        check_size(data, size, usize::MAX)?;
+        ::zstd::block::decompress(data, size)
+    }
+}
+
+// DEFLATE compresses at most 1032 to 1.
+#[cfg(feature = "deflate")]
+const DEFLATE_MAX_RATIO: usize = 1032;
+
+/// The DEFLATE format, which compresses better than `Lz4`, but slower.
+#[cfg(feature = "deflate")]
+#[derive(Clone, Copy, Debug)]
+pub struct Deflate {
+    level: u8,
+}
+
+#[cfg(feature = "deflate")]
+impl Deflate {
+    /// Compress at `level`, from 0, no compression, to 10, the best.
+    pub fn new(level: u8) -> Deflate {
+        Deflate {
+            level: level.min(10),
+        }
+    }
+}
+
+#[cfg(feature = "deflate")]
+impl Default for Deflate {
+    fn default() -> Deflate {
+        Deflate::new(6)
+    }
+}
+
+#[cfg(feature = "deflate")]
+impl Compressor for Deflate {
+    fn tag(&self) -> u8 {
+        2
+    }
+
+    fn compress(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        Ok(::miniz_oxide::deflate::compress_to_vec(data, self.level))
+    }
+
+    fn decompress(&self, data: &[u8], size: usize) -> io::Result<Vec<u8>> {
+        use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+        use miniz_oxide::inflate::TINFLStatus;
+
+        // the output never grows past `size`, so that frames can't inflate without bounds.
+        check_size(data, size, DEFLATE_MAX_RATIO)?;
+        let mut out = vec![0; size];
+        let mut inflater = DecompressorOxide::default();
+        let flags = inflate_flags::TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF;
+        match decompress(&mut inflater, data, &mut out, 0, flags) {
+            (TINFLStatus::Done, _, written) if written == size => Ok(out),
+            (status, _, _) => Err(invalid(&format!("invalid deflate data: {:?}", status))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_blocks_follow_the_format() {
+        // "abc", a match of 9 bytes, 3 bytes back, and the last literals.
+        let block = b"\x35abc\x03\x00\x50xyzzy";
+        let expected = b"abcabcabcabcxyzzy";
+        assert_eq!(
+            Lz4.decompress(block, expected.len()).unwrap(),
+            &expected[..]
+        );
+        assert!(Lz4.decompress(b"\x35abc\x09\x00\x50xyzzy", 17).is_err());
+        assert!(Lz4.decompress(block, 10).is_err());
+        // sizes that the block cannot have are rejected before they are allocated.
+        assert!(Lz4.decompress(block, usize::MAX).is_err());
+        assert!(Lz4.decompress(block, MAX_DECOMPRESSED_SIZE + 1).is_err());
+
+        let mut noise = Vec::new();
+        let mut seed = 7u32;
+        for _ in 0..5_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+            noise.push((seed >> 16) as u8);
+        }
+        let long_literals = (0..300u32).map(|i| i as u8).collect::<Vec<_>>();
+        for data in [
+            &b""[..],
+            &b"short"[..],
+            "temp=21;humidity=40;".repeat(500).as_bytes(),
+            &vec![0u8; 70_000],
+            &noise,
+            &long_literals,
+        ]
+        .iter()
+        {
+            let compressed = Lz4.compress(data).unwrap();
+            assert_eq!(&Lz4.decompress(&compressed, data.len()).unwrap()[..], *data);
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn codecs_only_compress_frames_that_shrink() {
+        let codec = CompressedCodec::new(Lz4, 32);
+        let telemetry = "sensor=7;temp=21.5;".repeat(200);
+        let frames = codec
+            .encode_multipart(vec!["small".as_bytes(), telemetry.as_bytes()])
+            .unwrap();
+        assert_eq!(frames[0], b"\x00small");
+        assert_eq!(frames[1][0], Lz4.tag());
+        assert!(frames[1].len() < telemetry.len() / 10);
+        let decoded = codec.decode_multipart(&frames).unwrap();
+        assert_eq!(decoded, vec![b"small".to_vec(), telemetry.into_bytes()]);
+
+        let mut limited = CompressedCodec::new(Lz4, 32);
+        limited.set_max_size(100);
+        assert!(limited.decode(&frames[1]).is_err());
+        assert!(codec.decode(b"\x07data").is_err());
+
+        let ctx = zmq::Context::new();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://compressed").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect("inproc://compressed").unwrap();
+        codec
+            .send_multipart(&push, vec![vec![b'x'; 1_000]], 0)
+            .unwrap();
+        assert_eq!(
+            codec.recv_multipart(&pull, 0).unwrap(),
+            vec![vec![b'x'; 1_000]]
+        );
+    }
+
+    #[cfg(feature = "deflate")]
+    #[test]
+    fn deflate_frames_are_bounded_by_their_size() {
+        let codec = CompressedCodec::new(Deflate::default(), 32);
+        let telemetry = "sensor=7;temp=21.5;".repeat(200);
+        let frame = codec.encode(telemetry.as_bytes()).unwrap();
+        assert_eq!(frame[0], Deflate::default().tag());
+        assert_eq!(codec.decode(&frame).unwrap(), telemetry.as_bytes());
+        let compressed = Deflate::default().compress(telemetry.as_bytes()).unwrap();
+        assert!(Deflate::default().decompress(&compressed, 100).is_err());
+        assert!(Deflate::default()
+            .decompress(&compressed, usize::MAX)
+            .is_err());
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_frames_carry_their_size() {
+        let codec = CompressedCodec::new(Zstd::default(), 32);
+        let telemetry = "sensor=7;temp=21.5;".repeat(200);
+        let frame = codec.encode(telemetry.as_bytes()).unwrap();
+        assert_eq!(frame[0], Zstd::default().tag());
+        assert_eq!(codec.decode(&frame).unwrap(), telemetry.as_bytes());
+        let compressed = Zstd::default().compress(telemetry.as_bytes()).unwrap();
+        assert!(Zstd::default().decompress(&compressed, 100).is_err());
+        assert!(Zstd::default().decompress(&compressed, usize::MAX).is_err());
+    }
+
+    #[cfg(all(feature = "async-tokio", feature = "lz4"))]
+    #[test]
+    fn framed_transports_compress_their_messages() {
+        use futures::{Future, Sink, Stream};
+        use socket::tokio::TokioSocket;
+        use tokio_core::reactor::Core;
+
+        let ctx = zmq::Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://compressed_framed").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect("inproc://compressed_framed").unwrap();
+        let pull = TokioSocket::new(pull, &handle).unwrap();
+        let push = TokioSocket::new(push, &handle).unwrap();
+
+        let codec = CompressedCodec::new(Lz4, 32);
+        let msg = vec![b"small".to_vec(), vec![b'x'; 1_000]];
+        let _ = core
+            .run(push.framed_with(codec.clone()).send(msg.clone()))
+            .unwrap();
+        let received = core
+            .run(pull.framed_with(codec).into_future().map_err(|(e, _)| e))
+            .unwrap()
+            .0
+            .unwrap();
+        assert_eq!(received, msg);
+    }
+}
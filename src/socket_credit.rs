@@ -0,0 +1,266 @@
+//! Credit-based flow control.
+//!
+//! An implementation of the
+//! "[credit-based flow control](http://zguide.zeromq.org/page:all#Credit-Based-Flow-Control)"
+//! from the zguide.
+//!
+//! A `FlowSender` is a `DEALER` socket that may only send as many messages as it has
+//! credit for. A `FlowReceiver` is a `ROUTER` socket that gives every sender an initial
+//! window of credit, and gives it back as its messages are received, so fast producers
+//! stall, instead of filling the queues up to the high-water mark, where messages are lost.
+//!
+//! Senders announce themselves with `[READY]`, and send messages as `[MESSAGE, frames...]`.
+//! Receivers grant credit with `[CREDIT, amount]`, where the amount is a big-endian `u32`.
+use super::flow::FlowError;
+
+use std::collections::HashMap;
+use zmq;
+
+/// First frame of the credit granted to senders.
+pub const CREDIT: &str = "$CREDIT";
+/// First frame of the messages from senders.
+pub const MESSAGE: &str = "$MESSAGE";
+/// Sent by senders when they connect, to ask for their initial credit.
+pub const READY: &str = "$READY";
+
+fn credit_frames(amount: u32) -> Vec<Vec<u8>> {
+    vec![CREDIT.as_bytes().to_vec(), amount.to_be_bytes().to_vec()]
+}
+
+fn decode_credit(frames: &[Vec<u8>]) -> Result<u32, FlowError> {
+    match frames {
+        [command, amount] if command == CREDIT.as_bytes() && amount.len() == 4 => {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(amount);
+            Ok(u32::from_be_bytes(bytes))
+        }
+        _ => Err(FlowError::InvalidMessage),
+    }
+}
+
+/// A `DEALER` socket that sends messages as long as it has credit from its receiver.
+pub struct FlowSender {
+    socket: zmq::Socket,
+    credit: u32,
+}
+
+impl FlowSender {
+    /// Create a new `FlowSender`, with a `DEALER` socket from `context`.
+    pub fn new(context: &zmq::Context) -> Result<FlowSender, FlowError> {
+        Ok(FlowSender {
+            socket: context.socket(zmq::DEALER)?,
+            credit: 0,
+        })
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Connect to a `FlowReceiver`, and ask for credit.
+    pub fn connect(&self, endpoint: &str) -> Result<(), FlowError> {
+        self.socket.connect(endpoint)?;
+        self.socket.send(READY, 0)?;
+        Ok(())
+    }
+
+    /// Returns the credit left, after taking the credit that was granted since the last
+    /// call.
+    pub fn credit(&mut self) -> Result<u32, FlowError> {
+        self.update_credit(0)?;
+        Ok(self.credit)
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it is `-1`, until there is credit
+    /// to send. Returns false if the timeout expired first.
+    pub fn wait_for_credit(&mut self, timeout: i64) -> Result<bool, FlowError> {
+        self.update_credit(timeout)?;
+        Ok(self.credit > 0)
+    }
+
+    /// Send a multipart message, using one unit of credit. Fails with
+    /// `FlowError::NoCredit` when there is none left, without sending the message.
+    pub fn send_multipart<I, T>(&mut self, msg: I, flags: i32) -> Result<(), FlowError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        if self.credit()? == 0 {
+            return Err(FlowError::NoCredit);
+        }
+        let mut frames = vec![MESSAGE.as_bytes().to_vec()];
+        frames.extend(msg.into_iter().map(Into::into));
+        self.socket.send_multipart(frames, flags)?;
+        self.credit -= 1;
+        Ok(())
+    }
+
+    /// Send a message, using one unit of credit.
+    pub fn send<T>(&mut self, msg: T, flags: i32) -> Result<(), FlowError>
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.send_multipart(vec![msg.into()], flags)
+    }
+
+    // Take the credit that was granted, waiting up to `timeout` for the first grant when
+    // there is no credit left.
+    fn update_credit(&mut self, timeout: i64) -> Result<(), FlowError> {
+        let mut timeout = if self.credit == 0 { timeout } else { 0 };
+        while self.socket.poll(zmq::POLLIN, timeout)? > 0 {
+            let frames = self.socket.recv_multipart(0)?;
+            self.credit = self.credit.saturating_add(decode_credit(&frames)?);
+            timeout = 0;
+        }
+        Ok(())
+    }
+}
+
+/// A `ROUTER` socket that grants credit to each `FlowSender`, and gives it back as their
+/// messages are received.
+pub struct FlowReceiver {
+    socket: zmq::Socket,
+    window: u32,
+    batch: u32,
+    received: HashMap<Vec<u8>, u32>,
+}
+
+impl FlowReceiver {
+    /// Create a new `FlowReceiver`, with a `ROUTER` socket from `context`, that lets each
+    /// sender have up to `window` messages in flight.
+    pub fn new(context: &zmq::Context, window: u32) -> Result<FlowReceiver, FlowError> {
+        Ok(FlowReceiver {
+            socket: context.socket(zmq::ROUTER)?,
+            window,
+            batch: (window / 2).max(1),
+            received: HashMap::new(),
+        })
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Returns the number of messages each sender may have in flight.
+    pub fn window(&self) -> u32 {
+        self.window
+    }
+
+    /// Set the number of messages that are received from a sender before their credit is
+    /// given back, in a single grant. Defaults to half of the window.
+    pub fn set_batch(&mut self, batch: u32) {
+        self.batch = batch.clamp(1, self.window.max(1));
+    }
+
+    /// Bind the socket to an endpoint.
+    pub fn bind(&self, endpoint: &str) -> Result<(), FlowError> {
+        Ok(self.socket.bind(endpoint)?)
+    }
+
+    /// Receive the next message, as the identity of its sender, and its frames. Credit
+    /// requests are answered on the way.
+    pub fn recv(&mut self, flags: i32) -> Result<(Vec<u8>, Vec<Vec<u8>>), FlowError> {
+        loop {
+            let mut frames = self.socket.recv_multipart(flags)?;
+            if frames.len() < 2 {
+                return Err(FlowError::InvalidMessage);
+            }
+            let msg = frames.split_off(2);
+            let identity = frames.swap_remove(0);
+            match &frames[0][..] {
+                b"$READY" => {
+                    nlog!(trace, "granting credit window={}", self.window);
+                    self.received.insert(identity.clone(), 0);
+                    self.grant(identity, self.window)?;
+                }
+                b"$MESSAGE" => {
+                    self.replenish(&identity)?;
+                    return Ok((identity, msg));
+                }
+                _ => return Err(FlowError::InvalidMessage),
+            }
+        }
+    }
+
+    // Count a message received from `identity`, giving its credit back once a batch is
+    // complete.
+    fn replenish(&mut self, identity: &[u8]) -> Result<(), FlowError> {
+        let received = self.received.entry(identity.to_vec()).or_insert(0);
+        *received += 1;
+        if *received < self.batch {
+            return Ok(());
+        }
+        let amount = *received;
+        *received = 0;
+        self.grant(identity.to_vec(), amount)
+    }
+
+    fn grant(&self, identity: Vec<u8>, amount: u32) -> Result<(), FlowError> {
+        let mut frames = vec![identity];
+        frames.extend(credit_frames(amount));
+        self.socket.send_multipart(frames, 0)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn setup(endpoint: &str, ctx: &zmq::Context, window: u32) -> (FlowSender, FlowReceiver) {
+        let receiver = FlowReceiver::new(ctx, window).unwrap();
+        receiver.bind(endpoint).unwrap();
+        let sender = FlowSender::new(ctx).unwrap();
+        sender.get_ref().set_linger(0).unwrap();
+        sender.connect(endpoint).unwrap();
+        (sender, receiver)
+    }
+
+    #[test]
+    fn senders_stall_without_credit() {
+        let ctx = zmq::Context::new();
+        let (mut sender, _receiver) = setup("inproc://credit_stall", &ctx, 4);
+        // the receiver has not answered the credit request yet.
+        assert_eq!(sender.credit().unwrap(), 0);
+        match sender.send("early", 0) {
+            Err(FlowError::NoCredit) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+        assert!(!sender.wait_for_credit(10).unwrap());
+    }
+
+    #[test]
+    fn senders_recover_as_receivers_drain() {
+        let ctx = zmq::Context::new();
+        let (mut sender, mut receiver) = setup("inproc://credit_recover", &ctx, 4);
+        receiver.get_ref().set_rcvtimeo(100).unwrap();
+        // the credit request is answered while waiting for messages.
+        assert!(receiver.recv(0).is_err());
+        assert!(sender.wait_for_credit(1_000).unwrap());
+        assert_eq!(sender.credit().unwrap(), 4);
+
+        for i in 0..4u8 {
+            sender.send(vec![i], 0).unwrap();
+        }
+        match sender.send("stalled", 0) {
+            Err(FlowError::NoCredit) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+
+        // draining half of the window gives that credit back.
+        for i in 0..2u8 {
+            let (_, msg) = receiver.recv(0).unwrap();
+            assert_eq!(msg, vec![vec![i]]);
+        }
+        assert!(sender.wait_for_credit(1_000).unwrap());
+        assert_eq!(sender.credit().unwrap(), 2);
+        sender.send("recovered", 0).unwrap();
+        for _ in 0..2 {
+            receiver.recv(0).unwrap();
+        }
+        let (_, msg) = receiver.recv(0).unwrap();
+        assert_eq!(msg, vec![b"recovered".to_vec()]);
+    }
+}
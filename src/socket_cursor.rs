@@ -0,0 +1,271 @@
+//! Multi-part messages that are sent one frame at a time.
+//!
+//! Once the first frame of a multi-part message is sent, the following frames have to be
+//! sent on the same socket before any other message. A `MultipartCursor` keeps how many
+//! frames went out, so that sends that fail with `WouldBlock`, or `Interrupted`, are retried
+//! from the failed frame. Other failures after the first frame abandon the message, with a
+//! `PartialSend` error that tells how many frames were sent; the socket is then left in the
+//! middle of the message, and should be closed.
+use super::SocketSend;
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::ops::Deref;
+use std::sync::Arc;
+use zmq;
+
+/// The error of a multi-part message that was abandoned after some of its frames were sent,
+/// as the inner error of an `io::Error` of the same kind as its `cause`.
+#[derive(Debug)]
+pub struct PartialSend {
+    /// Number of frames that were sent.
+    pub sent: usize,
+    /// Number of frames of the message.
+    pub total: usize,
+    /// The error of the frame that failed.
+    pub cause: io::Error,
+}
+
+impl PartialSend {
+    /// Returns the `PartialSend` inside of `error`, if any.
+    pub fn from_io(error: &io::Error) -> Option<&PartialSend> {
+        error.get_ref().and_then(|inner| inner.downcast_ref())
+    }
+}
+
+impl fmt::Display for PartialSend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "multi-part message abandoned after {} of {} frames: {}",
+            self.sent, self.total, self.cause
+        )
+    }
+}
+
+impl error::Error for PartialSend {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.cause)
+    }
+}
+
+// A frame of a message, which retries send without cloning it.
+enum Frame {
+    Owned(Vec<u8>),
+    Shared(Arc<[u8]>),
+}
+
+impl Deref for Frame {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match *self {
+            Frame::Owned(ref frame) => frame,
+            Frame::Shared(ref frame) => frame,
+        }
+    }
+}
+
+/// A multi-part message, and the number of its frames that were sent.
+pub struct MultipartCursor {
+    frames: Vec<Frame>,
+    sent: usize,
+    abandoned: bool,
+}
+
+impl MultipartCursor {
+    /// Create a new `MultipartCursor` for the `frames` of a message.
+    pub fn new<I, T>(frames: I) -> MultipartCursor
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        MultipartCursor::from_frames(frames.into_iter().map(|f| Frame::Owned(f.into())))
+    }
+
+    /// Create a new `MultipartCursor` from shared buffers, which are not cloned by retries.
+    pub fn from_shared(frames: Vec<Arc<[u8]>>) -> MultipartCursor {
+        MultipartCursor::from_frames(frames.into_iter().map(Frame::Shared))
+    }
+
+    fn from_frames<I: Iterator<Item = Frame>>(frames: I) -> MultipartCursor {
+        MultipartCursor {
+            frames: frames.collect(),
+            sent: 0,
+            abandoned: false,
+        }
+    }
+
+    /// Returns the number of frames that were sent.
+    pub fn sent(&self) -> usize {
+        self.sent
+    }
+
+    /// Returns the number of frames of the message.
+    pub fn total(&self) -> usize {
+        self.frames.len()
+    }
+
+    /// Returns true once some, but not all, of the frames were sent, and the rest are still
+    /// to be sent.
+    pub fn is_partial(&self) -> bool {
+        self.sent > 0 && !self.is_done() && !self.abandoned
+    }
+
+    /// Returns true once every frame was sent.
+    pub fn is_done(&self) -> bool {
+        self.sent == self.frames.len()
+    }
+
+    /// Returns true if the message was abandoned, after a `PartialSend` error.
+    pub fn is_abandoned(&self) -> bool {
+        self.abandoned
+    }
+
+    /// Returns the frames of the message.
+    pub fn into_frames(self) -> Vec<Vec<u8>> {
+        self.frames
+            .into_iter()
+            .map(|frame| match frame {
+                Frame::Owned(frame) => frame,
+                Frame::Shared(frame) => frame.to_vec(),
+            })
+            .collect()
+    }
+
+    /// Send the frames that were not sent yet, on `socket`, with `flags`. Fails with a
+    /// `PartialSend` error if the message has to be abandoned after its first frame, and
+    /// does nothing once it was.
+    pub fn send<S: SocketSend>(&mut self, socket: &S, flags: i32) -> io::Result<()> {
+        while !self.is_done() && !self.abandoned {
+            let more = if self.sent + 1 < self.frames.len() {
+                zmq::SNDMORE
+            } else {
+                0
+            };
+            match socket.send(&self.frames[self.sent][..], flags | more) {
+                Ok(()) => self.sent += 1,
+                Err(e) => return Err(self.failed(e)),
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the error of a failed frame, abandoning the message unless it can be retried.
+    fn failed(&mut self, cause: io::Error) -> io::Error {
+        let retries = matches!(
+            cause.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+        );
+        if self.sent == 0 || retries {
+            return cause;
+        }
+        let kind = cause.kind();
+        let partial = PartialSend {
+            sent: self.sent,
+            total: self.frames.len(),
+            cause,
+        };
+        self.abandoned = true;
+        io::Error::new(kind, partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    // Accepts `limit` frames, and then fails with `kind`.
+    struct Flaky {
+        socket: zmq::Socket,
+        limit: Cell<usize>,
+        kind: io::ErrorKind,
+    }
+
+    impl super::super::SocketWrapper for Flaky {
+        fn get_socket_ref(&self) -> &zmq::Socket {
+            &self.socket
+        }
+        fn get_rcvmore(&self) -> io::Result<bool> {
+            Ok(self.socket.get_rcvmore()?)
+        }
+    }
+
+    impl SocketSend for Flaky {
+        fn send<M: Into<zmq::Message>>(&self, msg: M, flags: i32) -> io::Result<()> {
+            if self.limit.get() == 0 {
+                return Err(self.kind.into());
+            }
+            self.limit.set(self.limit.get() - 1);
+            SocketSend::send(&self.socket, msg, flags)
+        }
+
+        fn send_multipart<I, T>(&self, msg: I, flags: i32) -> io::Result<()>
+        where
+            I: IntoIterator<Item = T>,
+            T: Into<zmq::Message>,
+        {
+            let mut frames = msg.into_iter().peekable();
+            while let Some(frame) = frames.next() {
+                let more = if frames.peek().is_some() {
+                    zmq::SNDMORE
+                } else {
+                    0
+                };
+                self.send(frame, flags | more)?;
+            }
+            Ok(())
+        }
+    }
+
+    fn flaky(endpoint: &str, limit: usize, kind: io::ErrorKind) -> (Flaky, zmq::Socket) {
+        let ctx = zmq::Context::new();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.bind(endpoint).unwrap();
+        let socket = ctx.socket(zmq::PAIR).unwrap();
+        socket.connect(endpoint).unwrap();
+        let limit = Cell::new(limit);
+        (
+            Flaky {
+                socket,
+                limit,
+                kind,
+            },
+            receiver,
+        )
+    }
+
+    #[test]
+    fn retries_resume_from_the_failed_frame() {
+        let (socket, receiver) = flaky("inproc://cursor_retry", 1, io::ErrorKind::WouldBlock);
+        let mut cursor = MultipartCursor::new(vec!["a", "b", "c"]);
+        let e = cursor.send(&socket, 0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::WouldBlock);
+        assert!(cursor.is_partial());
+        socket.limit.set(2);
+        cursor.send(&socket, 0).unwrap();
+        assert!(cursor.is_done());
+        let msg = receiver.recv_multipart(0).unwrap();
+        assert_eq!(msg, vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()]);
+    }
+
+    #[test]
+    fn failures_midway_abandon_the_message() {
+        let (socket, _receiver) = flaky("inproc://cursor_abandon", 2, io::ErrorKind::Other);
+        let mut cursor = MultipartCursor::new(vec!["a", "b", "c"]);
+        let e = cursor.send(&socket, 0).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::Other);
+        let partial = PartialSend::from_io(&e).unwrap();
+        assert_eq!((partial.sent, partial.total), (2, 3));
+        assert!(cursor.is_abandoned());
+        assert!(cursor.send(&socket, 0).is_ok());
+        // failures of the first frame leave the message untouched.
+        let (socket, _receiver) = flaky("inproc://cursor_first", 0, io::ErrorKind::Other);
+        let mut cursor = MultipartCursor::new(vec!["a", "b"]);
+        let e = cursor.send(&socket, 0).unwrap_err();
+        assert!(PartialSend::from_io(&e).is_none());
+        assert_eq!(cursor.sent(), 0);
+    }
+}
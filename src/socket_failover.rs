@@ -0,0 +1,467 @@
+//! Connecting sockets to an ordered list of endpoints.
+//!
+//! An `EndpointList` holds the endpoints of a service, in order of preference, and a
+//! `ConnectPolicy` for them: connect to all of them at once, or to one at a time, moving
+//! on to the next one when the socket monitor reports that the connection failed, or was
+//! lost. Dead peers that keep their connection open are found with the `PING`, and `PONG`,
+//! heartbeats of ZMTP 3.1, see `EndpointList::heartbeat`, which close the connection of a
+//! peer that stops answering.
+//!
+//! libzmq does not monitor `inproc` transports, and endpoints are told apart by the address
+//! that the monitor reports, so failover needs numeric addresses, e.g. `tcp://127.0.0.1:5555`.
+//!
+//! Sockets do not fail back on their own: once a socket moved on from its preferred
+//! endpoint, it stays on the endpoint that works, until that one fails too, or
+//! `FailoverSocket::fail_back` is called, e.g. when the preferred peer is known to be up.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::{ConnectPolicy, EndpointList, SocketWrapper};
+//! use std::time::Duration;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let socket = ctx.socket(zmq::DEALER).unwrap();
+//! let failover = EndpointList::new(ConnectPolicy::Failover)
+//!     .endpoint("tcp://127.0.0.1:5555")
+//!     .endpoint("tcp://127.0.0.1:5556")
+//!     .heartbeat(Duration::from_secs(1), Duration::from_secs(3))
+//!     .connect(&ctx, socket)
+//!     .unwrap();
+//! assert_eq!(failover.current(), Some("tcp://127.0.0.1:5555"));
+//! assert_eq!(failover.get_socket_ref().get_socket_type().unwrap(), zmq::DEALER);
+//! # }
+//! ```
+use super::{SocketError, SocketRecv, SocketSend, SocketWrapper, ZmtpHeartbeat};
+
+use std::cell::Cell;
+use std::io;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use uuid::Uuid;
+use zmq;
+
+// Events used to follow the connection of the current endpoint.
+const CONNECTION_EVENTS: i32 = zmq::SocketEvent::CONNECTED as i32
+    | zmq::SocketEvent::CONNECT_RETRIED as i32
+    | zmq::SocketEvent::DISCONNECTED as i32;
+
+// The endpoint that the next `RoundRobin` socket starts with.
+static NEXT_START: AtomicUsize = AtomicUsize::new(0);
+
+/// How a socket connects to the endpoints of an `EndpointList`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectPolicy {
+    /// Connect to every endpoint, letting the socket spread the messages, as per its type.
+    ConnectAll,
+    /// Connect to one endpoint at a time, starting with the first, and moving on to the
+    /// next one, in order, when the connection fails.
+    Failover,
+    /// Connect to one endpoint at a time, as `Failover`, but each socket starts with the
+    /// endpoint after the one that the previous socket started with, so that sockets are
+    /// spread over the endpoints.
+    RoundRobin,
+}
+
+/// A change of the connections of a `FailoverSocket`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum FailoverEvent {
+    /// The socket connected to `endpoint`.
+    Connected { endpoint: String },
+    /// The connection to `from` failed, or was lost, and the socket moved on to `to`.
+    FailedOver { from: String, to: String },
+}
+
+/// Ordered list of endpoints, with the policy to connect to them.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EndpointList {
+    endpoints: Vec<String>,
+    policy: ConnectPolicy,
+    heartbeat: Option<ZmtpHeartbeat>,
+}
+
+impl EndpointList {
+    /// Create an empty `EndpointList`, connected to with `policy`.
+    pub fn new(policy: ConnectPolicy) -> EndpointList {
+        EndpointList {
+            endpoints: Vec::new(),
+            policy,
+            heartbeat: None,
+        }
+    }
+
+    /// Add `endpoint` at the end of the list, after the preferred ones.
+    pub fn endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoints.push(endpoint.to_string());
+        self
+    }
+
+    /// Send a ZMTP 3.1 `PING` every `interval` on every connection, and close connections
+    /// that receive nothing for `timeout`, so that dead peers count as failed. Peers that
+    /// only speak older versions of ZMTP are not checked.
+    pub fn heartbeat(mut self, interval: Duration, timeout: Duration) -> Self {
+        let ttl = Duration::from_secs(0);
+        self.heartbeat = Some(ZmtpHeartbeat::new(interval, timeout, ttl));
+        self
+    }
+
+    /// Returns the endpoints, in order of preference.
+    pub fn endpoints(&self) -> &[String] {
+        &self.endpoints
+    }
+
+    /// Returns the connect policy.
+    pub fn policy(&self) -> ConnectPolicy {
+        self.policy
+    }
+
+    /// Connect `socket`, which was created by `context`, to the endpoints.
+    pub fn connect(
+        self,
+        context: &zmq::Context,
+        socket: zmq::Socket,
+    ) -> Result<FailoverSocket, SocketError> {
+        if self.endpoints.is_empty() {
+            return Err(SocketError::InvalidOption(
+                "no endpoints to connect to".into(),
+            ));
+        }
+        if let Some(ref heartbeat) = self.heartbeat {
+            heartbeat.apply(&socket)?;
+        }
+        let endpoint = format!(
+            "inproc://neuras.failover.monitor.{}",
+            Uuid::new_v4().to_simple()
+        );
+        socket.monitor(&endpoint, CONNECTION_EVENTS)?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(&endpoint)?;
+
+        let current = match self.policy {
+            ConnectPolicy::ConnectAll => {
+                for endpoint in &self.endpoints {
+                    socket.connect(endpoint)?;
+                }
+                None
+            }
+            ConnectPolicy::Failover => Some(0),
+            ConnectPolicy::RoundRobin => {
+                Some(NEXT_START.fetch_add(1, Ordering::SeqCst) % self.endpoints.len())
+            }
+        };
+        if let Some(current) = current {
+            socket.connect(&self.endpoints[current])?;
+        }
+        Ok(FailoverSocket {
+            socket,
+            monitor,
+            endpoints: self.endpoints,
+            preferred: current,
+            current: Cell::new(current),
+            sending: Cell::new(false),
+        })
+    }
+}
+
+/// Socket connected to the endpoints of an `EndpointList`.
+///
+/// Connection changes are handled whenever messages are sent, or received, with the
+/// socket, other than halfway through a multipart message, and by `poll_events`.
+pub struct FailoverSocket {
+    socket: zmq::Socket,
+    monitor: zmq::Socket,
+    endpoints: Vec<String>,
+    // the endpoint that the socket started with.
+    preferred: Option<usize>,
+    current: Cell<Option<usize>>,
+    // true while a multipart message is being sent.
+    sending: Cell<bool>,
+}
+
+impl FailoverSocket {
+    /// Returns the endpoint that the socket is connected to, or `None` if it is connected
+    /// to all of them.
+    pub fn current(&self) -> Option<&str> {
+        self.current
+            .get()
+            .map(|current| &self.endpoints[current][..])
+    }
+
+    /// Returns the endpoints, in order of preference.
+    pub fn endpoints(&self) -> &[String] {
+        &self.endpoints
+    }
+
+    /// Returns a reference to the underlying socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Handle the connection changes seen so far, and return their events.
+    pub fn poll_events(&self) -> io::Result<Vec<FailoverEvent>> {
+        let mut events = Vec::new();
+        self.handle_monitor(&mut events)?;
+        Ok(events)
+    }
+
+    /// Move back to the endpoint that the socket started with, if it moved on from it.
+    /// Returns the `FailoverEvent::FailedOver` event of the move, if there was one.
+    pub fn fail_back(&self) -> io::Result<Option<FailoverEvent>> {
+        match (self.current.get(), self.preferred) {
+            (Some(current), Some(preferred)) if current != preferred => {
+                Ok(Some(self.move_to(current, preferred)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    // Handle the connection changes, unless a multipart message is halfway sent, or
+    // received, which moving to another endpoint would cut.
+    fn handle_changes(&self) -> io::Result<()> {
+        if self.sending.get() || self.socket.get_rcvmore()? {
+            return Ok(());
+        }
+        self.handle_monitor(&mut Vec::new())
+    }
+
+    // Remember whether a multipart message is halfway sent, after sending a frame with
+    // `flags`.
+    fn sent(&self, resulting: io::Result<()>, flags: i32) -> io::Result<()> {
+        if resulting.is_ok() {
+            self.sending.set(flags & zmq::SNDMORE != 0);
+        }
+        resulting
+    }
+
+    fn handle_monitor(&self, events: &mut Vec<FailoverEvent>) -> io::Result<()> {
+        loop {
+            let msg = match self.monitor.recv_multipart(zmq::DONTWAIT) {
+                Ok(msg) => msg,
+                Err(zmq::Error::EAGAIN) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            if msg.len() < 2 || msg[0].len() < 2 {
+                continue;
+            }
+            let event = u16::from(msg[0][0]) | (u16::from(msg[0][1]) << 8);
+            let endpoint = String::from_utf8_lossy(&msg[1]).into_owned();
+            let event = match zmq::SocketEvent::from_raw(event) {
+                zmq::SocketEvent::CONNECTED => FailoverEvent::Connected { endpoint },
+                zmq::SocketEvent::CONNECT_RETRIED | zmq::SocketEvent::DISCONNECTED => {
+                    match self.fail_over(&endpoint)? {
+                        Some(event) => event,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            nlog!(debug, "failover {:?}", event);
+            events.push(event);
+        }
+    }
+
+    // Move on from `endpoint`, if it is the current one, to the next one.
+    fn fail_over(&self, endpoint: &str) -> io::Result<Option<FailoverEvent>> {
+        let current = match self.current.get() {
+            Some(current) if self.endpoints[current] == endpoint => current,
+            _ => return Ok(None),
+        };
+        let next = (current + 1) % self.endpoints.len();
+        if next == current {
+            return Ok(None);
+        }
+        Ok(Some(self.move_to(current, next)?))
+    }
+
+    // Connect to the endpoint at `next`, instead of the one at `current`.
+    fn move_to(&self, current: usize, next: usize) -> io::Result<FailoverEvent> {
+        self.socket.disconnect(&self.endpoints[current])?;
+        self.socket.connect(&self.endpoints[next])?;
+        self.current.set(Some(next));
+        Ok(FailoverEvent::FailedOver {
+            from: self.endpoints[current].clone(),
+            to: self.endpoints[next].clone(),
+        })
+    }
+}
+
+/// Implementation of the `SocketWrapper` API for failover sockets.
+impl SocketWrapper for FailoverSocket {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        Ok(self.socket.get_rcvmore()?)
+    }
+}
+
+/// Implementation of the `SocketSend` API for failover sockets, which handles the
+/// connection changes before each message.
+impl SocketSend for FailoverSocket {
+    fn send<T>(&self, msg: T, flags: i32) -> io::Result<()>
+    where
+        T: Into<zmq::Message>,
+    {
+        self.handle_changes()?;
+        self.sent(SocketSend::send(&self.socket, msg, flags), flags)
+    }
+
+    fn send_multipart<I, T>(&self, msg: I, flags: i32) -> io::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<zmq::Message>,
+    {
+        self.handle_changes()?;
+        self.sent(SocketSend::send_multipart(&self.socket, msg, flags), flags)
+    }
+}
+
+/// Implementation of the `SocketRecv` API for failover sockets, which handles the
+/// connection changes before each message.
+impl SocketRecv for FailoverSocket {
+    fn recv(&self, msg: &mut zmq::Message, flags: i32) -> io::Result<()> {
+        self.handle_changes()?;
+        SocketRecv::recv(&self.socket, msg, flags)
+    }
+
+    fn recv_into(&self, msg: &mut [u8], flags: i32) -> io::Result<usize> {
+        self.handle_changes()?;
+        SocketRecv::recv_into(&self.socket, msg, flags)
+    }
+
+    fn recv_msg(&self, flags: i32) -> io::Result<zmq::Message> {
+        self.handle_changes()?;
+        SocketRecv::recv_msg(&self.socket, flags)
+    }
+
+    fn recv_bytes(&self, flags: i32) -> io::Result<Vec<u8>> {
+        self.handle_changes()?;
+        SocketRecv::recv_bytes(&self.socket, flags)
+    }
+
+    fn recv_string(&self, flags: i32) -> io::Result<result::Result<String, Vec<u8>>> {
+        self.handle_changes()?;
+        SocketRecv::recv_string(&self.socket, flags)
+    }
+
+    fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
+        self.handle_changes()?;
+        SocketRecv::recv_multipart(&self.socket, flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use testkit::wait_until;
+
+    // Returns a `tcp` endpoint that nobody listens on. libzmq closes the listeners of
+    // sockets in the background, so the port of a dropped `ROUTER` may still accept
+    // connections for a while, unlike the port of a dropped `TcpListener`.
+    fn dead_endpoint() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        format!("tcp://{}", listener.local_addr().unwrap())
+    }
+
+    fn bind_router(ctx: &zmq::Context) -> (zmq::Socket, String) {
+        let router = ctx.socket(zmq::ROUTER).unwrap();
+        router.set_rcvtimeo(2_000).unwrap();
+        router.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = router.get_last_endpoint().unwrap().unwrap();
+        (router, endpoint)
+    }
+
+    fn dealer(ctx: &zmq::Context) -> zmq::Socket {
+        let dealer = ctx.socket(zmq::DEALER).unwrap();
+        dealer.set_linger(0).unwrap();
+        dealer.set_reconnect_ivl(10).unwrap();
+        dealer
+    }
+
+    #[test]
+    fn failover_moves_on_from_dead_endpoints_in_order() {
+        let ctx = zmq::Context::new();
+        let first = dead_endpoint();
+        let second = dead_endpoint();
+        let (router, live) = bind_router(&ctx);
+        let failover = EndpointList::new(ConnectPolicy::Failover)
+            .endpoint(&first)
+            .endpoint(&second)
+            .endpoint(&live)
+            .heartbeat(Duration::from_millis(100), Duration::from_millis(300))
+            .connect(&ctx, dealer(&ctx))
+            .unwrap();
+        assert_eq!(failover.current(), Some(&first[..]));
+        assert_eq!(failover.get_ref().get_heartbeat_ivl().unwrap(), 100);
+
+        let mut events = Vec::new();
+        assert!(wait_until(2_000, || {
+            events.extend(failover.poll_events().unwrap());
+            failover.current() == Some(&live[..])
+        }));
+        assert_eq!(
+            events[0],
+            FailoverEvent::FailedOver {
+                from: first.clone(),
+                to: second.clone(),
+            }
+        );
+        assert!(events.contains(&FailoverEvent::FailedOver {
+            from: second.clone(),
+            to: live.clone(),
+        }));
+        failover.send_multipart(vec!["hello"], 0).unwrap();
+        assert_eq!(router.recv_multipart(0).unwrap()[1], b"hello");
+
+        // moving back to the first endpoint is up to the caller.
+        assert_eq!(
+            failover.fail_back().unwrap(),
+            Some(FailoverEvent::FailedOver {
+                from: live.clone(),
+                to: first.clone(),
+            })
+        );
+        assert_eq!(failover.current(), Some(&first[..]));
+        assert_eq!(failover.fail_back().unwrap(), None);
+    }
+
+    #[test]
+    fn sockets_connect_to_every_endpoint_or_spread_over_them() {
+        let ctx = zmq::Context::new();
+        let (first, first_endpoint) = bind_router(&ctx);
+        let (second, second_endpoint) = bind_router(&ctx);
+        let all = EndpointList::new(ConnectPolicy::ConnectAll)
+            .endpoint(&first_endpoint)
+            .endpoint(&second_endpoint)
+            .connect(&ctx, dealer(&ctx))
+            .unwrap();
+        assert_eq!(all.current(), None);
+        let mut connected = 0;
+        assert!(wait_until(2_000, || {
+            connected += all.poll_events().unwrap().len();
+            connected == 2
+        }));
+        all.send_multipart(vec!["one"], 0).unwrap();
+        all.send_multipart(vec!["two"], 0).unwrap();
+        assert!(first.recv_multipart(0).is_ok());
+        assert!(second.recv_multipart(0).is_ok());
+
+        let list = EndpointList::new(ConnectPolicy::RoundRobin)
+            .endpoint(&first_endpoint)
+            .endpoint(&second_endpoint);
+        let one = list.clone().connect(&ctx, dealer(&ctx)).unwrap();
+        let other = list.connect(&ctx, dealer(&ctx)).unwrap();
+        assert_ne!(one.current(), other.current());
+
+        match EndpointList::new(ConnectPolicy::Failover).connect(&ctx, dealer(&ctx)) {
+            Err(SocketError::InvalidOption(_)) => {}
+            _ => panic!("lists need endpoints"),
+        }
+    }
+}
@@ -0,0 +1,457 @@
+//! Flow-control for outgoing messages.
+//!
+//! A `FlowControl` configures the high-water marks of a socket, and what happens to messages
+//! that are sent while its queue is full. A `FlowSocket` applies that policy on every send,
+//! and tells a full queue apart from a socket that has no peers to send to.
+use super::{SocketSend, SocketWrapper};
+
+use std::cell::Cell;
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use uuid::Uuid;
+use zmq;
+
+/// Flow-control errors.
+#[derive(Debug, Fail)]
+pub enum FlowError {
+    #[fail(display = "send queue is full, the high-water mark was reached")]
+    HighWaterMark,
+    #[fail(display = "socket has no connected peers")]
+    NotConnected,
+    #[fail(display = "no credit left, the receiver has not given it back yet")]
+    NoCredit,
+    #[fail(display = "invalid flow-control message")]
+    InvalidMessage,
+    #[fail(display = "messages could not be spilled to disk: {}", _0)]
+    Spill(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Socket(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Zmq(#[cause] zmq::Error),
+}
+
+impl From<zmq::Error> for FlowError {
+    fn from(e: zmq::Error) -> FlowError {
+        FlowError::Zmq(e)
+    }
+}
+
+/// Callback for messages dropped because the send queue was full.
+pub type DropCallback = Arc<dyn Fn(&[Vec<u8>]) + Send + Sync>;
+
+/// What to do with outgoing messages when the send queue is full.
+#[derive(Clone, Default)]
+pub enum Overflow {
+    /// Wait until the message can be queued, or the socket's send timeout expires.
+    #[default]
+    Block,
+    /// Drop the message, handing it over to the callback, if any.
+    Drop(Option<DropCallback>),
+    /// Append the message to a file, to be resent with `FlowSocket::resend_spilled`.
+    Spill(PathBuf),
+}
+
+impl fmt::Debug for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Overflow::Block => write!(f, "Block"),
+            Overflow::Drop(_) => write!(f, "Drop"),
+            Overflow::Spill(ref path) => write!(f, "Spill({:?})", path),
+        }
+    }
+}
+
+/// Flow-control configuration for sockets.
+#[derive(Clone, Debug, Default)]
+pub struct FlowControl {
+    /// High-water mark for outgoing messages. Uses the socket default when unset.
+    pub sndhwm: Option<i32>,
+    /// High-water mark for incoming messages. Uses the socket default when unset.
+    pub rcvhwm: Option<i32>,
+    /// What to do with outgoing messages when the send queue is full.
+    pub overflow: Overflow,
+}
+
+impl FlowControl {
+    /// Create a new `FlowControl` with the socket defaults, that blocks on full queues.
+    pub fn new() -> Self {
+        FlowControl::default()
+    }
+
+    /// Set the high-water mark for outgoing messages.
+    pub fn set_sndhwm(&mut self, hwm: i32) {
+        self.sndhwm = Some(hwm);
+    }
+
+    /// Set the high-water mark for incoming messages.
+    pub fn set_rcvhwm(&mut self, hwm: i32) {
+        self.rcvhwm = Some(hwm);
+    }
+
+    /// Set what to do with outgoing messages when the send queue is full.
+    pub fn set_overflow(&mut self, overflow: Overflow) {
+        self.overflow = overflow;
+    }
+
+    /// Apply the high-water marks to a socket. Must be called before it binds or connects.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        if let Some(hwm) = self.sndhwm {
+            socket.set_sndhwm(hwm)?;
+        }
+        if let Some(hwm) = self.rcvhwm {
+            socket.set_rcvhwm(hwm)?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a successful send through a `FlowSocket`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SendOutcome {
+    /// The message was queued for sending.
+    Sent,
+    /// The message was dropped by the `Overflow::Drop` policy.
+    Dropped,
+    /// The message was written to disk by the `Overflow::Spill` policy.
+    Spilled,
+}
+
+// Events used to count the connected peers.
+const PEER_EVENTS: i32 = zmq::SocketEvent::CONNECTED as i32
+    | zmq::SocketEvent::ACCEPTED as i32
+    | zmq::SocketEvent::DISCONNECTED as i32;
+
+/// Socket that applies a `FlowControl` policy to outgoing messages.
+///
+/// Peers are counted with a socket monitor, so that `FlowError::NotConnected` can be told
+/// apart from `FlowError::HighWaterMark`. libzmq does not monitor `inproc` transports, so
+/// sockets that were bound or connected to `inproc` endpoints always report full queues.
+pub struct FlowSocket {
+    inner: zmq::Socket,
+    flow: FlowControl,
+    monitor: zmq::Socket,
+    peers: Cell<usize>,
+    inproc: Cell<bool>,
+}
+
+impl FlowSocket {
+    /// Create a new `FlowSocket` from a socket, in the context that created it. The
+    /// high-water marks are applied right away.
+    pub fn new(
+        context: &zmq::Context,
+        socket: zmq::Socket,
+        flow: FlowControl,
+    ) -> Result<FlowSocket, FlowError> {
+        flow.apply(&socket)?;
+        let endpoint = format!(
+            "inproc://neuras.flow.monitor.{}",
+            Uuid::new_v4().to_simple()
+        );
+        socket.monitor(&endpoint, PEER_EVENTS)?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(&endpoint)?;
+        Ok(FlowSocket {
+            inner: socket,
+            flow,
+            monitor,
+            peers: Cell::new(0),
+            inproc: Cell::new(false),
+        })
+    }
+
+    /// Returns the flow-control configuration.
+    pub fn flow_control(&self) -> &FlowControl {
+        &self.flow
+    }
+
+    /// Bind the socket to an endpoint.
+    pub fn bind(&self, endpoint: &str) -> Result<(), zmq::Error> {
+        self.track_transport(endpoint);
+        self.inner.bind(endpoint)
+    }
+
+    /// Connect the socket to an endpoint.
+    pub fn connect(&self, endpoint: &str) -> Result<(), zmq::Error> {
+        self.track_transport(endpoint);
+        self.inner.connect(endpoint)
+    }
+
+    /// Returns the number of connected peers seen by the socket monitor.
+    pub fn peers(&self) -> usize {
+        self.update_peers();
+        self.peers.get()
+    }
+
+    /// Send a multipart message, applying the overflow policy if the send queue is full.
+    pub fn send_multipart<I, T>(&self, msg: I, flags: i32) -> Result<SendOutcome, FlowError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let frames: Vec<Vec<u8>> = msg.into_iter().map(Into::into).collect();
+        let flags = match self.flow.overflow {
+            Overflow::Block => flags,
+            _ => flags | zmq::DONTWAIT,
+        };
+        match SocketSend::send_multipart(&self.inner, frames.iter().map(|f| &f[..]), flags) {
+            Ok(()) => Ok(SendOutcome::Sent),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => self.overflow(frames),
+            Err(e) => Err(FlowError::Socket(e)),
+        }
+    }
+
+    /// Send a message, applying the overflow policy if the send queue is full.
+    pub fn send<T>(&self, msg: T, flags: i32) -> Result<SendOutcome, FlowError>
+    where
+        T: Into<Vec<u8>>,
+    {
+        self.send_multipart(vec![msg.into()], flags)
+    }
+
+    /// Try to resend the messages spilled to disk, in the order they were spilled. Returns
+    /// the number of messages that were sent; the rest are kept in the spill file.
+    pub fn resend_spilled(&self) -> Result<usize, FlowError> {
+        let path = match self.flow.overflow {
+            Overflow::Spill(ref path) => path,
+            _ => return Ok(0),
+        };
+        let spilled = read_spilled(path).map_err(FlowError::Spill)?;
+        let mut sent = 0;
+        for msg in &spilled {
+            let frames = msg.iter().map(|f| &f[..]);
+            if SocketSend::send_multipart(&self.inner, frames, zmq::DONTWAIT).is_err() {
+                break;
+            }
+            sent += 1;
+        }
+        if sent > 0 {
+            write_spilled(path, &spilled[sent..], false).map_err(FlowError::Spill)?;
+        }
+        Ok(sent)
+    }
+
+    fn overflow(&self, frames: Vec<Vec<u8>>) -> Result<SendOutcome, FlowError> {
+        self.update_peers();
+        if self.peers.get() == 0 && !self.inproc.get() {
+            return Err(FlowError::NotConnected);
+        }
+        match self.flow.overflow {
+            Overflow::Block => Err(FlowError::HighWaterMark),
+            Overflow::Drop(ref callback) => {
+                if let Some(ref callback) = *callback {
+                    callback(&frames);
+                }
+                Ok(SendOutcome::Dropped)
+            }
+            Overflow::Spill(ref path) => {
+                write_spilled(path, &[frames], true).map_err(FlowError::Spill)?;
+                Ok(SendOutcome::Spilled)
+            }
+        }
+    }
+
+    fn track_transport(&self, endpoint: &str) {
+        if endpoint.starts_with("inproc://") {
+            self.inproc.set(true);
+        }
+    }
+
+    // Drain the monitor events that are pending, updating the number of peers.
+    fn update_peers(&self) {
+        while let Ok(msg) = self.monitor.recv_multipart(zmq::DONTWAIT) {
+            if msg.is_empty() || msg[0].len() < 2 {
+                continue;
+            }
+            let event = u16::from(msg[0][0]) | (u16::from(msg[0][1]) << 8);
+            match zmq::SocketEvent::from_raw(event) {
+                zmq::SocketEvent::CONNECTED | zmq::SocketEvent::ACCEPTED => {
+                    self.peers.set(self.peers.get() + 1)
+                }
+                zmq::SocketEvent::DISCONNECTED => {
+                    self.peers.set(self.peers.get().saturating_sub(1))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl SocketWrapper for FlowSocket {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        &self.inner
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.inner.get_rcvmore().map_err(|e| e.into())
+    }
+}
+
+// Spill files hold one record per message: the number of frames, followed by the length
+// and content of each frame, with lengths as big-endian `u32`.
+fn write_spilled(path: &Path, msgs: &[Vec<Vec<u8>>], append: bool) -> io::Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)?;
+    let mut writer = BufWriter::new(file);
+    for msg in msgs {
+        writer.write_all(&(msg.len() as u32).to_be_bytes())?;
+        for frame in msg {
+            writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+            writer.write_all(frame)?;
+        }
+    }
+    writer.flush()
+}
+
+fn read_spilled(path: &Path) -> io::Result<Vec<Vec<Vec<u8>>>> {
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut reader = BufReader::new(file);
+    let mut msgs = Vec::new();
+    while let Some(count) = read_u32(&mut reader)? {
+        let mut msg = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            let len = read_u32(&mut reader)?.ok_or(io::ErrorKind::UnexpectedEof)?;
+            let mut frame = vec![0; len as usize];
+            reader.read_exact(&mut frame)?;
+            msg.push(frame);
+        }
+        msgs.push(msg);
+    }
+    Ok(msgs)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<Option<u32>> {
+    let mut buf = [0u8; 4];
+    match reader.read_exact(&mut buf) {
+        Ok(()) => Ok(Some(u32::from_be_bytes(buf))),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+    use std::time::Duration;
+    use testkit::{bind_ephemeral, wait_until};
+
+    fn flow_with(overflow: Overflow) -> FlowControl {
+        let mut flow = FlowControl::new();
+        flow.set_sndhwm(1);
+        flow.set_overflow(overflow);
+        flow
+    }
+
+    // Send until the queue of a PUSH socket with a connected, idle, PULL peer is full.
+    fn fill_queue(
+        ctx: &zmq::Context,
+        flow: FlowControl,
+    ) -> (FlowSocket, zmq::Socket, Vec<SendOutcome>) {
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.set_rcvhwm(1).unwrap();
+        let endpoint = bind_ephemeral(&pull).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.set_linger(0).unwrap();
+        let push = FlowSocket::new(ctx, push, flow).unwrap();
+        push.connect(&endpoint).unwrap();
+        assert!(wait_until(1_000, || push.peers() > 0));
+        let mut outcomes = Vec::new();
+        for i in 0..100u8 {
+            outcomes.push(push.send(vec![i], 0).unwrap());
+            if outcomes[outcomes.len() - 1] != SendOutcome::Sent {
+                break;
+            }
+        }
+        (push, pull, outcomes)
+    }
+
+    #[test]
+    fn flow_control_applies_high_water_marks() {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::PUSH).unwrap();
+        let mut flow = FlowControl::new();
+        flow.set_sndhwm(10);
+        flow.set_rcvhwm(20);
+        flow.apply(&socket).unwrap();
+        assert_eq!(socket.get_sndhwm().unwrap(), 10);
+        assert_eq!(socket.get_rcvhwm().unwrap(), 20);
+    }
+
+    #[test]
+    fn sending_without_peers_is_not_connected() {
+        let ctx = zmq::Context::new();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.set_linger(0).unwrap();
+        let flow = flow_with(Overflow::Drop(None));
+        let push = FlowSocket::new(&ctx, push, flow).unwrap();
+        push.connect("tcp://127.0.0.1:1").unwrap();
+        // messages are queued for the pending connection, until the high-water mark.
+        let mut outcome = push.send("nobody", 0);
+        while let Ok(SendOutcome::Sent) = outcome {
+            outcome = push.send("nobody", 0);
+        }
+        match outcome {
+            Err(FlowError::NotConnected) => {}
+            other => panic!("unexpected outcome: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn full_queues_drop_messages_into_the_callback() {
+        let ctx = zmq::Context::new();
+        let dropped = Arc::new(AtomicUsize::new(0));
+        let counter = dropped.clone();
+        let callback: DropCallback = Arc::new(move |_: &[Vec<u8>]| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let flow = flow_with(Overflow::Drop(Some(callback)));
+        let (_push, _pull, outcomes) = fill_queue(&ctx, flow);
+        assert_eq!(outcomes.last(), Some(&SendOutcome::Dropped));
+        assert_eq!(dropped.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn full_queues_spill_messages_to_disk_for_resending() {
+        let ctx = zmq::Context::new();
+        let path = env::temp_dir().join(format!("neuras-spill-{}", Uuid::new_v4()));
+        let flow = flow_with(Overflow::Spill(path.clone()));
+        let (push, pull, outcomes) = fill_queue(&ctx, flow);
+        assert_eq!(outcomes.last(), Some(&SendOutcome::Spilled));
+        assert_eq!(read_spilled(&path).unwrap().len(), 1);
+
+        let sent = outcomes.len() - 1;
+        for _ in 0..sent {
+            pull.recv_bytes(0).unwrap();
+        }
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(push.resend_spilled().unwrap(), 1);
+        assert_eq!(pull.recv_bytes(0).unwrap(), vec![sent as u8]);
+        assert!(read_spilled(&path).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn spill_files_keep_frames_in_order() {
+        let path = env::temp_dir().join(format!("neuras-spill-{}", Uuid::new_v4()));
+        let msgs = vec![vec![b"a".to_vec(), b"".to_vec()], vec![b"bc".to_vec()]];
+        write_spilled(&path, &msgs[..1], true).unwrap();
+        write_spilled(&path, &msgs[1..], true).unwrap();
+        assert_eq!(read_spilled(&path).unwrap(), msgs);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,166 @@
+//! ZMTP 3.1 heartbeats.
+//!
+//! libzmq sends a `PING` command on every connection of a socket with a heartbeat interval,
+//! and closes connections that receive no traffic for the heartbeat timeout, so that peers
+//! that died, or were cut off, without closing their connections are disconnected, and
+//! reconnected to, as the socket monitor reports. The heartbeat TTL is sent along, and
+//! tells the peer to close the connection if it hears nothing for that long, even if the
+//! peer sends no heartbeats of its own.
+//!
+//! These heartbeats are handled by the I/O threads of libzmq, and are never seen by the
+//! application. They tell that a connection is alive, but not that the application on the
+//! other end is, e.g. a worker that is stuck in a request keeps answering `PING`s. Protocols
+//! with heartbeats of their own, such as the Paranoid Pirate heartbeats of `ppp`, or the
+//! heartbeats of actors, are still needed for that, and a ZMTP heartbeat that is shorter
+//! than them makes dead connections fail fast, instead of waiting for the application
+//! heartbeats to run out.
+use std::cmp;
+use std::time::Duration;
+
+use zmq;
+
+/// ZMTP 3.1 heartbeat options of a socket.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZmtpHeartbeat {
+    /// Interval between `PING`s, as `ZMQ_HEARTBEAT_IVL`.
+    pub interval: Duration,
+    /// Time without traffic after which a connection is closed, as `ZMQ_HEARTBEAT_TIMEOUT`.
+    pub timeout: Duration,
+    /// Time without traffic after which peers close the connection, as
+    /// `ZMQ_HEARTBEAT_TTL`, which libzmq rounds down to tenths of a second. Zero leaves it
+    /// to the peer.
+    pub ttl: Duration,
+}
+
+impl ZmtpHeartbeat {
+    /// Create new heartbeat options.
+    pub fn new(interval: Duration, timeout: Duration, ttl: Duration) -> ZmtpHeartbeat {
+        ZmtpHeartbeat {
+            interval,
+            timeout,
+            ttl,
+        }
+    }
+
+    /// Set the options of `socket`. They apply to the connections made afterwards.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        socket.set_heartbeat_ivl(millis(self.interval))?;
+        socket.set_heartbeat_timeout(millis(self.timeout))?;
+        socket.set_heartbeat_ttl(millis(self.ttl))
+    }
+}
+
+// Returns `duration` in milliseconds, as a socket option.
+fn millis(duration: Duration) -> i32 {
+    cmp::min(duration.as_millis(), i32::MAX as u128) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{Shutdown, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+    use testkit::wait_until;
+
+    // Forward the bytes of one connection to `upstream`, until `frozen`, when the bytes
+    // are read, and dropped, without closing the connection, as a peer that died.
+    fn relay(upstream: String, frozen: Arc<AtomicBool>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = format!("tcp://{}", listener.local_addr().unwrap());
+        thread::spawn(move || {
+            let (client, _) = listener.accept().unwrap();
+            let server = TcpStream::connect(&upstream["tcp://".len()..]).unwrap();
+            let pipe = |mut from: TcpStream, mut to: TcpStream, frozen: Arc<AtomicBool>| {
+                thread::spawn(move || {
+                    let mut buf = [0; 1024];
+                    while let Ok(n) = from.read(&mut buf) {
+                        if n == 0 {
+                            let _ = to.shutdown(Shutdown::Write);
+                            return;
+                        }
+                        if !frozen.load(Ordering::SeqCst) && to.write_all(&buf[..n]).is_err() {
+                            return;
+                        }
+                    }
+                })
+            };
+            pipe(
+                client.try_clone().unwrap(),
+                server.try_clone().unwrap(),
+                Arc::clone(&frozen),
+            );
+            pipe(server, client, frozen);
+        });
+        endpoint
+    }
+
+    fn monitored(ctx: &zmq::Context, socket: &zmq::Socket, name: &str) -> zmq::Socket {
+        let endpoint = format!("inproc://heartbeat-monitor-{}", name);
+        socket
+            .monitor(&endpoint, zmq::SocketEvent::DISCONNECTED as i32)
+            .unwrap();
+        let monitor = ctx.socket(zmq::PAIR).unwrap();
+        monitor.connect(&endpoint).unwrap();
+        monitor
+    }
+
+    fn disconnected(monitor: &zmq::Socket, timeout: u64) -> bool {
+        wait_until(timeout, || {
+            monitor.poll(zmq::POLLIN, 10).unwrap() > 0 && monitor.recv_multipart(0).is_ok()
+        })
+    }
+
+    #[test]
+    fn heartbeats_are_set_on_sockets() {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::DEALER).unwrap();
+        ZmtpHeartbeat::new(
+            Duration::from_millis(100),
+            Duration::from_millis(300),
+            Duration::from_secs(1),
+        )
+        .apply(&socket)
+        .unwrap();
+        assert_eq!(socket.get_heartbeat_ivl().unwrap(), 100);
+        assert_eq!(socket.get_heartbeat_timeout().unwrap(), 300);
+        assert_eq!(socket.get_heartbeat_ttl().unwrap(), 1_000);
+    }
+
+    #[test]
+    fn dead_peers_are_disconnected_by_heartbeats() {
+        let ctx = zmq::Context::new();
+        let router = ctx.socket(zmq::ROUTER).unwrap();
+        router.bind("tcp://127.0.0.1:*").unwrap();
+        let upstream = router.get_last_endpoint().unwrap().unwrap();
+
+        let mut dealers = Vec::new();
+        for name in &["silent", "heartbeat"] {
+            let frozen = Arc::new(AtomicBool::new(false));
+            let dealer = ctx.socket(zmq::DEALER).unwrap();
+            dealer.set_linger(0).unwrap();
+            if *name == "heartbeat" {
+                let heartbeat = ZmtpHeartbeat::new(
+                    Duration::from_millis(50),
+                    Duration::from_millis(200),
+                    Duration::from_secs(0),
+                );
+                heartbeat.apply(&dealer).unwrap();
+            }
+            let monitor = monitored(&ctx, &dealer, name);
+            dealer
+                .connect(&relay(upstream.clone(), Arc::clone(&frozen)))
+                .unwrap();
+            dealer.send(*name, 0).unwrap();
+            assert_eq!(router.recv_multipart(0).unwrap()[1], name.as_bytes());
+            frozen.store(true, Ordering::SeqCst);
+            dealers.push((dealer, monitor));
+        }
+
+        // without heartbeats, the connection to the dead peer looks alive.
+        assert!(!disconnected(&dealers[0].1, 500));
+        assert!(disconnected(&dealers[1].1, 2_000));
+    }
+}
@@ -0,0 +1,376 @@
+//! Wire protocol versions, and features, negotiated between peers.
+//!
+//! Peers that run different versions of the crate may frame their messages differently,
+//! e.g. with, or without, the envelopes of `message`. Before they talk, peers may exchange a
+//! `Hello`, with the range of protocol versions, and the `Features`, that they speak, and
+//! go on with the highest version that both speak, and the features that both have, as
+//! `Negotiated`. The exchange is optional: peers that do not answer a hello are older, and
+//! are talked to as `Negotiated::legacy()`.
+//!
+//! A hello is a message of its own, `[$HELLO, version, min_version, features]`, which is
+//! sent over the socket of the pattern that the peers use anyway:
+//!
+//! * `Hello::exchange` sends the hello, then waits for the hello of the peer, for `PAIR`,
+//!   and `DEALER` sockets.
+//! * `Hello::answer` waits for the hello of the peer, then replies with its own, for
+//!   `PAIR`, `DEALER`, and `ROUTER` sockets, which reply to the envelope of the hello.
+//!
+//! `REQ` and `REP` sockets are not supported: a `REQ` socket whose peer does not answer in
+//! time is left waiting for the reply, and fails every later send with `EFSM`.
+//!
+//! Messages that are not hellos, e.g. from older peers that talk right away, are handed
+//! back to the caller with `HelloError::NotHello`, instead of being dropped.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::{Features, Hello};
+//! use std::thread;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let server = ctx.socket(zmq::ROUTER).unwrap();
+//! server.bind("inproc://hello").unwrap();
+//! let client = ctx.socket(zmq::DEALER).unwrap();
+//! client.connect("inproc://hello").unwrap();
+//!
+//! let answered = thread::spawn(move || {
+//!     Hello::new(Features::DELIVERY | Features::HEARTBEAT)
+//!         .answer(&server, 1_000)
+//!         .unwrap()
+//! });
+//! let negotiated = Hello::new(Features::DELIVERY).exchange(&client, 1_000).unwrap();
+//! assert!(negotiated.supports(Features::DELIVERY));
+//! assert!(!negotiated.supports(Features::HEARTBEAT));
+//! assert_eq!(answered.join().unwrap(), negotiated);
+//! # }
+//! ```
+use super::{SocketRecv, SocketSend, SocketWrapper};
+
+use std::cmp;
+use std::io;
+use std::ops::{BitAnd, BitOr};
+use zmq;
+
+/// First frame of a hello.
+pub const HELLO: &[u8] = b"$HELLO";
+/// Version of the wire protocol of the crate.
+pub const PROTOCOL_VERSION: u16 = 1;
+/// Oldest version of the wire protocol that the crate still speaks.
+pub const MIN_PROTOCOL_VERSION: u16 = 1;
+
+/// Hello errors.
+#[derive(Debug, Fail)]
+pub enum HelloError {
+    #[fail(
+        display = "no common protocol version, ours are {} to {}, theirs are {} to {}",
+        _0, _1, _2, _3
+    )]
+    Incompatible(u16, u16, u16, u16),
+    #[fail(display = "invalid hello message")]
+    InvalidHello,
+    #[fail(display = "the peer sent a message that is not a hello")]
+    NotHello(Vec<Vec<u8>>),
+    #[fail(display = "the peer did not say hello in time")]
+    Timeout,
+    #[fail(display = "{}", _0)]
+    Socket(#[cause] io::Error),
+}
+
+impl From<io::Error> for HelloError {
+    fn from(e: io::Error) -> HelloError {
+        HelloError::Socket(e)
+    }
+}
+
+/// Set of optional features of the wire protocol.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub struct Features(u32);
+
+impl Features {
+    /// Envelopes with delivery modes, and acknowledgments, of `message::DeliverySocket`.
+    pub const DELIVERY: Features = Features(1);
+    /// Compressed frames, of `CompressedCodec`.
+    pub const COMPRESSION: Features = Features(1 << 1);
+    /// ZMTP 3.1 heartbeats, of `ZmtpHeartbeat`.
+    pub const HEARTBEAT: Features = Features(1 << 2);
+    /// Integrity checks, of `security::IntegrityEnvelope`.
+    pub const INTEGRITY: Features = Features(1 << 3);
+    /// Many messages packed into a frame, of `SocketSend::send_packed`.
+    pub const PACKED: Features = Features(1 << 4);
+
+    /// Returns no features.
+    pub fn empty() -> Features {
+        Features(0)
+    }
+
+    /// Returns the features of `bits`, including those that this version does not know,
+    /// which are never negotiated.
+    pub fn from_bits(bits: u32) -> Features {
+        Features(bits)
+    }
+
+    /// Returns the bits of the features.
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if every feature of `other` is in the set.
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Returns true if the set has no features.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for Features {
+    type Output = Features;
+
+    fn bitor(self, other: Features) -> Features {
+        Features(self.0 | other.0)
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Features;
+
+    fn bitand(self, other: Features) -> Features {
+        Features(self.0 & other.0)
+    }
+}
+
+/// The protocol version, and the features, that peers agreed on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Negotiated {
+    pub version: u16,
+    pub features: Features,
+}
+
+impl Negotiated {
+    /// Returns what is agreed with peers that do not say hello: the version `0`, without
+    /// features.
+    pub fn legacy() -> Negotiated {
+        Negotiated {
+            version: 0,
+            features: Features::empty(),
+        }
+    }
+
+    /// Returns true if both peers have `features`.
+    pub fn supports(&self, features: Features) -> bool {
+        self.features.contains(features)
+    }
+}
+
+/// The protocol versions, and the features, that a peer speaks.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Hello {
+    pub version: u16,
+    pub min_version: u16,
+    pub features: Features,
+}
+
+impl Hello {
+    /// Create a new `Hello`, for the protocol versions of the crate, and `features`.
+    pub fn new(features: Features) -> Hello {
+        Hello {
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            features,
+        }
+    }
+
+    /// Returns the frames of the hello.
+    pub fn encode(&self) -> Vec<Vec<u8>> {
+        vec![
+            HELLO.to_vec(),
+            self.version.to_be_bytes().to_vec(),
+            self.min_version.to_be_bytes().to_vec(),
+            self.features.bits().to_be_bytes().to_vec(),
+        ]
+    }
+
+    /// Returns the hello of `frames`. Fails with `HelloError::InvalidHello` if they are not
+    /// a hello.
+    pub fn decode<T: AsRef<[u8]>>(frames: &[T]) -> Result<Hello, HelloError> {
+        let frames: Vec<&[u8]> = frames.iter().map(AsRef::as_ref).collect();
+        match frames[..] {
+            [HELLO, version, min_version, features]
+                if version.len() == 2 && min_version.len() == 2 && features.len() == 4 =>
+            {
+                let mut bits = [0u8; 4];
+                bits.copy_from_slice(features);
+                Ok(Hello {
+                    version: u16::from_be_bytes([version[0], version[1]]),
+                    min_version: u16::from_be_bytes([min_version[0], min_version[1]]),
+                    features: Features::from_bits(u32::from_be_bytes(bits)),
+                })
+            }
+            _ => Err(HelloError::InvalidHello),
+        }
+    }
+
+    /// Returns the highest version, and the features, that both hellos have. Fails with
+    /// `HelloError::Incompatible` if they have no version in common.
+    pub fn negotiate(&self, theirs: &Hello) -> Result<Negotiated, HelloError> {
+        let version = cmp::min(self.version, theirs.version);
+        if version < cmp::max(self.min_version, theirs.min_version) {
+            return Err(HelloError::Incompatible(
+                self.min_version,
+                self.version,
+                theirs.min_version,
+                theirs.version,
+            ));
+        }
+        Ok(Negotiated {
+            version,
+            features: self.features & theirs.features,
+        })
+    }
+
+    /// Send the hello on `socket`, then wait up to `timeout` milliseconds, or forever if it
+    /// is `-1`, for the hello of the peer, and negotiate with it. Fails with
+    /// `HelloError::Timeout` if the peer did not answer, e.g. it is older, and with
+    /// `HelloError::NotHello` if it answered with another message.
+    pub fn exchange<S>(&self, socket: &S, timeout: i64) -> Result<Negotiated, HelloError>
+    where
+        S: SocketWrapper + SocketSend + SocketRecv,
+    {
+        socket.send_multipart(self.encode(), 0)?;
+        let msg = recv_hello(socket, timeout)?;
+        if msg.first().map(|frame| &frame[..]) != Some(HELLO) {
+            return Err(HelloError::NotHello(msg));
+        }
+        self.negotiate(&Hello::decode(&msg)?)
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it is `-1`, for the hello of a peer
+    /// on `socket`, reply to its envelope, e.g. the identity of the peer on `ROUTER`
+    /// sockets, with the hello, and negotiate with it. Fails with `HelloError::NotHello`,
+    /// without replying, if the peer sent another message, e.g. it is older, or one whose
+    /// first frame after the envelope is not `HELLO`.
+    pub fn answer<S>(&self, socket: &S, timeout: i64) -> Result<Negotiated, HelloError>
+    where
+        S: SocketWrapper + SocketSend + SocketRecv,
+    {
+        let mut msg = recv_hello(socket, timeout)?;
+        // the hello follows the identity of the peer on `ROUTER` sockets, and its empty
+        // delimiter, if any.
+        let start = match socket.get_socket_ref().get_socket_type() {
+            Ok(zmq::ROUTER) if msg.get(1).is_some_and(|frame| frame.is_empty()) => 2,
+            Ok(zmq::ROUTER) => 1,
+            Ok(_) => 0,
+            Err(e) => return Err(io::Error::from(e).into()),
+        };
+        if msg.get(start).map(|frame| &frame[..]) != Some(HELLO) {
+            return Err(HelloError::NotHello(msg));
+        }
+        let theirs = Hello::decode(&msg[start..])?;
+        msg.truncate(start);
+        msg.extend(self.encode());
+        socket.send_multipart(msg, 0)?;
+        self.negotiate(&theirs)
+    }
+}
+
+fn recv_hello<S>(socket: &S, timeout: i64) -> Result<Vec<Vec<u8>>, HelloError>
+where
+    S: SocketWrapper + SocketRecv,
+{
+    let ready = socket.get_socket_ref().poll(zmq::POLLIN, timeout);
+    if ready.map_err(io::Error::from)? == 0 {
+        return Err(HelloError::Timeout);
+    }
+    Ok(socket.recv_multipart(0)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use testkit::inproc_endpoint;
+
+    #[test]
+    fn peers_agree_on_common_versions_and_features() {
+        let ours = Hello {
+            version: 3,
+            min_version: 1,
+            features: Features::DELIVERY | Features::HEARTBEAT | Features::from_bits(1 << 31),
+        };
+        let theirs = Hello {
+            version: 2,
+            min_version: 2,
+            features: Features::DELIVERY | Features::INTEGRITY | Features::from_bits(1 << 31),
+        };
+        let negotiated = ours.negotiate(&theirs).unwrap();
+        assert_eq!(negotiated.version, 2);
+        assert!(negotiated.supports(Features::DELIVERY));
+        assert!(!negotiated.supports(Features::HEARTBEAT | Features::INTEGRITY));
+        assert_eq!(negotiated, theirs.negotiate(&ours).unwrap());
+        assert_eq!(Hello::decode(&ours.encode()).unwrap(), ours);
+
+        let newer = Hello {
+            version: 5,
+            min_version: 4,
+            features: Features::empty(),
+        };
+        match ours.negotiate(&newer) {
+            Err(HelloError::Incompatible(1, 3, 4, 5)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(Hello::decode(&[HELLO, b"1"]).is_err());
+        assert!(Negotiated::legacy().features.is_empty());
+    }
+
+    #[test]
+    fn routers_answer_the_hellos_of_their_peers() {
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("hello");
+        let router = ctx.socket(zmq::ROUTER).unwrap();
+        router.bind(&endpoint).unwrap();
+        let dealer = ctx.socket(zmq::DEALER).unwrap();
+        dealer.connect(&endpoint).unwrap();
+
+        // an older router, that does not say hello, leaves the dealer waiting.
+        match Hello::new(Features::PACKED).exchange(&dealer, 50) {
+            Err(HelloError::Timeout) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        let old = router.recv_multipart(0).unwrap();
+        assert_eq!(old[1], HELLO);
+
+        // an older dealer, that talks right away, gets its message back to the router.
+        dealer.send("request", 0).unwrap();
+        match Hello::new(Features::PACKED).answer(&router, 1_000) {
+            Err(HelloError::NotHello(msg)) => assert_eq!(msg[1], b"request"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        // hellos in the payload of other messages do not count.
+        dealer.send_multipart(vec![&b"data"[..], HELLO], 0).unwrap();
+        match Hello::new(Features::PACKED).answer(&router, 1_000) {
+            Err(HelloError::NotHello(msg)) => assert_eq!(&msg[1..], &[b"data", HELLO]),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let answered = thread::spawn(move || {
+            Hello::new(Features::PACKED | Features::COMPRESSION)
+                .answer(&router, 1_000)
+                .unwrap()
+        });
+        let negotiated = Hello::new(Features::PACKED)
+            .exchange(&dealer, 1_000)
+            .unwrap();
+        assert_eq!(answered.join().unwrap(), negotiated);
+        assert_eq!(
+            negotiated,
+            Negotiated {
+                version: PROTOCOL_VERSION,
+                features: Features::PACKED,
+            }
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! Socket identities.
+//!
+//! `ROUTER` sockets prefix every message with the identity of the peer that sent it, and
+//! route replies by the identity in their first frame. libzmq requires identities of 1 to
+//! 255 bytes, and those set on sockets must not start with a zero byte, which it reserves
+//! for the identities it makes up for peers that set none. An `Identity` is checked like
+//! libzmq does: those of envelopes may be made up, and can be routed to, but not set on a
+//! socket. It shows up readably in logs, as its text if it is printable, or hex-encoded
+//! otherwise.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::Identity;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let router = ctx.socket(zmq::ROUTER).unwrap();
+//! router.bind("inproc://identity").unwrap();
+//! let dealer = ctx.socket(zmq::DEALER).unwrap();
+//! let identity = Identity::named("worker-1").unwrap();
+//! identity.apply(&dealer).unwrap();
+//! dealer.connect("inproc://identity").unwrap();
+//! dealer.send("ready", 0).unwrap();
+//!
+//! let msg = router.recv_multipart(0).unwrap();
+//! let sender = Identity::from_envelope(&msg).unwrap();
+//! assert_eq!(sender.to_string(), "worker-1");
+//! router.send_multipart(sender.route(vec!["welcome"]), 0).unwrap();
+//! assert_eq!(dealer.recv_bytes(0).unwrap(), b"welcome");
+//! # }
+//! ```
+use super::SocketError;
+
+use std::fmt;
+use std::ops::Deref;
+use uuid::Uuid;
+use zmq;
+
+/// Longest identity that libzmq accepts.
+pub const MAX_IDENTITY_SIZE: usize = 255;
+
+/// Identity of a socket, as seen by the `ROUTER` sockets it connects to.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Identity(Vec<u8>);
+
+impl Identity {
+    /// Returns the identity of `bytes`. Fails with `SocketError::InvalidOption` if they are
+    /// empty, longer than `MAX_IDENTITY_SIZE`, or start with a zero byte.
+    pub fn from_bytes<B: Into<Vec<u8>>>(bytes: B) -> Result<Identity, SocketError> {
+        let bytes = bytes.into();
+        check_size(&bytes)?;
+        check_settable(&bytes)?;
+        Ok(Identity(bytes))
+    }
+
+    /// Returns the identity of a readable `name`, e.g. the name of a worker.
+    pub fn named(name: &str) -> Result<Identity, SocketError> {
+        Identity::from_bytes(name)
+    }
+
+    /// Returns the identity of `uuid`, in its simple form, e.g. the UUID of an actor.
+    pub fn from_uuid(uuid: &Uuid) -> Identity {
+        Identity(uuid.to_simple().to_string().into_bytes())
+    }
+
+    /// Returns a new identity of 16 random bytes.
+    pub fn random() -> Identity {
+        let mut bytes = Uuid::new_v4().as_bytes().to_vec();
+        if bytes[0] == 0 {
+            bytes[0] = 1;
+        }
+        Identity(bytes)
+    }
+
+    /// Returns the identity of the sender of a message received by a `ROUTER` socket, from
+    /// its first frame, including those that libzmq made up, which start with a zero byte.
+    pub fn from_envelope<T: Deref<Target = [u8]>>(msg: &[T]) -> Result<Identity, SocketError> {
+        match msg.first() {
+            Some(frame) => {
+                check_size(frame)?;
+                Ok(Identity(frame.to_vec()))
+            }
+            None => Err(SocketError::InvalidOption(
+                "message has no identity frame".to_string(),
+            )),
+        }
+    }
+
+    /// Returns `frames`, prefixed with the identity, to be routed to its socket by a
+    /// `ROUTER` socket.
+    pub fn route<I, T>(&self, frames: I) -> Vec<Vec<u8>>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut msg = vec![self.0.clone()];
+        msg.extend(frames.into_iter().map(Into::into));
+        msg
+    }
+
+    /// Set the identity of `socket`, before it connects. Fails with
+    /// `SocketError::InvalidOption` if it starts with a zero byte, i.e. libzmq made it up.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), SocketError> {
+        check_settable(&self.0)?;
+        socket.set_identity(&self.0)?;
+        Ok(())
+    }
+
+    /// Returns the bytes of the identity.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the bytes of the identity.
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+// Check that `bytes` have as many bytes as libzmq accepts for identities.
+fn check_size(bytes: &[u8]) -> Result<(), SocketError> {
+    if bytes.is_empty() || bytes.len() > MAX_IDENTITY_SIZE {
+        return Err(SocketError::InvalidOption(format!(
+            "identity must have 1 to {} bytes",
+            MAX_IDENTITY_SIZE
+        )));
+    }
+    Ok(())
+}
+
+// Check that `bytes` may be set as the identity of a socket.
+fn check_settable(bytes: &[u8]) -> Result<(), SocketError> {
+    if bytes.first() == Some(&0) {
+        return Err(SocketError::InvalidOption(
+            "identity must not start with a zero byte".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+impl AsRef<[u8]> for Identity {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Identity> for Vec<u8> {
+    fn from(identity: Identity) -> Vec<u8> {
+        identity.0
+    }
+}
+
+/// Shows the identity as text if it is printable ASCII, or as hex, e.g. `0xa1b200`.
+impl fmt::Display for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0.iter().all(|b| (0x20..0x7f).contains(b)) {
+            return f.write_str(&String::from_utf8_lossy(&self.0));
+        }
+        f.write_str("0x")?;
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities_are_checked_like_libzmq_does() {
+        assert!(Identity::from_bytes(Vec::new()).is_err());
+        assert!(Identity::from_bytes(vec![0, 1]).is_err());
+        assert!(Identity::from_bytes(vec![1; MAX_IDENTITY_SIZE + 1]).is_err());
+        assert!(Identity::from_bytes(vec![1; MAX_IDENTITY_SIZE]).is_ok());
+        assert!(Identity::from_envelope::<Vec<u8>>(&[]).is_err());
+        assert!(Identity::from_envelope(&[vec![1; MAX_IDENTITY_SIZE + 1]]).is_err());
+
+        // peers that set no identity get one made up by the router.
+        let ctx = zmq::Context::new();
+        let router = ctx.socket(zmq::ROUTER).unwrap();
+        router.bind("inproc://identity-made-up").unwrap();
+        let dealer = ctx.socket(zmq::DEALER).unwrap();
+        dealer.connect("inproc://identity-made-up").unwrap();
+        dealer.send("hello", 0).unwrap();
+        let made_up = Identity::from_envelope(&router.recv_multipart(0).unwrap()).unwrap();
+        assert_eq!(made_up.as_bytes()[0], 0);
+        router.send_multipart(made_up.route(vec!["hi"]), 0).unwrap();
+        assert_eq!(dealer.recv_bytes(0).unwrap(), b"hi");
+        assert!(made_up.apply(&dealer).is_err());
+
+        let uuid = Uuid::new_v4();
+        let identity = Identity::from_uuid(&uuid);
+        assert_eq!(identity.to_string(), uuid.to_simple().to_string());
+        assert_ne!(Identity::random(), Identity::random());
+        assert_eq!(Identity::random().as_bytes().len(), 16);
+    }
+
+    #[test]
+    fn unprintable_identities_are_shown_as_hex() {
+        assert_eq!(Identity::named("worker 1").unwrap().to_string(), "worker 1");
+        let identity = Identity::from_bytes(vec![0xa1, b'b', 0]).unwrap();
+        assert_eq!(identity.to_string(), "0xa16200");
+        assert_eq!(
+            identity.route(vec!["hello"]),
+            vec![vec![0xa1, b'b', 0], b"hello".to_vec()]
+        );
+    }
+}
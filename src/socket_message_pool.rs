@@ -0,0 +1,240 @@
+//! Recycled message buffers.
+//!
+//! Every frame received with `SocketRecv::recv_bytes`, or `SocketRecv::recv_multipart`, is
+//! copied into a new `Vec<u8>`, and each multipart message into a new `Vec` of frames,
+//! which adds up to several allocations per message on busy sockets. A `MessagePool` keeps
+//! the buffers that are handed back to it, and the `_pooled` receive methods of
+//! `SocketRecv` fill them, instead of allocating new ones.
+//!
+//! Only the buffers of the crate are pooled. libzmq keeps frames of up to 33 bytes inside
+//! its `zmq::Message`, and allocates larger ones itself, for every message it receives,
+//! whether the message is new or not, so there is nothing to save by pooling them.
+//!
+//! The pool is bounded: it keeps up to `capacity` buffers of each kind, and drops frames
+//! that grew beyond `max_frame_size`, so that a few large messages do not pin their memory.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::{MessagePool, SocketRecv};
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let pull = ctx.socket(zmq::PULL).unwrap();
+//! pull.bind("inproc://pooled").unwrap();
+//! let push = ctx.socket(zmq::PUSH).unwrap();
+//! push.connect("inproc://pooled").unwrap();
+//!
+//! let pool = MessagePool::new(64);
+//! for _ in 0..3 {
+//!     push.send_multipart(vec!["topic", "body"], 0).unwrap();
+//!     let msg = pull.recv_multipart_pooled(&pool, 0).unwrap();
+//!     assert_eq!(msg, vec![b"topic".to_vec(), b"body".to_vec()]);
+//!     pool.recycle_multipart(msg);
+//! }
+//! assert_eq!(pool.stats().reused, 6);
+//! # }
+//! ```
+use std::sync::Mutex;
+
+/// Default largest capacity, in bytes, of the frames that are kept by a `MessagePool`.
+pub const MAX_POOLED_FRAME_SIZE: usize = 64 * 1024;
+
+/// Counters of a `MessagePool`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PoolStats {
+    /// Buffers that were taken from the pool, and used without growing.
+    pub reused: usize,
+    /// Buffers that were allocated, because the pool had none, or had to grow, because
+    /// the one that it had was too small.
+    pub allocated: usize,
+    /// Buffers that were dropped, because the pool was full, or they were too large.
+    pub dropped: usize,
+}
+
+#[derive(Default)]
+struct Pooled {
+    frames: Vec<Vec<u8>>,
+    multiparts: Vec<Vec<Vec<u8>>>,
+    stats: PoolStats,
+}
+
+/// Bounded pool of message buffers, which may be shared by the sockets of a thread, or
+/// across threads.
+pub struct MessagePool {
+    pooled: Mutex<Pooled>,
+    capacity: usize,
+    max_frame_size: usize,
+}
+
+impl MessagePool {
+    /// Create a new, empty, `MessagePool`, that keeps up to `capacity` buffers of each
+    /// kind.
+    pub fn new(capacity: usize) -> MessagePool {
+        MessagePool {
+            pooled: Mutex::new(Pooled::default()),
+            capacity,
+            max_frame_size: MAX_POOLED_FRAME_SIZE,
+        }
+    }
+
+    /// Keep frames of up to `max_frame_size` bytes of capacity, instead of
+    /// `MAX_POOLED_FRAME_SIZE`.
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.max_frame_size = max_frame_size;
+        self
+    }
+
+    /// Returns an empty frame from the pool, or a new one.
+    pub fn frame(&self) -> Vec<u8> {
+        self.frame_with_capacity(0)
+    }
+
+    /// Returns an empty frame of at least `capacity` bytes, from the pool, or a new one.
+    /// Frames of the pool that are too small grow, which counts as an allocation.
+    pub fn frame_with_capacity(&self, capacity: usize) -> Vec<u8> {
+        let mut pooled = self.pooled.lock().unwrap();
+        match pooled.frames.pop() {
+            Some(mut frame) => {
+                if frame.capacity() >= capacity {
+                    pooled.stats.reused += 1;
+                } else {
+                    frame.reserve(capacity);
+                    pooled.stats.allocated += 1;
+                }
+                frame
+            }
+            None => {
+                pooled.stats.allocated += 1;
+                Vec::with_capacity(capacity)
+            }
+        }
+    }
+
+    /// Returns an empty multipart message from the pool, or a new one.
+    pub fn multipart(&self) -> Vec<Vec<u8>> {
+        let mut pooled = self.pooled.lock().unwrap();
+        let msg = pooled.multiparts.pop();
+        take(&mut pooled.stats, msg).unwrap_or_default()
+    }
+
+    /// Hand `frame` back to the pool.
+    pub fn recycle(&self, frame: Vec<u8>) {
+        let mut pooled = self.pooled.lock().unwrap();
+        self.keep_frame(&mut pooled, frame);
+    }
+
+    /// Hand the frames of `msg`, and `msg` itself, back to the pool.
+    pub fn recycle_multipart(&self, mut msg: Vec<Vec<u8>>) {
+        let mut pooled = self.pooled.lock().unwrap();
+        // in reverse, so that the next message takes its frames back in the same order.
+        for frame in msg.drain(..).rev() {
+            self.keep_frame(&mut pooled, frame);
+        }
+        if pooled.multiparts.len() < self.capacity {
+            pooled.multiparts.push(msg);
+        } else {
+            pooled.stats.dropped += 1;
+        }
+    }
+
+    /// Returns the counters of the pool.
+    pub fn stats(&self) -> PoolStats {
+        self.pooled.lock().unwrap().stats
+    }
+
+    /// Returns the number of buffers that the pool holds, of every kind.
+    pub fn len(&self) -> usize {
+        let pooled = self.pooled.lock().unwrap();
+        pooled.frames.len() + pooled.multiparts.len()
+    }
+
+    /// Returns true if the pool holds no buffers.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn keep_frame(&self, pooled: &mut Pooled, mut frame: Vec<u8>) {
+        if pooled.frames.len() < self.capacity && frame.capacity() <= self.max_frame_size {
+            frame.clear();
+            pooled.frames.push(frame);
+        } else {
+            pooled.stats.dropped += 1;
+        }
+    }
+}
+
+// Count `buffer` as reused, or allocated if the pool had none.
+fn take<T>(stats: &mut PoolStats, buffer: Option<T>) -> Option<T> {
+    match buffer {
+        Some(_) => stats.reused += 1,
+        None => stats.allocated += 1,
+    }
+    buffer
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::SocketRecv;
+    use super::*;
+    use testkit::inproc_endpoint;
+    use zmq;
+
+    #[test]
+    fn pools_keep_a_bounded_number_of_small_buffers() {
+        let pool = MessagePool::new(2).with_max_frame_size(16);
+        pool.recycle(Vec::with_capacity(8));
+        pool.recycle(Vec::with_capacity(32));
+        pool.recycle_multipart(vec![vec![1; 4], vec![2; 4]]);
+        assert_eq!(pool.len(), 3);
+        assert_eq!(pool.stats().dropped, 2);
+
+        let frame = pool.frame();
+        assert!(frame.is_empty() && frame.capacity() >= 4);
+        pool.frame();
+        assert_eq!(pool.frame().capacity(), 0);
+        assert!(pool.multipart().is_empty());
+        assert_eq!(
+            pool.stats(),
+            PoolStats {
+                reused: 3,
+                allocated: 1,
+                dropped: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn pooled_receives_fill_recycled_buffers() {
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("message-pool");
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind(&endpoint).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        let pool = MessagePool::new(8);
+
+        push.send_multipart(vec!["first", "message"], 0).unwrap();
+        let msg = SocketRecv::recv_multipart_pooled(&pull, &pool, 0).unwrap();
+        assert_eq!(msg, vec![b"first".to_vec(), b"message".to_vec()]);
+        let buffer = msg[0].as_ptr();
+        pool.recycle_multipart(msg);
+
+        push.send("again", 0).unwrap();
+        let frame = SocketRecv::recv_bytes_pooled(&pull, &pool, 0).unwrap();
+        assert_eq!(frame, b"again");
+        assert_eq!(frame.as_ptr(), buffer);
+        pool.recycle(frame);
+
+        // frames that are too small for a message grow, instead of counting as reused.
+        let reused = pool.stats().reused;
+        push.send(vec![3; 64], 0).unwrap();
+        let frame = SocketRecv::recv_bytes_pooled(&pull, &pool, 0).unwrap();
+        assert_eq!(frame, vec![3; 64]);
+        assert_eq!(pool.stats().reused, reused);
+
+        assert!(SocketRecv::recv_bytes_pooled(&pull, &pool, zmq::DONTWAIT).is_err());
+        assert_eq!(pool.len(), 2);
+    }
+}
@@ -0,0 +1,536 @@
+//! Durable outbox for outgoing messages.
+//!
+//! An `Outbox` is a write-ahead journal: every message is appended to a local file before it
+//! is sent, and marked as delivered once it is confirmed. Confirmation depends on the
+//! messaging pattern, e.g. PUSH and PUB sockets can confirm as soon as a message is queued,
+//! while REQ and DEALER sockets should wait for the reply. Messages that were never
+//! confirmed, e.g. because the actor crashed, are recovered when the outbox is opened again,
+//! and can be resent with `OutboxSocket::recover`.
+use super::SocketSend;
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+
+use zmq;
+
+// Journal records start with one of these tags, followed by a big-endian `u64` sequence.
+const RECORD_MESSAGE: u8 = 1;
+const RECORD_CONFIRM: u8 = 2;
+// Starts fresh journals with the next sequence number, so that it is never reused.
+const RECORD_SEQUENCE: u8 = 3;
+// Size of the tag, and sequence, of every record.
+const HEADER_LEN: u64 = 9;
+// Messages waiting to be confirmed, by sequence number.
+type Pending = BTreeMap<u64, Vec<Vec<u8>>>;
+// Default size of the journal before it is rotated.
+const DEFAULT_MAX_BYTES: u64 = 16 * 1024 * 1024;
+
+/// Outbox errors.
+#[derive(Debug, Fail)]
+pub enum OutboxError {
+    #[fail(display = "outbox journal is corrupt at byte {}", _0)]
+    Corrupt(u64),
+    #[fail(display = "outbox journal failed: {}", _0)]
+    Journal(#[cause] io::Error),
+    #[fail(display = "{}", _0)]
+    Socket(#[cause] io::Error),
+}
+
+impl From<io::Error> for OutboxError {
+    fn from(e: io::Error) -> OutboxError {
+        OutboxError::Journal(e)
+    }
+}
+
+/// When journaled messages are confirmed as delivered.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Confirm {
+    /// As soon as the message is queued by the socket, e.g. for PUSH and PUB sockets.
+    OnSend,
+    /// Only when `Outbox::confirm` is called, e.g. when the reply to a request arrives.
+    Manual,
+}
+
+/// Outbox configuration.
+#[derive(Clone, Debug)]
+pub struct OutboxConfig {
+    /// When messages are confirmed as delivered.
+    pub confirm: Confirm,
+    /// Size of the journal, in bytes, after which it is rotated.
+    pub max_bytes: u64,
+    /// Whether to flush every journal record to disk before going on.
+    pub sync: bool,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            confirm: Confirm::OnSend,
+            max_bytes: DEFAULT_MAX_BYTES,
+            sync: true,
+        }
+    }
+}
+
+/// Append-only journal of outgoing messages.
+pub struct Outbox {
+    path: PathBuf,
+    file: File,
+    config: OutboxConfig,
+    pending: Pending,
+    next_sequence: u64,
+    size: u64,
+}
+
+impl Outbox {
+    /// Open the journal at `path`, creating it if needed, and recover the messages that were
+    /// journaled but never confirmed. A record that was cut short, e.g. by a crash while it
+    /// was written, is truncated away, along with anything after it.
+    pub fn open<P: AsRef<Path>>(path: P, config: OutboxConfig) -> Result<Outbox, OutboxError> {
+        let path = path.as_ref().to_path_buf();
+        let (pending, next_sequence, size) = read_journal(&path)?;
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        if file.metadata()?.len() > size {
+            file.set_len(size)?;
+            file.sync_all()?;
+        }
+        let mut outbox = Outbox {
+            path,
+            file,
+            config,
+            pending,
+            next_sequence,
+            size,
+        };
+        outbox.rotate_if_needed()?;
+        Ok(outbox)
+    }
+
+    /// Returns the path to the journal.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the outbox configuration.
+    pub fn config(&self) -> &OutboxConfig {
+        &self.config
+    }
+
+    /// Returns the size of the journal in bytes.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the number of messages that have not been confirmed.
+    pub fn len(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Returns true if every journaled message has been confirmed.
+    pub fn is_empty(&self) -> bool {
+        self.pending.is_empty()
+    }
+
+    /// Returns the messages that have not been confirmed, with their sequence numbers, in
+    /// the order they were journaled.
+    pub fn pending(&self) -> Vec<(u64, Vec<Vec<u8>>)> {
+        self.pending
+            .iter()
+            .map(|(seq, msg)| (*seq, msg.clone()))
+            .collect()
+    }
+
+    /// Append a message to the journal, returning its sequence number.
+    pub fn journal(&mut self, msg: Vec<Vec<u8>>) -> Result<u64, OutboxError> {
+        let sequence = self.next_sequence;
+        let mut record = record_header(RECORD_MESSAGE, sequence);
+        encode_frames(&mut record, &msg);
+        self.append(&record)?;
+        self.pending.insert(sequence, msg);
+        self.next_sequence += 1;
+        Ok(sequence)
+    }
+
+    /// Confirm the message with the `sequence` number as delivered. The journal is truncated
+    /// once every message has been confirmed.
+    pub fn confirm(&mut self, sequence: u64) -> Result<(), OutboxError> {
+        if self.pending.remove(&sequence).is_none() {
+            return Ok(());
+        }
+        if self.pending.is_empty() {
+            self.file.set_len(0)?;
+            self.size = 0;
+            let next = record_header(RECORD_SEQUENCE, self.next_sequence);
+            return self.append(&next);
+        }
+        self.append(&record_header(RECORD_CONFIRM, sequence))?;
+        self.rotate_if_needed()
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<(), OutboxError> {
+        self.file.write_all(record)?;
+        if self.config.sync {
+            self.file.sync_data()?;
+        }
+        self.size += record.len() as u64;
+        Ok(())
+    }
+
+    // Rewrite the pending messages into a fresh journal, once the current one is too large.
+    fn rotate_if_needed(&mut self) -> Result<(), OutboxError> {
+        if self.size <= self.config.max_bytes {
+            return Ok(());
+        }
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(".rotate");
+        let rotated = PathBuf::from(rotated);
+        let mut buf = record_header(RECORD_SEQUENCE, self.next_sequence);
+        for (sequence, msg) in &self.pending {
+            buf.extend(record_header(RECORD_MESSAGE, *sequence));
+            encode_frames(&mut buf, msg);
+        }
+        {
+            let mut file = File::create(&rotated)?;
+            file.write_all(&buf)?;
+            file.sync_all()?;
+        }
+        fs::rename(&rotated, &self.path)?;
+        sync_dir(&self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.size = buf.len() as u64;
+        Ok(())
+    }
+}
+
+/// Socket that journals every outgoing message in an `Outbox` before sending it.
+pub struct OutboxSocket<S> {
+    inner: S,
+    outbox: Outbox,
+}
+
+impl<S: SocketSend> OutboxSocket<S> {
+    /// Create a new `OutboxSocket` that journals into `outbox`.
+    pub fn new(inner: S, outbox: Outbox) -> Self {
+        OutboxSocket { inner, outbox }
+    }
+
+    /// Returns a reference to the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Returns a reference to the outbox.
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    /// Journal and send a multipart message, returning its sequence number. Messages that
+    /// fail to be sent stay in the journal, to be resent with `recover`.
+    pub fn send_multipart<I, T>(&mut self, msg: I, flags: i32) -> Result<u64, OutboxError>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let msg: Vec<Vec<u8>> = msg.into_iter().map(Into::into).collect();
+        let sequence = self.outbox.journal(msg.clone())?;
+        self.send_journaled(sequence, msg, flags)?;
+        Ok(sequence)
+    }
+
+    /// Confirm the message with the `sequence` number as delivered.
+    pub fn confirm(&mut self, sequence: u64) -> Result<(), OutboxError> {
+        self.outbox.confirm(sequence)
+    }
+
+    /// Resend the messages that were never confirmed, e.g. after a restart. Returns the
+    /// sequence numbers of the messages that were resent.
+    pub fn recover(&mut self) -> Result<Vec<u64>, OutboxError> {
+        let mut resent = Vec::new();
+        for (sequence, msg) in self.outbox.pending() {
+            self.send_journaled(sequence, msg, 0)?;
+            resent.push(sequence);
+        }
+        Ok(resent)
+    }
+
+    fn send_journaled(
+        &mut self,
+        sequence: u64,
+        msg: Vec<Vec<u8>>,
+        flags: i32,
+    ) -> Result<(), OutboxError> {
+        self.inner
+            .send_multipart(msg.into_iter().map(zmq::Message::from), flags)
+            .map_err(OutboxError::Socket)?;
+        if self.outbox.config.confirm == Confirm::OnSend {
+            self.outbox.confirm(sequence)?;
+        }
+        Ok(())
+    }
+}
+
+fn record_header(tag: u8, sequence: u64) -> Vec<u8> {
+    let mut record = vec![tag];
+    record.extend(&sequence.to_be_bytes());
+    record
+}
+
+fn encode_frames(buf: &mut Vec<u8>, msg: &[Vec<u8>]) {
+    buf.extend(&(msg.len() as u32).to_be_bytes());
+    for frame in msg {
+        buf.extend(&(frame.len() as u32).to_be_bytes());
+        buf.extend(frame);
+    }
+}
+
+// Read the pending messages from a journal, along with the next sequence number, and the
+// size of its complete records. A record that was cut short, e.g. by a crash while it was
+// written, ends the journal.
+fn read_journal(path: &Path) -> Result<(Pending, u64, u64), OutboxError> {
+    let mut pending = Pending::new();
+    let mut next_sequence = 0;
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok((pending, 0, 0)),
+        Err(e) => return Err(e.into()),
+    };
+    let len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut offset = 0u64;
+    loop {
+        let mut header = [0u8; HEADER_LEN as usize];
+        if !read_full(&mut reader, &mut header)? {
+            break;
+        }
+        let mut sequence = [0u8; 8];
+        sequence.copy_from_slice(&header[1..]);
+        let sequence = u64::from_be_bytes(sequence);
+        let mut end = offset + HEADER_LEN;
+        match header[0] {
+            RECORD_MESSAGE => match read_frames(&mut reader, len - end)? {
+                Some(msg) => {
+                    end += 4 + msg.iter().map(|f| 4 + f.len() as u64).sum::<u64>();
+                    pending.insert(sequence, msg);
+                    next_sequence = next_sequence.max(sequence + 1);
+                }
+                None => break,
+            },
+            RECORD_CONFIRM => {
+                pending.remove(&sequence);
+            }
+            RECORD_SEQUENCE => next_sequence = next_sequence.max(sequence),
+            _ => return Err(OutboxError::Corrupt(offset)),
+        }
+        offset = end;
+    }
+    Ok((pending, next_sequence, offset))
+}
+
+// Read the frames of a message, which has at most `remaining` bytes left in the journal.
+// Returns `None` if the message was cut short.
+fn read_frames<R: Read>(
+    reader: &mut R,
+    mut remaining: u64,
+) -> Result<Option<Vec<Vec<u8>>>, OutboxError> {
+    let mut len = [0u8; 4];
+    if !read_full(reader, &mut len)? {
+        return Ok(None);
+    }
+    let count = u64::from(u32::from_be_bytes(len));
+    // every frame takes at least its length.
+    remaining = remaining.saturating_sub(4);
+    if count * 4 > remaining {
+        return Ok(None);
+    }
+    let mut msg = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        if !read_full(reader, &mut len)? {
+            return Ok(None);
+        }
+        let size = u64::from(u32::from_be_bytes(len));
+        if 4 + size > remaining {
+            return Ok(None);
+        }
+        remaining -= 4 + size;
+        let mut frame = vec![0; size as usize];
+        if !read_full(reader, &mut frame)? {
+            return Ok(None);
+        }
+        msg.push(frame);
+    }
+    Ok(Some(msg))
+}
+
+// Flush the directory of `path` to disk, e.g. once the file was renamed into it.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    if cfg!(unix) {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok(())
+}
+
+// Fill `buf`, returning false if the reader ended first.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<bool> {
+    match reader.read_exact(buf) {
+        Ok(()) => Ok(true),
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use uuid::Uuid;
+
+    fn journal_path() -> PathBuf {
+        env::temp_dir().join(format!("neuras-outbox-{}", Uuid::new_v4()))
+    }
+
+    fn manual() -> OutboxConfig {
+        OutboxConfig {
+            confirm: Confirm::Manual,
+            sync: false,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn unconfirmed_messages_are_recovered_on_open() {
+        let path = journal_path();
+        {
+            let mut outbox = Outbox::open(&path, manual()).unwrap();
+            outbox.journal(vec![b"a".to_vec()]).unwrap();
+            let second = outbox.journal(vec![b"b".to_vec(), b"c".to_vec()]).unwrap();
+            outbox.journal(vec![b"d".to_vec()]).unwrap();
+            outbox.confirm(second).unwrap();
+        }
+        let mut outbox = Outbox::open(&path, manual()).unwrap();
+        let pending = outbox.pending();
+        assert_eq!(pending.len(), 2);
+        assert_eq!(pending[0], (0, vec![b"a".to_vec()]));
+        assert_eq!(pending[1], (2, vec![b"d".to_vec()]));
+        assert_eq!(outbox.journal(vec![b"e".to_vec()]).unwrap(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journals_are_truncated_once_everything_is_confirmed() {
+        let path = journal_path();
+        let mut outbox = Outbox::open(&path, manual()).unwrap();
+        let seq = outbox.journal(vec![b"a".to_vec()]).unwrap();
+        assert!(outbox.size() > 0);
+        outbox.confirm(seq).unwrap();
+        assert!(outbox.is_empty());
+        // only the next sequence number is kept, so that it is not reused.
+        assert_eq!(fs::metadata(&path).unwrap().len(), HEADER_LEN);
+        drop(outbox);
+        let mut outbox = Outbox::open(&path, manual()).unwrap();
+        assert!(outbox.is_empty());
+        assert_eq!(outbox.journal(vec![b"b".to_vec()]).unwrap(), seq + 1);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn journals_are_rotated_when_too_large() {
+        let path = journal_path();
+        let config = OutboxConfig {
+            max_bytes: 64,
+            ..manual()
+        };
+        let mut outbox = Outbox::open(&path, config.clone()).unwrap();
+        for _ in 0..4 {
+            outbox.journal(vec![vec![0u8; 16]]).unwrap();
+        }
+        for seq in 0..3 {
+            outbox.confirm(seq).unwrap();
+        }
+        assert!(outbox.size() <= 64);
+        drop(outbox);
+        let outbox = Outbox::open(&path, config).unwrap();
+        assert_eq!(outbox.pending(), vec![(3, vec![vec![0u8; 16]])]);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncated_records_end_the_journal() {
+        let path = journal_path();
+        {
+            let mut outbox = Outbox::open(&path, manual()).unwrap();
+            outbox.journal(vec![b"whole".to_vec()]).unwrap();
+            outbox.journal(vec![b"partial".to_vec()]).unwrap();
+        }
+        let len = fs::metadata(&path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len - 3)
+            .unwrap();
+        let mut outbox = Outbox::open(&path, manual()).unwrap();
+        assert_eq!(outbox.len(), 1);
+        // the torn record is truncated, so that the next records can be read back.
+        assert_eq!(outbox.size(), fs::metadata(&path).unwrap().len());
+        outbox.journal(vec![b"next".to_vec()]).unwrap();
+        drop(outbox);
+        let outbox = Outbox::open(&path, manual()).unwrap();
+        assert_eq!(outbox.len(), 2);
+
+        // lengths past the end of the journal are torn records, not allocations.
+        let mut torn = record_header(RECORD_MESSAGE, 9);
+        torn.extend(&u32::MAX.to_be_bytes());
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&torn)
+            .unwrap();
+        assert_eq!(Outbox::open(&path, manual()).unwrap().len(), 2);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn outbox_sockets_resend_unconfirmed_messages() {
+        let ctx = zmq::Context::new();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://outbox").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect("inproc://outbox").unwrap();
+        let path = journal_path();
+
+        let mut socket = OutboxSocket::new(push, Outbox::open(&path, manual()).unwrap());
+        let seq = socket.send_multipart(vec!["hello"], 0).unwrap();
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"hello");
+        assert_eq!(socket.outbox().len(), 1);
+
+        assert_eq!(socket.recover().unwrap(), vec![seq]);
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"hello");
+        socket.confirm(seq).unwrap();
+        assert!(socket.outbox().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn outbox_sockets_confirm_on_send() {
+        let ctx = zmq::Context::new();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://outbox_on_send").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect("inproc://outbox_on_send").unwrap();
+        let path = journal_path();
+
+        let outbox = Outbox::open(&path, OutboxConfig::default()).unwrap();
+        let mut socket = OutboxSocket::new(push, outbox);
+        socket.send_multipart(vec!["hello"], 0).unwrap();
+        assert!(socket.outbox().is_empty());
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"hello");
+        fs::remove_file(&path).unwrap();
+    }
+}
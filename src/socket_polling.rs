@@ -9,26 +9,102 @@
 //!
 //! This module also adds `mio`-compatibility for sockets, by implementing
 //! the `mio::Evented` trait, which is used for registering the
-//! socket with a `mio::Poll` instance.
-use super::{SocketRecv, SocketSend, SocketWrapper};
+//! socket with a `mio::Poll` instance. On unix, the `ZMQ_FD` of the socket is
+//! registered directly, elsewhere it is watched from a thread of its own.
+//!
+//! Endpoints that are bound, or connected, with `PollingSocket::bind`, and
+//! `PollingSocket::connect`, are remembered, so that `close_with_linger` can
+//! unbind, and disconnect, them before the socket is closed.
+#[cfg(unix)]
+#[path = "socket_polling_unix.rs"]
+mod sys;
+#[cfg(not(unix))]
+#[path = "socket_polling_watch.rs"]
+mod sys;
+#[cfg(all(unix, test))]
+#[path = "socket_polling_watch.rs"]
+mod watch;
+
+use super::stats::{SocketCounters, SocketStats};
+use super::{IntoFrame, SocketRecv, SocketSend, SocketWrapper};
 
+use std::cell::RefCell;
+use std::cmp;
+use std::ffi::CString;
 use std::io;
-use std::os::unix::io::RawFd;
+use std::time::Duration;
 
-use mio_lib::unix::EventedFd;
 use mio_lib::Evented;
 use mio_lib::{Poll, PollOpt, Ready, Token};
-use zmq::{Message, Sendable, Socket, DONTWAIT};
+use zmq::{self, Message, Socket, DONTWAIT};
+use zmq_sys::{self, RawFd};
 
 /// Socket used for polling with `mio::Poll`.
 pub struct PollingSocket {
+    // dropped before `inner`, which is polled until then.
+    readiness: sys::Readiness,
     inner: Socket,
+    stats: Option<SocketCounters>,
+    endpoints: RefCell<Vec<Attached>>,
+}
+
+// An endpoint that the socket was bound, or connected, to.
+#[derive(Clone, Debug, PartialEq)]
+enum Attached {
+    Bound(String),
+    Connected(String),
 }
 
 impl PollingSocket {
     /// Create a new `PollingSocket` instance.
     pub fn new(inner: Socket) -> PollingSocket {
-        PollingSocket { inner }
+        PollingSocket {
+            readiness: sys::Readiness::new(),
+            inner,
+            stats: None,
+            endpoints: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Create a new `PollingSocket` instance that keeps statistics.
+    pub fn with_stats(inner: Socket) -> PollingSocket {
+        PollingSocket {
+            readiness: sys::Readiness::new(),
+            inner,
+            stats: Some(SocketCounters::default()),
+            endpoints: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Start keeping statistics, if not already enabled.
+    pub fn enable_stats(&mut self) {
+        if self.stats.is_none() {
+            self.stats = Some(SocketCounters::default());
+        }
+    }
+
+    /// Return a snapshot of the socket statistics, if enabled.
+    pub fn stats(&self) -> Option<SocketStats> {
+        self.stats.as_ref().map(SocketCounters::snapshot)
+    }
+
+    // Returns true if the frame that was received has more frames after it.
+    fn more(&self) -> bool {
+        self.inner.get_rcvmore().unwrap_or(false)
+    }
+
+    /// Return the current readiness of the socket, from its `ZMQ_EVENTS`. Readiness of the
+    /// `ZMQ_FD` may be stale, and reading `ZMQ_EVENTS` is what signals it again.
+    pub fn events(&self) -> io::Result<Ready> {
+        let events = self.inner.get_events()?;
+        let mut readiness = Ready::empty();
+        if events.contains(zmq::POLLIN) {
+            readiness |= Ready::readable();
+        }
+        if events.contains(zmq::POLLOUT) {
+            readiness |= Ready::writable();
+        }
+        Ok(readiness)
     }
 
     /// Return a result with the `RawFd` from the underlying socket.
@@ -36,6 +112,88 @@ impl PollingSocket {
         let fd = self.inner.get_fd()?;
         Ok(fd)
     }
+
+    /// Bind the socket to `endpoint`, returning the endpoint it was actually bound to, e.g.
+    /// the port of `tcp://127.0.0.1:*`.
+    pub fn bind(&self, endpoint: &str) -> io::Result<String> {
+        self.inner.bind(endpoint)?;
+        let bound = match self.inner.get_last_endpoint()? {
+            Ok(bound) => bound,
+            Err(_) => endpoint.to_string(),
+        };
+        self.endpoints
+            .borrow_mut()
+            .push(Attached::Bound(bound.clone()));
+        Ok(bound)
+    }
+
+    /// Connect the socket to `endpoint`.
+    pub fn connect(&self, endpoint: &str) -> io::Result<()> {
+        self.inner.connect(endpoint)?;
+        self.endpoints
+            .borrow_mut()
+            .push(Attached::Connected(endpoint.to_string()));
+        Ok(())
+    }
+
+    /// Returns the endpoints that the socket was bound, or connected, to, with `bind`, and
+    /// `connect`.
+    pub fn endpoints(&self) -> Vec<String> {
+        self.endpoints
+            .borrow()
+            .iter()
+            .map(|attached| match *attached {
+                Attached::Bound(ref endpoint) | Attached::Connected(ref endpoint) => {
+                    endpoint.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Unbind, and disconnect, every endpoint that the socket was bound, or connected, to,
+    /// with `bind`, and `connect`. Messages that are queued for disconnected peers are sent
+    /// for as long as the `ZMQ_LINGER` of the socket allows.
+    pub fn detach_all(&mut self) -> io::Result<()> {
+        let endpoints: Vec<Attached> = self.endpoints.borrow_mut().drain(..).collect();
+        for attached in endpoints {
+            let detached = match attached {
+                Attached::Bound(ref endpoint) => unbind(&mut self.inner, endpoint),
+                Attached::Connected(ref endpoint) => self.inner.disconnect(endpoint),
+            };
+            match detached {
+                // the endpoint may be gone already, e.g. after `unbind` on the socket.
+                Ok(()) | Err(zmq::Error::ENOENT) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the socket, after setting its `ZMQ_LINGER` to `linger`, and detaching it from
+    /// its endpoints, see `detach_all`.
+    ///
+    /// Closing does not block; the messages that were not sent yet are sent in the
+    /// background, and dropped once `linger` elapses. Terminating the context waits for
+    /// them, see `TokioSocket::close_with_linger` to wait for a single socket.
+    pub fn close_with_linger(mut self, linger: Duration) -> io::Result<()> {
+        self.inner.set_linger(linger_millis(linger))?;
+        self.detach_all()
+    }
+}
+
+// Unbind the socket from `endpoint`, which is not wrapped by the `zmq` crate.
+fn unbind(socket: &mut Socket, endpoint: &str) -> Result<(), zmq::Error> {
+    let endpoint = CString::new(endpoint).map_err(|_| zmq::Error::EINVAL)?;
+    let rc = unsafe { zmq_sys::zmq_unbind(socket.as_mut_ptr(), endpoint.as_ptr()) };
+    if rc == -1 {
+        return Err(zmq::Error::from_raw(unsafe { zmq_sys::zmq_errno() }));
+    }
+    Ok(())
+}
+
+/// Returns `linger` in milliseconds, as the `ZMQ_LINGER` socket option.
+pub fn linger_millis(linger: Duration) -> i32 {
+    cmp::min(linger.as_millis(), i32::MAX as u128) as i32
 }
 
 /// Implementation of the `SocketWrapper` API for pollable sockets.
@@ -52,11 +210,18 @@ impl SocketWrapper for PollingSocket {
 impl SocketSend for PollingSocket {
     fn send<M>(&self, msg: M, flags: i32) -> io::Result<()>
     where
-        M: Sendable,
+        M: Into<Message>,
     {
-        self.get_socket_ref()
+        let msg = msg.into();
+        let bytes = msg.len();
+        let resulting = self
+            .get_socket_ref()
             .send(msg, DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            stats.sent(&resulting, bytes, flags & zmq::SNDMORE != 0);
+        }
+        resulting
     }
 
     fn send_multipart<I, M>(&self, iter: I, flags: i32) -> io::Result<()>
@@ -64,48 +229,133 @@ impl SocketSend for PollingSocket {
         I: IntoIterator<Item = M>,
         M: Into<Message>,
     {
-        self.get_socket_ref()
-            .send_multipart(iter, DONTWAIT | flags)
-            .map_err(|e| e.into())
+        let mut span = nspan!("send_multipart");
+        let stats = match self.stats {
+            Some(ref stats) => stats,
+            None => {
+                let mut count = 0;
+                let resulting = self
+                    .get_socket_ref()
+                    .send_multipart(iter.into_iter().inspect(|_| count += 1), DONTWAIT | flags)
+                    .map_err(|e| e.into());
+                span.record(format_args!("frames={}", count));
+                return resulting;
+            }
+        };
+        let frames: Vec<Message> = iter.into_iter().map(Into::into).collect();
+        span.record(format_args!("frames={}", frames.len()));
+        let bytes = frames.iter().map(|f| f.len()).sum();
+        let resulting = self
+            .get_socket_ref()
+            .send_multipart(frames, DONTWAIT | flags)
+            .map_err(|e| e.into());
+        stats.sent(&resulting, bytes, flags & zmq::SNDMORE != 0);
+        resulting
+    }
+
+    fn send_frame<F>(&self, frame: F, flags: i32) -> io::Result<()>
+    where
+        F: IntoFrame,
+    {
+        let frame = frame.into_frame();
+        let bytes = frame.len();
+        let resulting = self
+            .get_socket_ref()
+            .send(frame, DONTWAIT | flags)
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            stats.sent(&resulting, bytes, flags & zmq::SNDMORE != 0);
+        }
+        resulting
     }
 }
 
 /// Implementation of the `SocketRecv` API for pollable sockets.
 impl SocketRecv for PollingSocket {
     fn recv(&self, buf: &mut Message, flags: i32) -> io::Result<()> {
-        self.get_socket_ref()
+        let resulting = self
+            .get_socket_ref()
             .recv(buf, DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            stats.received(&resulting, |_| buf.len(), buf.get_more());
+        }
+        resulting
     }
 
     fn recv_into(&self, buf: &mut [u8], flags: i32) -> io::Result<usize> {
-        self.get_socket_ref()
+        let resulting = self
+            .get_socket_ref()
             .recv_into(buf, DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            stats.received(&resulting, |n| *n, self.more());
+        }
+        resulting
     }
 
     fn recv_msg(&self, flags: i32) -> io::Result<Message> {
-        self.get_socket_ref()
+        let resulting = self
+            .get_socket_ref()
             .recv_msg(DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            let more = resulting
+                .as_ref()
+                .map(|msg| msg.get_more())
+                .unwrap_or(false);
+            stats.received(&resulting, |msg| msg.len(), more);
+        }
+        resulting
     }
 
     fn recv_bytes(&self, flags: i32) -> io::Result<Vec<u8>> {
-        self.get_socket_ref()
+        let resulting = self
+            .get_socket_ref()
             .recv_bytes(DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            stats.received(&resulting, |bytes| bytes.len(), self.more());
+        }
+        resulting
     }
 
     fn recv_string(&self, flags: i32) -> io::Result<Result<String, Vec<u8>>> {
-        self.get_socket_ref()
+        let resulting = self
+            .get_socket_ref()
             .recv_string(DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Some(ref stats) = self.stats {
+            let more = self.more();
+            stats.received(
+                &resulting,
+                |string| match *string {
+                    Ok(ref s) => s.len(),
+                    Err(ref bytes) => bytes.len(),
+                },
+                more,
+            );
+        }
+        resulting
     }
 
     fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
-        self.get_socket_ref()
+        let mut span = nspan!("recv_multipart");
+        let resulting = self
+            .get_socket_ref()
             .recv_multipart(DONTWAIT | flags)
-            .map_err(|e| e.into())
+            .map_err(|e| e.into());
+        if let Ok(ref frames) = resulting {
+            span.record(format_args!("frames={}", frames.len()));
+        }
+        if let Some(ref stats) = self.stats {
+            stats.received(
+                &resulting,
+                |frames| frames.iter().map(|f| f.len()).sum(),
+                false,
+            );
+        }
+        resulting
     }
 }
 
@@ -117,9 +367,9 @@ impl From<Socket> for PollingSocket {
 }
 
 /// Converts from a pollable socket into a regular socket.
-impl Into<Socket> for PollingSocket {
-    fn into(self) -> Socket {
-        self.inner
+impl From<PollingSocket> for Socket {
+    fn from(socket: PollingSocket) -> Self {
+        socket.inner
     }
 }
 
@@ -133,7 +383,7 @@ impl Evented for PollingSocket {
         opts: PollOpt,
     ) -> io::Result<()> {
         let fd = self.as_fd()?;
-        EventedFd(&fd).register(poll, token, interest, opts)
+        self.readiness.register(fd, poll, token, interest, opts)
     }
 
     fn reregister(
@@ -144,18 +394,19 @@ impl Evented for PollingSocket {
         opts: PollOpt,
     ) -> io::Result<()> {
         let fd = self.as_fd()?;
-        EventedFd(&fd).reregister(poll, token, interest, opts)
+        self.readiness.reregister(fd, poll, token, interest, opts)
     }
 
     fn deregister(&self, poll: &Poll) -> io::Result<()> {
         let fd = self.as_fd()?;
-        EventedFd(&fd).deregister(poll)
+        self.readiness.deregister(fd, poll)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use testkit::wait_until;
     use zmq::{self, Context, Socket};
 
     fn setup_socket() -> Socket {
@@ -178,4 +429,71 @@ mod tests {
         let pollable: PollingSocket = socket.into();
         assert_eq!(pollable.inner.get_identity(), Ok(b"my_identity".to_vec()));
     }
+
+    #[test]
+    fn closing_with_linger_detaches_from_every_endpoint() {
+        use std::time::Duration;
+
+        let ctx = Context::new();
+        let mut pollable = PollingSocket::new(ctx.socket(zmq::PUSH).unwrap());
+        let bound = pollable.bind("tcp://127.0.0.1:*").unwrap();
+        assert!(!bound.ends_with(":*"));
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("inproc://polling_linger").unwrap();
+        pollable.connect("inproc://polling_linger").unwrap();
+        assert_eq!(
+            pollable.endpoints(),
+            vec![bound.clone(), "inproc://polling_linger".to_string()]
+        );
+
+        pollable.send("bye", 0).unwrap();
+        pollable.detach_all().unwrap();
+        assert!(pollable.endpoints().is_empty());
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"bye");
+        // the port is released by the I/O thread, so that it can be bound again.
+        let rebound = ctx.socket(zmq::PULL).unwrap();
+        assert!(wait_until(2_000, || rebound.bind(&bound).is_ok()));
+        pollable
+            .close_with_linger(Duration::from_millis(10))
+            .unwrap();
+    }
+
+    #[test]
+    fn pollable_sockets_have_no_stats_by_default() {
+        let pollable = PollingSocket::new(setup_socket());
+        assert_eq!(pollable.stats(), None);
+    }
+
+    #[test]
+    fn pollable_sockets_with_stats_count_messages_and_bytes() {
+        let ctx = Context::new();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://polling_stats").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://polling_stats").unwrap();
+        let sender = PollingSocket::with_stats(sender);
+        let mut receiver = PollingSocket::new(receiver);
+        receiver.enable_stats();
+
+        assert!(receiver.recv_bytes(0).is_err());
+        sender
+            .send_multipart(vec![b"hello".to_vec(), b"world".to_vec()], 0)
+            .unwrap();
+        sender.send_frame(b"!".to_vec(), 0).unwrap();
+        sender.send("ab", zmq::SNDMORE).unwrap();
+        sender.send("c", 0).unwrap();
+        assert_eq!(receiver.recv_multipart(0).unwrap().len(), 2);
+        assert_eq!(receiver.recv_bytes(0).unwrap(), b"!");
+        assert_eq!(receiver.recv_bytes(0).unwrap(), b"ab");
+        assert_eq!(receiver.recv_bytes(0).unwrap(), b"c");
+
+        let sent = sender.stats().unwrap();
+        assert_eq!(sent.messages_sent, 3);
+        assert_eq!(sent.bytes_sent, 14);
+        let received = receiver.stats().unwrap();
+        assert_eq!(received.messages_received, 3);
+        assert_eq!(received.bytes_received, 14);
+        assert_eq!(received.would_block, 1);
+        assert!(received.last_activity.is_some());
+    }
 }
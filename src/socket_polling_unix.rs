@@ -0,0 +1,42 @@
+//! Readiness of sockets on unix, where `mio` polls the `ZMQ_FD` of sockets directly.
+use std::io;
+
+use mio_lib::unix::EventedFd;
+use mio_lib::{Evented, Poll, PollOpt, Ready, Token};
+use zmq_sys::RawFd;
+
+/// Registers the `ZMQ_FD` of a socket with `mio::Poll`.
+#[derive(Debug, Default)]
+pub struct Readiness;
+
+impl Readiness {
+    pub fn new() -> Readiness {
+        Readiness
+    }
+
+    pub fn register(
+        &self,
+        fd: RawFd,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&fd).register(poll, token, interest, opts)
+    }
+
+    pub fn reregister(
+        &self,
+        fd: RawFd,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&fd).reregister(poll, token, interest, opts)
+    }
+
+    pub fn deregister(&self, fd: RawFd, poll: &Poll) -> io::Result<()> {
+        EventedFd(&fd).deregister(poll)
+    }
+}
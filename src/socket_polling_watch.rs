@@ -0,0 +1,160 @@
+//! Readiness of sockets on other platforms, e.g. Windows, where `mio` can not poll the
+//! `ZMQ_FD` of sockets, which is a `SOCKET` there.
+//!
+//! A watcher thread polls the `ZMQ_FD` with `zmq::poll` instead, and sets the readiness of
+//! a `mio::Registration` whenever it is readable. As on unix, a readable `ZMQ_FD` only
+//! tells that the events of the socket changed, so readiness may be spurious, and reading
+//! from the socket may still fail with `WouldBlock`. While the `ZMQ_FD` stays readable, the
+//! watcher signals again every `WATCH_INTERVAL` milliseconds.
+use super::super::super::utils::run_named_thread;
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use mio_lib::{Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use zmq;
+use zmq_sys::RawFd;
+
+// Milliseconds between the signals of a `ZMQ_FD` that stays readable.
+const WATCH_INTERVAL: u64 = 1;
+// Milliseconds between the checks for the watcher to stop.
+const WATCH_TIMEOUT: i64 = 10;
+
+// A watcher thread, and the registration whose readiness it sets.
+struct Watcher {
+    registration: Registration,
+    stop: Arc<AtomicBool>,
+    thread: Option<thread::JoinHandle<io::Result<()>>>,
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        // the `ZMQ_FD` must not be polled after its socket is closed.
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Watches the `ZMQ_FD` of a socket, on its own thread, for `mio::Poll`.
+#[derive(Default)]
+pub struct Readiness {
+    watcher: Mutex<Option<Watcher>>,
+}
+
+impl Readiness {
+    pub fn new() -> Readiness {
+        Readiness::default()
+    }
+
+    pub fn register(
+        &self,
+        fd: RawFd,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        let mut watcher = self.watcher.lock().unwrap();
+        if watcher.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "socket is already registered",
+            ));
+        }
+        let (registration, readiness) = Registration::new2();
+        poll.register(&registration, token, interest, opts)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = run_named_thread("poll-watch", move || watch(fd, &readiness, &stopped))?;
+        *watcher = Some(Watcher {
+            registration,
+            stop,
+            thread: Some(thread),
+        });
+        Ok(())
+    }
+
+    pub fn reregister(
+        &self,
+        _fd: RawFd,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        match *self.watcher.lock().unwrap() {
+            Some(ref watcher) => poll.reregister(&watcher.registration, token, interest, opts),
+            None => Err(not_registered()),
+        }
+    }
+
+    pub fn deregister(&self, _fd: RawFd, poll: &Poll) -> io::Result<()> {
+        match self.watcher.lock().unwrap().take() {
+            Some(watcher) => poll.deregister(&watcher.registration),
+            None => Err(not_registered()),
+        }
+    }
+}
+
+fn not_registered() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "socket is not registered")
+}
+
+// Poll `fd` until stopped, setting `readiness` whenever it is readable.
+fn watch(fd: RawFd, readiness: &SetReadiness, stop: &AtomicBool) -> io::Result<()> {
+    while !stop.load(Ordering::SeqCst) {
+        let mut items = [zmq::PollItem::from_fd(fd, zmq::POLLIN)];
+        if zmq::poll(&mut items, WATCH_TIMEOUT)? > 0 {
+            // events are only delivered when the readiness changes.
+            readiness.set_readiness(Ready::empty())?;
+            readiness.set_readiness(Ready::readable() | Ready::writable())?;
+            thread::sleep(Duration::from_millis(WATCH_INTERVAL));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mio_lib::Events;
+
+    #[test]
+    fn watched_sockets_become_ready() {
+        let ctx = zmq::Context::new();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.bind("inproc://poll_watch").unwrap();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.connect("inproc://poll_watch").unwrap();
+
+        let poll = Poll::new().unwrap();
+        let readiness = Readiness::new();
+        let fd = receiver.get_fd().unwrap();
+        readiness
+            .register(fd, &poll, Token(7), Ready::readable(), PollOpt::edge())
+            .unwrap();
+        assert!(readiness
+            .register(fd, &poll, Token(7), Ready::readable(), PollOpt::edge())
+            .is_err());
+        readiness
+            .reregister(fd, &poll, Token(8), Ready::readable(), PollOpt::edge())
+            .unwrap();
+        sender.send("ping", 0).unwrap();
+
+        let mut events = Events::with_capacity(8);
+        poll.poll(&mut events, Some(Duration::from_secs(1)))
+            .unwrap();
+        let event = events.iter().next().unwrap();
+        assert_eq!(event.token(), Token(8));
+        assert!(event.readiness().is_readable());
+        assert_eq!(receiver.recv_bytes(zmq::DONTWAIT).unwrap(), b"ping");
+
+        readiness.deregister(fd, &poll).unwrap();
+        assert!(readiness.deregister(fd, &poll).is_err());
+    }
+}
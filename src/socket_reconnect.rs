@@ -0,0 +1,310 @@
+//! Reconnect, and backoff, policies for sockets.
+//!
+//! libzmq reconnects its sockets on its own, every `reconnect_ivl` milliseconds, doubling
+//! the interval on each attempt up to `reconnect_ivl_max`. Connecting itself can fail
+//! though, e.g. with `EMFILE`, or while a transport is not ready, so a `ReconnectPolicy`
+//! also retries application-level operations with an exponential backoff, and jitter.
+//!
+//! Only `Transient` errors are retried: an invalid endpoint, or an unsupported transport,
+//! fails the same way every time, and is returned at once.
+use rand::{self, Rng};
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use zmq;
+
+/// Errors that may go away when the operation that failed is retried.
+pub trait Transient {
+    /// Returns true if retrying the operation may succeed.
+    fn is_transient(&self) -> bool;
+}
+
+impl Transient for zmq::Error {
+    fn is_transient(&self) -> bool {
+        matches!(
+            *self,
+            zmq::Error::EAGAIN
+                | zmq::Error::EINTR
+                | zmq::Error::EBUSY
+                | zmq::Error::EINPROGRESS
+                | zmq::Error::EMFILE
+                | zmq::Error::EMTHREAD
+                | zmq::Error::ENOBUFS
+                | zmq::Error::ENOMEM
+                | zmq::Error::ENODEV
+                | zmq::Error::ENETDOWN
+                | zmq::Error::EHOSTUNREACH
+                | zmq::Error::ECONNREFUSED
+                | zmq::Error::EADDRINUSE
+                | zmq::Error::EADDRNOTAVAIL
+        )
+    }
+}
+
+impl Transient for io::Error {
+    fn is_transient(&self) -> bool {
+        matches!(
+            self.kind(),
+            io::ErrorKind::WouldBlock
+                | io::ErrorKind::Interrupted
+                | io::ErrorKind::TimedOut
+                | io::ErrorKind::ConnectionRefused
+                | io::ErrorKind::ConnectionReset
+                | io::ErrorKind::ConnectionAborted
+                | io::ErrorKind::NotConnected
+                | io::ErrorKind::AddrInUse
+                | io::ErrorKind::AddrNotAvailable
+        )
+    }
+}
+
+/// Callback for each retry of a `ReconnectPolicy`.
+pub type RetryCallback = Arc<dyn Fn(&RetryAttempt) + Send + Sync>;
+
+/// A retry about to be made by a `ReconnectPolicy`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryAttempt {
+    /// Number of the retry, starting at 1.
+    pub attempt: usize,
+    /// Delay before the retry.
+    pub delay: Duration,
+    /// The error of the previous attempt.
+    pub error: String,
+}
+
+/// Reconnect configuration for sockets, and backoff for retried operations.
+#[derive(Clone)]
+pub struct ReconnectPolicy {
+    /// Milliseconds between reconnects of the socket. Uses the socket default when unset.
+    pub reconnect_ivl: Option<i32>,
+    /// Maximum milliseconds between reconnects of the socket, which doubles the interval
+    /// after each attempt. Uses the socket default when unset.
+    pub reconnect_ivl_max: Option<i32>,
+    /// Delay before the first retry.
+    pub initial_backoff: Duration,
+    /// Maximum delay between retries.
+    pub max_backoff: Duration,
+    /// Factor the delay grows by after each retry.
+    pub multiplier: f64,
+    /// Fraction, between 0 and 1, of the delay that is randomly added, or removed.
+    pub jitter: f64,
+    /// Maximum number of retries, or unlimited if unset.
+    pub max_retries: Option<usize>,
+    /// Called before each retry.
+    pub on_retry: Option<RetryCallback>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            reconnect_ivl: None,
+            reconnect_ivl_max: None,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(30),
+            multiplier: 2.0,
+            jitter: 0.2,
+            max_retries: Some(5),
+            on_retry: None,
+        }
+    }
+}
+
+impl fmt::Debug for ReconnectPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReconnectPolicy")
+            .field("reconnect_ivl", &self.reconnect_ivl)
+            .field("reconnect_ivl_max", &self.reconnect_ivl_max)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("jitter", &self.jitter)
+            .field("max_retries", &self.max_retries)
+            .field("on_retry", &self.on_retry.is_some())
+            .finish()
+    }
+}
+
+impl ReconnectPolicy {
+    /// Create a new `ReconnectPolicy` with the socket defaults, that retries up to 5 times,
+    /// from 100 milliseconds up to 30 seconds apart.
+    pub fn new() -> Self {
+        ReconnectPolicy::default()
+    }
+
+    /// Set the milliseconds between reconnects of the socket.
+    pub fn set_reconnect_ivl(&mut self, ivl: i32) {
+        self.reconnect_ivl = Some(ivl);
+    }
+
+    /// Set the maximum milliseconds between reconnects of the socket.
+    pub fn set_reconnect_ivl_max(&mut self, ivl: i32) {
+        self.reconnect_ivl_max = Some(ivl);
+    }
+
+    /// Set the delays before the first retry, and between later retries at most.
+    pub fn set_backoff(&mut self, initial: Duration, max: Duration) {
+        self.initial_backoff = initial;
+        self.max_backoff = max;
+    }
+
+    /// Set the factor the delay grows by after each retry.
+    pub fn set_multiplier(&mut self, multiplier: f64) {
+        self.multiplier = multiplier;
+    }
+
+    /// Set the fraction of the delay that is randomly added, or removed.
+    pub fn set_jitter(&mut self, jitter: f64) {
+        self.jitter = jitter.clamp(0.0, 1.0);
+    }
+
+    /// Set the maximum number of retries, or `None` to retry forever.
+    pub fn set_max_retries(&mut self, retries: Option<usize>) {
+        self.max_retries = retries;
+    }
+
+    /// Set the callback called before each retry.
+    pub fn set_on_retry(&mut self, callback: RetryCallback) {
+        self.on_retry = Some(callback);
+    }
+
+    /// Apply the reconnect intervals to a socket. Must be called before it connects.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        if let Some(ivl) = self.reconnect_ivl {
+            socket.set_reconnect_ivl(ivl)?;
+        }
+        if let Some(ivl) = self.reconnect_ivl_max {
+            socket.set_reconnect_ivl_max(ivl)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the delay before retry number `attempt`, starting at 1, with jitter.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let delay = self.initial_backoff.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max_backoff.as_secs_f64());
+        // a random factor within [1 - jitter, 1 + jitter].
+        let factor = 1.0 + self.jitter * rand::thread_rng().gen_range(-1.0, 1.0);
+        Duration::from_secs_f64((delay * factor).max(0.0))
+    }
+
+    /// Run `operation` until it succeeds, sleeping with backoff between attempts, and
+    /// returning the last error once the retries are exhausted, or as soon as the error is
+    /// not transient.
+    pub fn retry<T, E, F>(&self, mut operation: F) -> Result<T, E>
+    where
+        E: fmt::Display + Transient,
+        F: FnMut() -> Result<T, E>,
+    {
+        let mut attempt = 0;
+        loop {
+            let error = match operation() {
+                Ok(value) => return Ok(value),
+                Err(e) => e,
+            };
+            if !error.is_transient() {
+                return Err(error);
+            }
+            attempt += 1;
+            if self.max_retries.is_some_and(|max| attempt > max) {
+                return Err(error);
+            }
+            let retry = RetryAttempt {
+                attempt,
+                delay: self.backoff(attempt),
+                error: error.to_string(),
+            };
+            nlog!(
+                debug,
+                "retrying attempt={} delay={:?} error={}",
+                retry.attempt,
+                retry.delay,
+                retry.error
+            );
+            if let Some(ref callback) = self.on_retry {
+                callback(&retry);
+            }
+            thread::sleep(retry.delay);
+        }
+    }
+
+    /// Connect `socket` to `endpoint`, retrying with backoff when it fails with a transient
+    /// error.
+    pub fn connect(&self, socket: &zmq::Socket, endpoint: &str) -> Result<(), zmq::Error> {
+        self.retry(|| socket.connect(endpoint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    #[test]
+    fn backoff_grows_up_to_its_maximum() {
+        let mut policy = ReconnectPolicy::new();
+        policy.set_backoff(Duration::from_millis(10), Duration::from_millis(50));
+        policy.set_jitter(0.0);
+        let delays: Vec<_> = (1..5).map(|n| policy.backoff(n).as_millis()).collect();
+        assert_eq!(delays, vec![10, 20, 40, 50]);
+
+        policy.set_jitter(0.5);
+        for _ in 0..20 {
+            let delay = policy.backoff(1);
+            assert!(delay >= Duration::from_millis(5) && delay <= Duration::from_millis(15));
+        }
+    }
+
+    #[test]
+    fn retries_call_back_until_they_are_exhausted() {
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let seen = attempts.clone();
+        let mut policy = ReconnectPolicy::new();
+        policy.set_backoff(Duration::from_millis(1), Duration::from_millis(1));
+        policy.set_max_retries(Some(2));
+        policy.set_on_retry(Arc::new(move |retry: &RetryAttempt| {
+            seen.lock().unwrap().push(retry.attempt);
+        }));
+        let failed: Result<(), _> = policy.retry(|| Err(zmq::Error::EMFILE));
+        assert_eq!(failed, Err(zmq::Error::EMFILE));
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2]);
+
+        // errors that won't go away are not retried.
+        let failed: Result<(), _> = policy.retry(|| Err(zmq::Error::EINVAL));
+        assert_eq!(failed, Err(zmq::Error::EINVAL));
+        assert_eq!(*attempts.lock().unwrap(), vec![1, 2]);
+
+        let mut calls = 0;
+        let succeeded = policy.retry(|| {
+            calls += 1;
+            if calls < 2 {
+                Err(zmq::Error::EAGAIN)
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(succeeded, Ok(2));
+    }
+
+    #[test]
+    fn reconnect_intervals_are_applied_to_sockets() {
+        let ctx = zmq::Context::new();
+        let socket = ctx.socket(zmq::DEALER).unwrap();
+        let mut policy = ReconnectPolicy::new();
+        policy.set_reconnect_ivl(250);
+        policy.set_reconnect_ivl_max(4_000);
+        policy.apply(&socket).unwrap();
+        assert_eq!(socket.get_reconnect_ivl().unwrap(), 250);
+        assert_eq!(socket.get_reconnect_ivl_max().unwrap(), 4_000);
+        policy
+            .connect(&socket, "inproc://reconnect_policy")
+            .unwrap();
+        policy.set_backoff(Duration::from_secs(10), Duration::from_secs(10));
+        assert_eq!(
+            policy.connect(&socket, "bogus://reconnect_policy"),
+            Err(zmq::Error::EPROTONOSUPPORT)
+        );
+    }
+}
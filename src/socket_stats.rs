@@ -0,0 +1,134 @@
+//! Socket statistics and throughput counters.
+//!
+//! Counters are optional, and have to be enabled for each socket, e.g. with
+//! `PollingSocket::with_stats`. Once enabled, `stats()` returns a `SocketStats` snapshot,
+//! which is cheap enough to be taken on every health check.
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Snapshot of the counters of a socket.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SocketStats {
+    /// Number of messages sent. Multipart messages count as one, once their last frame is
+    /// sent.
+    pub messages_sent: usize,
+    /// Number of messages received. Multipart messages count as one, once their last frame
+    /// is received.
+    pub messages_received: usize,
+    /// Number of bytes sent.
+    pub bytes_sent: usize,
+    /// Number of bytes received.
+    pub bytes_received: usize,
+    /// Number of operations that failed with `std::io::ErrorKind::WouldBlock`.
+    pub would_block: usize,
+    /// System time of the last message sent or received, as milliseconds since UNIX EPOCH.
+    pub last_activity: Option<i64>,
+}
+
+// Counters shared by the socket wrappers.
+#[derive(Debug, Default)]
+pub(crate) struct SocketCounters {
+    messages_sent: AtomicUsize,
+    messages_received: AtomicUsize,
+    bytes_sent: AtomicUsize,
+    bytes_received: AtomicUsize,
+    would_block: AtomicUsize,
+    // milliseconds since UNIX EPOCH, zero when there has been no activity.
+    last_activity: AtomicU64,
+}
+
+impl SocketCounters {
+    // Count the outcome of sending `bytes` of content, which end a message unless `more` of
+    // its frames follow.
+    pub fn sent<T>(&self, result: &io::Result<T>, bytes: usize, more: bool) {
+        match *result {
+            Ok(_) => {
+                if !more {
+                    self.messages_sent.fetch_add(1, Ordering::Relaxed);
+                }
+                self.bytes_sent.fetch_add(bytes, Ordering::Relaxed);
+                self.touch();
+            }
+            Err(ref e) => self.failed(e),
+        }
+    }
+
+    // Count the outcome of receiving content, measured with `bytes`, which ends a message
+    // unless `more` of its frames follow.
+    pub fn received<T, F>(&self, result: &io::Result<T>, bytes: F, more: bool)
+    where
+        F: FnOnce(&T) -> usize,
+    {
+        match *result {
+            Ok(ref msg) => {
+                if !more {
+                    self.messages_received.fetch_add(1, Ordering::Relaxed);
+                }
+                self.bytes_received.fetch_add(bytes(msg), Ordering::Relaxed);
+                self.touch();
+            }
+            Err(ref e) => self.failed(e),
+        }
+    }
+
+    pub fn snapshot(&self) -> SocketStats {
+        let last_activity = match self.last_activity.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(millis as i64),
+        };
+        SocketStats {
+            messages_sent: self.messages_sent.load(Ordering::Relaxed),
+            messages_received: self.messages_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            would_block: self.would_block.load(Ordering::Relaxed),
+            last_activity,
+        }
+    }
+
+    fn failed(&self, e: &io::Error) {
+        if e.kind() == io::ErrorKind::WouldBlock {
+            self.would_block.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn touch(&self) {
+        if let Ok(d) = SystemTime::now().duration_since(UNIX_EPOCH) {
+            let millis = d.as_secs() * 1_000 + u64::from(d.subsec_millis());
+            self.last_activity.store(millis, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_counters_are_empty() {
+        let counters = SocketCounters::default();
+        assert_eq!(counters.snapshot(), SocketStats::default());
+    }
+
+    #[test]
+    fn counters_track_messages_bytes_and_would_block() {
+        let counters = SocketCounters::default();
+        counters.sent(&Ok(()), 5, false);
+        counters.received(&Ok(vec![1u8, 2, 3]), |v| v.len(), false);
+        counters.received::<Vec<u8>, _>(&Err(io::ErrorKind::WouldBlock.into()), |v| v.len(), false);
+        counters.sent::<()>(&Err(io::ErrorKind::Other.into()), 5, false);
+        // frames of a multipart message count as one message, with all of their bytes.
+        counters.sent(&Ok(()), 2, true);
+        counters.sent(&Ok(()), 3, false);
+        counters.received(&Ok(vec![1u8]), |v| v.len(), true);
+        counters.received(&Ok(vec![2u8]), |v| v.len(), false);
+        let stats = counters.snapshot();
+        assert_eq!(stats.messages_sent, 2);
+        assert_eq!(stats.bytes_sent, 10);
+        assert_eq!(stats.messages_received, 2);
+        assert_eq!(stats.bytes_received, 5);
+        assert_eq!(stats.would_block, 1);
+        assert!(stats.last_activity.is_some());
+    }
+}
@@ -0,0 +1,244 @@
+//! Raw TCP peers, with `STREAM` sockets.
+//!
+//! A `STREAM` socket talks to plain TCP peers, that do not speak ZMTP, e.g. line protocols,
+//! or HTTP health probes. Every message is the identity of a peer, followed by bytes as
+//! they arrived, in chunks of any size. An empty message is sent by the socket when a peer
+//! connects, and again when it disconnects, and closes the connection when it is sent.
+//!
+//! `RawStream` keeps track of the connected peers, to tell those events apart, and hides
+//! the identity frames behind a bytes-oriented API. It is a `SocketWrapper`, so it can be
+//! polled alongside other sockets.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::{RawStream, StreamEvent};
+//! use std::io::{Read, Write};
+//! use std::net::TcpStream;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let stream = RawStream::new(&ctx).unwrap();
+//! let endpoint = stream.bind("tcp://127.0.0.1:*").unwrap();
+//!
+//! let mut client = TcpStream::connect(endpoint.trim_start_matches("tcp://")).unwrap();
+//! let peer = match stream.recv(0).unwrap() {
+//!     StreamEvent::Connected(peer) => peer,
+//!     other => panic!("unexpected event: {:?}", other),
+//! };
+//! stream.send(&peer, b"hello\n").unwrap();
+//! let mut line = [0u8; 6];
+//! client.read_exact(&mut line).unwrap();
+//! assert_eq!(&line, b"hello\n");
+//! client.write_all(b"bye\n").unwrap();
+//! # }
+//! ```
+use super::{SocketError, SocketWrapper};
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::io;
+use zmq;
+
+/// What is received on a `RawStream`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum StreamEvent {
+    /// A peer connected, either to a bound endpoint, or after `RawStream::connect`.
+    Connected(Vec<u8>),
+    /// A peer disconnected, or was closed.
+    Disconnected(Vec<u8>),
+    /// Bytes sent by a peer.
+    Data(Vec<u8>, Vec<u8>),
+}
+
+impl StreamEvent {
+    /// Returns the identity of the peer of the event.
+    pub fn peer(&self) -> &[u8] {
+        match *self {
+            StreamEvent::Connected(ref peer)
+            | StreamEvent::Disconnected(ref peer)
+            | StreamEvent::Data(ref peer, _) => peer,
+        }
+    }
+}
+
+/// A `STREAM` socket, for raw TCP peers.
+pub struct RawStream {
+    socket: zmq::Socket,
+    peers: RefCell<HashSet<Vec<u8>>>,
+}
+
+impl RawStream {
+    /// Create a new `STREAM` socket in `context`.
+    pub fn new(context: &zmq::Context) -> Result<RawStream, SocketError> {
+        let socket = context.socket(zmq::STREAM)?;
+        socket.set_linger(0)?;
+        RawStream::from_socket(socket)
+    }
+
+    /// Wrap a `STREAM` socket. Fails with `SocketError::Zmq(zmq::Error::EINVAL)` for other
+    /// types of sockets.
+    pub fn from_socket(socket: zmq::Socket) -> Result<RawStream, SocketError> {
+        if socket.get_socket_type()? != zmq::STREAM {
+            return Err(zmq::Error::EINVAL.into());
+        }
+        Ok(RawStream {
+            socket,
+            peers: RefCell::new(HashSet::new()),
+        })
+    }
+
+    /// Bind to a TCP `endpoint`, to accept peers, returning the endpoint that it was
+    /// actually bound to, e.g. for `tcp://127.0.0.1:*`.
+    pub fn bind(&self, endpoint: &str) -> Result<String, SocketError> {
+        self.socket.bind(endpoint)?;
+        self.socket
+            .get_last_endpoint()?
+            .map_err(SocketError::Endpoint)
+    }
+
+    /// Connect to a TCP `endpoint`. The identity of the peer is known once it connects,
+    /// with `StreamEvent::Connected`.
+    pub fn connect(&self, endpoint: &str) -> Result<(), SocketError> {
+        self.socket.connect(endpoint)?;
+        Ok(())
+    }
+
+    /// Returns the identities of the connected peers.
+    pub fn peers(&self) -> Vec<Vec<u8>> {
+        self.peers.borrow().iter().cloned().collect()
+    }
+
+    /// Returns true if the peer with `identity` is connected.
+    pub fn is_connected(&self, identity: &[u8]) -> bool {
+        self.peers.borrow().contains(identity)
+    }
+
+    /// Receive the next event, waiting for it unless `flags` has `zmq::DONTWAIT`.
+    pub fn recv(&self, flags: i32) -> io::Result<StreamEvent> {
+        let mut msg = self.socket.recv_multipart(flags)?;
+        if msg.len() != 2 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "stream messages have an identity, and data",
+            ));
+        }
+        let data = msg.pop().unwrap_or_default();
+        let peer = msg.pop().unwrap_or_default();
+        if !data.is_empty() {
+            return Ok(StreamEvent::Data(peer, data));
+        }
+        let mut peers = self.peers.borrow_mut();
+        if peers.remove(&peer) {
+            Ok(StreamEvent::Disconnected(peer))
+        } else {
+            peers.insert(peer.clone());
+            Ok(StreamEvent::Connected(peer))
+        }
+    }
+
+    /// Send `data` to the peer with `identity`. Empty `data` is not sent, as it would close
+    /// the connection, see `RawStream::close`. Fails with `EHOSTUNREACH` if the peer is not
+    /// connected.
+    pub fn send(&self, identity: &[u8], data: &[u8]) -> io::Result<()> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        self.socket.send(identity, zmq::SNDMORE)?;
+        self.socket.send(data, 0)?;
+        Ok(())
+    }
+
+    /// Close the connection to the peer with `identity`, once the data sent to it is
+    /// flushed. libzmq ends the connection as the socket is used, e.g. polled, afterwards.
+    pub fn close(&self, identity: &[u8]) -> io::Result<()> {
+        self.peers.borrow_mut().remove(identity);
+        self.socket.send(identity, zmq::SNDMORE)?;
+        self.socket.send(&b""[..], 0)?;
+        Ok(())
+    }
+}
+
+impl SocketWrapper for RawStream {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.socket.get_rcvmore().map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    #[test]
+    fn raw_streams_accept_and_close_tcp_peers() {
+        let ctx = zmq::Context::new();
+        let stream = RawStream::new(&ctx).unwrap();
+        let endpoint = stream.bind("tcp://127.0.0.1:*").unwrap();
+        let mut client = TcpStream::connect(endpoint.trim_start_matches("tcp://")).unwrap();
+        let peer = match stream.recv(0).unwrap() {
+            StreamEvent::Connected(peer) => peer,
+            other => panic!("unexpected event: {:?}", other),
+        };
+        assert!(stream.is_connected(&peer));
+
+        client.write_all(b"ping\n").unwrap();
+        let mut received = Vec::new();
+        while received.len() < 5 {
+            match stream.recv(0).unwrap() {
+                StreamEvent::Data(from, data) => {
+                    assert_eq!(from, peer);
+                    received.extend(data);
+                }
+                other => panic!("unexpected event: {:?}", other),
+            }
+        }
+        assert_eq!(received, b"ping\n");
+        stream.send(&peer, b"pong\n").unwrap();
+        stream.close(&peer).unwrap();
+        // libzmq ends the connection as the socket is polled.
+        client
+            .set_read_timeout(Some(Duration::from_millis(10)))
+            .unwrap();
+        let mut reply: Vec<u8> = Vec::new();
+        let mut buf = [0u8; 16];
+        loop {
+            match client.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => reply.extend(&buf[..n]),
+                Err(_) => assert_eq!(stream.get_socket_ref().poll(zmq::POLLIN, 10).unwrap(), 0),
+            }
+        }
+        assert_eq!(reply, b"pong\n");
+        assert!(stream.peers().is_empty());
+    }
+
+    #[test]
+    fn raw_streams_connect_to_tcp_servers() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let ctx = zmq::Context::new();
+        let stream = RawStream::new(&ctx).unwrap();
+        stream.connect(&format!("tcp://{}", address)).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        let peer = match stream.recv(0).unwrap() {
+            StreamEvent::Connected(peer) => peer,
+            other => panic!("unexpected event: {:?}", other),
+        };
+        stream.send(&peer, b"hello").unwrap();
+        let mut hello = [0u8; 5];
+        server.read_exact(&mut hello).unwrap();
+        assert_eq!(&hello, b"hello");
+
+        drop(server);
+        assert_eq!(stream.recv(0).unwrap(), StreamEvent::Disconnected(peer));
+        assert!(RawStream::from_socket(ctx.socket(zmq::PUSH).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,277 @@
+//! Subscribers that remember their subscriptions.
+//!
+//! A `Subscriber` is a `SUB` socket that keeps the set of topics it subscribed to, which
+//! may be done before it is connected to any endpoint. Its connections are watched with a
+//! socket monitor, and every time an endpoint is connected again, e.g. after the publisher
+//! restarted, or after `Subscriber::replace_endpoint`, a `SubscriberEvent::Resubscribed`
+//! event is emitted.
+//!
+//! libzmq sends every subscription of a `SUB` socket over each of its connections,
+//! including those made again after a publisher restarted, so the subscriptions are never
+//! applied by hand. It does so once the socket handles its pending commands, which a
+//! reconnect leaves it, so the subscriber has the socket handle them on
+//! `SubscriberEvent::Resubscribed`, rather than on the next receive.
+//!
+//! libzmq does not monitor `inproc` transports, so their reconnects are not seen.
+//! Endpoints are told apart by the address that the monitor reports, so they should be
+//! connected with numeric addresses, e.g. `tcp://127.0.0.1:5556`.
+use super::SocketError;
+
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet, VecDeque};
+use std::io;
+
+use uuid::Uuid;
+use zmq;
+
+// Events used to follow the connections of the socket.
+const CONNECTION_EVENTS: i32 =
+    zmq::SocketEvent::CONNECTED as i32 | zmq::SocketEvent::DISCONNECTED as i32;
+
+/// A change of the connections of a `Subscriber`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SubscriberEvent {
+    /// The subscriber connected to `endpoint`, for the first time.
+    Connected { endpoint: String },
+    /// The subscriber lost its connection to `endpoint`. libzmq reconnects on its own.
+    Disconnected { endpoint: String },
+    /// The subscriber connected to `endpoint` again, and sent its `topics` over the new
+    /// connection.
+    Resubscribed { endpoint: String, topics: usize },
+}
+
+/// `SUB` socket that remembers its subscriptions, and tells when they are sent again.
+pub struct Subscriber {
+    socket: zmq::Socket,
+    monitor: zmq::Socket,
+    topics: BTreeSet<Vec<u8>>,
+    endpoints: Vec<String>,
+    // endpoints that were connected at least once.
+    seen: RefCell<HashSet<String>>,
+    // endpoints that replaced others, whose first connection is a resubscription.
+    renewed: RefCell<HashSet<String>>,
+    events: RefCell<VecDeque<SubscriberEvent>>,
+}
+
+impl Subscriber {
+    /// Create a new `Subscriber`, with no subscriptions, in `context`.
+    pub fn new(context: &zmq::Context) -> Result<Subscriber, SocketError> {
+        let socket = context.socket(zmq::SUB)?;
+        let endpoint = format!(
+            "inproc://neuras.subscriber.monitor.{}",
+            Uuid::new_v4().to_simple()
+        );
+        socket.monitor(&endpoint, CONNECTION_EVENTS)?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(&endpoint)?;
+        Ok(Subscriber {
+            socket,
+            monitor,
+            topics: BTreeSet::new(),
+            endpoints: Vec::new(),
+            seen: RefCell::new(HashSet::new()),
+            renewed: RefCell::new(HashSet::new()),
+            events: RefCell::new(VecDeque::new()),
+        })
+    }
+
+    /// Subscribe to `topic`. Subscribing to a topic twice has no effect.
+    pub fn subscribe(&mut self, topic: &[u8]) -> Result<(), SocketError> {
+        if self.topics.insert(topic.to_vec()) {
+            self.socket.set_subscribe(topic)?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from `topic`.
+    pub fn unsubscribe(&mut self, topic: &[u8]) -> Result<(), SocketError> {
+        if self.topics.remove(topic) {
+            self.socket.set_unsubscribe(topic)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the topics that are subscribed to, in order.
+    pub fn subscriptions(&self) -> Vec<Vec<u8>> {
+        self.topics.iter().cloned().collect()
+    }
+
+    /// Connect to the publisher at `endpoint`.
+    pub fn connect(&mut self, endpoint: &str) -> Result<(), SocketError> {
+        self.socket.connect(endpoint)?;
+        self.endpoints.push(endpoint.to_string());
+        Ok(())
+    }
+
+    /// Disconnect from the publisher at `endpoint`.
+    pub fn disconnect(&mut self, endpoint: &str) -> Result<(), SocketError> {
+        self.socket.disconnect(endpoint)?;
+        self.endpoints.retain(|connected| connected != endpoint);
+        Ok(())
+    }
+
+    /// Move from the publisher at `old` to the one at `new`, keeping the subscriptions.
+    /// Connecting to `new` emits a `SubscriberEvent::Resubscribed` event.
+    pub fn replace_endpoint(&mut self, old: &str, new: &str) -> Result<(), SocketError> {
+        self.connect(new)?;
+        self.disconnect(old)?;
+        self.seen.borrow_mut().insert(new.to_string());
+        self.renewed.borrow_mut().insert(new.to_string());
+        Ok(())
+    }
+
+    /// Returns the endpoints that the subscriber is connected to.
+    pub fn endpoints(&self) -> &[String] {
+        &self.endpoints
+    }
+
+    /// Returns a reference to the underlying socket, e.g. to poll it.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Receive a multipart message. Connection changes are handled first, so that
+    /// subscriptions are applied again before waiting.
+    pub fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
+        self.handle_monitor()?;
+        Ok(self.socket.recv_multipart(flags)?)
+    }
+
+    /// Handle the connection changes seen so far, and return their events, including
+    /// those that were handled while receiving.
+    pub fn poll_events(&self) -> io::Result<Vec<SubscriberEvent>> {
+        self.handle_monitor()?;
+        Ok(self.events.borrow_mut().drain(..).collect())
+    }
+
+    fn handle_monitor(&self) -> io::Result<()> {
+        loop {
+            let msg = match self.monitor.recv_multipart(zmq::DONTWAIT) {
+                Ok(msg) => msg,
+                Err(zmq::Error::EAGAIN) => return Ok(()),
+                Err(e) => return Err(e.into()),
+            };
+            if msg.len() < 2 || msg[0].len() < 2 {
+                continue;
+            }
+            let event = u16::from(msg[0][0]) | (u16::from(msg[0][1]) << 8);
+            let endpoint = String::from_utf8_lossy(&msg[1]).into_owned();
+            let event = match zmq::SocketEvent::from_raw(event) {
+                zmq::SocketEvent::CONNECTED => match self.connected(endpoint)? {
+                    Some(event) => event,
+                    None => continue,
+                },
+                zmq::SocketEvent::DISCONNECTED => SubscriberEvent::Disconnected { endpoint },
+                _ => continue,
+            };
+            nlog!(debug, "subscriber {:?}", event);
+            self.events.borrow_mut().push_back(event);
+        }
+    }
+
+    // Returns the event of a connection to `endpoint`, or `None` if the subscriber is no
+    // longer connected to it.
+    fn connected(&self, endpoint: String) -> io::Result<Option<SubscriberEvent>> {
+        let renewed = self.renewed.borrow_mut().remove(&endpoint);
+        if !renewed && self.seen.borrow_mut().insert(endpoint.clone()) {
+            return Ok(Some(SubscriberEvent::Connected { endpoint }));
+        }
+        if !renewed && !self.endpoints.contains(&endpoint) {
+            nlog!(
+                debug,
+                "subscriber reconnected to unknown endpoint={}",
+                endpoint
+            );
+            return Ok(None);
+        }
+        // reading the events of the socket has it handle its commands, and resend.
+        self.socket.get_events()?;
+        Ok(Some(SubscriberEvent::Resubscribed {
+            endpoint,
+            topics: self.topics.len(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Subscription;
+    use super::*;
+    use testkit::wait_until;
+
+    fn bind_xpub(ctx: &zmq::Context, endpoint: &str) -> zmq::Socket {
+        let xpub = ctx.socket(zmq::XPUB).unwrap();
+        xpub.set_linger(0).unwrap();
+        xpub.set_rcvtimeo(2_000).unwrap();
+        assert!(wait_until(2_000, || xpub.bind(endpoint).is_ok()));
+        xpub
+    }
+
+    #[test]
+    fn subscriptions_are_kept_before_connecting() {
+        let ctx = zmq::Context::new();
+        let mut subscriber = Subscriber::new(&ctx).unwrap();
+        subscriber.subscribe(b"b").unwrap();
+        subscriber.subscribe(b"a").unwrap();
+        subscriber.subscribe(b"a").unwrap();
+        subscriber.unsubscribe(b"b").unwrap();
+        assert_eq!(subscriber.subscriptions(), vec![b"a".to_vec()]);
+
+        let xpub = bind_xpub(&ctx, "inproc://subscriber-early");
+        assert!(subscriber.endpoints().is_empty());
+        subscriber.connect("inproc://subscriber-early").unwrap();
+        assert_eq!(
+            Subscription::parse(&xpub.recv_bytes(0).unwrap()),
+            Some(Subscription::Subscribed {
+                topic: b"a".to_vec()
+            })
+        );
+        xpub.send_multipart(vec!["b", "skipped"], 0).unwrap();
+        xpub.send_multipart(vec!["a", "hello"], 0).unwrap();
+        assert_eq!(
+            subscriber.recv_multipart(0).unwrap(),
+            vec![b"a".to_vec(), b"hello".to_vec()]
+        );
+    }
+
+    #[test]
+    fn reconnects_send_the_subscriptions_again() {
+        let ctx = zmq::Context::new();
+        let probe = ctx.socket(zmq::XPUB).unwrap();
+        probe.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = probe.get_last_endpoint().unwrap().unwrap();
+        drop(probe);
+
+        let mut subscriber = Subscriber::new(&ctx).unwrap();
+        subscriber.get_ref().set_reconnect_ivl(10).unwrap();
+        subscriber.subscribe(b"news").unwrap();
+        let xpub = bind_xpub(&ctx, &endpoint);
+        subscriber.connect(&endpoint).unwrap();
+        assert_eq!(xpub.recv_bytes(0).unwrap(), b"\x01news");
+
+        // the publisher restarts on the same endpoint.
+        drop(xpub);
+        let xpub = bind_xpub(&ctx, &endpoint);
+        let mut events = Vec::new();
+        assert!(wait_until(2_000, || {
+            events.extend(subscriber.poll_events().unwrap());
+            events
+                .iter()
+                .any(|event| matches!(*event, SubscriberEvent::Resubscribed { .. }))
+        }));
+        assert_eq!(xpub.recv_bytes(0).unwrap(), b"\x01news");
+        assert_eq!(
+            events[0],
+            SubscriberEvent::Connected {
+                endpoint: endpoint.clone()
+            }
+        );
+        assert!(events.contains(&SubscriberEvent::Disconnected {
+            endpoint: endpoint.clone()
+        }));
+        assert!(events.contains(&SubscriberEvent::Resubscribed {
+            endpoint: endpoint.clone(),
+            topics: 1,
+        }));
+    }
+}
@@ -1,4 +1,8 @@
 //! `tokio`-compatibility for sockets.
+#[path = "socket_tokio_cancel.rs"]
+pub mod cancel;
+#[path = "socket_tokio_framed.rs"]
+pub mod framed;
 #[path = "socket_tokio_future.rs"]
 pub mod future;
 #[path = "socket_tokio_sink.rs"]
@@ -6,38 +10,106 @@ pub mod sink;
 #[path = "socket_tokio_stream.rs"]
 pub mod stream;
 
-use self::future::{RecvMessage, RecvMultipartMessage};
+use self::cancel::Deadline;
+use self::framed::{Framed, MultipartCodec};
+use self::future::{Closing, RecvMessage, RecvMultipartMessage};
 use self::future::{SendMessage, SendMultipartMessage};
-use self::sink::{MessageMultipartSink, MessageSink};
-use self::stream::{MessageMultipartStream, MessageStream};
-use super::PollingSocket;
-use super::{SocketRecv, SocketSend, SocketWrapper};
+use self::sink::{MessageMultipartSink, MessageSink, PackedSink};
+use self::stream::{LogicalMessageStream, MessageBatchStream, MessageMultipartStream};
+use self::stream::{MessageStream, PackedStream, SubscriptionStream};
+use super::polling::linger_millis;
+use super::{IntoFrame, SocketRecv, SocketSend, SocketWrapper};
+use super::{PollingSocket, SocketStats};
 
-use futures::Async;
+use futures::{task, Async};
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio_core::reactor::{Handle, PollEvented};
-use zmq::{Message, Sendable, Socket};
+use uuid::Uuid;
+use zmq::{self, Message, Socket};
 
 /// `tokio`-compatible wrapper for sockets.
 pub struct TokioSocket {
     inner: PollEvented<PollingSocket>,
+    handle: Handle,
 }
 
 impl TokioSocket {
     pub fn new(socket: Socket, handle: &Handle) -> io::Result<TokioSocket> {
         let inner = PollEvented::new(PollingSocket::new(socket), handle)?;
-        Ok(TokioSocket { inner })
+        Ok(TokioSocket {
+            inner,
+            handle: handle.clone(),
+        })
+    }
+
+    /// Create a new `TokioSocket` instance that keeps statistics.
+    pub fn with_stats(socket: Socket, handle: &Handle) -> io::Result<TokioSocket> {
+        let inner = PollEvented::new(PollingSocket::with_stats(socket), handle)?;
+        Ok(TokioSocket {
+            inner,
+            handle: handle.clone(),
+        })
+    }
+
+    /// Return a snapshot of the socket statistics, if enabled.
+    pub fn stats(&self) -> Option<SocketStats> {
+        self.inner.get_ref().stats()
+    }
+
+    /// Bind the socket to `endpoint`, returning the endpoint it was actually bound to, see
+    /// `PollingSocket::bind`.
+    pub fn bind(&self, endpoint: &str) -> io::Result<String> {
+        self.inner.get_ref().bind(endpoint)
+    }
+
+    /// Connect the socket to `endpoint`, see `PollingSocket::connect`.
+    pub fn connect(&self, endpoint: &str) -> io::Result<()> {
+        self.inner.get_ref().connect(endpoint)
+    }
+
+    /// Returns the endpoints that the socket was bound, or connected, to, with `bind`, and
+    /// `connect`.
+    pub fn endpoints(&self) -> Vec<String> {
+        self.inner.get_ref().endpoints()
+    }
+
+    /// Close the socket, after setting its `ZMQ_LINGER` to `linger`, and detaching it from
+    /// its endpoints, see `PollingSocket::close_with_linger`.
+    ///
+    /// Returns a future that resolves once the pending messages were sent, or `linger`
+    /// elapsed and they were dropped. The socket is watched with a monitor, which is why
+    /// `context` must be the context that created the socket.
+    pub fn close_with_linger(
+        mut self,
+        context: &zmq::Context,
+        linger: Duration,
+    ) -> io::Result<Closing> {
+        let endpoint = format!(
+            "inproc://neuras.close.monitor.{}",
+            Uuid::new_v4().to_simple()
+        );
+        let socket = self.get_socket_ref();
+        socket.set_linger(linger_millis(linger))?;
+        socket.monitor(&endpoint, zmq::SocketEvent::MONITOR_STOPPED as i32)?;
+        let monitor = context.socket(zmq::PAIR)?;
+        monitor.connect(&endpoint)?;
+        let monitor = TokioSocket::new(monitor, &self.handle)?;
+        let closing = Closing::new(monitor, Instant::now() + linger, &self.handle)?;
+        self.inner.get_mut().detach_all()?;
+        Ok(closing)
     }
 }
 
 impl TokioSocket {
     /// Sends a type implementing `Into<zmq::Message>` as a `Future`.
-    pub fn send<M: Into<Message>>(&self, message: M, flags: i32) -> SendMessage {
+    pub fn send<M: Into<Message>>(&self, message: M, flags: i32) -> SendMessage<'_> {
         SendMessage::new(self, message, flags)
     }
 
     /// Sends a type implementing `Into<zmq::Message>` as a `Future`.
-    pub fn send_multipart<I, M>(&self, messages: I, flags: i32) -> SendMultipartMessage
+    pub fn send_multipart<I, M>(&self, messages: I, flags: i32) -> SendMultipartMessage<'_>
     where
         I: IntoIterator<Item = M>,
         M: Into<Vec<u8>>,
@@ -45,34 +117,107 @@ impl TokioSocket {
         SendMultipartMessage::new(self, messages, flags)
     }
 
+    /// Sends shared buffers as a multi-part message `Future`, without cloning them.
+    pub fn send_multipart_shared<I>(&self, messages: I, flags: i32) -> SendMultipartMessage<'_>
+    where
+        I: IntoIterator<Item = Arc<[u8]>>,
+    {
+        SendMultipartMessage::from_shared(self, messages.into_iter().collect(), flags)
+    }
+
     /// Returns a `Future` that resolves into a `zmq::Message`
     pub fn recv<'a, 'b>(&'a self, msg: &'b mut Message, flags: i32) -> RecvMessage<'a, 'b> {
         RecvMessage::new(self, msg, flags)
     }
 
     /// Returns a `Future` that resolves into a `Vec<zmq::Message>`
-    pub fn recv_multipart(&self, flags: i32) -> RecvMultipartMessage {
+    pub fn recv_multipart(&self, flags: i32) -> RecvMultipartMessage<'_> {
         RecvMultipartMessage::new(self, flags)
     }
 
-    /// Returns a `Stream` of incoming messages.
-    pub fn stream(&self) -> MessageStream<Self> {
+    /// Returns a `Future` that resolves into a `Vec<zmq::Message>`, or fails with
+    /// `io::ErrorKind::TimedOut` if none arrives within `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Deadline<RecvMultipartMessage<'_>> {
+        let deadline = Instant::now() + timeout;
+        Deadline::new(self.recv_multipart(0), deadline, &self.handle)
+    }
+
+    /// Returns a `Stream` of incoming frames, one at a time.
+    pub fn stream(&self) -> MessageStream<'_, Self> {
         MessageStream::new(self)
     }
 
+    /// Returns a `Stream` of incoming logical messages, with all of their frames.
+    pub fn stream_logical(&self) -> LogicalMessageStream<'_, Self> {
+        LogicalMessageStream::new(self)
+    }
+
     /// Returns a `Stream` of incoming multi-part messages.
-    pub fn stream_multipart(&self) -> MessageMultipartStream<Self> {
+    pub fn stream_multipart(&self) -> MessageMultipartStream<'_, Self> {
         MessageMultipartStream::new(self)
     }
 
+    /// Returns a `Stream` of incoming multi-part messages, that fails with
+    /// `io::ErrorKind::TimedOut` once the `deadline` passes.
+    pub fn stream_with_deadline(
+        &self,
+        deadline: Instant,
+    ) -> Deadline<MessageMultipartStream<'_, Self>> {
+        Deadline::new(self.stream_multipart(), deadline, &self.handle)
+    }
+
+    /// Returns a `Stream` of batches of incoming multi-part messages, with up to
+    /// `max_messages` messages each.
+    pub fn stream_batch(&self, max_messages: usize) -> MessageBatchStream<'_, Self> {
+        MessageBatchStream::new(self, max_messages)
+    }
+
+    /// Returns a `Stream` of the messages unpacked from incoming packed frames.
+    pub fn stream_packed(&self) -> PackedStream<'_, Self> {
+        PackedStream::new(self)
+    }
+
+    /// Returns a `Stream` of the subscriptions, and unsubscriptions, received by an `XPUB`
+    /// socket.
+    pub fn stream_subscriptions(&self) -> SubscriptionStream<'_, Self> {
+        SubscriptionStream::new(self)
+    }
+
+    /// Returns a `Stream`, and `Sink`, of whole multi-part messages.
+    pub fn framed(&self) -> Framed<'_, Self, MultipartCodec> {
+        Framed::new(self, MultipartCodec)
+    }
+
+    /// Returns a `Stream`, and `Sink`, of multi-part messages, turned into items by `codec`.
+    pub fn framed_with<C>(&self, codec: C) -> Framed<'_, Self, C> {
+        Framed::new(self, codec)
+    }
+
     /// Returns a `Sink` for outgoing messages.
-    pub fn sink(&self) -> MessageSink<Self> {
-        MessageSink::new(self)
+    pub fn sink(&self) -> MessageSink<'_, Self> {
+        MessageSink::new(self, &self.handle)
+    }
+
+    /// Returns a `Sink` for outgoing messages, that keeps up to `capacity` messages while
+    /// the socket can't take them, instead of `sink::SINK_CAPACITY`.
+    pub fn sink_with_capacity(&self, capacity: usize) -> MessageSink<'_, Self> {
+        MessageSink::with_capacity(self, capacity, &self.handle)
     }
 
     /// Returns a `Sink` for outgoing multi-part messages.
-    pub fn sink_multipart(&self) -> MessageMultipartSink<Self> {
-        MessageMultipartSink::new(self)
+    pub fn sink_multipart(&self) -> MessageMultipartSink<'_, Self> {
+        MessageMultipartSink::new(self, &self.handle)
+    }
+
+    /// Returns a `Sink` for outgoing multi-part messages, that keeps up to `capacity`
+    /// messages while the socket can't take them, instead of `sink::SINK_CAPACITY`.
+    pub fn sink_multipart_with_capacity(&self, capacity: usize) -> MessageMultipartSink<'_, Self> {
+        MessageMultipartSink::with_capacity(self, capacity, &self.handle)
+    }
+
+    /// Returns a `Sink` that packs outgoing messages into frames of up to `max_size` bytes.
+    pub fn sink_packed(&self, max_size: usize) -> PackedSink<'_, Self> {
+        PackedSink::new(self, max_size)
     }
 }
 
@@ -103,7 +248,7 @@ where
 {
     fn send<M>(&self, msg: M, flags: i32) -> io::Result<()>
     where
-        M: Sendable,
+        M: Into<Message>,
     {
         SocketSend::send(*self, msg, flags)
     }
@@ -115,19 +260,26 @@ where
     {
         SocketSend::send_multipart(*self, iter, flags)
     }
+
+    fn send_frame<F>(&self, frame: F, flags: i32) -> io::Result<()>
+    where
+        F: IntoFrame,
+    {
+        SocketSend::send_frame(*self, frame, flags)
+    }
 }
 
 impl SocketSend for TokioSocket {
     fn send<M>(&self, msg: M, flags: i32) -> io::Result<()>
     where
-        M: Sendable,
+        M: Into<Message>,
     {
-        if let Async::NotReady = self.inner.poll_write() {
+        if !write_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketSend::send(&self.inner, msg, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_write();
+            clear_write(&self.inner);
         }
         resulting
     }
@@ -137,12 +289,26 @@ impl SocketSend for TokioSocket {
         I: IntoIterator<Item = M>,
         M: Into<Message>,
     {
-        if let Async::NotReady = self.inner.poll_write() {
+        if !write_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketSend::send_multipart(&self.inner, iter, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_write();
+            clear_write(&self.inner);
+        }
+        resulting
+    }
+
+    fn send_frame<F>(&self, frame: F, flags: i32) -> io::Result<()>
+    where
+        F: IntoFrame,
+    {
+        if !write_ready(&self.inner) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let resulting = SocketSend::send_frame(&self.inner, frame, flags);
+        if is_wouldblock(&resulting) {
+            clear_write(&self.inner);
         }
         resulting
     }
@@ -152,12 +318,12 @@ impl SocketRecv for TokioSocket {
     /// Receive a message into a `Message`. The length passed to `zmq_msg_recv` is the length
     /// of the buffer.
     fn recv(&self, buf: &mut Message, flags: i32) -> io::Result<()> {
-        if let Async::NotReady = self.inner.poll_read() {
+        if !read_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv(&self.inner, buf, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_read();
+            clear_read(&self.inner);
         }
         resulting
     }
@@ -166,36 +332,36 @@ impl SocketRecv for TokioSocket {
     /// return value is the number of bytes in the message, which may be larger than the length of
     /// the slice, indicating truncation.
     fn recv_into(&self, buf: &mut [u8], flags: i32) -> io::Result<usize> {
-        if let Async::NotReady = self.inner.poll_read() {
+        if !read_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_into(&self.inner, buf, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_read();
+            clear_read(&self.inner);
         }
         resulting
     }
 
     /// Receive a message into a fresh `Message`.
     fn recv_msg(&self, flags: i32) -> io::Result<Message> {
-        if let Async::NotReady = self.inner.poll_read() {
+        if !read_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_msg(&self.inner, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_read();
+            clear_read(&self.inner);
         }
         resulting
     }
 
     /// Receive a message as a byte vector.
     fn recv_bytes(&self, flags: i32) -> io::Result<Vec<u8>> {
-        if let Async::NotReady = self.inner.poll_read() {
+        if !read_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_bytes(&self.inner, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_read();
+            clear_read(&self.inner);
         }
         resulting
     }
@@ -205,12 +371,12 @@ impl SocketRecv for TokioSocket {
     /// If the received message is not valid UTF-8, it is returned as the original `Vec`
     /// in the `Err` part of the inner result.
     fn recv_string(&self, flags: i32) -> io::Result<Result<String, Vec<u8>>> {
-        if let Async::NotReady = self.inner.poll_read() {
+        if !read_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_string(&self.inner, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_read();
+            clear_read(&self.inner);
         }
         resulting
     }
@@ -221,18 +387,18 @@ impl SocketRecv for TokioSocket {
     /// will be possible to process the different parts sequentially and reuse allocations that
     /// way.
     fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
-        if let Async::NotReady = self.inner.poll_read() {
+        if !read_ready(&self.inner) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_multipart(&self.inner, flags);
         if is_wouldblock(&resulting) {
-            self.inner.need_read();
+            clear_read(&self.inner);
         }
         resulting
     }
 }
 
-impl<'b> SocketRecv for &'b TokioSocket {
+impl SocketRecv for &TokioSocket {
     /// Receive a message into a `Message`. The length passed to `zmq_msg_recv` is the length
     /// of the buffer.
     fn recv(&self, buf: &mut Message, flags: i32) -> io::Result<()> {
@@ -286,14 +452,14 @@ impl<T: SocketWrapper> SocketWrapper for PollEvented<T> {
 impl<T: SocketSend> SocketSend for PollEvented<T> {
     fn send<M>(&self, msg: M, flags: i32) -> io::Result<()>
     where
-        M: Sendable,
+        M: Into<Message>,
     {
-        if let Async::NotReady = self.poll_write() {
+        if !write_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketSend::send(self.get_ref(), msg, flags);
         if is_wouldblock(&resulting) {
-            self.need_write();
+            clear_write(self);
         }
         resulting
     }
@@ -303,12 +469,26 @@ impl<T: SocketSend> SocketSend for PollEvented<T> {
         I: IntoIterator<Item = M>,
         M: Into<Message>,
     {
-        if let Async::NotReady = self.poll_write() {
+        if !write_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketSend::send_multipart(self.get_ref(), iter, flags);
         if is_wouldblock(&resulting) {
-            self.need_write();
+            clear_write(self);
+        }
+        resulting
+    }
+
+    fn send_frame<F>(&self, frame: F, flags: i32) -> io::Result<()>
+    where
+        F: IntoFrame,
+    {
+        if !write_ready(self) {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let resulting = SocketSend::send_frame(self.get_ref(), frame, flags);
+        if is_wouldblock(&resulting) {
+            clear_write(self);
         }
         resulting
     }
@@ -318,12 +498,12 @@ impl<T: SocketRecv> SocketRecv for PollEvented<T> {
     /// Receive a message into a `Message`. The length passed to `zmq_msg_recv` is the length
     /// of the buffer.
     fn recv(&self, buf: &mut Message, flags: i32) -> io::Result<()> {
-        if let Async::NotReady = self.poll_read() {
+        if !read_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv(self.get_ref(), buf, flags);
         if is_wouldblock(&resulting) {
-            self.need_read();
+            clear_read(self);
         }
         resulting
     }
@@ -332,36 +512,36 @@ impl<T: SocketRecv> SocketRecv for PollEvented<T> {
     /// return value is the number of bytes in the message, which may be larger than the length of
     /// the slice, indicating truncation.
     fn recv_into(&self, buf: &mut [u8], flags: i32) -> io::Result<usize> {
-        if let Async::NotReady = self.poll_read() {
+        if !read_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_into(self.get_ref(), buf, flags);
         if is_wouldblock(&resulting) {
-            self.need_read();
+            clear_read(self);
         }
         resulting
     }
 
     /// Receive a message into a fresh `Message`.
     fn recv_msg(&self, flags: i32) -> io::Result<Message> {
-        if let Async::NotReady = self.poll_read() {
+        if !read_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_msg(self.get_ref(), flags);
         if is_wouldblock(&resulting) {
-            self.need_read();
+            clear_read(self);
         }
         resulting
     }
 
     /// Receive a message as a byte vector.
     fn recv_bytes(&self, flags: i32) -> io::Result<Vec<u8>> {
-        if let Async::NotReady = self.poll_read() {
+        if !read_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_bytes(self.get_ref(), flags);
         if is_wouldblock(&resulting) {
-            self.need_read();
+            clear_read(self);
         }
         resulting
     }
@@ -371,12 +551,12 @@ impl<T: SocketRecv> SocketRecv for PollEvented<T> {
     /// If the received message is not valid UTF-8, it is returned as the original `Vec`
     /// in the `Err` part of the inner result.
     fn recv_string(&self, flags: i32) -> io::Result<Result<String, Vec<u8>>> {
-        if let Async::NotReady = self.poll_read() {
+        if !read_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_string(self.get_ref(), flags);
         if is_wouldblock(&resulting) {
-            self.need_read();
+            clear_read(self);
         }
         resulting
     }
@@ -387,12 +567,12 @@ impl<T: SocketRecv> SocketRecv for PollEvented<T> {
     /// will be possible to process the different parts sequentially and reuse allocations that
     /// way.
     fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
-        if let Async::NotReady = self.poll_read() {
+        if !read_ready(self) {
             return Err(io::ErrorKind::WouldBlock.into());
         }
         let resulting = SocketRecv::recv_multipart(self.get_ref(), flags);
         if is_wouldblock(&resulting) {
-            self.need_read();
+            clear_read(self);
         }
         resulting
     }
@@ -405,6 +585,52 @@ impl<'b> From<(Socket, &'b Handle)> for TokioSocket {
     }
 }
 
+// Returns true if `evented` may be read without blocking. The `ZMQ_FD` only signals changes
+// of `ZMQ_EVENTS`, and writes may take the edge of incoming messages, so `ZMQ_EVENTS` is
+// consulted whenever the reactor has no readiness.
+fn read_ready<T: SocketWrapper>(evented: &PollEvented<T>) -> bool {
+    match evented.poll_read() {
+        Async::Ready(()) => true,
+        Async::NotReady => has_events(evented, zmq::POLLIN),
+    }
+}
+
+// Returns true if `evented` may be written without blocking, as `read_ready`.
+fn write_ready<T: SocketWrapper>(evented: &PollEvented<T>) -> bool {
+    match evented.poll_write() {
+        Async::Ready(()) => true,
+        Async::NotReady => has_events(evented, zmq::POLLOUT),
+    }
+}
+
+// Clear the read readiness of `evented`, after reading would block. Reading `ZMQ_EVENTS`
+// re-arms the `ZMQ_FD`, and the task is polled again if messages arrived meanwhile, whose
+// edge was already taken, instead of waiting for one that never comes.
+fn clear_read<T: SocketWrapper>(evented: &PollEvented<T>) {
+    evented.need_read();
+    if has_events(evented, zmq::POLLIN) {
+        task::current().notify();
+    }
+}
+
+// Clear the write readiness of `evented`, after writing would block, as `clear_read`.
+fn clear_write<T: SocketWrapper>(evented: &PollEvented<T>) {
+    evented.need_write();
+    if has_events(evented, zmq::POLLOUT) {
+        task::current().notify();
+    }
+}
+
+// Returns true if the `ZMQ_EVENTS` of `evented` contain `events`.
+fn has_events<T: SocketWrapper>(evented: &PollEvented<T>, events: zmq::PollEvents) -> bool {
+    evented
+        .get_ref()
+        .get_socket_ref()
+        .get_events()
+        .map(|current| current.contains(events))
+        .unwrap_or(false)
+}
+
 // Convenience function to check if messaging will block or not.
 fn is_wouldblock<T>(resulting: &io::Result<T>) -> bool {
     match *resulting {
@@ -416,6 +642,7 @@ fn is_wouldblock<T>(resulting: &io::Result<T>) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use futures::Stream;
     use tokio_core::reactor::Core;
     use zmq::{self, Context, Socket};
 
@@ -438,6 +665,373 @@ mod tests {
         );
     }
 
+    #[test]
+    fn stream_batch_yields_all_queued_messages() {
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://tokio_batch").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://tokio_batch").unwrap();
+        // the socket's FD is only signaled for messages sent after a receive would block.
+        assert!(receiver.recv_bytes(zmq::DONTWAIT).is_err());
+        for i in 0..3 {
+            sender.send_multipart(vec![vec![i], vec![i]], 0).unwrap();
+        }
+        let tokio = TokioSocket::new(receiver, &handle).unwrap();
+        let (batch, _) = core
+            .run(tokio.stream_batch(10).into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        let batch = batch.unwrap();
+        assert_eq!(batch.len(), 3);
+        assert_eq!(&*batch[1][0], &[1]);
+    }
+
+    #[test]
+    fn streams_see_messages_queued_before_the_reactor() {
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://tokio_queued").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://tokio_queued").unwrap();
+        sender.send("queued", 0).unwrap();
+        // reading `ZMQ_EVENTS` takes the edge of the queued message.
+        assert!(receiver.get_events().unwrap().contains(zmq::POLLIN));
+        let tokio = TokioSocket::new(receiver, &handle).unwrap();
+        let (msg, _) = core
+            .run(tokio.stream().into_future())
+            .map_err(|(e, _)| e)
+            .unwrap();
+        assert_eq!(&*msg.unwrap(), b"queued");
+    }
+
+    #[test]
+    fn sends_do_not_hide_incoming_messages() {
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let peer = ctx.socket(zmq::PAIR).unwrap();
+        peer.bind("inproc://tokio_hidden").unwrap();
+        let socket = ctx.socket(zmq::PAIR).unwrap();
+        socket.connect("inproc://tokio_hidden").unwrap();
+        let tokio = TokioSocket::new(socket, &handle).unwrap();
+        peer.send("incoming", 0).unwrap();
+        core.run(tokio.send("outgoing", 0)).unwrap();
+        let msg = core.run(tokio.recv_multipart(0)).unwrap();
+        assert_eq!(&*msg[0], b"incoming");
+        assert_eq!(peer.recv_bytes(0).unwrap(), b"outgoing");
+    }
+
+    #[test]
+    fn packed_sinks_and_streams_carry_many_messages_per_frame() {
+        use futures::{stream, Sink};
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://tokio_packed").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://tokio_packed").unwrap();
+        assert!(receiver.recv_bytes(zmq::DONTWAIT).is_err());
+        let sender = TokioSocket::new(sender, &handle).unwrap();
+        let receiver = TokioSocket::new(receiver, &handle).unwrap();
+
+        // four messages of 4 + 4 bytes, in frames of up to 16 bytes.
+        let msgs: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 4]).collect();
+        let packed = sender
+            .sink_packed(16)
+            .send_all(stream::iter_ok::<_, io::Error>(msgs.clone()));
+        let _ = core.run(packed).unwrap();
+        assert_eq!(receiver.get_socket_ref().recv_bytes(0).unwrap().len(), 16);
+        let received = core
+            .run(receiver.stream_packed().take(2).collect())
+            .unwrap();
+        assert_eq!(received, msgs[2..].to_vec());
+    }
+
+    #[test]
+    fn framed_transports_keep_envelopes_across_the_split() {
+        use futures::{Future, Sink};
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let router = ctx.socket(zmq::ROUTER).unwrap();
+        router.bind("inproc://tokio_framed").unwrap();
+        let dealer = ctx.socket(zmq::DEALER).unwrap();
+        dealer.set_identity(b"client").unwrap();
+        dealer.connect("inproc://tokio_framed").unwrap();
+        let router = TokioSocket::new(router, &handle).unwrap();
+        let dealer = TokioSocket::new(dealer, &handle).unwrap();
+
+        let request = vec![Message::from(""), Message::from("ping")];
+        let _ = core.run(dealer.framed().send(request)).unwrap();
+        // echo every request, with the envelope received by the router.
+        let (sink, stream) = router.framed().split();
+        let _ = core.run(stream.take(1).forward(sink)).unwrap();
+        let reply = core
+            .run(dealer.framed().into_future().map_err(|(e, _)| e))
+            .unwrap()
+            .0
+            .unwrap();
+        assert_eq!(reply.len(), 2);
+        assert_eq!(&*reply[1], b"ping");
+    }
+
+    #[test]
+    fn logical_streams_yield_whole_multipart_messages() {
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://tokio_logical").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://tokio_logical").unwrap();
+        assert!(receiver.recv_bytes(zmq::DONTWAIT).is_err());
+        sender.send_multipart(["a", "b", "c"], 0).unwrap();
+        sender.send("d", 0).unwrap();
+        let tokio = TokioSocket::new(receiver, &handle).unwrap();
+        let msgs = core.run(tokio.stream_logical().take(2).collect()).unwrap();
+        assert_eq!(msgs[0].len(), 3);
+        assert_eq!(&*msgs[0][2], b"c");
+        assert_eq!(msgs[1].len(), 1);
+    }
+
+    #[test]
+    fn sinks_flush_every_item_before_completing() {
+        use futures::{stream, Sink};
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://tokio_sink_flush").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://tokio_sink_flush").unwrap();
+        let sender = TokioSocket::new(sender, &handle).unwrap();
+
+        let msgs: Vec<Vec<Vec<u8>>> = (0..3u8).map(|i| vec![vec![i], vec![i]]).collect();
+        let sent = sender
+            .sink_multipart()
+            .send_all(stream::iter_ok::<_, io::Error>(msgs.clone()));
+        let (sink, _) = core.run(sent).unwrap();
+        assert_eq!(sink.pending(), 0);
+        for msg in msgs {
+            assert_eq!(receiver.recv_multipart(0).unwrap(), msg);
+        }
+    }
+
+    #[test]
+    fn hung_peers_time_out_and_pending_receives_can_be_cancelled() {
+        use self::cancel::{is_cancelled, CancelHandle};
+        use std::thread;
+        use std::time::Duration;
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.bind("inproc://tokio_hung").unwrap();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.connect("inproc://tokio_hung").unwrap();
+        let receiver = TokioSocket::new(receiver, &handle).unwrap();
+
+        let timed_out = core.run(receiver.recv_timeout(Duration::from_millis(20)));
+        assert_eq!(timed_out.unwrap_err().kind(), io::ErrorKind::TimedOut);
+
+        sender.send("first", 0).unwrap();
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let stream = receiver.stream_with_deadline(deadline);
+        let (first, stream) = core.run(stream.into_future()).ok().unwrap();
+        assert_eq!(&*first.unwrap()[0], b"first");
+        let (ended, _) = core.run(stream.into_future()).err().unwrap();
+        assert_eq!(ended.kind(), io::ErrorKind::TimedOut);
+        assert!(Instant::now() >= deadline);
+
+        let cancel = CancelHandle::new();
+        let canceller = cancel.clone();
+        let cancelling = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            canceller.cancel();
+        });
+        let cancelled = core.run(cancel.cancellable(receiver.recv_multipart(0)));
+        assert!(is_cancelled(&cancelled.unwrap_err()));
+        cancelling.join().unwrap();
+        assert!(cancel.is_cancelled());
+    }
+
+    #[test]
+    fn subscription_streams_yield_xpub_events() {
+        use super::super::Subscription;
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let xpub = ctx.socket(zmq::XPUB).unwrap();
+        xpub.bind("inproc://tokio_xpub").unwrap();
+        let xpub = TokioSocket::new(xpub, &handle).unwrap();
+        let sub = ctx.socket(zmq::SUB).unwrap();
+        sub.connect("inproc://tokio_xpub").unwrap();
+        sub.set_subscribe(b"news").unwrap();
+        sub.set_unsubscribe(b"news").unwrap();
+
+        let events = core
+            .run(xpub.stream_subscriptions().take(2).collect())
+            .unwrap();
+        assert_eq!(
+            events,
+            vec![
+                Subscription::Subscribed {
+                    topic: b"news".to_vec()
+                },
+                Subscription::Unsubscribed {
+                    topic: b"news".to_vec()
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn closing_with_linger_waits_for_pending_messages() {
+        use self::future::CloseOutcome;
+        use std::time::Duration;
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind("tcp://127.0.0.1:*").unwrap();
+        let endpoint = pull.get_last_endpoint().unwrap().unwrap();
+        let push = TokioSocket::new(ctx.socket(zmq::PUSH).unwrap(), &handle).unwrap();
+        push.connect(&endpoint).unwrap();
+        assert_eq!(push.endpoints(), vec![endpoint.clone()]);
+        for _ in 0..10 {
+            core.run(push.send("pending", 0)).unwrap();
+        }
+        let closing = push
+            .close_with_linger(&ctx, Duration::from_secs(5))
+            .unwrap();
+        assert_eq!(core.run(closing).unwrap(), CloseOutcome::Flushed);
+        for _ in 0..10 {
+            assert_eq!(pull.recv_bytes(0).unwrap(), b"pending");
+        }
+
+        // nobody listens on a connected endpoint, so messages stay pending.
+        drop(pull);
+        let push = TokioSocket::new(ctx.socket(zmq::PUSH).unwrap(), &handle).unwrap();
+        push.connect(&endpoint).unwrap();
+        core.run(push.send("lost", 0)).unwrap();
+        let started = Instant::now();
+        let closing = push
+            .close_with_linger(&ctx, Duration::from_millis(50))
+            .unwrap();
+        assert_eq!(core.run(closing).unwrap(), CloseOutcome::LingerExpired);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+    }
+
+    #[test]
+    fn bounded_sinks_flush_as_the_socket_becomes_writable() {
+        use futures::{stream, Sink};
+        use std::thread;
+        use std::time::Duration;
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.set_rcvhwm(1).unwrap();
+        pull.bind("inproc://tokio_sink_bounded").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.set_sndhwm(1).unwrap();
+        push.connect("inproc://tokio_sink_bounded").unwrap();
+        let push = TokioSocket::new(push, &handle).unwrap();
+
+        let msgs: Vec<Vec<Vec<u8>>> = (0..32u8).map(|i| vec![vec![i]]).collect();
+        let expected = msgs.clone();
+        let receiver = thread::spawn(move || {
+            (0..expected.len())
+                .map(|_| {
+                    thread::sleep(Duration::from_millis(1));
+                    pull.recv_multipart(0).unwrap()
+                })
+                .collect::<Vec<_>>()
+                == expected
+        });
+        let sent = push
+            .sink_multipart_with_capacity(2)
+            .send_all(stream::iter_ok::<_, io::Error>(msgs));
+        let (sink, _) = core.run(sent).unwrap();
+        assert_eq!(sink.pending(), 0);
+        assert!(receiver.join().unwrap());
+    }
+
+    #[test]
+    fn closing_sinks_drop_pending_items_without_linger() {
+        use futures::{future, AsyncSink, Sink};
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.set_rcvhwm(1).unwrap();
+        pull.bind("inproc://tokio_sink_hwm").unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.set_sndhwm(1).unwrap();
+        push.set_linger(0).unwrap();
+        push.connect("inproc://tokio_sink_hwm").unwrap();
+        let push = TokioSocket::new(push, &handle).unwrap();
+
+        let (flushed, pending, closed, dropped) = core
+            .run(future::lazy(|| {
+                let mut sink = push.sink_multipart();
+                for i in 0..8u8 {
+                    if let AsyncSink::NotReady(_) = sink.start_send(vec![vec![i]])? {
+                        break;
+                    }
+                }
+                let flushed = sink.poll_complete()?.is_ready();
+                let pending = sink.pending();
+                // LINGER is 0, so closing drops what the socket did not take.
+                let closed = sink.close()?.is_ready();
+                Ok::<_, io::Error>((flushed, pending, closed, sink.pending()))
+            }))
+            .unwrap();
+        assert!(!flushed);
+        assert!(pending > 0);
+        assert!(closed);
+        assert_eq!(dropped, 0);
+    }
+
+    #[test]
+    fn closing_sinks_time_out_after_linger() {
+        use futures::{future, Sink};
+
+        let ctx = Context::new();
+        let mut core = Core::new().unwrap();
+        let handle = core.handle();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.set_linger(50).unwrap();
+        push.set_immediate(true).unwrap();
+        // no peer ever answers, so the socket never becomes writable.
+        push.connect("tcp://127.0.0.1:1").unwrap();
+        let push = TokioSocket::new(push, &handle).unwrap();
+
+        let mut sink = push.sink();
+        let started = Instant::now();
+        core.run(future::lazy(|| sink.start_send(Message::from("stuck"))))
+            .unwrap();
+        assert_eq!(sink.pending(), 1);
+        let closed = core.run(future::poll_fn(|| sink.close()));
+        assert_eq!(closed.unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(sink.pending(), 0);
+    }
+
     #[test]
     fn convert_from_zmq_socket_reference_to_tokio_socket() {
         let (socket, core) = setup_socket();
@@ -0,0 +1,206 @@
+//! Cancellation, and deadlines, of futures and streams of tokio-compatible sockets.
+//!
+//! A `Deadline` fails with `io::ErrorKind::TimedOut` once its deadline passes, and a
+//! `Cancellable` fails with a `Cancelled` error once its `CancelHandle` is cancelled, from
+//! any thread, so that futures, and streams, do not wait forever on a peer that hung up.
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use futures::task::{self, Task};
+use futures::{Async, Future, Poll, Stream};
+use tokio_core::reactor::{Handle, Timeout};
+
+/// The error of futures, and streams, that were cancelled by their `CancelHandle`, as the
+/// inner error of an `io::ErrorKind::Interrupted` error.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Returns true if `error` is the error of a cancelled future, or stream.
+pub fn is_cancelled(error: &io::Error) -> bool {
+    error.get_ref().is_some_and(|inner| inner.is::<Cancelled>())
+}
+
+fn cancelled() -> io::Error {
+    io::Error::new(io::ErrorKind::Interrupted, Cancelled)
+}
+
+// State shared by the clones of a handle.
+#[derive(Default)]
+struct Cancellation {
+    cancelled: AtomicBool,
+    // the tasks that wait on futures, or streams, of the handle.
+    tasks: Mutex<Vec<Task>>,
+}
+
+/// Cancels every future, and stream, that it made `Cancellable`.
+#[derive(Clone, Default)]
+pub struct CancelHandle {
+    inner: Arc<Cancellation>,
+}
+
+impl CancelHandle {
+    /// Create a new `CancelHandle`.
+    pub fn new() -> CancelHandle {
+        CancelHandle::default()
+    }
+
+    /// Returns `inner`, which fails with `Cancelled` once the handle is cancelled.
+    pub fn cancellable<F>(&self, inner: F) -> Cancellable<F> {
+        Cancellable {
+            inner,
+            handle: self.clone(),
+        }
+    }
+
+    /// Cancel the futures, and streams, of the handle, waking the tasks that wait on them.
+    pub fn cancel(&self) {
+        self.inner.cancelled.store(true, Ordering::SeqCst);
+        let tasks: Vec<Task> = self.inner.tasks.lock().unwrap().drain(..).collect();
+        for task in tasks {
+            task.notify();
+        }
+    }
+
+    /// Returns true once the handle was cancelled.
+    pub fn is_cancelled(&self) -> bool {
+        self.inner.cancelled.load(Ordering::SeqCst)
+    }
+
+    // Fail if cancelled, or else wake the current task on cancellation.
+    fn check(&self) -> io::Result<()> {
+        if self.is_cancelled() {
+            return Err(cancelled());
+        }
+        let mut tasks = self.inner.tasks.lock().unwrap();
+        if !tasks.iter().any(Task::will_notify_current) {
+            tasks.push(task::current());
+        }
+        drop(tasks);
+        // the handle may have been cancelled before the task was added.
+        if self.is_cancelled() {
+            return Err(cancelled());
+        }
+        Ok(())
+    }
+}
+
+/// A future, or stream, that fails with `Cancelled` once its `CancelHandle` is cancelled.
+pub struct Cancellable<F> {
+    inner: F,
+    handle: CancelHandle,
+}
+
+impl<F> Future for Cancellable<F>
+where
+    F: Future<Error = io::Error>,
+{
+    type Item = F::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        self.handle.check()?;
+        self.inner.poll()
+    }
+}
+
+impl<S> Stream for Cancellable<S>
+where
+    S: Stream<Error = io::Error>,
+{
+    type Item = S::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.handle.check()?;
+        self.inner.poll()
+    }
+}
+
+/// A future, or stream, that fails with `io::ErrorKind::TimedOut` once its deadline passes.
+pub struct Deadline<F> {
+    inner: F,
+    deadline: Instant,
+    handle: Handle,
+    // created on the first poll, so that creating the deadline can not fail.
+    timeout: Option<Timeout>,
+}
+
+impl<F> Deadline<F> {
+    /// Create a new `Deadline` for `inner`, at `deadline`, on the reactor of `handle`.
+    pub fn new(inner: F, deadline: Instant, handle: &Handle) -> Deadline<F> {
+        Deadline {
+            inner,
+            deadline,
+            handle: handle.clone(),
+            timeout: None,
+        }
+    }
+
+    /// Returns the deadline.
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    fn poll_timeout(&mut self) -> io::Result<()> {
+        if self.timeout.is_none() {
+            self.timeout = Some(Timeout::new_at(self.deadline, &self.handle)?);
+        }
+        match self.timeout.as_mut().map(Future::poll) {
+            Some(Ok(Async::Ready(()))) => Err(io::ErrorKind::TimedOut.into()),
+            Some(Err(e)) => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<F> Future for Deadline<F>
+where
+    F: Future<Error = io::Error>,
+{
+    type Item = F::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        match self.inner.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(item)),
+            Async::NotReady => {
+                self.poll_timeout()?;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
+
+impl<S> Stream for Deadline<S>
+where
+    S: Stream<Error = io::Error>,
+{
+    type Item = S::Item;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        // streams that are never idle end at their deadline, too.
+        if Instant::now() >= self.deadline {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        match self.inner.poll()? {
+            Async::Ready(item) => Ok(Async::Ready(item)),
+            Async::NotReady => {
+                self.poll_timeout()?;
+                Ok(Async::NotReady)
+            }
+        }
+    }
+}
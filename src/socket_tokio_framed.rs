@@ -0,0 +1,167 @@
+//! Framed transports for tokio-compatible sockets.
+//!
+//! A `Framed` transport is both a `Stream`, and a `Sink`, of whole multipart messages, so
+//! it can be `split` into halves that keep request, and response, envelopes intact. Items
+//! are turned into frames, and back, by a codec; `MultipartCodec` passes the frames as they
+//! are.
+use super::super::{SocketRecv, SocketSend};
+
+use std::io;
+use std::ops::Deref;
+
+use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use zmq::{self, Message};
+
+/// Turns the frames of a multipart message into an item.
+pub trait Decoder {
+    type Item;
+    type Error: From<io::Error>;
+
+    /// Decode the complete multipart message in `frames`.
+    fn decode(&mut self, frames: Vec<Message>) -> Result<Self::Item, Self::Error>;
+}
+
+/// Turns an item into the frames of a multipart message.
+pub trait Encoder {
+    type Item;
+    type Error: From<io::Error>;
+
+    /// Encode `item` as the frames of a multipart message.
+    fn encode(&mut self, item: Self::Item) -> Result<Vec<Message>, Self::Error>;
+}
+
+/// Codec for multipart messages, as `Vec<zmq::Message>`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MultipartCodec;
+
+impl Decoder for MultipartCodec {
+    type Item = Vec<Message>;
+    type Error = io::Error;
+
+    fn decode(&mut self, frames: Vec<Message>) -> io::Result<Vec<Message>> {
+        Ok(frames)
+    }
+}
+
+impl Encoder for MultipartCodec {
+    type Item = Vec<Message>;
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Vec<Message>) -> io::Result<Vec<Message>> {
+        Ok(item)
+    }
+}
+
+/// `Stream`, and `Sink`, of multipart messages, over a socket.
+///
+/// Frames are received until `RCVMORE` is unset, so every item is a complete multipart
+/// message. Outgoing messages are kept until their last frame is accepted by the socket.
+pub struct Framed<'a, T: 'a, C> {
+    socket: &'a T,
+    codec: C,
+    reading: Vec<Message>,
+    writing: Vec<Message>,
+    written: usize,
+}
+
+impl<'a, T, C> Framed<'a, T, C> {
+    pub fn new(socket: &'a T, codec: C) -> Framed<'a, T, C> {
+        Framed {
+            socket,
+            codec,
+            reading: Vec::new(),
+            writing: Vec::new(),
+            written: 0,
+        }
+    }
+
+    /// Returns a reference to the codec.
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+
+    /// Returns a mutable reference to the codec.
+    pub fn codec_mut(&mut self) -> &mut C {
+        &mut self.codec
+    }
+}
+
+impl<'a, T, C> Framed<'a, T, C>
+where
+    T: SocketSend + 'a,
+{
+    // Send the frames of the pending message, from the first one that was not accepted.
+    fn poll_write(&mut self) -> Poll<(), io::Error> {
+        while self.written < self.writing.len() {
+            let more = self.written + 1 < self.writing.len();
+            let flags = if more { zmq::SNDMORE } else { 0 };
+            let frame = self.writing[self.written].deref();
+            match SocketSend::send(self.socket, frame, flags) {
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(Async::NotReady);
+                    } else {
+                        return Err(e);
+                    }
+                }
+                Ok(_) => self.written += 1,
+            }
+        }
+        self.writing.clear();
+        self.written = 0;
+        Ok(Async::Ready(()))
+    }
+}
+
+impl<'a, T, C> Stream for Framed<'a, T, C>
+where
+    T: SocketRecv + 'a,
+    C: Decoder,
+{
+    type Item = C::Item;
+    type Error = C::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match SocketRecv::recv_msg(self.socket, 0) {
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(Async::NotReady);
+                    } else {
+                        return Err(e.into());
+                    }
+                }
+                Ok(frame) => {
+                    self.reading.push(frame);
+                    if !self.socket.get_rcvmore()? {
+                        let frames = ::std::mem::take(&mut self.reading);
+                        let item = self.codec.decode(frames)?;
+                        return Ok(Async::Ready(Some(item)));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<'a, T, C> Sink for Framed<'a, T, C>
+where
+    T: SocketSend + 'a,
+    C: Encoder,
+{
+    type SinkItem = C::Item;
+    type SinkError = C::Error;
+
+    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
+        if self.poll_write()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.writing = self.codec.encode(item)?;
+        self.poll_write()?;
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        Ok(self.poll_write()?)
+    }
+}
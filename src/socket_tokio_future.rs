@@ -1,11 +1,14 @@
 //! Futures for tokio-compatible sockets.
-use super::super::{SocketRecv, SocketSend};
+use super::super::{MultipartCursor, SocketRecv, SocketSend};
 use super::TokioSocket;
 
 use futures::{Async, Future, Poll};
 use std::io;
 use std::ops::Deref;
-use zmq::Message;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio_core::reactor::{Handle, Timeout};
+use zmq::{self, Message};
 
 /// A Future that sends a `Message`.
 pub struct SendMessage<'a> {
@@ -45,9 +48,13 @@ impl<'a> Future for SendMessage<'a> {
 }
 
 /// A Future that sends a multi-part `Message`.
+///
+/// Frames are kept by a `MultipartCursor`, so that retrying the send when the socket would
+/// block does not clone them, and retries resume from the frame that would block. Failures
+/// after the first frame fail with a `PartialSend` error, see `MultipartCursor`.
 pub struct SendMultipartMessage<'a> {
     socket: &'a TokioSocket,
-    messages: Vec<Vec<u8>>,
+    cursor: MultipartCursor,
     flags: i32,
 }
 
@@ -58,10 +65,22 @@ impl<'a> SendMultipartMessage<'a> {
         I: IntoIterator<Item = M>,
         M: Into<Vec<u8>>,
     {
-        let messages: Vec<Vec<u8>> = iter.into_iter().map(|m| m.into()).collect();
         SendMultipartMessage {
             socket,
-            messages,
+            cursor: MultipartCursor::new(iter),
+            flags,
+        }
+    }
+
+    /// Create a new `SendMultipartMessage` from shared buffers, without cloning them.
+    pub fn from_shared(
+        socket: &'a TokioSocket,
+        messages: Vec<Arc<[u8]>>,
+        flags: i32,
+    ) -> SendMultipartMessage<'a> {
+        SendMultipartMessage {
+            socket,
+            cursor: MultipartCursor::from_shared(messages),
             flags,
         }
     }
@@ -72,7 +91,7 @@ impl<'a> Future for SendMultipartMessage<'a> {
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
-        match SocketSend::send_multipart(self.socket, &self.messages, self.flags) {
+        match self.cursor.send(self.socket, self.flags) {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
                     Ok(Async::NotReady)
@@ -148,3 +167,67 @@ impl<'a> Future for RecvMultipartMessage<'a> {
         }
     }
 }
+
+/// How a socket closed by `TokioSocket::close_with_linger` went.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CloseOutcome {
+    /// Every pending message was sent before the linger elapsed.
+    Flushed,
+    /// The linger elapsed, and the messages that were still pending were dropped.
+    LingerExpired,
+}
+
+/// A Future that resolves once a closed socket has sent its pending messages, or its
+/// linger elapsed.
+///
+/// libzmq stops the monitor of a socket once the socket is destroyed, which waits for the
+/// messages that were queued when it was closed, so the future resolves on the
+/// `ZMQ_EVENT_MONITOR_STOPPED` event of the socket.
+pub struct Closing {
+    monitor: TokioSocket,
+    deadline: Instant,
+    timeout: Timeout,
+}
+
+impl Closing {
+    /// Create a new `Closing` future, for the socket monitored by `monitor`, whose linger
+    /// elapses at `deadline`.
+    pub fn new(monitor: TokioSocket, deadline: Instant, handle: &Handle) -> io::Result<Closing> {
+        let timeout = Timeout::new_at(deadline, handle)?;
+        Ok(Closing {
+            monitor,
+            deadline,
+            timeout,
+        })
+    }
+}
+
+impl Future for Closing {
+    type Item = CloseOutcome;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match SocketRecv::recv_multipart(&self.monitor, zmq::DONTWAIT) {
+                Ok(msg) => {
+                    if msg.is_empty() || msg[0].len() < 2 {
+                        continue;
+                    }
+                    let event = u16::from(msg[0][0]) | (u16::from(msg[0][1]) << 8);
+                    if let zmq::SocketEvent::MONITOR_STOPPED = zmq::SocketEvent::from_raw(event) {
+                        if Instant::now() < self.deadline {
+                            return Ok(Async::Ready(CloseOutcome::Flushed));
+                        }
+                        return Ok(Async::Ready(CloseOutcome::LingerExpired));
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+        match self.timeout.poll()? {
+            Async::Ready(()) => Ok(Async::Ready(CloseOutcome::LingerExpired)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
@@ -1,23 +1,184 @@
 //! Sinks for tokio-compatible sockets.
-use super::super::SocketSend;
+//!
+//! Sinks keep the items that the socket could not take yet, and `poll_complete` is only
+//! ready once the last frame of every item was accepted by the socket. Closing a sink
+//! respects the socket's `LINGER`: pending items are dropped right away when it is 0,
+//! waited for up to `LINGER` milliseconds, with a timer of the reactor of the sink, when
+//! it is positive, and waited for as long as it takes when it is -1.
+//!
+//! Sinks keep up to their capacity of items, and only answer `AsyncSink::NotReady` once
+//! they are full. Kept items are sent, in order, on the next `start_send`, or
+//! `poll_complete`, after the socket becomes writable.
+use super::super::{MultipartCursor, PackedEncoder, SocketSend};
 
+use std::collections::VecDeque;
 use std::io;
-use std::ops::Deref;
+use std::time::{Duration, Instant};
 
-use futures::{Async, AsyncSink, Poll, Sink, StartSend};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend};
+use tokio_core::reactor::{Handle, Timeout};
 use zmq;
 
+/// Default number of items that sinks keep while the socket can't take them.
+pub const SINK_CAPACITY: usize = 16;
+
+// Returns true if ZMQ_EVENTS reports that a message can be sent without blocking.
+fn is_writable(socket: &zmq::Socket) -> io::Result<bool> {
+    Ok(socket.get_events()?.contains(zmq::POLLOUT))
+}
+
+// Items that may be left partially sent, which have to be sent before any other.
+trait SendItem {
+    fn is_partial(&self) -> bool {
+        false
+    }
+}
+
+impl SendItem for zmq::Message {}
+
+impl SendItem for MultipartCursor {
+    fn is_partial(&self) -> bool {
+        MultipartCursor::is_partial(self)
+    }
+}
+
+// Items waiting to be sent, in order.
+struct SendQueue<I> {
+    items: VecDeque<I>,
+    capacity: usize,
+    closing: Option<Instant>,
+    handle: Handle,
+    // wakes the task of `close` once `LINGER` elapsed, if the socket never becomes writable.
+    linger: Option<Timeout>,
+}
+
+impl<I: SendItem> SendQueue<I> {
+    fn new(capacity: usize, handle: &Handle) -> SendQueue<I> {
+        SendQueue {
+            items: VecDeque::new(),
+            capacity: capacity.max(1),
+            closing: None,
+            handle: handle.clone(),
+            linger: None,
+        }
+    }
+
+    fn start_send<F>(
+        &mut self,
+        socket: &zmq::Socket,
+        mut item: I,
+        send: F,
+    ) -> StartSend<I, io::Error>
+    where
+        F: FnMut(&mut I) -> io::Result<()>,
+    {
+        let mut send = send;
+        // items that were kept go first, as soon as the socket takes them.
+        if !self.items.is_empty() && is_writable(socket)? {
+            self.flush(&mut send)?;
+        }
+        if self.items.is_empty() && is_writable(socket)? {
+            match send(&mut item) {
+                Ok(()) => return Ok(AsyncSink::Ready),
+                // the rest of its frames go first.
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && item.is_partial() => {
+                    self.items.push_back(item);
+                    return Ok(AsyncSink::Ready);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if self.items.len() >= self.capacity && self.flush(&mut send)?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.items.push_back(item);
+        Ok(AsyncSink::Ready)
+    }
+
+    fn flush<F>(&mut self, mut send: F) -> Poll<(), io::Error>
+    where
+        F: FnMut(&mut I) -> io::Result<()>,
+    {
+        while let Some(item) = self.items.front_mut() {
+            if let Err(e) = send(item) {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    return Ok(Async::NotReady);
+                }
+                // the item is abandoned, and the next one goes on the next flush.
+                self.items.pop_front();
+                return Err(e);
+            }
+            self.items.pop_front();
+        }
+        Ok(Async::Ready(()))
+    }
+
+    fn close<F>(&mut self, socket: &zmq::Socket, send: F) -> Poll<(), io::Error>
+    where
+        F: FnMut(&mut I) -> io::Result<()>,
+    {
+        if self.flush(send)?.is_ready() {
+            return Ok(Async::Ready(()));
+        }
+        let linger = socket.get_linger()?;
+        if linger < 0 {
+            return Ok(Async::NotReady);
+        }
+        if linger > 0 {
+            let closing = *self.closing.get_or_insert_with(Instant::now);
+            if self.linger.is_none() {
+                let deadline = closing + Duration::from_millis(linger as u64);
+                self.linger = Some(Timeout::new_at(deadline, &self.handle)?);
+            }
+            if let Some(ref mut timeout) = self.linger {
+                if timeout.poll()?.is_not_ready() {
+                    return Ok(Async::NotReady);
+                }
+            }
+        }
+        nlog!(
+            debug,
+            "sink closed with unsent items count={} linger={}",
+            self.items.len(),
+            linger
+        );
+        self.items.clear();
+        if linger == 0 {
+            Ok(Async::Ready(()))
+        } else {
+            Err(io::ErrorKind::TimedOut.into())
+        }
+    }
+}
+
 /// Single-message sink for sockets.
 pub struct MessageSink<'a, T: 'a> {
     socket: &'a T,
+    queue: SendQueue<zmq::Message>,
 }
 
 impl<'a, T> MessageSink<'a, T>
 where
     T: SocketSend + 'a,
 {
-    pub fn new(socket: &'a T) -> MessageSink<'a, T> {
-        MessageSink { socket }
+    /// Create a new `MessageSink`, whose `close` waits for `LINGER` on the reactor of `handle`.
+    pub fn new(socket: &'a T, handle: &Handle) -> MessageSink<'a, T> {
+        MessageSink::with_capacity(socket, SINK_CAPACITY, handle)
+    }
+
+    /// Create a new `MessageSink` that keeps up to `capacity` items while the socket can't
+    /// take them.
+    pub fn with_capacity(socket: &'a T, capacity: usize, handle: &Handle) -> MessageSink<'a, T> {
+        MessageSink {
+            socket,
+            queue: SendQueue::new(capacity, handle),
+        }
+    }
+
+    /// Returns the number of items that were not accepted by the socket yet.
+    pub fn pending(&self) -> usize {
+        self.queue.items.len()
     }
 }
 
@@ -29,34 +190,60 @@ where
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: zmq::Message) -> StartSend<zmq::Message, Self::SinkError> {
-        match SocketSend::send(self.socket, item.deref(), 0) {
-            Err(e) => {
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    Ok(AsyncSink::NotReady(item))
-                } else {
-                    Err(e)
-                }
-            }
-            Ok(_) => Ok(AsyncSink::Ready),
-        }
+        let socket = self.socket;
+        let send = |msg: &mut zmq::Message| SocketSend::send(socket, &msg[..], 0);
+        self.queue.start_send(socket.get_socket_ref(), item, send)
     }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        Ok(Async::Ready(()))
+        let socket = self.socket;
+        self.queue
+            .flush(|msg| SocketSend::send(socket, &msg[..], 0))
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        let socket = self.socket;
+        self.queue.close(socket.get_socket_ref(), |msg| {
+            SocketSend::send(socket, &msg[..], 0)
+        })
     }
 }
 
 /// Multipart-message sink for sockets.
+///
+/// Messages are sent one frame at a time, with a `MultipartCursor`, so that a message
+/// whose frames would block midway is finished before any other. Failures after the first
+/// frame fail with a `PartialSend` error.
 pub struct MessageMultipartSink<'a, T: 'a> {
     socket: &'a T,
+    queue: SendQueue<MultipartCursor>,
 }
 
 impl<'a, T> MessageMultipartSink<'a, T>
 where
     T: SocketSend + 'a,
 {
-    pub fn new(socket: &'a T) -> MessageMultipartSink<'a, T> {
-        MessageMultipartSink { socket }
+    /// Create a new `MessageMultipartSink`, whose `close` waits for `LINGER` on the reactor of `handle`.
+    pub fn new(socket: &'a T, handle: &Handle) -> MessageMultipartSink<'a, T> {
+        MessageMultipartSink::with_capacity(socket, SINK_CAPACITY, handle)
+    }
+
+    /// Create a new `MessageMultipartSink` that keeps up to `capacity` items while the
+    /// socket can't take them.
+    pub fn with_capacity(
+        socket: &'a T,
+        capacity: usize,
+        handle: &Handle,
+    ) -> MessageMultipartSink<'a, T> {
+        MessageMultipartSink {
+            socket,
+            queue: SendQueue::new(capacity, handle),
+        }
+    }
+
+    /// Returns the number of items that were not accepted by the socket yet.
+    pub fn pending(&self) -> usize {
+        self.queue.items.len()
     }
 }
 
@@ -68,19 +255,87 @@ where
     type SinkError = io::Error;
 
     fn start_send(&mut self, item: Vec<Vec<u8>>) -> StartSend<Vec<Vec<u8>>, Self::SinkError> {
-        match SocketSend::send_multipart(self.socket, &item, 0) {
+        let socket = self.socket;
+        let send = |cursor: &mut MultipartCursor| cursor.send(socket, 0);
+        let cursor = MultipartCursor::new(item);
+        match self
+            .queue
+            .start_send(socket.get_socket_ref(), cursor, send)?
+        {
+            AsyncSink::Ready => Ok(AsyncSink::Ready),
+            AsyncSink::NotReady(cursor) => Ok(AsyncSink::NotReady(cursor.into_frames())),
+        }
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
+        let socket = self.socket;
+        self.queue.flush(|cursor| cursor.send(socket, 0))
+    }
+
+    fn close(&mut self) -> Poll<(), Self::SinkError> {
+        let socket = self.socket;
+        self.queue
+            .close(socket.get_socket_ref(), |cursor| cursor.send(socket, 0))
+    }
+}
+
+/// Sink that packs messages into frames of up to a maximum size, to be received with
+/// `SocketRecv::recv_packed`, or a `PackedStream`.
+///
+/// Frames are sent when they are full, and when the sink is flushed with `poll_complete`.
+pub struct PackedSink<'a, T: 'a> {
+    socket: &'a T,
+    encoder: PackedEncoder,
+}
+
+impl<'a, T> PackedSink<'a, T>
+where
+    T: SocketSend + 'a,
+{
+    pub fn new(socket: &'a T, max_size: usize) -> PackedSink<'a, T> {
+        PackedSink {
+            socket,
+            encoder: PackedEncoder::new(max_size),
+        }
+    }
+
+    // Send the packed frame, keeping it in the encoder if the socket would block.
+    fn flush(&mut self) -> Poll<(), io::Error> {
+        if self.encoder.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+        match SocketSend::send(self.socket, self.encoder.frame(), 0) {
             Err(e) => {
                 if e.kind() == io::ErrorKind::WouldBlock {
-                    Ok(AsyncSink::NotReady(item))
+                    Ok(Async::NotReady)
                 } else {
                     Err(e)
                 }
             }
-            Ok(_) => Ok(AsyncSink::Ready),
+            Ok(_) => {
+                self.encoder.clear();
+                Ok(Async::Ready(()))
+            }
         }
     }
+}
+
+impl<'a, T> Sink for PackedSink<'a, T>
+where
+    T: SocketSend + 'a,
+{
+    type SinkItem = Vec<u8>;
+    type SinkError = io::Error;
+
+    fn start_send(&mut self, item: Vec<u8>) -> StartSend<Vec<u8>, Self::SinkError> {
+        if self.encoder.is_full_for(&item) && self.flush()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(item));
+        }
+        self.encoder.push(&item)?;
+        Ok(AsyncSink::Ready)
+    }
 
     fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        Ok(Async::Ready(()))
+        self.flush()
     }
 }
@@ -1,12 +1,17 @@
 //! Streams for tokio-compatible sockets.
-use super::super::SocketRecv;
+use super::super::{SocketRecv, Subscription};
+use super::framed::{Framed, MultipartCodec};
 
+use std::collections::VecDeque;
 use std::io;
 
 use futures::{Async, Poll, Stream};
 use zmq;
 
 /// Single-message stream for sockets.
+///
+/// Every item is a single frame, so the frames of multipart messages are yielded one by
+/// one. Use a `LogicalMessageStream` to receive whole messages.
 pub struct MessageStream<'a, T: 'a> {
     socket: &'a T,
 }
@@ -42,6 +47,38 @@ where
     }
 }
 
+/// Stream of logical messages, which aggregates frames until `RCVMORE` is unset.
+///
+/// Frames that were received before the socket would block are kept for the next poll, so
+/// the frames of a multipart message are never split across items. It is the receiving
+/// half of a `Framed` transport, with a `MultipartCodec`.
+pub struct LogicalMessageStream<'a, T: 'a> {
+    framed: Framed<'a, T, MultipartCodec>,
+}
+
+impl<'a, T> LogicalMessageStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    pub fn new(socket: &'a T) -> LogicalMessageStream<'a, T> {
+        LogicalMessageStream {
+            framed: Framed::new(socket, MultipartCodec),
+        }
+    }
+}
+
+impl<'a, T> Stream for LogicalMessageStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    type Item = Vec<zmq::Message>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.framed.poll()
+    }
+}
+
 /// Multipart-message stream for sockets.
 pub struct MessageMultipartStream<'a, T: 'a> {
     socket: &'a T,
@@ -79,3 +116,133 @@ where
         }
     }
 }
+
+/// Stream of batches of multipart-messages, draining the socket on every wakeup.
+pub struct MessageBatchStream<'a, T: 'a> {
+    socket: &'a T,
+    max_messages: usize,
+}
+
+impl<'a, T> MessageBatchStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    pub fn new(socket: &'a T, max_messages: usize) -> MessageBatchStream<'a, T> {
+        MessageBatchStream {
+            socket,
+            max_messages,
+        }
+    }
+}
+
+impl<'a, T> Stream for MessageBatchStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    type Item = Vec<Vec<zmq::Message>>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match SocketRecv::recv_batch(self.socket, self.max_messages, 0) {
+            Err(e) => {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    Ok(Async::NotReady)
+                } else {
+                    Err(e)
+                }
+            }
+            Ok(batch) => {
+                let msgs = batch
+                    .iter()
+                    .map(|vecs| vecs.iter().map(|v| v.into()).collect())
+                    .collect();
+                Ok(Async::Ready(Some(msgs)))
+            }
+        }
+    }
+}
+
+/// Stream of the messages unpacked from frames sent with `SocketSend::send_packed`.
+pub struct PackedStream<'a, T: 'a> {
+    socket: &'a T,
+    unpacked: VecDeque<Vec<u8>>,
+}
+
+impl<'a, T> PackedStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    pub fn new(socket: &'a T) -> PackedStream<'a, T> {
+        PackedStream {
+            socket,
+            unpacked: VecDeque::new(),
+        }
+    }
+}
+
+impl<'a, T> Stream for PackedStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    type Item = Vec<u8>;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        while self.unpacked.is_empty() {
+            match SocketRecv::recv_packed(self.socket, 0) {
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(Async::NotReady);
+                    } else {
+                        return Err(e);
+                    }
+                }
+                Ok(msgs) => self.unpacked.extend(msgs),
+            }
+        }
+        Ok(Async::Ready(self.unpacked.pop_front()))
+    }
+}
+
+/// Stream of the subscriptions, and unsubscriptions, received by `XPUB` sockets, see
+/// `XPubSocket`. Messages that are neither are dropped.
+pub struct SubscriptionStream<'a, T: 'a> {
+    socket: &'a T,
+}
+
+impl<'a, T> SubscriptionStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    pub fn new(socket: &'a T) -> SubscriptionStream<'a, T> {
+        SubscriptionStream { socket }
+    }
+}
+
+impl<'a, T> Stream for SubscriptionStream<'a, T>
+where
+    T: SocketRecv + 'a,
+{
+    type Item = Subscription;
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        loop {
+            match SocketRecv::recv_multipart(self.socket, 0) {
+                Err(e) => {
+                    if e.kind() == io::ErrorKind::WouldBlock {
+                        return Ok(Async::NotReady);
+                    } else {
+                        return Err(e);
+                    }
+                }
+                Ok(msg) => {
+                    let parsed = msg.first().and_then(|frame| Subscription::parse(frame));
+                    if let Some(subscription) = parsed {
+                        return Ok(Async::Ready(Some(subscription)));
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Socket types of the crate.
+//!
+//! They mirror the ones of the `zmq` crate, and convert to, and from, them, so that
+//! downstream crates can use the crate without depending on a matching version of `zmq`.
+//! Every API that takes a `zmq::SocketType` also takes a `SocketType`. Send flags, and poll
+//! events, are taken as they are by `zmq`, which is re-exported as `neuras::zmq`.
+use super::SocketError;
+
+use std::fmt;
+use std::str::FromStr;
+use zmq;
+
+/// Types of sockets.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Hash, Serialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum SocketType {
+    Pair,
+    Pub,
+    Sub,
+    Req,
+    Rep,
+    Dealer,
+    Router,
+    Pull,
+    Push,
+    XPub,
+    XSub,
+    Stream,
+}
+
+impl SocketType {
+    /// Returns the name of the socket type, e.g. `XPUB`.
+    pub fn name(self) -> &'static str {
+        match self {
+            SocketType::Pair => "PAIR",
+            SocketType::Pub => "PUB",
+            SocketType::Sub => "SUB",
+            SocketType::Req => "REQ",
+            SocketType::Rep => "REP",
+            SocketType::Dealer => "DEALER",
+            SocketType::Router => "ROUTER",
+            SocketType::Pull => "PULL",
+            SocketType::Push => "PUSH",
+            SocketType::XPub => "XPUB",
+            SocketType::XSub => "XSUB",
+            SocketType::Stream => "STREAM",
+        }
+    }
+}
+
+impl fmt::Display for SocketType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl FromStr for SocketType {
+    type Err = SocketError;
+
+    /// Parse the name of a socket type, in any case.
+    fn from_str(name: &str) -> Result<SocketType, SocketError> {
+        Ok(match name.to_uppercase().as_str() {
+            "PAIR" => SocketType::Pair,
+            "PUB" => SocketType::Pub,
+            "SUB" => SocketType::Sub,
+            "REQ" => SocketType::Req,
+            "REP" => SocketType::Rep,
+            "DEALER" => SocketType::Dealer,
+            "ROUTER" => SocketType::Router,
+            "PULL" => SocketType::Pull,
+            "PUSH" => SocketType::Push,
+            "XPUB" => SocketType::XPub,
+            "XSUB" => SocketType::XSub,
+            "STREAM" => SocketType::Stream,
+            _ => return Err(SocketError::UnknownSocketType(name.to_string())),
+        })
+    }
+}
+
+impl From<SocketType> for zmq::SocketType {
+    fn from(socket_type: SocketType) -> zmq::SocketType {
+        match socket_type {
+            SocketType::Pair => zmq::PAIR,
+            SocketType::Pub => zmq::PUB,
+            SocketType::Sub => zmq::SUB,
+            SocketType::Req => zmq::REQ,
+            SocketType::Rep => zmq::REP,
+            SocketType::Dealer => zmq::DEALER,
+            SocketType::Router => zmq::ROUTER,
+            SocketType::Pull => zmq::PULL,
+            SocketType::Push => zmq::PUSH,
+            SocketType::XPub => zmq::XPUB,
+            SocketType::XSub => zmq::XSUB,
+            SocketType::Stream => zmq::STREAM,
+        }
+    }
+}
+
+impl From<zmq::SocketType> for SocketType {
+    fn from(socket_type: zmq::SocketType) -> SocketType {
+        match socket_type {
+            zmq::PAIR => SocketType::Pair,
+            zmq::PUB => SocketType::Pub,
+            zmq::SUB => SocketType::Sub,
+            zmq::REQ => SocketType::Req,
+            zmq::REP => SocketType::Rep,
+            zmq::DEALER => SocketType::Dealer,
+            zmq::ROUTER => SocketType::Router,
+            zmq::PULL => SocketType::Pull,
+            zmq::PUSH => SocketType::Push,
+            zmq::XPUB => SocketType::XPub,
+            zmq::XSUB => SocketType::XSub,
+            zmq::STREAM => SocketType::Stream,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crate_types_convert_to_and_from_zmq() {
+        let ctx = zmq::Context::new();
+        for name in &["pair", "XPUB", "Router", "stream"] {
+            let socket_type: SocketType = name.parse().unwrap();
+            let socket = ctx.socket(socket_type.into()).unwrap();
+            let kind = SocketType::from(socket.get_socket_type().unwrap());
+            assert_eq!(kind, socket_type);
+            assert_eq!(kind.to_string(), name.to_uppercase());
+        }
+        match "QUEUE".parse::<SocketType>() {
+            Err(SocketError::UnknownSocketType(name)) => assert_eq!(name, "QUEUE"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
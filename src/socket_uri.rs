@@ -0,0 +1,233 @@
+//! Endpoints with inline socket options.
+//!
+//! A `SocketUri` is an endpoint followed by a query of socket options, like the endpoints
+//! of CLI tools, and config files, e.g. `tcp://0.0.0.0:5555?sndhwm=1000&linger=0`. Options
+//! are set when the socket is built, see `SocketBuilder::bind_uri`, and
+//! `SocketBuilder::connect_uri`.
+//!
+//! | option              | value                         |
+//! |---------------------|-------------------------------|
+//! | `sndhwm`, `rcvhwm`  | messages                      |
+//! | `sndbuf`, `rcvbuf`  | bytes                         |
+//! | `linger`            | milliseconds                  |
+//! | `identity`          | text                          |
+//! | `rate`              | kilobits per second           |
+//! | `recovery_ivl`      | milliseconds                  |
+//! | `multicast_hops`    | hops                          |
+//! | `reconnect_ivl`     | milliseconds                  |
+//! | `reconnect_ivl_max` | milliseconds                  |
+//! | `curve`             | `server`, or `client`         |
+//!
+//! CURVE keys can't be given inline, so `curve` only requires the socket to be secured,
+//! with `SocketBuilder::curve_server`, or `SocketBuilder::curve_client`, and building it
+//! fails otherwise.
+//!
+//! ```
+//! use neuras::socket::{SocketUri, UriOption};
+//!
+//! let uri: SocketUri = "tcp://127.0.0.1:5555?sndhwm=1000&linger=0".parse().unwrap();
+//! assert_eq!(uri.endpoint.to_string(), "tcp://127.0.0.1:5555");
+//! assert_eq!(uri.options, vec![UriOption::SndHwm(1000), UriOption::Linger(0)]);
+//! assert_eq!(uri.to_string(), "tcp://127.0.0.1:5555?sndhwm=1000&linger=0");
+//! ```
+use super::super::endpoint::Endpoint;
+use super::SocketError;
+
+use std::fmt;
+use std::str::FromStr;
+use zmq;
+
+/// Role of a socket in the CURVE handshake.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CurveRole {
+    Server,
+    Client,
+}
+
+/// A socket option of a `SocketUri`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum UriOption {
+    SndHwm(i32),
+    RcvHwm(i32),
+    SndBuf(i32),
+    RcvBuf(i32),
+    Linger(i32),
+    Identity(Vec<u8>),
+    Rate(i32),
+    RecoveryIvl(i32),
+    MulticastHops(i32),
+    ReconnectIvl(i32),
+    ReconnectIvlMax(i32),
+    Curve(CurveRole),
+}
+
+impl UriOption {
+    /// Parse the option with `name`, and its `value`.
+    pub fn parse(name: &str, value: &str) -> Result<UriOption, SocketError> {
+        let invalid = || SocketError::InvalidOption(format!("{}={}", name, value));
+        let number = || value.parse::<i32>().map_err(|_| invalid());
+        let option = match name {
+            "sndhwm" => UriOption::SndHwm(number()?),
+            "rcvhwm" => UriOption::RcvHwm(number()?),
+            "sndbuf" => UriOption::SndBuf(number()?),
+            "rcvbuf" => UriOption::RcvBuf(number()?),
+            "linger" => UriOption::Linger(number()?),
+            "identity" => UriOption::Identity(value.as_bytes().to_vec()),
+            "rate" => UriOption::Rate(number()?),
+            "recovery_ivl" => UriOption::RecoveryIvl(number()?),
+            "multicast_hops" => UriOption::MulticastHops(number()?),
+            "reconnect_ivl" => UriOption::ReconnectIvl(number()?),
+            "reconnect_ivl_max" => UriOption::ReconnectIvlMax(number()?),
+            "curve" => match value {
+                "server" => UriOption::Curve(CurveRole::Server),
+                "client" => UriOption::Curve(CurveRole::Client),
+                _ => return Err(invalid()),
+            },
+            _ => return Err(invalid()),
+        };
+        Ok(option)
+    }
+
+    /// Set the option on `socket`. `curve` is not set, as it has no keys.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        match *self {
+            UriOption::SndHwm(hwm) => socket.set_sndhwm(hwm),
+            UriOption::RcvHwm(hwm) => socket.set_rcvhwm(hwm),
+            UriOption::SndBuf(size) => socket.set_sndbuf(size),
+            UriOption::RcvBuf(size) => socket.set_rcvbuf(size),
+            UriOption::Linger(linger) => socket.set_linger(linger),
+            UriOption::Identity(ref identity) => socket.set_identity(identity),
+            UriOption::Rate(rate) => socket.set_rate(rate),
+            UriOption::RecoveryIvl(ivl) => socket.set_recovery_ivl(ivl),
+            UriOption::MulticastHops(hops) => socket.set_multicast_hops(hops),
+            UriOption::ReconnectIvl(ivl) => socket.set_reconnect_ivl(ivl),
+            UriOption::ReconnectIvlMax(ivl) => socket.set_reconnect_ivl_max(ivl),
+            UriOption::Curve(_) => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for UriOption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            UriOption::SndHwm(hwm) => write!(f, "sndhwm={}", hwm),
+            UriOption::RcvHwm(hwm) => write!(f, "rcvhwm={}", hwm),
+            UriOption::SndBuf(size) => write!(f, "sndbuf={}", size),
+            UriOption::RcvBuf(size) => write!(f, "rcvbuf={}", size),
+            UriOption::Linger(linger) => write!(f, "linger={}", linger),
+            UriOption::Identity(ref identity) => {
+                write!(f, "identity={}", String::from_utf8_lossy(identity))
+            }
+            UriOption::Rate(rate) => write!(f, "rate={}", rate),
+            UriOption::RecoveryIvl(ivl) => write!(f, "recovery_ivl={}", ivl),
+            UriOption::MulticastHops(hops) => write!(f, "multicast_hops={}", hops),
+            UriOption::ReconnectIvl(ivl) => write!(f, "reconnect_ivl={}", ivl),
+            UriOption::ReconnectIvlMax(ivl) => write!(f, "reconnect_ivl_max={}", ivl),
+            UriOption::Curve(CurveRole::Server) => write!(f, "curve=server"),
+            UriOption::Curve(CurveRole::Client) => write!(f, "curve=client"),
+        }
+    }
+}
+
+/// An endpoint, with the options of the socket that binds, or connects, to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SocketUri {
+    pub endpoint: Endpoint,
+    pub options: Vec<UriOption>,
+}
+
+impl SocketUri {
+    /// Parse an endpoint, followed by an optional `?` and `&`-separated `name=value`
+    /// options.
+    pub fn parse(uri: &str) -> Result<SocketUri, SocketError> {
+        let (endpoint, query) = match uri.find('?') {
+            Some(at) => (&uri[..at], &uri[at + 1..]),
+            None => (uri, ""),
+        };
+        let mut options = Vec::new();
+        for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+            let mut parts = pair.splitn(2, '=');
+            let name = parts.next().unwrap_or_default();
+            match parts.next() {
+                Some(value) => options.push(UriOption::parse(name, value)?),
+                None => return Err(SocketError::InvalidOption(pair.to_string())),
+            }
+        }
+        Ok(SocketUri {
+            endpoint: Endpoint::parse(endpoint)?,
+            options,
+        })
+    }
+
+    /// Returns the role that the socket must have in the CURVE handshake, if any.
+    pub fn curve(&self) -> Option<CurveRole> {
+        self.options.iter().rev().find_map(|option| match *option {
+            UriOption::Curve(role) => Some(role),
+            _ => None,
+        })
+    }
+
+    /// Set the options on `socket`, in order.
+    pub fn apply(&self, socket: &zmq::Socket) -> Result<(), zmq::Error> {
+        for option in &self.options {
+            option.apply(socket)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for SocketUri {
+    type Err = SocketError;
+
+    fn from_str(s: &str) -> Result<SocketUri, SocketError> {
+        SocketUri::parse(s)
+    }
+}
+
+impl fmt::Display for SocketUri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.endpoint)?;
+        for (i, option) in self.options.iter().enumerate() {
+            let separator = if i == 0 { '?' } else { '&' };
+            write!(f, "{}{}", separator, option)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uris_are_parsed_into_endpoints_and_options() {
+        let uri = SocketUri::parse("tcp://0.0.0.0:5555?sndhwm=1000&linger=0&curve=server").unwrap();
+        assert_eq!(uri.endpoint, Endpoint::parse("tcp://0.0.0.0:5555").unwrap());
+        assert_eq!(
+            uri.options,
+            vec![
+                UriOption::SndHwm(1000),
+                UriOption::Linger(0),
+                UriOption::Curve(CurveRole::Server),
+            ]
+        );
+        assert_eq!(uri.curve(), Some(CurveRole::Server));
+        assert_eq!(SocketUri::parse(&uri.to_string()).unwrap(), uri);
+        assert!(SocketUri::parse("inproc://plain")
+            .unwrap()
+            .options
+            .is_empty());
+
+        for invalid in &[
+            "tcp://127.0.0.1:5555?sndhwm=lots",
+            "tcp://127.0.0.1:5555?color=blue",
+            "tcp://127.0.0.1:5555?linger",
+            "tcp://127.0.0.1:5555?curve=both",
+        ] {
+            match SocketUri::parse(invalid) {
+                Err(SocketError::InvalidOption(_)) => {}
+                other => panic!("{} was not invalid: {:?}", invalid, other),
+            }
+        }
+    }
+}
@@ -0,0 +1,268 @@
+//! Subscription notifications of `XPUB` sockets.
+//!
+//! An `XPUB` socket receives a message for every subscription, and unsubscription, of
+//! its subscribers: a byte, `1` to subscribe, or `0` to unsubscribe, followed by the topic.
+//! `XPubSocket` turns them into `Subscription` events, and keeps the topics that are
+//! subscribed to, so that a service can start producing the messages of a topic once
+//! someone subscribes to it, and stop once the last subscriber is gone.
+//!
+//! `TokioSocket::stream_subscriptions` yields the same events from the tokio stream layer.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::{Subscription, XPubSocket};
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let xpub = ctx.socket(zmq::XPUB).unwrap();
+//! xpub.bind("inproc://xpub").unwrap();
+//! let xpub = XPubSocket::new(xpub);
+//! let sub = ctx.socket(zmq::SUB).unwrap();
+//! sub.connect("inproc://xpub").unwrap();
+//! sub.set_subscribe(b"weather").unwrap();
+//!
+//! assert_eq!(
+//!     xpub.recv_subscription(0).unwrap(),
+//!     Subscription::Subscribed { topic: b"weather".to_vec() }
+//! );
+//! assert!(xpub.has_subscribers(b"weather.paris"));
+//! assert!(!xpub.has_subscribers(b"traffic"));
+//! # }
+//! ```
+use super::{SocketRecv, SocketSend, SocketWrapper};
+
+use std::cell::RefCell;
+use std::collections::BTreeSet;
+use std::io;
+use std::sync::Arc;
+
+use zmq::{self, Message};
+
+/// A subscription, or unsubscription, received by an `XPUB` socket.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Subscription {
+    /// A subscriber subscribed to `topic`.
+    Subscribed { topic: Vec<u8> },
+    /// A subscriber unsubscribed from `topic`.
+    Unsubscribed { topic: Vec<u8> },
+}
+
+impl Subscription {
+    /// Parse the message of a subscription, returning `None` if it is not one, e.g. a
+    /// message sent by an `XSUB` socket.
+    pub fn parse(msg: &[u8]) -> Option<Subscription> {
+        let (kind, topic) = msg.split_first()?;
+        let topic = topic.to_vec();
+        match *kind {
+            1 => Some(Subscription::Subscribed { topic }),
+            0 => Some(Subscription::Unsubscribed { topic }),
+            _ => None,
+        }
+    }
+
+    /// Returns the topic of the subscription.
+    pub fn topic(&self) -> &[u8] {
+        match *self {
+            Subscription::Subscribed { ref topic } | Subscription::Unsubscribed { ref topic } => {
+                topic
+            }
+        }
+    }
+}
+
+/// Callback for each subscription, and unsubscription, of an `XPubSocket`.
+pub type SubscriptionCallback = Arc<dyn Fn(&Subscription) + Send + Sync>;
+
+/// Wrapper for `XPUB` sockets, that keeps the topics its subscribers subscribed to.
+///
+/// libzmq only passes on the first subscription to a topic, and the last unsubscription
+/// from it, unless `ZMQ_XPUB_VERBOSE` is set, so the topics are those that have at least
+/// one subscriber. Verbose sockets pass on every subscription, but still only the last
+/// unsubscription, so the topics are kept right either way.
+pub struct XPubSocket<S = zmq::Socket> {
+    socket: S,
+    topics: RefCell<BTreeSet<Vec<u8>>>,
+    callbacks: Vec<SubscriptionCallback>,
+}
+
+impl<S: SocketRecv> XPubSocket<S> {
+    /// Wrap `socket`, which should be an `XPUB` socket, with no topics.
+    pub fn new(socket: S) -> XPubSocket<S> {
+        XPubSocket {
+            socket,
+            topics: RefCell::new(BTreeSet::new()),
+            callbacks: Vec::new(),
+        }
+    }
+
+    /// Call `callback` for every subscription, and unsubscription, that is received.
+    pub fn on_subscription(mut self, callback: SubscriptionCallback) -> Self {
+        self.callbacks.push(callback);
+        self
+    }
+
+    /// Receive the next subscription, or unsubscription. Messages that are neither are
+    /// dropped.
+    pub fn recv_subscription(&self, flags: i32) -> io::Result<Subscription> {
+        loop {
+            let msg = self.socket.recv_bytes(flags)?;
+            while self.socket.get_rcvmore()? {
+                self.socket.recv_bytes(flags)?;
+            }
+            match Subscription::parse(&msg) {
+                Some(subscription) => {
+                    self.track(&subscription);
+                    return Ok(subscription);
+                }
+                None => nlog!(debug, "dropped message that is not a subscription"),
+            }
+        }
+    }
+
+    /// Receive every subscription, and unsubscription, that is queued, without blocking.
+    pub fn process_subscriptions(&self) -> io::Result<Vec<Subscription>> {
+        let mut subscriptions = Vec::new();
+        loop {
+            match self.recv_subscription(zmq::DONTWAIT) {
+                Ok(subscription) => subscriptions.push(subscription),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(subscriptions),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Returns true if messages of `topic` reach any subscriber, i.e. if a subscribed topic
+    /// is a prefix of it.
+    pub fn has_subscribers(&self, topic: &[u8]) -> bool {
+        self.topics
+            .borrow()
+            .iter()
+            .any(|subscribed| topic.starts_with(subscribed))
+    }
+
+    /// Returns the topics that are subscribed to, in order.
+    pub fn topics(&self) -> Vec<Vec<u8>> {
+        self.topics.borrow().iter().cloned().collect()
+    }
+
+    /// Returns a reference to the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Returns the wrapped socket.
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    fn track(&self, subscription: &Subscription) {
+        match *subscription {
+            Subscription::Subscribed { ref topic } => {
+                self.topics.borrow_mut().insert(topic.clone());
+            }
+            Subscription::Unsubscribed { ref topic } => {
+                self.topics.borrow_mut().remove(topic);
+            }
+        }
+        for callback in &self.callbacks {
+            callback(subscription);
+        }
+    }
+}
+
+impl<S: SocketWrapper> SocketWrapper for XPubSocket<S> {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        self.socket.get_socket_ref()
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.socket.get_rcvmore()
+    }
+}
+
+impl<S: SocketSend> SocketSend for XPubSocket<S> {
+    fn send<T>(&self, msg: T, flags: i32) -> io::Result<()>
+    where
+        T: Into<Message>,
+    {
+        self.socket.send(msg, flags)
+    }
+
+    fn send_multipart<I, T>(&self, msg: I, flags: i32) -> io::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Message>,
+    {
+        self.socket.send_multipart(msg, flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use testkit::inproc_endpoint;
+
+    #[test]
+    fn subscriptions_are_parsed_from_their_first_byte() {
+        assert_eq!(
+            Subscription::parse(b"\x01news"),
+            Some(Subscription::Subscribed {
+                topic: b"news".to_vec()
+            })
+        );
+        assert_eq!(Subscription::parse(b"\x00").unwrap().topic(), &b""[..]);
+        assert_eq!(Subscription::parse(b"\x02news"), None);
+        assert_eq!(Subscription::parse(b""), None);
+    }
+
+    #[test]
+    fn xpub_sockets_keep_the_topics_that_have_subscribers() {
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("xpub");
+        let xpub = ctx.socket(zmq::XPUB).unwrap();
+        xpub.bind(&endpoint).unwrap();
+        let seen = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&seen);
+        let callback: SubscriptionCallback = Arc::new(move |_: &Subscription| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+        let xpub = XPubSocket::new(xpub).on_subscription(callback);
+
+        let first = ctx.socket(zmq::SUB).unwrap();
+        first.connect(&endpoint).unwrap();
+        first.set_subscribe(b"a").unwrap();
+        let second = ctx.socket(zmq::SUB).unwrap();
+        second.connect(&endpoint).unwrap();
+        second.set_subscribe(b"a").unwrap();
+        second.set_subscribe(b"b").unwrap();
+        xpub.recv_subscription(0).unwrap();
+        xpub.recv_subscription(0).unwrap();
+        assert_eq!(xpub.topics(), vec![b"a".to_vec(), b"b".to_vec()]);
+        assert!(xpub.has_subscribers(b"apples"));
+
+        // the topic keeps a subscriber until the second one is gone.
+        first.set_unsubscribe(b"a").unwrap();
+        second.set_unsubscribe(b"b").unwrap();
+        assert_eq!(
+            xpub.recv_subscription(0).unwrap(),
+            Subscription::Unsubscribed {
+                topic: b"b".to_vec()
+            }
+        );
+        assert!(xpub.process_subscriptions().unwrap().is_empty());
+        drop(second);
+        xpub.get_ref().set_rcvtimeo(2_000).unwrap();
+        assert_eq!(
+            xpub.recv_subscription(0).unwrap(),
+            Subscription::Unsubscribed {
+                topic: b"a".to_vec()
+            }
+        );
+        assert!(!xpub.has_subscribers(b"apples"));
+        xpub.send_multipart(vec!["apples", "none"], 0).unwrap();
+        assert_eq!(seen.load(Ordering::SeqCst), 4);
+    }
+}
@@ -0,0 +1,121 @@
+//! Messages from shared buffers, and from many segments.
+//!
+//! `zmq::Message` can take ownership of a `Vec<u8>` or a `Box<[u8]>` without copying, see
+//! `IntoFrame`. Shared buffers, such as `Arc<[u8]>`, are kept by their owners, and sent many
+//! times, over many sockets. The zmq crate has no constructor for messages that borrow a
+//! buffer, so `shared_message` copies the content of the shared buffer into the message,
+//! which libzmq allocates, without cloning the buffer itself.
+//!
+//! `coalesced_message` builds a single frame out of many borrowed segments, e.g. a header
+//! and a payload, copying each into the frame once, instead of concatenating them into a
+//! `Vec<u8>` first.
+use std::io::IoSlice;
+use std::sync::Arc;
+
+use zmq::Message;
+
+/// Create a `zmq::Message` with the content of the shared buffer, which is copied into the
+/// message, leaving the buffer to its owners.
+pub fn shared_message(buf: &Arc<[u8]>) -> Message {
+    Message::from(&buf[..])
+}
+
+/// Create a `zmq::Message` of the total size of `segments`, with their content, in order.
+pub fn coalesced_message(segments: &[IoSlice]) -> Message {
+    let size = segments.iter().map(|segment| segment.len()).sum();
+    let mut msg = Message::with_size(size);
+    let mut offset = 0;
+    for segment in segments {
+        msg[offset..offset + segment.len()].copy_from_slice(segment);
+        offset += segment.len();
+    }
+    msg
+}
+
+/// Buffers that can be turned into frames.
+///
+/// Owned buffers are moved into the frame without copying; shared buffers are copied, see
+/// `shared_message`.
+pub trait IntoFrame {
+    /// Convert into a `zmq::Message`.
+    fn into_frame(self) -> Message;
+}
+
+impl IntoFrame for Arc<[u8]> {
+    fn into_frame(self) -> Message {
+        shared_message(&self)
+    }
+}
+
+impl IntoFrame for &Arc<[u8]> {
+    fn into_frame(self) -> Message {
+        shared_message(self)
+    }
+}
+
+impl IntoFrame for Vec<u8> {
+    fn into_frame(self) -> Message {
+        self.into()
+    }
+}
+
+impl IntoFrame for Box<[u8]> {
+    fn into_frame(self) -> Message {
+        self.into()
+    }
+}
+
+impl IntoFrame for Message {
+    fn into_frame(self) -> Message {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zmq;
+
+    #[test]
+    fn shared_messages_leave_the_buffer_to_its_owners() {
+        let buf: Arc<[u8]> = Arc::from(&b"shared"[..]);
+        let msg = shared_message(&buf);
+        assert_eq!(&*msg, &*buf);
+        assert_eq!(Arc::strong_count(&buf), 1);
+        let owned = vec![1u8; 64];
+        let ptr = owned.as_ptr();
+        assert_eq!(owned.into_frame().as_ptr(), ptr);
+    }
+
+    #[test]
+    fn coalesced_messages_hold_every_segment() {
+        let segments = [
+            IoSlice::new(b"header:"),
+            IoSlice::new(b""),
+            IoSlice::new(b"payload"),
+        ];
+        assert_eq!(&*coalesced_message(&segments), b"header:payload");
+        assert!(coalesced_message(&[]).is_empty());
+    }
+
+    #[test]
+    fn shared_messages_with_empty_buffers_are_empty() {
+        let buf: Arc<[u8]> = Arc::from(&b""[..]);
+        assert!(shared_message(&buf).is_empty());
+    }
+
+    #[test]
+    fn shared_messages_are_sent_and_released() {
+        let ctx = zmq::Context::new();
+        let sender = ctx.socket(zmq::PAIR).unwrap();
+        sender.bind("inproc://zerocopy").unwrap();
+        let receiver = ctx.socket(zmq::PAIR).unwrap();
+        receiver.connect("inproc://zerocopy").unwrap();
+
+        let buf: Arc<[u8]> = Arc::from(vec![7u8; 4096]);
+        sender.send(buf.clone().into_frame(), 0).unwrap();
+        let received = receiver.recv_bytes(0).unwrap();
+        assert_eq!(&received[..], &buf[..]);
+        assert_eq!(Arc::strong_count(&buf), 1);
+    }
+}
@@ -0,0 +1,373 @@
+//! Utilities to test actors, and sockets, without sleeping.
+//!
+//! Enabled by the `testkit` feature, for the tests of downstream crates:
+//!
+//! * `inproc_endpoint`, and `bind_ephemeral`, return endpoints that no other test uses, so
+//!   that tests can run in parallel.
+//! * `FakeClock` has the API of `Clock`, but its time only moves when it is advanced, or
+//!   when it is asked to sleep. It is also a `TimeSource`, for the `Clock` of the code
+//!   under test.
+//! * `expect_message`, and `expect_silence`, wait on a socket, for up to a timeout, and
+//!   panic with the frames that were received, unless they match.
+//! * `wait_until` retries a condition, e.g. to wait for slow joiners, instead of sleeping
+//!   for a fixed time.
+//! * `ShutdownGuard` runs cleanups, and joins threads, when the test ends, even when it
+//!   panics.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! # #[cfg(feature = "testkit")]
+//! use neuras::testkit::{expect_message, inproc_endpoint, MessageMatcher};
+//!
+//! # #[cfg(not(feature = "testkit"))]
+//! # fn main() {}
+//! # #[cfg(feature = "testkit")]
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let endpoint = inproc_endpoint("doc");
+//! let pull = ctx.socket(zmq::PULL).unwrap();
+//! pull.bind(&endpoint).unwrap();
+//! let push = ctx.socket(zmq::PUSH).unwrap();
+//! push.connect(&endpoint).unwrap();
+//! push.send_multipart(["job", "1"], 0).unwrap();
+//! expect_message(&pull, &MessageMatcher::prefix(&["job"]), 1_000);
+//! # }
+//! ```
+use super::clock::{Clock, ClockError, TimeSource};
+
+use std::fmt;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+use zmq;
+
+/// Returns an `inproc://` endpoint, starting with `prefix`, that is unique to the process.
+pub fn inproc_endpoint(prefix: &str) -> String {
+    format!("inproc://{}.{}", prefix, Uuid::new_v4().to_simple())
+}
+
+/// Bind `socket` to an ephemeral TCP port on the loopback interface, and return the
+/// endpoint that peers connect to.
+pub fn bind_ephemeral(socket: &zmq::Socket) -> zmq::Result<String> {
+    socket.bind("tcp://127.0.0.1:*")?;
+    Ok(socket
+        .get_last_endpoint()?
+        .expect("unparsable ephemeral endpoint"))
+}
+
+/// A clock with the API of `Clock`, whose time only moves when it is told to.
+///
+/// Clones share their time, so a clock that is moved to another thread can be advanced
+/// from the test.
+#[derive(Clone, Debug, Default)]
+pub struct FakeClock {
+    // time at which the clock started, in milliseconds since the UNIX epoch.
+    epoch: i64,
+    // microseconds since the clock started.
+    elapsed: Arc<AtomicI64>,
+}
+
+impl FakeClock {
+    /// Create a clock whose monotonic time is 0, and whose system time is the UNIX epoch.
+    pub fn new() -> FakeClock {
+        FakeClock::default()
+    }
+
+    /// Create a clock whose system time starts at `epoch`, in milliseconds.
+    pub fn at(epoch: i64) -> FakeClock {
+        FakeClock {
+            epoch,
+            ..FakeClock::default()
+        }
+    }
+
+    /// Move the clock forward by a number of milliseconds.
+    pub fn advance(&self, ms: u64) {
+        self.advance_usecs(ms as i64 * 1_000);
+    }
+
+    /// Move the clock forward by a number of microseconds.
+    pub fn advance_usecs(&self, usecs: i64) {
+        self.elapsed.fetch_add(usecs, Ordering::SeqCst);
+    }
+
+    /// Move the clock forward by a number of milliseconds, without blocking.
+    pub fn sleep(&self, ms: u64) {
+        self.advance(ms);
+    }
+
+    /// Returns monotonic clock in milliseconds.
+    pub fn mono(&self) -> i64 {
+        self.usecs() / 1_000
+    }
+
+    /// Returns monotonic clock in microseconds.
+    pub fn usecs(&self) -> i64 {
+        self.elapsed.load(Ordering::SeqCst)
+    }
+
+    /// Returns system clock in milliseconds since the UNIX epoch.
+    pub fn time(&self) -> i64 {
+        self.epoch + self.mono()
+    }
+
+    /// Returns a `Clock` that reads its time from this one.
+    pub fn clock(&self) -> Clock<FakeClock> {
+        Clock::with_source(self.clone())
+    }
+}
+
+impl TimeSource for FakeClock {
+    fn usecs(&self) -> i64 {
+        FakeClock::usecs(self)
+    }
+
+    fn since_epoch(&self) -> Result<Duration, ClockError> {
+        let usecs = self.epoch * 1_000 + FakeClock::usecs(self);
+        Ok(Duration::from_micros(usecs.max(0) as u64))
+    }
+
+    fn sleep(&self, ms: u64) {
+        self.advance(ms);
+    }
+}
+
+/// Expected frames of a message.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MessageMatcher {
+    /// Any message.
+    Any,
+    /// Messages with exactly these frames.
+    Exactly(Vec<Vec<u8>>),
+    /// Messages whose first frames are these.
+    Prefix(Vec<Vec<u8>>),
+    /// Messages with this number of frames.
+    Frames(usize),
+}
+
+impl MessageMatcher {
+    /// Match messages with exactly `frames`.
+    pub fn exactly<T: AsRef<[u8]>>(frames: &[T]) -> MessageMatcher {
+        MessageMatcher::Exactly(to_frames(frames))
+    }
+
+    /// Match messages that start with `frames`.
+    pub fn prefix<T: AsRef<[u8]>>(frames: &[T]) -> MessageMatcher {
+        MessageMatcher::Prefix(to_frames(frames))
+    }
+
+    /// Returns true if `msg` matches.
+    pub fn matches(&self, msg: &[Vec<u8>]) -> bool {
+        match *self {
+            MessageMatcher::Any => true,
+            MessageMatcher::Exactly(ref frames) => msg == &frames[..],
+            MessageMatcher::Prefix(ref frames) => msg.starts_with(frames),
+            MessageMatcher::Frames(count) => msg.len() == count,
+        }
+    }
+}
+
+impl fmt::Display for MessageMatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageMatcher::Any => write!(f, "any message"),
+            MessageMatcher::Exactly(ref frames) => write!(f, "exactly {}", Printable(frames)),
+            MessageMatcher::Prefix(ref frames) => write!(f, "prefix {}", Printable(frames)),
+            MessageMatcher::Frames(count) => write!(f, "{} frames", count),
+        }
+    }
+}
+
+fn to_frames<T: AsRef<[u8]>>(frames: &[T]) -> Vec<Vec<u8>> {
+    frames.iter().map(|frame| frame.as_ref().to_vec()).collect()
+}
+
+// Frames, printed as text when they are UTF-8, for the messages of failed expectations.
+struct Printable<'a>(&'a [Vec<u8>]);
+
+impl<'a> fmt::Display for Printable<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, frame) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match ::std::str::from_utf8(frame) {
+                Ok(text) => write!(f, "{:?}", text)?,
+                Err(_) => write!(f, "{:?}", frame)?,
+            }
+        }
+        write!(f, "]")
+    }
+}
+
+/// Wait up to `timeout` milliseconds for a message on `socket`.
+pub fn recv_timeout(socket: &zmq::Socket, timeout: i64) -> zmq::Result<Option<Vec<Vec<u8>>>> {
+    if socket.poll(zmq::POLLIN, timeout)? == 0 {
+        return Ok(None);
+    }
+    Ok(Some(socket.recv_multipart(zmq::DONTWAIT)?))
+}
+
+/// Wait up to `timeout` milliseconds for a message on `socket`, and return it.
+///
+/// Panics if there is none, or if it does not match `matcher`.
+pub fn expect_message(
+    socket: &zmq::Socket,
+    matcher: &MessageMatcher,
+    timeout: i64,
+) -> Vec<Vec<u8>> {
+    match recv_timeout(socket, timeout) {
+        Ok(Some(msg)) => {
+            if !matcher.matches(&msg) {
+                panic!("expected {}, received {}", matcher, Printable(&msg));
+            }
+            msg
+        }
+        Ok(None) => panic!("expected {}, received nothing in {}ms", matcher, timeout),
+        Err(e) => panic!("expected {}, the socket failed: {}", matcher, e),
+    }
+}
+
+/// Panics if a message arrives on `socket` in the next `timeout` milliseconds.
+pub fn expect_silence(socket: &zmq::Socket, timeout: i64) {
+    match recv_timeout(socket, timeout) {
+        Ok(None) => {}
+        Ok(Some(msg)) => panic!("expected silence, received {}", Printable(&msg)),
+        Err(e) => panic!("expected silence, the socket failed: {}", e),
+    }
+}
+
+/// Call `condition` until it returns true, or until `timeout` milliseconds went by.
+/// Returns the last result of `condition`.
+pub fn wait_until<F>(timeout: u64, mut condition: F) -> bool
+where
+    F: FnMut() -> bool,
+{
+    let deadline = Instant::now() + Duration::from_millis(timeout);
+    loop {
+        if condition() {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Cleanups, and threads, of a test, which are run, and joined, when the guard is dropped,
+/// in the reverse order they were added.
+#[derive(Default)]
+pub struct ShutdownGuard {
+    cleanups: Vec<Box<dyn FnOnce() + Send>>,
+}
+
+impl ShutdownGuard {
+    /// Create a guard without cleanups.
+    pub fn new() -> ShutdownGuard {
+        ShutdownGuard::default()
+    }
+
+    /// Run `cleanup` when the guard is dropped.
+    pub fn on_drop<F>(&mut self, cleanup: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.cleanups.push(Box::new(cleanup));
+    }
+
+    /// Join `thread` when the guard is dropped, after the cleanups that were added after it.
+    pub fn join<T: Send + 'static>(&mut self, thread: thread::JoinHandle<T>) {
+        self.on_drop(move || {
+            let _ = thread.join();
+        });
+    }
+
+    /// Run every cleanup now.
+    pub fn shutdown(&mut self) {
+        while let Some(cleanup) = self.cleanups.pop() {
+            cleanup();
+        }
+    }
+}
+
+impl Drop for ShutdownGuard {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expectations_match_frames_without_sleeping() {
+        let clock = FakeClock::at(1_000);
+        let shared = clock.clone();
+        shared.sleep(1_500);
+        clock.advance_usecs(250);
+        assert_eq!(clock.mono(), 1_500);
+        assert_eq!(clock.usecs(), 1_500_250);
+        assert_eq!(clock.time(), 2_500);
+
+        // clocks of the code under test follow the fake one.
+        let under_test = clock.clock();
+        let deadline = under_test.deadline(100);
+        let stopwatch = under_test.stopwatch();
+        under_test.sleep(60);
+        assert_eq!(deadline.remaining(), 40);
+        clock.advance(40);
+        assert!(deadline.is_expired());
+        assert_eq!(stopwatch.elapsed(), 100);
+        assert_eq!(under_test.time().unwrap(), 2_600);
+
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("testkit");
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind(&endpoint).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+
+        let mut guard = ShutdownGuard::new();
+        let thread = thread::spawn(move || {
+            push.send_multipart(["job", "1"], 0).unwrap();
+            push.send_multipart(["job", "2", "urgent"], 0).unwrap();
+        });
+        guard.join(thread);
+
+        expect_message(&pull, &MessageMatcher::exactly(&["job", "1"]), 1_000);
+        let msg = expect_message(&pull, &MessageMatcher::Frames(3), 1_000);
+        assert!(MessageMatcher::prefix(&["job", "2"]).matches(&msg));
+        assert!(!MessageMatcher::prefix(&["task"]).matches(&msg));
+        expect_silence(&pull, 10);
+
+        let tcp = ctx.socket(zmq::PULL).unwrap();
+        let endpoint = bind_ephemeral(&tcp).unwrap();
+        assert!(endpoint.starts_with("tcp://127.0.0.1:"));
+        let mut calls = 0;
+        assert!(wait_until(1_000, || {
+            calls += 1;
+            calls == 3
+        }));
+        assert!(!wait_until(5, || false));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected exactly [\"pong\"], received [\"ping\"]")]
+    fn unexpected_messages_panic_with_their_frames() {
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("testkit");
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind(&endpoint).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+        push.send("ping", 0).unwrap();
+        expect_message(&pull, &MessageMatcher::exactly(&["pong"]), 1_000);
+    }
+}
@@ -0,0 +1,307 @@
+//! Rate limits, with token buckets.
+//!
+//! A `TokenBucket` holds up to `burst` tokens, and is refilled with `rate` tokens per
+//! second. `Throttled` wraps any `SocketSend`, and takes a token for every message, or for
+//! every byte, that it sends, so that bursts do not overwhelm slow consumers, or multicast
+//! transports. Sends block until the bucket has enough tokens, or fail with
+//! `io::ErrorKind::WouldBlock` when they are given `zmq::DONTWAIT`, as in polling contexts.
+//! Messages that cost more than the burst wait for a full bucket, and empty it.
+//!
+//! Buckets read the time from a `Clock`, which can be a `FakeClock` in tests.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::socket::SocketSend;
+//! use neuras::throttle::{Charge, Throttled, TokenBucket};
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let push = ctx.socket(zmq::PUSH).unwrap();
+//! push.bind("inproc://throttled").unwrap();
+//! let pull = ctx.socket(zmq::PULL).unwrap();
+//! pull.connect("inproc://throttled").unwrap();
+//! // 1000 messages per second, in bursts of up to 10.
+//! let throttled = Throttled::new(push, TokenBucket::new(1_000, 10), Charge::PerMessage);
+//! for _ in 0..10 {
+//!     throttled.send_multipart(vec!["tick"], zmq::DONTWAIT).unwrap();
+//! }
+//! assert!(throttled.send_multipart(vec!["tick"], zmq::DONTWAIT).is_err());
+//! # }
+//! ```
+use super::clock::{Clock, SystemTimeSource, TimeSource};
+use super::socket::{pack, IntoFrame, SocketSend, SocketWrapper};
+
+use std::cell::RefCell;
+use std::io;
+use zmq;
+
+/// Tokens that are refilled at a steady rate, up to a burst.
+#[derive(Clone, Debug)]
+pub struct TokenBucket<C = SystemTimeSource> {
+    clock: Clock<C>,
+    rate: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: i64,
+}
+
+impl TokenBucket {
+    /// Create a full bucket of `burst` tokens, refilled with `rate` tokens per second.
+    /// Both are at least 1.
+    pub fn new(rate: u64, burst: u64) -> TokenBucket {
+        TokenBucket::with_clock(rate, burst, Clock::new())
+    }
+}
+
+impl<C: TimeSource + Clone> TokenBucket<C> {
+    /// Create a bucket, as with `new`, that reads the time from `clock`.
+    pub fn with_clock(rate: u64, burst: u64, clock: Clock<C>) -> TokenBucket<C> {
+        let burst = burst.max(1) as f64;
+        let refilled = clock.usecs();
+        TokenBucket {
+            clock,
+            rate: rate.max(1) as f64,
+            burst,
+            tokens: burst,
+            refilled,
+        }
+    }
+
+    /// Returns the tokens refilled every second.
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    /// Returns the most tokens that the bucket holds.
+    pub fn burst(&self) -> u64 {
+        self.burst as u64
+    }
+
+    fn refill(&mut self) {
+        let now = self.clock.usecs();
+        let elapsed = (now - self.refilled).max(0) as f64;
+        self.tokens = (self.tokens + elapsed * self.rate / 1e6).min(self.burst);
+        self.refilled = now;
+    }
+
+    // Tokens taken for `cost`, which is capped at the burst.
+    fn capped(&self, cost: u64) -> f64 {
+        (cost as f64).min(self.burst)
+    }
+
+    /// Returns the whole tokens in the bucket.
+    pub fn available(&mut self) -> u64 {
+        self.refill();
+        self.tokens as u64
+    }
+
+    /// Returns the milliseconds until the bucket holds `cost` tokens, or `0` if it does.
+    pub fn wait_time(&mut self, cost: u64) -> i64 {
+        self.refill();
+        let missing = self.capped(cost) - self.tokens;
+        if missing <= 0.0 {
+            0
+        } else {
+            (missing * 1e3 / self.rate).ceil() as i64
+        }
+    }
+
+    /// Take `cost` tokens, if the bucket holds them. Returns false otherwise, without
+    /// taking any.
+    pub fn try_take(&mut self, cost: u64) -> bool {
+        self.refill();
+        let cost = self.capped(cost);
+        if self.tokens < cost {
+            return false;
+        }
+        self.tokens -= cost;
+        true
+    }
+
+    /// Take `cost` tokens, sleeping on the clock of the bucket until it holds them.
+    pub fn take(&mut self, cost: u64) {
+        self.wait(cost);
+        self.try_take(cost);
+    }
+
+    // Sleep until the bucket holds `cost` tokens.
+    fn wait(&mut self, cost: u64) {
+        loop {
+            match self.wait_time(cost) {
+                0 => return,
+                ms => self.clock.sleep(ms as u64),
+            }
+        }
+    }
+}
+
+/// What `Throttled` sockets take a token for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Charge {
+    /// A token for every message.
+    PerMessage,
+    /// A token for every byte of the frames of a message.
+    PerByte,
+}
+
+/// A socket whose sends are limited by a `TokenBucket`.
+pub struct Throttled<S, C = SystemTimeSource> {
+    socket: S,
+    bucket: RefCell<TokenBucket<C>>,
+    charge: Charge,
+}
+
+impl<S: SocketSend, C: TimeSource + Clone> Throttled<S, C> {
+    /// Wrap `socket`, charging its sends to `bucket`.
+    pub fn new(socket: S, bucket: TokenBucket<C>, charge: Charge) -> Throttled<S, C> {
+        Throttled {
+            socket,
+            bucket: RefCell::new(bucket),
+            charge,
+        }
+    }
+
+    /// Returns a reference to the wrapped socket.
+    pub fn get_ref(&self) -> &S {
+        &self.socket
+    }
+
+    /// Returns the wrapped socket.
+    pub fn into_inner(self) -> S {
+        self.socket
+    }
+
+    /// Returns the whole tokens left in the bucket.
+    pub fn available(&self) -> u64 {
+        self.bucket.borrow_mut().available()
+    }
+
+    fn cost(&self, frames: &[zmq::Message]) -> u64 {
+        match self.charge {
+            Charge::PerMessage => 1,
+            Charge::PerByte => frames.iter().map(|f| f.len() as u64).sum(),
+        }
+    }
+
+    // Wait for `cost` tokens, unless `flags` has `zmq::DONTWAIT`, send, and then take them.
+    fn charged<F>(&self, cost: u64, flags: i32, send: F) -> io::Result<()>
+    where
+        F: FnOnce() -> io::Result<()>,
+    {
+        let mut bucket = self.bucket.borrow_mut();
+        if flags & zmq::DONTWAIT != 0 {
+            if bucket.wait_time(cost) > 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+        } else {
+            bucket.wait(cost);
+        }
+        send()?;
+        bucket.try_take(cost);
+        Ok(())
+    }
+}
+
+impl<S: SocketSend, C: TimeSource + Clone> SocketWrapper for Throttled<S, C> {
+    fn get_socket_ref(&self) -> &zmq::Socket {
+        self.socket.get_socket_ref()
+    }
+
+    fn get_rcvmore(&self) -> io::Result<bool> {
+        self.socket.get_rcvmore()
+    }
+}
+
+impl<S: SocketSend, C: TimeSource + Clone> SocketSend for Throttled<S, C> {
+    fn send<T>(&self, msg: T, flags: i32) -> io::Result<()>
+    where
+        T: Into<zmq::Message>,
+    {
+        let frame = msg.into();
+        let cost = self.cost(::std::slice::from_ref(&frame));
+        self.charged(cost, flags, || self.socket.send(frame, flags))
+    }
+
+    fn send_multipart<I, T>(&self, msg: I, flags: i32) -> io::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<zmq::Message>,
+    {
+        let frames: Vec<zmq::Message> = msg.into_iter().map(Into::into).collect();
+        let cost = self.cost(&frames);
+        self.charged(cost, flags, || self.socket.send_multipart(frames, flags))
+    }
+
+    fn send_frame<F>(&self, frame: F, flags: i32) -> io::Result<()>
+    where
+        F: IntoFrame,
+    {
+        let frame = frame.into_frame();
+        let cost = self.cost(::std::slice::from_ref(&frame));
+        self.charged(cost, flags, || self.socket.send(frame, flags))
+    }
+
+    fn send_packed<I, T>(&self, msgs: I, flags: i32) -> io::Result<()>
+    where
+        I: IntoIterator<Item = T>,
+        T: AsRef<[u8]>,
+    {
+        self.send_multipart(vec![pack(msgs)?], flags)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testkit::{inproc_endpoint, FakeClock};
+
+    #[test]
+    fn buckets_refill_at_their_rate() {
+        let fake = FakeClock::new();
+        let mut bucket = TokenBucket::with_clock(100, 5, fake.clock());
+        assert!(bucket.try_take(5));
+        assert!(!bucket.try_take(1));
+        assert_eq!(bucket.wait_time(1), 10);
+        fake.advance(25);
+        assert_eq!(bucket.available(), 2);
+        // costs over the burst wait for a full bucket.
+        assert_eq!(bucket.wait_time(50), 25);
+        bucket.take(50);
+        assert_eq!(fake.mono(), 50);
+        assert_eq!(bucket.available(), 0);
+        fake.advance(1_000);
+        assert_eq!(bucket.available(), 5);
+    }
+
+    #[test]
+    fn throttled_sockets_block_or_would_block() {
+        let ctx = zmq::Context::new();
+        let endpoint = inproc_endpoint("throttle");
+        let pull = ctx.socket(zmq::PULL).unwrap();
+        pull.bind(&endpoint).unwrap();
+        let push = ctx.socket(zmq::PUSH).unwrap();
+        push.connect(&endpoint).unwrap();
+
+        let fake = FakeClock::new();
+        let bucket = TokenBucket::with_clock(1_000, 10, fake.clock());
+        let throttled = Throttled::new(push, bucket, Charge::PerByte);
+        throttled
+            .send_multipart(vec!["12345", "678"], zmq::DONTWAIT)
+            .unwrap();
+        assert_eq!(throttled.available(), 2);
+        match throttled.send_multipart(vec!["abc"], zmq::DONTWAIT) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        // blocking sends sleep on the fake clock until the bucket refills.
+        throttled.send_multipart(vec!["abcdef"], 0).unwrap();
+        assert_eq!(fake.mono(), 4);
+        throttled.send_packed(vec!["a"], 0).unwrap();
+
+        assert_eq!(pull.recv_multipart(0).unwrap().len(), 2);
+        assert_eq!(pull.recv_bytes(0).unwrap(), b"abcdef");
+        assert!(pull.poll(zmq::POLLIN, 1_000).unwrap() > 0);
+    }
+}
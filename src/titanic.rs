@@ -0,0 +1,549 @@
+//! Disconnected, disk-based, reliable requests.
+//!
+//! An implementation of the
+//! "[Titanic Service Protocol](https://rfc.zeromq.org/spec/9/)" (TSP), as described in
+//! "[Disconnected Reliability](http://zguide.zeromq.org/page:all#Disconnected-Reliability-Titanic-Pattern)",
+//! on top of the Majordomo `broker`.
+//!
+//! `Titanic` registers three services with a broker:
+//!
+//! | service           | request                 | reply                                   |
+//! |-------------------|-------------------------|-----------------------------------------|
+//! | `titanic.request` | service, request frames | `200`, and the UUID of the request      |
+//! | `titanic.reply`   | UUID                    | `200` and the reply, `300`, or `400`    |
+//! | `titanic.close`   | UUID                    | `200`                                   |
+//!
+//! Requests are written to a `Store` on disk before their UUID is returned, and a
+//! dispatcher thread sends them to their service through the broker, writing each reply to
+//! the store, so that clients fetch it later, even after either side restarted. Requests
+//! that had no reply when Titanic stopped are dispatched again when it starts. `300` means
+//! that the reply is pending, and `400` that the UUID is unknown, e.g. it was closed.
+//!
+//! `TitanicClient` speaks the protocol to the broker.
+use super::actor::ShutdownToken;
+use super::broker::{Client, Worker};
+use super::socket::{pack, unpack};
+use super::utils::run_named_thread;
+
+use failure::Error;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use zmq;
+
+/// Service that stores requests.
+pub const TITANIC_REQUEST: &str = "titanic.request";
+/// Service that fetches replies.
+pub const TITANIC_REPLY: &str = "titanic.reply";
+/// Service that forgets requests, and their replies.
+pub const TITANIC_CLOSE: &str = "titanic.close";
+
+/// Status of successful requests.
+pub const STATUS_OK: &str = "200";
+/// Status of the replies that are pending.
+pub const STATUS_PENDING: &str = "300";
+/// Status of unknown requests.
+pub const STATUS_UNKNOWN: &str = "400";
+/// Status of requests that failed.
+pub const STATUS_FAILED: &str = "500";
+
+/// Default milliseconds between attempts to dispatch the pending requests.
+pub const DISPATCH_INTERVAL: i64 = 1_000;
+
+// Extensions of the files of requests, and of replies.
+const REQUEST: &str = "request";
+const REPLY: &str = "reply";
+
+/// Titanic Errors.
+#[derive(Debug, Fail)]
+pub enum TitanicError {
+    #[fail(display = "invalid titanic message")]
+    InvalidMessage,
+    #[fail(display = "unknown request: {}", _0)]
+    UnknownRequest(Uuid),
+    #[fail(display = "titanic failed with status {}", _0)]
+    Failed(String),
+}
+
+/// Lookup of the reply to a stored request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stored {
+    /// The reply of the service.
+    Reply(Vec<Vec<u8>>),
+    /// The request is waiting to be dispatched, or for its reply.
+    Pending,
+    /// The request is not known.
+    Unknown,
+}
+
+/// A request, as it was stored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoredRequest {
+    pub service: Vec<u8>,
+    pub frames: Vec<Vec<u8>>,
+}
+
+/// Requests, and their replies, in a directory, one file each.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    /// Open the store in `dir`, creating it if needed.
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Store, Error> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Store {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    /// Returns the directory of the store.
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Store a request for `service`, returning its UUID.
+    pub fn put_request(&self, service: &[u8], request: &[Vec<u8>]) -> Result<Uuid, Error> {
+        let uuid = Uuid::new_v4();
+        let frames = ::std::iter::once(service).chain(request.iter().map(|frame| &frame[..]));
+        self.write(&self.path(&uuid, REQUEST), &pack(frames)?)?;
+        Ok(uuid)
+    }
+
+    /// Returns the request with `uuid`, if it is stored.
+    pub fn request(&self, uuid: &Uuid) -> Result<Option<StoredRequest>, Error> {
+        let mut frames = match self.read(&self.path(uuid, REQUEST))? {
+            Some(frames) => frames,
+            None => return Ok(None),
+        };
+        if frames.is_empty() {
+            return Err(TitanicError::InvalidMessage.into());
+        }
+        let request = frames.split_off(1);
+        Ok(Some(StoredRequest {
+            service: frames.remove(0),
+            frames: request,
+        }))
+    }
+
+    /// Store the reply to the request with `uuid`.
+    pub fn put_reply(&self, uuid: &Uuid, reply: &[Vec<u8>]) -> Result<(), Error> {
+        self.write(&self.path(uuid, REPLY), &pack(reply)?)
+    }
+
+    /// Look up the reply to the request with `uuid`.
+    pub fn reply(&self, uuid: &Uuid) -> Result<Stored, Error> {
+        if let Some(reply) = self.read(&self.path(uuid, REPLY))? {
+            return Ok(Stored::Reply(reply));
+        }
+        if self.path(uuid, REQUEST).exists() {
+            Ok(Stored::Pending)
+        } else {
+            Ok(Stored::Unknown)
+        }
+    }
+
+    /// Forget the request with `uuid`, and its reply.
+    pub fn close(&self, uuid: &Uuid) -> Result<(), Error> {
+        for extension in &[REQUEST, REPLY] {
+            match fs::remove_file(self.path(uuid, extension)) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                result => result?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the UUIDs of the requests without a reply, oldest first.
+    pub fn pending(&self) -> Result<Vec<Uuid>, Error> {
+        let mut pending = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(REQUEST) {
+                continue;
+            }
+            let uuid = match path.file_stem().and_then(|s| s.to_str()) {
+                Some(stem) => match Uuid::parse_str(stem) {
+                    Ok(uuid) => uuid,
+                    Err(_) => continue,
+                },
+                None => continue,
+            };
+            if self.path(&uuid, REPLY).exists() {
+                continue;
+            }
+            // requests closed since they were listed are gone.
+            match fs::metadata(&path).and_then(|metadata| metadata.modified()) {
+                Ok(modified) => pending.push((modified, uuid)),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        pending.sort();
+        Ok(pending.into_iter().map(|(_, uuid)| uuid).collect())
+    }
+
+    fn path(&self, uuid: &Uuid, extension: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.{}", uuid.to_hyphenated(), extension))
+    }
+
+    // Write the file whole, or not at all, and sync it, and its name in the directory, to
+    // the disk, so that it survives a crash once this returns.
+    fn write(&self, path: &Path, contents: &[u8]) -> Result<(), Error> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".partial");
+        let mut file = fs::File::create(&partial)?;
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&partial, path)?;
+        // directories can't be opened as files on every platform, e.g. Windows.
+        #[cfg(unix)]
+        fs::File::open(&self.dir)?.sync_all()?;
+        Ok(())
+    }
+
+    fn read(&self, path: &Path) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        match fs::read(path) {
+            Ok(contents) => Ok(Some(unpack(&contents)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// The Titanic services, and their dispatcher, for a Majordomo broker.
+pub struct Titanic {
+    broker: String,
+    context: zmq::Context,
+    store: Store,
+    interval: i64,
+}
+
+impl Titanic {
+    /// Create a `Titanic` for the broker at the given address, storing requests in `dir`.
+    pub fn new<P: AsRef<Path>>(broker: &str, dir: P, context: zmq::Context) -> Result<Self, Error> {
+        Ok(Titanic {
+            broker: broker.to_string(),
+            context,
+            store: Store::open(dir)?,
+            interval: DISPATCH_INTERVAL,
+        })
+    }
+
+    /// Set the milliseconds between attempts to dispatch the pending requests, which is
+    /// also how long the dispatcher waits for each reply.
+    pub fn set_interval(&mut self, interval: i64) {
+        self.interval = interval;
+    }
+
+    /// Returns the store of the requests.
+    pub fn store(&self) -> &Store {
+        &self.store
+    }
+
+    /// Start the workers of the Titanic services, on threads of their own, and the
+    /// dispatcher, which runs until `token` is signaled. The workers serve for as long as
+    /// the broker does.
+    pub fn start(
+        &self,
+        token: ShutdownToken,
+    ) -> Result<thread::JoinHandle<Result<(), Error>>, Error> {
+        let (wake, woken) = mpsc::channel();
+        self.serve(TITANIC_REQUEST, move |store, mut msg| {
+            if msg.is_empty() {
+                return Err(TitanicError::InvalidMessage.into());
+            }
+            let request = msg.split_off(1);
+            let uuid = store.put_request(&msg[0], &request)?;
+            let _ = wake.send(());
+            Ok(vec![uuid.to_hyphenated().to_string().into_bytes()])
+        })?;
+        self.serve(TITANIC_REPLY, |store, msg| {
+            match store.reply(&parse_uuid(&msg)?)? {
+                Stored::Reply(reply) => Ok(reply),
+                Stored::Pending => Err(TitanicError::Failed(STATUS_PENDING.to_string()).into()),
+                Stored::Unknown => Err(TitanicError::Failed(STATUS_UNKNOWN.to_string()).into()),
+            }
+        })?;
+        self.serve(TITANIC_CLOSE, |store, msg| {
+            store.close(&parse_uuid(&msg)?)?;
+            Ok(Vec::new())
+        })?;
+
+        let mut client = Client::new(&self.broker, self.context.clone())?;
+        client.set_timeout(self.interval);
+        client.set_retries(1);
+        let store = self.store.clone();
+        let interval = Duration::from_millis(self.interval as u64);
+        Ok(run_named_thread("titanic.dispatch", move || {
+            while !token.is_shutdown() {
+                let pending = store.pending().unwrap_or_else(|e| {
+                    nlog!(warn, "titanic requests not listed error={}", e);
+                    Vec::new()
+                });
+                // requests that can't be read, e.g. corrupt files, are left for an operator.
+                for uuid in pending {
+                    if let Err(e) = dispatch(&store, &mut client, &uuid) {
+                        nlog!(warn, "titanic request skipped uuid={} error={}", uuid, e);
+                    }
+                }
+                match woken.recv_timeout(interval) {
+                    Ok(()) | Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    Err(mpsc::RecvTimeoutError::Disconnected) => thread::sleep(interval),
+                }
+            }
+            Ok(())
+        })?)
+    }
+
+    // Serve `service` on a thread of its own. `handle` answers the body of each request,
+    // which is sent after `200`; errors are sent as their status, if they have one, or as
+    // `500`.
+    fn serve<F>(&self, service: &str, mut handle: F) -> Result<(), Error>
+    where
+        F: FnMut(&Store, Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, Error> + Send + 'static,
+    {
+        let mut worker = Worker::new(&self.broker, service, self.context.clone())?;
+        let store = self.store.clone();
+        run_named_thread(service, move || {
+            let mut reply = None;
+            loop {
+                let request = match worker.recv(reply.take()) {
+                    Ok(request) => request,
+                    Err(e) => {
+                        nlog!(warn, "titanic request not received error={}", e);
+                        continue;
+                    }
+                };
+                let mut frames = vec![STATUS_OK.as_bytes().to_vec()];
+                match handle(&store, request) {
+                    Ok(body) => frames.extend(body),
+                    Err(e) => {
+                        frames[0] = match e.downcast::<TitanicError>() {
+                            Ok(TitanicError::Failed(status)) => status.into_bytes(),
+                            Ok(TitanicError::InvalidMessage) => STATUS_UNKNOWN.into(),
+                            _ => STATUS_FAILED.into(),
+                        }
+                    }
+                }
+                reply = Some(frames);
+            }
+        })?;
+        Ok(())
+    }
+}
+
+// Send the request with `uuid` to its service, if the broker knows it, and store the reply.
+fn dispatch(store: &Store, client: &mut Client, uuid: &Uuid) -> Result<(), Error> {
+    let StoredRequest { service, frames } = match store.request(uuid)? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+    let known = match client.send("mmi.service", vec![service.clone()]) {
+        Ok(reply) => reply.first().map(|status| &status[..]) == Some(STATUS_OK.as_bytes()),
+        Err(_) => false,
+    };
+    if !known {
+        return Ok(());
+    }
+    let service = String::from_utf8_lossy(&service).into_owned();
+    match client.send(&service, frames) {
+        Ok(reply) => store.put_reply(uuid, &reply),
+        Err(e) => {
+            nlog!(
+                debug,
+                "titanic request not dispatched uuid={} error={}",
+                uuid,
+                e
+            );
+            Ok(())
+        }
+    }
+}
+
+fn parse_uuid(msg: &[Vec<u8>]) -> Result<Uuid, Error> {
+    let uuid = msg
+        .first()
+        .and_then(|frame| ::std::str::from_utf8(frame).ok())
+        .and_then(|uuid| Uuid::parse_str(uuid).ok())
+        .ok_or(TitanicError::InvalidMessage)?;
+    Ok(uuid)
+}
+
+/// Blocking client of the Titanic services.
+pub struct TitanicClient {
+    client: Client,
+}
+
+impl TitanicClient {
+    /// Create a new `TitanicClient`, connected to the broker at the given address.
+    pub fn new(broker: &str, context: zmq::Context) -> Result<Self, Error> {
+        Ok(TitanicClient {
+            client: Client::new(broker, context)?,
+        })
+    }
+
+    /// Returns the underlying Majordomo client, e.g. to set its timeout.
+    pub fn client_mut(&mut self) -> &mut Client {
+        &mut self.client
+    }
+
+    /// Store a request for `service`, returning its UUID, to fetch its reply with.
+    pub fn request<I, T>(&mut self, service: &str, request: I) -> Result<Uuid, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        let mut msg = vec![service.as_bytes().to_vec()];
+        msg.extend(request.into_iter().map(Into::into));
+        let reply = self.send(TITANIC_REQUEST, msg)?;
+        parse_uuid(&reply)
+    }
+
+    /// Fetch the reply to the request with `uuid`, or `None` while it is pending.
+    pub fn reply(&mut self, uuid: &Uuid) -> Result<Option<Vec<Vec<u8>>>, Error> {
+        match self.send(
+            TITANIC_REPLY,
+            vec![uuid.to_hyphenated().to_string().into_bytes()],
+        ) {
+            Ok(reply) => Ok(Some(reply)),
+            Err(e) => match e.downcast::<TitanicError>() {
+                Ok(TitanicError::Failed(ref status)) if status == STATUS_PENDING => Ok(None),
+                Ok(TitanicError::Failed(ref status)) if status == STATUS_UNKNOWN => {
+                    Err(TitanicError::UnknownRequest(*uuid).into())
+                }
+                Ok(e) => Err(e.into()),
+                Err(e) => Err(e),
+            },
+        }
+    }
+
+    /// Forget the request with `uuid`, and its reply.
+    pub fn close(&mut self, uuid: &Uuid) -> Result<(), Error> {
+        self.send(
+            TITANIC_CLOSE,
+            vec![uuid.to_hyphenated().to_string().into_bytes()],
+        )?;
+        Ok(())
+    }
+
+    // Send `msg` to `service`, returning the body of a `200` reply.
+    fn send(&mut self, service: &str, msg: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>, Error> {
+        let mut reply = self.client.send(service, msg)?;
+        if reply.is_empty() {
+            return Err(TitanicError::InvalidMessage.into());
+        }
+        let body = reply.split_off(1);
+        if reply[0] == STATUS_OK.as_bytes() {
+            Ok(body)
+        } else {
+            Err(TitanicError::Failed(String::from_utf8_lossy(&reply[0]).into_owned()).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::actor::ShutdownController;
+    use super::super::broker::Broker;
+    use super::*;
+    use std::env;
+    use testkit::wait_until;
+
+    fn store_dir() -> PathBuf {
+        env::temp_dir().join(format!("neuras-titanic-{}", Uuid::new_v4()))
+    }
+
+    fn start_broker(addr: &str) -> Broker {
+        let broker = Broker::new(addr).unwrap();
+        broker.start().unwrap();
+        broker.pipe().recv_msg(0).unwrap();
+        broker
+    }
+
+    fn start_echo(addr: &str, context: zmq::Context) {
+        let mut worker = Worker::new(addr, "echo", context).unwrap();
+        thread::spawn(move || {
+            let mut reply = None;
+            loop {
+                reply = Some(worker.recv(reply.take()).unwrap());
+            }
+        });
+    }
+
+    #[test]
+    fn stores_keep_requests_until_they_are_closed() {
+        let dir = store_dir();
+        let store = Store::open(&dir).unwrap();
+        let first = store.put_request(b"echo", &[b"one".to_vec()]).unwrap();
+        let second = store.put_request(b"echo", &[]).unwrap();
+        assert_eq!(store.reply(&first).unwrap(), Stored::Pending);
+        assert_eq!(
+            store.request(&first).unwrap(),
+            Some(StoredRequest {
+                service: b"echo".to_vec(),
+                frames: vec![b"one".to_vec()],
+            })
+        );
+
+        store.put_reply(&first, &[b"ONE".to_vec()]).unwrap();
+        let reopened = Store::open(&dir).unwrap();
+        assert_eq!(reopened.pending().unwrap(), vec![second]);
+        assert_eq!(
+            reopened.reply(&first).unwrap(),
+            Stored::Reply(vec![b"ONE".to_vec()])
+        );
+        reopened.close(&first).unwrap();
+        assert_eq!(reopened.reply(&first).unwrap(), Stored::Unknown);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn titanic_replies_to_requests_stored_before_it_started() {
+        let broker = start_broker("inproc://titanic");
+        let context = broker.context();
+        start_echo("inproc://titanic", context.clone());
+
+        // a request left pending by a previous run, behind one that is corrupt.
+        let dir = store_dir();
+        let corrupt = Store::open(&dir).unwrap().path(&Uuid::new_v4(), REQUEST);
+        fs::write(&corrupt, b"\xff\xff").unwrap();
+        let earlier = Store::open(&dir)
+            .unwrap()
+            .put_request(b"echo", &[b"earlier".to_vec()])
+            .unwrap();
+        let mut titanic = Titanic::new("inproc://titanic", &dir, context.clone()).unwrap();
+        titanic.set_interval(50);
+        let shutdown = ShutdownController::new();
+        let dispatcher = titanic.start(shutdown.token()).unwrap();
+
+        let mut client = TitanicClient::new("inproc://titanic", context).unwrap();
+        let uuid = client.request("echo", vec!["hello"]).unwrap();
+        let mut reply = None;
+        assert!(wait_until(2_000, || {
+            reply = client.reply(&uuid).unwrap();
+            reply.is_some()
+        }));
+        assert_eq!(reply, Some(vec![b"hello".to_vec()]));
+        assert_eq!(
+            client.reply(&earlier).unwrap(),
+            Some(vec![b"earlier".to_vec()])
+        );
+
+        client.close(&uuid).unwrap();
+        match client.reply(&uuid).unwrap_err().downcast::<TitanicError>() {
+            Ok(TitanicError::UnknownRequest(unknown)) => assert_eq!(unknown, uuid),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(corrupt.exists());
+        shutdown.signal();
+        dispatcher.join().unwrap().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
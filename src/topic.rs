@@ -0,0 +1,493 @@
+//! Hierarchical topics for subscribers.
+//!
+//! Topics are paths of levels separated by `/`, e.g. `sensors/room1/temp`. Subscriptions
+//! are filters on those paths, where a level can be replaced by a wildcard:
+//!
+//! * `+` matches exactly one level, e.g. `sensors/+/temp` matches `sensors/room1/temp`.
+//! * `#` matches any number of levels, including none, and must be the last level, e.g.
+//!   `sensors/#` matches `sensors`, and `sensors/room1/temp`.
+//!
+//! ØMQ subscriptions only match prefixes, so a `TopicSubscriber` subscribes its `SUB` socket
+//! to the literal prefix of every filter, and drops the messages that its `TopicTree` does
+//! not match.
+//!
+//! ```
+//! use neuras::topic::TopicTree;
+//!
+//! let mut tree = TopicTree::new();
+//! tree.insert("sensors/+/temp", "temperatures").unwrap();
+//! tree.insert("sensors/#", "everything").unwrap();
+//! let mut matched = tree.matches("sensors/room1/temp");
+//! matched.sort();
+//! assert_eq!(matched, vec![&"everything", &"temperatures"]);
+//! ```
+use std::collections::HashMap;
+use std::io;
+use zmq;
+
+#[path = "topic_seq.rs"]
+mod seq;
+
+pub use self::seq::{SeqEvent, SeqMessage, SeqPublisher, SeqSubscriber};
+
+#[path = "topic_sync.rs"]
+mod sync;
+
+pub use self::sync::{SyncPub, SyncSub, SYNC_TOPIC};
+
+/// Separator between topic levels.
+pub const SEPARATOR: char = '/';
+/// Wildcard for exactly one level.
+pub const SINGLE_LEVEL: &str = "+";
+/// Wildcard for any number of levels.
+pub const MULTI_LEVEL: &str = "#";
+
+/// Errors from topics, and topic filters.
+#[derive(Debug, Fail, PartialEq)]
+pub enum TopicError {
+    #[fail(display = "topic is empty")]
+    Empty,
+    #[fail(display = "topics can not have wildcards: {}", _0)]
+    WildcardTopic(String),
+    #[fail(display = "invalid topic filter: {}", _0)]
+    InvalidFilter(String),
+    #[fail(display = "invalid sequenced message")]
+    InvalidMessage,
+    #[fail(display = "{} of {} subscribers synced in time", _0, _1)]
+    SyncTimeout(usize, usize),
+}
+
+/// Check that `topic` can be published, i.e. that it is not empty, and has no wildcards.
+pub fn validate_topic(topic: &str) -> Result<(), TopicError> {
+    if topic.is_empty() {
+        return Err(TopicError::Empty);
+    }
+    if topic
+        .split(SEPARATOR)
+        .any(|level| level.contains(SINGLE_LEVEL) || level.contains(MULTI_LEVEL))
+    {
+        return Err(TopicError::WildcardTopic(topic.to_string()));
+    }
+    Ok(())
+}
+
+/// One level of a topic filter.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Level {
+    Exact(String),
+    /// `+`
+    Single,
+    /// `#`
+    Multi,
+}
+
+/// A parsed topic filter.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicFilter {
+    filter: String,
+    levels: Vec<Level>,
+}
+
+impl TopicFilter {
+    /// Parse a topic filter. Wildcards must take a whole level, and `#` can only be the
+    /// last level.
+    pub fn parse(filter: &str) -> Result<TopicFilter, TopicError> {
+        if filter.is_empty() {
+            return Err(TopicError::Empty);
+        }
+        let parts: Vec<&str> = filter.split(SEPARATOR).collect();
+        let mut levels = Vec::with_capacity(parts.len());
+        for (i, part) in parts.iter().enumerate() {
+            let level = match *part {
+                SINGLE_LEVEL => Level::Single,
+                MULTI_LEVEL if i == parts.len() - 1 => Level::Multi,
+                _ if part.contains(SINGLE_LEVEL) || part.contains(MULTI_LEVEL) => {
+                    return Err(TopicError::InvalidFilter(filter.to_string()));
+                }
+                _ => Level::Exact(part.to_string()),
+            };
+            levels.push(level);
+        }
+        Ok(TopicFilter {
+            filter: filter.to_string(),
+            levels,
+        })
+    }
+
+    /// Returns the filter as a string.
+    pub fn as_str(&self) -> &str {
+        &self.filter
+    }
+
+    /// Returns the levels of the filter.
+    pub fn levels(&self) -> &[Level] {
+        &self.levels
+    }
+
+    /// Returns true if the filter has no wildcards.
+    pub fn is_exact(&self) -> bool {
+        self.levels
+            .iter()
+            .all(|level| matches!(*level, Level::Exact(_)))
+    }
+
+    /// Returns the literal prefix of the filter, up to its first wildcard, to be used as
+    /// a ØMQ subscription. Every topic matched by the filter starts with it.
+    pub fn prefix(&self) -> &str {
+        let mut end = 0;
+        for level in &self.levels {
+            match *level {
+                Level::Exact(ref name) => end += name.len() + 1,
+                // `sensors/#` also matches `sensors`, so the separator is left out.
+                Level::Multi => return &self.filter[..end.saturating_sub(1)],
+                Level::Single => break,
+            }
+        }
+        &self.filter[..end.min(self.filter.len())]
+    }
+
+    /// Returns true if `topic` is matched by the filter.
+    pub fn matches(&self, topic: &str) -> bool {
+        let mut topic = topic.split(SEPARATOR);
+        for level in &self.levels {
+            match (level, topic.next()) {
+                (Level::Multi, _) => return true,
+                (Level::Single, Some(_)) => {}
+                (Level::Exact(name), Some(part)) if name == part => {}
+                _ => return false,
+            }
+        }
+        topic.next().is_none()
+    }
+}
+
+/// A tree of topic filters, each one with a value, which finds the values of every filter
+/// that matches a topic.
+#[derive(Debug)]
+pub struct TopicTree<T> {
+    root: Node<T>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    value: Option<T>,
+    multi: Option<T>,
+    single: Option<Box<Node<T>>>,
+    children: HashMap<String, Node<T>>,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Node {
+            value: None,
+            multi: None,
+            single: None,
+            children: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Node<T> {
+    fn is_empty(&self) -> bool {
+        self.value.is_none()
+            && self.multi.is_none()
+            && self.single.is_none()
+            && self.children.is_empty()
+    }
+
+    fn collect<'a>(&'a self, levels: &[&str], found: &mut Vec<&'a T>) {
+        if let Some(ref value) = self.multi {
+            found.push(value);
+        }
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => {
+                if let Some(ref value) = self.value {
+                    found.push(value);
+                }
+                return;
+            }
+        };
+        if let Some(child) = self.children.get(*level) {
+            child.collect(rest, found);
+        }
+        if let Some(ref child) = self.single {
+            child.collect(rest, found);
+        }
+    }
+
+    fn remove(&mut self, levels: &[Level]) -> Option<T> {
+        let (level, rest) = match levels.split_first() {
+            Some(split) => split,
+            None => return self.value.take(),
+        };
+        match *level {
+            Level::Multi => self.multi.take(),
+            Level::Single => {
+                let removed = self.single.as_mut().and_then(|child| child.remove(rest));
+                if self.single.as_ref().is_some_and(|child| child.is_empty()) {
+                    self.single = None;
+                }
+                removed
+            }
+            Level::Exact(ref name) => {
+                let removed = self
+                    .children
+                    .get_mut(name)
+                    .and_then(|child| child.remove(rest));
+                if self
+                    .children
+                    .get(name)
+                    .is_some_and(|child| child.is_empty())
+                {
+                    self.children.remove(name);
+                }
+                removed
+            }
+        }
+    }
+}
+
+impl<T> Default for TopicTree<T> {
+    fn default() -> Self {
+        TopicTree {
+            root: Node::default(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> TopicTree<T> {
+    /// Create an empty `TopicTree`.
+    pub fn new() -> Self {
+        TopicTree::default()
+    }
+
+    /// Returns the number of filters in the tree.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if the tree has no filters.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Insert the `value` for `filter`, returning the value that it replaced, if any.
+    pub fn insert(&mut self, filter: &str, value: T) -> Result<Option<T>, TopicError> {
+        let filter = TopicFilter::parse(filter)?;
+        let mut node = &mut self.root;
+        let mut slot = None;
+        for level in filter.levels() {
+            node = match *level {
+                Level::Exact(ref name) => node.children.entry(name.clone()).or_default(),
+                Level::Single => node.single.get_or_insert_with(Box::default),
+                Level::Multi => {
+                    slot = Some(&mut node.multi);
+                    break;
+                }
+            };
+        }
+        let slot = match slot {
+            Some(slot) => slot,
+            None => &mut node.value,
+        };
+        let replaced = slot.replace(value);
+        if replaced.is_none() {
+            self.len += 1;
+        }
+        Ok(replaced)
+    }
+
+    /// Remove `filter` from the tree, returning its value.
+    pub fn remove(&mut self, filter: &str) -> Result<Option<T>, TopicError> {
+        let filter = TopicFilter::parse(filter)?;
+        let removed = self.root.remove(filter.levels());
+        if removed.is_some() {
+            self.len -= 1;
+        }
+        Ok(removed)
+    }
+
+    /// Returns the values of every filter that matches `topic`.
+    pub fn matches(&self, topic: &str) -> Vec<&T> {
+        let levels: Vec<&str> = topic.split(SEPARATOR).collect();
+        let mut found = Vec::new();
+        self.root.collect(&levels, &mut found);
+        found
+    }
+
+    /// Returns true if any filter matches `topic`.
+    pub fn is_match(&self, topic: &str) -> bool {
+        !self.matches(topic).is_empty()
+    }
+}
+
+/// A `SUB` socket that subscribes to topic filters.
+///
+/// Messages are expected to carry their topic in the first frame. The socket is subscribed
+/// to the prefix of each filter, and messages whose topic does not match any filter are
+/// dropped when they are received.
+pub struct TopicSubscriber {
+    socket: zmq::Socket,
+    filters: TopicTree<TopicFilter>,
+}
+
+impl TopicSubscriber {
+    /// Create a new `TopicSubscriber`, with a `SUB` socket from `context`.
+    pub fn new(context: &zmq::Context) -> Result<TopicSubscriber, zmq::Error> {
+        Ok(TopicSubscriber::from_socket(context.socket(zmq::SUB)?))
+    }
+
+    /// Create a new `TopicSubscriber` from a `SUB` socket.
+    pub fn from_socket(socket: zmq::Socket) -> TopicSubscriber {
+        TopicSubscriber {
+            socket,
+            filters: TopicTree::new(),
+        }
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Returns the tree of filters that the subscriber matches against.
+    pub fn filters(&self) -> &TopicTree<TopicFilter> {
+        &self.filters
+    }
+
+    /// Subscribe to `filter`. Subscribing to the same filter twice has no effect.
+    pub fn subscribe(&mut self, filter: &str) -> io::Result<()> {
+        let parsed = TopicFilter::parse(filter)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        let prefix = parsed.prefix().to_string();
+        let replaced = self
+            .filters
+            .insert(filter, parsed)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        if replaced.is_none() {
+            self.socket.set_subscribe(prefix.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Unsubscribe from `filter`. Returns false if the subscriber was not subscribed to it.
+    pub fn unsubscribe(&mut self, filter: &str) -> io::Result<bool> {
+        let removed = self
+            .filters
+            .remove(filter)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+        match removed {
+            Some(parsed) => {
+                self.socket.set_unsubscribe(parsed.prefix().as_bytes())?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Receive the next message whose topic matches a filter. Messages that were only
+    /// matched by a subscription prefix are dropped.
+    pub fn recv_multipart(&self, flags: i32) -> io::Result<Vec<Vec<u8>>> {
+        loop {
+            let msg = self.socket.recv_multipart(flags)?;
+            let matched = msg
+                .first()
+                .and_then(|topic| ::std::str::from_utf8(topic).ok())
+                .is_some_and(|topic| self.filters.is_match(topic));
+            if matched {
+                return Ok(msg);
+            }
+            nlog!(trace, "dropped message with unmatched topic");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn filters_are_validated() {
+        assert_eq!(TopicFilter::parse(""), Err(TopicError::Empty));
+        assert!(TopicFilter::parse("sensors/#/temp").is_err());
+        assert!(TopicFilter::parse("sensors/room+/temp").is_err());
+        assert!(TopicFilter::parse("sensors/+/#").is_ok());
+        assert!(validate_topic("sensors/room1/temp").is_ok());
+        assert!(validate_topic("sensors/+/temp").is_err());
+    }
+
+    #[test]
+    fn filters_match_topics_by_level() {
+        let single = TopicFilter::parse("sensors/+/temp").unwrap();
+        assert!(single.matches("sensors/room1/temp"));
+        assert!(!single.matches("sensors/room1/humidity"));
+        assert!(!single.matches("sensors/room1/temp/celsius"));
+        assert_eq!(single.prefix(), "sensors/");
+
+        let multi = TopicFilter::parse("sensors/#").unwrap();
+        assert!(multi.matches("sensors"));
+        assert!(multi.matches("sensors/room1/temp"));
+        assert!(!multi.matches("sensorsX/room1"));
+        assert_eq!(multi.prefix(), "sensors");
+
+        let exact = TopicFilter::parse("sensors/room1").unwrap();
+        assert!(exact.is_exact());
+        assert_eq!(exact.prefix(), "sensors/room1");
+        assert_eq!(TopicFilter::parse("#").unwrap().prefix(), "");
+    }
+
+    #[test]
+    fn topic_trees_find_every_matching_filter() {
+        let mut tree = TopicTree::new();
+        tree.insert("sensors/room1/temp", 1).unwrap();
+        tree.insert("sensors/+/temp", 2).unwrap();
+        tree.insert("sensors/#", 3).unwrap();
+        tree.insert("actuators/#", 4).unwrap();
+        assert_eq!(tree.len(), 4);
+
+        let mut matched = tree.matches("sensors/room1/temp");
+        matched.sort();
+        assert_eq!(matched, vec![&1, &2, &3]);
+        assert_eq!(tree.matches("sensors/room2/temp").len(), 2);
+        assert_eq!(tree.matches("sensors"), vec![&3]);
+        assert!(!tree.is_match("lights/room1"));
+
+        assert_eq!(tree.remove("sensors/+/temp").unwrap(), Some(2));
+        assert_eq!(tree.remove("sensors/+/temp").unwrap(), None);
+        assert_eq!(tree.matches("sensors/room2/temp"), vec![&3]);
+        assert_eq!(tree.len(), 3);
+    }
+
+    #[test]
+    fn topic_subscribers_filter_on_the_client() {
+        let ctx = zmq::Context::new();
+        let publisher = ctx.socket(zmq::PUB).unwrap();
+        publisher.bind("inproc://topic_subscribers").unwrap();
+        let mut subscriber = TopicSubscriber::new(&ctx).unwrap();
+        subscriber.subscribe("sensors/+/temp").unwrap();
+        subscriber
+            .get_ref()
+            .connect("inproc://topic_subscribers")
+            .unwrap();
+        // let the subscription reach the publisher.
+        thread::sleep(Duration::from_millis(50));
+
+        publisher
+            .send_multipart(["sensors/room1/humidity", "40"], 0)
+            .unwrap();
+        publisher.send_multipart(["lights/room1", "on"], 0).unwrap();
+        publisher
+            .send_multipart(["sensors/room1/temp", "21"], 0)
+            .unwrap();
+        let msg = subscriber.recv_multipart(0).unwrap();
+        assert_eq!(msg, vec![b"sensors/room1/temp".to_vec(), b"21".to_vec()]);
+
+        assert!(subscriber.unsubscribe("sensors/+/temp").unwrap());
+        assert!(!subscriber.unsubscribe("sensors/+/temp").unwrap());
+    }
+}
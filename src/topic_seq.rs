@@ -0,0 +1,277 @@
+//! Sequenced topics, to detect lost messages.
+//!
+//! A `SeqPublisher` sends every message as `[topic, sequence, frames...]`, where the
+//! sequence is a big-endian `u64` that starts at 1, and grows by one on every message of
+//! the same topic. A `SeqSubscriber` keeps track of the next sequence that it expects on
+//! each topic, and reports a `SeqEvent::GapDetected` when messages were lost, e.g. because
+//! the subscriber joined late, or because the high-water mark was reached, so that it can
+//! ask for the current state, and resync.
+use super::{validate_topic, TopicError, TopicSubscriber};
+
+use failure::Error;
+use std::collections::HashMap;
+use zmq;
+
+/// A message received on a sequenced topic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SeqMessage {
+    pub topic: String,
+    pub sequence: u64,
+    pub frames: Vec<Vec<u8>>,
+}
+
+impl SeqMessage {
+    /// Parse a message from its frames.
+    pub fn from_frames(mut frames: Vec<Vec<u8>>) -> Result<SeqMessage, TopicError> {
+        if frames.len() < 2 || frames[1].len() != 8 {
+            return Err(TopicError::InvalidMessage);
+        }
+        let rest = frames.split_off(2);
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&frames[1]);
+        let topic =
+            String::from_utf8(frames.swap_remove(0)).map_err(|_| TopicError::InvalidMessage)?;
+        Ok(SeqMessage {
+            topic,
+            sequence: u64::from_be_bytes(bytes),
+            frames: rest,
+        })
+    }
+}
+
+/// Events from a `SeqSubscriber`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SeqEvent {
+    /// A message, in sequence.
+    Message(SeqMessage),
+    /// The message on `topic` with the `expected` sequence was not received, `got` was
+    /// received instead. The message that was received is returned on the next `recv`.
+    GapDetected {
+        topic: String,
+        expected: u64,
+        got: u64,
+    },
+}
+
+/// A `PUB` socket that numbers the messages of each topic.
+pub struct SeqPublisher {
+    socket: zmq::Socket,
+    sequences: HashMap<String, u64>,
+}
+
+impl SeqPublisher {
+    /// Create a new `SeqPublisher`, with a `PUB` socket from `context`.
+    pub fn new(context: &zmq::Context) -> Result<SeqPublisher, Error> {
+        Ok(SeqPublisher::from_socket(context.socket(zmq::PUB)?))
+    }
+
+    /// Create a new `SeqPublisher` from a `PUB`, or `XPUB`, socket.
+    pub fn from_socket(socket: zmq::Socket) -> SeqPublisher {
+        SeqPublisher {
+            socket,
+            sequences: HashMap::new(),
+        }
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Returns the sequence of the last message sent on `topic`, or 0 if there was none.
+    pub fn sequence(&self, topic: &str) -> u64 {
+        self.sequences.get(topic).cloned().unwrap_or(0)
+    }
+
+    /// Send `frames` on `topic`, returning the sequence that the message was given.
+    pub fn send<I, T>(&mut self, topic: &str, frames: I) -> Result<u64, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        validate_topic(topic)?;
+        let sequence = self.sequence(topic) + 1;
+        let mut msg = vec![topic.as_bytes().to_vec(), sequence.to_be_bytes().to_vec()];
+        msg.extend(frames.into_iter().map(|f| f.into()));
+        self.socket.send_multipart(msg, 0)?;
+        self.sequences.insert(topic.to_string(), sequence);
+        Ok(sequence)
+    }
+}
+
+/// A `SUB` socket that detects gaps in the sequences of the topics that it receives.
+pub struct SeqSubscriber {
+    subscriber: TopicSubscriber,
+    expected: HashMap<String, u64>,
+    pending: Option<SeqMessage>,
+}
+
+impl SeqSubscriber {
+    /// Create a new `SeqSubscriber`, with a `SUB` socket from `context`.
+    pub fn new(context: &zmq::Context) -> Result<SeqSubscriber, Error> {
+        Ok(SeqSubscriber::from_subscriber(TopicSubscriber::new(
+            context,
+        )?))
+    }
+
+    /// Create a new `SeqSubscriber` from a `TopicSubscriber`.
+    pub fn from_subscriber(subscriber: TopicSubscriber) -> SeqSubscriber {
+        SeqSubscriber {
+            subscriber,
+            expected: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    /// Returns a reference to the inner socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        self.subscriber.get_ref()
+    }
+
+    /// Subscribe to `filter`.
+    pub fn subscribe(&mut self, filter: &str) -> Result<(), Error> {
+        Ok(self.subscriber.subscribe(filter)?)
+    }
+
+    /// Unsubscribe from `filter`.
+    pub fn unsubscribe(&mut self, filter: &str) -> Result<bool, Error> {
+        Ok(self.subscriber.unsubscribe(filter)?)
+    }
+
+    /// Returns the sequence expected next on `topic`, if any message was received on it.
+    pub fn expected(&self, topic: &str) -> Option<u64> {
+        self.expected.get(topic).cloned()
+    }
+
+    /// Set the sequence of the last message applied on `topic`, e.g. from a state
+    /// snapshot, after a gap was detected. Messages up to `sequence` are then ignored.
+    pub fn resync(&mut self, topic: &str, sequence: u64) {
+        self.expected.insert(topic.to_string(), sequence + 1);
+    }
+
+    /// Forget the sequence of `topic`, so that the next message on it is accepted as is.
+    pub fn reset(&mut self, topic: &str) {
+        self.expected.remove(topic);
+    }
+
+    /// Receive the next event. The first message of a topic is always in sequence.
+    pub fn recv(&mut self, flags: i32) -> Result<SeqEvent, Error> {
+        loop {
+            let msg = match self.pending.take() {
+                Some(msg) => msg,
+                None => SeqMessage::from_frames(self.subscriber.recv_multipart(flags)?)?,
+            };
+            let expected = match self.expected.get(&msg.topic) {
+                Some(&expected) => expected,
+                None => msg.sequence,
+            };
+            if msg.sequence == expected {
+                self.expected.insert(msg.topic.clone(), msg.sequence + 1);
+                return Ok(SeqEvent::Message(msg));
+            }
+            if msg.sequence > expected {
+                let gap = SeqEvent::GapDetected {
+                    topic: msg.topic.clone(),
+                    expected,
+                    got: msg.sequence,
+                };
+                nlog!(
+                    debug,
+                    "gap detected topic={} expected={} got={}",
+                    msg.topic,
+                    expected,
+                    msg.sequence
+                );
+                // Accept the message that revealed the gap on the next call.
+                self.expected.insert(msg.topic.clone(), msg.sequence);
+                self.pending = Some(msg);
+                return Ok(gap);
+            }
+            // Already applied, e.g. older than the state that the subscriber resynced to. A
+            // publisher that restarts its sequences needs a `reset` of its topics.
+            nlog!(
+                trace,
+                "dropped stale message topic={} sequence={}",
+                msg.topic,
+                msg.sequence
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn setup(endpoint: &str, ctx: &zmq::Context) -> (SeqPublisher, SeqSubscriber) {
+        let publisher = SeqPublisher::new(ctx).unwrap();
+        publisher.get_ref().bind(endpoint).unwrap();
+        let mut subscriber = SeqSubscriber::new(ctx).unwrap();
+        subscriber.subscribe("sensors/#").unwrap();
+        subscriber.get_ref().connect(endpoint).unwrap();
+        // let the subscription reach the publisher.
+        thread::sleep(Duration::from_millis(50));
+        (publisher, subscriber)
+    }
+
+    #[test]
+    fn sequences_are_kept_per_topic() {
+        let ctx = zmq::Context::new();
+        let (mut publisher, mut subscriber) = setup("inproc://seq_per_topic", &ctx);
+        assert_eq!(publisher.send("sensors/temp", vec!["21"]).unwrap(), 1);
+        assert_eq!(publisher.send("sensors/humidity", vec!["40"]).unwrap(), 1);
+        assert_eq!(publisher.send("sensors/temp", vec!["22"]).unwrap(), 2);
+        assert!(publisher.send("sensors/+", vec!["0"]).is_err());
+
+        for _ in 0..3 {
+            match subscriber.recv(0).unwrap() {
+                SeqEvent::Message(_) => {}
+                gap => panic!("unexpected {:?}", gap),
+            }
+        }
+        assert_eq!(subscriber.expected("sensors/temp"), Some(3));
+        assert_eq!(subscriber.expected("sensors/humidity"), Some(2));
+    }
+
+    #[test]
+    fn gaps_are_detected_and_followed_by_the_message() {
+        let ctx = zmq::Context::new();
+        let (mut publisher, mut subscriber) = setup("inproc://seq_gaps", &ctx);
+        publisher.send("sensors/temp", vec!["20"]).unwrap();
+        subscriber.recv(0).unwrap();
+        // messages 2, and 3, are lost.
+        publisher.sequences.insert("sensors/temp".into(), 3);
+        publisher.send("sensors/temp", vec!["23"]).unwrap();
+
+        assert_eq!(
+            subscriber.recv(0).unwrap(),
+            SeqEvent::GapDetected {
+                topic: "sensors/temp".into(),
+                expected: 2,
+                got: 4,
+            }
+        );
+        match subscriber.recv(0).unwrap() {
+            SeqEvent::Message(msg) => {
+                assert_eq!(msg.sequence, 4);
+                assert_eq!(msg.frames, vec![b"23".to_vec()]);
+            }
+            gap => panic!("unexpected {:?}", gap),
+        }
+    }
+
+    #[test]
+    fn resynced_subscribers_drop_stale_messages() {
+        let ctx = zmq::Context::new();
+        let (mut publisher, mut subscriber) = setup("inproc://seq_resync", &ctx);
+        subscriber.resync("sensors/temp", 1);
+        publisher.send("sensors/temp", vec!["20"]).unwrap();
+        publisher.send("sensors/temp", vec!["21"]).unwrap();
+        match subscriber.recv(0).unwrap() {
+            SeqEvent::Message(msg) => assert_eq!(msg.sequence, 2),
+            gap => panic!("unexpected {:?}", gap),
+        }
+    }
+}
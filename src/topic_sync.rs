@@ -0,0 +1,215 @@
+//! Synchronized publishers, and subscribers, that do not lose the first messages.
+//!
+//! A `SUB` socket that connects to a publisher only receives the messages that are sent
+//! after its subscriptions reach the publisher, so the first messages of a publisher that
+//! starts broadcasting right away are silently lost by slow joiners. A `SyncPub` instead
+//! waits, on a `REP` socket, until the expected number of `SyncSub` have confirmed that
+//! they are subscribed, as in the "node coordination" pattern of the zguide.
+//!
+//! While waiting, the publisher keeps publishing `SYNC_TOPIC` messages. A subscriber only
+//! confirms once it receives one of them, which proves that its subscriptions reached the
+//! publisher. Subscribers drop those messages, but other `SUB` sockets subscribed to an
+//! empty prefix receive them too.
+use super::super::clock::Deadline;
+use super::TopicError;
+
+use failure::Error;
+use zmq;
+
+/// Topic of the messages published while waiting for subscribers.
+pub const SYNC_TOPIC: &str = "$SYNC";
+/// Milliseconds between the messages published while waiting for subscribers.
+pub const SYNC_INTERVAL: i64 = 10;
+
+const SYNC_REQUEST: &[u8] = b"$SYNC";
+const SYNC_REPLY: &[u8] = b"$SYNCED";
+
+/// A `PUB` socket that waits for its subscribers before broadcasting.
+pub struct SyncPub {
+    socket: zmq::Socket,
+    sync: zmq::Socket,
+    expected: usize,
+    synced: usize,
+}
+
+impl SyncPub {
+    /// Create a new `SyncPub`, that waits for `expected` subscribers.
+    pub fn new(context: &zmq::Context, expected: usize) -> Result<SyncPub, Error> {
+        Ok(SyncPub {
+            socket: context.socket(zmq::PUB)?,
+            sync: context.socket(zmq::REP)?,
+            expected,
+            synced: 0,
+        })
+    }
+
+    /// Returns a reference to the inner `PUB` socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Bind the `PUB` socket to `endpoint`.
+    pub fn bind(&self, endpoint: &str) -> Result<(), Error> {
+        Ok(self.socket.bind(endpoint)?)
+    }
+
+    /// Bind the `REP` socket, where subscribers confirm their subscriptions, to `endpoint`.
+    pub fn bind_sync(&self, endpoint: &str) -> Result<(), Error> {
+        Ok(self.sync.bind(endpoint)?)
+    }
+
+    /// Returns the number of subscribers that confirmed their subscriptions.
+    pub fn synced(&self) -> usize {
+        self.synced
+    }
+
+    /// Wait up to `timeout` milliseconds, or forever if it is `-1`, until the expected
+    /// number of subscribers confirmed their subscriptions.
+    pub fn wait_for_subscribers(&mut self, timeout: i64) -> Result<(), Error> {
+        let deadline = Deadline::after(timeout);
+        while self.synced < self.expected {
+            self.socket.send(SYNC_TOPIC, 0)?;
+            let wait = match deadline.remaining() {
+                -1 => SYNC_INTERVAL,
+                left => left.min(SYNC_INTERVAL),
+            };
+            if self.sync.poll(zmq::POLLIN, wait)? > 0 {
+                let request = self.sync.recv_bytes(0)?;
+                self.sync.send(SYNC_REPLY, 0)?;
+                if request == SYNC_REQUEST {
+                    self.synced += 1;
+                    nlog!(debug, "subscriber synced {}/{}", self.synced, self.expected);
+                }
+            } else if deadline.is_expired() {
+                return Err(TopicError::SyncTimeout(self.synced, self.expected).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Send a multipart message to the subscribers.
+    pub fn send_multipart<I, T>(&self, frames: I, flags: i32) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<zmq::Message>,
+    {
+        Ok(self.socket.send_multipart(frames, flags)?)
+    }
+}
+
+/// A `SUB` socket that confirms its subscriptions to a `SyncPub`.
+pub struct SyncSub {
+    socket: zmq::Socket,
+    context: zmq::Context,
+}
+
+impl SyncSub {
+    /// Create a new `SyncSub`, with a `SUB` socket from `context`.
+    pub fn new(context: &zmq::Context) -> Result<SyncSub, Error> {
+        let socket = context.socket(zmq::SUB)?;
+        socket.set_subscribe(SYNC_TOPIC.as_bytes())?;
+        Ok(SyncSub {
+            socket,
+            context: context.clone(),
+        })
+    }
+
+    /// Returns a reference to the inner `SUB` socket.
+    pub fn get_ref(&self) -> &zmq::Socket {
+        &self.socket
+    }
+
+    /// Connect the `SUB` socket to the publisher at `endpoint`.
+    pub fn connect(&self, endpoint: &str) -> Result<(), Error> {
+        Ok(self.socket.connect(endpoint)?)
+    }
+
+    /// Subscribe to messages starting with `prefix`.
+    pub fn subscribe(&self, prefix: &[u8]) -> Result<(), Error> {
+        Ok(self.socket.set_subscribe(prefix)?)
+    }
+
+    /// Confirm the subscriptions to the publisher, whose `REP` socket is at
+    /// `sync_endpoint`, once they reached it. Waits up to `timeout` milliseconds, or
+    /// forever if it is `-1`. Subscriptions made afterwards are not confirmed.
+    pub fn sync(&self, sync_endpoint: &str, timeout: i64) -> Result<(), Error> {
+        let deadline = Deadline::after(timeout);
+        // the publisher is reached once one of its sync messages arrives.
+        loop {
+            if self.socket.poll(zmq::POLLIN, deadline.remaining())? == 0 {
+                return Err(TopicError::SyncTimeout(0, 1).into());
+            }
+            if self.socket.recv_bytes(0)? == SYNC_TOPIC.as_bytes() {
+                break;
+            }
+        }
+        let sync = self.context.socket(zmq::REQ)?;
+        sync.set_linger(0)?;
+        sync.connect(sync_endpoint)?;
+        sync.send(SYNC_REQUEST, 0)?;
+        if sync.poll(zmq::POLLIN, deadline.remaining())? == 0 {
+            return Err(TopicError::SyncTimeout(0, 1).into());
+        }
+        sync.recv_bytes(0)?;
+        Ok(())
+    }
+
+    /// Receive the next multipart message, dropping the sync messages of the publisher.
+    pub fn recv_multipart(&self, flags: i32) -> Result<Vec<Vec<u8>>, Error> {
+        loop {
+            let frames = self.socket.recv_multipart(flags)?;
+            if frames.len() != 1 || frames[0] != SYNC_TOPIC.as_bytes() {
+                return Ok(frames);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn subscribers_receive_the_first_message() {
+        let ctx = zmq::Context::new();
+        let mut publisher = SyncPub::new(&ctx, 2).unwrap();
+        publisher.bind("inproc://sync_pub").unwrap();
+        publisher.bind_sync("inproc://sync_pub_rep").unwrap();
+
+        let subscribers: Vec<_> = (0..2)
+            .map(|_| {
+                let ctx = ctx.clone();
+                thread::spawn(move || {
+                    let subscriber = SyncSub::new(&ctx).unwrap();
+                    subscriber.subscribe(b"data").unwrap();
+                    subscriber.connect("inproc://sync_pub").unwrap();
+                    subscriber.sync("inproc://sync_pub_rep", 1_000).unwrap();
+                    subscriber.recv_multipart(0).unwrap()
+                })
+            })
+            .collect();
+
+        publisher.wait_for_subscribers(1_000).unwrap();
+        assert_eq!(publisher.synced(), 2);
+        publisher.send_multipart(["data", "first"], 0).unwrap();
+        for subscriber in subscribers {
+            assert_eq!(
+                subscriber.join().unwrap(),
+                vec![b"data".to_vec(), b"first".to_vec()]
+            );
+        }
+    }
+
+    #[test]
+    fn publishers_time_out_without_subscribers() {
+        let ctx = zmq::Context::new();
+        let mut publisher = SyncPub::new(&ctx, 1).unwrap();
+        publisher.bind_sync("inproc://sync_pub_alone").unwrap();
+        let err = publisher.wait_for_subscribers(30).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<TopicError>(),
+            Some(&TopicError::SyncTimeout(0, 1))
+        );
+    }
+}
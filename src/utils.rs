@@ -1,4 +1,21 @@
 //! Helpful utilities.
+#[cfg(unix)]
+#[path = "utils_signal.rs"]
+mod signal;
+
+#[cfg(unix)]
+pub use self::signal::SignalCatcher;
+
+#[path = "utils_runtime.rs"]
+mod runtime;
+#[path = "utils_sockopts.rs"]
+mod sockopts;
+
+pub use self::runtime::{
+    require_capability, zmq_runtime_info, Capability, UnsupportedCapability, ZmqRuntimeInfo,
+};
+pub use self::sockopts::{socket_options_snapshot, OptionChange, SocketOptions};
+
 use std::io;
 use std::thread;
 
@@ -0,0 +1,145 @@
+//! Version, and capabilities, of the libzmq that the crate is linked to.
+//!
+//! Optional features of libzmq, e.g. CURVE security, or multicast transports, depend on how
+//! it was built. Modules that need one of them `require` it first, so that they fail with
+//! an `UnsupportedCapability` error, instead of with whatever libzmq returns when the
+//! feature is missing.
+use std::fmt;
+use zmq;
+
+/// Optional features of libzmq.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    /// CURVE security, and key generation.
+    Curve,
+    /// `ipc://` transports.
+    Ipc,
+    /// `pgm://`, and `epgm://`, multicast transports.
+    Pgm,
+    /// `norm://` multicast transports.
+    Norm,
+    /// `tipc://` transports.
+    Tipc,
+    /// GSSAPI security.
+    Gssapi,
+    /// The draft API, e.g. `udp://` transports, and RADIO/DISH sockets.
+    Draft,
+}
+
+impl Capability {
+    /// Returns the name of the capability, as known by `zmq_has`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Curve => "curve",
+            Capability::Ipc => "ipc",
+            Capability::Pgm => "pgm",
+            Capability::Norm => "norm",
+            Capability::Tipc => "tipc",
+            Capability::Gssapi => "gssapi",
+            Capability::Draft => "draft",
+        }
+    }
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Error of a capability that the local libzmq lacks.
+#[derive(Debug, Fail)]
+#[fail(display = "libzmq {}.{}.{} does not support {}", _1, _2, _3, _0)]
+pub struct UnsupportedCapability(pub Capability, pub i32, pub i32, pub i32);
+
+/// Version, and capabilities, of the local libzmq.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ZmqRuntimeInfo {
+    /// Major, minor, and patch version.
+    pub version: (i32, i32, i32),
+    pub curve: bool,
+    pub ipc: bool,
+    pub pgm: bool,
+    pub norm: bool,
+    pub tipc: bool,
+    pub gssapi: bool,
+    pub draft: bool,
+}
+
+impl ZmqRuntimeInfo {
+    /// Returns true if the local libzmq has `capability`.
+    pub fn supports(&self, capability: Capability) -> bool {
+        match capability {
+            Capability::Curve => self.curve,
+            Capability::Ipc => self.ipc,
+            Capability::Pgm => self.pgm,
+            Capability::Norm => self.norm,
+            Capability::Tipc => self.tipc,
+            Capability::Gssapi => self.gssapi,
+            Capability::Draft => self.draft,
+        }
+    }
+
+    /// Returns an `UnsupportedCapability` error if the local libzmq lacks `capability`.
+    pub fn require(&self, capability: Capability) -> Result<(), UnsupportedCapability> {
+        if self.supports(capability) {
+            return Ok(());
+        }
+        let (major, minor, patch) = self.version;
+        Err(UnsupportedCapability(capability, major, minor, patch))
+    }
+}
+
+/// Returns the version, and capabilities, of the local libzmq.
+pub fn zmq_runtime_info() -> ZmqRuntimeInfo {
+    let has = |capability: Capability| zmq::has(capability.name()).unwrap_or(false);
+    ZmqRuntimeInfo {
+        version: zmq::version(),
+        curve: has(Capability::Curve),
+        ipc: has(Capability::Ipc),
+        pgm: has(Capability::Pgm),
+        norm: has(Capability::Norm),
+        tipc: has(Capability::Tipc),
+        gssapi: has(Capability::Gssapi),
+        draft: has(Capability::Draft),
+    }
+}
+
+/// Returns an `UnsupportedCapability` error if the local libzmq lacks `capability`.
+pub fn require_capability(capability: Capability) -> Result<(), UnsupportedCapability> {
+    zmq_runtime_info().require(capability)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_capabilities_are_reported_with_the_version() {
+        let info = zmq_runtime_info();
+        assert_eq!(info.version, zmq::version());
+        let (major, minor, patch) = info.version;
+        let capabilities = [
+            Capability::Curve,
+            Capability::Ipc,
+            Capability::Pgm,
+            Capability::Norm,
+            Capability::Tipc,
+            Capability::Gssapi,
+            Capability::Draft,
+        ];
+        for &capability in capabilities.iter() {
+            match info.require(capability) {
+                Ok(()) => assert!(info.supports(capability)),
+                Err(e) => {
+                    assert!(!info.supports(capability));
+                    let expected = format!(
+                        "libzmq {}.{}.{} does not support {}",
+                        major, minor, patch, capability
+                    );
+                    assert_eq!(e.to_string(), expected);
+                }
+            }
+        }
+    }
+}
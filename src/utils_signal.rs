@@ -0,0 +1,166 @@
+//! Catching process signals, on unix.
+use super::super::actor::ShutdownController;
+
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use mio_lib::unix::EventedFd;
+use mio_lib::{Evented, Poll, PollOpt, Ready, Token};
+use signal_hook::{self, SigId};
+use zmq;
+
+/// Catches process signals without the `async-tokio` feature.
+///
+/// Uses the self-pipe trick: signal handlers write a byte into a non-blocking pipe, whose
+/// read end can be polled with `zmq::poll` (see `SignalCatcher::as_poll_item`), or registered
+/// with `mio::Poll`, e.g. in a `Poller`. When a `ShutdownController` is set, catching a
+/// signal also signals it to shut down.
+pub struct SignalCatcher {
+    reader: UnixStream,
+    // kept alive, so the signal handlers have somewhere to write.
+    _writer: UnixStream,
+    last: Arc<AtomicUsize>,
+    ids: Vec<SigId>,
+    controller: Option<Arc<ShutdownController>>,
+}
+
+impl SignalCatcher {
+    /// Create a new `SignalCatcher` for `SIGINT` and `SIGTERM`.
+    pub fn new() -> io::Result<SignalCatcher> {
+        SignalCatcher::with_signals(&[signal_hook::SIGINT, signal_hook::SIGTERM])
+    }
+
+    /// Create a new `SignalCatcher` for the given signals.
+    pub fn with_signals(signals: &[i32]) -> io::Result<SignalCatcher> {
+        let (reader, writer) = UnixStream::pair()?;
+        reader.set_nonblocking(true)?;
+        writer.set_nonblocking(true)?;
+        let last = Arc::new(AtomicUsize::new(0));
+        let mut ids = Vec::new();
+        for &signal in signals {
+            ids.push(signal_hook::flag::register_usize(
+                signal,
+                last.clone(),
+                signal as usize,
+            )?);
+            ids.push(signal_hook::pipe::register_raw(signal, writer.as_raw_fd())?);
+        }
+        Ok(SignalCatcher {
+            reader,
+            _writer: writer,
+            last,
+            ids,
+            controller: None,
+        })
+    }
+
+    /// Set a `ShutdownController` to be signaled whenever a signal is caught.
+    pub fn set_controller(&mut self, controller: Arc<ShutdownController>) {
+        self.controller = Some(controller);
+    }
+
+    /// Return the `RawFd` that becomes readable when a signal is caught.
+    pub fn as_fd(&self) -> RawFd {
+        self.reader.as_raw_fd()
+    }
+
+    /// Return a `zmq::PollItem` for the signal pipe, to be used along with sockets in
+    /// `zmq::poll`.
+    pub fn as_poll_item(&self) -> zmq::PollItem<'_> {
+        zmq::PollItem::from_fd(self.as_fd(), zmq::POLLIN)
+    }
+
+    /// Check for caught signals without blocking. Returns the last signal caught since the
+    /// previous check, if any.
+    pub fn try_recv(&self) -> io::Result<Option<i32>> {
+        let mut buf = [0u8; 32];
+        let mut caught = false;
+        loop {
+            match (&self.reader).read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => caught = true,
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        if !caught {
+            return Ok(None);
+        }
+        if let Some(ref controller) = self.controller {
+            controller.signal();
+        }
+        Ok(Some(self.last.swap(0, Ordering::SeqCst) as i32))
+    }
+
+    /// Wait up to `timeout` milliseconds (-1 to wait forever) for a signal.
+    pub fn wait(&self, timeout: i64) -> io::Result<Option<i32>> {
+        let mut items = [self.as_poll_item()];
+        zmq::poll(&mut items, timeout)?;
+        self.try_recv()
+    }
+}
+
+impl Drop for SignalCatcher {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            signal_hook::unregister(id);
+        }
+    }
+}
+
+/// Implementation of the external `mio::Evented` API for signal catchers.
+impl Evented for SignalCatcher {
+    fn register(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.as_fd()).register(poll, token, interest, opts)
+    }
+
+    fn reregister(
+        &self,
+        poll: &Poll,
+        token: Token,
+        interest: Ready,
+        opts: PollOpt,
+    ) -> io::Result<()> {
+        EventedFd(&self.as_fd()).reregister(poll, token, interest, opts)
+    }
+
+    fn deregister(&self, poll: &Poll) -> io::Result<()> {
+        EventedFd(&self.as_fd()).deregister(poll)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use libc;
+
+    #[test]
+    fn signal_catcher_is_quiet_without_signals() {
+        let catcher = SignalCatcher::with_signals(&[libc::SIGUSR1]).unwrap();
+        assert_eq!(catcher.try_recv().unwrap(), None);
+    }
+
+    #[test]
+    fn signal_catcher_reports_caught_signal_and_triggers_controller() {
+        let mut catcher = SignalCatcher::with_signals(&[libc::SIGUSR2]).unwrap();
+        let controller = Arc::new(ShutdownController::new());
+        let token = controller.token();
+        catcher.set_controller(controller);
+        unsafe {
+            libc::raise(libc::SIGUSR2);
+        }
+        assert_eq!(catcher.wait(1_000).unwrap(), Some(libc::SIGUSR2));
+        assert!(token.is_shutdown());
+        assert_eq!(catcher.try_recv().unwrap(), None);
+    }
+}
@@ -0,0 +1,186 @@
+//! Snapshots of socket options, for debugging.
+//!
+//! `socket_options_snapshot` reads the options that usually explain why sockets do not
+//! connect, or do not deliver, e.g. their security mechanism, high-water marks, and last
+//! endpoint. Snapshots are displayed as `name=value` pairs, and `SocketOptions::diff` tells
+//! the options that differ between two snapshots, e.g. of the two ends of a connection.
+use std::fmt;
+use zmq;
+
+/// The options of a socket, as read by `socket_options_snapshot`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct SocketOptions {
+    pub socket_type: String,
+    /// The identity, which is not valid UTF-8 for generated identities.
+    pub identity: String,
+    /// The security mechanism, i.e. `NULL`, `PLAIN`, `CURVE`, or `GSSAPI`.
+    pub mechanism: String,
+    pub curve_server: bool,
+    pub plain_server: bool,
+    pub zap_domain: String,
+    /// The endpoint that the socket was last bound, or connected, to.
+    pub last_endpoint: Option<String>,
+    pub sndhwm: i32,
+    pub rcvhwm: i32,
+    pub linger: i32,
+    pub sndtimeo: i32,
+    pub rcvtimeo: i32,
+    pub reconnect_ivl: i32,
+    pub reconnect_ivl_max: i32,
+    pub connect_timeout: i32,
+    pub handshake_ivl: i32,
+    pub heartbeat_ivl: i32,
+    pub backlog: i32,
+    pub maxmsgsize: i64,
+    pub ipv6: bool,
+    pub immediate: bool,
+    pub affinity: u64,
+}
+
+/// An option whose value differs between two snapshots.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OptionChange {
+    pub name: &'static str,
+    pub before: String,
+    pub after: String,
+}
+
+impl fmt::Display for OptionChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {} -> {}", self.name, self.before, self.after)
+    }
+}
+
+impl SocketOptions {
+    /// Returns the options whose values differ in `other`.
+    pub fn diff(&self, other: &SocketOptions) -> Vec<OptionChange> {
+        self.entries()
+            .into_iter()
+            .zip(other.entries())
+            .filter(|&((_, ref before), (_, ref after))| before != after)
+            .map(|((name, before), (_, after))| OptionChange {
+                name,
+                before,
+                after,
+            })
+            .collect()
+    }
+
+    // The name, and displayed value, of every option.
+    fn entries(&self) -> Vec<(&'static str, String)> {
+        let endpoint = self.last_endpoint.as_ref().map_or("-", String::as_str);
+        vec![
+            ("socket_type", self.socket_type.clone()),
+            ("identity", format!("{:?}", self.identity)),
+            ("mechanism", self.mechanism.clone()),
+            ("curve_server", self.curve_server.to_string()),
+            ("plain_server", self.plain_server.to_string()),
+            ("zap_domain", format!("{:?}", self.zap_domain)),
+            ("last_endpoint", endpoint.to_string()),
+            ("sndhwm", self.sndhwm.to_string()),
+            ("rcvhwm", self.rcvhwm.to_string()),
+            ("linger", self.linger.to_string()),
+            ("sndtimeo", self.sndtimeo.to_string()),
+            ("rcvtimeo", self.rcvtimeo.to_string()),
+            ("reconnect_ivl", self.reconnect_ivl.to_string()),
+            ("reconnect_ivl_max", self.reconnect_ivl_max.to_string()),
+            ("connect_timeout", self.connect_timeout.to_string()),
+            ("handshake_ivl", self.handshake_ivl.to_string()),
+            ("heartbeat_ivl", self.heartbeat_ivl.to_string()),
+            ("backlog", self.backlog.to_string()),
+            ("maxmsgsize", self.maxmsgsize.to_string()),
+            ("ipv6", self.ipv6.to_string()),
+            ("immediate", self.immediate.to_string()),
+            ("affinity", self.affinity.to_string()),
+        ]
+    }
+}
+
+impl fmt::Display for SocketOptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entries: Vec<String> = self
+            .entries()
+            .into_iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        write!(f, "{}", entries.join(" "))
+    }
+}
+
+/// Take a snapshot of the options of `socket`.
+pub fn socket_options_snapshot(socket: &zmq::Socket) -> Result<SocketOptions, zmq::Error> {
+    let mechanism = match socket.get_mechanism()? {
+        zmq::Mechanism::ZMQ_NULL => "NULL",
+        zmq::Mechanism::ZMQ_PLAIN => "PLAIN",
+        zmq::Mechanism::ZMQ_CURVE => "CURVE",
+        zmq::Mechanism::ZMQ_GSSAPI => "GSSAPI",
+    };
+    let last_endpoint = lossy(socket.get_last_endpoint()?);
+    Ok(SocketOptions {
+        socket_type: format!("{:?}", socket.get_socket_type()?),
+        identity: String::from_utf8_lossy(&socket.get_identity()?).to_string(),
+        mechanism: mechanism.to_string(),
+        curve_server: socket.is_curve_server()?,
+        plain_server: socket.is_plain_server()?,
+        zap_domain: lossy(socket.get_zap_domain()?),
+        last_endpoint: if last_endpoint.is_empty() {
+            None
+        } else {
+            Some(last_endpoint)
+        },
+        sndhwm: socket.get_sndhwm()?,
+        rcvhwm: socket.get_rcvhwm()?,
+        linger: socket.get_linger()?,
+        sndtimeo: socket.get_sndtimeo()?,
+        rcvtimeo: socket.get_rcvtimeo()?,
+        reconnect_ivl: socket.get_reconnect_ivl()?,
+        reconnect_ivl_max: socket.get_reconnect_ivl_max()?,
+        connect_timeout: socket.get_connect_timeout()?,
+        handshake_ivl: socket.get_handshake_ivl()?,
+        heartbeat_ivl: socket.get_heartbeat_ivl()?,
+        backlog: socket.get_backlog()?,
+        maxmsgsize: socket.get_maxmsgsize()?,
+        ipv6: socket.is_ipv6()?,
+        immediate: socket.is_immediate()?,
+        affinity: socket.get_affinity()?,
+    })
+}
+
+fn lossy(value: Result<String, Vec<u8>>) -> String {
+    value.unwrap_or_else(|bytes| String::from_utf8_lossy(&bytes).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshots_tell_what_differs_between_sockets() {
+        let ctx = zmq::Context::new();
+        let server = ctx.socket(zmq::ROUTER).unwrap();
+        server.set_sndhwm(10).unwrap();
+        server.set_zap_domain("global").unwrap();
+        server.bind("inproc://sockopts").unwrap();
+        let client = ctx.socket(zmq::DEALER).unwrap();
+        client.set_identity(b"client").unwrap();
+
+        let before = socket_options_snapshot(&client).unwrap();
+        assert_eq!(before.last_endpoint, None);
+        client.connect("inproc://sockopts").unwrap();
+        let after = socket_options_snapshot(&client).unwrap();
+        let changes = after.diff(&before);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(
+            changes[0].to_string(),
+            "last_endpoint: inproc://sockopts -> -"
+        );
+
+        let server = socket_options_snapshot(&server).unwrap();
+        assert!(server.to_string().contains("sndhwm=10 "));
+        let names: Vec<&str> = after.diff(&server).iter().map(|c| c.name).collect();
+        assert_eq!(
+            names,
+            vec!["socket_type", "identity", "zap_domain", "sndhwm"]
+        );
+    }
+}
@@ -0,0 +1,432 @@
+//! Pools of worker threads, fed over `PUSH`/`PULL` sockets.
+//!
+//! A `WorkerPool` pushes jobs to its worker threads, which share its context, and pulls
+//! their results back on a sink, as in the "divide and conquer" pattern of the zguide. Each
+//! job is numbered, so that its result can be told apart from the others, which arrive in
+//! any order.
+//!
+//! A job whose handler panics is answered with `WorkError::WorkerPanicked`, and the worker
+//! goes on with the next job.
+//!
+//! ```
+//! extern crate neuras;
+//! extern crate zmq;
+//!
+//! use neuras::work::WorkerPool;
+//!
+//! # fn main() {
+//! let ctx = zmq::Context::new();
+//! let mut pool = WorkerPool::new(&ctx, 2, |job: Vec<Vec<u8>>| {
+//!     job.into_iter().map(|frame| frame.to_ascii_uppercase()).collect()
+//! })
+//! .unwrap();
+//! let id = pool.submit(vec!["hello"]).unwrap();
+//! assert_eq!(pool.recv(1_000).unwrap(), Some((id, vec![b"HELLO".to_vec()])));
+//! pool.shutdown(1_000).unwrap();
+//! # }
+//! ```
+use super::clock::Clock;
+use super::utils::run_named_thread;
+
+use failure::Error;
+use std::any::Any;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use uuid::Uuid;
+use zmq;
+
+const READY: &[u8] = b"$READY";
+const STOP: &[u8] = b"$STOP";
+const STATUS_OK: &[u8] = b"OK";
+const STATUS_PANIC: &[u8] = b"PANIC";
+
+/// Milliseconds that `WorkerPool::new` waits for its workers to be ready.
+pub const READY_TIMEOUT: i64 = 5_000;
+/// Milliseconds that a dropped `WorkerPool` waits for its workers to stop.
+pub const STOP_TIMEOUT: i64 = 1_000;
+
+/// The result of a job, with its id.
+pub type JobResult = (u64, Vec<Vec<u8>>);
+
+// Thread handle for a running worker.
+type WorkerThread = thread::JoinHandle<Result<(), Error>>;
+
+/// Worker pool Errors.
+#[derive(Debug, Fail, PartialEq)]
+pub enum WorkError {
+    #[fail(display = "worker {} panicked on job {}: {}", _0, _1, _2)]
+    WorkerPanicked(String, u64, String),
+    #[fail(display = "worker {} panicked: {}", _0, _1)]
+    WorkerDied(String, String),
+    #[fail(display = "{} jobs were not drained in time", _0)]
+    DrainTimeout(usize),
+    #[fail(display = "only {} of {} workers were ready in time", _0, _1)]
+    NotReady(usize, usize),
+    #[fail(display = "invalid job message")]
+    InvalidMessage,
+}
+
+// Describe the payload of a panic.
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(msg) = payload.downcast_ref::<&str>() {
+        msg.to_string()
+    } else if let Some(msg) = payload.downcast_ref::<String>() {
+        msg.clone()
+    } else {
+        "unknown panic".to_string()
+    }
+}
+
+// Split the id of a job, or of a result, from its frames.
+fn split_id(mut frames: Vec<Vec<u8>>) -> Result<JobResult, WorkError> {
+    if frames.is_empty() || frames[0].len() != 8 {
+        return Err(WorkError::InvalidMessage);
+    }
+    let rest = frames.split_off(1);
+    let mut id = [0u8; 8];
+    id.copy_from_slice(&frames[0]);
+    Ok((u64::from_be_bytes(id), rest))
+}
+
+/// A pool of worker threads, that run a handler on every job.
+///
+/// Dropping the pool stops its workers, and waits up to `STOP_TIMEOUT` for them.
+pub struct WorkerPool {
+    jobs: zmq::Socket,
+    results: zmq::Socket,
+    workers: Vec<(String, WorkerThread)>,
+    next_id: u64,
+    pending: usize,
+}
+
+impl WorkerPool {
+    /// Create a new `WorkerPool` of `size` threads, that run `handler` on every job, and
+    /// send back the frames it returns. Fails with the error of a worker that could not
+    /// start, or `WorkError::NotReady` if they are not ready within `READY_TIMEOUT`.
+    pub fn new<F>(context: &zmq::Context, size: usize, handler: F) -> Result<WorkerPool, Error>
+    where
+        F: Fn(Vec<Vec<u8>>) -> Vec<Vec<u8>> + Send + Sync + 'static,
+    {
+        let uuid = Uuid::new_v4().to_simple();
+        let jobs_addr = format!("inproc://neuras.work.{}.jobs", uuid);
+        let results_addr = format!("inproc://neuras.work.{}.results", uuid);
+        let jobs = context.socket(zmq::PUSH)?;
+        jobs.set_linger(0)?;
+        jobs.bind(&jobs_addr)?;
+        let results = context.socket(zmq::PULL)?;
+        results.bind(&results_addr)?;
+
+        let handler = Arc::new(handler);
+        let mut workers = Vec::with_capacity(size);
+        for n in 0..size {
+            let name = format!("worker-{}", n);
+            let context = context.clone();
+            let handler = handler.clone();
+            let (jobs_addr, results_addr) = (jobs_addr.clone(), results_addr.clone());
+            let worker_name = name.clone();
+            let handle = run_named_thread(&name, move || {
+                let jobs = context.socket(zmq::PULL)?;
+                jobs.connect(&jobs_addr)?;
+                let results = context.socket(zmq::PUSH)?;
+                results.connect(&results_addr)?;
+                results.send(READY, 0)?;
+                run_worker(&worker_name, &jobs, &results, &*handler)
+            })?;
+            workers.push((name, handle));
+        }
+        let mut pool = WorkerPool {
+            jobs,
+            results,
+            workers,
+            next_id: 0,
+            pending: 0,
+        };
+        // jobs, and stops, are pushed round-robin, so every worker has to be connected.
+        let clock = Clock::new();
+        for ready in 0..size {
+            let wait = (READY_TIMEOUT - clock.mono()).max(0);
+            if pool.results.poll(zmq::POLLIN, wait)? == 0 {
+                return Err(pool.not_ready(ready));
+            }
+            pool.results.recv_bytes(0)?;
+        }
+        Ok(pool)
+    }
+
+    // Returns the error of the first worker that failed to start, if any.
+    fn not_ready(&mut self, ready: usize) -> Error {
+        let size = self.workers.len();
+        let (finished, running): (Vec<_>, Vec<_>) =
+            self.workers.drain(..).partition(|(_, h)| h.is_finished());
+        self.workers = running;
+        for (name, handle) in finished {
+            match handle.join() {
+                Ok(Err(e)) => return e,
+                Ok(Ok(())) => {}
+                Err(payload) => {
+                    return WorkError::WorkerDied(name, panic_message(&*payload)).into()
+                }
+            }
+        }
+        WorkError::NotReady(ready, size).into()
+    }
+
+    /// Returns the number of worker threads.
+    pub fn size(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Returns the number of jobs whose results were not received yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Submit a job, returning its id.
+    pub fn submit<I, T>(&mut self, frames: I) -> Result<u64, Error>
+    where
+        I: IntoIterator<Item = T>,
+        T: Into<Vec<u8>>,
+    {
+        self.next_id += 1;
+        let mut msg = vec![self.next_id.to_be_bytes().to_vec()];
+        msg.extend(frames.into_iter().map(Into::into));
+        self.jobs.send_multipart(msg, 0)?;
+        self.pending += 1;
+        Ok(self.next_id)
+    }
+
+    /// Receive the next result, waiting up to `timeout` milliseconds, or forever if it is
+    /// `-1`. Returns `None` if no result arrived in time.
+    ///
+    /// Jobs whose handler panicked fail with `WorkError::WorkerPanicked`.
+    pub fn recv(&mut self, timeout: i64) -> Result<Option<JobResult>, Error> {
+        if self.results.poll(zmq::POLLIN, timeout)? == 0 {
+            return Ok(None);
+        }
+        let (id, mut frames) = split_id(self.results.recv_multipart(0)?)?;
+        self.pending = self.pending.saturating_sub(1);
+        if frames.is_empty() {
+            return Err(WorkError::InvalidMessage.into());
+        }
+        let rest = frames.split_off(1);
+        match &frames[0][..] {
+            STATUS_OK => Ok(Some((id, rest))),
+            STATUS_PANIC if rest.len() == 2 => Err(WorkError::WorkerPanicked(
+                String::from_utf8_lossy(&rest[0]).to_string(),
+                id,
+                String::from_utf8_lossy(&rest[1]).to_string(),
+            )
+            .into()),
+            _ => Err(WorkError::InvalidMessage.into()),
+        }
+    }
+
+    /// Drain the pending jobs, waiting up to `timeout` milliseconds for their results, then
+    /// stop, and join, the workers. Returns the results that were drained, or the first
+    /// error, e.g. `WorkError::DrainTimeout`, after the workers are stopped.
+    pub fn shutdown(mut self, timeout: i64) -> Result<Vec<JobResult>, Error> {
+        let drained = self.drain(timeout);
+        for _ in 0..self.workers.len() {
+            self.jobs.send(STOP, 0)?;
+        }
+        for (name, handle) in self.workers.drain(..) {
+            match handle.join() {
+                Ok(result) => result?,
+                Err(payload) => {
+                    return Err(WorkError::WorkerDied(name, panic_message(&*payload)).into())
+                }
+            }
+        }
+        drained
+    }
+
+    // Receive the results of the pending jobs.
+    fn drain(&mut self, timeout: i64) -> Result<Vec<JobResult>, Error> {
+        let clock = Clock::new();
+        let mut drained = Vec::new();
+        let mut failed = None;
+        while self.pending > 0 {
+            let wait = if timeout < 0 {
+                -1
+            } else {
+                (timeout - clock.mono()).max(0)
+            };
+            match self.recv(wait) {
+                Ok(Some(result)) => drained.push(result),
+                Ok(None) => return Err(WorkError::DrainTimeout(self.pending).into()),
+                Err(e) => {
+                    if failed.is_none() {
+                        failed = Some(e);
+                    }
+                }
+            }
+        }
+        match failed {
+            Some(e) => Err(e),
+            None => Ok(drained),
+        }
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        if self.workers.is_empty() {
+            return;
+        }
+        for _ in 0..self.workers.len() {
+            if self.jobs.send(STOP, zmq::DONTWAIT).is_err() {
+                break;
+            }
+        }
+        let clock = Clock::new();
+        let mut pending: Vec<_> = self.workers.drain(..).collect();
+        loop {
+            let (finished, running): (Vec<_>, Vec<_>) =
+                pending.into_iter().partition(|(_, h)| h.is_finished());
+            for (_, handle) in finished {
+                let _ = handle.join();
+            }
+            pending = running;
+            if pending.is_empty() || clock.mono() >= STOP_TIMEOUT {
+                break;
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+        for (name, _) in pending {
+            nlog!(warn, "worker not stopped name={}", name);
+        }
+    }
+}
+
+// Run jobs until the pool stops the worker.
+fn run_worker<F>(
+    name: &str,
+    jobs: &zmq::Socket,
+    results: &zmq::Socket,
+    handler: &F,
+) -> Result<(), Error>
+where
+    F: Fn(Vec<Vec<u8>>) -> Vec<Vec<u8>>,
+{
+    loop {
+        let msg = jobs.recv_multipart(0)?;
+        if msg.len() == 1 && msg[0] == STOP {
+            nlog!(debug, "worker stopping name={}", name);
+            return Ok(());
+        }
+        let (id, job) = match split_id(msg) {
+            Ok(job) => job,
+            Err(_) => {
+                nlog!(debug, "dropped invalid job");
+                continue;
+            }
+        };
+        let mut reply = vec![id.to_be_bytes().to_vec()];
+        match panic::catch_unwind(AssertUnwindSafe(|| handler(job))) {
+            Ok(frames) => {
+                reply.push(STATUS_OK.to_vec());
+                reply.extend(frames);
+            }
+            Err(payload) => {
+                let message = panic_message(&*payload);
+                nlog!(
+                    error,
+                    "job panicked worker={} job={} error={}",
+                    name,
+                    id,
+                    message
+                );
+                reply.push(STATUS_PANIC.to_vec());
+                reply.push(name.as_bytes().to_vec());
+                reply.push(message.into_bytes());
+            }
+        }
+        results.send_multipart(reply, 0)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn jobs_are_spread_over_the_workers() {
+        let ctx = zmq::Context::new();
+        let mut pool = WorkerPool::new(&ctx, 3, |job: Vec<Vec<u8>>| {
+            let name = thread::current().name().unwrap_or("").to_string();
+            vec![job[0].clone(), name.into_bytes()]
+        })
+        .unwrap();
+        assert_eq!(pool.size(), 3);
+        for n in 0..9 {
+            pool.submit(vec![n.to_string()]).unwrap();
+        }
+        let results = pool.shutdown(1_000).unwrap();
+        assert_eq!(results.len(), 9);
+        for (id, frames) in &results {
+            assert_eq!(frames[0], (id - 1).to_string().into_bytes());
+            assert!(frames[1].starts_with(b"worker-"));
+        }
+    }
+
+    #[test]
+    fn panics_are_reported_as_pool_errors() {
+        let ctx = zmq::Context::new();
+        let mut pool = WorkerPool::new(&ctx, 1, |job: Vec<Vec<u8>>| {
+            if job[0] == b"boom" {
+                panic!("job failed");
+            }
+            job
+        })
+        .unwrap();
+        let boom = pool.submit(vec!["boom"]).unwrap();
+        let err = pool.recv(1_000).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WorkError>(),
+            Some(&WorkError::WorkerPanicked(
+                "worker-0".into(),
+                boom,
+                "job failed".into()
+            ))
+        );
+        // the worker goes on with the next job.
+        let fine = pool.submit(vec!["fine"]).unwrap();
+        assert_eq!(
+            pool.recv(1_000).unwrap(),
+            Some((fine, vec![b"fine".to_vec()]))
+        );
+        assert!(pool.shutdown(1_000).unwrap().is_empty());
+    }
+
+    #[test]
+    fn shutdown_fails_when_jobs_are_not_drained_in_time() {
+        let ctx = zmq::Context::new();
+        let mut pool = WorkerPool::new(&ctx, 1, |job: Vec<Vec<u8>>| {
+            thread::sleep(::std::time::Duration::from_millis(100));
+            job
+        })
+        .unwrap();
+        pool.submit(vec!["slow"]).unwrap();
+        let err = pool.shutdown(10).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<WorkError>(),
+            Some(&WorkError::DrainTimeout(1))
+        );
+    }
+
+    #[test]
+    fn dropped_pools_stop_their_workers() {
+        let ctx = zmq::Context::new();
+        let pool = WorkerPool::new(&ctx, 2, |job: Vec<Vec<u8>>| job).unwrap();
+        drop(pool);
+        // the workers closed their sockets, so the context terminates.
+        let (done, finished) = ::std::sync::mpsc::channel();
+        thread::spawn(move || {
+            drop(ctx);
+            done.send(()).unwrap();
+        });
+        finished.recv_timeout(Duration::from_secs(1)).unwrap();
+    }
+}